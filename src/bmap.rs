@@ -0,0 +1,214 @@
+//! Module handling the block maps of raw images for bmaptool.
+//!
+//! `bmaptool copy` only writes the blocks listed in the block map, which is much faster than `dd` for the sparse raw images.
+//! The block map is generated in the bmap 2.0 format from the *uncompressed* raw image, right before it is compressed:
+//! the ranges of mapped blocks are found with `SEEK_DATA` and `SEEK_HOLE`, and each range is checksummed with SHA-256, as bmaptool verifies the decompressed data.
+//! It is saved as `<name of the image>.bmap` next to the output, e.g. `aosc-os_base_rawimg_..._amd64.img.bmap` for `aosc-os_base_rawimg_..._amd64.img.xz`.
+use std::{
+	fs::{self, File},
+	io::{Read, Seek, SeekFrom},
+	os::fd::AsRawFd,
+	path::Path,
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Size of the blocks in the block maps.
+pub const BMAP_BLOCK_SIZE: u64 = 4096;
+/// Version of the bmap format written.
+const BMAP_VERSION: &str = "2.0";
+
+/// Find the byte ranges `(start, end)` of `fd` holding data, the holes in between are not allocated.
+pub fn data_ranges(fd: &File) -> Result<Vec<(u64, u64)>> {
+	let raw = fd.as_raw_fd();
+	let size = fd.metadata()?.len();
+	let mut ranges = Vec::new();
+	let mut offset = 0;
+	while offset < size {
+		let start = unsafe { libc::lseek(raw, offset as libc::off_t, libc::SEEK_DATA) };
+		if start < 0 {
+			let e = std::io::Error::last_os_error();
+			// No data behind the offset.
+			if e.raw_os_error() == Some(libc::ENXIO) {
+				break;
+			}
+			return Err(e).context("Unable to find the data in the raw image");
+		}
+		let end = unsafe { libc::lseek(raw, start, libc::SEEK_HOLE) };
+		if end < 0 {
+			return Err(std::io::Error::last_os_error())
+				.context("Unable to find the holes in the raw image");
+		}
+		ranges.push((start as u64, end as u64));
+		offset = end as u64;
+	}
+	Ok(ranges)
+}
+
+/// Convert the byte ranges to inclusive ranges of blocks of `block_size` bytes, merging the ones sharing or touching a block.
+pub fn block_ranges(data: &[(u64, u64)], block_size: u64) -> Vec<(u64, u64)> {
+	let mut blocks: Vec<(u64, u64)> = Vec::new();
+	for &(start, end) in data.iter().filter(|(start, end)| end > start) {
+		let first = start / block_size;
+		let last = (end - 1) / block_size;
+		match blocks.last_mut() {
+			Some(prev) if first <= prev.1 + 1 => prev.1 = prev.1.max(last),
+			_ => blocks.push((first, last)),
+		}
+	}
+	blocks
+}
+
+/// Generate the block map of the raw image at `image`.
+pub fn generate_bmap(image: &Path) -> Result<String> {
+	let mut fd = File::open(image).context(format!("Unable to open {}", image.display()))?;
+	let size = fd.metadata()?.len();
+	let blocks_count = size.div_ceil(BMAP_BLOCK_SIZE);
+	let ranges = block_ranges(&data_ranges(&fd)?, BMAP_BLOCK_SIZE);
+	let mapped: u64 = ranges.iter().map(|(first, last)| last - first + 1).sum();
+	let mut block_map = String::new();
+	let mut buf = vec![0; 1048576];
+	for (first, last) in &ranges {
+		let start = first * BMAP_BLOCK_SIZE;
+		// The last block may be partial.
+		let end = ((last + 1) * BMAP_BLOCK_SIZE).min(size);
+		fd.seek(SeekFrom::Start(start))?;
+		let mut hasher = Sha256::new();
+		let mut left = end - start;
+		while left > 0 {
+			let len = left.min(buf.len() as u64) as usize;
+			fd.read_exact(&mut buf[..len])?;
+			hasher.update(&buf[..len]);
+			left -= len as u64;
+		}
+		let range = if first == last {
+			first.to_string()
+		} else {
+			format!("{}-{}", first, last)
+		};
+		block_map += &format!(
+			"\t\t<Range chksum=\"{:x}\"> {} </Range>\n",
+			hasher.finalize(),
+			range
+		);
+	}
+	let bmap = format!(
+		r#"<?xml version="1.0" ?>
+<!-- Block map of {name}, generated by mkrawimg. -->
+<bmap version="{version}">
+	<!-- Image size in bytes: {size_mib} MiB -->
+	<ImageSize> {size} </ImageSize>
+	<!-- Size of a block in bytes -->
+	<BlockSize> {block_size} </BlockSize>
+	<!-- Count of blocks in the image file -->
+	<BlocksCount> {blocks_count} </BlocksCount>
+	<!-- Count of mapped blocks: {mapped_mib} MiB or {percent:.1}% -->
+	<MappedBlocksCount> {mapped} </MappedBlocksCount>
+	<!-- Type of checksum used in this file -->
+	<ChecksumType> sha256 </ChecksumType>
+	<!-- The checksum of this bmap file, calculated with all digits of it being 0 -->
+	<BmapFileChecksum> {checksum} </BmapFileChecksum>
+	<!-- Ranges of the mapped blocks, with the checksum of each range -->
+	<BlockMap>
+{block_map}	</BlockMap>
+</bmap>
+"#,
+		name = image
+			.file_name()
+			.map(|x| x.to_string_lossy())
+			.unwrap_or_default(),
+		version = BMAP_VERSION,
+		size_mib = size / 1048576,
+		block_size = BMAP_BLOCK_SIZE,
+		mapped_mib = mapped * BMAP_BLOCK_SIZE / 1048576,
+		percent = if blocks_count == 0 {
+			0.0
+		} else {
+			mapped as f64 * 100.0 / blocks_count as f64
+		},
+		checksum = "0".repeat(64),
+	);
+	let checksum = format!("{:x}", Sha256::digest(bmap.as_bytes()));
+	Ok(bmap.replacen(&"0".repeat(64), &checksum, 1))
+}
+
+/// Generate the block map of the raw image at `image` and save it to `to`.
+pub fn write_bmap(image: &Path, to: &Path) -> Result<()> {
+	let bmap = generate_bmap(image)?;
+	let filename = to
+		.file_name()
+		.context("Block map has no file name")?
+		.to_string_lossy();
+	// Written under a temporary name, so a partial block map is never mistaken for a complete one.
+	let tmp = to.with_file_name(format!(".{}.part", filename));
+	fs::write(&tmp, bmap).context(format!("Unable to write {}", tmp.display()))?;
+	fs::rename(&tmp, to).context(format!("Unable to save {}", to.display()))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Write;
+
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	#[test]
+	fn test_block_ranges() {
+		assert_eq!(block_ranges(&[], 4096), []);
+		assert_eq!(
+			block_ranges(&[(0, 4096), (4096, 8192), (16384, 16385)], 4096),
+			[(0, 1), (4, 4)]
+		);
+		// Sharing a block
+		assert_eq!(block_ranges(&[(0, 100), (200, 300)], 4096), [(0, 0)]);
+		assert_eq!(
+			block_ranges(&[(4000, 8200), (12288, 12289)], 4096),
+			[(0, 3)]
+		);
+		assert_eq!(block_ranges(&[(5, 5)], 4096), []);
+	}
+
+	#[test]
+	fn test_generate_bmap() -> Result<()> {
+		let dir = TestDir::new("bmap")?;
+		let path = dir.join("disk.img");
+		let mut fd = File::create(&path)?;
+		fd.set_len(16 * 1048576 + 100)?;
+		fd.write_all(&[1; 8192])?;
+		fd.seek(SeekFrom::Start(8 * 1048576))?;
+		fd.write_all(&[2; 4096])?;
+		// The partial last block
+		fd.seek(SeekFrom::Start(16 * 1048576))?;
+		fd.write_all(&[3; 100])?;
+		fd.sync_all()?;
+		let data = data_ranges(&fd)?;
+		assert_eq!(data.first().map(|x| x.0), Some(0));
+		let bmap = generate_bmap(&path)?;
+		assert!(bmap.contains("<bmap version=\"2.0\">"), "{}", bmap);
+		assert!(bmap.contains(&format!("<ImageSize> {} </ImageSize>", 16 * 1048576 + 100)));
+		assert!(bmap.contains("<BlocksCount> 4097 </BlocksCount>"));
+		// Filesystems allocate in blocks of their own, at least the written ones are mapped
+		let first = format!("{:x}", Sha256::digest([1; 8192]));
+		let last = format!("{:x}", Sha256::digest([3; 100]));
+		if bmap.contains("<MappedBlocksCount> 4 </MappedBlocksCount>") {
+			assert!(bmap.contains(&format!("<Range chksum=\"{}\"> 0-1 </Range>", first)));
+			assert!(bmap.contains("> 2048 </Range>"));
+			assert!(bmap.contains(&format!("<Range chksum=\"{}\"> 4096 </Range>", last)));
+		}
+		// The checksum of the file itself
+		let checksum = bmap
+			.split("<BmapFileChecksum> ")
+			.nth(1)
+			.and_then(|x| x.split(' ').next())
+			.unwrap()
+			.to_owned();
+		let zeroed = bmap.replace(&checksum, &"0".repeat(64));
+		assert_eq!(format!("{:x}", Sha256::digest(zeroed.as_bytes())), checksum);
+
+		let to = path.with_extension("img.bmap");
+		write_bmap(&path, &to)?;
+		assert_eq!(fs::read_to_string(&to)?, bmap);
+		Ok(())
+	}
+}
@@ -5,20 +5,46 @@
 //! - Run a script (within the same directory as the `device.toml` file)
 //! - Apply (“flash”) a file to the specific partition of the target image
 //! - Apply (“flash”) a file to the specific offset of the target image
+//! - Run a command on the build host, and flash the file it produces
 //!
 //! For details please go to [`BootloaderSpec`].
 //!
 use std::{
-	fs::File,
-	io::{BufReader, Seek, copy},
+	fs::{self, File},
+	io::{ErrorKind, Read, Seek, SeekFrom, Write},
+	os::fd::AsRawFd,
 	path::{Path, PathBuf},
+	process::Command,
 };
 
-use anyhow::{Context, Result};
-use log::info;
+use anyhow::{Context, Result, bail};
+use gptman::linux::{BlockError, get_sector_size};
+use log::{debug, info};
 use serde::Deserialize;
 
-use crate::{context::ImageContext, utils::run_script_with_chroot};
+use crate::{
+	context::ImageContext,
+	device::PartitionMapData,
+	export::{INITRD_PLACEHOLDER, LINUX_PLACEHOLDER, ROOTFS_PLACEHOLDER},
+	filesystem::FilesystemType,
+	partition::{PartitionType, PartitionUsage},
+	utils::{
+		chroot_script_command, chroot_str_script_command, copy_preserving, download_file,
+		find_program, format_command, is_contained_path, partition_path, path_str,
+		run_script_with_chroot, run_str_script_with_chroot, sectors_to_bytes, sha256_file,
+		shell_quote,
+	},
+};
+
+/// Where to flash the file produced by a [host command](BootloaderSpec::HostCommand).
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum FlashTarget {
+	/// Flash to the specific partition, same as [`BootloaderSpec::FlashPartition`].
+	Partition { partition: u64 },
+	/// Flash to the specific offset, same as [`BootloaderSpec::FlashOffset`].
+	Offset { offset: u64, max_size: Option<u64> },
+}
 
 /// Specifies how to apply a bootloader image (file) to the target image.
 ///
@@ -59,7 +85,7 @@ use crate::{context::ImageContext, utils::run_script_with_chroot};
 /// type = flash_partition
 /// # Path to the bootloader image within the target root filesystem (symbolic links allowed).
 /// path = "/usr/lib/u-boot/rk64/rk3588-orange-pi-5-max-idbloader.img"
-/// # The index of the target partition
+/// # The number of the target partition (the `num` field of the partition)
 /// partition = 1
 /// ```
 ///
@@ -71,9 +97,67 @@ use crate::{context::ImageContext, utils::run_script_with_chroot};
 /// partition = 2
 /// ```
 ///
+/// ### Install GRUB
+///
+/// Runs `grub-install` and `grub-mkconfig` in the target system. The `grub` package for the target must be installed.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = "grub"
+/// # One of the GRUB platforms, e.g. x86_64-efi, arm64-efi, i386-pc.
+/// target = "x86_64-efi"
+/// # Optional, defaults to the mountpoint of the EFI system partition.
+/// efi_directory = "/efi"
+/// # Optional, install to the removable media path (e.g. /EFI/BOOT/BOOTX64.EFI).
+/// removable = true
+/// ```
+///
+/// ### Install systemd-boot
+///
+/// Runs `bootctl install` in the target system, and generates a boot loader entry for the installed kernel. The `systemd-boot` package must be installed.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = "systemd_boot"
+/// # Optional, defaults to "AOSC OS".
+/// entry_title = "AOSC OS"
+/// # Optional, defaults to the kernel_cmdline of the device. Must not contain root=.
+/// kernel_cmdline = "rw console=ttyAMA0,115200"
+/// ```
+///
+/// ### Generate extlinux.conf for U-Boot
+///
+/// Writes `extlinux.conf` for the U-Boot distro boot (`distro_bootcmd`) to the firmware partition, or the boot partition if the device has no firmware partition.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = "extlinux"
+/// # Directory relative to the boot partition.
+/// dir = "extlinux"
+/// # Must not contain root=.
+/// cmdline = "rw console=ttyS2,1500000 rootwait"
+/// # Optional, either fdt or fdtdir, relative to the boot partition.
+/// fdtdir = "/dtbs"
+/// ```
+///
+/// ### Use a bootloader image from outside of the target root filesystem
+///
+/// By default `path` refers to a file within the target root filesystem. Set `source` to flash a file from the directory containing `device.toml`, or from an URL.
+/// Files downloaded from URLs must have their SHA256 checksum specified. See [`PayloadSource`] for details.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = flash_partition
+/// source = "device_dir"
+/// path = "u-boot.itb"
+/// partition = 2
+/// ```
+///
 /// ### Flash a bootloader image to the specific location of the target image
 ///
-/// The offset is not checked for overlaps of the partition/filesystem. Use it at your own risk.
+/// The offset must not fall into a partition containing a filesystem. Optionally, declare the maximum size of the image with `max_size`, to have the whole region checked against the partition layout.
+/// During the build, images which would overwrite the next partition containing a filesystem are rejected.
+/// If the image is smaller than `max_size`, the rest of the region is zeroed, so no stale bytes are left there.
 ///
 /// ```toml
 /// [[bootloader]]
@@ -81,6 +165,8 @@ use crate::{context::ImageContext, utils::run_script_with_chroot};
 /// path = "/path/to/bootlodaer/image"
 /// # Offset from the start of the target image in bytes.
 /// offset = 0x400
+/// # Optional, maximum size of the image in bytes.
+/// max_size = 0x100000
 /// ```
 ///
 /// [Defined variables]: crate::device::DeviceSpec#available-defined-variables
@@ -102,24 +188,30 @@ pub enum BootloaderSpec {
 	Script { name: String },
 	/// Flash a bootloader image to the specific partition of the target image.
 	///
-	/// The path must be (or point to) a regular file within the target root filesystem.
+	/// The path must be (or point to) a regular file within the target root filesystem, unless `source` is specified (see [`PayloadSource`]).
 	///
 	/// ```toml
 	/// [[bootloader]]
 	/// type = flash_partition
 	/// # Path to the bootloader image within the target root filesystem (symbolic links allowed).
 	/// path = "/usr/lib/u-boot/rk64/rk3588-orange-pi-5-max-idbloader.img"
-	/// # The index of the target partition
+	/// # The number of the target partition (the `num` field of the partition)
 	/// partition = 1
 	/// ```
-	FlashPartition { path: PathBuf, partition: u64 },
+	FlashPartition {
+		path: PathBuf,
+		partition: u64,
+		#[serde(default)]
+		source: PayloadSource,
+		sha256: Option<String>,
+	},
 	/// Flash a bootloader image to the specific location of the target image.
 	///
-	/// The path must be (or point to) a regular file within the target root filesystem.
+	/// The path must be (or point to) a regular file within the target root filesystem, unless `source` is specified (see [`PayloadSource`]).
 	///
 	/// <div class="warning">
 	///
-	/// - Always make sure the image will not overlap existing partitions and filesystems.
+	/// - Always make sure the image will not overlap existing partitions and filesystems. Only partitions containing a filesystem are checked.
 	/// - If your bootloader image is too large (e.g. exceeds 960KiB), you must adjust the starting position of the first partition (since the default starting sector is 2048 (1 MiB)).
 	/// - Therefore it is advised to create dedicated partitions reserved for bootloaders and flash them to their specific partition.
 	///
@@ -131,12 +223,220 @@ pub enum BootloaderSpec {
 	/// path = "/path/to/bootlodaer/image"
 	/// # Offset from the start of the target image in bytes.
 	/// offset = 0x400
+	/// # Optional, maximum size of the image in bytes.
+	/// max_size = 0x100000
 	/// ```
-	FlashOffset { path: PathBuf, offset: u64 },
+	FlashOffset {
+		path: PathBuf,
+		offset: u64,
+		max_size: Option<u64>,
+		#[serde(default)]
+		source: PayloadSource,
+		sha256: Option<String>,
+	},
+	/// Run a command on the build host, then flash the file it produces to `flash`.
+	///
+	/// Useful for bootloaders which must be processed by vendor tools at build time, e.g. signing the U-Boot image for Amlogic devices.
+	///
+	/// The command runs in the sketch directory of the image, with the [defined variables] exported to the environment.
+	/// The program is searched in `PATH`, unless it contains a `/`, in which case it is relative to the directory containing `device.toml`.
+	/// `output` is relative to the sketch directory. The output of the command is captured into the log.
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = "host_command"
+	/// command = ["./sign-uboot.sh", "u-boot.bin"]
+	/// output = "u-boot.bin.sd.bin"
+	/// # Either flash to an offset, with an optional maximum size ...
+	/// flash = { offset = 512, max_size = 0x100000 }
+	/// # ... or to a partition.
+	/// # flash = { partition = 1 }
+	/// ```
+	///
+	/// [defined variables]: crate::device::DeviceSpec#available-defined-variables
+	HostCommand {
+		command: Vec<String>,
+		output: PathBuf,
+		flash: FlashTarget,
+	},
+	/// Install GRUB with `grub-install`, then generate `/boot/grub/grub.cfg` with `grub-mkconfig`.
+	///
+	/// For EFI targets, GRUB is installed to the EFI system partition, and the NVRAM of the build host is never touched.
+	/// For BIOS targets (`i386-pc`), GRUB is installed to the image itself.
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = "grub"
+	/// target = "x86_64-efi"
+	/// # Optional, defaults to the mountpoint of the EFI system partition.
+	/// efi_directory = "/efi"
+	/// # Optional, defaults to false.
+	/// removable = true
+	/// ```
+	Grub {
+		target: String,
+		efi_directory: Option<String>,
+		#[serde(default)]
+		removable: bool,
+	},
+	/// Install systemd-boot with `bootctl install`, then generate a [Boot Loader Specification] entry at `$ESP/loader/entries/aosc-os.conf`.
+	///
+	/// The kernel and the init ramdisk are discovered from `/boot` of the target system, and copied to the EFI system partition if they are not there already.
+	/// The `root=` parameter is generated from the root partition (see [`DeviceSpec::gen_root_param`](crate::device::DeviceSpec)).
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = "systemd_boot"
+	/// # Optional, defaults to "AOSC OS".
+	/// entry_title = "AOSC OS"
+	/// # Optional, defaults to the kernel_cmdline of the device.
+	/// kernel_cmdline = "rw console=ttyAMA0,115200"
+	/// ```
+	///
+	/// [Boot Loader Specification]: https://uapi-group.org/specifications/specs/boot_loader_specification/
+	SystemdBoot {
+		entry_title: Option<String>,
+		kernel_cmdline: Option<String>,
+	},
+	/// Generate `<boot mountpoint>/<dir>/extlinux.conf` for U-Boot devices booting with `distro_bootcmd`.
+	///
+	/// The kernel and the init ramdisk are discovered from the boot partition, then `/boot` of the root filesystem, and copied to the boot partition if necessary.
+	/// The init ramdisk is skipped if the device is `initrdless`. `root=PARTUUID=...` is prepended to `cmdline`.
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = "extlinux"
+	/// dir = "extlinux"
+	/// cmdline = "rw console=ttyS2,1500000 rootwait"
+	/// # Optional, path to the device tree blob.
+	/// fdt = "/dtbs/rockchip/rk3588-orangepi-5-max.dtb"
+	/// # Optional, path to the directory containing device tree blobs. Conflicts with fdt.
+	/// fdtdir = "/dtbs"
+	/// ```
+	Extlinux {
+		dir: String,
+		cmdline: String,
+		fdt: Option<String>,
+		fdtdir: Option<String>,
+	},
+}
+
+/// Find the kernel image and the init ramdisk with the latest version in `boot_dir`.
+///
+/// Kernel images are named `vmlinuz-VERSION` or `vmlinux-VERSION`, init ramdisks are named `initramfs-VERSION.img`, `initrd.img-VERSION` or `initrd-VERSION.img`.
+pub fn find_kernel_images(boot_dir: &Path) -> Result<(PathBuf, Option<PathBuf>)> {
+	let mut kernels = Vec::new();
+	for entry in fs::read_dir(boot_dir).context(format!(
+		"Unable to read the boot directory '{}'",
+		boot_dir.display()
+	))? {
+		let entry = entry?;
+		// Kernels with non-UTF-8 names could not be written into the boot entries anyway.
+		let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+			continue;
+		};
+		if !entry.file_type()?.is_file() {
+			continue;
+		}
+		if let Some(version) = name
+			.strip_prefix("vmlinuz-")
+			.or_else(|| name.strip_prefix("vmlinux-"))
+		{
+			kernels.push((version.to_owned(), entry.path()));
+		}
+	}
+	// Compare the dot-separated numeric components, then the whole string.
+	let version_key = |v: &str| {
+		let nums = v
+			.split(['.', '-', '+'])
+			.map(|x| x.parse::<u64>().unwrap_or(0))
+			.collect::<Vec<_>>();
+		(nums, v.to_owned())
+	};
+	kernels.sort_by_key(|(v, _)| version_key(v));
+	let (version, kernel) = kernels
+		.pop()
+		.context(format!("No kernel image found in '{}'", boot_dir.display()))?;
+	let initrd = [
+		format!("initramfs-{}.img", version),
+		format!("initrd.img-{}", version),
+		format!("initrd-{}.img", version),
+	]
+	.iter()
+	.map(|x| boot_dir.join(x))
+	.find(|x| x.is_file());
+	Ok((kernel, initrd))
+}
+
+/// GRUB platforms supported by the `grub` bootloader action.
+pub const GRUB_EFI_TARGETS: &[&str] = &[
+	"x86_64-efi",
+	"i386-efi",
+	"arm64-efi",
+	"arm-efi",
+	"loongarch64-efi",
+	"riscv64-efi",
+];
+pub const GRUB_BIOS_TARGETS: &[&str] = &["i386-pc"];
+
+/// Where the bootloader image to flash comes from.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = "flash_offset"
+/// source = "url"
+/// path = "https://example.com/idbloader.img"
+/// sha256 = "d2c7...e1f0"
+/// offset = 0x8000
+/// ```
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadSource {
+	/// `path` is a path within the target root filesystem. This is the default.
+	#[default]
+	Rootfs,
+	/// `path` is relative to the directory containing `device.toml`, just like scripts.
+	DeviceDir,
+	/// `path` is an URL, the file is downloaded into the working directory at build time.
+	///
+	/// The `sha256` field is mandatory, the file is verified before flashing.
+	Url,
 }
 
 impl BootloaderSpec {
-	fn run_script<P, Q>(container: P, script: Q, binds: &[&str]) -> Result<()>
+	/// The program this bootloader runs in the target system, if it runs any.
+	pub fn target_program(&self) -> Option<&str> {
+		match self {
+			BootloaderSpec::Script { name } => Some(name),
+			BootloaderSpec::Grub { .. } => Some("grub-install"),
+			BootloaderSpec::SystemdBoot { .. } => Some("bootctl"),
+			BootloaderSpec::FlashPartition { .. }
+			| BootloaderSpec::FlashOffset { .. }
+			| BootloaderSpec::HostCommand { .. }
+			| BootloaderSpec::Extlinux { .. } => None,
+		}
+	}
+
+	/// The directories of the root filesystem this bootloader writes into, besides the partitions mounted in it, or `None` if it may write anywhere.
+	///
+	/// Only these are writable when the bootloaders are applied to an existing image again, see [`ImageContext::rebootload`].
+	pub fn rootfs_writes(&self) -> Option<Vec<&str>> {
+		match self {
+			BootloaderSpec::Script { .. } => None,
+			BootloaderSpec::Grub { efi_directory, .. } => Some(
+				std::iter::once("/boot/grub")
+					.chain(efi_directory.as_deref())
+					.collect(),
+			),
+			BootloaderSpec::FlashPartition { .. }
+			| BootloaderSpec::FlashOffset { .. }
+			| BootloaderSpec::HostCommand { .. }
+			| BootloaderSpec::SystemdBoot { .. }
+			| BootloaderSpec::Extlinux { .. } => Some(Vec::new()),
+		}
+	}
+
+	fn run_script<P, Q>(container: P, machine: &str, script: Q, binds: &[&str]) -> Result<()>
 	where
 		P: AsRef<Path>,
 		Q: AsRef<Path>,
@@ -146,63 +446,548 @@ impl BootloaderSpec {
 		info!("Running script {}", script.display());
 		let filename = script.file_name().unwrap();
 		let dst = container.join("tmp").join(filename);
-		std::fs::copy(script, dst)?;
-		run_script_with_chroot(container, &Path::new("/tmp").join(filename), binds, None)
+		copy_preserving(script, dst)?;
+		run_script_with_chroot(
+			container,
+			machine,
+			&Path::new("/tmp").join(filename),
+			binds,
+			None,
+		)
 	}
 
-	fn apply_offset<P, Q, R>(img: P, offset: u64, container: Q, loopdev: R) -> Result<()>
+	/// Generate the script installing GRUB.
+	///
+	/// `efi_directory` is required for EFI targets, `loopdev` is used for BIOS targets.
+	fn grub_script(
+		target: &str,
+		efi_directory: Option<&str>,
+		removable: bool,
+		loopdev: &Path,
+	) -> Result<String> {
+		let mut install = vec![
+			"grub-install".to_owned(),
+			format!("--target={}", shell_quote(target)),
+		];
+		if GRUB_EFI_TARGETS.contains(&target) {
+			let efi_directory =
+				efi_directory.context("EFI directory is required for EFI targets")?;
+			install.push(format!("--efi-directory={}", shell_quote(efi_directory)));
+			// We are in a container, do not touch the NVRAM of the build host.
+			install.push("--no-nvram".to_owned());
+			if removable {
+				install.push("--removable".to_owned());
+			}
+		} else if GRUB_BIOS_TARGETS.contains(&target) {
+			install.push(shell_quote(path_str(loopdev)?));
+		} else {
+			bail!("Unsupported GRUB target '{}'", target);
+		}
+		Ok(format!(
+			"set -e\n{}\ngrub-mkconfig -o /boot/grub/grub.cfg\n",
+			install.join(" ")
+		))
+	}
+
+	/// Generate the script installing systemd-boot.
+	fn bootctl_script(esp_mountpoint: &str) -> String {
+		// We are in a container, do not touch the EFI variables of the build host.
+		format!(
+			"bootctl install --esp-path={} --no-variables\n",
+			shell_quote(esp_mountpoint)
+		)
+	}
+
+	/// Generate a Boot Loader Specification entry.
+	fn bls_entry(title: &str, linux: &str, initrd: Option<&str>, options: &str) -> String {
+		let mut entry = format!("title {}\nlinux {}\n", title, linux);
+		if let Some(initrd) = initrd {
+			entry += &format!("initrd {}\n", initrd);
+		}
+		entry += &format!("options {}\n", options);
+		entry
+	}
+
+	/// Generate the content of `extlinux.conf`.
+	fn extlinux_conf(
+		linux: &str,
+		initrd: Option<&str>,
+		fdt: Option<&str>,
+		fdtdir: Option<&str>,
+		append: &str,
+	) -> String {
+		let mut conf = "default aosc-os\nmenu title AOSC OS\ntimeout 30\n\nlabel aosc-os\n\tmenu label AOSC OS\n".to_owned();
+		conf += &format!("\tlinux {}\n", linux);
+		if let Some(initrd) = initrd {
+			conf += &format!("\tinitrd {}\n", initrd);
+		}
+		if let Some(fdt) = fdt {
+			conf += &format!("\tfdt {}\n", fdt);
+		}
+		if let Some(fdtdir) = fdtdir {
+			conf += &format!("\tfdtdir {}\n", fdtdir);
+		}
+		conf += &format!("\tappend {}\n", append);
+		conf
+	}
+
+	/// Flash the image at `offset` of the loop device.
+	///
+	/// `reserved` is the size of the gap reserved for the image, the rest of it is zeroed so nothing is left from the previous builds.
+	/// `limit` is the number and the starting offset of the next partition which must not be overwritten.
+	pub(crate) fn apply_offset<P, Q>(
+		img: P,
+		offset: u64,
+		reserved: Option<u64>,
+		limit: Option<(u32, u64)>,
+		loopdev: Q,
+	) -> Result<()>
 	where
 		P: AsRef<Path>,
 		Q: AsRef<Path>,
-		R: AsRef<Path>,
 	{
 		let img = img.as_ref();
-		let container = container.as_ref();
 		let loopdev = loopdev.as_ref();
-		// Users want to specify absolute paths. However join()ing with an absolute path replaces the whole path.
-		let img_canon = container.join(img.to_string_lossy().trim_start_matches('/'));
-		let img_fd = File::options().read(true).create(false).open(&img_canon)?;
+		let img_fd = File::options().read(true).create(false).open(img)?;
+		let img_size = img_fd.metadata()?.len();
+		if let Some((num, start)) = limit
+			&& offset.saturating_add(img_size) > start
+		{
+			bail!(
+				"Bootloader image '{}' ({} bytes) flashed at offset {:#x} would overwrite partition {} starting at {:#x}",
+				img.display(),
+				img_size,
+				offset,
+				num,
+				start
+			);
+		}
 		let mut loop_dev_fd = File::options()
 			.write(true)
 			.truncate(false)
 			.append(false)
 			.open(loopdev)?;
-		let pos = loop_dev_fd.seek(std::io::SeekFrom::Start(offset))?;
-		assert!(pos == offset);
-		let mut bufrdr = BufReader::with_capacity(512, img_fd);
-		copy(&mut bufrdr, &mut loop_dev_fd)?;
-		Ok(())
+		let sector_size = sector_size(&mut loop_dev_fd)?;
+		if let Some(reserved) = reserved
+			&& img_size < reserved
+		{
+			// Never beyond the next partition, whatever is reserved.
+			let end = limit.map_or(offset + reserved, |(_, start)| start.min(offset + reserved));
+			zero_range(&mut loop_dev_fd, offset + img_size, end, sector_size)?;
+		}
+		write_at(img_fd, &mut loop_dev_fd, offset, sector_size)
 	}
 
-	fn apply_to_partition<P, Q, R>(img: P, container: Q, partition: R) -> Result<()>
+	fn apply_to_partition<P, Q>(img: P, partition: Q) -> Result<()>
 	where
 		P: AsRef<Path>,
 		Q: AsRef<Path>,
-		R: AsRef<Path>,
 	{
 		let img = img.as_ref();
-		let container = container.as_ref();
 		let partition = partition.as_ref();
-		let img_canon = container.join(img.to_string_lossy().trim_start_matches('/'));
-		let img_fd = File::options().read(true).create(false).open(&img_canon)?;
+		let img_fd = File::options().read(true).create(false).open(img)?;
+		let img_size = img_fd.metadata()?.len();
 		let mut partition_fd = File::options()
 			.write(true)
 			.truncate(false)
 			.append(false)
 			.open(partition)?;
-		let mut bufrdr = BufReader::with_capacity(512, img_fd);
-		copy(&mut bufrdr, &mut partition_fd)?;
-		Ok(())
+		// Block devices report a zero length in their metadata, seek to the end to get the actual size.
+		let partition_size = partition_fd.seek(SeekFrom::End(0))?;
+		partition_fd.seek(SeekFrom::Start(0))?;
+		if img_size > partition_size {
+			bail!(
+				"Bootloader image '{}' ({} bytes) is larger than the target partition '{}' ({} bytes)",
+				img.display(),
+				img_size,
+				partition.display(),
+				partition_size
+			);
+		}
+		let sector_size = sector_size(&mut partition_fd)?;
+		write_at(img_fd, &mut partition_fd, 0, sector_size)
+	}
+}
+
+/// Size of the buffer used to flash the bootloader images.
+const FLASH_BUFFER_SIZE: usize = 1048576;
+
+/// Logical sector size of the block device `dev`, or 512 bytes if it is a regular file.
+fn sector_size(dev: &mut File) -> Result<u64> {
+	match get_sector_size(dev) {
+		Ok(size) => Ok(size),
+		Err(BlockError::NotBlock) => Ok(512),
+		Err(e) => Err(e).context("Unable to get the sector size of the target device"),
+	}
+}
+
+/// Write the whole content of `src` to `dest` at `offset`, and flush it to the device.
+///
+/// Writes are coalesced into chunks of [`FLASH_BUFFER_SIZE`], which end at the sector boundaries except the last one.
+fn write_at(mut src: File, dest: &mut File, offset: u64, sector_size: u64) -> Result<()> {
+	dest.seek(SeekFrom::Start(offset))?;
+	let mut buf = vec![0u8; FLASH_BUFFER_SIZE];
+	let mut pos = offset;
+	loop {
+		// Only the first chunk can be shorter, if the offset is not aligned.
+		let want = FLASH_BUFFER_SIZE - (pos % sector_size) as usize;
+		let mut len = 0;
+		while len < want {
+			match src.read(&mut buf[len..want]) {
+				Ok(0) => break,
+				Ok(n) => len += n,
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(e) => return Err(e.into()),
+			}
+		}
+		if len == 0 {
+			break;
+		}
+		dest.write_all(&buf[..len])?;
+		pos += len as u64;
 	}
+	dest.sync_all()?;
+	Ok(())
+}
+
+/// Zero the bytes of `dest` from `start` to `end`.
+///
+/// The range aligned to the sectors is deallocated with `fallocate(2)`, which the loop driver passes to its backing file. The unaligned ends, or the whole range if `fallocate(2)` is not supported, are overwritten with zeros.
+fn zero_range(dest: &mut File, start: u64, end: u64, sector_size: u64) -> Result<()> {
+	let aligned_start = start.next_multiple_of(sector_size);
+	let aligned_end = end / sector_size * sector_size;
+	let mut fill = vec![(start, end)];
+	if aligned_start < aligned_end {
+		debug!(
+			"Deallocating {:#x}..{:#x} of the target device",
+			aligned_start, aligned_end
+		);
+		let ret = unsafe {
+			libc::fallocate(
+				dest.as_raw_fd(),
+				libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+				aligned_start as libc::off_t,
+				(aligned_end - aligned_start) as libc::off_t,
+			)
+		};
+		if ret == 0 {
+			fill = vec![(start, aligned_start), (aligned_end, end)];
+		} else {
+			let e = std::io::Error::last_os_error();
+			if e.raw_os_error() != Some(libc::EOPNOTSUPP) {
+				return Err(e).context("Unable to zero the reserved range of the bootloader image");
+			}
+		}
+	}
+	let zeros = vec![0u8; FLASH_BUFFER_SIZE];
+	for (mut pos, end) in fill {
+		dest.seek(SeekFrom::Start(pos))?;
+		while pos < end {
+			let len = (end - pos).min(FLASH_BUFFER_SIZE as u64) as usize;
+			dest.write_all(&zeros[..len])?;
+			pos += len as u64;
+		}
+	}
+	Ok(())
 }
 
 impl ImageContext<'_> {
-	#[allow(unused_variables)]
+	/// Get the path to the bootloader image on the host, downloading it if necessary.
+	fn resolve_payload(
+		&self,
+		path: &Path,
+		source: &PayloadSource,
+		sha256: &Option<String>,
+		rootfs: &Path,
+		device_spec_dir: &Path,
+	) -> Result<PathBuf> {
+		let resolved = match source {
+			// Users want to specify absolute paths. However join()ing with an absolute path replaces the whole path.
+			PayloadSource::Rootfs => rootfs.join(path.strip_prefix("/").unwrap_or(path)),
+			PayloadSource::DeviceDir => {
+				if !is_contained_path(path) {
+					bail!(
+						"Bootloader image '{}' is not within the directory of the device.toml",
+						path.display()
+					);
+				}
+				device_spec_dir.join(path)
+			}
+			PayloadSource::Url => {
+				let sha256 = sha256.as_ref().context(
+					"Bootloader images downloaded from URLs must have a SHA256 checksum",
+				)?;
+				let url = path_str(path)?;
+				let dest = self
+					.workdir
+					.join("downloads")
+					.join(sha256.to_ascii_lowercase());
+				if dest.is_file() && sha256_file(&dest)?.eq_ignore_ascii_case(sha256) {
+					self.info(format!("Using the downloaded bootloader image {}", &url));
+				} else {
+					self.info(format!("Downloading bootloader image {} ...", &url));
+					download_file(url, &dest)?;
+				}
+				dest
+			}
+		};
+		if let Some(sha256) = sha256 {
+			let actual = sha256_file(&resolved)?;
+			if !actual.eq_ignore_ascii_case(sha256) {
+				bail!(
+					"Checksum mismatch for bootloader image '{}':\nExpected: {}\nActual: {}",
+					path.display(),
+					sha256,
+					actual
+				);
+			}
+		}
+		Ok(resolved)
+	}
+
+	/// Make the file in the target system reachable from the partition mounted at `part_root`, by copying it if necessary.
+	///
+	/// Returns the path relative to the root of the partition.
+	fn file_on_partition(&self, file: &Path, part_root: &Path) -> Result<String> {
+		if let Ok(rel) = file.strip_prefix(part_root) {
+			return Ok(format!("/{}", path_str(rel)?));
+		}
+		let filename = file.file_name().context("Invalid file name")?;
+		let dst_dir = part_root.join("aosc-os");
+		fs::create_dir_all(&dst_dir)?;
+		copy_preserving(file, dst_dir.join(filename))?;
+		Ok(format!("/aosc-os/{}", path_str(Path::new(filename))?))
+	}
+
+	/// The mountpoint of the EFI system partition.
+	pub(crate) fn esp_mountpoint(&self) -> Option<&String> {
+		self.device
+			.partitions
+			.iter()
+			.find(|p| p.part_type == PartitionType::EFI)
+			.and_then(|p| p.mountpoint.as_ref())
+	}
+
+	/// The kernel command line in the boot loader entry of systemd-boot.
+	fn bls_options(
+		&self,
+		pm_data: &PartitionMapData,
+		kernel_cmdline: Option<&str>,
+	) -> Result<String> {
+		Ok(match kernel_cmdline {
+			Some(x) => format!("{} {}", self.device.gen_root_param(pm_data)?, x),
+			None if self.device.kernel_cmdline.is_some() => {
+				self.device.gen_kernel_cmdline(pm_data)?
+			}
+			None => format!("{} rw", self.device.gen_root_param(pm_data)?),
+		})
+	}
+
+	/// The `append` line of `extlinux.conf`.
+	fn extlinux_append(&self, pm_data: &PartitionMapData, cmdline: &str) -> Result<String> {
+		let root_part = self
+			.device
+			.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Rootfs)
+			.context("Unable to find a root filesystem")?;
+		let root_data = pm_data
+			.data
+			.get(&root_part.num)
+			.context("Unable to get partition data for the root partition")?;
+		Ok(format!(
+			"root=PARTUUID={} {}",
+			&root_data.part_uuid, cmdline
+		))
+	}
+
+	fn install_systemd_boot(
+		&self,
+		rootfs: &Path,
+		binds: &[&str],
+		pm_data: &PartitionMapData,
+		entry_title: Option<&str>,
+		kernel_cmdline: Option<&str>,
+	) -> Result<()> {
+		let esp_mountpoint = self
+			.esp_mountpoint()
+			.context("systemd-boot requires an EFI system partition with a mountpoint")?;
+		self.info("Installing systemd-boot ...");
+		let script = BootloaderSpec::bootctl_script(esp_mountpoint);
+		run_str_script_with_chroot(&rootfs, &self.machine_name(), &script, binds, None)
+			.context("Failed to install systemd-boot")?;
+		let esp = rootfs.join(esp_mountpoint.trim_start_matches('/'));
+		let (kernel, initrd) = find_kernel_images(&rootfs.join("boot"))?;
+		let linux = self.file_on_partition(&kernel, &esp)?;
+		let initrd = if self.device.initrdless {
+			None
+		} else {
+			let initrd = initrd.context("No init ramdisk found for the kernel")?;
+			Some(self.file_on_partition(&initrd, &esp)?)
+		};
+		let entry = BootloaderSpec::bls_entry(
+			entry_title.unwrap_or("AOSC OS"),
+			&linux,
+			initrd.as_deref(),
+			&self.bls_options(pm_data, kernel_cmdline)?,
+		);
+		let entries_dir = esp.join("loader/entries");
+		fs::create_dir_all(&entries_dir)?;
+		self.info(format!(
+			"Writing boot loader entry for {}",
+			kernel.file_name().unwrap_or_default().to_string_lossy()
+		));
+		fs::write(entries_dir.join("aosc-os.conf"), entry)?;
+		Ok(())
+	}
+
+	fn write_extlinux_conf(
+		&self,
+		rootfs: &Path,
+		pm_data: &PartitionMapData,
+		dir: &str,
+		cmdline: &str,
+		fdt: Option<&str>,
+		fdtdir: Option<&str>,
+	) -> Result<()> {
+		let boot_mountpoint = self
+			.device
+			.boot_files_partition()
+			.and_then(|p| p.mountpoint.as_ref())
+			.context("extlinux requires a firmware or boot partition with a mountpoint")?;
+		let boot_root = rootfs.join(boot_mountpoint.trim_start_matches('/'));
+		let (kernel, initrd) =
+			find_kernel_images(&boot_root).or_else(|_| find_kernel_images(&rootfs.join("boot")))?;
+		let linux = self.file_on_partition(&kernel, &boot_root)?;
+		let initrd = if self.device.initrdless {
+			None
+		} else {
+			let initrd = initrd.context("No init ramdisk found for the kernel")?;
+			Some(self.file_on_partition(&initrd, &boot_root)?)
+		};
+		let append = self.extlinux_append(pm_data, cmdline)?;
+		let conf = BootloaderSpec::extlinux_conf(&linux, initrd.as_deref(), fdt, fdtdir, &append);
+		let conf_dir = boot_root.join(dir.trim_start_matches('/'));
+		fs::create_dir_all(&conf_dir)?;
+		self.info(format!(
+			"Writing {}/{}/extlinux.conf ...",
+			boot_mountpoint.trim_end_matches('/'),
+			dir.trim_matches('/')
+		));
+		fs::write(conf_dir.join("extlinux.conf"), conf)?;
+		Ok(())
+	}
+
+	/// Flash the image at `offset` of the loop device, making sure it fits in `max_size` and does not overwrite any filesystem.
+	fn flash_offset(
+		&self,
+		img: &Path,
+		offset: u64,
+		max_size: Option<u64>,
+		loopdev: &Path,
+	) -> Result<()> {
+		if let Some(max_size) = max_size {
+			let size = fs::metadata(img)?.len();
+			if size > max_size {
+				bail!(
+					"Bootloader image '{}' ({} bytes) exceeds the maximum size of {} bytes",
+					img.display(),
+					size,
+					max_size
+				);
+			}
+		}
+		let limit = self
+			.formatted_partition_starts(loopdev)?
+			.into_iter()
+			.filter(|(_, start)| *start > offset)
+			.min_by_key(|(_, start)| *start);
+		BootloaderSpec::apply_offset(img, offset, max_size, limit, loopdev)
+	}
+
+	/// Run a host command in `sketch_dir`, and return the path to the produced file.
+	fn run_host_command(
+		&self,
+		command: &[String],
+		output: &Path,
+		sketch_dir: &Path,
+		device_spec_dir: &Path,
+		vars: &[(String, String)],
+	) -> Result<PathBuf> {
+		let program = command.first().context("Host command is empty")?;
+		let program_path = find_program(program, device_spec_dir)
+			.context(format!("Program '{}' not found", program))?;
+		self.info(format!("Running host command {:?} ...", command));
+		let output_path = sketch_dir.join(output);
+		if output_path.exists() {
+			fs::remove_file(&output_path)?;
+		}
+		let result = Command::new(&program_path)
+			.args(&command[1..])
+			.current_dir(sketch_dir)
+			.envs(vars.iter().map(|(k, v)| (k, v)))
+			.output()
+			.context(format!("Failed to run {}", program_path.display()))?;
+		for line in String::from_utf8_lossy(&result.stdout)
+			.lines()
+			.chain(String::from_utf8_lossy(&result.stderr).lines())
+		{
+			self.info(format!("{}: {}", program, line));
+		}
+		if !result.status.success() {
+			bail!("Host command {:?} failed: {}", command, result.status);
+		}
+		if !output_path.is_file() {
+			bail!(
+				"Host command {:?} did not produce '{}'",
+				command,
+				output_path.display()
+			);
+		}
+		Ok(output_path)
+	}
+
+	/// Get the actual starting offsets of the partitions containing a filesystem from sysfs.
+	fn formatted_partition_starts(&self, loopdev: &Path) -> Result<Vec<(u32, u64)>> {
+		let loop_name = loopdev.file_name().context("Invalid loop device path")?;
+		let mut starts = Vec::new();
+		for partition in &self.device.partitions {
+			if partition.filesystem == FilesystemType::None {
+				continue;
+			}
+			let sysfs_path = Path::new("/sys/class/block")
+				.join(partition_path(loop_name, partition.num))
+				.join("start");
+			// Always in 512-byte sectors, regardless of the sector size of the device.
+			let start = fs::read_to_string(&sysfs_path)
+				.context(format!("Unable to read {}", sysfs_path.display()))?
+				.trim()
+				.parse::<u64>()?;
+			starts.push((partition.num, sectors_to_bytes(start, 512)?));
+		}
+		Ok(starts)
+	}
+
 	pub fn apply_bootloaders<P: AsRef<Path>>(
 		&self,
 		rootfs: P,
 		loopdev: P,
+		sketch_dir: P,
+		binds: &[&str],
+		pm_data: &PartitionMapData,
+	) -> Result<()> {
+		self.apply_selected_bootloaders(rootfs, loopdev, sketch_dir, binds, pm_data, None)
+	}
+
+	/// Apply the bootloaders at the indices (starting from 1) in `selected`, or all of them if `None`, in the order of the device spec.
+	pub fn apply_selected_bootloaders<P: AsRef<Path>>(
+		&self,
+		rootfs: P,
+		loopdev: P,
+		sketch_dir: P,
 		binds: &[&str],
+		pm_data: &PartitionMapData,
+		selected: Option<&[usize]>,
 	) -> Result<()> {
 		if self.device.bootloaders.is_none() {
 			return Ok(());
@@ -216,24 +1001,517 @@ impl ImageContext<'_> {
 			.file_path
 			.parent()
 			.context("Failed to reach the directory containing the device spec file")?;
-		for bl in *bl_list {
+		for (i, bl) in bl_list.iter().enumerate() {
+			if let Some(selected) = selected
+				&& !selected.contains(&(i + 1))
+			{
+				continue;
+			}
 			match bl {
 				BootloaderSpec::Script { name } => {
-					BootloaderSpec::run_script(rootfs, device_spec_dir.join(name), binds)?;
+					BootloaderSpec::run_script(
+						rootfs,
+						&self.machine_name(),
+						device_spec_dir.join(name),
+						binds,
+					)?;
+				}
+				BootloaderSpec::FlashPartition {
+					path,
+					partition,
+					source,
+					sha256,
+				} => {
+					let img =
+						self.resolve_payload(path, source, sha256, rootfs, device_spec_dir)?;
+					BootloaderSpec::apply_to_partition(img, partition_path(loopdev, *partition))?;
+				}
+				BootloaderSpec::Grub {
+					target,
+					efi_directory,
+					removable,
+				} => {
+					let efi_directory = efi_directory
+						.clone()
+						.or_else(|| self.esp_mountpoint().cloned());
+					self.info(format!("Installing GRUB for {} ...", target));
+					let script = BootloaderSpec::grub_script(
+						target,
+						efi_directory.as_deref(),
+						*removable,
+						loopdev,
+					)?;
+					run_str_script_with_chroot(&rootfs, &self.machine_name(), &script, binds, None)
+						.context("Failed to install GRUB")?;
+				}
+				BootloaderSpec::SystemdBoot {
+					entry_title,
+					kernel_cmdline,
+				} => {
+					self.install_systemd_boot(
+						rootfs,
+						binds,
+						pm_data,
+						entry_title.as_deref(),
+						kernel_cmdline.as_deref(),
+					)?;
 				}
-				BootloaderSpec::FlashPartition { path, partition } => {
-					let partition = format!("{}p{}", &loopdev.to_string_lossy(), partition);
-					BootloaderSpec::apply_to_partition(
-						path.as_path(),
+				BootloaderSpec::Extlinux {
+					dir,
+					cmdline,
+					fdt,
+					fdtdir,
+				} => {
+					self.write_extlinux_conf(
 						rootfs,
-						Path::new(&partition),
+						pm_data,
+						dir,
+						cmdline,
+						fdt.as_deref(),
+						fdtdir.as_deref(),
 					)?;
 				}
-				BootloaderSpec::FlashOffset { path, offset } => {
-					BootloaderSpec::apply_offset(path, *offset, rootfs, loopdev)?;
+				BootloaderSpec::FlashOffset {
+					path,
+					offset,
+					max_size,
+					source,
+					sha256,
+				} => {
+					let img =
+						self.resolve_payload(path, source, sha256, rootfs, device_spec_dir)?;
+					self.flash_offset(&img, *offset, *max_size, loopdev)?;
+				}
+				BootloaderSpec::HostCommand {
+					command,
+					output,
+					flash,
+				} => {
+					let root_num = self
+						.device
+						.partitions
+						.iter()
+						.find(|p| p.usage == PartitionUsage::Rootfs)
+						.context("Unable to find a root filesystem")?
+						.num;
+					let rootpart = partition_path(loopdev, root_num);
+					let vars = self.spec_vars(&loopdev, &rootpart, pm_data)?;
+					let img = self.run_host_command(
+						command,
+						output,
+						sketch_dir.as_ref(),
+						device_spec_dir,
+						&vars,
+					)?;
+					match flash {
+						FlashTarget::Partition { partition } => {
+							BootloaderSpec::apply_to_partition(
+								img,
+								partition_path(loopdev, *partition),
+							)?;
+						}
+						FlashTarget::Offset { offset, max_size } => {
+							self.flash_offset(&img, *offset, *max_size, loopdev)?;
+						}
+					}
 				}
 			}
 		}
 		Ok(())
 	}
+
+	/// Write what [`Self::apply_bootloaders`] would do into `dir` without touching any image, see [`crate::export`].
+	///
+	/// Generated scripts and configuration files are written to `dir`, the command lines are returned.
+	pub fn export_bootloaders(
+		&self,
+		loopdev: &Path,
+		binds: &[&str],
+		pm_data: &PartitionMapData,
+		dir: &Path,
+	) -> Result<Vec<String>> {
+		let Some(bl_list) = &self.device.bootloaders else {
+			return Ok(Vec::new());
+		};
+		let device_spec_dir = self
+			.device
+			.file_path
+			.parent()
+			.context("Failed to reach the directory containing the device spec file")?;
+		let rootfs = Path::new(ROOTFS_PLACEHOLDER);
+		let machine = self.machine_name();
+		let initrd = (!self.device.initrdless).then_some(INITRD_PLACEHOLDER);
+		let mut lines = Vec::new();
+		for (idx, bl) in bl_list.iter().enumerate() {
+			let prefix = format!("{:02}", idx + 1);
+			match bl {
+				BootloaderSpec::Script { name } => {
+					let filename = Path::new(name).file_name().context("Invalid file name")?;
+					fs::copy(
+						device_spec_dir.join(name),
+						dir.join(format!("{}-{}", prefix, path_str(Path::new(filename))?)),
+					)
+					.context(format!("Unable to copy the bootloader script {}", name))?;
+					let cmd = chroot_script_command(
+						rootfs,
+						&machine,
+						&Path::new("/tmp").join(filename),
+						binds,
+						None,
+					)?;
+					lines.push(format!("# {}: script {}", prefix, name));
+					lines.push(format_command(&cmd));
+				}
+				BootloaderSpec::FlashPartition {
+					path,
+					partition,
+					source,
+					..
+				} => {
+					lines.push(format!(
+						"# {}: flash {} ({:?}) to {}",
+						prefix,
+						path.display(),
+						source,
+						partition_path(loopdev, *partition).display()
+					));
+				}
+				BootloaderSpec::FlashOffset {
+					path,
+					offset,
+					max_size,
+					source,
+					..
+				} => {
+					lines.push(format!(
+						"# {}: flash {} ({:?}) to {} at offset {:#x}, at most {} bytes",
+						prefix,
+						path.display(),
+						source,
+						loopdev.display(),
+						offset,
+						max_size.map_or("unlimited".to_owned(), |x| x.to_string())
+					));
+				}
+				BootloaderSpec::HostCommand {
+					command,
+					output,
+					flash,
+				} => {
+					let program = command.first().context("Host command is empty")?;
+					let mut cmd = Command::new(program);
+					cmd.args(&command[1..]);
+					let target = match flash {
+						FlashTarget::Partition { partition } => {
+							partition_path(loopdev, *partition).display().to_string()
+						}
+						FlashTarget::Offset { offset, .. } => {
+							format!("{} at offset {:#x}", loopdev.display(), offset)
+						}
+					};
+					lines.push(format!(
+						"# {}: host command in the sketch directory with the variables of spec.sh exported, {} is flashed to {}",
+						prefix,
+						output.display(),
+						target
+					));
+					lines.push(format_command(&cmd));
+				}
+				BootloaderSpec::Grub {
+					target,
+					efi_directory,
+					removable,
+				} => {
+					let efi_directory = efi_directory
+						.clone()
+						.or_else(|| self.esp_mountpoint().cloned());
+					let script = BootloaderSpec::grub_script(
+						target,
+						efi_directory.as_deref(),
+						*removable,
+						loopdev,
+					)?;
+					fs::write(dir.join(format!("{}-grub.sh", prefix)), &script)?;
+					let cmd = chroot_str_script_command(&rootfs, &machine, &script, binds, None);
+					lines.push(format!("# {}: GRUB for {}", prefix, target));
+					lines.push(format_command(&cmd));
+				}
+				BootloaderSpec::SystemdBoot {
+					entry_title,
+					kernel_cmdline,
+				} => {
+					let esp_mountpoint = self.esp_mountpoint().context(
+						"systemd-boot requires an EFI system partition with a mountpoint",
+					)?;
+					let script = BootloaderSpec::bootctl_script(esp_mountpoint);
+					fs::write(dir.join(format!("{}-systemd-boot.sh", prefix)), &script)?;
+					let entry = BootloaderSpec::bls_entry(
+						entry_title.as_deref().unwrap_or("AOSC OS"),
+						LINUX_PLACEHOLDER,
+						initrd,
+						&self.bls_options(pm_data, kernel_cmdline.as_deref())?,
+					);
+					fs::write(dir.join(format!("{}-aosc-os.conf", prefix)), entry)?;
+					let cmd = chroot_str_script_command(&rootfs, &machine, &script, binds, None);
+					lines.push(format!(
+						"# {}: systemd-boot, the entry is written to {}/loader/entries/aosc-os.conf",
+						prefix,
+						esp_mountpoint.trim_end_matches('/')
+					));
+					lines.push(format_command(&cmd));
+				}
+				BootloaderSpec::Extlinux {
+					dir: conf_dir,
+					cmdline,
+					fdt,
+					fdtdir,
+				} => {
+					let boot_mountpoint = self
+						.device
+						.boot_files_partition()
+						.and_then(|p| p.mountpoint.as_ref())
+						.context(
+							"extlinux requires a firmware or boot partition with a mountpoint",
+						)?;
+					let conf = BootloaderSpec::extlinux_conf(
+						LINUX_PLACEHOLDER,
+						initrd,
+						fdt.as_deref(),
+						fdtdir.as_deref(),
+						&self.extlinux_append(pm_data, cmdline)?,
+					);
+					fs::write(dir.join(format!("{}-extlinux.conf", prefix)), conf)?;
+					lines.push(format!(
+						"# {}: extlinux, written to {}/{}/extlinux.conf",
+						prefix,
+						boot_mountpoint.trim_end_matches('/'),
+						conf_dir.trim_matches('/')
+					));
+				}
+			}
+		}
+		Ok(lines)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::TestDir;
+	use std::fs;
+
+	#[test]
+	fn test_rootfs_writes() -> Result<()> {
+		let spec = |s: &str| toml::from_str::<BootloaderSpec>(s);
+		assert_eq!(
+			spec("type = \"grub\"\ntarget = \"x86_64-efi\"\nefi_directory = \"/efi\"")?
+				.rootfs_writes(),
+			Some(vec!["/boot/grub", "/efi"])
+		);
+		assert_eq!(
+			spec("type = \"systemd_boot\"")?.rootfs_writes(),
+			Some(vec![])
+		);
+		assert_eq!(
+			spec("type = \"script\"\nname = \"apply-bootloader.sh\"")?.rootfs_writes(),
+			None
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_find_kernel_images() -> Result<()> {
+		let dir = TestDir::new("kernel")?;
+		for f in [
+			"vmlinuz-6.9.12-aosc-main",
+			"vmlinuz-6.12.8-aosc-main",
+			"initramfs-6.12.8-aosc-main.img",
+			"initramfs-6.9.12-aosc-main.img",
+			"config-6.12.8-aosc-main",
+		] {
+			fs::write(dir.join(f), b"")?;
+		}
+		let (kernel, initrd) = find_kernel_images(&dir)?;
+		assert_eq!(kernel, dir.join("vmlinuz-6.12.8-aosc-main"));
+		assert_eq!(initrd, Some(dir.join("initramfs-6.12.8-aosc-main.img")));
+		fs::remove_file(dir.join("initramfs-6.12.8-aosc-main.img"))?;
+		let (_, initrd) = find_kernel_images(&dir)?;
+		assert_eq!(initrd, None);
+		Ok(())
+	}
+
+	#[test]
+	fn test_extlinux_conf() {
+		let conf = BootloaderSpec::extlinux_conf(
+			"/vmlinuz-6.12.8",
+			Some("/initramfs-6.12.8.img"),
+			None,
+			Some("/dtbs"),
+			"root=PARTUUID=1234 rw",
+		);
+		assert!(conf.contains("\tlinux /vmlinuz-6.12.8\n"));
+		assert!(conf.contains("\tinitrd /initramfs-6.12.8.img\n"));
+		assert!(conf.contains("\tfdtdir /dtbs\n"));
+		assert!(!conf.contains("\tfdt "));
+		assert!(conf.ends_with("\tappend root=PARTUUID=1234 rw\n"));
+		let conf = BootloaderSpec::extlinux_conf("/vmlinuz", None, Some("/a.dtb"), None, "rw");
+		assert!(!conf.contains("initrd"));
+		assert!(conf.contains("\tfdt /a.dtb\n"));
+	}
+
+	#[test]
+	fn test_bls_entry() {
+		assert_eq!(
+			BootloaderSpec::bls_entry(
+				"AOSC OS",
+				"/vmlinuz-6.12.8",
+				Some("/initramfs-6.12.8.img"),
+				"root=UUID=1234 rw"
+			),
+			"title AOSC OS\nlinux /vmlinuz-6.12.8\ninitrd /initramfs-6.12.8.img\noptions root=UUID=1234 rw\n"
+		);
+		assert!(!BootloaderSpec::bls_entry("AOSC OS", "/vmlinuz", None, "rw").contains("initrd"));
+	}
+
+	#[test]
+	fn test_grub_script() -> Result<()> {
+		let loopdev = Path::new("/dev/loop0");
+		let script = BootloaderSpec::grub_script("x86_64-efi", Some("/efi"), true, loopdev)?;
+		assert!(script.contains(
+			"grub-install --target='x86_64-efi' --efi-directory='/efi' --no-nvram --removable\n"
+		));
+		assert!(script.contains("grub-mkconfig -o /boot/grub/grub.cfg"));
+		let script = BootloaderSpec::grub_script("i386-pc", None, false, loopdev)?;
+		assert!(script.contains("grub-install --target='i386-pc' '/dev/loop0'\n"));
+		assert!(BootloaderSpec::grub_script("arm64-efi", None, false, loopdev).is_err());
+		assert!(BootloaderSpec::grub_script("x86_64-pc", None, false, loopdev).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_payload_source() -> Result<()> {
+		let bl: BootloaderSpec = toml::from_str(
+			"type = \"flash_partition\"\npath = \"/boot/u-boot.bin\"\npartition = 1",
+		)?;
+		assert!(matches!(
+			bl,
+			BootloaderSpec::FlashPartition {
+				source: PayloadSource::Rootfs,
+				sha256: None,
+				..
+			}
+		));
+		let bl: BootloaderSpec = toml::from_str(
+			"type = \"flash_offset\"\nsource = \"url\"\npath = \"https://example.com/a.img\"\nsha256 = \"00\"\noffset = 0x8000",
+		)?;
+		assert!(matches!(
+			bl,
+			BootloaderSpec::FlashOffset {
+				source: PayloadSource::Url,
+				sha256: Some(_),
+				..
+			}
+		));
+		Ok(())
+	}
+
+	#[test]
+	fn test_apply_offset_limit() -> Result<()> {
+		let dir = TestDir::new("bl-ofs")?;
+		fs::create_dir_all(dir.join("boot"))?;
+		let image = dir.join("disk.img");
+		fs::write(&image, vec![0u8; 16384])?;
+		fs::write(dir.join("boot/idbloader.img"), vec![1u8; 4096])?;
+		let img = dir.join("boot/idbloader.img");
+		BootloaderSpec::apply_offset(&img, 4096, None, Some((1, 8192)), &image)?;
+		assert!(
+			BootloaderSpec::apply_offset(&img, 6144, None, Some((1, 8192)), &image)
+				.is_err_and(|e| e.to_string().contains("partition 1"))
+		);
+		let content = fs::read(&image)?;
+		assert!(content[4096..8192].iter().all(|x| *x == 1));
+		assert!(content[8192..].iter().all(|x| *x == 0));
+		Ok(())
+	}
+
+	/// The original implementation of [`BootloaderSpec::apply_offset`], writing 512 bytes at a time.
+	fn apply_offset_unbuffered(img: &Path, offset: u64, dev: &Path) -> Result<()> {
+		let mut dev = File::options().write(true).open(dev)?;
+		dev.seek(SeekFrom::Start(offset))?;
+		let mut rdr = std::io::BufReader::with_capacity(512, File::open(img)?);
+		std::io::copy(&mut rdr, &mut dev)?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_apply_offset_output() -> Result<()> {
+		let dir = TestDir::new("bl-out")?;
+		// Stale bytes from a previous build
+		let stale = vec![0xaau8; 8 * 1048576];
+		// Not a multiple of the sector size
+		let mut seed = 1u32;
+		let blob: Vec<u8> = (0..3 * 1048576 + 123)
+			.map(|_| {
+				seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+				(seed >> 16) as u8
+			})
+			.collect();
+		let img = dir.join("u-boot.itb");
+		fs::write(&img, &blob)?;
+		let (old, new) = (dir.join("old.img"), dir.join("new.img"));
+		for offset in [0x8000, 0x8000 + 17] {
+			fs::write(&old, &stale)?;
+			fs::write(&new, &stale)?;
+			let t = std::time::Instant::now();
+			apply_offset_unbuffered(&img, offset, &old)?;
+			let t_old = t.elapsed();
+			let t = std::time::Instant::now();
+			BootloaderSpec::apply_offset(&img, offset, None, None, &new)?;
+			let t_new = t.elapsed();
+			println!(
+				"Offset {:#x}: {:?} with 512-byte writes, {:?} with coalesced writes and fsync",
+				offset, t_old, t_new
+			);
+			let expected = fs::read(&old)?;
+			assert!(fs::read(&new)? == expected);
+			// The rest of the reserved gap is zeroed, up to the next partition.
+			let end = offset as usize + blob.len();
+			let limit = offset as usize + 4 * 1048576;
+			BootloaderSpec::apply_offset(
+				&img,
+				offset,
+				Some(5 * 1048576),
+				Some((1, limit as u64)),
+				&new,
+			)?;
+			let content = fs::read(&new)?;
+			assert_eq!(content.len(), stale.len());
+			assert!(content[..end] == expected[..end]);
+			assert!(content[end..limit].iter().all(|x| *x == 0));
+			assert!(content[limit..].iter().all(|x| *x == 0xaa));
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_apply_to_partition_size() -> Result<()> {
+		let dir = TestDir::new("bl")?;
+		fs::create_dir_all(dir.join("boot"))?;
+		let partition = dir.join("partition.img");
+		fs::write(&partition, vec![0u8; 4096])?;
+		fs::write(dir.join("boot/small.bin"), vec![1u8; 1024])?;
+		fs::write(dir.join("boot/large.bin"), vec![2u8; 8192])?;
+		BootloaderSpec::apply_to_partition(dir.join("boot/small.bin"), &partition)?;
+		let content = fs::read(&partition)?;
+		assert_eq!(content.len(), 4096);
+		assert!(content[..1024].iter().all(|x| *x == 1));
+		assert!(
+			BootloaderSpec::apply_to_partition(dir.join("boot/large.bin"), &partition).is_err()
+		);
+		// Nothing should be written if the image does not fit.
+		let content = fs::read(&partition)?;
+		assert_eq!(content.len(), 4096);
+		assert!(content[1024..].iter().all(|x| *x == 0));
+		Ok(())
+	}
 }
@@ -0,0 +1,275 @@
+//! Module handling the provenance of the bootstrapped distributions.
+//!
+//! The SHA-256 of every aoscbootstrap config, recipe and script file passed to aoscbootstrap is recorded in [`BOOTSTRAP_HASHES_PATH`] of the distribution, which is copied into the images along with it.
+//! They are also recorded for each build in the build report of the output directory.
+//!
+//! Builds can be pinned to known hashes with `--pin-bootstrap-hashes`. The pin file is in the format of `sha256sum`, and can be generated by the `pin-bootstrap` action.
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use walkdir::WalkDir;
+
+use crate::{context::ImageVariant, utils::sha256_file};
+
+/// Directory of the aoscbootstrap configs, recipes and scripts.
+pub const AB_DIR: &str = "/usr/share/aoscbootstrap";
+/// Path to the recorded hashes, relative to the root of the distribution.
+pub const BOOTSTRAP_HASHES_PATH: &str = "etc/mkrawimg/bootstrap-hashes.json";
+
+/// SHA-256 of the files, keyed by their paths.
+pub type BootstrapHashes = BTreeMap<String, String>;
+
+/// The files passed to aoscbootstrap.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BootstrapInputs {
+	pub config: PathBuf,
+	/// Scripts passed with `-s`, in order.
+	pub scripts: Vec<PathBuf>,
+	/// The recipe passed with `--include-files`.
+	pub recipe: PathBuf,
+}
+
+impl BootstrapInputs {
+	/// The files used to bootstrap `variant` from the aoscbootstrap files in `ab_dir`.
+	///
+	/// `reset_repo` adds the script resetting the sources, it is skipped with a custom `sources.list`. A custom `recipe_list` replaces the recipe of the variant.
+	pub fn new(
+		ab_dir: &Path,
+		variant: &ImageVariant,
+		reset_repo: bool,
+		recipe_list: Option<&Path>,
+	) -> Self {
+		let mut scripts = Vec::new();
+		if reset_repo {
+			scripts.push(ab_dir.join("scripts/reset-repo.sh"));
+		}
+		scripts.push(ab_dir.join("scripts/enable-dkms.sh"));
+		let recipe = match recipe_list {
+			Some(x) => x.to_owned(),
+			None => ab_dir.join(format!(
+				"recipes/mainline/{}-common.lst",
+				match variant {
+					ImageVariant::Desktop => "kde".to_owned(),
+					_ => variant.to_string().to_lowercase(),
+				}
+			)),
+		};
+		BootstrapInputs {
+			config: ab_dir.join("config/aosc-mainline.toml"),
+			scripts,
+			recipe,
+		}
+	}
+
+	/// SHA-256 of all files.
+	pub fn hashes(&self) -> Result<BootstrapHashes> {
+		hash_files(
+			std::iter::once(&self.config)
+				.chain(&self.scripts)
+				.chain(std::iter::once(&self.recipe)),
+		)
+	}
+}
+
+/// SHA-256 of `files`, keyed by their paths as given.
+pub fn hash_files<I, P>(files: I) -> Result<BootstrapHashes>
+where
+	I: IntoIterator<Item = P>,
+	P: AsRef<Path>,
+{
+	files
+		.into_iter()
+		.map(|p| {
+			let p = p.as_ref();
+			Ok((p.to_string_lossy().into_owned(), sha256_file(p)?))
+		})
+		.collect()
+}
+
+/// SHA-256 of all files in `dir` and `extra`, as the pins of the current system.
+///
+/// Paths in `extra` are made absolute.
+pub fn system_pins<P: AsRef<Path>>(dir: &Path, extra: &[P]) -> Result<BootstrapHashes> {
+	let mut files = Vec::new();
+	for entry in WalkDir::new(dir).sort_by_file_name() {
+		let entry = entry.context(format!("Unable to read {}", dir.display()))?;
+		if entry.file_type().is_file() {
+			files.push(entry.into_path());
+		}
+	}
+	// The recipe lists of the devices are passed with absolute paths.
+	for p in extra {
+		files.push(std::path::absolute(p.as_ref())?);
+	}
+	hash_files(files)
+}
+
+/// Format `hashes` like `sha256sum`.
+pub fn format_pins(hashes: &BootstrapHashes) -> String {
+	hashes
+		.iter()
+		.map(|(path, hash)| format!("{}  {}\n", hash, path))
+		.collect()
+}
+
+/// Parse the pins in the format of `sha256sum`. Empty lines and lines starting with `#` are ignored.
+pub fn parse_pins(content: &str) -> Result<BootstrapHashes> {
+	let mut pins = BootstrapHashes::new();
+	for (i, line) in content.lines().enumerate() {
+		if line.trim().is_empty() || line.starts_with('#') {
+			continue;
+		}
+		// The path follows a space and a space (text mode) or '*' (binary mode).
+		let (hash, path) = line
+			.split_once(' ')
+			.and_then(|(hash, rest)| Some((hash, rest.strip_prefix([' ', '*'])?)))
+			.context(format!("Line {}: expected '<SHA-256>  <path>'", i + 1))?;
+		if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+			bail!("Line {}: invalid SHA-256 '{}'", i + 1, hash);
+		}
+		if pins
+			.insert(path.to_owned(), hash.to_ascii_lowercase())
+			.is_some()
+		{
+			bail!("Line {}: '{}' is pinned more than once", i + 1, path);
+		}
+	}
+	Ok(pins)
+}
+
+/// Load the pin file at `path`.
+pub fn load_pins(path: &Path) -> Result<BootstrapHashes> {
+	let content = fs::read_to_string(path)
+		.context(format!("Unable to read the pin file {}", path.display()))?;
+	parse_pins(&content).context(format!("Invalid pin file {}", path.display()))
+}
+
+/// Make sure every file in `recorded` is pinned with the same hash in `pins`.
+///
+/// Pinned files which are not used are fine, as the pins usually cover all files of aoscbootstrap.
+pub fn check_pins(recorded: &BootstrapHashes, pins: &BootstrapHashes) -> Result<()> {
+	let mut problems = Vec::new();
+	for (path, hash) in recorded {
+		match pins.get(path) {
+			None => problems.push(format!("{} is not pinned", path)),
+			Some(pinned) if pinned != hash => {
+				problems.push(format!("{} has SHA-256 {}, pinned {}", path, hash, pinned))
+			}
+			_ => (),
+		}
+	}
+	if !problems.is_empty() {
+		bail!(
+			"The aoscbootstrap files do not match the pinned hashes:\n{}",
+			problems.join("\n")
+		);
+	}
+	Ok(())
+}
+
+/// Save the hashes of the files used to bootstrap the distribution at `rootdir`.
+pub fn save_hashes(rootdir: &Path, hashes: &BootstrapHashes) -> Result<()> {
+	let path = rootdir.join(BOOTSTRAP_HASHES_PATH);
+	fs::create_dir_all(path.parent().unwrap())?;
+	fs::write(&path, serde_json::to_string_pretty(hashes)? + "\n")
+		.context(format!("Unable to write {}", path.display()))
+}
+
+/// Load the hashes recorded in the distribution at `rootdir`, if any.
+///
+/// Distributions bootstrapped by an older version of mkrawimg have none.
+pub fn load_hashes(rootdir: &Path) -> Result<Option<BootstrapHashes>> {
+	let path = rootdir.join(BOOTSTRAP_HASHES_PATH);
+	let content = match fs::read_to_string(&path) {
+		Ok(x) => x,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e).context(format!("Unable to read {}", path.display())),
+	};
+	Ok(Some(
+		serde_json::from_str(&content).context(format!("Unable to parse {}", path.display()))?,
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::{TestDir, assert_golden};
+
+	/// Fake aoscbootstrap files, relative to the root of the crate.
+	const FIXTURE_AB_DIR: &str = "tests/fixtures/aoscbootstrap";
+
+	#[test]
+	fn test_bootstrap_inputs() -> Result<()> {
+		let dir = Path::new(FIXTURE_AB_DIR);
+		let inputs = BootstrapInputs::new(dir, &ImageVariant::Desktop, true, None);
+		assert_eq!(inputs.recipe, dir.join("recipes/mainline/kde-common.lst"));
+		assert_eq!(inputs.scripts.len(), 2);
+		let hashes = inputs.hashes()?;
+		assert_eq!(hashes.len(), 4);
+		assert_eq!(
+			hashes[&format!("{}/scripts/enable-dkms.sh", FIXTURE_AB_DIR)],
+			sha256_file(dir.join("scripts/enable-dkms.sh"))?
+		);
+		// Custom sources and recipe
+		let recipe = dir.join("recipes/mainline/base-common.lst");
+		let inputs = BootstrapInputs::new(dir, &ImageVariant::Server, false, Some(&recipe));
+		assert_eq!(inputs.scripts, vec![dir.join("scripts/enable-dkms.sh")]);
+		assert_eq!(inputs.recipe, recipe);
+		// Missing files are errors
+		let inputs = BootstrapInputs::new(dir, &ImageVariant::Server, true, None);
+		assert!(inputs.hashes().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_pins() -> Result<()> {
+		let dir = Path::new(FIXTURE_AB_DIR);
+		let pins = system_pins::<&Path>(dir, &[])?;
+		assert_golden("bootstrap-pins.txt", &format_pins(&pins))?;
+		assert_eq!(parse_pins(&format_pins(&pins))?, pins);
+		let recorded = BootstrapInputs::new(dir, &ImageVariant::Base, true, None).hashes()?;
+		check_pins(&recorded, &pins)?;
+		// Changed file
+		let mut changed = pins.clone();
+		let config = format!("{}/config/aosc-mainline.toml", FIXTURE_AB_DIR);
+		changed.insert(config.clone(), "0".repeat(64));
+		let err = check_pins(&recorded, &changed).unwrap_err().to_string();
+		assert!(err.contains(&format!("{} has SHA-256", config)), "{}", err);
+		// Missing file
+		changed.remove(&config);
+		let err = check_pins(&recorded, &changed).unwrap_err().to_string();
+		assert!(
+			err.contains(&format!("{} is not pinned", config)),
+			"{}",
+			err
+		);
+		// Unused pins are fine
+		check_pins(&BootstrapHashes::new(), &pins)?;
+		// Syntax
+		let hash = "A".repeat(64);
+		assert_eq!(
+			parse_pins(&format!("# comment\n\n{} */a b\n", hash))?,
+			BootstrapHashes::from([("/a b".to_owned(), "a".repeat(64))])
+		);
+		assert!(parse_pins("abc  /a\n").is_err());
+		assert!(parse_pins(&format!("{}/a\n", hash)).is_err());
+		assert!(parse_pins(&format!("{}  /a\n{}  /a\n", hash, hash)).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_recorded_hashes() -> Result<()> {
+		let dir = TestDir::new("bootstrap")?;
+		assert_eq!(load_hashes(&dir)?, None);
+		let hashes =
+			BootstrapInputs::new(Path::new(FIXTURE_AB_DIR), &ImageVariant::Base, true, None)
+				.hashes()?;
+		save_hashes(&dir, &hashes)?;
+		assert_eq!(load_hashes(&dir)?, Some(hashes));
+		Ok(())
+	}
+}
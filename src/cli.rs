@@ -13,6 +13,11 @@
 //!
 //! - `pretty`: table format which contains basic information.
 //! - `simple`: simple column-based format splitted by tab character (`'\t'`).
+//! - `json`: a JSON array of the devices.
+//!
+//! Add `--tag TAG` to list only the devices having the tag (or any of the tags, if specified more than once).
+//! Add `--compatible STR` to list only the device having the compatible string `STR`, e.g. the first string of `/proc/device-tree/compatible`.
+//! Add `--show-sizes` to show the image sizes of each variant, and the sizes of the latest builds in the output directory.
 //!
 //! ### Build images for one specific device
 //!
@@ -29,6 +34,7 @@
 //! - `DEVICE`: A string identifying the target device, can be one of the following:
 //!   - Device ID (defined in `device.toml`).
 //!   - Device alias (defined in `device.toml`).
+//!   - The compatible string of the device tree (defined in `device.toml`).
 //!   - The path to the `device.toml` file.
 //!
 //! ### Build Images for All Devices (in the registry)
@@ -41,18 +47,50 @@
 //! # ./target/release/mkrawimg build-all --variants VARIANTS
 //! ```
 //!
+//! ### Compress an existing raw image
+//!
+//! ```shell
+//! $ ./target/release/mkrawimg compress -x zstd -- INPUT [OUTPUT]
+//! ```
+//!
 //! ### Check validity of the device specification files
 //!
 //! ```shell
 //! $ ./target/release/mkrawimg check
 //! ```
 //!
+//! Unknown fields, usually typos, are warned about when loading the device specification files. Pass `--strict` to `check` to treat them as errors.
+//!
 //! For the advanced usage, please go to [`Cmdline`].
 use std::{path::PathBuf, vec};
 
+use anyhow::{Context, Result, bail};
 use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+use crate::{
+	config::Config,
+	context::ImageVariant,
+	device::{DeviceArch, PartitionMapType},
+	filename::FilenameTemplate,
+	gc::RetentionPolicy,
+	patch::PatchStep,
+	report::USAGE_WARN_THRESHOLD,
+	user::RootPolicy,
+	utils::{LOOP_ATTACH_ATTEMPTS, UserOptions, prompt_password, read_password_file},
+	validate::{validate_password, validate_user_options, validate_username},
+};
 
-use crate::context::ImageVariant;
+/// Default working directory.
+pub const DEFAULT_WORKDIR: &str = "./work";
+/// Default output directory.
+pub const DEFAULT_OUTDIR: &str = "./out";
+/// Default package repository mirror.
+pub const DEFAULT_MIRROR: &str = "https://repo.aosc.io/debs";
+/// Default username of the built-in user.
+pub const DEFAULT_USER: &str = "aosc";
+/// Default password of the built-in user.
+pub const DEFAULT_PASSWORD: &str = "anthon";
 
 /// Overrides the filesystem type of the root filesystem.
 ///
@@ -89,11 +127,32 @@ pub enum Compression {
 	None,
 }
 
-#[derive(Clone, ValueEnum)]
+/// Output format of the build actions.
+#[derive(Copy, Debug, Clone, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+	/// A partitioned raw image.
+	Rawimg,
+	/// A tarball of the root filesystem.
+	Tarball,
+	/// An Android sparse image converted from the raw image, e.g. for `fastboot flash`.
+	Simg,
+}
+
+/// Layout of the output directory.
+#[derive(Copy, Debug, Clone, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputLayout {
+	/// `os-<arch>/<variant>/<rawimg|rootfs|simg>/<vendor>/`, following the directory hierarchy of AOSC OS releases.
+	Hierarchy,
+	/// All images in the output directory itself.
+	Flat,
+}
+
+#[derive(Clone, Debug, ValueEnum)]
 pub enum ListFormat {
 	Pretty,
 	Simple,
-	// Json,
+	Json,
 }
 
 /// Command line usage
@@ -109,22 +168,63 @@ pub enum ListFormat {
 /// ==============
 ///
 /// - `--debug`: Enables the debug output. Does not have a short option.
+/// - `--config` `PATH`: Read the defaults of the global options below from the TOML file at `PATH`, instead of the first one found of `./mkrawimg.toml` and `/etc/mkrawimg/config.toml`.
+///   The fields have the names of the options in snake case (e.g. `log_dir = "/var/log/mkrawimg"` for `--log-dir`), see `mkrawimg.example.toml` in the source tree. Options given on the command line take precedence over the config file, relative paths in the config file are relative to the directory containing it.
 /// - `-r`, `--registry`: Overrides the path to the [device registry].
 /// - `-D`, `--workdir`: Overrides the working directory path. The default path is `./work`.
 /// - `-O`, `--outdir`: Overrides the output directory path. The default path is `./out`.
-/// - `-m`, `--mirror`: Overrides the package repository mirror for package downloads. The default mirror is the AOSC OS upstream mirror.
-/// - `-U`, `--user`: Overrides the username of the built-in user. The default username is `aosc`.
-/// - `-P`, `--password`: Overrides the password of the built-in user. The default password is `anthon`.
-/// - `-c`, `--cleanup`: Clean up the sketch directories after building, to free some space.
+/// - `-m`, `--mirror`: Overrides the package repository mirror for package downloads. The default mirror is the AOSC OS upstream mirror. It also takes the place of `{mirror}` in the extra package sources of the devices.
+/// - `-U`, `--user`: Overrides the username of the built-in user. The default username is `aosc`. It must start with a lowercase letter or `_`, followed by lowercase letters, digits, `_` and `-`, up to 32 characters, as required by useradd.
+/// - `-P`, `--password`: Overrides the password of the built-in user. The default password is `anthon`, which is warned about when building images.
+///   The password can not contain `:` or newlines. It can also be a crypt(3) hash, starting with `$6$` (SHA-512) or `$y$` (yescrypt), e.g. from `mkpasswd`, which is set as is.
+///   As the password is visible to other users of the build host in the process list, prefer one of the following options. Only one of the three can be given.
+/// - `--password-file` `PATH`: Read the password of the built-in user (or its hash) from the file at `PATH`, without the trailing newline.
+/// - `--password-prompt`: Prompt for the password of the built-in user, without echoing it. Requires stdin to be a terminal.
+/// - `--root-password-hash` `HASH`: Set the password of root to the crypt(3) hash `HASH` (starting with `$6$` or `$y$`, e.g. from `mkpasswd`) to allow root to log in, unless refused by `allow_root_login = false` of the device. Images with an embedded root password are marked as such in the build report.
+/// - `--lock-root`: Lock the password of root, which is the default. Overrides `root_password_hash` of the config file.
+/// - `--user-groups` `GROUPS`, `--user-shell` `PATH`, `--user-uid` `UID`: Override `user_groups` (comma-separated), `user_shell` and `user_uid` of the devices for the built-in user, see the [device specification file](crate::device::DeviceSpec).
+/// - `-c`, `--cleanup`: Clean up the sketch directories after building, to free some space. Every run builds in sketch directories of its own, e.g. `sketches/rpi-5b-Base-1a2b3c4d` in the working directory, and `sketches/latest` links to the one of the image being built.
 /// - `-C`, `--cleanup-bootstrap`: Clean up the bootstrapped system distributions after building, to free some space.
+/// - `--cleanup-on-success-only`: Skip `--cleanup` and `--cleanup-bootstrap` if any image failed to build (with `--keep-going`), keeping the sketch directories and the distributions for inspection.
+/// - `--force-detach`: Detach the loop devices still attached to raw images in the sketch directories before removing them (by `--cleanup`, the `gc` action or a build starting over in the sketch directory found by `--resume`). They are skipped with a warning otherwise.
+/// - `--force-clean-stale`: Unmount the filesystems and detach the loop devices left behind in the sketch directories of previous builds, e.g. killed ones. Builds refuse to start if there are any otherwise, except in the sketch directories resumed with `--resume`. The content of the sketch directories is kept.
+/// - `--wait-for-lock`: Wait for another build or garbage collection using the working directory to finish, instead of failing right away. Builds lock the working directory with `.mkrawimg.lock` in it, which records the ID of the process holding it.
+/// - `--min-free-inodes` `COUNT`: Fail the build if an ext4 or XFS partition has less than `COUNT` free inodes after the packages are installed. The inode usage of these partitions is always logged.
+/// - `--usage-warn-threshold` `PERCENT`: Warn about the partitions of a raw image using more than `PERCENT` of their space (90 by default) once the image is built. The usage of the partitions is always logged and recorded in the build report.
+/// - `--usage-fail-threshold` `PERCENT`: Fail the build if a partition of a raw image uses more than `PERCENT` of its space.
+/// - `--loop-attempts` `N`: Attach the raw image to a loop device up to `N` times (5 by default), if another program takes the free loop device before us.
+/// - `--log-dir` `DIR`: Write the build log of each image to `DIR/<name of the sketch directory>.log`, instead of `build.log` in its sketch directory (which is removed by `--cleanup`), e.g. for CI to collect them. The build log records the output of the commands run in the containers, which is still printed to the console.
+/// - `--output-layout` `LAYOUT`: Layout of the output directory, `hierarchy` (the default) places the images in `os-<arch>/<variant>/<rawimg|rootfs|simg>/<vendor>/` like the AOSC OS releases, `flat` places them in the output directory itself. The `SHA256SUMS` files are always next to the images. Images with the same path are refused before anything is built, e.g. two devices of a filename template without `{id}`.
+/// - `--filename-template` `TEMPLATE`: Name the output files after `TEMPLATE` instead of `aosc-os_{variant}_{format}_{vendor}_{id}_{date}{revision}_{arch}.{ext}{compress_ext}`. Possible placeholders: `{variant}`, `{format}` (`rawimg`, `rootfs` or `simg`), `{vendor}`, `{id}`, `{alias0}` (the first alias, or the ID), `{date}`, `{revision}` (e.g. `.1`, or empty), `{arch}`, `{ext}` (`img`, `tar` or `simg`) and `{compress_ext}` (e.g. `.xz`, or empty). Unknown placeholders and `/` are rejected.
+/// - `--timings-json` `PATH`: Write the timings of a build run to `PATH` in JSON: the time spent bootstrapping each distribution, and the stages of each image. They are always summarized at the end of the build run.
+/// - `--preserve-env`: When run with sudo, import `http_proxy`, `https_proxy`, `no_proxy` and `RSYNC_PROXY` from the environment of the invoking user, and put the caches into the cache directory of the invoking user (`XDG_CACHE_HOME`, or `~/.cache`) instead of root's. No other variables are imported.
+///   Variables already set in the environment of mkrawimg (e.g. with `sudo -E`, or `sudo http_proxy=... mkrawimg`) take precedence over the imported ones. mkrawimg has no proxy options of its own, neither on the command line nor in the config file, so these are the only settings: a proxy given explicitly for the command always wins over the one of the invoking user.
+/// - `-k`, `--keep-going`: Skip images which can not be built (e.g. executing programs of an architecture without binfmt_misc support) instead of aborting the entire run, and continue with the next image if one fails to build. The filesystems and the loop device of a failed image are released, its raw image is kept for `--resume`. The skipped and failed images are listed with the reasons in the table printed at the end of the run. Failures and skips are also recorded in `--timings-json`, under `failures` and `skipped`.
+/// - `--pin-bootstrap-hashes` `FILE`: Fail the build if the aoscbootstrap config, recipe and script files used to bootstrap the distributions do not match the SHA-256 pinned in `FILE`, or are not pinned at all. `FILE` is in the format of `sha256sum`, see the `pin-bootstrap` action.
+///   The files are checked before bootstrapping, and the hashes recorded while bootstrapping are checked for the distributions already bootstrapped in the working directory.
+///   Either way, the hashes are recorded in `/etc/mkrawimg/bootstrap-hashes.json` of the distribution (thus the images), and in `build-report.json` of the output directory for each build.
+/// - `--lenient-scan`: Skip the device specs which can not be read (with a warning) when assembling the [device registry], instead of aborting, e.g. to build a device while another one is broken. The selected device must still be found, and conflicting device IDs or aliases are still fatal. This is always the case for the `list` action.
+/// - `--no-color`: Disables colored output. Colors are also disabled if the `NO_COLOR` environment variable is set, or stderr is not a terminal.
+/// - `--no-progress`: Disables the progress bar at the bottom of the terminal. The progress bar is also disabled if stderr is not a terminal.
+/// - `--gc-max-size` `MIB`, `--gc-max-age` `DAYS`, `--gc-keep-bootstraps` `N`: The retention policy of the working directory, see the `gc` action.
+/// - `--gc-before-build`: Enforce the retention policy at the start of each build. Another build can not be using the working directory meanwhile, as builds lock it exclusively.
 ///
 /// Actions
 /// =======
 ///
 /// - `build`: Build images for one specific device.
 /// - `build-all`: Build images for all devices registered in the registry.
+/// - `rebootload`: Apply the bootloaders to an existing raw image again.
+/// - `patch`: Run a few steps of the build on an existing image, saving it as the next revision.
+/// - `compress`: Compress an existing raw image.
 /// - `check`: Check the validity of the device specification files.
+/// - `export-scripts`: Write the scripts and configuration files a build would generate, without building.
+/// - `estimate`: Estimate the disk space, memory and time needed to build images.
 /// - `list`: List all of the devices registered in the registry.
+/// - `show`: Show the device specification of one device, with everything it inherits merged.
+/// - `new-device`: Create the directory of a new device in the registry, with a device specification to start from.
+/// - `gc`: Remove old items from the working directory.
+/// - `pin-bootstrap`: Write a pin file of the aoscbootstrap files on this system, for `--pin-bootstrap-hashes`.
 ///
 /// Notes
 /// -----
@@ -141,6 +241,9 @@ pub enum ListFormat {
 /// ./target/release/mkrawimg [GLOBAL_OPTIONS] build [OPTIONS] [--] DEVICE
 /// ```
 ///
+/// At the end of the run, a table lists every image of the run with its status (`ok`, `failed` or `skipped`), output, size and duration, followed by the counts of each status. Failed images refer to their build logs.
+/// The same is recorded as `last_run` in `build-report.json` of the output directory. mkrawimg exits with a non-zero status if any image failed or is skipped.
+///
 /// Options for `build`
 /// -------------------
 ///
@@ -166,6 +269,8 @@ pub enum ListFormat {
 ///
 ///   Use a positive integer as the revision of the image. The revision will be added to the filename of the output.
 ///
+///   All images of a run share the same date in their filenames, which is the UTC date when the run started, so a run crossing midnight does not produce mixed dates. The ID of the run is printed at the start and the end of the run.
+///
 /// - `-p`, `--additional-packages` `PKG [PKG...]`
 ///
 ///   Supply a list of package names to install into the target system. This does not override the defined list.
@@ -174,6 +279,76 @@ pub enum ListFormat {
 ///
 ///   Enroll addition topic(s) during installation.
 ///
+/// - `--override-spec` `PATH`
+///
+///   Merge a partial device specification file on top of the target device's specification, e.g. to try out a different kernel package or partition size without editing the registry.
+///
+///   Tables are merged recursively, partitions are matched by `num`, lists like `bsp_packages` are appended to the original ones as with `inherits` (unless `<name>_replace = true` is set), and other values replace the original ones. The identity fields (`id`, `vendor`) can not be changed. Only available for the `build` action.
+///
+///   The override is recorded in the target system as `/etc/mkrawimg/spec-override.toml`.
+///
+/// - `--defer-triggers`
+///
+///   Do not process package triggers during the build, and process them on the first boot instead. Same as `defer_triggers = true` in the device specification, refused for `initrdless` devices.
+///
+/// - `--skip-size-check`
+///
+///   Before the distribution is installed into a raw image, its size plus an allowance for each package to be installed is compared with the free space of the root filesystem, and the build fails if it does not fit.
+///   Skip this check if the estimate is wrong, e.g. for a distribution with many hard links.
+///
+/// - `--shrink`
+///
+///   Shrink the root filesystem of raw images to the used size plus a margin, and truncate the images behind it, so they are smaller once decompressed.
+///   The root filesystem must be ext4 or Btrfs, and the root partition must be the last one with `size_in_sectors = 0`. It is expanded to the size of the medium during the first boot as usual.
+///
+/// - `--no-bmap`
+///
+///   Do not generate the block maps of raw images, which are saved as `<name of the image>.bmap` next to them for `bmaptool copy`.
+///
+/// - `--keep-raw`
+///
+///   Also save the uncompressed image in the output directory, named like the output without the extension of the compression (e.g. `aosc-os_base_rawimg_..._amd64.img` next to `aosc-os_base_rawimg_..._amd64.img.xz`), for debugging or flashing it right away.
+///   It is hard-linked from the sketch directory if possible, and copied otherwise, keeping the holes. Sparse images and tarballs are kept likewise. Without it, the raw image in the sketch directory is removed as soon as the output is saved, so the disk only holds both of them while compressing.
+///
+/// - `--debug-shell`
+///
+///   Open an interactive shell in the target system (with the same bind mounts, and `spec.sh` sourced) once the bootloaders are applied, and continue the build after the shell exits.
+///   If a step running in the container fails, the shell is opened before cleaning up instead. Only available for the `build` action, and requires stdin to be a terminal.
+///
+/// - `--no-scrub`
+///
+///   Keep the machine ID, the SSH host keys and the journals generated during the build in the image, instead of removing them (see `scrub` in the device specification), e.g. to debug what generated them. Only available for the `build` action.
+///
+/// - `--oobe-package` `PACKAGE`
+///
+///   Install `PACKAGE` as the OOBE wizard instead of the one selected by the device (see `oobe_package` in the device specification), e.g. to try out a new wizard. Ignored for devices without `oobe_wizard = true`. Only available for the `build` action.
+///
+/// - `--resume`
+///
+///   Continue an interrupted build of a raw image from the first incomplete stage, instead of starting over: the raw image in the most recent sketch directory of the image is attached and mounted again, using the stages and the partition map data recorded in `build-state.json` next to it.
+///   Stages are partitioning, formatting, installing the distribution, installing the packages, post installation and applying the bootloaders. An interrupted stage runs again from its beginning, while the pre-compress hooks and the compression always run again.
+///   Filesystems left mounted in the sketch directory and loop devices left attached to the raw image are released first. If nothing is recorded, or the raw image does not match the device spec anymore, the build starts over as usual. Only available for the `build` action, and refused for `--format tarball`.
+///
+/// - `--format` `FORMAT`
+///
+///   Output format, defaults to `rawimg`. Possible values:
+///   - `rawimg`: A partitioned raw image.
+///   - `tarball`: A tarball of the configured root filesystem, e.g. for containers. Partitioning and bootloaders are skipped, `/etc/fstab` only contains placeholder comments. Output filename: `aosc-os_<variant>_rootfs_<vendor>_<device>_<date>_<arch>.tar` plus the extension of the compression format.
+///   - `simg`: The raw image converted to the Android sparse image format, e.g. for `fastboot flash`. The holes of the raw image and the blocks filled with zeros take no space. Output filename: `aosc-os_<variant>_simg_<vendor>_<device>_<date>_<arch>.simg` plus the extension of the compression format. No block map is generated, and images patched later stay sparse images.
+///
+/// - `--reproducible[=SEED]`
+///
+///   Build reproducible images: the disk GUID or MBR disk signature, partition UUIDs, filesystem UUIDs and the hostname suffix are derived from `SEED` (defaults to `0`), the device ID and the partition number instead of being random.
+///   Timestamps of the files written by mkrawimg itself are clamped to `SOURCE_DATE_EPOCH` (or the Unix epoch if not set).
+///
+///   Two builds with the same seed and the same packages have the same partition table. Other sources of nondeterminism are out of scope, including the package contents and the timestamps, inode allocations and hash seeds in the filesystem metadata written by mkfs.
+///
+///   `SOURCE_DATE_EPOCH` is always honoured for the date in the output filename.
+///
+/// - `--by-compatible`
+///
+///   Look up `DEVICE` by the compatible strings of the devices only, for the rare case that a compatible string is also the ID or alias of another device. Only available for the `build` action.
+///
 /// Arguments for `build`
 /// ---------------------
 ///
@@ -182,6 +357,7 @@ pub enum ListFormat {
 /// `DEVICE` is a string identifying the target device. It can be one of the following:
 /// - A device ID defined in the device specification file.
 /// - A device alias defined in the device specification file.
+/// - The compatible string (`compatible`) defined in the device specification file, e.g. the first string of `/proc/device-tree/compatible` on the device. IDs and aliases take precedence, use `--by-compatible` to look up the compatible strings only.
 /// - Path to the device specification file `device.toml`.
 /// - Path to the directory containing the `device.toml`. The specification file must reside directly within this directory.
 ///
@@ -201,6 +377,8 @@ pub enum ListFormat {
 /// mkrawimg build ./devices/raspberrypi/rpi-5b/device.toml
 /// # Supply the (relative) path to the directory containing the target device specification file
 /// mkrawimg build raspberrypi/rpi-5b
+/// # Use the compatible string, if `compatible = "raspberrypi,5-model-b"`
+/// mkrawimg build raspberrypi,5-model-b
 /// ```
 ///
 /// By default images are built for all distribution variants. You can override this by using `-V` or `--variants`:
@@ -231,18 +409,133 @@ pub enum ListFormat {
 ///
 /// The `build-all` action takes the same options as the `build` action. [See above](#options-for-build) for available options.
 ///
+/// - `--tag` `TAG`: Only build the devices having the tag `TAG` (see the `tags` field of the [device specification file](crate::device::DeviceSpec)). Can be specified more than once to build the devices having any of them. Unknown tags are errors.
+///
 /// The `build-all` action takes no arguments.
 ///
+/// Action `rebootload`
+/// ===================
+///
+/// This action runs only the bootloader stage against an existing, uncompressed raw image, using the current bootloader list of the device spec.
+/// The Secure Boot chain is staged again afterwards, if `efi_secureboot` is enabled.
+/// Useful for iterating on bootloader scripts without rebuilding the entire image.
+///
+/// ```shell
+/// # ./target/release/mkrawimg [GLOBAL_OPTIONS] rebootload [OPTIONS] [--] DEVICE IMAGE
+/// ```
+///
+/// The partition table is read back from the image and checked against the device spec (partition map type, number of partitions, start sectors and sizes).
+/// `spec.sh` is regenerated from the PARTUUIDs and filesystem UUIDs found in the image. The root filesystem is mounted read-only, except `/boot/grub` and the `efi_directory` for `grub`. The other partitions are mounted read-write, as the bootloader actions write to them.
+/// If the device has a bootloader of the `script` type, which may write anywhere (e.g. `/etc/default/grub`), the root filesystem is mounted read-write instead.
+///
+/// - `-x`, `--compression` `COMPRESSION`: Compress the image afterwards to `IMAGE` plus the extension of the format. Defaults to `none`.
+///
+/// Action `patch`
+/// ==============
+///
+/// This action runs only the given steps against an existing image, which may be compressed, and saves the result as the next revision of the image.
+/// Useful for shipping a fixed bootloader or configuration file without rebuilding and re-testing the entire image.
+///
+/// ```shell
+/// # ./target/release/mkrawimg [GLOBAL_OPTIONS] patch [--] DEVICE IMAGE STEP...
+/// ```
+///
+/// The steps run in the given order, and can be:
+///
+/// - `bootloader:N`: Apply the `N`-th bootloader of the device spec, counting from 1. The Secure Boot chain is staged again afterwards, if `efi_secureboot` is enabled.
+/// - `file:N:PATH`: Copy `PATH` of the [content](crate::content) of partition `N` into the partition again. `PATH` is relative to the content directory, or the name of a member of the content tarball.
+/// - `postinst:NAME`: Run the post installation script `NAME` of the device again, e.g. `postinst:postinst.sh`.
+///
+/// The image is decompressed into the working directory, and the partition table is read back and checked against the device spec like `rebootload`.
+/// The steps are recorded in `/etc/mkrawimg/patches.json` of the target system, with the time, the version of mkrawimg, the name of the patched image and the new revision.
+/// The image is then checked like a fresh build (leftover temporary source entries, the Secure Boot chain), and compressed with its original format.
+///
+/// The patched image is saved next to `IMAGE`, with the name of the next revision rendered from `--filename-template`, e.g. `aosc-os_base_rawimg_raspberrypi_rpi-5b_20241108.1_arm64.img.xz` for an image without a revision.
+/// `IMAGE` must match the template, which must have `{revision}`. Its checksum is added to `SHA256SUMS` in the same directory, and the original image is left untouched.
+///
+/// Action `compress`
+/// =================
+///
+/// This action compresses an existing raw image (e.g. from an interrupted run) with the same multi-threaded compression used by the builds.
+/// The SHA256 checksum of the output is added to the `SHA256SUMS` file in the same directory.
+/// No device specification is involved.
+///
+/// ```shell
+/// ./target/release/mkrawimg [GLOBAL_OPTIONS] compress [OPTIONS] [--] INPUT [OUTPUT]
+/// ```
+///
+/// - `INPUT`: Path to the raw image, or `-` to read from stdin.
+/// - `OUTPUT`: Path to the compressed image, or `-` to write to stdout (no checksum file is written in this case).
+///   Defaults to `INPUT` with the extension of the compression format appended.
+///
+/// Options for `compress`
+/// ----------------------
+///
+/// - `-x`, `--compression` `COMPRESSION`: Specify the compression format, see [`Compression`]. The default format is `xz`.
+/// - `-l`, `--level` `LEVEL`: Specify the compression level. The default level is 9.
+///   Valid levels are 0-9 for `xz` and `gzip`, 1-22 for `zstd`.
+/// - `--benchmark`: Compress a sample (the first 256MiB) of `INPUT` with each compression format and a few levels,
+///   then print a table of time, ratio and speed. Nothing is written.
+///
 /// Action `check`
 /// ==============
 ///
 /// This action checks for the validity of the device specificatoin files.
+/// The partitions are laid out on the images of every variant exactly like the build does, so layouts the partitioner would reject fail here.
+///
+/// ```shell
+/// ./target/release/mkrawimg [GLOBAL_OPTIONS] check [--strict] [DEVICE]
+/// ```
+///
+/// All devices in the registry are checked, unless `DEVICE` is given.
+///
+/// Options for `check`
+/// -------------------
+///
+/// - `--strict`: Treat unknown fields in the device specification files as errors. Without it, they are only warned about, along with the closest known field.
+///
+/// Action `export-scripts`
+/// =======================
+///
+/// This action writes the scripts and the configuration files a build of one device would generate into a directory, without building anything.
+/// Root privileges are not required.
 ///
 /// ```shell
-/// ./target/release/mkrawimg [GLOBAL_OPTIONS] check
+/// ./target/release/mkrawimg [GLOBAL_OPTIONS] export-scripts [OPTIONS] [--] DEVICE
 /// ```
 ///
-/// `check` action does not take any options besides the global options, and it does not take any arguments.
+/// The files are written to `OUTDIR/DEVICE_ID-VARIANT`: `spec.sh`, the `fstab` entries, the kernel command line, the bootloader scripts and configuration files, and `commands.sh` listing the commands the build runs.
+/// Values only known during a build, like the loop device and the UUIDs, are replaced with placeholders such as `@LOOPDEV@` and `@PARTUUID_2@`, which are explained in the `README` written along.
+///
+/// Options for `export-scripts`
+/// ----------------------------
+///
+/// - `-V`, `--variant` `VARIANT`: Variant of the image, defaults to `base`.
+/// - `-o`, `--outdir` `OUTDIR`: Directory to write to, defaults to `./export`.
+///
+/// Action `estimate`
+/// =================
+///
+/// This action estimates the resources needed to build images for one or all devices, without building anything.
+///
+/// ```shell
+/// ./target/release/mkrawimg [GLOBAL_OPTIONS] estimate [OPTIONS] [--] [DEVICE]
+/// ```
+///
+/// For each device and variant, the following are printed:
+///
+/// - The peak disk usage of the working and output directories: the bootstrapped distribution, the raw image (or the copied root filesystem) and the output.
+///   The bootstrapped distribution is measured if it is present in the working directory, otherwise its size is guessed from the image size.
+/// - The memory needed by the compression, using the default level and thread count.
+/// - The duration, which is the mean of the latest successful builds of the same device, variant, format and compression.
+///   Builds record their stage timings in `timings.json` in the working directory.
+///   If there are no recorded builds, the duration is guessed from the size of the distribution.
+///
+/// Options for `estimate`
+/// ----------------------
+///
+/// - `-x`, `--compression` `COMPRESSION`, `-V`, `--variants` `VARIANT [VARIANT...]`, `--format` `FORMAT`: Same as the options of `build`.
+/// - `--json`: Print the estimates in JSON instead of a table.
 ///
 /// Action `list`
 /// =============
@@ -253,7 +546,8 @@ pub enum ListFormat {
 /// ./target/releases/mkrawimg [--registry REGISTRY] list [OPTIONS]
 /// ```
 ///
-/// Other global options are accepted but almost all options except `--registry` are ignored.
+/// Other global options are accepted but almost all options except `--registry` and `--outdir` are ignored.
+/// Broken device specs are skipped with a warning, as if `--lenient-scan` is given.
 ///
 /// `list` action takes no arguments.
 ///
@@ -267,6 +561,88 @@ pub enum ListFormat {
 ///   Possible values are:
 ///   - `pretty`: A table-like format which shows the basic information of devices.
 ///   - `simple`: A much simpler format which contains three colums splitted by tab character (`'\t'`), and one device per line.
+///   - `json`: A JSON array of the devices, with their IDs, vendors, architectures, names, aliases, tags and compatible strings.
+///
+/// - `--show-sizes`
+///
+///   Show the image size of each variant, and the size of its latest build if it is recorded in `build-report.json` of the output directory (see `--outdir`). Every successful build records its output there.
+///   In the `pretty` format, they follow the description, or take the next line if the description line would be wider than 100 columns. In the `json` format, they are nested under `sizes`, keyed by the variant: `mib` and `human` are the image size in MiB and in a human-readable string, `last_build` has the same for the latest build along with its path relative to the output directory, format and compression. The `simple` format does not show them.
+///
+/// - `--compatible` `STR`
+///
+///   List only the device whose compatible string (`compatible`) is exactly `STR`.
+///
+/// Action `show`
+/// =============
+///
+/// This action prints the device specification of one device in TOML, as it is used by the other actions: the inherited specs (see `inherits` of the [device specification file](crate::device::DeviceSpec)), the partition layout and the spec override are merged.
+///
+/// ```shell
+/// ./target/release/mkrawimg [GLOBAL_OPTIONS] show [--override-spec PATH] [--] DEVICE
+/// ```
+///
+/// Fields which are not defined anywhere are not shown, their default values apply.
+///
+/// Options for `show`
+/// ------------------
+///
+/// - `--override-spec` `PATH`: Merge a partial device spec on top, like `build` does.
+///
+/// Action `new-device`
+/// ===================
+///
+/// This action creates `VENDOR/ID` in the registry with a [device specification file](crate::device::DeviceSpec) to start from, and a stub `postinst.bash` listing the variables of `spec.sh`.
+///
+/// ```shell
+/// ./target/release/mkrawimg [GLOBAL_OPTIONS] new-device --arch ARCH [--partition-map MAP] [--] VENDOR ID
+/// ```
+///
+/// The device spec has a boot partition (an ESP on GPT) and a root partition, a placeholder name, no aliases, no BSP packages and a commented-out bootloader. Fields to fill in are marked with `TODO`.
+/// `VENDOR` and `ID` must consist of ASCII letters, digits and `-_.,+`. Existing files are never overwritten, and the new device spec is checked like the `check` action right after it is written.
+///
+/// Options for `new-device`
+/// ------------------------
+///
+/// - `--arch` `ARCH`: Architecture of the device, e.g. `arm64`. Required.
+/// - `--partition-map` `MAP`: `gpt` (default) or `mbr`.
+///
+/// Action `gc`
+/// ===========
+///
+/// This action removes the bootstrapped distributions, sketch directories and logs in the working directory according to the retention policy given by the global options:
+///
+/// - `--gc-max-age` `DAYS`: Remove sketch directories and logs not modified for more than `DAYS` days.
+/// - `--gc-keep-bootstraps` `N`: Keep the `N` most recently modified bootstrapped distributions for each architecture.
+/// - `--gc-max-size` `MIB`: Remove the oldest items until the rest take up to `MIB` MiB.
+///
+/// Nothing is removed if no limit is set. The working directory must not be used by a running build.
+/// Sketch directories containing raw images still attached to loop devices are skipped, unless `--force-detach` is given.
+///
+/// ```shell
+/// ./target/release/mkrawimg --workdir WORKDIR --gc-max-age 7 --gc-keep-bootstraps 1 gc [--dry-run]
+/// ```
+///
+/// Options for `gc`
+/// ----------------
+///
+/// - `--dry-run`: Only report what would be removed.
+///
+/// Action `pin-bootstrap`
+/// ======================
+///
+/// This action writes the SHA-256 of all files in `/usr/share/aoscbootstrap` of this system, to pin the builds to them with `--pin-bootstrap-hashes`.
+///
+/// ```shell
+/// ./target/release/mkrawimg pin-bootstrap [-o FILE] [EXTRA...]
+/// ```
+///
+/// The output is in the format of `sha256sum`, and can also be checked with `sha256sum -c`.
+/// Devices with a recipe list of their own (`<variant>.lst` next to `device.toml`) need them pinned as well, pass their paths as `EXTRA`.
+///
+/// Options for `pin-bootstrap`
+/// ---------------------------
+///
+/// - `-o`, `--output` `FILE`: Write to `FILE` instead of stdout.
 ///
 /// [device registry]: crate::registry::DeviceRegistry
 #[derive(Parser)]
@@ -275,36 +651,274 @@ pub struct Cmdline {
 	/// Turns on debug output.
 	#[arg(long, action = ArgAction::SetTrue)]
 	pub debug: bool,
+	/// Read the defaults of the global options from PATH [default: ./mkrawimg.toml or /etc/mkrawimg/config.toml]
+	#[arg(long, value_name = "PATH")]
+	pub config: Option<PathBuf>,
 	/// Override path to the device registry
 	#[arg(short = 'r', long)]
 	pub registry: Option<PathBuf>,
-	/// Working directory
-	#[arg(short = 'D', long, default_value = "./work")]
+	/// Working directory [default: ./work]
+	#[arg(short = 'D', long = "workdir", value_name = "WORKDIR")]
+	workdir_arg: Option<PathBuf>,
+	/// Working directory, resolved by [`Cmdline::apply_config`].
+	#[arg(skip = PathBuf::from(DEFAULT_WORKDIR))]
 	pub workdir: PathBuf,
-	/// Output directory
-	#[arg(short = 'O', long, default_value = "./out")]
+	/// Output directory [default: ./out]
+	#[arg(short = 'O', long = "outdir", value_name = "OUTDIR")]
+	outdir_arg: Option<PathBuf>,
+	/// Output directory, resolved by [`Cmdline::apply_config`].
+	#[arg(skip = PathBuf::from(DEFAULT_OUTDIR))]
 	pub outdir: PathBuf,
-	/// The mirror to download packages from.
-	#[arg(short = 'm', long, default_value = "https://repo.aosc.io/debs")]
+	/// The mirror to download packages from [default: https://repo.aosc.io/debs]
+	#[arg(short = 'm', long = "mirror", value_name = "MIRROR")]
+	mirror_arg: Option<String>,
+	/// The mirror, resolved by [`Cmdline::apply_config`].
+	#[arg(skip = DEFAULT_MIRROR.to_owned())]
 	pub mirror: String,
-	/// Specify username for the OS
-	#[arg(short = 'U', long, default_value = "aosc")]
+	/// Specify username for the OS [default: aosc]
+	#[arg(short = 'U', long = "user", value_name = "USER")]
+	user_arg: Option<String>,
+	/// Username, resolved by [`Cmdline::apply_config`].
+	#[arg(skip = DEFAULT_USER.to_owned())]
 	pub user: String,
-	/// Specify password for the OS
-	#[arg(short = 'P', long, default_value = "anthon")]
+	/// Specify password for the OS [default: anthon]
+	#[arg(short = 'P', long = "password", value_name = "PASSWORD")]
+	password_arg: Option<String>,
+	/// Read the password for the OS from a file
+	#[arg(long, value_name = "PATH", conflicts_with_all = ["password_arg", "password_prompt"])]
+	password_file: Option<PathBuf>,
+	/// Prompt for the password for the OS
+	#[arg(long, action = ArgAction::SetTrue, conflicts_with = "password_arg")]
+	password_prompt: bool,
+	/// Password, resolved by [`Cmdline::apply_config`].
+	#[arg(skip = DEFAULT_PASSWORD.to_owned())]
 	pub password: String,
+	/// Whether the password is the built-in default, resolved by [`Cmdline::apply_config`].
+	#[arg(skip = true)]
+	pub default_password: bool,
+	/// Groups of the user in addition to the default ones, instead of the ones of the device
+	#[arg(long = "user-groups", value_name = "GROUPS", value_delimiter = ',')]
+	user_groups_arg: Option<Vec<String>>,
+	/// Login shell of the user, instead of the one of the device
+	#[arg(long = "user-shell", value_name = "PATH")]
+	user_shell_arg: Option<String>,
+	/// UID of the user, instead of the one of the device
+	#[arg(long = "user-uid", value_name = "UID")]
+	user_uid_arg: Option<u32>,
+	/// Settings of the user overriding the ones of the devices, resolved by [`Cmdline::apply_config`].
+	#[arg(skip)]
+	pub user_options: UserOptions,
+	/// Set the password of root to the crypt(3) hash [default: locked]
+	#[arg(
+		long = "root-password-hash",
+		value_name = "HASH",
+		conflicts_with = "lock_root"
+	)]
+	root_password_hash_arg: Option<String>,
+	/// Lock the password of root, even if the config file sets one
+	#[arg(long, action = ArgAction::SetTrue)]
+	lock_root: bool,
+	/// What to do with the password of root, resolved by [`Cmdline::apply_config`].
+	#[arg(skip)]
+	pub root_policy: RootPolicy,
 	/// Clean up the sketch directory after building
 	#[arg(short = 'c', long, action = clap::ArgAction::SetTrue)]
 	pub cleanup: bool,
 	/// Clean up the bootstrapped system distributions after building
 	#[arg(short = 'C', long, action = clap::ArgAction::SetTrue)]
 	pub cleanup_bootstrap: bool,
+	/// Skip the cleanup if any image failed to build
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub cleanup_on_success_only: bool,
+	/// Detach loop devices still attached to files in the sketch directories before removing them
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub force_detach: bool,
+	/// Release the filesystems left mounted in the sketch directories of previous builds
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub force_clean_stale: bool,
+	/// Wait for other builds using the working directory to finish, instead of failing
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub wait_for_lock: bool,
+	/// Minimum number of free inodes on ext4 and XFS partitions after installing the packages
+	#[arg(long, value_name = "COUNT")]
+	pub min_free_inodes: Option<u64>,
+	/// Warn about partitions using more than this percentage of their space [default: 90]
+	#[arg(long = "usage-warn-threshold", value_name = "PERCENT")]
+	usage_warn_threshold_arg: Option<u8>,
+	/// Percentage, resolved by [`Cmdline::apply_config`].
+	#[arg(skip = USAGE_WARN_THRESHOLD)]
+	pub usage_warn_threshold: u8,
+	/// Fail the build if a partition uses more than this percentage of its space
+	#[arg(long, value_name = "PERCENT")]
+	pub usage_fail_threshold: Option<u8>,
+	/// Number of attempts to attach a loop device, if others take the free one at the same time [default: 5]
+	#[arg(long = "loop-attempts", value_name = "N")]
+	loop_attempts_arg: Option<u32>,
+	/// Number of attempts, resolved by [`Cmdline::apply_config`].
+	#[arg(skip = LOOP_ATTACH_ATTEMPTS)]
+	pub loop_attempts: u32,
+	/// Directory to write the build logs to, instead of the sketch directories
+	#[arg(long, value_name = "DIR")]
+	pub log_dir: Option<PathBuf>,
+	/// Import the proxy and cache environment of the user invoking sudo
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub preserve_env: bool,
+	/// Layout of the output directory [default: hierarchy]
+	#[arg(long = "output-layout", value_enum, value_name = "OUTPUT_LAYOUT")]
+	output_layout_arg: Option<OutputLayout>,
+	/// Layout of the output directory, resolved by [`Cmdline::apply_config`].
+	#[arg(skip = OutputLayout::Hierarchy)]
+	pub output_layout: OutputLayout,
+	/// Template of the output filenames, e.g. "{id}_{arch}_{variant}{revision}.{ext}{compress_ext}"
+	#[arg(long, value_name = "TEMPLATE")]
+	pub filename_template: Option<FilenameTemplate>,
+	/// Write the timings of the build run to PATH in JSON
+	#[arg(long, value_name = "PATH")]
+	pub timings_json: Option<PathBuf>,
+	/// Skip devices which can not be built and images which fail to build, instead of aborting the entire run
+	#[arg(short = 'k', long, action = ArgAction::SetTrue)]
+	pub keep_going: bool,
+	/// Skip the device specs in the registry which can not be read, instead of aborting
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub lenient_scan: bool,
+	/// Fail the build if the aoscbootstrap files do not match the SHA-256 pinned in FILE
+	#[arg(long, value_name = "FILE")]
+	pub pin_bootstrap_hashes: Option<PathBuf>,
+	/// Disable colored output
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub no_color: bool,
+	/// Disable the progress bar
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub no_progress: bool,
+	/// Maximum total size of the working directory in MiB
+	#[arg(long, value_name = "MIB")]
+	pub gc_max_size: Option<u64>,
+	/// Maximum age of the sketch directories and logs in days
+	#[arg(long, value_name = "DAYS")]
+	pub gc_max_age: Option<u64>,
+	/// Number of the bootstrapped distributions kept for each architecture
+	#[arg(long, value_name = "N")]
+	pub gc_keep_bootstraps: Option<usize>,
+	/// Enforce the retention policy of the working directory before building
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub gc_before_build: bool,
 	/// The action to take.
 	#[command(subcommand)]
 	pub action: Action,
 }
 
-#[derive(Subcommand)]
+impl Cmdline {
+	/// Fill the global options not given on the command line from `config`, or the built-in defaults.
+	pub fn apply_config(&mut self, config: Config) -> Result<()> {
+		self.registry = self.registry.take().or(config.registry);
+		self.workdir = self
+			.workdir_arg
+			.take()
+			.or(config.workdir)
+			.unwrap_or_else(|| DEFAULT_WORKDIR.into());
+		self.outdir = self
+			.outdir_arg
+			.take()
+			.or(config.outdir)
+			.unwrap_or_else(|| DEFAULT_OUTDIR.into());
+		self.mirror = self
+			.mirror_arg
+			.take()
+			.or(config.mirror)
+			.unwrap_or_else(|| DEFAULT_MIRROR.to_owned());
+		self.user = self
+			.user_arg
+			.take()
+			.or(config.user)
+			.unwrap_or_else(|| DEFAULT_USER.to_owned());
+		let password = if let Some(password) = self.password_arg.take() {
+			Some(password)
+		} else if let Some(path) = &self.password_file {
+			Some(read_password_file(path)?)
+		} else if self.password_prompt {
+			let password = prompt_password("Password of the built-in user: ")?;
+			if password.is_empty() {
+				bail!("The password is empty");
+			}
+			if prompt_password("Retype the password: ")? != password {
+				bail!("The passwords do not match");
+			}
+			Some(password)
+		} else {
+			match (config.password, &config.password_file) {
+				(Some(_), Some(_)) => {
+					bail!("Only one of password and password_file can be set in the config file")
+				}
+				(Some(password), None) => Some(password),
+				(None, Some(path)) => Some(read_password_file(path)?),
+				(None, None) => None,
+			}
+		};
+		self.default_password = password.is_none();
+		self.password = password.unwrap_or_else(|| DEFAULT_PASSWORD.to_owned());
+		// Fail before building instead of while setting up the user.
+		validate_username(&self.user)?;
+		validate_password(&self.password)?;
+		self.user_options = UserOptions {
+			groups: self.user_groups_arg.take().or(config.user_groups),
+			shell: self.user_shell_arg.take().or(config.user_shell),
+			uid: self.user_uid_arg.or(config.user_uid),
+		};
+		validate_user_options(&self.user_options)?;
+		self.root_policy = match self.root_password_hash_arg.take() {
+			_ if self.lock_root => RootPolicy::Locked,
+			Some(hash) => RootPolicy::password_hash(hash)?,
+			None => match config.root_password_hash {
+				Some(hash) => RootPolicy::password_hash(hash)
+					.context("Invalid root_password_hash in the config file")?,
+				None => RootPolicy::Locked,
+			},
+		};
+		self.min_free_inodes = self.min_free_inodes.or(config.min_free_inodes);
+		self.usage_warn_threshold = self
+			.usage_warn_threshold_arg
+			.or(config.usage_warn_threshold)
+			.unwrap_or(USAGE_WARN_THRESHOLD);
+		self.usage_fail_threshold = self.usage_fail_threshold.or(config.usage_fail_threshold);
+		for threshold in std::iter::once(self.usage_warn_threshold).chain(self.usage_fail_threshold)
+		{
+			if !(1..=100).contains(&threshold) {
+				bail!(
+					"Invalid usage threshold {}%, expected a percentage from 1 to 100",
+					threshold
+				);
+			}
+		}
+		self.loop_attempts = self
+			.loop_attempts_arg
+			.or(config.loop_attempts)
+			.unwrap_or(LOOP_ATTACH_ATTEMPTS);
+		self.log_dir = self.log_dir.take().or(config.log_dir);
+		self.output_layout = self
+			.output_layout_arg
+			.or(config.output_layout)
+			.unwrap_or(OutputLayout::Hierarchy);
+		if self.filename_template.is_none()
+			&& let Some(template) = &config.filename_template
+		{
+			self.filename_template = Some(
+				template
+					.parse()
+					.context("Invalid filename_template in the config file")?,
+			);
+		}
+		self.pin_bootstrap_hashes = self
+			.pin_bootstrap_hashes
+			.take()
+			.or(config.pin_bootstrap_hashes);
+		self.gc_max_size = self.gc_max_size.or(config.gc_max_size);
+		self.gc_max_age = self.gc_max_age.or(config.gc_max_age);
+		self.gc_keep_bootstraps = self.gc_keep_bootstraps.or(config.gc_keep_bootstraps);
+		Ok(())
+	}
+}
+
+#[derive(Clone, Subcommand)]
 pub enum Action {
 	/// Build images for a device.
 	Build {
@@ -332,12 +946,65 @@ pub enum Action {
 		#[arg(short = 'T', num_args = 1..)]
 		topics: Option<Vec<String>>,
 
+		/// Merge a partial device spec on top of the target device spec
+		#[arg(long, value_name = "PATH")]
+		override_spec: Option<PathBuf>,
+
+		/// Defer package triggers to the first boot
+		#[arg(long, action = ArgAction::SetTrue)]
+		defer_triggers: bool,
+		/// Do not check whether the distribution fits in the root partition before installing it
+		#[arg(long, action = ArgAction::SetTrue)]
+		skip_size_check: bool,
+		/// Shrink the root filesystem of raw images to the used size
+		#[arg(long, action = ArgAction::SetTrue)]
+		shrink: bool,
+		/// Do not generate the block maps of raw images for bmaptool
+		#[arg(long, action = ArgAction::SetTrue)]
+		no_bmap: bool,
+		/// Also save the uncompressed image next to the compressed output
+		#[arg(long, action = ArgAction::SetTrue)]
+		keep_raw: bool,
+		/// Output format
+		#[arg(long, value_enum, default_value_t = OutputFormat::Rawimg)]
+		format: OutputFormat,
+		/// Derive the identifiers from SEED instead of generating random ones
+		#[arg(
+			long,
+			value_name = "SEED",
+			num_args = 0..=1,
+			require_equals = true,
+			default_missing_value = "0"
+		)]
+		reproducible: Option<String>,
+
+		/// Open a shell in the target system before finishing up, or when a step in the container fails
+		#[arg(long, action = ArgAction::SetTrue)]
+		debug_shell: bool,
+
+		/// Keep the machine ID and the SSH host keys generated during the build
+		#[arg(long, action = ArgAction::SetTrue)]
+		no_scrub: bool,
+
+		/// Package of the OOBE wizard, overriding the one of the device
+		#[arg(long, value_name = "PACKAGE")]
+		oobe_package: Option<String>,
+
+		/// Continue an interrupted build from the first incomplete stage
+		#[arg(long, action = ArgAction::SetTrue)]
+		resume: bool,
+
+		/// Look up DEVICE by the compatible strings only, not the IDs and aliases
+		#[arg(long, action = ArgAction::SetTrue)]
+		by_compatible: bool,
+
 		/// ID or alias of the target device.
 		///
 		/// Can be one of the following:
 		///
 		/// - The exact ID of the device, defined in `device.toml`.
 		/// - One of the aliases for the device, defined in `device.toml`.
+		/// - The compatible string of the device, defined in `device.toml`.
 		/// - Path to the directory containing a `device.toml`.
 		/// - Path to the `device.toml` itself.
 		#[arg(verbatim_doc_comment)]
@@ -368,6 +1035,82 @@ pub enum Action {
 		/// Topics to be enrolled
 		#[arg(short = 'T', num_args = 1..)]
 		topics: Option<Vec<String>>,
+
+		/// Defer package triggers to the first boot
+		#[arg(long, action = ArgAction::SetTrue)]
+		defer_triggers: bool,
+		/// Do not check whether the distribution fits in the root partition before installing it
+		#[arg(long, action = ArgAction::SetTrue)]
+		skip_size_check: bool,
+		/// Shrink the root filesystem of raw images to the used size
+		#[arg(long, action = ArgAction::SetTrue)]
+		shrink: bool,
+		/// Do not generate the block maps of raw images for bmaptool
+		#[arg(long, action = ArgAction::SetTrue)]
+		no_bmap: bool,
+		/// Also save the uncompressed image next to the compressed output
+		#[arg(long, action = ArgAction::SetTrue)]
+		keep_raw: bool,
+		/// Output format
+		#[arg(long, value_enum, default_value_t = OutputFormat::Rawimg)]
+		format: OutputFormat,
+		/// Derive the identifiers from SEED instead of generating random ones
+		#[arg(
+			long,
+			value_name = "SEED",
+			num_args = 0..=1,
+			require_equals = true,
+			default_missing_value = "0"
+		)]
+		reproducible: Option<String>,
+
+		/// Only build the devices having TAG, can be specified more than once
+		#[arg(long = "tag", value_name = "TAG")]
+		tags: Vec<String>,
+	},
+	/// Apply the bootloaders to an existing raw image again.
+	Rebootload {
+		/// Compress the image afterwards
+		#[arg(short = 'x', long, value_enum, default_value_t = Compression::None)]
+		compression: Compression,
+
+		/// ID or alias of the target device, or path to its device spec.
+		device: String,
+
+		/// Path to the uncompressed raw image.
+		image: PathBuf,
+	},
+	/// Run a few steps of the build on an existing image, saving it as the next revision.
+	Patch {
+		/// ID or alias of the target device, or path to its device spec.
+		device: String,
+
+		/// Path to the image, which may be compressed.
+		image: PathBuf,
+
+		/// Steps to run: bootloader:N, file:N:PATH or postinst:NAME.
+		#[arg(required = true)]
+		steps: Vec<PatchStep>,
+	},
+	/// Compress an existing raw image.
+	Compress {
+		/// Image compression format
+		#[arg(short = 'x', long, value_enum, default_value_t = Compression::Xz)]
+		compression: Compression,
+
+		/// Compression level (default: 9)
+		#[arg(short, long)]
+		level: Option<u32>,
+
+		/// Compress a sample with each format and a few levels, and print the results
+		#[arg(long, action = ArgAction::SetTrue)]
+		benchmark: bool,
+
+		/// Path to the raw image, or `-` for stdin.
+		input: PathBuf,
+
+		/// Path to the output, or `-` for stdout. Defaults to the input path with the extension of the compression format.
+		output: Option<PathBuf>,
 	},
 	/// Check for validity of the devices registry.
 	Check {
@@ -381,12 +1124,118 @@ pub enum Action {
 		/// - Path to the `device.toml` itself.
 		#[arg(verbatim_doc_comment)]
 		device: Option<String>,
+
+		/// Treat unknown fields in the device specification files as errors.
+		///
+		/// They are only warned about by default.
+		#[arg(long)]
+		strict: bool,
+	},
+	/// Write the scripts and configuration files a build would generate
+	ExportScripts {
+		/// Variant of the image
+		#[arg(short = 'V', long, value_enum, default_value_t = ImageVariant::Base)]
+		variant: ImageVariant,
+
+		/// Directory to write to, a subdirectory named after the device and the variant is created
+		#[arg(short, long, default_value = "./export")]
+		outdir: PathBuf,
+
+		/// ID or alias of the target device, or path to its device spec.
+		device: String,
+	},
+	/// Estimate the resources needed to build images
+	Estimate {
+		/// Image compression format
+		#[arg(short = 'x', long, value_enum, default_value_t = Compression::Xz)]
+		compression: Compression,
+
+		/// Variants to estimate (All if not specified)
+		#[arg(short = 'V', long, value_enum, num_args = 1.., default_values = vec!["base", "desktop", "server"])]
+		variants: Vec<ImageVariant>,
+
+		/// Output format
+		#[arg(long, value_enum, default_value_t = OutputFormat::Rawimg)]
+		format: OutputFormat,
+
+		/// Print the estimates in JSON
+		#[arg(long, action = ArgAction::SetTrue)]
+		json: bool,
+
+		/// ID or alias of the target device, or all devices if not specified.
+		device: Option<String>,
 	},
 	/// List all available devices
 	List {
 		#[arg(short, long, default_value = "pretty")]
 		format: ListFormat,
+
+		/// Only list the devices having TAG, can be specified more than once
+		#[arg(long = "tag", value_name = "TAG")]
+		tags: Vec<String>,
+
+		/// Only list the device having the compatible string STR
+		#[arg(long, value_name = "STR")]
+		compatible: Option<String>,
+
+		/// Show the image sizes of each variant, and the sizes of the latest builds in the output directory
+		#[arg(long, action = ArgAction::SetTrue)]
+		show_sizes: bool,
+	},
+	/// Show the fully merged device spec of a device
+	Show {
+		/// Merge a partial device spec on top of the target device spec
+		#[arg(long, value_name = "PATH")]
+		override_spec: Option<PathBuf>,
+
+		/// ID or alias of the target device, or path to its device spec.
+		device: String,
 	},
+	/// Create the directory of a new device in the registry
+	NewDevice {
+		/// Architecture of the device
+		#[arg(long, value_enum)]
+		arch: DeviceArch,
+
+		/// Type of the partition map
+		#[arg(long, value_enum, default_value_t = PartitionMapType::GPT)]
+		partition_map: PartitionMapType,
+
+		/// Vendor of the device, the directory containing it in the registry.
+		vendor: String,
+
+		/// ID of the device.
+		id: String,
+	},
+	/// Remove old items from the working directory
+	Gc {
+		/// Only report what would be removed
+		#[arg(long, action = ArgAction::SetTrue)]
+		dry_run: bool,
+	},
+	/// Write a pin file of the aoscbootstrap files on this system
+	PinBootstrap {
+		/// Path to the pin file, or `-` for stdout
+		#[arg(short, long, default_value = "-")]
+		output: PathBuf,
+
+		/// Other files to pin, e.g. the recipe lists of the devices
+		extra: Vec<PathBuf>,
+	},
+}
+
+#[doc(hidden)]
+impl Cmdline {
+	/// The retention policy of the working directory.
+	pub fn retention_policy(&self) -> RetentionPolicy {
+		RetentionPolicy {
+			max_size: self.gc_max_size.map(|x| x * 1048576),
+			max_age: self
+				.gc_max_age
+				.map(|x| std::time::Duration::from_secs(x * 86400)),
+			keep_bootstraps: self.gc_keep_bootstraps,
+		}
+	}
 }
 
 #[doc(hidden)]
@@ -0,0 +1,864 @@
+//! Module handling the compression of the raw images.
+//!
+//! The compression step is shared by the image builds and the `compress` action.
+//! All codecs are multi-threaded except gzip, and the SHA256 checksums of the input and the output are calculated on the fly.
+//!
+//! Every codec, including the plain copy, runs in the same pipeline:
+//! a reader thread reading ahead, a hasher thread hashing the input, the encoder,
+//! and a writer thread hashing and writing the output.
+//! The stages pass buffers through bounded channels, so the encoder does not wait for the disks or the hashing.
+//! The zstd encoder writes its output itself, as it already compresses in worker threads of its own.
+//! Regular files are read with [`SparseReader`], so the holes of the raw images are not read from the disks.
+use std::{
+	fs::File,
+	io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write, copy},
+	path::Path,
+	sync::mpsc::{Receiver, SyncSender, sync_channel},
+	thread,
+	time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use crate::{bmap::data_ranges, cli::Compression};
+
+/// Name of the checksum file in the output directory.
+pub const SHA256SUMS: &str = "SHA256SUMS";
+/// Size of the sample used by the compression benchmark.
+const BENCHMARK_SAMPLE_SIZE: u64 = 256 * 1024 * 1024;
+/// Size of the buffers passed between the stages of the pipeline.
+const PIPELINE_BUFFER_SIZE: usize = 1048576;
+/// Number of buffers each stage of the pipeline can be ahead of the next one.
+const PIPELINE_DEPTH: usize = 16;
+
+/// A writer calculating the SHA256 checksum and the size of the data written through it.
+pub struct HashingWriter<W: Write> {
+	inner: W,
+	hasher: Sha256,
+	written: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+	pub fn new(inner: W) -> Self {
+		HashingWriter {
+			inner,
+			hasher: Sha256::new(),
+			written: 0,
+		}
+	}
+
+	/// Returns the inner writer, the checksum in lowercase hex and the number of bytes written.
+	pub fn finish(self) -> (W, String, u64) {
+		(
+			self.inner,
+			format!("{:x}", self.hasher.finalize()),
+			self.written,
+		)
+	}
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let len = self.inner.write(buf)?;
+		self.hasher.update(&buf[..len]);
+		self.written += len as u64;
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// The receiving end of a pipeline stage, reading the buffers sent by the previous stage.
+pub struct ChannelReader {
+	rx: Receiver<Vec<u8>>,
+	buf: Vec<u8>,
+	pos: usize,
+}
+
+impl Read for ChannelReader {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		if self.pos == self.buf.len() {
+			// The previous stage hung up, either at the end of the input or on an error.
+			// Errors are reported by joining its thread.
+			match self.rx.recv() {
+				Ok(next) => {
+					self.buf = next;
+					self.pos = 0;
+				}
+				Err(_) => return Ok(0),
+			}
+		}
+		let len = buf.len().min(self.buf.len() - self.pos);
+		buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+		self.pos += len;
+		Ok(len)
+	}
+}
+
+/// The sending end of a pipeline stage, collecting the data written into buffers for the next stage.
+pub struct ChannelWriter {
+	tx: SyncSender<Vec<u8>>,
+	buf: Vec<u8>,
+}
+
+impl ChannelWriter {
+	fn new(tx: SyncSender<Vec<u8>>) -> Self {
+		ChannelWriter {
+			tx,
+			buf: Vec::with_capacity(PIPELINE_BUFFER_SIZE),
+		}
+	}
+
+	fn send(&mut self) -> std::io::Result<()> {
+		if self.buf.is_empty() {
+			return Ok(());
+		}
+		let buf = std::mem::replace(&mut self.buf, Vec::with_capacity(PIPELINE_BUFFER_SIZE));
+		self.tx
+			.send(buf)
+			.map_err(|_| std::io::Error::new(ErrorKind::BrokenPipe, "Output stage exited early"))
+	}
+
+	/// Send the remaining data and hang up.
+	pub fn finish(mut self) -> std::io::Result<()> {
+		self.send()
+	}
+}
+
+impl Write for ChannelWriter {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let len = buf.len().min(PIPELINE_BUFFER_SIZE - self.buf.len());
+		self.buf.extend_from_slice(&buf[..len]);
+		if self.buf.len() == PIPELINE_BUFFER_SIZE {
+			self.send()?;
+		}
+		Ok(len)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.send()
+	}
+}
+
+/// Where the encoder of a pipeline writes its output.
+pub enum PipelineOutput<W: Write> {
+	/// To the writer thread.
+	Channel(ChannelWriter),
+	/// Straight to the output, hashing it in the encoder thread.
+	Direct(HashingWriter<BufWriter<W>>),
+}
+
+impl<W: Write> Write for PipelineOutput<W> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self {
+			PipelineOutput::Channel(writer) => writer.write(buf),
+			PipelineOutput::Direct(writer) => writer.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		match self {
+			PipelineOutput::Channel(writer) => writer.flush(),
+			PipelineOutput::Direct(writer) => writer.flush(),
+		}
+	}
+}
+
+/// Checksums of the data passed through a pipeline, and the size of the output.
+pub struct PipelineDigests {
+	/// SHA256 checksum of the input, in lowercase hex.
+	pub input_sha256: String,
+	/// SHA256 checksum of the output, in lowercase hex.
+	pub sha256: String,
+	pub size: u64,
+}
+
+/// Run `encode` from `from` to `to`, reading and hashing the input in their own threads.
+///
+/// With `writer_thread`, the output is also hashed and written in its own thread.
+pub fn run_pipeline<R, W, F>(
+	from: R,
+	to: W,
+	writer_thread: bool,
+	encode: F,
+) -> Result<PipelineDigests>
+where
+	R: Read + Send,
+	W: Write + Send,
+	F: FnOnce(&mut ChannelReader, PipelineOutput<W>) -> std::io::Result<PipelineOutput<W>>,
+{
+	let (read_tx, read_rx) = sync_channel::<Vec<u8>>(PIPELINE_DEPTH);
+	let (input_tx, input_rx) = sync_channel::<Vec<u8>>(PIPELINE_DEPTH);
+	let (output_tx, output_rx) = sync_channel::<Vec<u8>>(PIPELINE_DEPTH);
+	let output_writer = |to| HashingWriter::new(BufWriter::with_capacity(PIPELINE_BUFFER_SIZE, to));
+	thread::scope(|s| {
+		let reader = s.spawn(move || -> std::io::Result<()> {
+			let mut from = from;
+			loop {
+				let mut buf = Vec::with_capacity(PIPELINE_BUFFER_SIZE);
+				(&mut from)
+					.take(PIPELINE_BUFFER_SIZE as u64)
+					.read_to_end(&mut buf)?;
+				// The next stage hung up on an error, which is reported by the encoder.
+				if buf.is_empty() || read_tx.send(buf).is_err() {
+					return Ok(());
+				}
+			}
+		});
+		let hasher = s.spawn(move || -> String {
+			let mut hasher = Sha256::new();
+			for buf in read_rx {
+				hasher.update(&buf);
+				if input_tx.send(buf).is_err() {
+					break;
+				}
+			}
+			format!("{:x}", hasher.finalize())
+		});
+		let (writer, output) = if writer_thread {
+			let writer = s.spawn(move || -> std::io::Result<(String, u64)> {
+				let mut writer = output_writer(to);
+				for buf in output_rx {
+					writer.write_all(&buf)?;
+				}
+				let (mut inner, sha256, size) = writer.finish();
+				inner.flush()?;
+				Ok((sha256, size))
+			});
+			(
+				Some(writer),
+				PipelineOutput::Channel(ChannelWriter::new(output_tx)),
+			)
+		} else {
+			(None, PipelineOutput::Direct(output_writer(to)))
+		};
+		let mut input = ChannelReader {
+			rx: input_rx,
+			buf: Vec::new(),
+			pos: 0,
+		};
+		let encoded = encode(&mut input, output).and_then(|output| match output {
+			PipelineOutput::Channel(writer) => writer.finish().map(|_| None),
+			PipelineOutput::Direct(writer) => {
+				let (mut inner, sha256, size) = writer.finish();
+				inner.flush()?;
+				Ok(Some((sha256, size)))
+			}
+		});
+		// Unblock the reader and the hasher if the encoder stopped early.
+		drop(input);
+		let read = reader.join().expect("The reader thread panicked");
+		let input_sha256 = hasher.join().expect("The hasher thread panicked");
+		let written = writer.map(|x| x.join().expect("The writer thread panicked"));
+		read.context("Unable to read the input")?;
+		// A failure of the writer also shows up as a broken pipe in the encoder, report the cause instead.
+		let written = written.transpose().context("Unable to write the output")?;
+		let encoded = encoded.context("Unable to compress the input")?;
+		let (sha256, size) = written
+			.or(encoded)
+			.expect("The output is written by either the writer thread or the encoder");
+		Ok(PipelineDigests {
+			input_sha256,
+			sha256,
+			size,
+		})
+	})
+}
+
+/// A reader of a sparse file, producing the zeros of the holes without reading them from the disk.
+pub struct SparseReader {
+	file: File,
+	/// Byte ranges of the file holding data, see [`data_ranges`].
+	data: Vec<(u64, u64)>,
+	size: u64,
+	pos: u64,
+}
+
+impl SparseReader {
+	pub fn new(file: File) -> Result<Self> {
+		let data = data_ranges(&file)?;
+		let size = file.metadata()?.len();
+		Ok(SparseReader {
+			file,
+			data,
+			size,
+			pos: 0,
+		})
+	}
+}
+
+impl Read for SparseReader {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		if self.pos >= self.size || buf.is_empty() {
+			return Ok(0);
+		}
+		// Skip the ranges behind the position.
+		let skipped = self.data.iter().take_while(|x| x.1 <= self.pos).count();
+		self.data.drain(..skipped);
+		match self.data.first() {
+			Some(&(start, end)) if start <= self.pos => {
+				let len = (buf.len() as u64).min(end - self.pos) as usize;
+				self.file.seek(SeekFrom::Start(self.pos))?;
+				let len = self.file.read(&mut buf[..len])?;
+				if len == 0 {
+					return Err(std::io::Error::new(
+						ErrorKind::UnexpectedEof,
+						"The file is truncated while being read",
+					));
+				}
+				self.pos += len as u64;
+				Ok(len)
+			}
+			next => {
+				let hole_end = next.map(|x| x.0).unwrap_or(self.size);
+				let len = (buf.len() as u64).min(hole_end - self.pos) as usize;
+				buf[..len].fill(0);
+				self.pos += len as u64;
+				Ok(len)
+			}
+		}
+	}
+}
+
+/// Result of a compression.
+pub struct CompressionResult {
+	pub duration: Duration,
+	/// SHA256 checksum of the uncompressed input, in lowercase hex.
+	pub input_sha256: String,
+	/// SHA256 checksum of the output, in lowercase hex.
+	pub sha256: String,
+	/// Size of the output in bytes.
+	pub size: u64,
+}
+
+impl Compression {
+	/// The default compression level.
+	pub fn default_level(&self) -> u32 {
+		match self {
+			Compression::Xz | Compression::Zstd | Compression::Gzip => 9,
+			Compression::None => 0,
+		}
+	}
+
+	/// The range of the valid compression levels.
+	pub fn level_range(&self) -> (u32, u32) {
+		match self {
+			Compression::Xz | Compression::Gzip => (0, 9),
+			Compression::Zstd => (1, 22),
+			Compression::None => (0, 0),
+		}
+	}
+
+	pub fn check_level(&self, level: u32) -> Result<()> {
+		let (min, max) = self.level_range();
+		if level < min || level > max {
+			bail!(
+				"Invalid compression level {} for {:?}, must be within {}..={}",
+				level,
+				self,
+				min,
+				max
+			);
+		}
+		Ok(())
+	}
+}
+
+pub fn get_compression_threads() -> u32 {
+	num_cpus::get().clamp(1, 32) as u32
+}
+
+/// Set up the multi-threaded xz encoder.
+fn xz_stream_builder(
+	level: u32,
+	threads: u32,
+) -> Result<xz2::stream::MtStreamBuilder, xz2::stream::Error> {
+	let mut xz_filter = xz2::stream::Filters::new();
+	let mut xz_options = xz2::stream::LzmaOptions::new_preset(level)?;
+	xz_options.nice_len(273);
+	xz_filter.lzma2(&xz_options);
+	let mut builder = xz2::stream::MtStreamBuilder::new();
+	builder
+		.filters(xz_filter)
+		.threads(threads)
+		.block_size(1048576)
+		.check(xz2::stream::Check::Crc32);
+	Ok(builder)
+}
+
+/// Estimate the memory needed by [`compress_stream`] in bytes, including the buffers of the pipeline.
+pub fn memory_usage(compression: &Compression, level: Option<u32>) -> Result<u64> {
+	let level = level.unwrap_or(compression.default_level());
+	compression.check_level(level)?;
+	let threads = get_compression_threads();
+	let encoder = match compression {
+		Compression::Xz => {
+			let usage = xz_stream_builder(level, threads)?.memusage();
+			if usage == u64::MAX {
+				bail!("Unable to get the memory usage of the xz encoder");
+			}
+			usage
+		}
+		// zstd has no stable API for this. Each worker holds roughly its window,
+		// its job which is 4 times the window, and the match tables.
+		Compression::Zstd => (threads as u64 * 6) << zstd_window_log(level),
+		// zlib takes about 256 KiB at most.
+		Compression::Gzip => 256 * 1024,
+		Compression::None => 0,
+	};
+	Ok(encoder + (2 * PIPELINE_DEPTH * PIPELINE_BUFFER_SIZE) as u64)
+}
+
+/// The window log zstd uses at `level`, for inputs larger than 256 KiB.
+fn zstd_window_log(level: u32) -> u32 {
+	match level {
+		0..=1 => 19,
+		2..=3 => 20,
+		4..=8 => 21,
+		9..=16 => 22,
+		17..=19 => 23,
+		20 => 25,
+		21 => 26,
+		_ => 27,
+	}
+}
+
+/// Compress everything from `from` into `to`.
+///
+/// Returns the time spent, the checksums and the size of the compressed output.
+pub fn compress_stream<R: Read + Send, W: Write + Send>(
+	from: R,
+	to: W,
+	compression: &Compression,
+	level: Option<u32>,
+) -> Result<CompressionResult> {
+	let level = level.unwrap_or(compression.default_level());
+	compression.check_level(level)?;
+	let num_cpus = get_compression_threads();
+	let start = Instant::now();
+	let writer_thread = compression != &Compression::Zstd;
+	let digests = run_pipeline(
+		from,
+		to,
+		writer_thread,
+		|reader, writer| match compression {
+			Compression::Xz => {
+				let encoder = xz_stream_builder(level, num_cpus)?.encoder()?;
+				let mut writer = xz2::write::XzEncoder::new_stream(writer, encoder);
+				copy(reader, &mut writer)?;
+				writer.finish()
+			}
+			Compression::Zstd => {
+				let mut writer = zstd::stream::Encoder::new(writer, level as i32)?;
+				writer.multithread(num_cpus)?;
+				copy(reader, &mut writer)?;
+				writer.finish()
+			}
+			Compression::Gzip => {
+				let mut writer =
+					flate2::write::GzEncoder::new(writer, flate2::Compression::new(level));
+				copy(reader, &mut writer)?;
+				writer.finish()
+			}
+			Compression::None => {
+				let mut writer = writer;
+				copy(reader, &mut writer)?;
+				Ok(writer)
+			}
+		},
+	)?;
+	let duration = start.elapsed();
+	Ok(CompressionResult {
+		duration,
+		input_sha256: digests.input_sha256,
+		sha256: digests.sha256,
+		size: digests.size,
+	})
+}
+
+/// Compress the file `from` into `to`. Either of them can be `-`, for stdin and stdout respectively.
+pub fn compress_file<P: AsRef<Path>, Q: AsRef<Path>>(
+	from: P,
+	to: Q,
+	compression: &Compression,
+	level: Option<u32>,
+) -> Result<CompressionResult> {
+	let from = from.as_ref();
+	let to = to.as_ref();
+	let reader: Box<dyn Read + Send> = if from == Path::new("-") {
+		Box::new(std::io::stdin())
+	} else {
+		let file = File::options()
+			.read(true)
+			.open(from)
+			.context(format!("Unable to open '{}'", from.display()))?;
+		// The holes are only found in regular files, e.g. not in block devices.
+		if file.metadata()?.is_file() {
+			Box::new(SparseReader::new(file)?)
+		} else {
+			Box::new(file)
+		}
+	};
+	if to == Path::new("-") {
+		return compress_stream(reader, std::io::stdout(), compression, level);
+	}
+	let to_fd = File::options()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(to)
+		.context(format!("Unable to open '{}'", to.display()))?;
+	let result = compress_stream(reader, &to_fd, compression, level)?;
+	to_fd.sync_all()?;
+	Ok(result)
+}
+
+/// Detect the compression of the file at `path` from the magic at its start.
+pub fn detect_compression(path: &Path) -> Result<Compression> {
+	let mut magic = [0u8; 6];
+	let len = File::open(path)
+		.context(format!("Unable to open '{}'", path.display()))?
+		.read(&mut magic)?;
+	Ok(match &magic[..len] {
+		[0x1f, 0x8b, ..] => Compression::Gzip,
+		[0xfd, b'7', b'z', b'X', b'Z', 0x00] => Compression::Xz,
+		[0x28, 0xb5, 0x2f, 0xfd, ..] => Compression::Zstd,
+		_ => Compression::None,
+	})
+}
+
+/// Open the file at `path`, decompressing it on the fly if it is compressed with gzip, xz or zstd.
+pub fn open_decompressed(path: &Path) -> Result<Box<dyn Read>> {
+	let compression = detect_compression(path)?;
+	let file = BufReader::new(File::open(path)?);
+	Ok(match compression {
+		Compression::Gzip => Box::new(flate2::read::GzDecoder::new(file)),
+		Compression::Xz => Box::new(xz2::read::XzDecoder::new(file)),
+		Compression::Zstd => Box::new(zstd::Decoder::with_buffer(file)?),
+		Compression::None => Box::new(file),
+	})
+}
+
+/// Decompress the file at `from` to `to`, or copy it if it is not compressed. Returns the size of the output.
+pub fn decompress_file(from: &Path, to: &Path) -> Result<u64> {
+	let mut reader = open_decompressed(from)?;
+	let to_fd = File::create(to).context(format!("Unable to open '{}'", to.display()))?;
+	let mut writer = BufWriter::new(&to_fd);
+	let size = copy(&mut reader, &mut writer)
+		.context(format!("Unable to decompress '{}'", from.display()))?;
+	// Dropping the writer would swallow the errors of the last write.
+	writer
+		.into_inner()
+		.map_err(|e| e.into_error())
+		.context(format!("Unable to write '{}'", to.display()))?;
+	to_fd.sync_all()?;
+	Ok(size)
+}
+
+/// Add or replace the checksum of `filename` in the `SHA256SUMS` file within `dir`.
+pub fn update_sha256sums<P: AsRef<Path>>(dir: P, filename: &str, sha256: &str) -> Result<()> {
+	let path = dir.as_ref().join(SHA256SUMS);
+	let content = if path.is_file() {
+		std::fs::read_to_string(&path)?
+	} else {
+		String::new()
+	};
+	let mut lines: Vec<String> = content
+		.lines()
+		.filter(|line| {
+			line.split_once(char::is_whitespace)
+				.is_none_or(|(_, name)| name.trim_start().trim_start_matches('*') != filename)
+		})
+		.map(|line| line.to_owned())
+		.collect();
+	lines.push(format!("{}  {}", sha256, filename));
+	let mut fd = File::options()
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(&path)?;
+	fd.write_all((lines.join("\n") + "\n").as_bytes())?;
+	fd.sync_all()?;
+	Ok(())
+}
+
+/// Compress a sample of the input with each codec and a few compression levels, and print the results in a table.
+pub fn benchmark<P: AsRef<Path>>(input: P) -> Result<()> {
+	let input = input.as_ref();
+	let mut sample = Vec::new();
+	File::open(input)
+		.context(format!("Unable to open '{}'", input.display()))?
+		.take(BENCHMARK_SAMPLE_SIZE)
+		.read_to_end(&mut sample)?;
+	if sample.is_empty() {
+		bail!("'{}' is empty, nothing to benchmark.", input.display());
+	}
+	if (sample.len() as u64) < BENCHMARK_SAMPLE_SIZE {
+		warn!(
+			"Input is smaller than the sample size, results may not be representative of large images."
+		);
+	}
+	let combinations: &[(Compression, &[u32])] = &[
+		(Compression::Xz, &[1, 6, 9]),
+		(Compression::Zstd, &[3, 9, 19]),
+		(Compression::Gzip, &[1, 6, 9]),
+	];
+	eprintln!(
+		"Sample: {} bytes from {}, {} threads\n",
+		sample.len(),
+		input.display(),
+		get_compression_threads()
+	);
+	println!(
+		"{:<8}{:>8}{:>12}{:>10}{:>14}",
+		"CODEC", "LEVEL", "TIME (s)", "RATIO", "SPEED (MiB/s)"
+	);
+	for (compression, levels) in combinations {
+		for level in *levels {
+			let result = compress_stream(
+				sample.as_slice(),
+				std::io::sink(),
+				compression,
+				Some(*level),
+			)?;
+			let secs = result.duration.as_secs_f64();
+			println!(
+				"{:<8}{:>8}{:>12.2}{:>9.2}%{:>14.1}",
+				format!("{:?}", compression).to_lowercase(),
+				level,
+				secs,
+				result.size as f64 / sample.len() as f64 * 100.0,
+				sample.len() as f64 / 1048576.0 / secs
+			);
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	#[test]
+	fn test_compress_stream() -> Result<()> {
+		let data = b"mkrawimg".repeat(65536);
+		for compression in [
+			Compression::Xz,
+			Compression::Zstd,
+			Compression::Gzip,
+			Compression::None,
+		] {
+			let mut out = Vec::new();
+			let level = (compression != Compression::None).then_some(1);
+			let result = compress_stream(data.as_slice(), &mut out, &compression, level)?;
+			assert_eq!(result.size, out.len() as u64);
+			assert_eq!(result.sha256, format!("{:x}", Sha256::digest(&out)));
+			assert_eq!(result.input_sha256, format!("{:x}", Sha256::digest(&data)));
+		}
+		assert!(
+			compress_stream(data.as_slice(), std::io::sink(), &Compression::Xz, Some(10)).is_err()
+		);
+		Ok(())
+	}
+
+	/// A writer failing after the given number of bytes.
+	struct FailingWriter(usize);
+
+	impl Write for FailingWriter {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			if self.0 < buf.len() {
+				return Err(std::io::Error::other("Disk full"));
+			}
+			self.0 -= buf.len();
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_memory_usage() -> Result<()> {
+		let buffers = (2 * PIPELINE_DEPTH * PIPELINE_BUFFER_SIZE) as u64;
+		assert_eq!(memory_usage(&Compression::None, None)?, buffers);
+		for compression in [Compression::Xz, Compression::Zstd] {
+			let (min, max) = compression.level_range();
+			assert!(memory_usage(&compression, Some(min))? > buffers);
+			assert!(
+				memory_usage(&compression, Some(min))? < memory_usage(&compression, Some(max))?
+			);
+		}
+		assert!(memory_usage(&Compression::Xz, Some(10)).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_pipeline_errors() {
+		let data = vec![0u8; 64 * PIPELINE_BUFFER_SIZE];
+		// Errors of the output are reported instead of a hang or the broken pipe.
+		// Zeros compress too well to fill up anything.
+		for (compression, space) in [
+			(Compression::None, 2 * PIPELINE_BUFFER_SIZE),
+			(Compression::Zstd, 0),
+		] {
+			let err = compress_stream(data.as_slice(), FailingWriter(space), &compression, None)
+				.err()
+				.expect("Writing to a full disk succeeded");
+			assert!(format!("{:#}", err).contains("Disk full"), "{:#}", err);
+		}
+		// Errors of the input abort the compression.
+		let input = data.as_slice().chain(FailingReader);
+		assert!(compress_stream(input, std::io::sink(), &Compression::None, None).is_err());
+	}
+
+	struct FailingReader;
+
+	impl Read for FailingReader {
+		fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+			Err(std::io::Error::other("I/O error"))
+		}
+	}
+
+	/// Compare the pipeline against reading, hashing, compressing and writing in the same thread.
+	///
+	/// The pipeline only pays off with more CPUs than the encoder keeps busy.
+	///
+	/// Run with `cargo test --release -- --ignored bench_pipeline --nocapture`.
+	#[test]
+	#[ignore]
+	fn bench_pipeline() -> Result<()> {
+		// Compressible but not trivial data, like the contents of a filesystem.
+		let data: Vec<u8> = (0..BENCHMARK_SAMPLE_SIZE as usize)
+			.map(|i| ((i / 4096) as u32).wrapping_mul(2654435761).to_le_bytes()[i % 3])
+			.collect();
+		let dir = TestDir::new("bench")?;
+		let path = dir.join("bench.img");
+		for (compression, level) in [
+			(Compression::Xz, 1),
+			(Compression::Zstd, 3),
+			(Compression::Gzip, 1),
+			(Compression::None, 0),
+		] {
+			let start = Instant::now();
+			let serial_input_sha256 = format!("{:x}", Sha256::digest(&data));
+			let mut writer = HashingWriter::new(BufWriter::new(File::create(&path)?));
+			writer = match compression {
+				Compression::Xz => {
+					let encoder = xz_stream_builder(level, get_compression_threads())?.encoder()?;
+					let mut encoder = xz2::write::XzEncoder::new_stream(writer, encoder);
+					copy(&mut data.as_slice(), &mut encoder)?;
+					encoder.finish()?
+				}
+				Compression::Zstd => {
+					let mut encoder = zstd::stream::Encoder::new(writer, level as i32)?;
+					encoder.multithread(get_compression_threads())?;
+					copy(&mut data.as_slice(), &mut encoder)?;
+					encoder.finish()?
+				}
+				Compression::Gzip => {
+					let mut encoder =
+						flate2::write::GzEncoder::new(writer, flate2::Compression::new(level));
+					copy(&mut data.as_slice(), &mut encoder)?;
+					encoder.finish()?
+				}
+				Compression::None => {
+					copy(&mut data.as_slice(), &mut writer)?;
+					writer
+				}
+			};
+			let (inner, serial_sha256, _) = writer.finish();
+			inner.into_inner()?.sync_all()?;
+			let serial = start.elapsed();
+			let fd = File::create(&path)?;
+			let result = compress_stream(data.as_slice(), &fd, &compression, Some(level))?;
+			fd.sync_all()?;
+			// The blocks of the deflate stream depend on the sizes of the writes.
+			if compression != Compression::Gzip {
+				assert_eq!(result.sha256, serial_sha256);
+			}
+			assert_eq!(result.input_sha256, serial_input_sha256);
+			println!(
+				"{:?}: serial {:.2}s, pipelined {:.2}s, speedup {:.2}x",
+				compression,
+				serial.as_secs_f64(),
+				result.duration.as_secs_f64(),
+				serial.as_secs_f64() / result.duration.as_secs_f64()
+			);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_update_sha256sums() -> Result<()> {
+		let dir = TestDir::new("sums")?;
+		update_sha256sums(&dir, "a.img.xz", "00")?;
+		update_sha256sums(&dir, "b.img.xz", "11")?;
+		update_sha256sums(&dir, "a.img.xz", "22")?;
+		let content = std::fs::read_to_string(dir.join(SHA256SUMS))?;
+		assert_eq!(content, "11  b.img.xz\n22  a.img.xz\n");
+		Ok(())
+	}
+
+	#[test]
+	fn test_decompress_file() -> Result<()> {
+		let dir = TestDir::new("decompress")?;
+		let raw = dir.join("raw.img");
+		let data = b"mkrawimg".repeat(65536);
+		std::fs::write(&raw, &data)?;
+		for compression in [
+			Compression::Xz,
+			Compression::Zstd,
+			Compression::Gzip,
+			Compression::None,
+		] {
+			let compressed = dir.join(format!("raw.img{}", compression.get_extension()));
+			if compression != Compression::None {
+				compress_file(&raw, &compressed, &compression, Some(1))?;
+			}
+			assert_eq!(detect_compression(&compressed)?, compression);
+			let out = dir.join("out.img");
+			assert_eq!(decompress_file(&compressed, &out)?, data.len() as u64);
+			assert_eq!(std::fs::read(&out)?, data);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_sparse_reader() -> Result<()> {
+		let dir = TestDir::new("sparse")?;
+		let raw = dir.join("raw.img");
+		let mut fd = File::create(&raw)?;
+		fd.set_len(4 * 1048576 + 10)?;
+		fd.write_all(b"mkrawimg")?;
+		fd.seek(SeekFrom::Start(3 * 1048576 - 3))?;
+		fd.write_all(&[0xaa; 5000])?;
+		fd.sync_all()?;
+		let data = std::fs::read(&raw)?;
+		let mut reader = SparseReader::new(File::open(&raw)?)?;
+		// Buffers not aligned with the ranges
+		let mut read = Vec::new();
+		let mut buf = vec![0xff; 12345];
+		loop {
+			let len = reader.read(&mut buf)?;
+			if len == 0 {
+				break;
+			}
+			read.extend_from_slice(&buf[..len]);
+		}
+		assert_eq!(read.len(), data.len());
+		assert!(read == data);
+
+		let compressed = dir.join("raw.img.zst");
+		compress_file(&raw, &compressed, &Compression::Zstd, Some(1))?;
+		let out = dir.join("out.img");
+		decompress_file(&compressed, &out)?;
+		assert!(std::fs::read(&out)? == data);
+		Ok(())
+	}
+}
@@ -0,0 +1,335 @@
+//! Module handling the configuration file of the global defaults.
+//!
+//! The global options taking a value can be given defaults in a TOML file, so they do not have to be typed (or leak into the shell history) on every invocation.
+//! The fields have the names of the options in snake case, e.g. `log_dir` for `--log-dir`. See `mkrawimg.example.toml` in the source tree for all of them.
+//!
+//! The first file found of `--config`, `./mkrawimg.toml` and `/etc/mkrawimg/config.toml` is used, they are not merged.
+//! Options given on the command line take precedence over the config file, which takes precedence over the built-in defaults.
+//! Relative paths in the config file are relative to the directory containing it.
+use std::{
+	fs,
+	os::unix::fs::PermissionsExt,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use log::warn;
+use serde::Deserialize;
+
+use crate::cli::OutputLayout;
+
+/// Name of the config file looked up in the current directory.
+pub const CONFIG_NAME: &str = "mkrawimg.toml";
+/// Path to the system-wide config file.
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/mkrawimg/config.toml";
+
+/// Defaults of the global options, all optional.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+	pub registry: Option<PathBuf>,
+	pub workdir: Option<PathBuf>,
+	pub outdir: Option<PathBuf>,
+	pub mirror: Option<String>,
+	pub user: Option<String>,
+	pub password: Option<String>,
+	pub password_file: Option<PathBuf>,
+	pub user_groups: Option<Vec<String>>,
+	pub user_shell: Option<String>,
+	pub user_uid: Option<u32>,
+	pub root_password_hash: Option<String>,
+	pub min_free_inodes: Option<u64>,
+	pub usage_warn_threshold: Option<u8>,
+	pub usage_fail_threshold: Option<u8>,
+	pub loop_attempts: Option<u32>,
+	pub log_dir: Option<PathBuf>,
+	pub output_layout: Option<OutputLayout>,
+	pub filename_template: Option<String>,
+	pub pin_bootstrap_hashes: Option<PathBuf>,
+	pub gc_max_size: Option<u64>,
+	pub gc_max_age: Option<u64>,
+	pub gc_keep_bootstraps: Option<usize>,
+}
+
+impl Config {
+	/// Load the config file at `path`.
+	pub fn from_path(path: &Path) -> Result<Self> {
+		let content = fs::read_to_string(path)
+			.context(format!("Unable to read the config file {}", path.display()))?;
+		let mut config: Config =
+			toml::from_str(&content).context(format!("Invalid config file {}", path.display()))?;
+		if config.password.is_some() && fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+			warn!(
+				"{} contains a password and is accessible by other users, consider chmod 600.",
+				path.display()
+			);
+		}
+		let dir = path.parent().unwrap_or(Path::new("."));
+		for p in [
+			&mut config.registry,
+			&mut config.workdir,
+			&mut config.outdir,
+			&mut config.log_dir,
+			&mut config.password_file,
+			&mut config.pin_bootstrap_hashes,
+		]
+		.into_iter()
+		.flatten()
+		{
+			if p.is_relative() {
+				*p = dir.join(&p);
+			}
+		}
+		Ok(config)
+	}
+
+	/// Find and load the config file, see [the module documentation](self).
+	///
+	/// Returns the default config if there is none. `explicit` must exist if given.
+	pub fn find(explicit: Option<&Path>) -> Result<(Option<PathBuf>, Self)> {
+		find_in(
+			explicit,
+			&[Path::new(CONFIG_NAME), Path::new(SYSTEM_CONFIG_PATH)],
+		)
+	}
+}
+
+/// Find and load the config file at `explicit`, or the first one found of `candidates`.
+fn find_in(explicit: Option<&Path>, candidates: &[&Path]) -> Result<(Option<PathBuf>, Config)> {
+	if let Some(path) = explicit {
+		if !path.is_file() {
+			bail!("Config file {} does not exist", path.display());
+		}
+		return Ok((Some(path.to_owned()), Config::from_path(path)?));
+	}
+	for path in candidates {
+		if path.is_file() {
+			return Ok((Some(path.to_path_buf()), Config::from_path(path)?));
+		}
+	}
+	Ok((None, Config::default()))
+}
+
+#[cfg(test)]
+mod tests {
+	use clap::Parser;
+
+	use super::*;
+	use crate::fixtures::TestDir;
+	use crate::{cli::Cmdline, user::RootPolicy};
+
+	/// The sample config shipped in the source tree.
+	const SAMPLE_CONFIG: &str = include_str!("../mkrawimg.example.toml");
+
+	fn cmdline(args: &[&str], config: Config) -> Result<Cmdline> {
+		let mut cmdline = Cmdline::try_parse_from([&["mkrawimg"], args, &["list"]].concat())?;
+		cmdline.apply_config(config)?;
+		Ok(cmdline)
+	}
+
+	#[test]
+	fn test_sample_config() -> Result<()> {
+		let config: Config = toml::from_str(SAMPLE_CONFIG)?;
+		assert_eq!(config.workdir, Some(PathBuf::from("./work")));
+		// Every commented field is known
+		let uncommented: String = SAMPLE_CONFIG
+			.lines()
+			.map(|x| match x.strip_prefix("# ") {
+				Some(x) if x.contains(" = ") => x,
+				_ => x,
+			})
+			.map(|x| x.to_owned() + "\n")
+			.collect();
+		let mut config: Config = toml::from_str(&uncommented)?;
+		assert!(config.password.is_some() && config.gc_keep_bootstraps.is_some());
+		// Alternatives to each other
+		assert!(cmdline(&[], config.clone()).is_err());
+		config.password_file = None;
+		cmdline(&[], config)?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_config_precedence() -> Result<()> {
+		// Built-in defaults
+		let c = cmdline(&[], Config::default())?;
+		assert_eq!(c.workdir, PathBuf::from("./work"));
+		assert_eq!(c.user, "aosc");
+		assert_eq!(c.loop_attempts, crate::utils::LOOP_ATTACH_ATTEMPTS);
+		assert_eq!(c.output_layout, OutputLayout::Hierarchy);
+		assert_eq!(c.registry, None);
+		assert_eq!(c.usage_warn_threshold, crate::report::USAGE_WARN_THRESHOLD);
+		assert_eq!(c.usage_fail_threshold, None);
+		let config = Config {
+			registry: Some("/srv/devices".into()),
+			workdir: Some("/srv/work".into()),
+			user: Some("tester".into()),
+			password: Some("secret".into()),
+			loop_attempts: Some(9),
+			output_layout: Some(OutputLayout::Flat),
+			filename_template: Some("{id}.{ext}{compress_ext}".into()),
+			..Default::default()
+		};
+		// The config file
+		let c = cmdline(&[], config.clone())?;
+		assert_eq!(c.registry, Some(PathBuf::from("/srv/devices")));
+		assert_eq!(c.workdir, PathBuf::from("/srv/work"));
+		assert_eq!(c.outdir, PathBuf::from("./out"));
+		assert_eq!(c.user, "tester");
+		assert_eq!(c.password, "secret");
+		assert_eq!(c.loop_attempts, 9);
+		assert_eq!(c.output_layout, OutputLayout::Flat);
+		assert_eq!(
+			c.filename_template.map(|x| x.to_string()),
+			Some("{id}.{ext}{compress_ext}".to_owned())
+		);
+		// The command line, even with the built-in default values
+		let c = cmdline(
+			&[
+				"--user-groups",
+				"gpio,i2c",
+				"-r",
+				"devices",
+				"-D",
+				"./work",
+				"-U",
+				"aosc",
+				"--loop-attempts",
+				"1",
+				"--output-layout",
+				"hierarchy",
+			],
+			config.clone(),
+		)?;
+		assert_eq!(c.registry, Some(PathBuf::from("devices")));
+		assert_eq!(c.workdir, PathBuf::from("./work"));
+		assert_eq!(c.user, "aosc");
+		assert_eq!(c.password, "secret");
+		assert_eq!(c.loop_attempts, 1);
+		assert_eq!(c.output_layout, OutputLayout::Hierarchy);
+		assert_eq!(
+			c.user_options.groups,
+			Some(vec!["gpio".to_owned(), "i2c".to_owned()])
+		);
+		// Invalid values in the config file
+		let config = Config {
+			filename_template: Some("{name}.img".into()),
+			..Default::default()
+		};
+		assert!(cmdline(&[], config).is_err());
+		let config = Config {
+			usage_warn_threshold: Some(80),
+			usage_fail_threshold: Some(101),
+			..Default::default()
+		};
+		assert!(cmdline(&[], config.clone()).is_err());
+		let c = cmdline(&["--usage-fail-threshold", "95"], config)?;
+		assert_eq!(c.usage_warn_threshold, 80);
+		assert_eq!(c.usage_fail_threshold, Some(95));
+		assert!(cmdline(&["--usage-warn-threshold", "0"], Config::default()).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_password_sources() -> Result<()> {
+		let dir = TestDir::new("password")?;
+		let path = dir.join("password");
+		fs::write(&path, "from-file\n")?;
+		let path_str = path.to_str().unwrap();
+		let c = cmdline(&[], Config::default())?;
+		assert_eq!(c.password, "anthon");
+		assert!(c.default_password);
+		let config = Config {
+			password: Some("from-config".into()),
+			..Default::default()
+		};
+		let c = cmdline(&[], config.clone())?;
+		assert_eq!(c.password, "from-config");
+		assert!(!c.default_password);
+		let c = cmdline(&["--password-file", path_str], config.clone())?;
+		assert_eq!(c.password, "from-file");
+		let c = cmdline(&["-P", "from-cmdline"], config)?;
+		assert_eq!(c.password, "from-cmdline");
+		let config = Config {
+			password_file: Some(path.clone()),
+			..Default::default()
+		};
+		assert_eq!(cmdline(&[], config)?.password, "from-file");
+		// Mutually exclusive
+		for args in [
+			&["-P", "x", "--password-file", path_str][..],
+			&["-P", "x", "--password-prompt"],
+			&["--password-file", path_str, "--password-prompt"],
+		] {
+			assert!(cmdline(args, Config::default()).is_err(), "{:?}", args);
+		}
+		// Rejected before building
+		assert!(cmdline(&["-P", "a:b"], Config::default()).is_err());
+		assert!(cmdline(&["--user-uid", "0"], Config::default()).is_err());
+		let config = Config {
+			user: Some("Admin".into()),
+			..Default::default()
+		};
+		assert!(cmdline(&[], config).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_root_policy() -> Result<()> {
+		assert_eq!(
+			cmdline(&[], Config::default())?.root_policy,
+			RootPolicy::Locked
+		);
+		let config = Config {
+			root_password_hash: Some("$6$config$hash".into()),
+			..Default::default()
+		};
+		let policy = |args: &[&str]| -> Result<RootPolicy> {
+			Ok(cmdline(args, config.clone())?.root_policy)
+		};
+		assert_eq!(
+			policy(&[])?,
+			RootPolicy::PasswordHash("$6$config$hash".into())
+		);
+		assert_eq!(
+			policy(&["--root-password-hash", "$y$cmdline$hash"])?,
+			RootPolicy::PasswordHash("$y$cmdline$hash".into())
+		);
+		assert_eq!(policy(&["--lock-root"])?, RootPolicy::Locked);
+		assert!(policy(&["--root-password-hash", "$6$a$b", "--lock-root"]).is_err());
+		assert!(policy(&["--root-password-hash", "anthon"]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_find_config() -> Result<()> {
+		let dir = TestDir::new("config")?;
+		let local = dir.join(CONFIG_NAME);
+		let system = dir.join("config.toml");
+		fs::write(
+			&system,
+			"workdir = \"/var/cache/mkrawimg\"\noutdir = \"out\"\n",
+		)?;
+		let (path, config) = find_in(None, &[&local, &system])?;
+		assert_eq!(path, Some(system.clone()));
+		assert_eq!(config.workdir, Some(PathBuf::from("/var/cache/mkrawimg")));
+		// Relative to the config file
+		assert_eq!(config.outdir, Some(dir.join("out")));
+		// The first one found wins
+		fs::write(&local, "user = \"local\"\n")?;
+		let (path, config) = find_in(None, &[&local, &system])?;
+		assert_eq!(path, Some(local.clone()));
+		assert_eq!(config.workdir, None);
+		// --config
+		assert_eq!(find_in(Some(&system), &[&local])?.0, Some(system.clone()));
+		assert!(find_in(Some(&dir.join("missing.toml")), &[&local]).is_err());
+		assert_eq!(
+			find_in(None, &[&dir.join("missing.toml")])?,
+			(None, Config::default())
+		);
+		// Typos
+		fs::write(&local, "work_dir = \"/tmp\"\n")?;
+		assert!(find_in(None, &[&local]).is_err());
+		Ok(())
+	}
+}
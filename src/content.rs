@@ -0,0 +1,440 @@
+//! Module handling the content of the partitions.
+//!
+//! Partitions can be populated with files which are not part of the OS, e.g. a recovery partition or a vendor "TOOLS" partition, from a directory or a tarball within the device directory.
+//!
+//! For details please go to [`PartitionContent`].
+//!
+use std::{
+	io::{self, Read},
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+	compress::open_decompressed, context::ImageContext, filesystem::FilesystemType,
+	utils::cmd_run_check_status,
+};
+
+/// Where the content of a partition comes from.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, strum::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ContentSource {
+	/// A directory, whose content is copied into the partition.
+	Dir,
+	/// A tarball, optionally compressed with gzip, xz or zstd.
+	Tar,
+}
+
+/// Files to populate a partition with.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// usage = "other"
+/// filesystem = "fat32"
+/// # Relative to the directory containing device.toml.
+/// content = { source = "dir", path = "tools/" }
+/// ```
+///
+/// Possible sources are:
+///
+/// - `dir`: The content of the directory is copied into the partition.
+/// - `tar`: The tarball is extracted into the partition. It can be compressed with gzip, xz or zstd.
+///
+/// The partition is populated right after it is formatted and mounted, before the system distribution is installed and the partitions are mounted in it, thus the content is never shadowed by another partition.
+/// Ownership, permissions, ACLs and extended attributes are preserved, except on FAT filesystems, which only keep the modification times. Symbolic links are copied as the files they point to on FAT for the same reason.
+///
+/// The partition must be formatted during the build, and can not be the root partition. The estimated size of the content (each file rounded up to 4 KiB) must fit in the partition.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PartitionContent {
+	pub source: ContentSource,
+	pub path: PathBuf,
+}
+
+/// The size of the files are rounded up to this when estimating the size of the content.
+const CONTENT_BLOCK_SIZE: u64 = 4096;
+
+/// Filesystems without Unix ownership, permissions and symbolic links.
+fn is_fat(filesystem: &FilesystemType) -> bool {
+	matches!(filesystem, FilesystemType::Fat16 | FilesystemType::Fat32)
+}
+
+impl PartitionContent {
+	/// Check the content for the device spec within `dirname`, returning its estimated size in bytes.
+	pub fn check(&self, dirname: &Path) -> Result<u64> {
+		if self.path.is_absolute() {
+			bail!(
+				"Content '{}' must be relative to the directory containing device.toml",
+				self.path.display()
+			);
+		}
+		let path = dirname.join(&self.path);
+		match self.source {
+			ContentSource::Dir => {
+				if !path.is_dir() {
+					bail!(
+						"Directory '{}' not found within the same directory as the device.toml",
+						self.path.display()
+					);
+				}
+				dir_content_size(&path)
+			}
+			ContentSource::Tar => {
+				if !path.is_file() {
+					bail!(
+						"Tarball '{}' not found within the same directory as the device.toml",
+						self.path.display()
+					);
+				}
+				open_decompressed(&path)
+					.and_then(tar_content_size)
+					.context(format!("Unable to read tarball {}", path.display()))
+			}
+		}
+	}
+
+	/// The command populating the `filesystem` mounted at `dst` with the content, for the device spec within `dirname`.
+	pub fn populate_command(
+		&self,
+		dirname: &Path,
+		filesystem: &FilesystemType,
+		dst: &Path,
+	) -> Command {
+		let src = dirname.join(&self.path);
+		match self.source {
+			ContentSource::Dir => {
+				let mut command = rsync_command(filesystem);
+				// Trailing slashes make rsync copy the contents of the directories.
+				for dir in [&src, dst] {
+					let mut arg = dir.as_os_str().to_owned();
+					arg.push("/");
+					command.arg(arg);
+				}
+				command
+			}
+			ContentSource::Tar => {
+				let mut command = Command::new("tar");
+				command.arg("--extract");
+				if is_fat(filesystem) {
+					command.args(["--no-same-owner", "--no-same-permissions"]);
+				} else {
+					command.args([
+						"--same-owner",
+						"--numeric-owner",
+						"--same-permissions",
+						"--acls",
+						"--xattrs",
+						"--xattrs-include=*",
+					]);
+				}
+				command.arg("--file").arg(&src).arg("--directory").arg(dst);
+				command
+			}
+		}
+	}
+
+	/// The command copying only `file` of the content into the `filesystem` mounted at `dst`, e.g. to patch an image.
+	///
+	/// `file` is relative to the content directory, or the name of a member of the tarball.
+	pub fn copy_file_command(
+		&self,
+		dirname: &Path,
+		filesystem: &FilesystemType,
+		dst: &Path,
+		file: &Path,
+	) -> Command {
+		match self.source {
+			ContentSource::Dir => {
+				let mut command = rsync_command(filesystem);
+				// The part after "/./" is recreated in the destination.
+				let mut src = dirname.join(&self.path).into_os_string();
+				src.push("/./");
+				src.push(file);
+				command.arg("--relative").arg(src).arg(dst);
+				command
+			}
+			ContentSource::Tar => {
+				let mut command = self.populate_command(dirname, filesystem, dst);
+				command.arg("--").arg(file);
+				command
+			}
+		}
+	}
+}
+
+/// rsync preserving what `filesystem` can keep.
+fn rsync_command(filesystem: &FilesystemType) -> Command {
+	let mut command = Command::new("rsync");
+	if is_fat(filesystem) {
+		command.arg("-rtL");
+	} else {
+		command.args(["-aAHXS", "--numeric-ids"]);
+	}
+	command
+}
+
+/// Estimated size of the files in `dir`, with each of them and the directories taking whole blocks.
+fn dir_content_size(dir: &Path) -> Result<u64> {
+	let mut size = 0;
+	for entry in WalkDir::new(dir).min_depth(1) {
+		let entry = entry?;
+		let metadata = entry.path().symlink_metadata()?;
+		if metadata.is_dir() {
+			size += CONTENT_BLOCK_SIZE;
+		} else if metadata.is_file() {
+			size += metadata.len().next_multiple_of(CONTENT_BLOCK_SIZE);
+		}
+	}
+	Ok(size)
+}
+
+/// Estimated size of the files in a tarball, the same way as [`dir_content_size`].
+///
+/// Only the headers are parsed, the data of the files is skipped.
+fn tar_content_size(mut tarball: impl Read) -> Result<u64> {
+	let mut size = 0;
+	let mut header = [0u8; 512];
+	loop {
+		if let Err(e) = tarball.read_exact(&mut header) {
+			if e.kind() == io::ErrorKind::UnexpectedEof {
+				bail!("The tarball is truncated");
+			}
+			return Err(e.into());
+		}
+		// The end of the archive is marked with zero blocks.
+		if header.iter().all(|x| *x == 0) {
+			break;
+		}
+		let data_size = tar_header_size(&header[124..136])?;
+		match header[156] {
+			b'0' | b'\0' | b'7' => size += data_size.next_multiple_of(CONTENT_BLOCK_SIZE),
+			b'5' => size += CONTENT_BLOCK_SIZE,
+			_ => (),
+		}
+		// The data is padded to whole records.
+		let skipped = io::copy(
+			&mut (&mut tarball).take(data_size.next_multiple_of(512)),
+			&mut io::sink(),
+		)?;
+		if skipped != data_size.next_multiple_of(512) {
+			bail!("The tarball is truncated");
+		}
+	}
+	Ok(size)
+}
+
+/// Parse the size field of a tar header, in octal or in the GNU base-256 encoding for the large ones.
+fn tar_header_size(field: &[u8]) -> Result<u64> {
+	if field[0] & 0x80 != 0 {
+		return Ok(field[1..]
+			.iter()
+			.fold(0u64, |size, x| (size << 8) | *x as u64));
+	}
+	let octal = std::str::from_utf8(field)?.trim_matches(|c| c == '\0' || c == ' ');
+	if octal.is_empty() {
+		return Ok(0);
+	}
+	u64::from_str_radix(octal, 8).context(format!("Invalid size '{}' in a tar header", octal))
+}
+
+impl ImageContext<'_> {
+	/// Populate the partitions having content, which are mounted in `mntdir_base` as `p1`, `p2`, etc.
+	///
+	/// This must be done before the partitions are mounted in the root filesystem, so the content is not shadowed.
+	pub fn populate_partitions(&self, mntdir_base: &Path) -> Result<()> {
+		let dirname = self
+			.device
+			.file_path
+			.parent()
+			.context("Failed to reach the directory containing the device spec file")?;
+		for partition in &self.device.partitions {
+			let Some(content) = &partition.content else {
+				continue;
+			};
+			self.info(format!(
+				"Populating partition {} from {} {} ...",
+				partition.num,
+				content.source,
+				content.path.display()
+			));
+			let dst = mntdir_base.join(format!("p{}", partition.num));
+			let mut command = content.populate_command(dirname, &partition.filesystem, &dst);
+			cmd_run_check_status(&mut command)
+				.context(format!("Failed to populate partition {}", partition.num))?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::TestDir;
+	use crate::utils::create_tarball;
+	use std::{
+		fs::{self, File},
+		io::Write,
+		os::unix::fs::symlink,
+	};
+
+	#[test]
+	fn test_content_size() -> Result<()> {
+		let dir = TestDir::new("content")?;
+		let src = dir.join("device/recovery");
+		fs::create_dir_all(src.join("sub"))?;
+		fs::write(src.join("a"), vec![0u8; 5000])?;
+		fs::write(src.join("sub/b"), "b")?;
+		symlink("a", src.join("c"))?;
+		let content = PartitionContent {
+			source: ContentSource::Dir,
+			path: "recovery".into(),
+		};
+		// 2 blocks for a, 1 for b and 1 for the directory
+		assert_eq!(content.check(&dir.join("device"))?, 4 * CONTENT_BLOCK_SIZE);
+
+		let tarball = dir.join("device/recovery.tar");
+		create_tarball(&src, &tarball)?;
+		let content = PartitionContent {
+			source: ContentSource::Tar,
+			path: "recovery.tar".into(),
+		};
+		// The tarball has `.` as well
+		assert_eq!(content.check(&dir.join("device"))?, 5 * CONTENT_BLOCK_SIZE);
+		let mut gz = flate2::write::GzEncoder::new(
+			File::create(dir.join("device/recovery.tar.gz"))?,
+			flate2::Compression::fast(),
+		);
+		gz.write_all(&fs::read(&tarball)?)?;
+		gz.finish()?;
+		let content = PartitionContent {
+			source: ContentSource::Tar,
+			path: "recovery.tar.gz".into(),
+		};
+		assert_eq!(content.check(&dir.join("device"))?, 5 * CONTENT_BLOCK_SIZE);
+
+		// Truncated tarballs
+		let truncated = fs::read(&tarball)?[..1024].to_vec();
+		fs::write(dir.join("device/truncated.tar"), truncated)?;
+		for (source, path) in [
+			(ContentSource::Tar, "truncated.tar"),
+			(ContentSource::Tar, "recovery"),
+			(ContentSource::Dir, "recovery.tar"),
+			(ContentSource::Dir, "nonexistent"),
+			(ContentSource::Dir, "/recovery"),
+		] {
+			let content = PartitionContent {
+				source,
+				path: path.into(),
+			};
+			assert!(
+				content.check(&dir.join("device")).is_err(),
+				"{} {} is accepted",
+				source,
+				path
+			);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_tar_header_size() -> Result<()> {
+		assert_eq!(tar_header_size(b"00000001750\0")?, 1000);
+		assert_eq!(tar_header_size(b"     1750 \0\0")?, 1000);
+		assert_eq!(tar_header_size(&[0u8; 12])?, 0);
+		let mut large = [0u8; 12];
+		large[0] = 0x80;
+		large[7] = 0x02;
+		assert_eq!(tar_header_size(&large)?, 0x200000000);
+		assert!(tar_header_size(b"0000000175x\0").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_populate_command() {
+		let content = PartitionContent {
+			source: ContentSource::Dir,
+			path: "tools".into(),
+		};
+		let args = |cmd: &Command| {
+			cmd.get_args()
+				.map(|x| x.to_string_lossy().into_owned())
+				.collect::<Vec<_>>()
+		};
+		let cmd = content.populate_command(
+			Path::new("/reg/dev"),
+			&FilesystemType::Fat32,
+			Path::new("/mnt/p1"),
+		);
+		assert_eq!(cmd.get_program(), "rsync");
+		assert_eq!(args(&cmd), ["-rtL", "/reg/dev/tools/", "/mnt/p1/"]);
+		let cmd = content.populate_command(
+			Path::new("/reg/dev"),
+			&FilesystemType::Ext4,
+			Path::new("/mnt/p1"),
+		);
+		assert_eq!(
+			args(&cmd),
+			["-aAHXS", "--numeric-ids", "/reg/dev/tools/", "/mnt/p1/"]
+		);
+		let content = PartitionContent {
+			source: ContentSource::Tar,
+			path: "tools.tar.zst".into(),
+		};
+		let cmd = content.populate_command(
+			Path::new("/reg/dev"),
+			&FilesystemType::Fat16,
+			Path::new("/mnt/p1"),
+		);
+		assert_eq!(cmd.get_program(), "tar");
+		assert_eq!(
+			args(&cmd),
+			[
+				"--extract",
+				"--no-same-owner",
+				"--no-same-permissions",
+				"--file",
+				"/reg/dev/tools.tar.zst",
+				"--directory",
+				"/mnt/p1"
+			]
+		);
+		let cmd = content.populate_command(
+			Path::new("/reg/dev"),
+			&FilesystemType::Btrfs,
+			Path::new("/mnt/p1"),
+		);
+		assert!(args(&cmd).contains(&"--xattrs".to_owned()));
+		// A single file
+		let cmd = content.copy_file_command(
+			Path::new("/reg/dev"),
+			&FilesystemType::Fat16,
+			Path::new("/mnt/p1"),
+			Path::new("boot/config.txt"),
+		);
+		assert_eq!(args(&cmd)[6..], ["/mnt/p1", "--", "boot/config.txt"]);
+		let content = PartitionContent {
+			source: ContentSource::Dir,
+			path: "tools".into(),
+		};
+		let cmd = content.copy_file_command(
+			Path::new("/reg/dev"),
+			&FilesystemType::Fat32,
+			Path::new("/mnt/p1"),
+			Path::new("boot/config.txt"),
+		);
+		assert_eq!(
+			args(&cmd),
+			[
+				"-rtL",
+				"--relative",
+				"/reg/dev/tools/./boot/config.txt",
+				"/mnt/p1"
+			]
+		);
+	}
+}
@@ -1,31 +1,56 @@
-use core::time;
 use std::{
-	fs::{File, create_dir_all},
-	io::{BufReader, BufWriter, Write, copy},
+	cell::RefCell,
+	collections::HashSet,
+	fs::{self, File, create_dir_all},
+	os::unix::fs::MetadataExt,
 	path::{Path, PathBuf},
-	thread,
-	time::{Duration, Instant},
+	process::Command,
+	time::Instant,
 };
 
 use crate::{
-	cli::Compression,
-	device::{DeviceArch, DeviceSpec, PartitionMapData, PartitionMapType},
+	bmap::write_bmap,
+	bootstrap::load_hashes,
+	cli::{Compression, OutputFormat, OutputLayout},
+	compress::{compress_file, decompress_file, get_compression_threads, update_sha256sums},
+	device::{DeviceSpec, PartitionMapData, PartitionMapType, SPEC_OVERRIDE_MARKER},
+	estimate::{BuildTimings, PACKAGE_SIZE, TimingStore, bootstrap_size, timing_key},
 	filesystem::FilesystemType,
+	fixups::{ContainerFixups, HOST_RESOLV_CONF},
+	gc::{latest_sketch, link_latest_sketch, release_sketch},
+	hook::{HookEnv, HookStage},
 	partition::PartitionUsage,
-	pm::{APT, Distro, Oma, PackageManager},
+	patch::{PatchRecord, PatchStep, record_patch},
+	plan::BuildRun,
+	pm::{
+		APT, DEFERRED_TRIGGERS_METADATA_PATH, DEFERRED_TRIGGERS_PENDING_PATH,
+		DEFERRED_TRIGGERS_UNIT_NAME, Distro, Oma, PackageManager, aosc_uses_apt,
+	},
+	report::{BuildReport, DiskUsage, ReportEntry, RunEntry, RunStatus, disk_usage_table},
+	resume::{BuildStage, BuildState},
+	scrub,
+	shrink::{ShrunkPartition, check_shrinkable, shrink_filesystem, shrink_partition_table},
+	simg::{decode_file, encode_file, is_simg},
+	swap::{SwapSpec, create_swapfile, write_zram_generator_conf},
 	topics::{Topic, save_topics},
+	user::{RootPolicy, UserSpec},
 	utils::{
-		add_user, create_sparse_file, refresh_partition_table, restore_term, rsync_sysroot,
-		run_script_with_chroot, set_locale, setup_scroll_region, sync_filesystem,
+		BuildLog, LOCALCONF_PATH, UserOptions, add_user, attach_loop_device, chroot_shell_command,
+		clamp_file_times, cmd_run_check_status, copy_preserving, create_sparse_file,
+		create_tarball, derive_bytes, draw_progressbar, filesystem_usage, find_unit_file,
+		free_space, inode_usage, lock_password, normalize_unit_name, nspawn_machine_name,
+		partition_path, path_str, refresh_partition_table, release_loop_devices, restore_term,
+		rsync_sysroot, run_script_with_chroot, set_locale, set_password, setup_scroll_region,
+		source_date_epoch, sync_filesystem, unmount_busy_retrying, wait_for_partitions,
 	},
 };
 use anyhow::{Context, Result, bail};
+use chrono::SecondsFormat;
 use clap::ValueEnum;
 use log::{debug, info, warn};
-use loopdev::LoopControl;
 use strum::{Display, VariantArray};
-use sys_mount::{Mount, UnmountFlags, unmount};
-use termsize::Size;
+use sys_mount::{Mount, MountFlags};
+use uuid::Uuid;
 
 #[derive(Copy, Clone, Debug, Display, PartialEq, Eq, PartialOrd, Ord, ValueEnum, VariantArray)]
 pub enum ImageVariant {
@@ -45,6 +70,9 @@ pub struct ImageContext<'a> {
 	pub outdir: &'a Path,
 	pub user: &'a str,
 	pub password: &'a str,
+	/// Settings of the user given for the build, overriding the ones of the device.
+	pub user_options: &'a UserOptions,
+	pub root_policy: &'a RootPolicy,
 	// Filename can not be a ref unless there's another thing that
 	// holds the (rather unique) filename during execution, since
 	// the filename is combined with several pieces.
@@ -52,8 +80,45 @@ pub struct ImageContext<'a> {
 	pub base_dist: PathBuf,
 	pub override_rootfs_fstype: &'a Option<FilesystemType>,
 	pub additional_packages: &'a Option<Vec<String>>,
+	/// Package of the OOBE wizard overriding the one of the device, see [`Self::oobe_package`].
+	pub oobe_package: &'a Option<String>,
 	pub compress: &'a Compression,
+	pub format: &'a OutputFormat,
 	pub topics: Option<&'a Vec<Topic>>,
+	/// Seed of the identifiers in reproducible builds.
+	pub seed: Option<&'a str>,
+	/// The run this image is built in.
+	pub run: &'a BuildRun,
+	/// Detach the loop devices still attached to a stale raw image.
+	pub force_detach: bool,
+	/// Minimum number of free inodes on ext4 and XFS partitions.
+	pub min_free_inodes: Option<u64>,
+	/// Percentage of the space used by a partition, above which a warning is printed.
+	pub usage_warn_threshold: u8,
+	/// Percentage of the space used by a partition, above which the build fails.
+	pub usage_fail_threshold: Option<u8>,
+	/// Number of attempts to attach a loop device.
+	pub loop_attempts: u32,
+	/// Directory to write the build logs to, instead of the sketch directories.
+	pub log_dir: Option<&'a Path>,
+	/// Open a shell in the target system before finishing up, or when a step in the container fails.
+	pub debug_shell: bool,
+	/// Keep the machine ID and the SSH host keys, even if the device scrubs them.
+	pub no_scrub: bool,
+	/// Do not check whether the distribution fits in the root filesystem.
+	pub skip_size_check: bool,
+	/// Shrink the root filesystem of a raw image to the used size, see [`crate::shrink`].
+	pub shrink: bool,
+	/// Do not generate the block map of a raw image, see [`crate::bmap`].
+	pub no_bmap: bool,
+	/// Also save the uncompressed image next to the output, see [`Self::uncompressed_output_path`].
+	pub keep_raw: bool,
+	/// Continue an interrupted build from the first incomplete stage, see [`BuildState`].
+	pub resume: bool,
+	/// Layout of the output directory.
+	pub output_layout: OutputLayout,
+	/// The mirror to download packages from, used in the extra package sources.
+	pub mirror: &'a str,
 }
 
 pub type ImageContextQueue<'a> = Vec<ImageContext<'a>>;
@@ -78,6 +143,62 @@ impl ImageContext<'_> {
 		);
 	}
 
+	/// Generate a random UUID, or derive it from the seed in reproducible builds.
+	pub(crate) fn gen_uuid(&self, purpose: &str) -> Uuid {
+		match self.seed {
+			Some(seed) => {
+				uuid::Builder::from_random_bytes(derive_bytes(seed, &[&self.device.id, purpose]))
+					.into_uuid()
+			}
+			None => Uuid::new_v4(),
+		}
+	}
+
+	/// Generate a random number, or derive it from the seed in reproducible builds.
+	pub(crate) fn gen_u32(&self, purpose: &str) -> u32 {
+		match self.seed {
+			Some(seed) => {
+				let bytes = derive_bytes(seed, &[&self.device.id, purpose]);
+				u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+			}
+			None => rand::random(),
+		}
+	}
+
+	/// Clamp the timestamps of the files written by mkrawimg to `SOURCE_DATE_EPOCH` in reproducible builds.
+	///
+	/// Files written by the package manager and the scripts are left as is.
+	fn clamp_timestamps(&self, rootdir: &Path) -> Result<()> {
+		if self.seed.is_none() {
+			return Ok(());
+		}
+		let epoch = source_date_epoch()?.unwrap_or(0);
+		let unit_path = Path::new("usr/lib/systemd/system").join(DEFERRED_TRIGGERS_UNIT_NAME);
+		let files = [
+			Path::new("etc/fstab"),
+			Path::new("etc/hostname"),
+			Path::new("etc/hosts"),
+			Path::new("etc/kernel/cmdline"),
+			Path::new(LOCALCONF_PATH),
+			Path::new(SPEC_OVERRIDE_MARKER),
+			Path::new(DEFERRED_TRIGGERS_METADATA_PATH),
+			Path::new(DEFERRED_TRIGGERS_PENDING_PATH),
+			&unit_path,
+		];
+		self.info(format!("Clamping the timestamps to {} ...", epoch));
+		for file in files {
+			let path = rootdir.join(file);
+			if path.symlink_metadata().is_err() {
+				continue;
+			}
+			clamp_file_times(&path, epoch).context(format!(
+				"Failed to clamp the timestamps of {}",
+				path.display()
+			))?;
+		}
+		Ok(())
+	}
+
 	#[inline]
 	fn partition_image<P: AsRef<Path>>(&self, dev: P) -> Result<PartitionMapData> {
 		let disk_path = dev.as_ref();
@@ -92,10 +213,10 @@ impl ImageContext<'_> {
 			"Informing the kernel to reload the partition table on {} ...",
 			disk_path.display()
 		));
-		// FIXME: BLKRRPART ioctl call EINVALs on loop devices.
-		// For now we call partprobe to tell the kernel to reread the partition table.
-		// gptman::linux::reread_partition_table(&mut File::options().read(true).write(true).open(disk_path)?)?;
-		refresh_partition_table(dev)?;
+		// The loop device is attached with partition scanning enabled, otherwise BLKRRPART EINVALs.
+		refresh_partition_table(disk_path)?;
+		let nums: Vec<u32> = self.device.partitions.iter().map(|p| p.num).collect();
+		wait_for_partitions(disk_path, &nums)?;
 		Ok(pm_data)
 	}
 
@@ -103,7 +224,7 @@ impl ImageContext<'_> {
 		&self,
 		loop_dev: P,
 		mntdir_base: P,
-		stack: &mut Vec<String>,
+		stack: &mut Vec<PathBuf>,
 	) -> Result<()> {
 		let mntdir_base = mntdir_base
 			.as_ref()
@@ -112,11 +233,10 @@ impl ImageContext<'_> {
 		let loop_dev = loop_dev.as_ref();
 		debug!("Base directory for mountpoints: {}", mntdir_base.display());
 		for partition in &self.device.partitions {
-			if partition.filesystem == FilesystemType::None {
+			if !partition.formatted_at_build() {
 				continue;
 			}
-			let src_dir = format!("{}p{}", loop_dev.to_string_lossy(), partition.num);
-			let src_dir = Path::new(&src_dir);
+			let src_dir = partition_path(loop_dev, partition.num);
 			let dst_dir = mntdir_base.join(format!("p{}", partition.num));
 			create_dir_all(&dst_dir)?;
 			debug!(
@@ -124,24 +244,15 @@ impl ImageContext<'_> {
 				src_dir.display(),
 				dst_dir.as_path().display()
 			);
-			// Shoud we handle standard options like ro, nosuid, noexec, etc?
-			if let Some(opts) = partition.mount_opts.as_ref() {
-				// "defaults" in mount options are ignored.
-				let opts: Vec<_> = opts
-					.iter()
-					.map(|x| x.as_str())
-					.filter(|x| x != &"defaults")
-					.collect();
-				let opts = opts.join(",");
-				let mount = Mount::builder()
-					.fstype(partition.filesystem.get_os_fstype()?)
-					.data(&opts);
-				mount.mount(src_dir, &dst_dir)?;
+			// Generic options like ro and noexec are left for fstab, the image must be writable here.
+			let opts = partition.mount_options()?.data();
+			let mount = Mount::builder().fstype(partition.filesystem.get_os_fstype()?);
+			if opts.is_empty() {
+				mount.mount(&src_dir, &dst_dir)?;
 			} else {
-				let mount = Mount::builder().fstype(partition.filesystem.get_os_fstype()?);
-				mount.mount(src_dir, &dst_dir)?;
-			};
-			stack.push(dst_dir.to_string_lossy().to_string());
+				mount.data(&opts).mount(&src_dir, &dst_dir)?;
+			}
+			stack.push(dst_dir);
 		}
 		Ok(())
 	}
@@ -150,126 +261,442 @@ impl ImageContext<'_> {
 		&self,
 		loop_dev: P,
 		rootdir: P,
-		stack: &mut Vec<String>,
+		stack: &mut Vec<PathBuf>,
 	) -> Result<()> {
 		let loop_dev = loop_dev.as_ref();
 		let rootdir = rootdir.as_ref();
-		for partition in &self.device.partitions {
-			if partition.filesystem == FilesystemType::None {
-				continue;
-			}
+		// Mounting a parent later would shadow its children. The stack unmounts them in reverse.
+		for partition in self.device.mountable_partitions() {
 			if partition.usage == PartitionUsage::Rootfs {
 				continue;
 			}
 			if let Some(mp) = &partition.mountpoint {
-				let src_dir = format!("{}p{}", loop_dev.to_string_lossy(), partition.num);
-				let src_dir = Path::new(&src_dir);
+				let src_dir = partition_path(loop_dev, partition.num);
 				// Joining paths with a leading slash replaces the whole path
 				let dst_dir = rootdir.join(mp.trim_start_matches('/'));
 				create_dir_all(&dst_dir)?;
 				let mount = Mount::builder().fstype(partition.filesystem.get_os_fstype()?);
-				mount.mount(src_dir, &dst_dir)?;
-				stack.push(dst_dir.to_string_lossy().to_string());
+				mount.mount(&src_dir, &dst_dir)?;
+				stack.push(dst_dir);
 			}
 		}
 		Ok(())
 	}
 
-	#[inline]
-	fn umount_stack(stack: &mut Vec<String>) -> Result<()> {
-		loop {
-			let cur = stack.pop();
-			if let Some(s) = cur {
-				debug!("Syncing filesystem {} ...", &s);
-				sync_filesystem(&s)?;
-				debug!("Umounting {} ...", &s);
-				let p = Path::new(&s);
-				unmount(p, UnmountFlags::empty())?;
-				thread::sleep(time::Duration::from_millis(100));
-			} else {
-				// exhausted
-				break;
+	/// Make sure the distribution and the packages to be installed fit in the root filesystem mounted at `rootdir`, unless `--skip-size-check` is given.
+	fn check_rootfs_size(&self, rootdir: &Path) -> Result<()> {
+		if self.skip_size_check {
+			return Ok(());
+		}
+		let packages = self.packages().len() as u64;
+		let needed = bootstrap_size(&self.base_dist)? + packages * PACKAGE_SIZE;
+		let free = free_space(rootdir)?;
+		debug!(
+			"Root filesystem: {} bytes needed ({} packages), {} bytes free",
+			needed, packages, free
+		);
+		if needed > free {
+			bail!(
+				"The root filesystem needs ~{} MiB, but the root partition has only {} MiB free.\nPlease increase [size].{} in the device specification, or pass --skip-size-check if the estimate is wrong.",
+				needed.div_ceil(1048576),
+				free / 1048576,
+				self.variant.to_string().to_lowercase()
+			);
+		}
+		Ok(())
+	}
+
+	/// Log the inode usage of the ext4 and XFS partitions mounted in `rootdir`, failing if any of them has less than `min_free_inodes` left.
+	fn check_free_inodes(&self, rootdir: &Path) -> Result<()> {
+		for partition in self.device.mountable_partitions() {
+			let fstype = match self.override_rootfs_fstype {
+				Some(fstype) if partition.usage == PartitionUsage::Rootfs => fstype,
+				_ => &partition.filesystem,
+			};
+			if !matches!(fstype, FilesystemType::Ext4 | FilesystemType::Xfs) {
+				continue;
+			}
+			let Some(mp) = &partition.mountpoint else {
+				continue;
+			};
+			let (used, free) = inode_usage(rootdir.join(mp.trim_start_matches('/')))?;
+			self.info(format!(
+				"Partition {} ({}): {} inodes used, {} free",
+				partition.num, mp, used, free
+			));
+			if let Some(min) = self.min_free_inodes
+				&& free < min
+			{
+				bail!(
+					"Partition {} ({}) has only {} free inodes left, less than {}.\nPlease enlarge the partition, or create the filesystem with more inodes (e.g. with the -N or -i option of mke2fs).",
+					partition.num,
+					mp,
+					free,
+					min
+				);
 			}
 		}
 		Ok(())
 	}
 
+	/// Log the usage of the partitions mounted in `rootdir`, warning about the ones above `usage_warn_threshold` and failing if any of them is above `usage_fail_threshold`.
+	fn check_disk_usage(&self, rootdir: &Path) -> Result<Vec<DiskUsage>> {
+		let mut usage = Vec::new();
+		for partition in self.device.mountable_partitions() {
+			let Some(mp) = &partition.mountpoint else {
+				continue;
+			};
+			let (size, used, free) = filesystem_usage(rootdir.join(mp.trim_start_matches('/')))?;
+			usage.push(DiskUsage {
+				num: partition.num,
+				mountpoint: mp.clone(),
+				filesystem: *self.partition_filesystem(partition),
+				size,
+				used,
+				free,
+			});
+		}
+		self.info(format!(
+			"Usage of the partitions:\n{}",
+			disk_usage_table(&usage)
+		));
+		let mut full = Vec::new();
+		for x in &usage {
+			let percent = x.percent();
+			if let Some(threshold) = self.usage_fail_threshold
+				&& percent > threshold as u64
+			{
+				full.push(format!(
+					"Partition {} ({}) is {}% full, more than {}%",
+					x.num, x.mountpoint, percent, threshold
+				));
+			} else if percent > self.usage_warn_threshold as u64 {
+				self.warn(format!(
+					"Partition {} ({}) is {}% full, consider enlarging it.",
+					x.num, x.mountpoint, percent
+				));
+			}
+		}
+		if !full.is_empty() {
+			bail!(
+				"{}.\nPlease enlarge the partitions in the device specification.",
+				full.join(".\n")
+			);
+		}
+		Ok(usage)
+	}
+
+	/// Make sure the root filesystem of the image can be shrunk, if `--shrink` is given.
+	pub fn check_shrink(&self) -> Result<()> {
+		if !self.shrink {
+			return Ok(());
+		}
+		if self.format == &OutputFormat::Tarball {
+			self.warn(
+				"--shrink only applies to raw images, ignoring it for the root filesystem tarball.",
+			);
+			return Ok(());
+		}
+		let root = self
+			.device
+			.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Rootfs)
+			.context("Unable to find a root filesystem")?;
+		check_shrinkable(self.partition_filesystem(root)).context(format!(
+			"Unable to shrink the images of device '{}'",
+			self.device.id
+		))?;
+		if root.size_in_sectors != 0 {
+			bail!(
+				"Unable to shrink the images of device '{}': the root partition {} must take the rest of the disk (size_in_sectors = 0), to be expanded during the first boot",
+				self.device.id,
+				root.num
+			);
+		}
+		Ok(())
+	}
+
+	/// Shrink the unmounted root filesystem on partition `root_dev_num` of `loop_dev_path`, if `--shrink` is given.
+	///
+	/// Btrfs is mounted on a directory in `mountdir_base` meanwhile.
+	fn shrink_rootfs(
+		&self,
+		loop_dev_path: &Path,
+		root_dev_num: u32,
+		mountdir_base: &Path,
+	) -> Result<Option<ShrunkPartition>> {
+		if !self.shrink {
+			return Ok(None);
+		}
+		let root = self
+			.device
+			.partitions
+			.iter()
+			.find(|p| p.num == root_dev_num)
+			.context("Unable to find a root filesystem")?;
+		let dev = partition_path(loop_dev_path, root_dev_num);
+		let sector_size = gptman::linux::get_sector_size(&mut File::open(loop_dev_path)?)?;
+		self.info("Shrinking the root filesystem ...");
+		let fs_size = shrink_filesystem(
+			&dev,
+			self.partition_filesystem(root),
+			&mountdir_base.join("shrink"),
+		)?;
+		self.info(format!(
+			"Root filesystem shrunk to {} MiB.",
+			fs_size / 1048576
+		));
+		Ok(Some(ShrunkPartition {
+			num: root_dev_num,
+			fs_size,
+			sector_size,
+		}))
+	}
+
+	/// Remove the machine ID and the SSH host keys from the system at `rootdir`, unless the device or `--no-scrub` keeps them.
+	fn scrub_identities(&self, rootdir: &Path) -> Result<()> {
+		if !self.device.scrub || self.no_scrub {
+			self.info("Keeping the machine ID and the SSH host keys.");
+			return Ok(());
+		}
+		self.info("Removing the machine ID and the SSH host keys ...");
+		let scrubbed = scrub::scrub_identities(rootdir)?;
+		if scrubbed.is_empty() {
+			self.info("Nothing to remove.");
+		}
+		for path in scrubbed {
+			self.info(format!("Removed /{}", path.display()));
+		}
+		Ok(())
+	}
+
+	/// Sync and unmount the filesystems in `stack`, the last mounted first.
+	///
+	/// Everything is written to the underlying devices when it returns.
+	pub(crate) fn umount_stack(stack: &mut Vec<PathBuf>) -> Result<()> {
+		let start = Instant::now();
+		let count = stack.len();
+		// Mountpoints sharing the same filesystem are synced once.
+		let mut synced = HashSet::new();
+		for p in stack.iter().rev() {
+			let dev = fs::metadata(p)
+				.context(format!("Unable to stat {}", p.display()))?
+				.dev();
+			if synced.insert(dev) {
+				debug!("Syncing filesystem {} ...", p.display());
+				sync_filesystem(p)?;
+			}
+		}
+		while let Some(p) = stack.pop() {
+			debug!("Umounting {} ...", p.display());
+			unmount_busy_retrying(&p)?;
+		}
+		info!(
+			"Unmounted {} filesystems in {:.03} seconds.",
+			count,
+			start.elapsed().as_secs_f32()
+		);
+		Ok(())
+	}
+
 	fn setup_chroot_mounts<P: AsRef<Path>>(
 		&self,
 		rootdir: P,
-		stack: &mut Vec<String>,
+		stack: &mut Vec<PathBuf>,
 	) -> Result<()> {
 		let rootdir = rootdir.as_ref();
 		let dst = rootdir.join("tmp");
 		debug!("Mounting tmpfs to {} ...", &dst.display());
 		let mount = Mount::builder().fstype("tmpfs");
 		mount.mount("tmpfs", &dst)?;
-		stack.push(dst.to_string_lossy().to_string());
+		stack.push(dst);
 		Ok(())
 	}
 
-	fn postinst_step<P: AsRef<Path>>(&self, rootdir: P, binds: &[&str]) -> Result<()> {
+	/// Make the mount of the root filesystem at `rootdir` read-only, except the directories `dirs` within it.
+	///
+	/// The directories are bind mounted onto themselves beforehand, which keeps them writable, as only the mount at `rootdir` is read-only and not the filesystem itself.
+	/// The partitions already mounted within `rootdir` stay writable too.
+	fn remount_rootfs_readonly(
+		&self,
+		rootdir: &Path,
+		dirs: &[&str],
+		stack: &mut Vec<PathBuf>,
+	) -> Result<()> {
+		for dir in dirs {
+			let path = rootdir.join(dir.trim_start_matches('/'));
+			create_dir_all(&path)?;
+			debug!("Keeping {} writable ...", path.display());
+			Mount::builder()
+				.flags(MountFlags::BIND)
+				.mount(&path, &path)
+				.context(format!("Unable to bind mount {}", path.display()))?;
+			stack.push(path);
+		}
+		debug!("Remounting {} read-only ...", rootdir.display());
+		Mount::builder()
+			.flags(MountFlags::BIND | MountFlags::REMOUNT | MountFlags::RDONLY)
+			.mount(rootdir, rootdir)
+			.context(format!("Unable to remount {} read-only", rootdir.display()))?;
+		Ok(())
+	}
+
+	fn postinst_step<P: AsRef<Path>>(
+		&self,
+		rootdir: P,
+		binds: &[&str],
+		pm_data: &PartitionMapData,
+	) -> Result<()> {
 		let rootdir = rootdir.as_ref();
-		self.info("Setting up the user and locale ...");
-		add_user(
-			rootdir,
-			&self.user,
-			&self.password,
-			Some("Default User"),
-			None,
-			None,
-		)?;
+		self.info("Setting up the users and locale ...");
+		// Use the shadow utilities of the build host if nothing else runs in the target.
+		let machine = self.requires_target_exec()?.then(|| self.machine_name());
+		if let Some(name) = self.default_user() {
+			let user =
+				UserSpec::default_user(name, &self.user_options.or(self.device.user_options()));
+			add_user(rootdir, machine.as_deref(), &user, Some(self.password))?;
+		}
+		for user in self.device.users.iter().flatten() {
+			self.info(format!("Creating user {} ...", user.name));
+			add_user(
+				rootdir,
+				machine.as_deref(),
+				user,
+				user.password_hash.as_deref(),
+			)?;
+		}
+		self.info(format!("Root account: {}", self.root_policy));
+		match self.root_policy {
+			RootPolicy::Locked => lock_password(rootdir, "root")?,
+			RootPolicy::PasswordHash(hash) => {
+				set_password(rootdir, machine.as_deref(), "root", hash)?
+			}
+		}
 		set_locale(rootdir, "en_US.UTF-8")?;
 		self.set_hostname(&rootdir)?;
+		self.write_kernel_cmdline(rootdir, pm_data)?;
+		self.setup_swap(rootdir)?;
 
-		let postinst_script_dir = self
-			.device
-			.file_path
-			.parent()
-			.context("Unable to find the directory containing the device spec")?;
-		let mut postinst_script_path = postinst_script_dir.join("postinst.bash");
-		if !postinst_script_path.is_file() {
-			postinst_script_path = postinst_script_dir.join("postinst.sh");
-		};
-		if !postinst_script_path.is_file() {
-			postinst_script_path = postinst_script_dir.join("postinst");
-		}
-		if postinst_script_path.is_file() {
-			self.info("Running post installation script ...");
-			debug!(
-				"Copying {} to {} ...",
-				&postinst_script_path.display(),
-				&rootdir.display()
-			);
-			let filename = postinst_script_path
-				.file_name()
-				.context("Unable to get the basename of the script")?;
-			let dst_path = &rootdir.join("tmp").join(filename);
-			std::fs::copy(&postinst_script_path, dst_path)
-				.context("Failed to copy the post installation script")?;
-			run_script_with_chroot(rootdir, &Path::new("/tmp").join(filename), binds, None)?;
-		} else {
+		let scripts = self.device.find_postinst_scripts(self.variant)?;
+		if scripts.is_empty() {
 			self.info("No postinst script found, skipping.");
 		}
+		for postinst_script_path in scripts {
+			self.run_postinst_script(rootdir, &postinst_script_path, binds)?;
+		}
 
 		Ok(())
 	}
 
-	fn compress_image<P: AsRef<Path>>(&self, from: P, to: P) -> Result<()> {
-		let from = from.as_ref();
-		let to = to.as_ref();
-		let from_fd = File::options().read(true).open(from)?;
-		let to_fd = File::options()
-			.write(true)
-			.create(true)
-			.truncate(true)
-			.open(to)?;
+	/// Set up the swapfile or zram of the device in the system at `rootdir`, see [`SwapSpec`].
+	fn setup_swap(&self, rootdir: &Path) -> Result<()> {
+		match &self.device.swap {
+			None => Ok(()),
+			Some(SwapSpec::File { .. }) if self.format == &OutputFormat::Tarball => {
+				self.warn(
+					"Swapfiles require a raw image, skipping it for the root filesystem tarball.",
+				);
+				Ok(())
+			}
+			Some(SwapSpec::File { size_mib }) => {
+				let rootfs = self
+					.device
+					.partitions
+					.iter()
+					.find(|x| x.usage == PartitionUsage::Rootfs)
+					.context("Unable to find a root filesystem")?;
+				self.info(format!("Creating the swapfile of {} MiB ...", size_mib));
+				create_swapfile(rootdir, *size_mib, self.partition_filesystem(rootfs))
+			}
+			Some(SwapSpec::Zram {
+				size_mib,
+				compression,
+			}) => {
+				self.info(format!("Configuring zram of {} MiB ...", size_mib));
+				write_zram_generator_conf(rootdir, *size_mib, compression.as_deref())
+			}
+		}
+	}
 
-		let num_cpus = num_cpus::get().clamp(1, 32) as u32;
+	/// Copy the post installation script at `path` into `/tmp` of the target system at `rootdir`, and run it.
+	pub fn run_postinst_script(&self, rootdir: &Path, path: &Path, binds: &[&str]) -> Result<()> {
+		let filename = path
+			.file_name()
+			.context("Unable to get the basename of the script")?;
+		self.info(format!(
+			"Running post installation script {} ...",
+			filename.to_string_lossy()
+		));
+		debug!("Copying {} to {} ...", &path.display(), &rootdir.display());
+		let dst_path = &rootdir.join("tmp").join(filename);
+		copy_preserving(path, dst_path).context("Failed to copy the post installation script")?;
+		run_script_with_chroot(
+			rootdir,
+			&self.machine_name(),
+			&Path::new("/tmp").join(filename),
+			binds,
+			None,
+		)
+	}
+
+	/// Write the kernel command line to `/etc/kernel/cmdline`, if it is defined in the device spec.
+	fn write_kernel_cmdline(&self, rootdir: &Path, pm_data: &PartitionMapData) -> Result<()> {
+		if self.device.kernel_cmdline.is_none() {
+			return Ok(());
+		}
+		let cmdline = self.kernel_cmdline(pm_data)?;
+		self.info(format!("Kernel command line: {}", &cmdline));
+		let dir = rootdir.join("etc/kernel");
+		create_dir_all(&dir).context("Failed to create /etc/kernel")?;
+		fs::write(dir.join("cmdline"), format!("{}\n", cmdline))
+			.context("Failed to write /etc/kernel/cmdline")?;
+		Ok(())
+	}
 
-		let start: Instant;
-		let duration: Duration;
+	/// Enable and disable the units listed in the device spec.
+	///
+	/// `systemctl --root` of the host is used, so the target systemd does not have to run under emulation.
+	fn setup_services<P: AsRef<Path>>(&self, rootdir: P) -> Result<()> {
+		let rootdir = rootdir.as_ref();
+		for (verb, units) in [
+			("disable", &self.device.disable_services),
+			("enable", &self.device.enable_services),
+		] {
+			let Some(units) = units else {
+				continue;
+			};
+			if units.is_empty() {
+				continue;
+			}
+			let units: Vec<String> = units.iter().map(|x| normalize_unit_name(x)).collect();
+			if let Some(unit) = units.iter().find(|x| find_unit_file(rootdir, x).is_none()) {
+				bail!(
+					"Unable to {} unit '{}': not found in the target system",
+					verb,
+					unit
+				);
+			}
+			self.info(format!(
+				"Running systemctl {} {} ...",
+				verb,
+				units.join(" ")
+			));
+			let mut cmd = Command::new("systemctl");
+			cmd.arg("--root")
+				.arg(rootdir)
+				.arg("--no-reload")
+				.arg(verb)
+				.arg("--")
+				.args(&units);
+			cmd_run_check_status(&mut cmd)?;
+		}
+		Ok(())
+	}
 
+	fn compress_image<P: AsRef<Path>>(&self, from: P, to: P) -> Result<()> {
+		let from = from.as_ref();
+		let to = to.as_ref();
 		match &self.compress {
 			Compression::None => {
 				self.info(format!(
@@ -284,69 +711,79 @@ impl ImageContext<'_> {
 					&self.compress
 				));
 				if self.compress != &Compression::Gzip {
-					self.info(format!("Using {} threads for compression", num_cpus));
+					self.info(format!(
+						"Using {} threads for compression",
+						get_compression_threads()
+					));
+				} else {
+					self.warn(
+						"Caution! GZip does not support multi-threading. Compression will be very slow.",
+					);
 				}
 			}
 		}
-		match &self.compress {
-			Compression::Xz => {
-				let mut bufreader = BufReader::with_capacity(1048576, from_fd);
-				let mut xz_filter = xz2::stream::Filters::new();
-				let mut xz_options = xz2::stream::LzmaOptions::new_preset(9)?;
-				xz_options.nice_len(273);
-				xz_filter.lzma2(&xz_options);
-				let encoder = xz2::stream::MtStreamBuilder::new()
-					.filters(xz_filter)
-					.threads(num_cpus)
-					.block_size(1048576)
-					.check(xz2::stream::Check::Crc32)
-					.encoder()?;
-				let mut writer = xz2::write::XzEncoder::new_stream(to_fd, encoder);
-				start = Instant::now();
-				copy(&mut bufreader, &mut writer)?;
-				writer.finish()?.flush()?;
-				duration = start.elapsed();
-			}
-			Compression::Zstd => {
-				// zstd::stream::copy_encode(from_fd, to_fd, 9)?;
-				let mut bufreader = BufReader::with_capacity(1048576, from_fd);
-				let mut writer = zstd::stream::Encoder::new(to_fd, 9)?;
-				writer.multithread(num_cpus)?;
-				start = Instant::now();
-				copy(&mut bufreader, &mut writer)?;
-				writer.finish()?.flush()?;
-				duration = start.elapsed();
-			}
-			Compression::Gzip => {
-				self.warn(
-					"Caution! GZip does not support multi-threading. Compression will be very slow.",
-				);
-				let bufreader = BufReader::with_capacity(1048576, from_fd);
-				let mut encoder =
-					flate2::bufread::GzEncoder::new(bufreader, flate2::Compression::new(9));
-				let mut bufwriter = BufWriter::with_capacity(1048576, to_fd);
-				start = Instant::now();
-				copy(&mut encoder, &mut bufwriter)?;
-				duration = start.elapsed();
-			}
-			Compression::None => {
-				// Using std::fs::copy.
-				self.info("No compression specified, copying file directly.");
-				// Close the files first.
-				drop(from_fd);
-				drop(to_fd);
-				std::fs::copy(from, to)?;
-				self.info("Done copying the raw image.");
-				return Ok(());
-			}
-		}
+		let filename = path_str(Path::new(
+			to.file_name().context("Output image has no file name")?,
+		))?;
+		let outdir = to
+			.parent()
+			.context("Output image has no parent directory")?;
+		// Written under a temporary name, so a partial image is never mistaken for a complete one.
+		let tmp = outdir.join(format!(".{}.part", filename));
+		let result = compress_file(from, &tmp, self.compress, None)?;
+		fs::rename(&tmp, to).context(format!("Unable to save {}", to.display()))?;
 		self.info(format!(
 			"Compression finished in {:.2} seconds.",
-			duration.as_secs_f64()
+			result.duration.as_secs_f64()
+		));
+		self.info(format!(
+			"SHA256 of the uncompressed image: {}",
+			result.input_sha256
 		));
+		update_sha256sums(outdir, filename, &result.sha256)?;
 		Ok(())
 	}
 
+	/// Save the uncompressed image at `from` next to the output with `--keep-raw`, hard-linked if possible.
+	fn keep_uncompressed(&self, from: &Path) -> Result<()> {
+		if !self.keep_raw {
+			return Ok(());
+		}
+		if self.compress == &Compression::None {
+			self.info("The output is not compressed, not keeping another copy of it.");
+			return Ok(());
+		}
+		let to = self.uncompressed_output_path();
+		self.info(format!(
+			"Saving the uncompressed image to {} ...",
+			to.display()
+		));
+		let filename = to
+			.file_name()
+			.context("Uncompressed image has no file name")?
+			.to_string_lossy();
+		// Written under a temporary name, like the compressed output.
+		let tmp = to.with_file_name(format!(".{}.part", filename));
+		if tmp.symlink_metadata().is_ok() {
+			fs::remove_file(&tmp)?;
+		}
+		match fs::hard_link(from, &tmp) {
+			Ok(()) => (),
+			// On another filesystem than the working directory.
+			Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+				cmd_run_check_status(
+					Command::new("cp")
+						.arg("--sparse=always")
+						.arg("--")
+						.arg(from)
+						.arg(&tmp),
+				)?;
+			}
+			Err(e) => return Err(e).context(format!("Unable to link {}", tmp.display())),
+		}
+		fs::rename(&tmp, &to).context(format!("Unable to save {}", to.display()))
+	}
+
 	fn save_topics(&self, rootdir: &dyn AsRef<Path>) -> Result<()> {
 		if self.device.distro != Distro::AOSC {
 			bail!("Topic is available for AOSC only.");
@@ -354,53 +791,814 @@ impl ImageContext<'_> {
 		if let Some(topics) = &self.topics {
 			self.info("Saving topics ...");
 			save_topics(rootdir.as_ref(), topics)?;
-			if !self.device.arch.is_native() && self.device.arch == DeviceArch::mips64r6el {
-				APT::upgrade_system(rootdir)?;
+			if aosc_uses_apt(&self.device.arch) {
+				APT::upgrade_system(rootdir, &self.machine_name())?;
 			} else {
-				Oma::upgrade_system(rootdir)?;
+				Oma::upgrade_system(rootdir, &self.machine_name())?;
 			}
 		}
 		Ok(())
 	}
 
-	pub fn execute(self, num: usize, len: usize) -> Result<()> {
+	/// Install the files needed by the steps in the container at `rootdir`, see [`crate::fixups`].
+	fn install_container_fixups(&self, rootdir: &Path) -> Result<ContainerFixups> {
+		self.info("Installing resolv.conf and policy-rc.d for the build ...");
+		ContainerFixups::install(rootdir, Path::new(HOST_RESOLV_CONF))
+	}
+
+	/// Restore the files replaced by [`Self::install_container_fixups`], after the last step in the container.
+	fn restore_container_fixups(&self, fixups: ContainerFixups) -> Result<()> {
+		self.info("Restoring resolv.conf and policy-rc.d ...");
+		fixups.restore()
+	}
+
+	/// Run the steps in the container at `rootdir`, then open the debug shell if `--debug-shell` is given.
+	///
+	/// The shell is opened even if the steps fail, before anything is cleaned up.
+	fn with_debug_shell(
+		&self,
+		rootdir: &Path,
+		binds: &[&str],
+		steps: impl FnOnce() -> Result<()>,
+	) -> Result<()> {
+		let result = steps();
+		if !self.debug_shell {
+			return result;
+		}
+		match &result {
+			Ok(_) => self.info("Entering the debug shell before finishing up ..."),
+			Err(e) => self.warn(format!(
+				"Build failed: {:#}\nEntering the debug shell before cleaning up ...",
+				e
+			)),
+		}
+		let shell_result = self.open_debug_shell(rootdir, binds);
+		match result {
+			Ok(_) => shell_result,
+			Err(e) => {
+				if let Err(shell_err) = shell_result {
+					self.warn(format!("{:#}", shell_err));
+				}
+				Err(e)
+			}
+		}
+	}
+
+	/// Open an interactive shell in the target system, and wait until it exits.
+	fn open_debug_shell(&self, rootdir: &Path, binds: &[&str]) -> Result<()> {
+		self.info("Exit the shell to continue.");
+		// Hand the whole terminal over to the shell.
+		restore_term();
+		let status = chroot_shell_command(rootdir, &self.machine_name(), binds)
+			.status()
+			.context("Failed to open the debug shell");
+		setup_scroll_region();
+		self.info(format!("Debug shell exited ({}), continuing ...", status?));
+		Ok(())
+	}
+
+	/// The package of the OOBE wizard installed in this image, `None` if the device does not enable it.
+	pub fn oobe_package(&self) -> Option<&str> {
+		if !self.device.oobe_wizard {
+			return None;
+		}
+		self.oobe_package
+			.as_deref()
+			.or(self.device.oobe_package(self.variant))
+	}
+
+	/// The packages installed in this image: the BSP packages, then the ones of the OOBE wizard and devena.
+	pub fn packages(&self) -> Vec<String> {
+		let mut packages = self.device.bsp_packages.clone();
+		packages.extend(self.oobe_package().map(|x| x.to_owned()));
+		packages.extend(
+			self.device
+				.devena_firstboot_target
+				.as_ref()
+				.map(|x| format!("devena-firstboot-{}", x)),
+		);
+		packages
+	}
+
+	/// Install the [packages](Self::packages) of this image into `rootdir`.
+	fn install_image_packages(&self, rootdir: &Path) -> Result<()> {
+		if let Some(package) = self.oobe_package() {
+			self.info(format!("OOBE wizard: {}", package));
+		}
+		let pkgs = self.packages();
+		// Eh we have to "convert" Vec<String> to Vec<&str>.
+		let pkgs = pkgs.iter().map(String::as_str).collect::<Vec<&str>>();
+		self.install_packages(pkgs.as_slice(), rootdir)
+	}
+
+	/// Name of the built-in user, `None` if the device skips it or the OOBE wizard creates the users.
+	pub fn default_user(&self) -> Option<&str> {
+		(!self.device.skip_default_user && !self.device.oobe_wizard).then_some(self.user)
+	}
+
+	/// Names of all users created in the image, the built-in user first.
+	pub fn usernames(&self) -> Vec<&str> {
+		self.default_user()
+			.into_iter()
+			.chain(self.device.users.iter().flatten().map(|x| x.name.as_str()))
+			.collect()
+	}
+
+	/// Make sure the built-in user does not clash with the users of the device, and the device allows the root policy.
+	pub fn check_users(&self) -> Result<()> {
+		if !self.device.allow_root_login && self.root_policy != &RootPolicy::Locked {
+			bail!(
+				"Device '{}' does not allow root login, --root-password-hash can not be used",
+				self.device.id
+			);
+		}
+		if let Some(name) = self.default_user()
+			&& self.device.users.iter().flatten().any(|x| x.name == name)
+		{
+			bail!(
+				"User '{}' of device '{}' has the name of the built-in user, please choose another one with --user",
+				name,
+				self.device.id
+			);
+		}
+		Ok(())
+	}
+
+	/// The machine name of the containers of this build.
+	pub fn machine_name(&self) -> String {
+		nspawn_machine_name(
+			&self.device.id,
+			&self.variant.to_string().to_lowercase(),
+			std::process::id(),
+		)
+	}
+
+	/// The steps of this build which execute programs of the target, from the device spec and the build options.
+	pub fn target_exec_reasons(&self) -> Result<Vec<String>> {
+		let mut reasons = self.device.target_exec_reasons(self.variant)?;
+		if self
+			.additional_packages
+			.as_ref()
+			.is_some_and(|x| !x.is_empty())
+		{
+			reasons.push("additional packages are installed".to_owned());
+		}
+		if self.topics.is_some() {
+			reasons.push("the system is upgraded with topics".to_owned());
+		}
+		if self.debug_shell {
+			reasons.push("the debug shell is opened".to_owned());
+		}
+		Ok(reasons)
+	}
+
+	/// Whether building this image executes programs of the target, see `requires_target_exec` of [`DeviceSpec`].
+	///
+	/// Fails if the device spec claims otherwise.
+	pub fn requires_target_exec(&self) -> Result<bool> {
+		let reasons = self.target_exec_reasons()?;
+		match self.device.requires_target_exec {
+			Some(true) => Ok(true),
+			Some(false) if !reasons.is_empty() => bail!(
+				"Device '{}' sets requires_target_exec to false, but building its {} image executes programs of the target: {}",
+				&self.device.id,
+				self.variant.to_string().to_lowercase(),
+				reasons.join(", ")
+			),
+			_ => Ok(!reasons.is_empty()),
+		}
+	}
+
+	/// The loop device and all of its partitions, to be bind mounted into the container.
+	pub(crate) fn nspawn_binds(&self, loop_dev_path: &Path) -> Result<Vec<String>> {
+		let mut binds = vec![path_str(loop_dev_path)?.to_owned()];
+		for partition in &self.device.partitions {
+			binds.push(path_str(&partition_path(loop_dev_path, partition.num))?.to_owned());
+		}
+		Ok(binds)
+	}
+
+	/// The directories of the root filesystem written by the bootloaders of the device, or `None` if any of them may write anywhere, see [`crate::bootloader::BootloaderSpec::rootfs_writes`].
+	fn bootloaders_rootfs_writes(&self) -> Option<Vec<&str>> {
+		let mut dirs = Vec::new();
+		for bl in self.device.bootloaders.iter().flatten() {
+			dirs.extend(bl.rootfs_writes()?);
+		}
+		Some(dirs)
+	}
+
+	/// Apply the bootloaders to an existing raw image again, without rebuilding it.
+	///
+	/// The partition table is read back from the image and verified against the device spec first.
+	/// The root filesystem is mounted read-only except the directories the bootloaders write into, unless a bootloader script is applied.
+	/// Returns the path to the compressed image, if compression is requested.
+	pub fn rebootload(&self, image: &Path) -> Result<Option<PathBuf>> {
+		let root_dev_num = self
+			.device
+			.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Rootfs)
+			.context("Unable to find a root filesystem")?
+			.num;
+		let image = image
+			.canonicalize()
+			.context(format!("Unable to find the image '{}'", image.display()))?;
+		let workdir_base = self.workdir.join(format!(
+			"sketches/{}-rebootload{}",
+			&self.device.id,
+			self.run.sketch_suffix()
+		));
+		let mountdir_base = workdir_base.join("mnt");
+		self.create_sketch_dir(&workdir_base)?;
+		create_dir_all(&mountdir_base)?;
+		let _log = self.start_build_log(&workdir_base)?;
+		let mut mountpoint_stack: Vec<PathBuf> = Vec::new();
+
+		self.info(format!("Attaching {} ...", image.display()));
+		let loop_dev = attach_loop_device(&image, self.loop_attempts)?;
+		let loop_dev_path = loop_dev
+			.path()
+			.context("Unable to get the path of the loop device")?;
+		let result = (|| -> Result<()> {
+			let nums: Vec<u32> = self.device.partitions.iter().map(|p| p.num).collect();
+			wait_for_partitions(&loop_dev_path, &nums)?;
+			self.info("Reading the partition table back from the image ...");
+			let pm_data = self
+				.read_partition_map(&loop_dev_path)
+				.context("The image does not match the device spec")?;
+			let binds = self.nspawn_binds(&loop_dev_path)?;
+			let binds = binds.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+			let rootpart_dev = partition_path(&loop_dev_path, root_dev_num);
+			self.info("Mounting partitions ...");
+			self.mount_partitions(&loop_dev_path, &mountdir_base, &mut mountpoint_stack)?;
+			let rootfs_mount = mountdir_base
+				.join(format!("p{}", root_dev_num))
+				.canonicalize()?;
+			self.mount_partitions_in_root(&loop_dev_path, &rootfs_mount, &mut mountpoint_stack)?;
+			// Bootloader scripts may write anywhere, e.g. /etc/default/grub.
+			let writable = self.bootloaders_rootfs_writes();
+			if let Some(dirs) = &writable {
+				self.info("Mounting the root filesystem read-only ...");
+				self.remount_rootfs_readonly(&rootfs_mount, dirs, &mut mountpoint_stack)?;
+			}
+			self.setup_chroot_mounts(&rootfs_mount, &mut mountpoint_stack)?;
+			self.write_spec_script(&loop_dev_path, &rootpart_dev, &rootfs_mount, &pm_data)?;
+			// Nothing is installed by the bootloaders writing only into their own directories.
+			let fixups = match writable {
+				Some(_) => None,
+				None => Some(self.install_container_fixups(&rootfs_mount)?),
+			};
+			self.apply_bootloaders(
+				&rootfs_mount,
+				&loop_dev_path,
+				&workdir_base,
+				&binds,
+				&pm_data,
+			)?;
+			if let Some(fixups) = fixups {
+				self.restore_container_fixups(fixups)?;
+			}
+			self.stage_secureboot(&rootfs_mount)?;
+			self.verify_secureboot(&rootfs_mount)
+		})();
+		self.info("Unmounting filesystems ...");
+		let umount_result = ImageContext::<'_>::umount_stack(&mut mountpoint_stack);
+		self.info("Detaching the loop device ...");
+		loop_dev.detach()?;
+		result?;
+		umount_result?;
+		sync_filesystem(&image)?;
+		if self.compress == &Compression::None {
+			return Ok(None);
+		}
+		let mut outfile = image.as_os_str().to_owned();
+		outfile.push(self.compress.get_extension());
+		let outfile = PathBuf::from(outfile);
+		self.compress_image(&image, &outfile)?;
+		Ok(Some(outfile))
+	}
+
+	/// Run `steps` on a copy of the existing image, and save it to `outfile` as `revision`, see [`crate::patch`].
+	///
+	/// The copy is decompressed into the working directory, and compressed with [`Self::compress`] afterwards.
+	/// Sparse images are converted to raw images for patching, and back afterwards, see [`crate::simg`].
+	pub fn patch(
+		&self,
+		image: &Path,
+		steps: &[PatchStep],
+		outfile: &Path,
+		revision: u32,
+	) -> Result<()> {
+		let root_dev_num = self
+			.device
+			.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Rootfs)
+			.context("Unable to find a root filesystem")?
+			.num;
+		let workdir_base = self.workdir.join(format!(
+			"sketches/{}-patch{}",
+			&self.device.id,
+			self.run.sketch_suffix()
+		));
+		let mountdir_base = workdir_base.join("mnt");
+		self.create_sketch_dir(&workdir_base)?;
+		create_dir_all(&mountdir_base)?;
+		let _log = self.start_build_log(&workdir_base)?;
+		let mut mountpoint_stack: Vec<PathBuf> = Vec::new();
+
+		let rawimg = workdir_base.join("patch.img");
+		self.info(format!(
+			"Decompressing {} to {} ...",
+			image.display(),
+			rawimg.display()
+		));
+		decompress_file(image, &rawimg)?;
+		let simg = is_simg(&rawimg)?;
+		if simg {
+			let simg_path = rawimg.with_extension("simg");
+			fs::rename(&rawimg, &simg_path)?;
+			self.info("Converting the sparse image back to a raw image ...");
+			decode_file(&simg_path, &rawimg)?;
+			fs::remove_file(&simg_path)?;
+		}
+		let loop_dev = attach_loop_device(&rawimg, self.loop_attempts)?;
+		let loop_dev_path = loop_dev
+			.path()
+			.context("Unable to get the path of the loop device")?;
+		let result = (|| -> Result<()> {
+			let nums: Vec<u32> = self.device.partitions.iter().map(|p| p.num).collect();
+			wait_for_partitions(&loop_dev_path, &nums)?;
+			self.info("Reading the partition table back from the image ...");
+			let pm_data = self
+				.read_partition_map(&loop_dev_path)
+				.context("The image does not match the device spec")?;
+			let binds = self.nspawn_binds(&loop_dev_path)?;
+			let binds = binds.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+			let rootpart_dev = partition_path(&loop_dev_path, root_dev_num);
+			self.info("Mounting partitions ...");
+			self.mount_partitions(&loop_dev_path, &mountdir_base, &mut mountpoint_stack)?;
+			let mountdir_base = mountdir_base.canonicalize()?;
+			let rootfs_mount = mountdir_base.join(format!("p{}", root_dev_num));
+			self.mount_partitions_in_root(&loop_dev_path, &rootfs_mount, &mut mountpoint_stack)?;
+			self.setup_chroot_mounts(&rootfs_mount, &mut mountpoint_stack)?;
+			self.write_spec_script(&loop_dev_path, &rootpart_dev, &rootfs_mount, &pm_data)?;
+			let device_spec_dir = self
+				.device
+				.file_path
+				.parent()
+				.context("Failed to reach the directory containing the device spec file")?;
+			let fixups = self.install_container_fixups(&rootfs_mount)?;
+			for step in steps {
+				self.info(format!("Running step {} ...", step));
+				match step {
+					PatchStep::Bootloader(index) => self.apply_selected_bootloaders(
+						&rootfs_mount,
+						&loop_dev_path,
+						&workdir_base,
+						&binds,
+						&pm_data,
+						Some(&[*index]),
+					)?,
+					PatchStep::File { partition, path } => {
+						let spec = self
+							.device
+							.partitions
+							.iter()
+							.find(|p| p.num == *partition)
+							.context(format!("Unable to find partition {}", partition))?;
+						let content = spec
+							.content
+							.as_ref()
+							.context(format!("Partition {} has no content", partition))?;
+						let mut command = content.copy_file_command(
+							device_spec_dir,
+							&spec.filesystem,
+							&mountdir_base.join(format!("p{}", partition)),
+							path,
+						);
+						cmd_run_check_status(&mut command).context(format!(
+							"Failed to copy '{}' to partition {}",
+							path.display(),
+							partition
+						))?;
+					}
+					PatchStep::Postinst(name) => self.run_postinst_script(
+						&rootfs_mount,
+						&device_spec_dir.join(name),
+						&binds,
+					)?,
+				}
+			}
+			self.restore_container_fixups(fixups)?;
+			// The bootloaders may replace the fallback boot loader.
+			if steps.iter().any(|x| matches!(x, PatchStep::Bootloader(_))) {
+				self.stage_secureboot(&rootfs_mount)?;
+			}
+			record_patch(
+				&rootfs_mount,
+				PatchRecord {
+					time: self
+						.run
+						.timestamp
+						.to_rfc3339_opts(SecondsFormat::Secs, true),
+					tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+					source: path_str(image.file_name().context("Invalid image path")?.as_ref())?
+						.to_owned(),
+					revision,
+					steps: steps.iter().map(|x| x.to_string()).collect(),
+				},
+			)?;
+			self.verify_extra_sources(&rootfs_mount)?;
+			self.verify_secureboot(&rootfs_mount)
+		})();
+		self.info("Unmounting filesystems ...");
+		let umount_result = ImageContext::<'_>::umount_stack(&mut mountpoint_stack);
+		self.info("Detaching the loop device ...");
+		loop_dev.detach()?;
+		result?;
+		umount_result?;
+		sync_filesystem(&rawimg)?;
+		if simg {
+			let simg_path = rawimg.with_extension("simg");
+			self.info("Converting the patched image to a sparse image again ...");
+			encode_file(&rawimg, &simg_path)?;
+			self.compress_image(simg_path.as_path(), outfile)?;
+			fs::remove_file(&simg_path)?;
+		} else {
+			self.compress_image(rawimg.as_path(), outfile)?;
+		}
+		fs::remove_file(&rawimg)?;
+		Ok(())
+	}
+
+	/// The sketch directory of this image, containing the raw image (or the root filesystem) and the mount points.
+	///
+	/// Each run has its own sketch directories, named with [`BuildRun::sketch_suffix`], e.g. `sketches/rpi-5b-Base-1a2b3c4d`.
+	/// With `--resume`, the most recent one of the image is used instead if there is any.
+	pub fn sketch_dir(&self) -> PathBuf {
+		let base = if self.format == &OutputFormat::Tarball {
+			format!("{}-{}-rootfs", &self.device.id, &self.variant)
+		} else {
+			format!("{}-{}", &self.device.id, &self.variant)
+		};
+		let sketches = self.workdir.join("sketches");
+		if self.resume
+			&& let Some(dir) = latest_sketch(&sketches, &base)
+		{
+			return dir;
+		}
+		sketches.join(format!("{}{}", base, self.run.sketch_suffix()))
+	}
+
+	/// Create the sketch directory `dir`, and point `sketches/latest` to it.
+	fn create_sketch_dir(&self, dir: &Path) -> Result<()> {
+		debug!(
+			"Creating directory '{}' and all of its parents ...",
+			dir.display()
+		);
+		create_dir_all(dir)?;
+		link_latest_sketch(dir)
+	}
+
+	/// Path to the build log of the job using `sketch_dir`.
+	fn build_log_path(&self, sketch_dir: &Path) -> Result<PathBuf> {
+		Ok(match self.log_dir {
+			Some(dir) => {
+				let mut name = sketch_dir
+					.file_name()
+					.context("Invalid sketch directory")?
+					.to_owned();
+				name.push(".log");
+				dir.join(name)
+			}
+			None => sketch_dir.join("build.log"),
+		})
+	}
+
+	/// Path to the build log of this image.
+	pub fn log_path(&self) -> Result<PathBuf> {
+		self.build_log_path(&self.sketch_dir())
+	}
+
+	/// The entry of this image in the summary of the build run, with nothing recorded but `status`.
+	pub fn run_entry(&self, status: RunStatus) -> RunEntry {
+		RunEntry {
+			device: self.device.id.clone(),
+			variant: self.variant.to_string().to_lowercase(),
+			status,
+			name: self.filename.clone(),
+			output: None,
+			secs: 0.0,
+			log: None,
+			reason: None,
+		}
+	}
+
+	/// Start the build log of the job using `sketch_dir`, see [`BuildLog`].
+	fn start_build_log(&self, sketch_dir: &Path) -> Result<BuildLog> {
+		let path = self.build_log_path(sketch_dir)?;
+		self.info(format!(
+			"Logging the output of the containers to {}",
+			path.display()
+		));
+		BuildLog::start(path)
+	}
+
+	/// The directory containing the output of this image.
+	///
+	/// Follows the directory hierarchy of AOSC OS releases, unless the layout is flat.
+	pub fn output_dir(&self) -> PathBuf {
+		let kind = match self.format {
+			OutputFormat::Rawimg => "rawimg",
+			OutputFormat::Tarball => "rootfs",
+			OutputFormat::Simg => "simg",
+		};
+		match self.output_layout {
+			OutputLayout::Hierarchy => self.outdir.join(format!(
+				"os-{}/{}/{}/{}",
+				&self.device.arch.to_string().to_lowercase(),
+				&self.variant.to_string().to_lowercase(),
+				kind,
+				&self.device.vendor
+			)),
+			OutputLayout::Flat => self.outdir.to_owned(),
+		}
+	}
+
+	/// The path of the output of this image.
+	pub fn output_path(&self) -> PathBuf {
+		self.output_dir().join(&self.filename)
+	}
+
+	/// The name of the output without the extension of the compression.
+	fn uncompressed_filename(&self) -> &str {
+		self.filename
+			.strip_suffix(self.compress.get_extension())
+			.unwrap_or(&self.filename)
+	}
+
+	/// Where the uncompressed image is saved with `--keep-raw`, next to the output.
+	pub fn uncompressed_output_path(&self) -> PathBuf {
+		self.output_dir().join(self.uncompressed_filename())
+	}
+
+	/// Where the block map of the raw image is saved, named after the image with `.bmap` in place of the extension of the compression.
+	pub fn bmap_path(&self) -> PathBuf {
+		self.output_dir()
+			.join(format!("{}.bmap", self.uncompressed_filename()))
+	}
+
+	/// Build a tarball of the root filesystem, without partitioning an image.
+	fn execute_tarball(&self, draw_progressbar: impl Fn(&str)) -> Result<()> {
+		let workdir_base = self.sketch_dir();
+		let outdir_base = self.output_dir();
+		let outfile_path = self.output_path();
+		let rootfs = workdir_base.join("rootfs");
+		let tarball_path = workdir_base.join("rootfs.tar");
+		let mut mountpoint_stack: Vec<PathBuf> = Vec::new();
+		// No partitions exist, the data is only used to fill in the defined variables.
+		let pm_data = PartitionMapData {
+			uuid: String::new(),
+			data: Default::default(),
+		};
+
+		self.info(format!(
+			"Root filesystem tarball:\n\t\"{}\" ({}) - {}",
+			&self.device.name, &self.device.id, &self.variant
+		));
+		self.info(format!("Output file:\n\t{}", &self.filename));
+		self.info("Initializing root filesystem ...");
+		draw_progressbar("Initializing root filesystem");
+		if rootfs.exists() {
+			self.warn("Root filesystem already exists in the workbench - removing it first.");
+			fs::remove_dir_all(&rootfs)?;
+		}
+		self.create_sketch_dir(&workdir_base)?;
+		create_dir_all(&rootfs)?;
+		create_dir_all(&outdir_base)?;
+		if let Some(hooks) = &self.device.hooks
+			&& hooks.iter().any(|h| h.stage.runs_on_host())
+		{
+			self.warn("Hooks running on the build host are skipped for root filesystem tarballs.");
+		}
+		if self.device.partitions.iter().any(|p| p.content.is_some()) {
+			self.warn("Contents of the partitions are skipped for root filesystem tarballs.");
+		}
+
+		self.info("Installing system distribution ...");
+		draw_progressbar("Installing base distribution");
+		rsync_sysroot(&self.base_dist, &rootfs)?;
+		self.generate_fstab(&pm_data, &rootfs)?;
+		self.write_override_marker(&rootfs)?;
+
+		self.info("Setting up bind mounts ...");
+		self.setup_chroot_mounts(&rootfs, &mut mountpoint_stack)?;
+		self.write_spec_script(&"", &"", &rootfs, &pm_data)?;
+		let fixups = self.install_container_fixups(&rootfs)?;
+
+		let hook_env = HookEnv {
+			loopdev: Path::new(""),
+			sketch_dir: &workdir_base,
+			mountdir: &rootfs,
+			binds: &[],
+		};
+		self.with_debug_shell(&rootfs, &[], || {
+			self.write_extra_sources(&rootfs)?;
+			self.run_hooks(HookStage::PostRootfs, &hook_env, &pm_data, Some(&rootfs))?;
+
+			self.begin_defer_triggers(&rootfs)?;
+			self.save_topics(&rootfs)?;
+
+			self.info("Installing BSP packages ...");
+			draw_progressbar("Installing packages");
+			self.install_image_packages(&rootfs)?;
+
+			self.setup_services(&rootfs)?;
+
+			self.info("Running post installation step ...");
+			draw_progressbar("Post installation step");
+			self.postinst_step(&rootfs, &[], &pm_data)?;
+			self.finish_defer_triggers(&rootfs)?;
+			self.remove_extra_sources(&rootfs)?;
+			self.clamp_timestamps(&rootfs)
+		})
+		.inspect_err(|_| self.abort_defer_triggers(&rootfs))?;
+
+		if self
+			.device
+			.bootloaders
+			.as_ref()
+			.is_some_and(|x| !x.is_empty())
+		{
+			self.warn(
+				"Bootloaders require a raw image, skipping them for the root filesystem tarball.",
+			);
+		}
+		if self.device.efi_secureboot.is_some() {
+			self.warn(
+				"Secure Boot requires a raw image, skipping it for the root filesystem tarball.",
+			);
+		}
+		self.run_hooks(HookStage::PreCompress, &hook_env, &pm_data, Some(&rootfs))?;
+		self.restore_container_fixups(fixups)?;
+		self.verify_extra_sources(&rootfs)?;
+		self.scrub_identities(&rootfs)?;
+
+		self.info("Finishing up ...");
+		draw_progressbar("Finishing up");
+		ImageContext::<'_>::umount_stack(&mut mountpoint_stack)?;
+		create_tarball(&rootfs, &tarball_path)?;
+		draw_progressbar("Compressing image");
+		self.compress_image(&tarball_path, &outfile_path)?;
+		self.keep_uncompressed(&tarball_path)?;
+		restore_term();
+		sync_filesystem(&tarball_path)?;
+		info!("Done! root filesystem tarball finished.");
+		Ok(())
+	}
+
+	/// Build the image, returning the timings of its stages and the output recorded in the build report.
+	pub fn execute(&self, num: usize, len: usize) -> Result<(BuildTimings, Option<ReportEntry>)> {
+		let start = Instant::now();
+		// The beginning of each stage, for the timings of the build.
+		let marks = RefCell::new(Vec::new());
 		let draw_progressbar = |content: &str| {
-			// we don't want to screw up the terminal.
-			let size = termsize::get().unwrap_or(Size { rows: 25, cols: 80 });
-			eprint!("\x1b7\x1b[{};0f\x1b[42m\x1b[0K\x1b[2K", size.rows);
-			eprint!(
-				"\x1b[30m[{}/{}] {} ({:?}): {}",
+			marks
+				.borrow_mut()
+				.push((content.to_owned(), Instant::now()));
+			draw_progressbar(&format!(
+				"[{}/{}] {} ({:?}): {}",
 				num, len, &self.device.id, &self.variant, content
-			);
-			eprint!("\x1b8");
+			));
 		};
 
 		// Set up the scroll region for progressbar.
 		setup_scroll_region();
+		self.info(format!(
+			"Building {} in run {} ...",
+			&self.filename, self.run.id
+		));
+
+		let _log = self.start_build_log(&self.sketch_dir())?;
+		let disk_usage = if self.format == &OutputFormat::Tarball {
+			self.execute_tarball(draw_progressbar)?;
+			Vec::new()
+		} else {
+			self.execute_rawimg(draw_progressbar)?
+		};
+		let timings = BuildTimings::from_marks(start, &marks.into_inner(), Instant::now());
+		self.record_timings(timings.clone());
+		let output = self.record_report(disk_usage);
+		Ok((timings, output))
+	}
+
+	/// Record the output of this build in the build report of the output directory, returning the recorded entry.
+	fn record_report(&self, disk_usage: Vec<DiskUsage>) -> Option<ReportEntry> {
+		let output = self.output_path();
+		let result = (|| -> Result<ReportEntry> {
+			let entry = ReportEntry {
+				file: path_str(output.strip_prefix(self.outdir).unwrap_or(&output))?.to_owned(),
+				format: format!("{:?}", self.format).to_lowercase(),
+				compression: format!("{:?}", self.compress).to_lowercase(),
+				size: fs::metadata(&output)?.len(),
+				run: self.run.id.to_string(),
+				bootstrap: load_hashes(&self.base_dist)?.unwrap_or_default(),
+				root_account: self.root_policy.to_string(),
+				oobe_package: self.oobe_package().map(|x| x.to_owned()),
+				disk_usage,
+			};
+			let mut report = BuildReport::load(self.outdir)?;
+			report.record(&self.device.id, self.variant, entry.clone());
+			report.save(self.outdir)?;
+			Ok(entry)
+		})();
+		match result {
+			Ok(entry) => Some(entry),
+			Err(e) => {
+				self.warn(format!("Unable to record the build report: {:?}", e));
+				None
+			}
+		}
+	}
+
+	/// Save the timings of this build for estimating the next builds.
+	fn record_timings(&self, timings: BuildTimings) {
+		let key = timing_key(self.device, self.variant, self.format, self.compress);
+		let result = TimingStore::load(self.workdir).and_then(|mut store| {
+			store.record(key, timings);
+			store.save(self.workdir)
+		});
+		if let Err(e) = result {
+			self.warn(format!("Unable to record the build timings: {:?}", e));
+		}
+	}
+
+	/// Unmount the filesystems and detach the loop devices a failed build left behind in its sketch directory.
+	///
+	/// The raw image and the build state are kept, so the build can still be resumed.
+	pub fn release_leftovers(&self) -> Result<()> {
+		let sketch_dir = self.sketch_dir();
+		if !sketch_dir.is_dir() {
+			return Ok(());
+		}
+		release_sketch(&sketch_dir)
+	}
+
+	/// Get the state of the interrupted build in `sketch_dir` to resume, releasing the raw image `rawimg` from it.
+	///
+	/// Returns `None` if there's nothing to resume, thus the build has to start over.
+	fn resumable_state(
+		&self,
+		sketch_dir: &Path,
+		rawimg: &Path,
+		size: u64,
+	) -> Result<Option<BuildState>> {
+		let state = match BuildState::load(sketch_dir) {
+			Ok(state) => state,
+			Err(e) => {
+				self.warn(format!("{:#}", e));
+				None
+			}
+		};
+		let Some(state) = state.filter(|s| s.completed.is_some()) else {
+			self.warn("No interrupted build to resume, starting over.");
+			return Ok(None);
+		};
+		let image_size = rawimg.metadata().map(|m| m.len()).ok();
+		if state.image_size != size || image_size != Some(size) {
+			self.warn(format!(
+				"The raw image does not match the device spec ({} bytes), starting over.",
+				size
+			));
+			return Ok(None);
+		}
+		// The filesystems and the loop devices are left behind by the interrupted build.
+		self.release_leftovers()?;
+		self.info(format!(
+			"Resuming the interrupted build, {:?} is the last completed stage.",
+			state.completed.unwrap()
+		));
+		Ok(Some(state))
+	}
 
+	/// Build the raw image, returning the usage of its partitions.
+	fn execute_rawimg(&self, draw_progressbar: impl Fn(&str)) -> Result<Vec<DiskUsage>> {
 		// Various paths being used
 		// The path which used specifically for this task
 		// Contains the raw image and the mount points
-		let workdir_base = self
-			.workdir
-			.join(format!("sketches/{}-{}", &self.device.id, &self.variant));
+		let workdir_base = self.sketch_dir();
 		// The path containing the output
-		// Follows the directory hierarchy of AOSC OS releases
-		let outdir_base = self.outdir.join(format!(
-			"os-{}/{}/rawimg/{}",
-			&self.device.arch.to_string().to_lowercase(),
-			&self.variant.to_string().to_lowercase(),
-			&self.device.vendor
-		));
+		let outdir_base = self.output_dir();
 		// The full path to the output file
-		let outfile_path = outdir_base.join(&self.filename);
+		let outfile_path = self.output_path();
 		// Base directory for temporary mount points
 		let mountdir_base = workdir_base.join("mnt");
 		// Total image size
-		let size = self.device.size.get_variant_size(self.variant) * (1 << 20);
+		let size = self.device.size.get_variant_size_bytes(self.variant)?;
 		// A stack which remembers all of the active mountpoints
 		// These mountpoints must be umounted before this function ends!
-		let mut mountpoint_stack: Vec<String> = Vec::new();
+		let mut mountpoint_stack: Vec<PathBuf> = Vec::new();
 		// The index of the partition which contains the root filesystem, in the partition table.
 		let mut root_dev_num = None;
 		for p in &self.device.partitions {
@@ -423,12 +1621,7 @@ impl ImageContext<'_> {
 
 		self.info("Initializing image ...");
 		draw_progressbar("Initializing image");
-		// Create workdir_base and all its parents.
-		debug!(
-			"Creating directory '{}' and all of its parents ...",
-			&workdir_base.display()
-		);
-		create_dir_all(&workdir_base)?;
+		self.create_sketch_dir(&workdir_base)?;
 		// Create outdir_base and all its parents.
 		debug!(
 			"Creating directory '{}' and all of its parents ...",
@@ -437,20 +1630,34 @@ impl ImageContext<'_> {
 		create_dir_all(&outdir_base)?;
 		create_dir_all(&mountdir_base)?;
 		let rawimg_path = workdir_base.join("rawmedia.img");
-		if rawimg_path.is_file() {
-			self.warn("Raw image file already exists in the workbench - removing it first.");
-			std::fs::remove_file(&rawimg_path)?;
-		}
-		create_sparse_file(&rawimg_path, size)?;
+		let resumed = if self.resume {
+			self.resumable_state(&workdir_base, &rawimg_path, size)?
+		} else {
+			None
+		};
+		let mut state = match resumed {
+			Some(state) => state,
+			None => {
+				if rawimg_path.is_file() {
+					if !release_loop_devices(&rawimg_path, self.force_detach)? {
+						bail!(
+							"Raw image file {} in the workbench is still attached to a loop device",
+							rawimg_path.display()
+						);
+					}
+					self.warn(
+						"Raw image file already exists in the workbench - removing it first.",
+					);
+					std::fs::remove_file(&rawimg_path)?;
+				}
+				BuildState::remove(&workdir_base)?;
+				create_sparse_file(&rawimg_path, size)?;
+				BuildState::new(size)
+			}
+		};
 
 		// Attach to a loop device
-		debug!("Getting fd on /dev/loop-control ...");
-		let loop_ctl = LoopControl::open()?;
-		debug!("Finding available loop device ...");
-		let loop_dev = loop_ctl
-			.next_free()
-			.context("No available loop device found")?;
-		loop_dev.attach_file(&rawimg_path)?;
+		let loop_dev = attach_loop_device(&rawimg_path, self.loop_attempts)?;
 		let loop_dev_path = loop_dev
 			.path()
 			.context("Unable to get the path of the loop device")?;
@@ -460,13 +1667,37 @@ impl ImageContext<'_> {
 			&loop_dev_path.display()
 		);
 
-		self.info("Creating partitions ...");
-		let mut pm_data = self
-			.partition_image(&loop_dev_path)
-			.context("Failed to partition the image")?;
+		let hook_env = HookEnv {
+			loopdev: &loop_dev_path,
+			sketch_dir: &workdir_base,
+			mountdir: &mountdir_base,
+			binds: &[],
+		};
+		let mut pm_data = if state.done(BuildStage::Partitioned) {
+			let nums: Vec<u32> = self.device.partitions.iter().map(|p| p.num).collect();
+			wait_for_partitions(&loop_dev_path, &nums)?;
+			state
+				.pm_data
+				.clone()
+				.context("No partition map data is recorded")?
+		} else {
+			self.info("Creating partitions ...");
+			let pm_data = self
+				.partition_image(&loop_dev_path)
+				.context("Failed to partition the image")?;
+			self.run_hooks(HookStage::PostPartition, &hook_env, &pm_data, None)?;
+			state.pm_data = Some(pm_data.clone());
+			state.complete(BuildStage::Partitioned, &workdir_base)?;
+			pm_data
+		};
 
-		self.info("Formating partitions ...");
-		self.format_partitions(&loop_dev_path, &mut pm_data)?;
+		if !state.done(BuildStage::Formatted) {
+			self.info("Formating partitions ...");
+			self.format_partitions(&loop_dev_path, &mut pm_data)?;
+			self.run_hooks(HookStage::PostFormat, &hook_env, &pm_data, None)?;
+			state.pm_data = Some(pm_data.clone());
+			state.complete(BuildStage::Formatted, &workdir_base)?;
+		}
 
 		// Bind mounts to be passed to systemd-nspawn(1).
 		// Switching to systemd-nspawn completely eliminates /dev,
@@ -477,20 +1708,12 @@ impl ImageContext<'_> {
 		// We can not bind them beforehand, the only option is to
 		// pass `--bind bind1 --bind bind2 ...` to the nspawn
 		// command line.
-		let mut binds = Vec::new();
-		binds.push(loop_dev_path.to_string_lossy().to_string());
-		for partition in &self.device.partitions {
-			binds.push(format!(
-				"{}p{}",
-				loop_dev_path.to_string_lossy(),
-				partition.num
-			));
-		}
+		let binds = self.nspawn_binds(&loop_dev_path)?;
 		let binds = binds.iter().map(|x| x.as_str()).collect::<Vec<_>>();
 		let binds = binds.as_slice();
 
 		// The path to the block device which contains the root filesystem.
-		let rootpart_dev = format!("{}p{}", &loop_dev_path.to_string_lossy(), root_dev_num);
+		let rootpart_dev = partition_path(&loop_dev_path, root_dev_num);
 		self.info("Mounting partitions ...");
 		self.mount_partitions(&loop_dev_path, &mountdir_base, &mut mountpoint_stack)?;
 		let rootfs_mount = mountdir_base
@@ -499,48 +1722,141 @@ impl ImageContext<'_> {
 			.context("Failed to canonicalize the path of root filesystem mountpoint")?;
 		debug!("Root filesystem mountpoint: {:?}", rootfs_mount);
 
-		self.info("Installing system distribution ...");
-		draw_progressbar("Installing base distribution");
-		rsync_sysroot(&self.base_dist, &rootfs_mount)?;
-		self.mount_partitions_in_root(&loop_dev_path, &rootfs_mount, &mut mountpoint_stack)?;
-		self.info("Generating fstab ...");
-		self.generate_fstab(&pm_data, &rootfs_mount)?;
+		if state.done(BuildStage::RootfsInstalled) {
+			self.mount_partitions_in_root(&loop_dev_path, &rootfs_mount, &mut mountpoint_stack)?;
+		} else {
+			// Nothing is mounted in the root filesystem yet, which would shadow the content.
+			self.populate_partitions(&mountdir_base)?;
+			self.run_hooks(HookStage::PreRootfs, &hook_env, &pm_data, None)?;
+
+			self.check_rootfs_size(&rootfs_mount)?;
+			self.info("Installing system distribution ...");
+			draw_progressbar("Installing base distribution");
+			rsync_sysroot(&self.base_dist, &rootfs_mount)?;
+			self.mount_partitions_in_root(&loop_dev_path, &rootfs_mount, &mut mountpoint_stack)?;
+			self.info("Generating fstab ...");
+			self.generate_fstab(&pm_data, &rootfs_mount)?;
+			self.write_override_marker(&rootfs_mount)?;
+			state.complete(BuildStage::RootfsInstalled, &workdir_base)?;
+		}
 
 		self.info("Setting up bind mounts ...");
 		self.setup_chroot_mounts(&rootfs_mount, &mut mountpoint_stack)?;
 
 		self.write_spec_script(&loop_dev_path, &rootpart_dev, &rootfs_mount, &pm_data)?;
 
-		self.save_topics(&rootfs_mount)?;
+		let container_hook_env = HookEnv { binds, ..hook_env };
+		let fixups = self.install_container_fixups(&rootfs_mount)?;
+		self.with_debug_shell(&rootfs_mount, binds, || {
+			// Written again if a resumed build continues from the post installation step, as it is removed when a step fails.
+			if !state.done(BuildStage::PostinstDone) {
+				self.begin_defer_triggers(&rootfs_mount)?;
+			}
+			if !state.done(BuildStage::PackagesInstalled) {
+				self.write_extra_sources(&rootfs_mount)?;
+				self.run_hooks(
+					HookStage::PostRootfs,
+					&container_hook_env,
+					&pm_data,
+					Some(&rootfs_mount),
+				)?;
 
-		self.info("Installing BSP packages ...");
-		draw_progressbar("Installing packages");
-		// Eh we have to "convert" Vec<String> to Vec<&str>.
-		let pkgs = &self
-			.device
-			.bsp_packages
-			.iter()
-			.map(String::as_str)
-			.collect::<Vec<&str>>();
-		self.install_packages(pkgs.as_slice(), &rootfs_mount)?;
+				self.save_topics(&rootfs_mount)?;
+
+				self.info("Installing BSP packages ...");
+				draw_progressbar("Installing packages");
+				self.install_image_packages(&rootfs_mount)?;
 
-		self.info("Running post installation step ...");
-		draw_progressbar("Post installation step");
-		self.postinst_step(&rootfs_mount, binds)?;
+				self.setup_services(&rootfs_mount)?;
+				state.complete(BuildStage::PackagesInstalled, &workdir_base)?;
+			}
 
-		self.apply_bootloaders(&rootfs_mount, &loop_dev_path, binds)?;
+			if !state.done(BuildStage::PostinstDone) {
+				self.info("Running post installation step ...");
+				draw_progressbar("Post installation step");
+				self.postinst_step(&rootfs_mount, binds, &pm_data)?;
+				self.finish_defer_triggers(&rootfs_mount)?;
+				self.remove_extra_sources(&rootfs_mount)?;
+				self.clamp_timestamps(&rootfs_mount)?;
+				state.complete(BuildStage::PostinstDone, &workdir_base)?;
+			}
+
+			if !state.done(BuildStage::BootloadersApplied) {
+				draw_progressbar("Applying bootloaders");
+				self.apply_bootloaders(
+					&rootfs_mount,
+					&loop_dev_path,
+					&workdir_base,
+					binds,
+					&pm_data,
+				)?;
+				self.stage_secureboot(&rootfs_mount)?;
+				state.complete(BuildStage::BootloadersApplied, &workdir_base)?;
+			}
+			Ok(())
+		})
+		.inspect_err(|_| self.abort_defer_triggers(&rootfs_mount))?;
+		self.run_hooks(
+			HookStage::PreCompress,
+			&container_hook_env,
+			&pm_data,
+			Some(&rootfs_mount),
+		)?;
+		self.restore_container_fixups(fixups)?;
+
+		self.verify_extra_sources(&rootfs_mount)?;
+		self.verify_secureboot(&rootfs_mount)?;
+		self.check_free_inodes(&rootfs_mount)?;
+		self.scrub_identities(&rootfs_mount)?;
+		let disk_usage = self.check_disk_usage(&rootfs_mount)?;
 
 		self.info("Finishing up ...");
 		draw_progressbar("Finishing up");
 		self.info("Unmounting filesystems ...");
 		ImageContext::<'_>::umount_stack(&mut mountpoint_stack)?;
+		let shrunk = self.shrink_rootfs(&loop_dev_path, root_dev_num, &mountdir_base)?;
 		self.info("Detaching the loop device ...");
 		loop_dev.detach()?;
-		// fs::remove_file(rawimg_path)?;
-		self.compress_image(&rawimg_path, &outfile_path)?;
+		if let Some(shrunk) = shrunk {
+			let len = shrink_partition_table(&rawimg_path, &self.device.partition_map, &shrunk)?;
+			self.info(format!("Raw image truncated to {} MiB.", len / 1048576));
+		}
+		// bmaptool can not write sparse images.
+		if !self.no_bmap && self.format == &OutputFormat::Rawimg {
+			let bmap = self.bmap_path();
+			self.info(format!("Generating the block map {} ...", bmap.display()));
+			write_bmap(&rawimg_path, &bmap)?;
+		}
+		if self.format == &OutputFormat::Simg {
+			let simg_path = rawimg_path.with_extension("simg");
+			self.info(format!(
+				"Converting the raw image to the sparse image {} ...",
+				simg_path.display()
+			));
+			let header = encode_file(&rawimg_path, &simg_path)?;
+			self.info(format!(
+				"Sparse image of {} chunks written.",
+				header.total_chunks
+			));
+			draw_progressbar("Compressing image");
+			self.compress_image(&simg_path, &outfile_path)?;
+			self.keep_uncompressed(&simg_path)?;
+			fs::remove_file(&simg_path)?;
+		} else {
+			draw_progressbar("Compressing image");
+			self.compress_image(&rawimg_path, &outfile_path)?;
+			self.keep_uncompressed(&rawimg_path)?;
+		}
+		BuildState::remove(&workdir_base)?;
+		// Frees the space as soon as the output is saved, rather than on --cleanup.
+		self.info(format!(
+			"Removing the raw image {} ...",
+			rawimg_path.display()
+		));
+		fs::remove_file(&rawimg_path)?;
 		restore_term();
-		sync_filesystem(&rawimg_path)?;
+		sync_filesystem(&workdir_base)?;
 		info!("Done! image finished.");
-		Ok(())
+		Ok(disk_usage)
 	}
 }
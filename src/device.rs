@@ -41,33 +41,52 @@ use std::{
 	collections::HashMap,
 	ffi::OsStr,
 	fs::{self, File},
-	io::Write,
+	io::{Seek, SeekFrom, Write},
 	path::{Path, PathBuf},
+	sync::OnceLock,
 };
 
 use crate::{
-	bootloader::BootloaderSpec,
+	bootloader::{BootloaderSpec, FlashTarget, GRUB_BIOS_TARGETS, GRUB_EFI_TARGETS, PayloadSource},
+	cli::OutputFormat,
 	context::{ImageContext, ImageVariant},
 	filesystem::FilesystemType,
-	partition::{PartitionSpec, PartitionType, PartitionUsage},
+	hook::{HookSpec, check_hooks},
+	partition::{PartitionSpec, PartitionType, PartitionUsage, check_gpt_label, place_partitions},
 	pm::Distro,
+	schema::{UnknownField, unknown_fields},
+	secureboot::SecureBootSpec,
+	sources::{CHECK_MIRROR, VariantSources},
+	swap::SwapSpec,
+	user::{UserSpec, check_users},
+	utils::{
+		MBR_MAX_SECTORS, PLANNING_SECTOR_SIZE, UserOptions, check_unit_name, find_program,
+		get_fsuuid, is_contained_path, mib_to_bytes, normalize_path, partition_path, path_str,
+		sectors_to_bytes, shell_quote,
+	},
+	validate::{FieldClass, validate_kernel_cmdline, validate_user_options},
 };
 use anyhow::{Context, Result, bail};
 use clap::ValueEnum;
 use gptman::{GPT, GPTPartitionEntry};
-use log::debug;
+use log::{debug, warn};
 use mbrman::{CHS, MBR, MBRPartitionEntry};
 use serde::{Deserialize, Serialize};
+use strum::VariantArray;
 use uuid::Uuid;
 
-const FORBIDDEN_CHARS: &[char] = &['\'', '"', '\\', '/', '{', '}', '[', ']', '!', '`', '*', '&'];
+/// Fields identifying a device, which can not be changed by spec overrides.
+const IDENTITY_FIELDS: &[&str] = &["id", "vendor"];
+/// Where the override applied to the device spec is recorded in the target.
+pub(crate) const SPEC_OVERRIDE_MARKER: &str = "etc/mkrawimg/spec-override.toml";
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, strum::Display)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, strum::Display, ValueEnum)]
 #[serde(rename_all = "lowercase")]
 // It is strange to see MBR as Mbr, GPT as Gpt.
 #[allow(clippy::upper_case_acronyms)]
 pub enum PartitionMapType {
 	#[serde(alias = "dos")]
+	#[value(alias = "dos")]
 	MBR,
 	GPT,
 }
@@ -94,6 +113,11 @@ pub enum DeviceArch {
 	riscv64,
 	/// 64-Bit MIPS Release 6
 	mips64r6el,
+	// Retro architectures
+	/// 32-bit ARMv7 with hardware floating point (armv7hf)
+	armhf,
+	/// 32-bit ARMv6 with hardware floating point, e.g. Raspberry Pi 1 and Zero
+	armv6hf,
 }
 
 /// Device Specification
@@ -140,6 +164,17 @@ pub enum DeviceArch {
 /// alias = ["pi9", "pi9b"]
 /// ```
 ///
+/// `tags` - Device Tags (Optional)
+/// -------------------------------
+///
+/// A list of strings grouping devices, e.g. by SoC vendor or support tier. Unlike aliases, tags are shared by many devices. Tags follows the same naming restrictions.
+///
+/// Devices having some tags can be built or listed with `build-all --tag TAG` and `list --tag TAG`.
+///
+/// ```toml
+/// tags = ["rockchip", "tier1"]
+/// ```
+///
 /// `vendor` - Device Vendor
 /// ------------------------
 ///
@@ -211,6 +246,102 @@ pub enum DeviceArch {
 /// bsp_packages = ["linux+kernel+rpi64+rpi9", "rpi-firmware-boot"]
 /// ```
 ///
+/// `enable_services`, `disable_services` - Services to be enabled or disabled (Optional)
+/// -----------------------------------------------------------------------------------
+///
+/// Lists of systemd units to be enabled or disabled after the BSP packages are installed, before the post installation script runs.
+///
+/// The units are enabled with `systemctl --root`, which operates on the symlinks directly, so nothing runs under emulation.
+/// Unit names without a suffix are treated as services. Units not found in the target system fail the build.
+///
+/// ```toml
+/// enable_services = ["sshd", "NetworkManager.service", "serial-getty@ttyS0.service"]
+/// disable_services = ["systemd-networkd-wait-online"]
+/// ```
+///
+/// `user_groups`, `user_shell`, `user_uid` - The built-in user (Optional)
+/// ----------------------------------------------------------------------
+///
+/// The built-in user is always in the `audio`, `video`, `cdrom`, `plugdev`, `tty` and `wheel` groups, with `/bin/bash` as the shell and a UID picked by `useradd`.
+///
+/// - `user_groups`: Groups the user is also in, e.g. for access to the hardware. Groups which do not exist in the target system are created as system groups with `groupadd -r`.
+/// - `user_shell`: Absolute path to the login shell. It must be installed, e.g. by `bsp_packages`.
+/// - `user_uid`: UID of the user, e.g. to line up with NFS home directories.
+///
+/// They can be overridden for a build with `--user-groups`, `--user-shell` and `--user-uid`, see the [global options](crate::cli::Cmdline).
+///
+/// ```toml
+/// user_groups = ["gpio", "i2c", "dialout", "render"]
+/// user_shell = "/usr/bin/zsh"
+/// user_uid = 1000
+/// ```
+///
+/// `allow_root_login` - Root login (Optional)
+/// ------------------------------------------
+///
+/// The password of root is locked unless `--root-password-hash` is given, see the [global options](crate::cli::Cmdline).
+/// Set `allow_root_login = false` to refuse `--root-password-hash` for this device, so root always stays locked, e.g. for desktop images. Default is `true`.
+///
+/// ```toml
+/// allow_root_login = false
+/// ```
+///
+/// `scrub` - Removing the machine identities (Optional)
+/// -----------------------------------------------------
+///
+/// The machine ID and the SSH host keys generated while the packages are installed would be shared by every device flashed with the image.
+/// Right before the image is finished, `/etc/machine-id` is reset to `uninitialized`, and `/var/lib/dbus/machine-id` (unless it is a link), `/etc/ssh/ssh_host_*` and the journals in `/var/log/journal` are removed, so each device generates its own on the first boot.
+/// Set `scrub = false` to keep them, e.g. for a device which is never flashed more than once. `--no-scrub` of the `build` action does the same for a single build. Default is `true`.
+///
+/// ```toml
+/// scrub = false
+/// ```
+///
+/// `[[user]]`, `skip_default_user` - More users (Optional)
+/// ------------------------------------------------------
+///
+/// A list of users created after the built-in user, with their passwords as crypt(3) hashes. Refer to [`UserSpec`] for details.
+/// Set `skip_default_user = true` to not create the built-in user given on the command line at all, in which case at least one user must be defined.
+///
+/// ```toml
+/// skip_default_user = true
+///
+/// [[user]]
+/// name = "admin"
+/// password_hash = "$y$j9T$..."
+/// groups = ["wheel"]
+///
+/// [[user]]
+/// name = "operator"
+/// password_hash = "$y$j9T$..."
+/// groups = ["dialout"]
+/// create_home = false
+/// ```
+///
+/// `oobe_wizard`, `devena_firstboot_target` - First boot setup (Optional)
+/// ----------------------------------------------------------------------
+///
+/// Set `oobe_wizard = true` to install the OOBE (out-of-box experience) wizard, which creates the first user on the first boot: `aosc-os-oobe-gui` for the desktop variant, `aosc-os-oobe-cli` otherwise.
+/// The built-in user is not created then, and `[[user]]` can not be defined. Default is `false`.
+///
+/// `devena_firstboot_target` installs the package `devena-firstboot-<target>`, which sets up the device on the first boot.
+/// The target may only contain lowercase ASCII letters, digits and `-.+`, starting with a letter or a digit.
+///
+/// ```toml
+/// oobe_wizard = true
+/// devena_firstboot_target = "rpi"
+/// ```
+///
+/// The `oobe_package` table selects another wizard package for some of the variants, e.g. the command line wizard for a desktop signage box without an input device during the setup. The variants not listed install the default package.
+/// It can only be defined with `oobe_wizard = true`. `--oobe-package` of the `build` action overrides it for all variants.
+///
+/// ```toml
+/// oobe_wizard = true
+///
+/// [oobe_package]
+/// desktop = "aosc-os-oobe-cli"
+/// ```
+///
 /// `initrdless` -  Booting without Init Ramdisk (Optional)
 /// -------------------------------------------------------
 ///
@@ -224,22 +355,60 @@ pub enum DeviceArch {
 /// initrdless = true
 /// ```
 ///
+/// `defer_triggers` - Defer package triggers to the first boot (Optional)
+/// ---------------------------------------------------------------------
+///
+/// Running package triggers under QEMU user emulation takes quite a lot of time, especially for desktop images.
+///
+/// Default is `false`. If set to `true`, triggers are not processed while installing packages. Packages with pending triggers are recorded in `/etc/mkrawimg/deferred-triggers.toml`,
+/// and a oneshot service `mkrawimg-deferred-triggers.service` processes them on the first boot. Can also be enabled with `--defer-triggers` for a build.
+///
+/// Can not be used with `initrdless`, as such images may boot with a read-only root filesystem.
+///
+/// ```toml
+/// defer_triggers = true
+/// ```
+///
+/// `requires_target_exec` - Executing programs of the target (Optional)
+/// --------------------------------------------------------------------
+///
+/// Whether building the images executes programs of the target architecture, which requires `binfmt_misc` support and QEMU user emulation if the
+/// architecture differs from the build host. By default it is detected for each image, programs of the target are executed if:
+///
+/// - `bsp_packages` is not empty, `oobe_wizard` or `devena_firstboot_target` is set, or topics or additional packages are requested for the build.
+/// - Post installation scripts are found for the variant.
+/// - Hooks at the `post_rootfs` or `pre_compress` stages are defined.
+/// - `script`, `grub` or `systemd_boot` bootloaders are defined.
+/// - `--debug-shell` is given.
+///
+/// Otherwise the image is built in the "no-exec" mode: the default user is created with `useradd` and `chpasswd` of the build host, and `binfmt_misc` support is not checked.
+/// Bootstrapping a release always executes programs of the target, so the support is still checked if the release of the variant is not bootstrapped yet.
+///
+/// Set it to `true` to always require `binfmt_misc` support, e.g. if a bootloader payload is built from the target system in a way not listed above.
+/// If set to `false`, images which would execute programs of the target fail the check, instead of running into a missing interpreter in the middle of a build.
+///
+/// ```toml
+/// requires_target_exec = false
+/// ```
+///
 /// `kernel_cmdline` - Kernel command line (Optional)
 /// -------------------------------------------------
 ///
-/// List of strings representing the kernel command line. Can not contain white spaces, and cannot contain `root=` argument.
-///
-/// The `root=` command line is automatically generated using either `PARTUUID` or `UUID`, depending on whether the device boots without an initrd image.
+/// A string, or a list of strings representing the kernel command line. Can not contain newlines, and double quotes must be balanced.
 ///
+/// Unless the command line already contains a `root=` argument, it is automatically generated using either `PARTUUID` or `UUID`, depending on whether the device boots without an initrd image.
 /// The final kernel command line will be `root=` argument concatenated with rest of the arguments.
 ///
-/// If this field is defined, the post installation script and any bootloader scripts will be able to reference it with `$KERNEL_CMDLINE`.
+/// If this field is defined, the final command line is written to `/etc/kernel/cmdline` of the target before the post installation script runs.
+/// The post installation script and any bootloader scripts will be able to reference it with `$KERNEL_CMDLINE`, and it is used by the `systemd-boot` bootloader unless it defines its own.
 ///
 /// If you want to generate the kernel command line yourself with a script, please skip this field.
 ///
 /// ```toml
 /// # The final command line $KERNEL_CMDLINE:
 /// # "root=PARTUUID=01234567-89ab-cdef-0123-456789abcdef console=ttyS0,115200 console=tty0 rw fsck.repair=yes"
+/// kernel_cmdline = "console=ttyS0,115200 console=tty0 rw fsck.repair=yes"
+/// # Or as a list:
 /// kernel_cmdline = ["console=ttyS0,115200", "console=tty0", "rw", "fsck.repair=yes"]
 /// ```
 ///
@@ -268,8 +437,8 @@ pub enum DeviceArch {
 ///
 /// Possible values:
 ///
-/// - `mbr` or `dos`: MBR Partition Table. Can have up to 4 partitions.
-/// - `gpt`: GUID Partition Table. Can have up to 128 partitions. Most bootloaders supports GPT.
+/// - `mbr` or `dos`: MBR Partition Table. Can have up to 4 partitions, and address up to 2 TiB with 512-byte sectors.
+/// - `gpt`: GUID Partition Table. Can have up to 128 partitions. Most bootloaders supports GPT. Required for images larger than 2 TiB.
 ///
 /// ```toml
 /// partition_map = "gpt"
@@ -310,6 +479,59 @@ pub enum DeviceArch {
 /// fs_label = "AOSC OS"
 /// ```
 ///
+/// `layout` - Shared Partition Layout (Optional)
+/// ---------------------------------------------
+///
+/// Name of a partition layout shared by many devices, defined in `layouts/<name>.toml` in the [device registry].
+/// A layout file can only define `partition_map`, `num_partitions` and `[[partition]]`, in the same format as `device.toml`.
+///
+/// The layout is resolved before the device spec is checked. Fields defined in `device.toml` take precedence: partitions are matched by `num`, the matched ones are merged field by field, and others are appended.
+///
+/// ```toml
+/// layout = "raspberrypi-gpt"
+///
+/// # Only change the starting sector of the first partition.
+/// [[partition]]
+/// num = 1
+/// start_sector = 2048
+/// ```
+///
+/// `inherits` - Inherited Spec (Optional)
+/// ---------------------------------------
+///
+/// Path to a partial spec shared by many similar devices, relative to `device.toml`. The partial spec has the same structure as `device.toml`, and all fields are optional.
+/// It can inherit another partial spec in turn. The path must stay within the [device registry], which is the directory above the vendor-level directory, and cyclic inheritance is an error.
+///
+/// The inherited spec is resolved before the layout and the checks, and fields defined in `device.toml` take precedence:
+///
+/// - Tables are merged recursively.
+/// - Partitions are matched by `num`: matched partitions are merged field by field, others are appended.
+/// - `tags`, `bsp_packages`, `enable_services`, `disable_services`, `user_groups` and `postinst_scripts` are appended to the inherited lists. Set `<name>_replace = true` to replace them instead, e.g. `bsp_packages_replace = true`.
+/// - Other values (including other lists) replace the inherited ones.
+///
+/// Use `mkrawimg show` to see the resulting spec.
+///
+/// ```toml
+/// # rockchip/rock-5b/device.toml
+/// inherits = "../rk3588-common.toml"
+/// id = "rock-5b"
+/// name = "ROCK 5B"
+/// compatible = "radxa,rock-5b"
+/// # Appended to the BSP packages of rk3588-common.toml
+/// bsp_packages = ["u-boot-aosc-utils"]
+/// ```
+///
+/// `[swap]` - Swap space (Optional)
+/// --------------------------------
+///
+/// Swap partitions are not allowed, a swapfile on the root filesystem or zram can be set up instead. Refer to [`SwapSpec`] for details.
+///
+/// ```toml
+/// [swap]
+/// type = "file"
+/// size_mib = 1024
+/// ```
+///
 /// `[[bootloader]]` - List of Bootloaders to be embedded (Optional)
 /// ----------------------------------------------------------------
 ///
@@ -338,13 +560,15 @@ pub enum DeviceArch {
 /// 2. An OS image is created with specified size, and is attached to a loop device.
 /// 3. The image is partitioned.
 /// 4. Partitions with filesystem assigned to them is formatted.
-/// 5. Filesystems with a mountpoint will be mounted.
+/// 5. Filesystems with a mountpoint will be mounted, shallower mountpoints first.
 /// 6. The standard system distribution is installed to the target filesystem, and `/etc/fstab` is generated.
-/// 7. BSP packages is installed.
-/// 8. The [post-installation script](#post-installation) is run.
-/// 9. The [bootloaders] will be applied, if defined in the spec file.
+/// 7. The [extra package sources] are added, and BSP packages is installed.
+/// 8. The [post-installation script](#post-installation) is run, then the temporary package sources are removed.
+/// 9. The [bootloaders] will be applied, if defined in the spec file, then the [Secure Boot chain] is staged if enabled.
 /// 10. The image is unmounted, detached from the loop device, and is compressed to the output directory.
 ///
+/// [Hooks] can be run between these steps.
+///
 /// Post Installation
 /// =================
 ///
@@ -362,6 +586,16 @@ pub enum DeviceArch {
 /// - `postinst.sh`
 /// - `postinst` (The shebang is not interpreted, thus must be a shell script)
 ///
+/// A variant specific script named `postinst-<variant>.bash` or `postinst-<variant>.sh` (e.g. `postinst-desktop.bash`) is run in addition to the generic one.
+/// The generic script always runs first, then the variant specific one. Both scripts get the same environment.
+///
+/// By default the scripts are discovered from the directory containing `device.toml`. To make sure the scripts are not missing, list them in `postinst_scripts`,
+/// then only the listed scripts are used, and `check` reports missing ones:
+///
+/// ```toml
+/// postinst_scripts = ["postinst.bash", "postinst-desktop.bash", "postinst-server.bash"]
+/// ```
+///
 /// Available defined variables
 /// ---------------------------
 ///
@@ -369,10 +603,14 @@ pub enum DeviceArch {
 ///
 /// - `DEVICE_ID`: Device ID.
 /// - `DEVICE_COMPATIBLE`: `of_compatible` field defined in the device specification. Empty if not defined.
+/// - `DEVICE_VENDOR`, `DEVICE_NAME`: Vendor and name of the device.
+/// - `VARIANT`: The variant being built, one of `base`, `desktop` and `server`.
+/// - `ARCH`: Target architecture, e.g. `arm64`.
+/// - `IMAGE_SIZE_MIB`: Size of the image in MiB.
 /// - `LOOPDEV`: The loop device this OS image is attached on.
 /// - `NUM_PARTITIONS`: Number of the partitions.
 /// - `ROOTPART`: The index of the root partition.
-/// - `DISKLABEL`: Either `mbr` or `gpt`.
+/// - `PARTITION_MAP`, `DISKLABEL`: Either `mbr` or `gpt`.
 /// - `DISKUUID`: UUID of the partition table.
 ///
 ///    Either a 32-bit hexadecimal integer or an UUID (Same as the output of `blkid`).
@@ -383,8 +621,18 @@ pub enum DeviceArch {
 /// - `PARTx_FSUUID`: Filesystem UUID of the xth partition.
 ///
 ///   Same as the output of `blkid`, can be used directly with `root=UUID=` argument. Empty if this partition does not contain a filesystem.
+/// - `PARTx_MOUNTPOINT`: Mountpoint of the xth partition. Empty if not mounted.
+/// - `PARTx_FSTYPE`: Filesystem of the xth partition, e.g. `ext4`, `fat32` or `none`.
+/// - `PARTx_USAGE`: Usage of the xth partition, one of `boot`, `firmware`, `rootfs`, `swap`, `data` and `other`.
 /// - `BOOT_PARTUUID`, `BOOT_FSUUID`: Partition and Filesystem UUID for the boot partition, if one is found.
+/// - `FIRMWARE_PARTUUID`, `FIRMWARE_FSUUID`: Partition and Filesystem UUID for the firmware partition, if one is found.
 /// - `ROOT_PARTUUID`, `ROOT_FSUUID`: Partition and Filesystem UUID for the root partition.
+/// - `DEFAULT_USER`: Name of the built-in user, empty with `skip_default_user` or `oobe_wizard`.
+/// - `USERS`: Names of all users created in the image, the built-in user first, separated by spaces.
+/// - `OOBE_WIZARD`: `1` if the OOBE wizard is installed, `0` otherwise.
+/// - `DEVENA_TARGET`: `devena_firstboot_target`, empty if not defined.
+///
+/// When building a root filesystem tarball (`--format tarball`), `LOOPDEV`, `ROOTPART` and `DISKUUID` are empty, the partition and filesystem UUIDs are not defined, and `KERNEL_CMDLINE` does not contain the generated `root=` argument.
 ///
 /// Examples
 /// ========
@@ -393,6 +641,9 @@ pub enum DeviceArch {
 ///
 /// [device registry]: crate::registry::DeviceRegistry
 /// [bootloaders]: crate::bootloader::BootloaderSpec
+/// [Hooks]: crate::hook::HookSpec
+/// [extra package sources]: crate::sources::VariantSources
+/// [Secure Boot chain]: crate::secureboot::SecureBootSpec
 /// [bootloader scripts]: crate::bootloader::BootloaderSpec#usage
 #[derive(Clone, Debug, Deserialize)]
 #[allow(dead_code)]
@@ -401,6 +652,8 @@ pub struct DeviceSpec {
 	pub id: String,
 	/// Optional aliases to identify the exact device. Can be any combination of letters, digits, hyphen `"-"` and underscore (`"_"`).
 	pub aliases: Option<Vec<String>>,
+	/// Optional tags grouping the devices. Follow the same rules as the aliases.
+	pub tags: Option<Vec<String>>,
 	/// The distribution wich will be installed on this device.
 	///
 	/// Possible values:
@@ -421,6 +674,8 @@ pub struct DeviceSpec {
 	/// - `ppc64el`
 	/// - `riscv64`
 	/// - `mips64r6el`
+	/// - `armhf`
+	/// - `armv6hf`
 	pub arch: DeviceArch,
 	/// Vendor of the SoC platform, optional, currently not used.
 	/// The name must present in arch/$ARCH/boot/dts in the kernel tree.
@@ -444,6 +699,46 @@ pub struct DeviceSpec {
 	/// List of BSP packages to be installed.
 	/// Must be a list of valid package names, no checks are performed.
 	pub bsp_packages: Vec<String>,
+	/// Systemd units to be enabled after the BSP packages are installed.
+	pub enable_services: Option<Vec<String>>,
+	/// Systemd units to be disabled after the BSP packages are installed.
+	pub disable_services: Option<Vec<String>>,
+	/// Groups of the built-in user in addition to the default ones.
+	pub user_groups: Option<Vec<String>>,
+	/// Login shell of the built-in user.
+	pub user_shell: Option<String>,
+	/// UID of the built-in user.
+	pub user_uid: Option<u32>,
+	/// Whether the password of root can be set with `--root-password-hash`.
+	#[serde(default = "default_allow_root_login")]
+	pub allow_root_login: bool,
+	/// Whether to remove the machine ID and the SSH host keys before finishing up.
+	#[serde(default = "default_scrub")]
+	pub scrub: bool,
+	/// Whether to skip creating the built-in user, only creating `users`.
+	#[serde(default)]
+	pub skip_default_user: bool,
+	/// Whether to install the OOBE wizard, which creates the first user on the first boot.
+	#[serde(default)]
+	pub oobe_wizard: bool,
+	/// Packages of the OOBE wizard overriding the default ones, for some of the variants.
+	pub oobe_package: Option<OobePackages>,
+	/// Target of devena set up on the first boot, installed as the package `devena-firstboot-<target>`.
+	pub devena_firstboot_target: Option<String>,
+	/// Users created after the built-in user. Refer to [`UserSpec`] for details.
+	///
+	/// Due to how lists of objects are represented in TOML, the singular "user" is explicitly allowed.
+	///
+	/// ### Example
+	///
+	/// ```toml
+	/// [[user]]
+	/// name = "operator"
+	/// password_hash = "$y$j9T$..."
+	/// groups = ["dialout"]
+	/// ```
+	#[serde(alias = "user")]
+	pub users: Option<Vec<UserSpec>>,
 	/// Whether the device boots without an initrd image.
 	/// Useful for embedded systems (most of devices targeted by this
 	/// project are embedded systems, aren't they).
@@ -454,9 +749,16 @@ pub struct DeviceSpec {
 	///   device if initrd is not being used.
 	#[serde(default)]
 	pub initrdless: bool,
+	/// Whether to defer the package triggers to the first boot.
+	#[serde(default)]
+	pub defer_triggers: bool,
+	/// Whether building the images executes programs of the target. Detected for each image if not specified.
+	pub requires_target_exec: Option<bool>,
+	/// Post installation scripts to be used, instead of discovering them.
+	pub postinst_scripts: Option<Vec<String>>,
 	/// Kernel command line.
-	/// Must be a list of strings, and `root=` must not present in this list (it is automatically generated).
-	pub kernel_cmdline: Option<Vec<String>>,
+	/// Can be a string or a list of strings. `root=` is automatically generated if it is not present.
+	pub kernel_cmdline: Option<KernelCmdline>,
 	/// The partition map used for the image.
 	///
 	/// Possible values:
@@ -501,6 +803,10 @@ pub struct DeviceSpec {
 	// Can be `[[partition]]` to avoid awkwardness.
 	#[serde(alias = "partition")]
 	pub partitions: Vec<PartitionSpec>,
+	/// Name of the shared partition layout, already resolved into `partitions`.
+	pub layout: Option<String>,
+	/// Path to the partial spec this spec inherits, already merged.
+	pub inherits: Option<String>,
 	/// Actions to apply bootloaders. Refer to [`BootloaderSpec`] for details.
 	///
 	/// Due to how lists of objects are represented in TOML, the singular "bootloader" is explicitly allowed.
@@ -510,19 +816,81 @@ pub struct DeviceSpec {
 	/// ```toml
 	/// [[bootloader]]
 	/// type = "script"
-	/// script = "apply-bootloader.sh"
+	/// name = "apply-bootloader.sh"
 	///
 	/// [[bootloader]]
 	/// type = "script"
-	/// script = "apply-bootloader2.sh"
+	/// name = "apply-bootloader2.sh"
 	/// ```
 	#[serde(alias = "bootloader")]
 	pub bootloaders: Option<Vec<BootloaderSpec>>,
-	/// Path to the device.toml.
+	/// Scripts to be run at defined stages of the build. Refer to [`HookSpec`] for details.
+	///
+	/// ### Example
+	///
+	/// ```toml
+	/// [[hook]]
+	/// stage = "post_format"
+	/// script = "copy-firmware.sh"
+	/// ```
+	#[serde(alias = "hook")]
+	pub hooks: Option<Vec<HookSpec>>,
+	/// Extra package sources for each variant. Refer to [`VariantSources`] for details.
+	///
+	/// ### Example
+	///
+	/// ```toml
+	/// [sources]
+	/// desktop = [{ line = "deb {mirror} stable main partner", persist = true }]
+	/// ```
+	pub sources: Option<VariantSources>,
+	/// Stage shim, the signed GRUB and a MOK certificate for Secure Boot. Refer to [`SecureBootSpec`] for details.
+	///
+	/// ### Example
+	///
+	/// ```toml
+	/// [efi_secureboot]
+	/// certificate = "mok.der"
+	/// ```
+	pub efi_secureboot: Option<SecureBootSpec>,
+	/// The swapfile or zram. Refer to [`SwapSpec`] for details.
+	pub swap: Option<SwapSpec>,
+	/// Absolute path to the device.toml.
 	///
 	/// This field is ignored during deserialization, and is automatically filled.
+	/// Symbolic links are kept, so a device directory linked into the registry still finds the `layouts` directory of the registry.
 	#[serde(skip_deserializing)]
 	pub file_path: PathBuf,
+	/// Path to the partial spec merged on top of this device spec, if any.
+	///
+	/// This field is ignored during deserialization, and is filled by [`DeviceSpec::apply_override`].
+	#[serde(skip_deserializing)]
+	pub override_spec: Option<PathBuf>,
+	/// Fields defined in the spec files which are not known, usually typos.
+	///
+	/// This field is ignored during deserialization, and is filled by [`DeviceSpec::from_path`] and [`DeviceSpec::apply_override`].
+	#[serde(skip_deserializing)]
+	pub unknown_fields: Vec<UnknownField>,
+}
+
+/// Packages of the OOBE wizard for each variant, see `oobe_package` of [`DeviceSpec`].
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct OobePackages {
+	pub base: Option<String>,
+	pub desktop: Option<String>,
+	pub server: Option<String>,
+}
+
+impl OobePackages {
+	/// The package for `variant`, `None` if not listed.
+	pub fn get_variant_package(&self, variant: &ImageVariant) -> Option<&str> {
+		match variant {
+			ImageVariant::Base => self.base.as_deref(),
+			ImageVariant::Desktop => self.desktop.as_deref(),
+			ImageVariant::Server => self.server.as_deref(),
+		}
+	}
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -532,14 +900,58 @@ pub struct ImageVariantSizes {
 	pub server: u64,
 }
 
+/// Byte range of a partition in the image, as planned from the device spec.
+///
+/// The layout is computed with 512-byte sectors and 1MiB alignment, the same way partitions are created.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionExtent {
+	pub num: u32,
+	/// Offset of the first byte.
+	pub start: u64,
+	/// Offset after the last byte, `None` if the partition takes the rest of the image.
+	pub end: Option<u64>,
+}
+
+impl PartitionExtent {
+	/// Whether the byte range `[start, end)` overlaps this partition.
+	/// `end` being `None` means the range has an unknown length, thus only `start` is considered.
+	pub fn overlaps(&self, start: u64, end: Option<u64>) -> bool {
+		let end = end.unwrap_or(start + 1);
+		start < self.end.unwrap_or(u64::MAX) && end > self.start
+	}
+}
+
+/// Kernel command line defined in the device spec, either as a string or a list of arguments.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum KernelCmdline {
+	String(String),
+	List(Vec<String>),
+}
+
+impl std::fmt::Display for KernelCmdline {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::String(x) => write!(f, "{}", x.trim()),
+			Self::List(x) => write!(f, "{}", x.join(" ")),
+		}
+	}
+}
+
+/// Whether the kernel command line contains a `root=` argument.
+pub fn cmdline_has_root(cmdline: &str) -> bool {
+	cmdline.split_whitespace().any(|x| x.starts_with("root="))
+}
+
 #[allow(dead_code)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PartitionMapData {
 	pub uuid: String,
 	/// Data for each partition
 	pub data: HashMap<u32, PartitionData>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PartitionData {
 	pub num: u32,
 	pub part_uuid: String,
@@ -556,6 +968,14 @@ impl Default for ImageVariantSizes {
 	}
 }
 
+fn default_allow_root_login() -> bool {
+	true
+}
+
+fn default_scrub() -> bool {
+	true
+}
+
 impl DeviceSpec {
 	pub fn from_path(file: &Path) -> Result<Self> {
 		if file.file_name() != Some(OsStr::new("device.toml")) {
@@ -564,55 +984,373 @@ impl DeviceSpec {
 				file.display()
 			)
 		};
-		let content = fs::read_to_string(file)
-			.context(format!("Unable to read file '{}'", &file.to_string_lossy()))?;
-		let mut device: DeviceSpec = toml::from_str(&content).context(format!(
+		let table = read_spec_table(file)?;
+		// Warn before deserializing, a typo may be the cause of a missing field.
+		let unknown = unknown_fields(&table);
+		for f in &unknown {
+			warn!("{}: {}", file.display(), f);
+		}
+		let mut device: DeviceSpec = toml::Value::Table(table).try_into().context(format!(
 			"Unable to treat '{}' as an entry of the registry",
 			&file.to_string_lossy()
 		))?;
-		device.file_path = file.canonicalize()?;
+		device.file_path = std::path::absolute(file)?;
+		device.unknown_fields = unknown;
+		Ok(device)
+	}
+
+	/// Merge a partial spec on top of this device spec.
+	///
+	/// The partial spec uses the same structure as `device.toml`, all fields are optional:
+	///
+	/// - Tables are merged recursively.
+	/// - Partitions are matched by `num`: matched partitions are merged, others are appended.
+	/// - The lists appended to the inherited ones are appended to the original ones as well, unless `<name>_replace = true` is set.
+	/// - Other values (including other lists) replace the original ones.
+	///
+	/// The identity fields (`id`, `vendor`) can not be changed.
+	pub fn apply_override(&self, overlay_path: &Path) -> Result<Self> {
+		let mut base = read_spec_table(&self.file_path)?;
+		let overlay = read_override(overlay_path)?;
+		for key in IDENTITY_FIELDS {
+			if let Some(v) = overlay.get(*key)
+				&& base.get(*key) != Some(v)
+			{
+				bail!(
+					"Spec override tries to change the identity field '{}' of device '{}'",
+					key,
+					&self.id
+				);
+			}
+		}
+		let overlay_unknown = unknown_fields(&overlay);
+		for f in &overlay_unknown {
+			warn!("{}: {}", overlay_path.display(), f);
+		}
+		merge_partial_spec(&mut base, overlay, overlay_path)?;
+		let mut device: DeviceSpec = toml::Value::Table(base)
+			.try_into()
+			.context("Unable to apply the spec override")?;
+		device.file_path = self.file_path.clone();
+		device.override_spec = Some(overlay_path.canonicalize()?);
+		device.unknown_fields = [self.unknown_fields.clone(), overlay_unknown].concat();
 		Ok(device)
 	}
 
+	/// The raw spec this device is deserialized from in TOML, i.e. with the inherited specs, the layout and the spec override merged.
+	pub fn show(&self) -> Result<String> {
+		let mut table = read_spec_table(&self.file_path)?;
+		if let Some(overlay_path) = &self.override_spec {
+			merge_partial_spec(&mut table, read_override(overlay_path)?, overlay_path)?;
+		}
+		Ok(toml::to_string_pretty(&table)?)
+	}
+
+	/// Plan the byte ranges of all partitions without touching any image.
+	pub fn partition_extents(&self) -> Result<Vec<PartitionExtent>> {
+		const ALIGN: u64 = 1048576;
+		let mut extents: Vec<PartitionExtent> = Vec::new();
+		for partition in &self.partitions {
+			let size = sectors_to_bytes(partition.size_in_sectors, PLANNING_SECTOR_SIZE)
+				.context(format!("Partition {} is too large", partition.num))?;
+			let start = if let Some(start) = partition.start_sector {
+				sectors_to_bytes(start, PLANNING_SECTOR_SIZE)
+					.context(format!("Partition {} starts too far", partition.num))?
+			} else if partition.num == 1 {
+				ALIGN
+			} else {
+				// First fit, just like find_first_place() of the partition table crates.
+				let mut candidate = ALIGN;
+				while let Some(e) = extents.iter().find(|e| {
+					e.overlaps(
+						candidate,
+						// A saturated end overlaps everything behind, just like a fill-remaining partition.
+						(size != 0)
+							.then_some(candidate.saturating_add(size))
+							.or(Some(u64::MAX)),
+					)
+				}) {
+					let Some(end) = e.end else {
+						break;
+					};
+					candidate = end
+						.div_ceil(ALIGN)
+						.checked_mul(ALIGN)
+						.context(format!("No space left for partition {}", partition.num))?;
+				}
+				candidate
+			};
+			let end = match size {
+				0 => None,
+				size => Some(start.checked_add(size).context(format!(
+					"Partition {} ends beyond a 64-bit byte offset",
+					partition.num
+				))?),
+			};
+			extents.push(PartitionExtent {
+				num: partition.num,
+				start,
+				end,
+			});
+		}
+		Ok(extents)
+	}
+
+	/// Byte ranges of the partitions containing a filesystem.
+	pub fn formatted_partition_extents(&self) -> Result<Vec<PartitionExtent>> {
+		Ok(self
+			.partition_extents()?
+			.into_iter()
+			.filter(|e| {
+				self.partitions
+					.iter()
+					.any(|p| p.num == e.num && p.filesystem != FilesystemType::None)
+			})
+			.collect())
+	}
+
+	fn check_payload(
+		dirname: &Path,
+		path: &Path,
+		source: &PayloadSource,
+		sha256: &Option<String>,
+	) -> Result<()> {
+		if let Some(sha256) = sha256
+			&& (sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()))
+		{
+			bail!(
+				"Invalid SHA256 checksum for bootloader image '{}'",
+				path.display()
+			);
+		}
+		match source {
+			PayloadSource::Rootfs => (),
+			PayloadSource::DeviceDir => {
+				if !is_contained_path(path) {
+					bail!(
+						"Bootloader image '{}' must be a relative path within the directory of the device.toml, without '..' components",
+						path.display()
+					);
+				}
+				if !dirname.join(path).is_file() {
+					bail!(
+						"Bootloader image '{}' not found within the same directory as the device.toml",
+						path.display()
+					);
+				}
+			}
+			PayloadSource::Url => {
+				let url = path.to_string_lossy();
+				if !(url.starts_with("https://") || url.starts_with("http://")) {
+					bail!("'{}' is not a valid URL for a bootloader image", url);
+				}
+				if sha256.is_none() {
+					bail!(
+						"Bootloader image '{}' downloaded from an URL must have a SHA256 checksum",
+						url
+					);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Settings of the built-in user defined by the device.
+	pub fn user_options(&self) -> UserOptions {
+		UserOptions {
+			groups: self.user_groups.clone(),
+			shell: self.user_shell.clone(),
+			uid: self.user_uid,
+		}
+	}
+
+	/// Find the post installation scripts to run for `variant`, the generic one first.
+	pub fn find_postinst_scripts(&self, variant: &ImageVariant) -> Result<Vec<PathBuf>> {
+		let dirname = self
+			.file_path
+			.parent()
+			.context("Unable to find the directory containing the device spec")?;
+		let variant = variant.to_string().to_lowercase();
+		let generic = ["postinst.bash", "postinst.sh", "postinst"];
+		let specific = [
+			format!("postinst-{}.bash", variant),
+			format!("postinst-{}.sh", variant),
+		];
+		let mut result = Vec::new();
+		if let Some(scripts) = &self.postinst_scripts {
+			for group in [
+				generic.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
+				specific.to_vec(),
+			] {
+				let mut listed = scripts.iter().filter(|x| group.contains(x));
+				if let Some(name) = listed.next() {
+					if let Some(another) = listed.next() {
+						bail!(
+							"Both '{}' and '{}' are listed in postinst_scripts",
+							name,
+							another
+						);
+					}
+					let path = dirname.join(name);
+					if !path.is_file() {
+						bail!(
+							"Post installation script '{}' not found within the same directory as the device.toml",
+							name
+						);
+					}
+					result.push(path);
+				}
+			}
+			return Ok(result);
+		}
+		if let Some(path) = generic
+			.iter()
+			.map(|x| dirname.join(x))
+			.find(|x| x.is_file())
+		{
+			result.push(path);
+		}
+		if let Some(path) = specific
+			.iter()
+			.map(|x| dirname.join(x))
+			.find(|x| x.is_file())
+		{
+			result.push(path);
+		}
+		Ok(result)
+	}
+
+	/// The steps of the device spec which execute programs of the target while building `variant` images.
+	/// The package of the OOBE wizard for `variant`, `None` without `oobe_wizard`.
+	///
+	/// Unless selected by `oobe_package`, it is `aosc-os-oobe-gui` for the desktop variant and `aosc-os-oobe-cli` otherwise.
+	pub fn oobe_package(&self, variant: &ImageVariant) -> Option<&str> {
+		if !self.oobe_wizard {
+			return None;
+		}
+		let package = self
+			.oobe_package
+			.as_ref()
+			.and_then(|x| x.get_variant_package(variant));
+		Some(package.unwrap_or(match variant {
+			ImageVariant::Desktop => "aosc-os-oobe-gui",
+			_ => "aosc-os-oobe-cli",
+		}))
+	}
+
+	pub fn target_exec_reasons(&self, variant: &ImageVariant) -> Result<Vec<String>> {
+		let mut reasons = Vec::new();
+		if !self.bsp_packages.is_empty() {
+			reasons.push("BSP packages are installed".to_owned());
+		}
+		if let Some(package) = self.oobe_package(variant) {
+			reasons.push(format!("OOBE wizard {} is installed", package));
+		}
+		if let Some(target) = &self.devena_firstboot_target {
+			reasons.push(format!("devena-firstboot-{} is installed", target));
+		}
+		for script in self.find_postinst_scripts(variant)? {
+			reasons.push(format!(
+				"post installation script {} is run",
+				script.file_name().unwrap_or_default().to_string_lossy()
+			));
+		}
+		for hook in self.hooks.iter().flatten() {
+			if !hook.stage.runs_on_host() {
+				reasons.push(format!(
+					"hook {} is run at the {} stage",
+					hook.script.display(),
+					hook.stage
+				));
+			}
+		}
+		for bootloader in self.bootloaders.iter().flatten() {
+			if let Some(program) = bootloader.target_program() {
+				reasons.push(format!("bootloader {} is run", program));
+			}
+		}
+		Ok(reasons)
+	}
+
 	pub fn check(&self) -> Result<()> {
 		let path: &Path = self.file_path.as_ref();
 		let dirname = path
 			.parent()
 			.context("Failed to get the directory containing the device spec file")?;
-		let mut strs_to_chk = vec![&self.id, &self.vendor];
+		let mut fields = vec![
+			("id", &self.id, FieldClass::Identifier),
+			("vendor", &self.vendor, FieldClass::Identifier),
+			("name", &self.name, FieldClass::DisplayName),
+		];
 		if let Some(aliases) = &self.aliases {
-			aliases.iter().for_each(|s| strs_to_chk.push(s));
+			aliases
+				.iter()
+				.for_each(|s| fields.push(("alias", s, FieldClass::Identifier)));
+		}
+		if let Some(tags) = &self.tags {
+			tags.iter()
+				.for_each(|s| fields.push(("tag", s, FieldClass::Identifier)));
 		}
 		if let Some(c) = &self.of_compatible {
-			strs_to_chk.push(c)
+			fields.push(("compatible", c, FieldClass::Identifier));
+		}
+		if let Some(m) = &self.model {
+			fields.push(("model", m, FieldClass::DisplayName));
 		}
-		for field in &strs_to_chk {
-			if !field.is_ascii() {
-				bail!("'{}' contains non-ASCII characters", field);
+		for partition in &self.partitions {
+			if let Some(l) = &partition.label {
+				fields.push(("label", l, FieldClass::PartitionLabel));
 			}
-			if field.contains(FORBIDDEN_CHARS) {
-				bail!(
-					"'{}' contains one of the following characters:\n{:?}",
-					field,
-					FORBIDDEN_CHARS
-				);
+			if let Some(l) = &partition.fs_label {
+				fields.push(("fs_label", l, FieldClass::Label));
+			}
+			if let Some(m) = &partition.mountpoint {
+				fields.push(("mountpoint", m, FieldClass::Mountpoint));
 			}
 		}
-		let mut strs_to_chk = vec![&self.name];
-		if let Some(m) = &self.model {
-			strs_to_chk.push(m);
+		for (name, value, class) in fields {
+			class.validate(name, value)?;
+		}
+		if self.partitions.is_empty() {
+			bail!("No partition defined for this device");
+		}
+		if let Some(cmdline) = &self.kernel_cmdline {
+			validate_kernel_cmdline(&cmdline.to_string())?;
+		}
+		if self.defer_triggers && self.initrdless {
+			bail!("defer_triggers can not be used with initrdless devices");
+		}
+		if self.requires_target_exec == Some(false) {
+			for variant in ImageVariant::value_variants() {
+				let reasons = self.target_exec_reasons(variant)?;
+				if !reasons.is_empty() {
+					bail!(
+						"requires_target_exec is false, but {} images execute programs of the target: {}",
+						variant.to_string().to_lowercase(),
+						reasons.join(", ")
+					);
+				}
+			}
 		}
-		for field in &strs_to_chk {
-			if field.contains(FORBIDDEN_CHARS) {
+		if let Some(scripts) = &self.postinst_scripts {
+			let valid_names: Vec<String> = ["postinst.bash", "postinst.sh", "postinst"]
+				.iter()
+				.map(|x| x.to_string())
+				.chain(ImageVariant::value_variants().iter().flat_map(|v| {
+					let v = v.to_string().to_lowercase();
+					[format!("postinst-{}.bash", v), format!("postinst-{}.sh", v)]
+				}))
+				.collect();
+			if let Some(name) = scripts.iter().find(|x| !valid_names.contains(x)) {
 				bail!(
-					"'{}' contains one of the following characters:\n{:?}",
-					field,
-					FORBIDDEN_CHARS
+					"Invalid post installation script name '{}', must be one of: {}",
+					name,
+					valid_names.join(", ")
 				);
 			}
-		}
-		if self.partitions.is_empty() {
-			bail!("No partition defined for this device");
+			for variant in ImageVariant::value_variants() {
+				self.find_postinst_scripts(variant)?;
+			}
 		}
 		// Check consistency
 		if self.num_partitions != self.partitions.len() as u32 {
@@ -640,15 +1378,20 @@ impl DeviceSpec {
 		// Some devices may use MBR partition map.
 		// Let's make the root partition the only requirement here.
 		let mut root_part = None;
+		let mut firmware_part = None;
+		let mut fs_uuids = HashMap::new();
+		// Estimated sizes of the contents of the partitions.
+		let mut content_sizes = Vec::new();
 		let mut last_partition_num = 0;
 		for partition in &self.partitions {
-			if let Some(start) = partition.start_sector {
-				if self.partition_map == PartitionMapType::GPT && start <= 33 {
-					bail!(
-						"Starting sector of partition {} overlaps the partition table itself.",
-						partition.num
-					);
-				}
+			if let Some(start) = partition.start_sector
+				&& self.partition_map == PartitionMapType::GPT
+				&& start <= 33
+			{
+				bail!(
+					"Starting sector of partition {} overlaps the partition table itself.",
+					partition.num
+				);
 			}
 			if partition.part_type == PartitionType::Swap {
 				bail!("Swap partitions are not allowed on raw images.");
@@ -671,6 +1414,54 @@ impl DeviceSpec {
 					bail!("Sorry, but for now root partition must have a mountpoint '/'.")
 				}
 			}
+			if partition.usage == PartitionUsage::Firmware
+				&& let Some(p) = firmware_part.replace(partition.num)
+			{
+				bail!(
+					"More than one firmware partition defined: {} and {}",
+					p,
+					partition.num
+				);
+			}
+			if partition.format_on_first_boot {
+				if partition.usage != PartitionUsage::Data {
+					bail!(
+						"Partition {} is a {:?} partition, only data partitions can be formatted on first boot",
+						partition.num,
+						partition.usage
+					);
+				}
+				if partition.filesystem == FilesystemType::None || partition.mountpoint.is_none() {
+					bail!(
+						"Partition {} is formatted on first boot, it must have a filesystem and a mountpoint",
+						partition.num
+					);
+				}
+				if partition.fs_label.is_some() || partition.fs_uuid.is_some() {
+					bail!(
+						"Partition {} is formatted on first boot, fs_label and fs_uuid can not be defined",
+						partition.num
+					);
+				}
+			}
+			if let Some(content) = &partition.content {
+				if partition.usage == PartitionUsage::Rootfs {
+					bail!(
+						"Partition {} is the root partition, it can not be populated with content",
+						partition.num
+					);
+				}
+				if !partition.formatted_at_build() {
+					bail!(
+						"Partition {} has content, it must have a filesystem created during the build",
+						partition.num
+					);
+				}
+				let size = content
+					.check(dirname)
+					.context(format!("Invalid content for partition {}", partition.num))?;
+				content_sizes.push((partition.num, size));
+			}
 			if let Some(l) = &partition.label {
 				if self.partition_map == PartitionMapType::MBR {
 					bail!(
@@ -678,19 +1469,249 @@ impl DeviceSpec {
 						partition.num
 					);
 				}
-				if l.len() > 35 {
-					bail!(
-						"Label for partition {} exceeds the 35-character limit",
+				check_gpt_label(l)
+					.context(format!("Invalid label for partition {}", partition.num))?;
+			}
+			last_partition_num = partition.num;
+			partition
+				.filesystem
+				.check(&partition.fs_label)
+				.context(format!(
+					"Invalid filesystem label for partition {}",
+					partition.num
+				))?;
+			if let Some(l) = &partition.fs_label
+				&& &partition.filesystem.normalize_label(l) != l
+			{
+				warn!(
+					"{}: Filesystem label '{}' of partition {} will be written as '{}'",
+					&self.id,
+					l,
+					partition.num,
+					partition.filesystem.normalize_label(l)
+				);
+			}
+			// The size of a partition filling the rest of the disk is unknown.
+			if partition.size_in_sectors != 0 {
+				partition
+					.filesystem
+					.check_size(partition.size_in_sectors)
+					.context(format!(
+						"Invalid filesystem for partition {}",
 						partition.num
+					))?;
+			}
+			if let Some(uuid) = &partition.fs_uuid {
+				let uuid = partition.filesystem.check_fs_uuid(uuid).context(format!(
+					"Invalid filesystem UUID for partition {}",
+					partition.num
+				))?;
+				if let Some(p) = fs_uuids.insert(uuid.clone(), partition.num) {
+					bail!(
+						"Partitions {} and {} have the same filesystem UUID {}",
+						p,
+						partition.num,
+						uuid
 					);
 				}
 			}
-			last_partition_num = partition.num;
-			partition.filesystem.check(&partition.fs_label)?;
+			let mount_opts = partition.mount_options().context(format!(
+				"Invalid mount options for partition {}",
+				partition.num
+			))?;
+			if !mount_opts.unknown.is_empty() {
+				warn!(
+					"Partition {} has mount options unknown to mkrawimg: {}",
+					partition.num,
+					mount_opts.unknown.join(",")
+				);
+			}
 		}
 		if root_part.is_none() {
 			bail!("No root partition defined");
 		}
+		// Sectors of the partitions with explicit starting sectors, `[start, end)`.
+		let mut ranges: Vec<(u32, u64, u64)> = Vec::new();
+		for partition in &self.partitions {
+			let Some(start) = partition.start_sector else {
+				continue;
+			};
+			let end = match partition.size_in_sectors {
+				0 => u64::MAX,
+				size => start.checked_add(size).context(format!(
+					"Partition {} ends beyond a 64-bit sector number",
+					partition.num
+				))?,
+			};
+			for (num, other_start, other_end) in &ranges {
+				if start < *other_start {
+					bail!(
+						"Partition {} starts before partition {}, please keep the partitions in order",
+						partition.num,
+						num
+					);
+				}
+				if start < *other_end && *other_start < end {
+					bail!("Partitions {} and {} overlap", num, partition.num);
+				}
+			}
+			ranges.push((partition.num, start, end));
+		}
+		// The first partition is aligned to 1MiB.
+		let fixed_size = self.partitions.iter().try_fold(1048576u64, |sum, p| {
+			sectors_to_bytes(p.size_in_sectors, PLANNING_SECTOR_SIZE)?
+				.checked_add(sum)
+				.context("The partitions take more than a 64-bit byte count")
+		})?;
+		// Planned ends of the partitions, for the explicitly placed ones as well.
+		let extents = self.partition_extents()?;
+		for variant in ImageVariant::value_variants() {
+			let size = self.size.get_variant_size_bytes(variant)?;
+			if self.partition_map == PartitionMapType::MBR
+				&& size / PLANNING_SECTOR_SIZE > MBR_MAX_SECTORS
+			{
+				bail!(
+					"MBR partition maps can address up to {} MiB, but {} images take {} MiB. Please use GPT instead.",
+					(MBR_MAX_SECTORS * PLANNING_SECTOR_SIZE) >> 20,
+					variant,
+					size >> 20
+				);
+			}
+			if fixed_size > size {
+				bail!(
+					"Partitions {} take {} MiB, exceeding the size of {} images ({} MiB)",
+					self.partitions
+						.iter()
+						.filter(|p| p.size_in_sectors != 0)
+						.map(|p| p.num.to_string())
+						.collect::<Vec<_>>()
+						.join(", "),
+					fixed_size.div_ceil(1048576),
+					variant,
+					size / 1048576
+				);
+			}
+			if let Some(e) = extents.iter().find(|e| e.end.unwrap_or(e.start) > size) {
+				bail!(
+					"Partition {} ends at {:#x}, beyond the end of {} images ({} MiB)",
+					e.num,
+					e.end.unwrap_or(e.start),
+					variant,
+					size >> 20
+				);
+			}
+			// Catch what the partitioner would reject during the build.
+			place_partitions(
+				&self.partitions,
+				&self.partition_map,
+				size / PLANNING_SECTOR_SIZE,
+				PLANNING_SECTOR_SIZE,
+			)
+			.context(format!(
+				"Unable to lay out the partitions on {} images ({} MiB)",
+				variant,
+				size >> 20
+			))?;
+			for (num, content_size) in &content_sizes {
+				let Some(e) = extents.iter().find(|e| e.num == *num) else {
+					continue;
+				};
+				let partition_size = e.end.unwrap_or(size) - e.start;
+				if *content_size > partition_size {
+					bail!(
+						"The content of partition {} takes about {} MiB, exceeding its size in {} images ({} MiB)",
+						num,
+						content_size.div_ceil(1048576),
+						variant,
+						partition_size >> 20
+					);
+				}
+			}
+		}
+		// As long as all of them are mounted, nested mountpoints always have their parent mounted, at least the root partition.
+		let mut mountpoints = HashMap::new();
+		for partition in &self.partitions {
+			let Some(mp) = &partition.mountpoint else {
+				continue;
+			};
+			if partition.filesystem == FilesystemType::None {
+				bail!(
+					"Partition {} has a mountpoint '{}' but no filesystem",
+					partition.num,
+					mp
+				);
+			}
+			if let Some(p) = mountpoints.insert(mp.trim_end_matches('/'), partition.num) {
+				bail!(
+					"Partitions {} and {} have the same mountpoint '{}'",
+					p,
+					partition.num,
+					mp
+				);
+			}
+		}
+		for unit in self
+			.enable_services
+			.iter()
+			.chain(self.disable_services.iter())
+			.flatten()
+		{
+			check_unit_name(unit)?;
+		}
+		validate_user_options(&self.user_options())?;
+		if let Some(target) = &self.devena_firstboot_target {
+			FieldClass::PackageName.validate("devena_firstboot_target", target)?;
+		}
+		if let Some(packages) = &self.oobe_package {
+			if !self.oobe_wizard {
+				bail!("oobe_package can only be defined with oobe_wizard = true");
+			}
+			for variant in ImageVariant::VARIANTS {
+				if let Some(package) = packages.get_variant_package(variant) {
+					FieldClass::PackageName.validate(
+						&format!("oobe_package.{}", variant.to_string().to_lowercase()),
+						package,
+					)?;
+				}
+			}
+		}
+		if self.oobe_wizard {
+			if self.users.as_ref().is_some_and(|x| !x.is_empty()) {
+				bail!(
+					"The OOBE wizard creates the users on the first boot, [[user]] can not be defined with oobe_wizard"
+				);
+			}
+			if self.skip_default_user {
+				bail!(
+					"The OOBE wizard already skips the built-in user, skip_default_user can not be set with oobe_wizard"
+				);
+			}
+		}
+		check_users(
+			self.users.as_deref().unwrap_or_default(),
+			self.skip_default_user,
+		)?;
+		if let Some(hooks) = &self.hooks {
+			check_hooks(hooks, dirname)?;
+		}
+		if let Some(sources) = &self.sources {
+			sources.check(CHECK_MIRROR, &self.arch.aosc_arch())?;
+		}
+		if let Some(swap) = &self.swap {
+			let rootfs_type = self
+				.partitions
+				.iter()
+				.find(|x| x.usage == PartitionUsage::Rootfs)
+				.map(|x| &x.filesystem)
+				.unwrap_or(&FilesystemType::None);
+			swap.check(&self.size, rootfs_type)
+				.context("Invalid swap")?;
+		}
+		if let Some(secureboot) = &self.efi_secureboot {
+			secureboot
+				.check(self, dirname)
+				.context("Invalid efi_secureboot")?;
+		}
 		if let Some(bootloaders) = &self.bootloaders {
 			for bl in bootloaders {
 				match bl {
@@ -703,28 +1724,128 @@ impl DeviceSpec {
 							);
 						}
 					}
-					BootloaderSpec::FlashPartition { path: _, partition } => {
-						if let Some(p) = self.partitions.get(*partition as usize) {
-							if p.filesystem != FilesystemType::None {
-								bail!(
-									"A bootloader tries to write to partition {} which already contains an active filesystem.",
-									p.num
-								);
+					BootloaderSpec::FlashPartition {
+						path,
+						partition,
+						source,
+						sha256,
+					} => {
+						Self::check_payload(dirname, path, source, sha256)?;
+						self.check_flash_partition(*partition)?;
+					}
+					BootloaderSpec::HostCommand {
+						command,
+						output,
+						flash,
+					} => {
+						let program = command
+							.first()
+							.context("Command of a host_command bootloader is empty")?;
+						if find_program(program, dirname).is_none() {
+							bail!(
+								"Program '{}' for a host_command bootloader is not found or not executable",
+								program
+							);
+						}
+						if output.as_os_str().is_empty() {
+							bail!("Output of a host_command bootloader is empty");
+						}
+						match flash {
+							FlashTarget::Partition { partition } => {
+								self.check_flash_partition(*partition)?
+							}
+							FlashTarget::Offset { offset, max_size } => {
+								self.check_flash_offset(*offset, *max_size)?
 							}
-						} else {
+						}
+					}
+					BootloaderSpec::Extlinux {
+						dir,
+						cmdline,
+						fdt,
+						fdtdir,
+					} => {
+						if self
+							.boot_files_partition()
+							.and_then(|p| p.mountpoint.as_ref())
+							.is_none()
+						{
+							bail!(
+								"extlinux requires a partition with usage 'firmware' or 'boot' and a mountpoint"
+							);
+						}
+						if cmdline_has_root(cmdline) {
+							bail!("cmdline for extlinux must not contain root=, it is generated");
+						}
+						if fdt.is_some() && fdtdir.is_some() {
 							bail!(
-								"Partition {} specified by a bootloader is not found.",
-								partition
+								"fdt and fdtdir for extlinux can not be specified at the same time"
 							);
 						}
+						FieldClass::Mountpoint
+							.validate("dir", &format!("/{}", dir.trim_start_matches('/')))?;
 					}
-					BootloaderSpec::FlashOffset { path: _, offset } => {
-						// Anything must start from at least LBA 34.
-						if self.partition_map == PartitionMapType::GPT && *offset < 512 * 34 {
+					BootloaderSpec::SystemdBoot { kernel_cmdline, .. } => {
+						if let Some(cmdline) = kernel_cmdline
+							&& cmdline_has_root(cmdline)
+						{
 							bail!(
-								"A bootloader tries to overlap the partition table. It must start from at least 0x4400 (17408), or LBA 34."
+								"kernel_cmdline for systemd-boot must not contain root=, it is generated"
 							);
 						}
+						if !self
+							.partitions
+							.iter()
+							.any(|p| p.part_type == PartitionType::EFI && p.mountpoint.is_some())
+						{
+							bail!(
+								"systemd-boot requires an EFI system partition with a mountpoint"
+							);
+						}
+					}
+					BootloaderSpec::Grub {
+						target,
+						efi_directory,
+						..
+					} => {
+						if GRUB_EFI_TARGETS.contains(&target.as_str()) {
+							let esp = self.partitions.iter().find(|p| {
+								p.part_type == PartitionType::EFI && p.mountpoint.is_some()
+							});
+							if esp.is_none() {
+								bail!(
+									"GRUB target '{}' requires an EFI system partition with a mountpoint",
+									target
+								);
+							}
+							if let Some(dir) = efi_directory
+								&& !self
+									.partitions
+									.iter()
+									.any(|p| p.mountpoint.as_ref() == Some(dir))
+							{
+								bail!(
+									"EFI directory '{}' for GRUB is not the mountpoint of any partition",
+									dir
+								);
+							}
+						} else if !GRUB_BIOS_TARGETS.contains(&target.as_str()) {
+							bail!(
+								"Unknown GRUB target '{}', must be one of:\n{:?}",
+								target,
+								[GRUB_EFI_TARGETS, GRUB_BIOS_TARGETS].concat()
+							);
+						}
+					}
+					BootloaderSpec::FlashOffset {
+						path,
+						offset,
+						max_size,
+						source,
+						sha256,
+					} => {
+						Self::check_payload(dirname, path, source, sha256)?;
+						self.check_flash_offset(*offset, *max_size)?;
 					}
 				}
 			}
@@ -732,43 +1853,400 @@ impl DeviceSpec {
 		Ok(())
 	}
 
+	/// Check that a bootloader flashed to `partition` does not overwrite a filesystem.
+	fn check_flash_partition(&self, partition: u64) -> Result<()> {
+		if let Some(p) = self.partitions.iter().find(|p| p.num as u64 == partition) {
+			if p.filesystem != FilesystemType::None {
+				bail!(
+					"A bootloader tries to write to partition {} which already contains an active filesystem.",
+					p.num
+				);
+			}
+		} else {
+			bail!(
+				"Partition {} specified by a bootloader is not found.",
+				partition
+			);
+		}
+		Ok(())
+	}
+
+	/// Check that a bootloader flashed at `offset` does not overwrite the partition table or a filesystem.
+	fn check_flash_offset(&self, offset: u64, max_size: Option<u64>) -> Result<()> {
+		// Anything must start from at least LBA 34.
+		if self.partition_map == PartitionMapType::GPT && offset < 512 * 34 {
+			bail!(
+				"A bootloader tries to overlap the partition table. It must start from at least 0x4400 (17408), or LBA 34."
+			);
+		}
+		let end = max_size
+			.map(|x| {
+				offset
+					.checked_add(x)
+					.context("The bootloader ends beyond a 64-bit byte offset")
+			})
+			.transpose()?;
+		if let Some(e) = self
+			.formatted_partition_extents()?
+			.iter()
+			.find(|e| e.overlaps(offset, end))
+		{
+			bail!(
+				"A bootloader flashed at offset {:#x} overlaps partition {}, which contains a filesystem.",
+				offset,
+				e.num
+			);
+		}
+		Ok(())
+	}
+
+	/// Partitions to be mounted in the root filesystem, parents before their children.
+	///
+	/// Partitions with the same depth are kept in the order of the spec. Partitions formatted on first boot are not included.
+	pub fn mountable_partitions(&self) -> Vec<&PartitionSpec> {
+		let mut partitions: Vec<&PartitionSpec> = self
+			.partitions
+			.iter()
+			.filter(|p| p.mountpoint.is_some() && p.formatted_at_build())
+			.collect();
+		partitions.sort_by_key(|p| {
+			p.mountpoint
+				.as_deref()
+				.map(|mp| Path::new(mp).components().count())
+		});
+		partitions
+	}
+
+	/// The partition the files of the bootloaders are written into.
+	///
+	/// It is the firmware partition if there is one, otherwise the boot partition.
+	pub fn boot_files_partition(&self) -> Option<&PartitionSpec> {
+		let find = |usage| self.partitions.iter().find(|p| p.usage == usage);
+		find(PartitionUsage::Firmware).or_else(|| find(PartitionUsage::Boot))
+	}
+
+	/// Generate the `root=` parameter of the kernel command line.
+	///
+	/// PARTUUID is used if the device boots without an init ramdisk, otherwise the filesystem UUID is used.
+	pub fn gen_root_param(&self, pm_data: &PartitionMapData) -> Result<String> {
+		let root_part = self
+			.partitions
+			.iter()
+			.find(|x| x.usage == PartitionUsage::Rootfs)
+			.context("Unable to find a root filesystem to generate kernel command line")?;
+		let root_data = pm_data
+			.data
+			.get(&root_part.num)
+			.context("Unable to get partition data for the root partition")?;
+		let root_param = if self.initrdless {
+			format!("root=PARTUUID={}", &root_data.part_uuid)
+		} else {
+			format!(
+				"root=UUID={}",
+				root_data
+					.fs_uuid
+					.as_ref()
+					.context("Root filesystem has no UUID")?
+			)
+		};
+		Ok(root_param)
+	}
+
+	/// Generate the final kernel command line, with the `root=` argument prepended if it is not defined.
+	///
+	/// Empty if `kernel_cmdline` is not defined.
 	pub fn gen_kernel_cmdline(&self, pm_data: &PartitionMapData) -> Result<String> {
-		let str = if let Some(cmdline) = self.kernel_cmdline.as_ref() {
-			let mut str = String::new();
-			let root_part = self
-				.partitions
-				.iter()
-				.find(|x| x.usage == PartitionUsage::Rootfs)
-				.context("Unable to find a root filesystem to generate kernel command line")?;
-			let root_param = if self.initrdless {
-				format!(
-					"root=PARTUUID={} ",
-					&pm_data.data.get(&root_part.num).as_ref().unwrap().part_uuid
-				)
-			} else {
-				format!(
-					"root=UUID={} ",
-					&pm_data
-						.data
-						.get(&root_part.num)
-						.as_ref()
-						.unwrap()
-						.fs_uuid
-						.as_ref()
-						.unwrap()
-				)
-			};
-			str += &root_param;
-			str += &cmdline.join(" ");
-			str
+		let Some(cmdline) = self.kernel_cmdline.as_ref() else {
+			return Ok(String::new());
+		};
+		let cmdline = cmdline.to_string();
+		let str = if cmdline_has_root(&cmdline) {
+			cmdline
+		} else if cmdline.is_empty() {
+			self.gen_root_param(pm_data)?
 		} else {
-			String::new()
+			format!("{} {}", self.gen_root_param(pm_data)?, cmdline)
 		};
 		Ok(str)
 	}
 }
 
+/// Lists which are appended to the inherited ones, unless `<name>_replace = true` is set.
+const APPENDED_LISTS: &[&str] = &[
+	"tags",
+	"bsp_packages",
+	"enable_services",
+	"disable_services",
+	"user_groups",
+	"postinst_scripts",
+];
+
+/// Whether `key` is the `<name>_replace` flag of one of the [`APPENDED_LISTS`].
+pub fn is_replace_flag(key: &str) -> bool {
+	key.strip_suffix("_replace")
+		.is_some_and(|x| APPENDED_LISTS.contains(&x))
+}
+
+/// Merge the partial spec `overlay` read from `file` on top of `base`.
+///
+/// Unlike [`merge_spec_tables`], the [`APPENDED_LISTS`] are appended to the ones of `base`, unless `<name>_replace = true` is set in `overlay`.
+fn merge_partial_spec(base: &mut toml::Table, mut overlay: toml::Table, file: &Path) -> Result<()> {
+	for list in APPENDED_LISTS {
+		let key = format!("{}_replace", list);
+		let replace = match overlay.remove(&key) {
+			None => false,
+			Some(toml::Value::Boolean(x)) => x,
+			Some(_) => bail!("{} in '{}' must be a boolean", key, file.display()),
+		};
+		if !replace
+			&& let Some(toml::Value::Array(inherited)) = base.get(*list)
+			&& let Some(toml::Value::Array(own)) = overlay.get_mut(*list)
+		{
+			own.splice(0..0, inherited.iter().cloned());
+		}
+	}
+	merge_spec_tables(base, overlay);
+	Ok(())
+}
+
+/// Read the spec override at `path`.
+fn read_override(path: &Path) -> Result<toml::Table> {
+	let content = fs::read_to_string(path).context(format!(
+		"Unable to read the spec override '{}'",
+		path.display()
+	))?;
+	toml::from_str(&content).context(format!(
+		"Unable to parse the spec override '{}'",
+		path.display()
+	))
+}
+
+/// Fields which can be defined in a partition layout.
+const LAYOUT_FIELDS: &[&str] = &["partition_map", "num_partitions", "partition", "partitions"];
+
+/// Read the raw device spec from `file`, with its inherited specs and partition layout resolved.
+fn read_spec_table(file: &Path) -> Result<toml::Table> {
+	let content = fs::read_to_string(file)
+		.context(format!("Unable to read file '{}'", &file.to_string_lossy()))?;
+	let table: toml::Table = toml::from_str(&content).context(format!(
+		"Unable to treat '{}' as an entry of the registry",
+		&file.to_string_lossy()
+	))?;
+	let table = resolve_inherits(table, file)?;
+	let Some(layout) = table.get("layout") else {
+		return Ok(table);
+	};
+	let layout = layout
+		.as_str()
+		.context(format!("layout in '{}' must be a string", file.display()))?
+		.to_owned();
+	// The device directory may be a symbolic link pointing out of the registry, look within the registry first.
+	let file = std::path::absolute(file)?;
+	let canonical = file.canonicalize()?;
+	let layouts_dir = file
+		.ancestors()
+		.skip(1)
+		.chain(canonical.ancestors().skip(1))
+		.map(|x| x.join("layouts"))
+		.find(|x| x.is_dir())
+		.context(format!(
+			"Device spec '{}' uses layout '{}', but no layouts directory is found in the registry",
+			file.display(),
+			layout
+		))?;
+	resolve_layout(table, &layout, &layouts_dir).context(format!(
+		"Unable to resolve the layout of '{}'",
+		file.display()
+	))
+}
+
+/// Merge the raw device spec `table` read from `file` on top of the partial specs it inherits.
+fn resolve_inherits(table: toml::Table, file: &Path) -> Result<toml::Table> {
+	if !table.contains_key("inherits") {
+		return Ok(table);
+	}
+	let file = std::path::absolute(file)?;
+	// The device-level directory is within a vendor-level directory of the registry.
+	let registry_dir = file.ancestors().nth(3).context(format!(
+		"Device spec '{}' inherits another spec, but it is not within a registry",
+		file.display()
+	))?;
+	let mut chain = vec![file.clone()];
+	inherit(table, &file, registry_dir, &mut chain)
+}
+
+/// Merge `table` read from `file` on top of the partial spec it inherits, recursively.
+///
+/// `chain` contains the files inheriting `file`, and `file` itself.
+fn inherit(
+	table: toml::Table,
+	file: &Path,
+	registry_dir: &Path,
+	chain: &mut Vec<PathBuf>,
+) -> Result<toml::Table> {
+	let Some(inherits) = table.get("inherits") else {
+		return Ok(table);
+	};
+	let inherits = inherits
+		.as_str()
+		.context(format!("inherits in '{}' must be a string", file.display()))?;
+	if Path::new(inherits).is_absolute() {
+		bail!(
+			"'{}' inherits '{}', which must be a path relative to it",
+			file.display(),
+			inherits
+		);
+	}
+	// Resolved lexically, like the layouts, in case the device directory is a symbolic link pointing out of the registry.
+	let path = normalize_path(&file.parent().unwrap().join(inherits));
+	if !path.starts_with(registry_dir) {
+		bail!(
+			"'{}' inherits '{}', which is out of the registry '{}'",
+			file.display(),
+			inherits,
+			registry_dir.display()
+		);
+	}
+	if chain.contains(&path) {
+		chain.push(path);
+		bail!(
+			"Cyclic inheritance: {}",
+			chain
+				.iter()
+				.map(|x| x.display().to_string())
+				.collect::<Vec<_>>()
+				.join(" -> ")
+		);
+	}
+	let content = fs::read_to_string(&path).context(format!(
+		"Unable to read '{}', inherited by '{}'",
+		path.display(),
+		file.display()
+	))?;
+	let parent: toml::Table = toml::from_str(&content).context(format!(
+		"Unable to parse the inherited spec '{}'",
+		path.display()
+	))?;
+	chain.push(path.clone());
+	let mut base = inherit(parent, &path, registry_dir, chain)?;
+	merge_partial_spec(&mut base, table, file)?;
+	Ok(base)
+}
+
+/// Merge the device spec `table` on top of the partition layout named `name` within `layouts_dir`.
+fn resolve_layout(table: toml::Table, name: &str, layouts_dir: &Path) -> Result<toml::Table> {
+	FieldClass::Identifier.validate("layout", name)?;
+	let layout_path = layouts_dir.join(format!("{}.toml", name));
+	if !layout_path.is_file() {
+		let mut available: Vec<String> = fs::read_dir(layouts_dir)?
+			.filter_map(|x| x.ok())
+			.map(|x| x.path())
+			.filter(|x| x.extension() == Some(OsStr::new("toml")))
+			.filter_map(|x| Some(x.file_stem()?.to_string_lossy().to_string()))
+			.collect();
+		available.sort();
+		bail!(
+			"Layout '{}' does not exist. Available layouts: {}",
+			name,
+			if available.is_empty() {
+				"(none)".to_owned()
+			} else {
+				available.join(", ")
+			}
+		);
+	}
+	let content = fs::read_to_string(&layout_path)
+		.context(format!("Unable to read layout '{}'", layout_path.display()))?;
+	let mut base: toml::Table = toml::from_str(&content).context(format!(
+		"Unable to parse layout '{}'",
+		layout_path.display()
+	))?;
+	if let Some(key) = base.keys().find(|k| !LAYOUT_FIELDS.contains(&k.as_str())) {
+		bail!(
+			"Layout '{}' defines '{}', only {} are allowed",
+			name,
+			key,
+			LAYOUT_FIELDS.join(", ")
+		);
+	}
+	merge_spec_tables(&mut base, table);
+	Ok(base)
+}
+
+/// Take the partition list out of a raw device spec, accepting both `partition` and `partitions`.
+fn take_partitions(table: &mut toml::Table) -> Option<Vec<toml::Value>> {
+	let mut result: Option<Vec<toml::Value>> = None;
+	for key in ["partitions", "partition"] {
+		if let Some(toml::Value::Array(arr)) = table.remove(key) {
+			result.get_or_insert_with(Vec::new).extend(arr);
+		}
+	}
+	result
+}
+
+fn partition_num(part: &toml::Value) -> Option<i64> {
+	part.get("num")
+		.or_else(|| part.get("no"))
+		.and_then(|x| x.as_integer())
+}
+
+fn merge_toml_values(base: &mut toml::Value, overlay: toml::Value) {
+	match (base, overlay) {
+		(toml::Value::Table(base), toml::Value::Table(overlay)) => {
+			for (k, v) in overlay {
+				match base.get_mut(&k) {
+					Some(b) => merge_toml_values(b, v),
+					None => {
+						base.insert(k, v);
+					}
+				}
+			}
+		}
+		(base, overlay) => *base = overlay,
+	}
+}
+
+/// Merge the raw device spec `overlay` on top of `base`.
+///
+/// Tables are merged recursively, partitions are matched by `num`, and other values (including lists) replace the ones of `base`.
+pub fn merge_spec_tables(base: &mut toml::Table, mut overlay: toml::Table) {
+	let overlay_parts = take_partitions(&mut overlay);
+	let mut base_parts = take_partitions(base);
+	if let Some(overlay_parts) = overlay_parts {
+		let parts = base_parts.get_or_insert_with(Vec::new);
+		for part in overlay_parts {
+			let num = partition_num(&part);
+			let existing = parts
+				.iter_mut()
+				.find(|p| num.is_some() && partition_num(p) == num);
+			if let Some(existing) = existing {
+				// "no" and "num" are the same field.
+				if let toml::Value::Table(t) = existing
+					&& part.get("num").is_some()
+				{
+					t.remove("no");
+				}
+				merge_toml_values(existing, part);
+			} else {
+				parts.push(part);
+			}
+		}
+	}
+	for (k, v) in overlay {
+		match base.get_mut(&k) {
+			Some(b) => merge_toml_values(b, v),
+			None => {
+				base.insert(k, v);
+			}
+		}
+	}
+	if let Some(parts) = base_parts {
+		base.insert("partitions".to_owned(), toml::Value::Array(parts));
+	}
+}
+
 impl ImageVariantSizes {
+	/// Size of the images of `variant` in MiB.
 	pub fn get_variant_size(&self, variant: &ImageVariant) -> u64 {
 		match variant {
 			ImageVariant::Base => self.base,
@@ -776,6 +2254,31 @@ impl ImageVariantSizes {
 			ImageVariant::Server => self.server,
 		}
 	}
+
+	/// Size of the images of `variant` in bytes.
+	pub fn get_variant_size_bytes(&self, variant: &ImageVariant) -> Result<u64> {
+		mib_to_bytes(self.get_variant_size(variant))
+			.context(format!("Invalid size of {} images", variant))
+	}
+}
+
+/// `PER_LINUX32` of `<sys/personality.h>`, not provided by the libc crate.
+const PER_LINUX32: libc::c_ulong = 0x0008;
+
+/// Whether the AArch64 host can run 32-bit ARM binaries, checked once.
+fn aarch32_supported() -> bool {
+	static SUPPORTED: OnceLock<bool> = OnceLock::new();
+	*SUPPORTED.get_or_init(|| {
+		// The kernel refuses the 32-bit personality without AArch32 support of the CPUs, or without CONFIG_COMPAT.
+		unsafe {
+			let current = libc::personality(0xffffffff);
+			if current == -1 || libc::personality(PER_LINUX32) == -1 {
+				return false;
+			}
+			libc::personality(current as libc::c_ulong);
+		}
+		true
+	})
 }
 
 impl DeviceArch {
@@ -795,16 +2298,53 @@ impl DeviceArch {
 			"riscv64" => Some(&Self::riscv64),
 			// TODO ppc64el needs work.
 			"powerpc64" => Some(&Self::ppc64el),
+			"arm" => {
+				if cfg!(target_feature = "v7") {
+					Some(&Self::armhf)
+				} else {
+					Some(&Self::armv6hf)
+				}
+			}
 			_ => None,
 		}
 	}
+
+	/// Whether binaries of this architecture run on the build host without emulation.
+	///
+	/// Besides the native architecture, 32-bit ARM binaries run on ARMv7 hosts (ARMv6 ones), and on AArch64 hosts if their CPUs and kernels support AArch32.
 	pub fn is_native(&self) -> bool {
-		if let Some(a) = Self::get_native_arch() {
-			if a == self {
-				return true;
-			}
+		match (Self::get_native_arch(), self) {
+			(Some(a), _) if a == self => true,
+			(Some(Self::armhf), Self::armv6hf) => true,
+			(Some(Self::arm64), Self::armhf | Self::armv6hf) => aarch32_supported(),
+			_ => false,
+		}
+	}
+
+	/// Name of this architecture in AOSC OS, i.e. the architecture of aoscbootstrap and dpkg.
+	pub fn aosc_arch(&self) -> String {
+		match self {
+			Self::armhf => "armv7hf".to_owned(),
+			_ => self.to_string().to_lowercase(),
 		}
-		false
+	}
+
+	/// Package names providing the QEMU user mode emulator for this architecture, with the distributions using them.
+	pub fn get_qemu_packages(&self) -> Vec<String> {
+		let fedora_suffix = match self {
+			Self::amd64 => "x86",
+			Self::arm64 => "aarch64",
+			Self::loongarch64 => "loongarch64",
+			Self::ppc64el => "ppc",
+			Self::riscv64 => "riscv",
+			Self::loongson3 | Self::mips64r6el => "mips",
+			Self::armhf | Self::armv6hf => "arm",
+		};
+		vec![
+			"qemu-user-static (AOSC OS, Debian, Ubuntu)".to_owned(),
+			"qemu-user-static-binfmt (Arch Linux)".to_owned(),
+			format!("qemu-user-static-{} (Fedora)", fedora_suffix),
+		]
 	}
 
 	pub fn get_qemu_binfmt_names(&self) -> &str {
@@ -816,6 +2356,20 @@ impl DeviceArch {
 			Self::loongson3 => "qemu-mips64el",
 			Self::riscv64 => "qemu-riscv64",
 			Self::mips64r6el => "qemu-mips64el",
+			Self::armhf | Self::armv6hf => "qemu-arm",
+		}
+	}
+
+	/// QEMU CPU models capable of running binaries of this architecture.
+	///
+	/// Only defined for architectures sharing the same binfmt_misc registration with an incompatible one:
+	/// Loongson 3 (pre-R6) and MIPS64 R6 binaries are both handled by `qemu-mips64el`, which selects the CPU model from the ELF header.
+	/// A QEMU build lacking the corresponding CPU models can not run the binaries (SIGILL).
+	pub fn get_qemu_cpu_models(&self) -> Option<&'static [&'static str]> {
+		match self {
+			Self::loongson3 => Some(&["Loongson-3A1000", "Loongson-3A4000"]),
+			Self::mips64r6el => Some(&["I6400", "I6500", "MIPS64R6-generic"]),
+			_ => None,
 		}
 	}
 }
@@ -833,7 +2387,7 @@ impl ImageContext<'_> {
 			img.display(),
 			sector_size
 		);
-		let rand_uuid = Uuid::new_v4();
+		let rand_uuid = self.gen_uuid("disk");
 		// NOTE UUIDs in GPT are like structs, they are "Mixed-endian."
 		// The first three components are little-endian, and the last two are big-endian.
 		// e.g. 01020304-0506-0708-090A-0B0C0D0E0F10 must be written as:
@@ -858,48 +2412,34 @@ impl ImageContext<'_> {
 		let size_in_lba = new_table.header.last_usable_lba;
 		self.info(format!("UUID: {}", &rand_uuid));
 		self.info(format!("Total LBA: {}", size_in_lba));
-		let num_partitions = self.device.num_partitions;
-		for partition in &self.device.partitions {
-			if partition.num == 0 {
-				bail!("Partition number must start from 1.");
-			}
-			let rand_part_uuid = Uuid::new_v4();
+		let disk_sectors = fd.seek(SeekFrom::End(0))? / sector_size;
+		// Place the partitions the same way as `check` does.
+		let placed = place_partitions(
+			&self.device.partitions,
+			&self.device.partition_map,
+			disk_sectors,
+			sector_size,
+		)?;
+		for (partition, placement) in self.device.partitions.iter().zip(placed) {
+			// The first and last usable sectors are derived the same way.
+			debug_assert!(
+				placement.start >= new_table.header.first_usable_lba
+					&& placement.end() <= size_in_lba
+			);
+			let rand_part_uuid = self.gen_uuid(&format!("partition-{}", partition.num));
 			let unique_partition_guid = rand_part_uuid.to_bytes_le();
-			let free_blocks = new_table.find_free_sectors();
-			debug!("Free blocks remaining: {:#?}", &free_blocks);
-			let last_free = free_blocks
-				.last()
-				.context("No more free space available for new partitions")?;
-			let size = if partition.size_in_sectors != 0 {
-				partition.size_in_sectors
-			} else {
-				if partition.num != num_partitions {
-					bail!("Max sized partition must stay at the end of the table.");
-				}
-				if last_free.1 < 1048576 / sector_size {
-					bail!("Not enough free space to create a partition");
-				}
-				last_free.1 - 1
-			};
-
 			let partition_type_guid = partition.part_type.to_uuid()?.to_bytes_le();
-			let starting_lba = if let Some(start) = partition.start_sector {
-				start
-			} else if partition.num == 1 {
-				// 1MB grain size to reserve some space for bootloaders
-				1048576 / sector_size as u64
-			} else {
-				new_table.find_first_place(size).context(format!(
-					"No suitable space found for partition:\n{:?}.",
-					&partition
-				))?
-			};
-			let ending_lba = starting_lba + size - 1;
+			let size = placement.sectors;
+			let starting_lba = placement.start;
+			let ending_lba = placement.end();
 			let name = if let Some(name) = partition.label.to_owned() {
 				name
 			} else {
 				"".into()
 			};
+			// gptman silently truncates overlong names, possibly in the middle of a surrogate pair.
+			check_gpt_label(&name)
+				.context(format!("Invalid label for partition {}", partition.num))?;
 			let partition_name = name.as_str();
 			self.info(format!(
 				"Creating an {:?} partition with PARTUUID {}:",
@@ -943,9 +2483,20 @@ impl ImageContext<'_> {
 
 	pub fn partition_mbr(&self, img: &Path) -> Result<PartitionMapData> {
 		let mut fd = File::options().write(true).open(img)?;
-		let sector_size =
-			TryInto::<u32>::try_into(gptman::linux::get_sector_size(&mut fd)?).unwrap_or(512);
-		let random_id: u32 = rand::random();
+		let sector_size = TryInto::<u32>::try_into(gptman::linux::get_sector_size(&mut fd)?)
+			.context("Sector size of the loop device exceeds the limit of MBR")?;
+		// mbrman silently caps the disk size at what MBR can address.
+		let disk_sectors = fd.seek(SeekFrom::End(0))? / sector_size as u64;
+		if disk_sectors > MBR_MAX_SECTORS {
+			bail!(
+				"MBR partition tables can address up to {} sectors, but {} has {} sectors of {} bytes. Please use GPT instead.",
+				MBR_MAX_SECTORS,
+				img.display(),
+				disk_sectors,
+				sector_size
+			);
+		}
+		let random_id = self.gen_u32("disk");
 		let disk_signature = random_id.to_le_bytes();
 		let disk_signature_str = format!("{:08x}", random_id);
 		let mut new_table = MBR::new_from(&mut fd, sector_size, disk_signature)?;
@@ -957,45 +2508,20 @@ impl ImageContext<'_> {
 			(random_id >> 16) as u16,
 			(random_id & 0xffff) as u16
 		));
-		for partition in &self.device.partitions {
-			if partition.num == 0 {
-				bail!("Partition number must start from 1.");
-			}
-			if partition.num > 4 {
-				bail!("Extended and logical partitions are not supported.");
-			}
-			let free_blocks = new_table.find_free_sectors();
-			debug!("Free blocks remaining: {:#?}", &free_blocks);
-			let last_free = free_blocks
-				.last()
-				.context("No more free space available for new partitions")?;
+		// Place the partitions the same way as `check` does.
+		let placed = place_partitions(
+			&self.device.partitions,
+			&self.device.partition_map,
+			disk_sectors,
+			sector_size as u64,
+		)?;
+		for (partition, placement) in self.device.partitions.iter().zip(placed) {
 			let idx = TryInto::<usize>::try_into(partition.num)
 				.context("Partition number exceeds the limit")?;
-			let sectors = if partition.size_in_sectors != 0 {
-				TryInto::<u32>::try_into(partition.size_in_sectors)
-					.context("Partition size exceeds the limit of MBR")?
-			} else {
-				// Make sure it is the last partition.
-				if partition.num != self.device.num_partitions {
-					bail!("Max sized partition must stay at the end of the table.");
-				}
-				last_free.1 - 1
-			};
-			if sectors < 1048576 / sector_size {
-				bail!("Not enough free space to create a partition");
-			}
-			let starting_lba = if let Some(start) = partition.start_sector {
-				TryInto::<u32>::try_into(start)
-					.context("Partition size exceeds the limit of MBR")?
-			} else if partition.num == 1 {
-				// 1MB grain size to reserve some space for bootloaders
-				1048576 / sector_size as u32
-			} else {
-				new_table.find_first_place(sectors).context(format!(
-					"No suitable free space found for partition: {:?}",
-					&partition
-				))?
-			};
+			// The disk size is checked above, every sector fits.
+			let sectors = placement.sectors as u32;
+			let starting_lba = placement.start as u32;
+			let ending_lba = placement.end() as u32;
 			let boot = if partition.usage == PartitionUsage::Boot {
 				mbrman::BOOT_ACTIVE
 			} else {
@@ -1005,9 +2531,7 @@ impl ImageContext<'_> {
 			self.info(format!("Creating an {:?} partition:", &partition.part_type));
 			self.info(format!(
 				"Size in LBA: {}, Start = {}, End = {}",
-				sectors,
-				starting_lba,
-				starting_lba + sectors - 1
+				sectors, starting_lba, ending_lba
 			));
 			let part = MBRPartitionEntry {
 				boot,
@@ -1037,63 +2561,219 @@ impl ImageContext<'_> {
 		Ok(pm_data)
 	}
 
-	pub fn write_spec_script(
+	/// Read the partition table back from an existing image, and verify it against the device spec.
+	///
+	/// Filesystem UUIDs are probed from the partitions, thus the partitions must be available (e.g. scanned by the kernel).
+	pub fn read_partition_map(&self, img: &Path) -> Result<PartitionMapData> {
+		let mut fd = File::options().read(true).open(img)?;
+		let sector_size = gptman::linux::get_sector_size(&mut fd)?;
+		let mut parts_data: HashMap<u32, PartitionData> = HashMap::new();
+		// (num, starting sector, size in sectors)
+		let mut found: Vec<(u32, u64, u64)> = Vec::new();
+		let uuid = match self.device.partition_map {
+			PartitionMapType::GPT => {
+				let table = GPT::find_from(&mut fd).context(format!(
+					"Unable to read a GPT partition table from {}",
+					img.display()
+				))?;
+				for (num, entry) in table.iter().filter(|(_, e)| e.is_used()) {
+					found.push((num, entry.starting_lba, entry.size()?));
+					parts_data.insert(
+						num,
+						PartitionData {
+							num,
+							part_uuid: Uuid::from_bytes_le(entry.unique_partition_guid).to_string(),
+							fs_uuid: None,
+						},
+					);
+				}
+				Uuid::from_bytes_le(table.header.disk_guid).to_string()
+			}
+			PartitionMapType::MBR => {
+				let table = MBR::read_from(&mut fd, sector_size as u32).context(format!(
+					"Unable to read a MBR partition table from {}",
+					img.display()
+				))?;
+				let disk_signature_str =
+					format!("{:08x}", u32::from_le_bytes(table.header.disk_signature));
+				for (idx, entry) in table.iter().filter(|(_, e)| e.is_used()) {
+					let num = idx as u32;
+					found.push((num, entry.starting_lba as u64, entry.sectors as u64));
+					parts_data.insert(
+						num,
+						PartitionData {
+							num,
+							part_uuid: format!("{}-{:02x}", &disk_signature_str, idx),
+							fs_uuid: None,
+						},
+					);
+				}
+				disk_signature_str
+			}
+		};
+		if found.len() != self.device.partitions.len() {
+			bail!(
+				"Image contains {} partitions, but device '{}' defines {}",
+				found.len(),
+				&self.device.id,
+				self.device.partitions.len()
+			);
+		}
+		for partition in &self.device.partitions {
+			let (_, start, size) = found
+				.iter()
+				.find(|(num, _, _)| *num == partition.num)
+				.context(format!(
+					"Partition {} defined by the device is not found in the image",
+					partition.num
+				))?;
+			if let Some(expected) = partition.start_sector
+				&& expected != *start
+			{
+				bail!(
+					"Partition {} starts at sector {} in the image, expected {}",
+					partition.num,
+					start,
+					expected
+				);
+			}
+			if partition.size_in_sectors != 0 && partition.size_in_sectors != *size {
+				bail!(
+					"Partition {} has {} sectors in the image, expected {}",
+					partition.num,
+					size,
+					partition.size_in_sectors
+				);
+			}
+			if partition.formatted_at_build() {
+				let fs_uuid = get_fsuuid(&partition_path(img, partition.num))?;
+				parts_data.get_mut(&partition.num).unwrap().fs_uuid = Some(fs_uuid);
+			}
+		}
+		Ok(PartitionMapData {
+			uuid,
+			data: parts_data,
+		})
+	}
+
+	/// The [defined variables] for scripts, also exported to host commands.
+	///
+	/// [defined variables]: DeviceSpec#available-defined-variables
+	pub fn spec_vars(
 		&self,
 		loopdev: &dyn AsRef<Path>,
 		rootpart: &dyn AsRef<Path>,
-		container: &dyn AsRef<Path>,
 		pm_data: &PartitionMapData,
-	) -> Result<()> {
-		let mut script = format!(
-			r#"DEVICE_ID='{0}'
-DEVICE_COMPATIBLE='{1}'
-LOOPDEV='{2}'
-NUM_PARTITIONS='{3}'
-ROOTPART='{4}'
-DISKLABEL='{5}'
-DISKUUID='{6}'
-KERNEL_CMDLINE='{7}'
-"#,
-			self.device.id,
-			&self.device.of_compatible.clone().unwrap_or("".to_string()),
-			loopdev.as_ref().to_string_lossy(),
-			self.device.num_partitions,
-			rootpart.as_ref().to_string_lossy(),
-			&self.device.partition_map.to_string().to_lowercase(),
-			&pm_data.uuid,
-			&self.device.gen_kernel_cmdline(pm_data)?
-		);
-		for part in &self.device.partitions {
-			let part_data = pm_data.data.get(&part.num).context(format!(
-				"Unable to get partition data for partition {}",
-				part.num
-			))?;
-			assert_eq!(part.num, part_data.num);
-			script += &format!(
-				"PART{0}_PARTUUID='{1}'\n",
-				part_data.num, part_data.part_uuid,
-			);
-			if part.usage == PartitionUsage::Rootfs {
-				script += &format!("ROOT_PARTUUID=\"$PART{0}_PARTUUID\"\n", part.num);
-			} else if part.usage == PartitionUsage::Boot {
-				script += &format!("BOOT_PARTUUID=\"$PART{0}_PARTUUID\"\n", part.num);
+	) -> Result<Vec<(String, String)>> {
+		let device = self.device;
+		let mut vars: Vec<(String, String)> = [
+			("DEVICE_ID", device.id.clone()),
+			(
+				"DEVICE_COMPATIBLE",
+				device.of_compatible.clone().unwrap_or_default(),
+			),
+			("DEVICE_VENDOR", device.vendor.clone()),
+			("DEVICE_NAME", device.name.clone()),
+			("VARIANT", self.variant.to_string().to_lowercase()),
+			("ARCH", device.arch.to_string().to_lowercase()),
+			(
+				"IMAGE_SIZE_MIB",
+				device.size.get_variant_size(self.variant).to_string(),
+			),
+			("LOOPDEV", path_str(loopdev.as_ref())?.to_owned()),
+			("NUM_PARTITIONS", device.num_partitions.to_string()),
+			("ROOTPART", path_str(rootpart.as_ref())?.to_owned()),
+			(
+				"PARTITION_MAP",
+				device.partition_map.to_string().to_lowercase(),
+			),
+			("DISKLABEL", device.partition_map.to_string().to_lowercase()),
+			("DISKUUID", pm_data.uuid.clone()),
+			("KERNEL_CMDLINE", self.kernel_cmdline(pm_data)?),
+			(
+				"DEFAULT_USER",
+				self.default_user().unwrap_or_default().to_owned(),
+			),
+			("USERS", self.usernames().join(" ")),
+			(
+				"OOBE_WIZARD",
+				if device.oobe_wizard { "1" } else { "0" }.to_owned(),
+			),
+			(
+				"DEVENA_TARGET",
+				device.devena_firstboot_target.clone().unwrap_or_default(),
+			),
+		]
+		.into_iter()
+		.map(|(k, v)| (k.to_owned(), v))
+		.collect();
+		for part in &device.partitions {
+			let n = part.num;
+			vars.push((
+				format!("PART{}_MOUNTPOINT", n),
+				part.mountpoint.clone().unwrap_or_default(),
+			));
+			vars.push((
+				format!("PART{}_FSTYPE", n),
+				format!("{:?}", part.filesystem).to_lowercase(),
+			));
+			vars.push((
+				format!("PART{}_USAGE", n),
+				format!("{:?}", part.usage).to_lowercase(),
+			));
+			let mut aliases = Vec::new();
+			match part.usage {
+				PartitionUsage::Rootfs => aliases.push("ROOT"),
+				PartitionUsage::Boot => aliases.push("BOOT"),
+				PartitionUsage::Firmware => aliases.push("FIRMWARE"),
+				_ => (),
 			}
 			if part.part_type == PartitionType::EFI {
-				script += &format!("EFI_PARTUUID=\"$PART{0}_PARTUUID\"\n", part.num);
+				aliases.push("EFI");
+			}
+			// Root filesystem tarballs have no partitions at all
+			if self.format == &OutputFormat::Tarball {
+				continue;
+			}
+			let part_data = self.part_data(pm_data, part.num)?;
+			assert_eq!(part.num, part_data.num);
+			vars.push((format!("PART{}_PARTUUID", n), part_data.part_uuid.clone()));
+			for alias in &aliases {
+				vars.push((format!("{}_PARTUUID", alias), part_data.part_uuid.clone()));
 			}
 			// We might not have a filesystem UUID under some circumstances
 			if let Some(fsuuid) = &part_data.fs_uuid {
-				script += &format!("PART{0}_FSUUID='{1}'\n", part_data.num, &fsuuid);
-				if part.usage == PartitionUsage::Rootfs {
-					script += &format!("ROOT_FSUUID=\"$PART{0}_FSUUID\"\n", part.num);
-				} else if part.usage == PartitionUsage::Boot {
-					script += &format!("BOOT_FSUUID=\"$PART{0}_FSUUID\"\n", part.num);
-				}
-				if part.part_type == PartitionType::EFI {
-					script += &format!("EFI_FSUUID=\"$PART{0}_FSUUID\"\n", part.num);
+				vars.push((format!("PART{}_FSUUID", n), fsuuid.clone()));
+				for alias in &aliases {
+					vars.push((format!("{}_FSUUID", alias), fsuuid.clone()));
 				}
 			}
 		}
+		Ok(vars)
+	}
+
+	/// Generate the content of `spec.sh`. All values are quoted with [`shell_quote`].
+	pub fn gen_spec_script(
+		&self,
+		loopdev: &dyn AsRef<Path>,
+		rootpart: &dyn AsRef<Path>,
+		pm_data: &PartitionMapData,
+	) -> Result<String> {
+		let mut script = String::new();
+		for (name, value) in self.spec_vars(loopdev, rootpart, pm_data)? {
+			script += &format!("{}={}\n", name, shell_quote(value));
+		}
+		Ok(script)
+	}
+
+	pub fn write_spec_script(
+		&self,
+		loopdev: &dyn AsRef<Path>,
+		rootpart: &dyn AsRef<Path>,
+		container: &dyn AsRef<Path>,
+		pm_data: &PartitionMapData,
+	) -> Result<()> {
+		let script = self.gen_spec_script(loopdev, rootpart, pm_data)?;
 		debug!("Script content: \n{}", &script);
 		let path = container.as_ref().join("tmp/spec.sh");
 		let mut fd = File::options()
@@ -1107,22 +2787,24 @@ KERNEL_CMDLINE='{7}'
 		Ok(())
 	}
 
-	pub fn generate_fstab(
-		&self,
-		pm_data: &PartitionMapData,
-		container: &dyn AsRef<Path>,
-	) -> Result<()> {
-		self.info("Generating /etc/fstab ...");
+	/// Generate the entries appended to `/etc/fstab` of the target.
+	pub fn gen_fstab(&self, pm_data: &PartitionMapData) -> Result<String> {
 		let mut content = String::from("\n# ---- Auto generated by mkrawimg ----\n");
+		let tarball = self.format == &OutputFormat::Tarball;
+		if tarball {
+			content += "# Built as a root filesystem tarball, no partitions exist yet.\n\
+				# Replace the sources of the following entries and uncomment them to use.\n";
+		}
 		for partition in &self.device.partitions {
 			if let Some(mountpoint) = &partition.mountpoint {
-				let part_data = pm_data.data.get(&partition.num).context(format!(
-					"Unable to get partition data for partition {}",
-					partition.num
-				))?;
-				let src = if self.device.initrdless {
+				let src = if tarball {
+					format!("# PARTLABEL=<partition {}>", partition.num)
+				} else if self.device.initrdless || partition.format_on_first_boot {
+					// The filesystem UUID is not known until it is created.
+					let part_data = self.part_data(pm_data, partition.num)?;
 					format!("PARTUUID=\"{0}\"", &part_data.part_uuid)
 				} else {
+					let part_data = self.part_data(pm_data, partition.num)?;
 					format!(
 						"UUID=\"{0}\"",
 						&part_data
@@ -1133,15 +2815,12 @@ KERNEL_CMDLINE='{7}'
 				};
 				// dst = mountpoint
 				// `genfstab(8)` uses the options field in `/proc/mounts`, which is the expanded result from `defaults`.
-				let options = if let Some(opts) = partition.mount_opts.as_ref() {
-					opts.join(",")
-				} else {
-					"defaults".to_owned()
-				};
-				let fsck_passno = if partition.usage == PartitionUsage::Rootfs {
-					1
-				} else {
-					2
+				let options = partition.mount_options()?.fstab();
+				let fsck_passno = match partition.usage {
+					PartitionUsage::Rootfs => 1,
+					// Firmware partitions are not ours to repair.
+					PartitionUsage::Firmware => 0,
+					_ => 2,
 				};
 				let entry = format!(
 					"{0}\t{1}\t{2}\t{3}\t{4}\t{5}\n",
@@ -1158,6 +2837,16 @@ KERNEL_CMDLINE='{7}'
 				continue;
 			}
 		}
+		Ok(content)
+	}
+
+	pub fn generate_fstab(
+		&self,
+		pm_data: &PartitionMapData,
+		container: &dyn AsRef<Path>,
+	) -> Result<()> {
+		self.info("Generating /etc/fstab ...");
+		let content = self.gen_fstab(pm_data)?;
 		let fstab_path = container.as_ref().join("etc/fstab");
 		let mut fstab_fd = File::options()
 			.truncate(false)
@@ -1169,9 +2858,59 @@ KERNEL_CMDLINE='{7}'
 		Ok(())
 	}
 
+	fn part_data<'b>(&self, pm_data: &'b PartitionMapData, num: u32) -> Result<&'b PartitionData> {
+		pm_data.data.get(&num).context(format!(
+			"Unable to get partition data for partition {}",
+			num
+		))
+	}
+
+	/// The final kernel command line of the image.
+	///
+	/// `root=` can not be generated for root filesystem tarballs, the command line is used as-is.
+	pub fn kernel_cmdline(&self, pm_data: &PartitionMapData) -> Result<String> {
+		if self.format == &OutputFormat::Tarball {
+			return Ok(self
+				.device
+				.kernel_cmdline
+				.as_ref()
+				.map(|x| x.to_string())
+				.unwrap_or_default());
+		}
+		self.device.gen_kernel_cmdline(pm_data)
+	}
+
+	/// Record the spec override in the target, if the device spec is overridden.
+	pub fn write_override_marker(&self, container: &dyn AsRef<Path>) -> Result<()> {
+		let Some(override_spec) = &self.device.override_spec else {
+			return Ok(());
+		};
+		self.warn("This image is built from an overridden device spec.");
+		let content = fs::read_to_string(override_spec)?;
+		let content = format!(
+			"# This image is built with the following override applied to device '{}'.\n\
+			# Source: {}\n{}",
+			&self.device.id,
+			override_spec.display(),
+			content
+		);
+		let marker_path = container.as_ref().join(SPEC_OVERRIDE_MARKER);
+		if let Some(parent) = marker_path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		let mut fd = File::options()
+			.create(true)
+			.write(true)
+			.truncate(true)
+			.open(&marker_path)?;
+		fd.write_all(content.as_bytes())?;
+		fd.sync_all()?;
+		Ok(())
+	}
+
 	pub fn set_hostname(&self, container: &dyn AsRef<Path>) -> Result<()> {
 		self.info("Setting up hostname ...");
-		let rand_id: u32 = rand::random();
+		let rand_id = self.gen_u32("hostname");
 		let hostname = format!(
 			"{:?}-{}-{:08x}",
 			&self.device.distro, &self.device.id, rand_id
@@ -1199,6 +2938,12 @@ KERNEL_CMDLINE='{7}'
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::{
+		content::{ContentSource, PartitionContent},
+		fixtures::{FIXTURE_REGISTRY, TestDir, test_context},
+		plan::BuildRun,
+		user::RootPolicy,
+	};
 	use log::info;
 	use owo_colors::OwoColorize;
 
@@ -1207,7 +2952,9 @@ mod tests {
 		env_logger::builder()
 			.filter_level(log::LevelFilter::Debug)
 			.build();
-		let walker = walkdir::WalkDir::new("devices").max_depth(4).into_iter();
+		let walker = walkdir::WalkDir::new(FIXTURE_REGISTRY)
+			.max_depth(4)
+			.into_iter();
 		for e in walker {
 			let e = e?;
 			if e.path().is_dir() || e.path().file_name() != Some(OsStr::new("device.toml")) {
@@ -1219,4 +2966,1258 @@ mod tests {
 		}
 		Ok(())
 	}
+
+	const TEST_FLASH_PARTITION: &str = r#"
+id = "test"
+vendor = "test"
+name = "Test Device"
+arch = "riscv64"
+bsp_packages = []
+partition_map = "gpt"
+num_partitions = 3
+
+[size]
+base = 7400
+desktop = 25000
+server = 7400
+
+[[partition]]
+num = 1
+type = "basic"
+usage = "other"
+size_in_sectors = 4096
+filesystem = "none"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+size_in_sectors = 8192
+filesystem = "ext4"
+mountpoint = "/"
+
+[[partition]]
+num = 3
+type = "basic"
+usage = "other"
+size_in_sectors = 0
+filesystem = "none"
+"#;
+
+	fn flash_partition_spec(partition: u64) -> Result<DeviceSpec> {
+		let mut device: DeviceSpec = toml::from_str(&format!(
+			"{}\n[[bootloader]]\ntype = \"flash_partition\"\npath = \"/boot/u-boot.bin\"\npartition = {}\n",
+			TEST_FLASH_PARTITION, partition
+		))?;
+		device.file_path = PathBuf::from("/nonexistent/device.toml");
+		Ok(device)
+	}
+
+	#[test]
+	fn test_check_flash_partition() -> Result<()> {
+		// Partitions are looked up by their number, not their position.
+		flash_partition_spec(1)?.check()?;
+		flash_partition_spec(3)?.check()?;
+		assert!(flash_partition_spec(2)?.check().is_err());
+		assert!(flash_partition_spec(4)?.check().is_err());
+		assert!(flash_partition_spec(0)?.check().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_payload_device_dir() -> Result<()> {
+		let dir = TestDir::new("payload")?;
+		fs::create_dir_all(dir.join("spec"))?;
+		fs::write(dir.join("spec/u-boot.bin"), "")?;
+		fs::write(dir.join("outside.bin"), "")?;
+		let spec = |path: &Path| -> Result<DeviceSpec> {
+			let mut device: DeviceSpec = toml::from_str(&format!(
+				"{}\n[[bootloader]]\ntype = \"flash_partition\"\npath = {:?}\nsource = \"device_dir\"\npartition = 1\n",
+				TEST_FLASH_PARTITION, path
+			))?;
+			device.file_path = dir.join("spec/device.toml");
+			Ok(device)
+		};
+		spec(Path::new("u-boot.bin"))?.check()?;
+		spec(Path::new("./u-boot.bin"))?.check()?;
+		// Existing files outside of the directory are refused
+		assert!(spec(Path::new("../outside.bin"))?.check().is_err());
+		assert!(spec(&dir.join("outside.bin"))?.check().is_err());
+		Ok(())
+	}
+
+	/// A 3 TiB image with a data partition beyond the first 2 TiB.
+	const TEST_LARGE_GPT: &str = r#"
+id = "test"
+vendor = "test"
+name = "Test Device"
+arch = "amd64"
+bsp_packages = []
+partition_map = "gpt"
+num_partitions = 3
+
+[size]
+base = 3145728
+desktop = 3145728
+server = 3145728
+
+[[partition]]
+num = 1
+type = "efi"
+usage = "boot"
+size_in_sectors = 524288
+filesystem = "fat32"
+mountpoint = "/efi"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+size_in_sectors = 134217728
+filesystem = "ext4"
+mountpoint = "/"
+
+[[partition]]
+num = 3
+type = "linux"
+usage = "data"
+start_sector = 4294967296
+size_in_sectors = 0
+filesystem = "ext4"
+mountpoint = "/srv"
+"#;
+
+	#[test]
+	fn test_large_gpt_layout() -> Result<()> {
+		let spec = |f: &dyn Fn(&mut DeviceSpec)| -> Result<DeviceSpec> {
+			let mut device: DeviceSpec = toml::from_str(TEST_LARGE_GPT)?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			f(&mut device);
+			Ok(device)
+		};
+		let device = spec(&|_| {})?;
+		device.check()?;
+		let extents = device.partition_extents()?;
+		assert_eq!(extents[1].end, Some(257 * 1048576 + (64 << 30)));
+		assert_eq!(extents[2].start, 2 << 40);
+		assert_eq!(extents[2].end, None);
+		assert_eq!(
+			device.size.get_variant_size_bytes(&ImageVariant::Base)?,
+			3 << 40
+		);
+		// 2 TiB more does not fit in the image
+		let device = spec(&|d| d.partitions[2].size_in_sectors = 1 << 32)?;
+		let err = device.check().unwrap_err().to_string();
+		assert!(err.contains("beyond the end"), "{}", err);
+		// Layouts the partitioner would reject, naming the variant
+		let device = spec(&|d| d.partitions[1].size_in_sectors = 0)?;
+		let err = format!("{:#}", device.check().unwrap_err());
+		assert!(err.contains("Base images"), "{}", err);
+		assert!(err.contains("Max sized partition 2"), "{}", err);
+		let device = spec(&|d| d.partitions[1].size_in_sectors = 5 << 30)?;
+		let err = format!("{:#}", device.check().unwrap_err());
+		assert!(err.contains("Partitions 2 and 3 overlap"), "{}", err);
+		// MBR can not address the image
+		let device = spec(&|d| {
+			d.partition_map = PartitionMapType::MBR;
+			d.partitions[0].part_type = PartitionType::Byte { byte: 0x0c };
+			d.partitions[1].part_type = PartitionType::Byte { byte: 0x83 };
+			d.partitions[2].part_type = PartitionType::Byte { byte: 0x83 };
+		})?;
+		let err = device.check().unwrap_err().to_string();
+		assert!(err.contains("Please use GPT"), "{}", err);
+		// Overflows are errors instead of panics or wrapped sizes
+		let device = spec(&|d| d.partitions[1].size_in_sectors = u64::MAX / 256)?;
+		assert!(device.check().is_err());
+		assert!(device.partition_extents().is_err());
+		let device = spec(&|d| d.size.base = u64::MAX / 1024)?;
+		assert!(device.check().is_err());
+		Ok(())
+	}
+
+	fn flash_offset_spec(offset: u64, max_size: Option<u64>) -> Result<DeviceSpec> {
+		let max_size = max_size.map(|x| format!("max_size = {}\n", x));
+		let mut device: DeviceSpec = toml::from_str(&format!(
+			"{}\n[[bootloader]]\ntype = \"flash_offset\"\npath = \"/boot/idbloader.img\"\noffset = {}\n{}",
+			TEST_FLASH_PARTITION,
+			offset,
+			max_size.unwrap_or_default()
+		))?;
+		device.file_path = PathBuf::from("/nonexistent/device.toml");
+		Ok(device)
+	}
+
+	#[test]
+	fn test_check_grub() -> Result<()> {
+		let grub_spec = |target: &str| -> Result<DeviceSpec> {
+			let mut device: DeviceSpec = toml::from_str(&format!(
+				"{}\n[[bootloader]]\ntype = \"grub\"\ntarget = \"{}\"\n",
+				TEST_FLASH_PARTITION, target
+			))?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			Ok(device)
+		};
+		grub_spec("i386-pc")?.check()?;
+		// No EFI system partition
+		assert!(grub_spec("x86_64-efi")?.check().is_err());
+		assert!(grub_spec("x86_64-pc")?.check().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_extlinux() -> Result<()> {
+		let boot = "usage = \"boot\"\nsize_in_sectors = 4096\nfilesystem = \"ext4\"\nmountpoint = \"/boot\"";
+		let extlinux_spec = |partition: &str, bootloader: &str| -> Result<DeviceSpec> {
+			let spec = TEST_FLASH_PARTITION.replacen(
+				"usage = \"other\"\nsize_in_sectors = 4096\nfilesystem = \"none\"",
+				partition,
+				1,
+			);
+			let mut device: DeviceSpec = toml::from_str(&format!(
+				"{}\n[[bootloader]]\ntype = \"extlinux\"\n{}\n",
+				spec, bootloader
+			))?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			Ok(device)
+		};
+		extlinux_spec(boot, "dir = \"extlinux\"\ncmdline = \"rw rootwait\"")?.check()?;
+		extlinux_spec(
+			boot,
+			"dir = \"/extlinux\"\ncmdline = \"rw\"\nfdt = \"/dtbs/a.dtb\"",
+		)?
+		.check()?;
+		// No boot partition
+		assert!(
+			extlinux_spec(
+				"usage = \"other\"\nsize_in_sectors = 4096\nfilesystem = \"none\"",
+				"dir = \"extlinux\"\ncmdline = \"rw\""
+			)?
+			.check()
+			.is_err()
+		);
+		assert!(
+			extlinux_spec(boot, "dir = \"extlinux\"\ncmdline = \"root=/dev/sda1 rw\"")?
+				.check()
+				.is_err()
+		);
+		assert!(
+			extlinux_spec(
+				boot,
+				"dir = \"extlinux\"\ncmdline = \"rw\"\nfdt = \"/a.dtb\"\nfdtdir = \"/dtbs\""
+			)?
+			.check()
+			.is_err()
+		);
+		assert!(
+			extlinux_spec(boot, "dir = \"../extlinux\"\ncmdline = \"rw\"")?
+				.check()
+				.is_err()
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_firmware_partition() -> Result<()> {
+		let other = "usage = \"other\"\nsize_in_sectors = 4096\nfilesystem = \"none\"";
+		let firmware = "usage = \"firmware\"\nsize_in_sectors = 4096\nfilesystem = \"fat16\"\nmountpoint = \"/boot/firmware\"";
+		let spec = |third: &str| -> Result<DeviceSpec> {
+			let spec = TEST_FLASH_PARTITION.replacen(other, firmware, 1).replacen(
+				"usage = \"other\"\nsize_in_sectors = 0\nfilesystem = \"none\"",
+				third,
+				1,
+			);
+			let mut device: DeviceSpec = toml::from_str(&spec)?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			Ok(device)
+		};
+		let device = spec(
+			"usage = \"boot\"\nsize_in_sectors = 0\nfilesystem = \"ext4\"\nmountpoint = \"/boot\"",
+		)?;
+		device.check()?;
+		assert_eq!(device.boot_files_partition().map(|p| p.num), Some(1));
+		assert_eq!(
+			device.partitions[0].mount_options()?.fstab(),
+			"defaults,nofail"
+		);
+		assert_eq!(device.partitions[2].mount_options()?.fstab(), "defaults");
+		assert!(
+			spec("usage = \"firmware\"\nsize_in_sectors = 0\nfilesystem = \"none\"")?
+				.check()
+				.is_err()
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_fs_uuids() -> Result<()> {
+		let spec = |first: &str, third: &str| -> Result<DeviceSpec> {
+			let spec = TEST_FLASH_PARTITION
+				.replacen(
+					"size_in_sectors = 4096\nfilesystem = \"none\"",
+					&format!("size_in_sectors = 4096\nfilesystem = \"fat16\"\n{}", first),
+					1,
+				)
+				.replacen(
+					"size_in_sectors = 0\nfilesystem = \"none\"",
+					&format!("size_in_sectors = 0\nfilesystem = \"ext4\"\n{}", third),
+					1,
+				);
+			let mut device: DeviceSpec = toml::from_str(&spec)?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			Ok(device)
+		};
+		let uuid = "fs_uuid = \"0fc63daf-8483-4772-8e79-3d69d8477de4\"";
+		spec("fs_uuid = \"abcd1234\"", uuid)?.check()?;
+		spec("", "")?.check()?;
+		// FAT needs a volume serial
+		assert!(spec(uuid, "")?.check().is_err());
+		assert!(spec("", "fs_uuid = \"ABCD-1234\"")?.check().is_err());
+		// Duplicates are compared in the canonical form
+		let mut device = spec("", uuid)?;
+		device.partitions[1].fs_uuid = Some("0FC63DAF-8483-4772-8E79-3D69D8477DE4".to_owned());
+		assert!(device.check().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_partition_label() -> Result<()> {
+		let spec = |label: &str| -> Result<DeviceSpec> {
+			let mut device: DeviceSpec = toml::from_str(TEST_FLASH_PARTITION)?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			device.partitions[1].label = Some(label.to_owned());
+			Ok(device)
+		};
+		spec(&"A".repeat(36))?.check()?;
+		assert!(spec(&"A".repeat(37))?.check().is_err());
+		spec(&"根文件系统".repeat(7))?.check()?;
+		assert!(spec(&"根文件系统".repeat(8))?.check().is_err());
+		spec(&"🐧".repeat(18))?.check()?;
+		assert!(spec(&"🐧".repeat(19))?.check().is_err());
+		Ok(())
+	}
+
+	/// Mountpoints in the order of `/boot/firmware`, `/` and `/boot`.
+	const TEST_NESTED_MOUNTPOINTS: &str = r#"
+id = "test"
+vendor = "test"
+name = "Test Device"
+arch = "arm64"
+bsp_packages = []
+partition_map = "gpt"
+num_partitions = 3
+
+[size]
+base = 7400
+desktop = 25000
+server = 7400
+
+[[partition]]
+num = 1
+type = "esp"
+usage = "firmware"
+size_in_sectors = 131072
+filesystem = "fat32"
+mountpoint = "/boot/firmware"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+size_in_sectors = 8388608
+filesystem = "ext4"
+mountpoint = "/"
+
+[[partition]]
+num = 3
+type = "linux"
+usage = "boot"
+size_in_sectors = 0
+filesystem = "ext4"
+mountpoint = "/boot"
+"#;
+
+	#[test]
+	fn test_mountable_partitions() -> Result<()> {
+		let spec = || -> Result<DeviceSpec> {
+			let mut device: DeviceSpec = toml::from_str(TEST_NESTED_MOUNTPOINTS)?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			Ok(device)
+		};
+		let device = spec()?;
+		device.check()?;
+		let order: Vec<u32> = device
+			.mountable_partitions()
+			.iter()
+			.map(|p| p.num)
+			.collect();
+		assert_eq!(order, vec![2, 3, 1]);
+		let mut device = spec()?;
+		device.partitions[0].mountpoint = Some("/boot/".to_owned());
+		assert!(device.check().is_err());
+		let mut device = spec()?;
+		device.partitions[2].filesystem = FilesystemType::None;
+		assert!(device.check().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_target_exec_reasons() -> Result<()> {
+		let dir = TestDir::new("target-exec")?;
+		let mut device: DeviceSpec = toml::from_str(TEST_NESTED_MOUNTPOINTS)?;
+		device.file_path = dir.join("device.toml");
+		device.requires_target_exec = Some(false);
+		assert!(
+			device
+				.target_exec_reasons(&ImageVariant::Desktop)?
+				.is_empty()
+		);
+		device.check()?;
+		fs::write(dir.join("postinst-desktop.sh"), "")?;
+		assert_eq!(
+			device.target_exec_reasons(&ImageVariant::Desktop)?,
+			vec!["post installation script postinst-desktop.sh is run"]
+		);
+		assert!(device.target_exec_reasons(&ImageVariant::Base)?.is_empty());
+		let e = device.check().unwrap_err().to_string();
+		assert!(e.contains("desktop images"), "{}", e);
+		device.requires_target_exec = None;
+		device.check()?;
+		device.bsp_packages = vec!["u-boot-rk3588".to_owned()];
+		device.bootloaders = Some(vec![BootloaderSpec::Grub {
+			target: "arm64-efi".to_owned(),
+			efi_directory: None,
+			removable: false,
+		}]);
+		assert_eq!(
+			device.target_exec_reasons(&ImageVariant::Base)?,
+			vec![
+				"BSP packages are installed",
+				"bootloader grub-install is run"
+			]
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_tags() -> Result<()> {
+		let spec = |tags: &[&str]| -> Result<DeviceSpec> {
+			let mut device: DeviceSpec = toml::from_str(TEST_NESTED_MOUNTPOINTS)?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			device.tags = Some(tags.iter().map(|t| t.to_string()).collect());
+			Ok(device)
+		};
+		spec(&["rockchip", "tier1", "ci-smoke"])?.check()?;
+		for tag in ["ci smoke", "", "tier1;", "tier/1"] {
+			assert!(spec(&[tag])?.check().is_err(), "'{}' is accepted", tag);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_user() -> Result<()> {
+		let spec = |extra: &str| -> Result<DeviceSpec> {
+			let mut device: DeviceSpec =
+				toml::from_str(&format!("{}\n{}", extra, TEST_NESTED_MOUNTPOINTS))?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			Ok(device)
+		};
+		let device = spec(
+			"user_groups = [\"gpio\", \"dialout\", \"wheel\"]\nuser_shell = \"/usr/bin/zsh\"\nuser_uid = 1500",
+		)?;
+		device.check()?;
+		let options = device.user_options();
+		assert_eq!(options.shell.as_deref(), Some("/usr/bin/zsh"));
+		assert_eq!(options.uid, Some(1500));
+		// Merged with the default groups
+		assert_eq!(
+			options.all_groups(),
+			vec![
+				"audio", "video", "cdrom", "plugdev", "tty", "wheel", "gpio", "dialout"
+			]
+		);
+		for extra in [
+			"user_groups = [\"GPIO\"]",
+			"user_shell = \"zsh\"",
+			"user_uid = 0",
+		] {
+			assert!(spec(extra)?.check().is_err(), "'{}' is accepted", extra);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_firstboot() -> Result<()> {
+		let spec = |extra: &str| -> Result<DeviceSpec> {
+			let mut device: DeviceSpec =
+				toml::from_str(&format!("{}\n{}", extra, TEST_NESTED_MOUNTPOINTS))?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			Ok(device)
+		};
+		let mut device = spec("oobe_wizard = true\ndevena_firstboot_target = \"rpi\"")?;
+		device.check()?;
+		assert_eq!(
+			device.oobe_package(&ImageVariant::Desktop),
+			Some("aosc-os-oobe-gui")
+		);
+		assert_eq!(
+			device.oobe_package(&ImageVariant::Server),
+			Some("aosc-os-oobe-cli")
+		);
+		assert_eq!(
+			device.target_exec_reasons(&ImageVariant::Base)?,
+			vec![
+				"OOBE wizard aosc-os-oobe-cli is installed",
+				"devena-firstboot-rpi is installed"
+			]
+		);
+		assert_eq!(spec("")?.oobe_package(&ImageVariant::Desktop), None);
+		// Selected for some of the variants
+		device.oobe_package = Some(OobePackages {
+			desktop: Some("aosc-os-oobe-cli".to_owned()),
+			server: Some("signage-setup".to_owned()),
+			..Default::default()
+		});
+		device.check()?;
+		assert_eq!(
+			device.oobe_package(&ImageVariant::Desktop),
+			Some("aosc-os-oobe-cli")
+		);
+		assert_eq!(
+			device.oobe_package(&ImageVariant::Server),
+			Some("signage-setup")
+		);
+		assert_eq!(
+			device.oobe_package(&ImageVariant::Base),
+			Some("aosc-os-oobe-cli")
+		);
+		device.oobe_package = Some(OobePackages {
+			desktop: Some("OOBE".to_owned()),
+			..Default::default()
+		});
+		assert!(device.check().is_err());
+		let mut device = spec("")?;
+		device.oobe_package = Some(OobePackages::default());
+		assert!(device.check().is_err());
+		let table: toml::Table = toml::from_str("[oobe_package]\ndesktp = \"a\"")?;
+		assert!(
+			table["oobe_package"]
+				.clone()
+				.try_into::<OobePackages>()
+				.is_err()
+		);
+		for extra in [
+			"devena_firstboot_target = \"Rpi\"",
+			"devena_firstboot_target = \"rpi 5\"",
+			"devena_firstboot_target = \"\"",
+			"oobe_wizard = true\nskip_default_user = true",
+		] {
+			assert!(spec(extra)?.check().is_err(), "'{}' is accepted", extra);
+		}
+		let mut device = spec("oobe_wizard = true")?;
+		device.users = Some(vec![UserSpec::default_user("admin", &Default::default())]);
+		assert!(device.check().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_swap() -> Result<()> {
+		let mut device: DeviceSpec = toml::from_str(TEST_NESTED_MOUNTPOINTS)?;
+		device.file_path = PathBuf::from("/nonexistent/device.toml");
+		device.swap = Some(SwapSpec::File { size_mib: 2048 });
+		device.check()?;
+		device.swap = Some(SwapSpec::File { size_mib: 8192 });
+		assert!(device.check().is_err());
+		device.swap = Some(SwapSpec::File { size_mib: 2048 });
+		device.partitions[1].filesystem = FilesystemType::Fat32;
+		assert!(device.check().is_err());
+		device.swap = Some(SwapSpec::Zram {
+			size_mib: 8192,
+			compression: Some("lz4".to_owned()),
+		});
+		device.check()?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_partition_content() -> Result<()> {
+		let dir = TestDir::new("partition-content")?;
+		std::fs::create_dir_all(dir.join("firmware"))?;
+		let spec = |num: usize| -> Result<DeviceSpec> {
+			let mut device: DeviceSpec = toml::from_str(TEST_NESTED_MOUNTPOINTS)?;
+			device.file_path = dir.join("device.toml");
+			device.partitions[num].content = Some(PartitionContent {
+				source: ContentSource::Dir,
+				path: "firmware".into(),
+			});
+			Ok(device)
+		};
+		let firmware = std::fs::File::create(dir.join("firmware/firmware.bin"))?;
+		firmware.set_len(32 << 20)?;
+		spec(0)?.check()?;
+		// The boot partition takes the rest of the image
+		spec(2)?.check()?;
+		let rootfs = format!("{:#}", spec(1)?.check().unwrap_err());
+		assert!(rootfs.contains("root partition"), "{}", rootfs);
+		let mut device = spec(2)?;
+		device.partitions[2].filesystem = FilesystemType::None;
+		device.partitions[2].mountpoint = None;
+		let unformatted = format!("{:#}", device.check().unwrap_err());
+		assert!(
+			unformatted.contains("created during the build"),
+			"{}",
+			unformatted
+		);
+		let mut device = spec(0)?;
+		device.partitions[0].content.as_mut().unwrap().path = "nonexistent".into();
+		assert!(device.check().is_err());
+		// 64 MiB partition
+		firmware.set_len(80 << 20)?;
+		let large = format!("{:#}", spec(0)?.check().unwrap_err());
+		assert!(
+			large.contains("The content of partition 1 takes about 80 MiB"),
+			"{}",
+			large
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_format_on_first_boot() -> Result<()> {
+		let spec = || -> Result<DeviceSpec> {
+			let mut device: DeviceSpec = toml::from_str(TEST_NESTED_MOUNTPOINTS)?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			let data = &mut device.partitions[2];
+			data.usage = PartitionUsage::Data;
+			data.mountpoint = Some("/data".to_owned());
+			data.format_on_first_boot = true;
+			Ok(device)
+		};
+		let device = spec()?;
+		device.check()?;
+		let order: Vec<u32> = device
+			.mountable_partitions()
+			.iter()
+			.map(|p| p.num)
+			.collect();
+		assert_eq!(order, vec![2, 1]);
+		let mut pm_data = test_pm_data();
+		pm_data.data.get_mut(&1).unwrap().fs_uuid = Some("ABCD-1234".to_owned());
+		let run = BuildRun::new(None)?;
+		let ctx = test_context(&device, &run);
+		let fstab = ctx.gen_fstab(&pm_data)?;
+		assert!(
+			fstab.ends_with(
+				"PARTUUID=\"00000003-0000-0000-0000-000000000000\"\t/data\text4\tdefaults,nofail,x-systemd.makefs\t0\t2\n"
+			),
+			"{}",
+			fstab
+		);
+		// Defined mount options are kept
+		let mut device = spec()?;
+		device.partitions[2].mount_opts = Some(vec!["noatime".to_owned()]);
+		assert_eq!(
+			device.partitions[2].mount_options()?.fstab(),
+			"noatime,x-systemd.makefs"
+		);
+		let mut device = spec()?;
+		device.partitions[2].usage = PartitionUsage::Boot;
+		assert!(device.check().is_err());
+		let mut device = spec()?;
+		device.partitions[1].format_on_first_boot = true;
+		assert!(device.check().is_err());
+		let mut device = spec()?;
+		device.partitions[2].fs_label = Some("Data".to_owned());
+		assert!(device.check().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_partition_ranges() -> Result<()> {
+		let spec = |starts: [Option<u64>; 3]| -> Result<DeviceSpec> {
+			let mut device: DeviceSpec = toml::from_str(TEST_FLASH_PARTITION)?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			for (p, start) in device.partitions.iter_mut().zip(starts) {
+				p.start_sector = start;
+			}
+			Ok(device)
+		};
+		spec([Some(2048), Some(6144), Some(14336)])?.check()?;
+		spec([Some(2048), None, Some(16384)])?.check()?;
+		// Partition 1 takes [2048, 6144)
+		let err = spec([Some(2048), Some(6143), None])?.check().unwrap_err();
+		assert!(err.to_string().contains("Partitions 1 and 2"), "{}", err);
+		let err = spec([Some(8192), Some(2048), None])?.check().unwrap_err();
+		assert!(
+			err.to_string()
+				.contains("Partition 2 starts before partition 1")
+		);
+		// Partition 3 takes the rest of the image
+		assert!(spec([Some(2048), None, Some(14336)])?.check().is_ok());
+		let mut device = spec([None, None, Some(14336)])?;
+		device.partitions[2].size_in_sectors = 0;
+		device.partitions[1].start_sector = Some(20480);
+		assert!(device.check().is_err());
+		// Fixed sizes must fit in the smallest variant
+		let mut device = spec([None; 3])?;
+		device.size.base = 9;
+		device.check()?;
+		// The fixed sizes fit, but the backup GPT leaves less than 1MiB to partition 3
+		device.size.base = 8;
+		let err = format!("{:#}", device.check().unwrap_err());
+		assert!(
+			err.contains("Not enough free space to create partition 3"),
+			"{}",
+			err
+		);
+		device.size.base = 9;
+		device.partitions[1].size_in_sectors = 12289;
+		let err = device.check().unwrap_err();
+		assert!(
+			err.to_string().contains("Partitions 1, 2 take 10 MiB"),
+			"{}",
+			err
+		);
+		Ok(())
+	}
+
+	fn test_pm_data() -> PartitionMapData {
+		PartitionMapData {
+			uuid: "01234567-89ab-cdef-0123-456789abcdef".to_owned(),
+			data: (1..=3)
+				.map(|num| {
+					(
+						num,
+						PartitionData {
+							num,
+							part_uuid: format!("0000000{}-0000-0000-0000-000000000000", num),
+							fs_uuid: (num == 2).then(|| "fs-uuid".to_owned()),
+						},
+					)
+				})
+				.collect(),
+		}
+	}
+
+	#[test]
+	fn test_gen_kernel_cmdline() -> Result<()> {
+		let pm_data = test_pm_data();
+		let mut device = flash_partition_spec(1)?;
+		assert_eq!(device.gen_kernel_cmdline(&pm_data)?, "");
+		device.kernel_cmdline = Some(KernelCmdline::String("rw rootwait ".to_owned()));
+		assert_eq!(
+			device.gen_kernel_cmdline(&pm_data)?,
+			"root=UUID=fs-uuid rw rootwait"
+		);
+		device.initrdless = true;
+		device.kernel_cmdline = Some(KernelCmdline::List(vec![
+			"console=ttyS2,1500000".to_owned(),
+			"rw".to_owned(),
+		]));
+		assert_eq!(
+			device.gen_kernel_cmdline(&pm_data)?,
+			"root=PARTUUID=00000002-0000-0000-0000-000000000000 console=ttyS2,1500000 rw"
+		);
+		// root= defined by the spec is kept as-is
+		device.kernel_cmdline = Some(KernelCmdline::String("root=/dev/mmcblk0p2 rw".to_owned()));
+		assert_eq!(
+			device.gen_kernel_cmdline(&pm_data)?,
+			"root=/dev/mmcblk0p2 rw"
+		);
+		device.check()?;
+		device.kernel_cmdline = Some(KernelCmdline::String("rw\nquiet".to_owned()));
+		assert!(device.check().is_err());
+		let parsed: DeviceSpec = toml::from_str(&format!(
+			"kernel_cmdline = \"rw quiet\"\n{}",
+			TEST_FLASH_PARTITION
+		))?;
+		assert_eq!(
+			parsed.kernel_cmdline,
+			Some(KernelCmdline::String("rw quiet".to_owned()))
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_tarball_spec_vars() -> Result<()> {
+		let mut device = flash_partition_spec(1)?;
+		device.kernel_cmdline = Some(KernelCmdline::String("rw quiet".to_owned()));
+		device.users = Some(
+			toml::from_str::<toml::Table>("[[user]]\nname = \"admin\"\n[[user]]\nname = \"aosc\"")?
+				["user"]
+				.clone()
+				.try_into()?,
+		);
+		let run = BuildRun::new(None)?;
+		let mut ctx = ImageContext {
+			format: &OutputFormat::Tarball,
+			..test_context(&device, &run)
+		};
+		let pm_data = PartitionMapData {
+			uuid: String::new(),
+			data: HashMap::new(),
+		};
+		let vars: HashMap<String, String> =
+			ctx.spec_vars(&"", &"", &pm_data)?.into_iter().collect();
+		assert_eq!(vars["KERNEL_CMDLINE"], "rw quiet");
+		assert_eq!(vars["PART2_USAGE"], "rootfs");
+		assert!(!vars.contains_key("PART2_PARTUUID"));
+		assert!(!vars.contains_key("ROOT_FSUUID"));
+		assert_eq!(vars["DEFAULT_USER"], "aosc");
+		assert_eq!(vars["USERS"], "aosc admin aosc");
+		assert_eq!(vars["OOBE_WIZARD"], "0");
+		assert_eq!(vars["DEVENA_TARGET"], "");
+		// The built-in user clashes with one of the device
+		assert!(ctx.check_users().is_err());
+		ctx.user = "kiosk";
+		ctx.check_users()?;
+		let vars: HashMap<String, String> =
+			ctx.spec_vars(&"", &"", &pm_data)?.into_iter().collect();
+		assert_eq!(vars["USERS"], "kiosk admin aosc");
+		// Root login
+		let hash = RootPolicy::PasswordHash("$6$salt$hash".to_owned());
+		ctx.root_policy = &hash;
+		ctx.check_users()?;
+		let mut locked_device = device.clone();
+		locked_device.allow_root_login = false;
+		let ctx = ImageContext {
+			device: &locked_device,
+			..ctx
+		};
+		assert!(ctx.check_users().is_err());
+		let ctx = ImageContext {
+			root_policy: &RootPolicy::Locked,
+			..ctx
+		};
+		ctx.check_users()?;
+		// The OOBE wizard creates the users instead
+		let mut oobe_device = device.clone();
+		oobe_device.oobe_wizard = true;
+		oobe_device.users = None;
+		oobe_device.devena_firstboot_target = Some("rpi".to_owned());
+		let ctx = ImageContext {
+			device: &oobe_device,
+			..ctx
+		};
+		let vars: HashMap<String, String> =
+			ctx.spec_vars(&"", &"", &pm_data)?.into_iter().collect();
+		assert_eq!(vars["DEFAULT_USER"], "");
+		assert_eq!(vars["USERS"], "");
+		assert_eq!(vars["OOBE_WIZARD"], "1");
+		assert_eq!(vars["DEVENA_TARGET"], "rpi");
+		assert_eq!(
+			ctx.packages(),
+			vec!["aosc-os-oobe-cli", "devena-firstboot-rpi"]
+		);
+		let oobe_package = Some("signage-setup".to_owned());
+		let ctx = ImageContext {
+			oobe_package: &oobe_package,
+			..ctx
+		};
+		assert_eq!(ctx.oobe_package(), Some("signage-setup"));
+		// Ignored without the wizard
+		let ctx = ImageContext {
+			device: &device,
+			..ctx
+		};
+		assert_eq!(ctx.oobe_package(), None);
+		assert!(ctx.packages().is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn test_gen_spec_script() -> Result<()> {
+		let mut device = flash_partition_spec(1)?;
+		device.name = "Test Device (Rev. 1.0) 'Pro'".to_owned();
+		device.vendor = "$(reboot)".to_owned();
+		let run = BuildRun::new(None)?;
+		let ctx = ImageContext {
+			variant: &ImageVariant::Desktop,
+			..test_context(&device, &run)
+		};
+		let pm_data = test_pm_data();
+		let script = ctx.gen_spec_script(&"/dev/loop0", &"/dev/loop0p2", &pm_data)?;
+		let dir = TestDir::new("spec")?;
+		let path = dir.join("spec.sh");
+		fs::write(&path, &script)?;
+		let syntax = std::process::Command::new("bash")
+			.arg("-n")
+			.arg(&path)
+			.status()?;
+		// Source it and print the values back
+		let output = std::process::Command::new("bash")
+			.arg("-c")
+			.arg(format!(
+				"source {} && printf '%s\\n' \"$DEVICE_NAME\" \"$DEVICE_VENDOR\" \"$VARIANT\" \"$IMAGE_SIZE_MIB\" \"$PART2_MOUNTPOINT\" \"$PART2_FSTYPE\" \"$PART2_USAGE\" \"$ROOT_FSUUID\"",
+				path.display()
+			))
+			.output()?;
+		assert!(syntax.success());
+		assert!(output.status.success());
+		assert_eq!(
+			String::from_utf8(output.stdout)?,
+			"Test Device (Rev. 1.0) 'Pro'\n$(reboot)\ndesktop\n25000\n/\next4\nrootfs\nfs-uuid\n"
+		);
+		// Paths written into the script must be valid UTF-8
+		use std::os::unix::ffi::OsStrExt;
+		let loopdev = Path::new(std::ffi::OsStr::from_bytes(b"/dev/loop\xff"));
+		let err = ctx
+			.gen_spec_script(&loopdev, &"/dev/loop0p2", &pm_data)
+			.unwrap_err();
+		assert!(err.to_string().contains("/dev/loop\u{FFFD}"), "{}", err);
+		Ok(())
+	}
+
+	#[test]
+	fn test_gen_fstab() -> Result<()> {
+		let mut device = flash_partition_spec(1)?;
+		let pm_data = test_pm_data();
+		let run = BuildRun::new(None)?;
+		for (initrdless, source) in [
+			(false, "UUID=\"fs-uuid\""),
+			(true, "PARTUUID=\"00000002-0000-0000-0000-000000000000\""),
+		] {
+			device.initrdless = initrdless;
+			let ctx = test_context(&device, &run);
+			// Partitions without a mountpoint have no entries
+			assert_eq!(
+				ctx.gen_fstab(&pm_data)?,
+				format!(
+					"\n# ---- Auto generated by mkrawimg ----\n{}\t/\text4\tdefaults\t0\t1\n",
+					source
+				)
+			);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_find_postinst_scripts() -> Result<()> {
+		let dir = TestDir::new("postinst")?;
+		fs::write(dir.join("postinst.sh"), "")?;
+		fs::write(dir.join("postinst-desktop.bash"), "")?;
+		let mut device = flash_partition_spec(1)?;
+		device.bootloaders = None;
+		device.file_path = dir.join("device.toml");
+		assert_eq!(
+			device.find_postinst_scripts(&ImageVariant::Desktop)?,
+			vec![dir.join("postinst.sh"), dir.join("postinst-desktop.bash")]
+		);
+		assert_eq!(
+			device.find_postinst_scripts(&ImageVariant::Server)?,
+			vec![dir.join("postinst.sh")]
+		);
+		device.postinst_scripts = Some(vec!["postinst-desktop.bash".to_owned()]);
+		device.check()?;
+		assert_eq!(
+			device.find_postinst_scripts(&ImageVariant::Desktop)?,
+			vec![dir.join("postinst-desktop.bash")]
+		);
+		assert!(
+			device
+				.find_postinst_scripts(&ImageVariant::Base)?
+				.is_empty()
+		);
+		device.postinst_scripts = Some(vec!["postinst-server.sh".to_owned()]);
+		assert!(device.check().is_err());
+		device.postinst_scripts = Some(vec!["postinst-custom.sh".to_owned()]);
+		assert!(device.check().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_host_command() -> Result<()> {
+		let host_command_spec = |command: &str, flash: &str| -> Result<DeviceSpec> {
+			let mut device: DeviceSpec = toml::from_str(&format!(
+				"{}\n[[bootloader]]\ntype = \"host_command\"\ncommand = {}\noutput = \"u-boot.bin.sd.bin\"\nflash = {}\n",
+				TEST_FLASH_PARTITION, command, flash
+			))?;
+			device.file_path = PathBuf::from("/nonexistent/device.toml");
+			Ok(device)
+		};
+		let device = host_command_spec(r#"["sh", "-c", "true"]"#, "{ partition = 1 }")?;
+		assert_eq!(
+			device.bootloaders.as_ref().unwrap()[0],
+			BootloaderSpec::HostCommand {
+				command: vec!["sh".to_owned(), "-c".to_owned(), "true".to_owned()],
+				output: PathBuf::from("u-boot.bin.sd.bin"),
+				flash: FlashTarget::Partition { partition: 1 },
+			}
+		);
+		device.check()?;
+		host_command_spec(r#"["sh"]"#, "{ offset = 0x4400, max_size = 0x8000 }")?.check()?;
+		// Overwrites the root filesystem
+		assert!(
+			host_command_spec(r#"["sh"]"#, "{ partition = 2 }")?
+				.check()
+				.is_err()
+		);
+		assert!(
+			host_command_spec(r#"["sh"]"#, "{ offset = 0x300000 }")?
+				.check()
+				.is_err()
+		);
+		assert!(
+			host_command_spec(r#"["mkrawimg-nonexistent-tool"]"#, "{ partition = 1 }")?
+				.check()
+				.is_err()
+		);
+		assert!(
+			host_command_spec("[]", "{ partition = 1 }")?
+				.check()
+				.is_err()
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_partition_extents() -> Result<()> {
+		let device = flash_partition_spec(1)?;
+		let extents = device.partition_extents()?;
+		assert_eq!(
+			extents,
+			vec![
+				PartitionExtent {
+					num: 1,
+					start: 1048576,
+					end: Some(1048576 + 4096 * 512)
+				},
+				PartitionExtent {
+					num: 2,
+					start: 3 * 1048576,
+					end: Some(3 * 1048576 + 8192 * 512)
+				},
+				PartitionExtent {
+					num: 3,
+					start: 7 * 1048576,
+					end: None
+				},
+			]
+		);
+		let formatted = device.formatted_partition_extents()?;
+		assert_eq!(formatted.len(), 1);
+		assert_eq!(formatted[0].num, 2);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_flash_offset() -> Result<()> {
+		// Inside the unformatted partition 1
+		flash_offset_spec(1048576, Some(2 * 1048576))?.check()?;
+		flash_offset_spec(0x8000, None)?.check()?;
+		// Partition 2 starts at 3MiB
+		assert!(flash_offset_spec(0x8000, Some(8 * 1048576)).is_ok_and(|d| d.check().is_err()));
+		assert!(flash_offset_spec(3 * 1048576, None).is_ok_and(|d| d.check().is_err()));
+		// Overlapping the partition table
+		assert!(flash_offset_spec(0x400, None).is_ok_and(|d| d.check().is_err()));
+		Ok(())
+	}
+
+	#[test]
+	fn test_merge_spec_tables() -> Result<()> {
+		let mut base: toml::Table = toml::from_str(
+			r#"
+id = "dev"
+bsp_packages = ["a", "b"]
+[size]
+base = 1024
+desktop = 2048
+server = 1024
+[[partition]]
+no = 1
+type = "efi"
+size_in_sectors = 2048
+usage = "boot"
+[[partition]]
+no = 2
+type = "linux"
+size_in_sectors = 0
+usage = "rootfs"
+"#,
+		)?;
+		let overlay: toml::Table = toml::from_str(
+			r#"
+bsp_packages = ["c"]
+[size]
+desktop = 4096
+[[partitions]]
+num = 1
+size_in_sectors = 4096
+"#,
+		)?;
+		merge_spec_tables(&mut base, overlay);
+		assert_eq!(base["bsp_packages"].as_array().unwrap().len(), 1);
+		assert_eq!(base["size"]["base"].as_integer(), Some(1024));
+		assert_eq!(base["size"]["desktop"].as_integer(), Some(4096));
+		assert!(base.get("partition").is_none());
+		let parts = base["partitions"].as_array().unwrap();
+		assert_eq!(parts.len(), 2);
+		assert_eq!(parts[0]["num"].as_integer(), Some(1));
+		assert!(parts[0].get("no").is_none());
+		assert_eq!(parts[0]["size_in_sectors"].as_integer(), Some(4096));
+		assert_eq!(parts[0]["type"].as_str(), Some("efi"));
+		assert_eq!(parts[1]["size_in_sectors"].as_integer(), Some(0));
+		Ok(())
+	}
+
+	#[test]
+	fn test_resolve_layout() -> Result<()> {
+		let dir = TestDir::new("layouts")?;
+		fs::write(
+			dir.join("two-parts.toml"),
+			r#"
+partition_map = "gpt"
+num_partitions = 2
+[[partition]]
+num = 1
+type = "esp"
+size_in_sectors = 614400
+usage = "boot"
+[[partition]]
+num = 2
+type = "linux"
+size_in_sectors = 0
+usage = "rootfs"
+"#,
+		)?;
+		fs::write(dir.join("bad.toml"), "id = \"bad\"\n")?;
+		let device: toml::Table = toml::from_str(
+			r#"
+layout = "two-parts"
+num_partitions = 3
+[[partition]]
+num = 1
+start_sector = 4096
+[[partition]]
+num = 3
+type = "linux"
+size_in_sectors = 2048
+usage = "other"
+"#,
+		)?;
+		let resolved = resolve_layout(device.clone(), "two-parts", &dir);
+		let missing = resolve_layout(device.clone(), "rockchip-standard", &dir);
+		let bad = resolve_layout(device, "bad", &dir);
+		let resolved = resolved?;
+		assert_eq!(resolved["num_partitions"].as_integer(), Some(3));
+		assert_eq!(resolved["partition_map"].as_str(), Some("gpt"));
+		let parts = resolved["partitions"].as_array().unwrap();
+		assert_eq!(parts.len(), 3);
+		assert_eq!(parts[0]["start_sector"].as_integer(), Some(4096));
+		assert_eq!(parts[0]["size_in_sectors"].as_integer(), Some(614400));
+		assert_eq!(parts[2]["usage"].as_str(), Some("other"));
+		let missing = format!("{:#}", missing.unwrap_err());
+		assert!(missing.contains("bad, two-parts"), "{}", missing);
+		assert!(bad.is_err());
+		// The registry fixture
+		let device = DeviceSpec::from_path(
+			&Path::new(FIXTURE_REGISTRY).join("other/gpt-layout/device.toml"),
+		)?;
+		assert_eq!(device.layout.as_deref(), Some("fixture-gpt"));
+		assert_eq!(device.partitions.len(), 3);
+		assert_eq!(device.partitions[1].size_in_sectors, 524288);
+		assert_eq!(
+			device.partitions[1].mount_opts,
+			Some(vec!["defaults".to_owned(), "noatime".to_owned()])
+		);
+		assert_eq!(device.partitions[2].usage, PartitionUsage::Rootfs);
+		device.check()?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_inherits() -> Result<()> {
+		let dir = Path::new("tests/fixtures/inherits");
+		let device = DeviceSpec::from_path(&dir.join("rockchip/rock-5b/device.toml"))?;
+		assert_eq!(device.inherits.as_deref(), Some("../rk3588-common.toml"));
+		assert_eq!(device.vendor, "rockchip");
+		assert_eq!(device.of_compatible.as_deref(), Some("radxa,rock-5b"));
+		assert_eq!(
+			device.tags,
+			Some(vec!["fixture".to_owned(), "tier1".to_owned()])
+		);
+		assert_eq!(
+			device.bsp_packages,
+			vec!["linux+kernel", "u-boot-rk3588", "firmware-rock-5b"]
+		);
+		assert_eq!(device.size.base, 4096);
+		assert_eq!(device.partitions.len(), 2);
+		assert_eq!(device.partitions[0].start_sector, Some(32768));
+		assert_eq!(device.partitions[1].filesystem, FilesystemType::Btrfs);
+		assert_eq!(device.bootloaders.as_ref().map(|x| x.len()), Some(1));
+		assert_eq!(device.unknown_fields, vec![]);
+		device.check()?;
+		crate::fixtures::assert_golden("inherits-rock-5b.toml", &device.show()?)?;
+		// Replaced lists
+		let device = DeviceSpec::from_path(&dir.join("rockchip/orangepi-5/device.toml"))?;
+		assert_eq!(
+			device.bsp_packages,
+			vec!["linux+kernel", "u-boot-orangepi-5"]
+		);
+		assert_eq!(device.tags, Some(vec!["fixture".to_owned()]));
+		assert_eq!(device.unknown_fields, vec![]);
+		device.check()?;
+		// Invalid inheritance
+		for (name, expected) in [
+			("cycle", "Cyclic inheritance"),
+			("escape", "which is out of the registry"),
+			("absolute", "which must be a path relative to it"),
+		] {
+			let err =
+				DeviceSpec::from_path(&dir.join("bad").join(name).join("device.toml")).unwrap_err();
+			let err = format!("{:#}", err);
+			assert!(err.contains(expected), "{}: {}", name, err);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_apply_override() -> Result<()> {
+		let dir = TestDir::new("override")?;
+		let device = DeviceSpec::from_path(Path::new(
+			"tests/fixtures/inherits/rockchip/rock-5b/device.toml",
+		))?;
+		let overlay = dir.join("override.toml");
+		// Appended like the inherited lists
+		fs::write(
+			&overlay,
+			"bsp_packages = [\"u-boot-tools\"]\ntags = [\"testing\"]\ntags_replace = true\n",
+		)?;
+		let overridden = device.apply_override(&overlay)?;
+		assert_eq!(
+			overridden.bsp_packages,
+			vec![
+				"linux+kernel",
+				"u-boot-rk3588",
+				"firmware-rock-5b",
+				"u-boot-tools"
+			]
+		);
+		assert_eq!(overridden.tags, Some(vec!["testing".to_owned()]));
+		assert_eq!(overridden.unknown_fields, vec![]);
+		let shown: toml::Table = toml::from_str(&overridden.show()?)?;
+		assert_eq!(shown["bsp_packages"].as_array().map(|x| x.len()), Some(4));
+		assert_eq!(shown.get("tags_replace"), None);
+		fs::write(&overlay, "bsp_packages_replace = \"yes\"\n")?;
+		let err = format!("{:#}", device.apply_override(&overlay).unwrap_err());
+		assert!(err.contains("must be a boolean"), "{}", err);
+		Ok(())
+	}
+
+	#[test]
+	fn test_arm32_arch() -> Result<()> {
+		let device = crate::fixtures::fixture_device("fixture-armhf-sunxi")?;
+		assert_eq!(device.arch, DeviceArch::armhf);
+		assert_eq!(device.arch.to_string().to_lowercase(), "armhf");
+		assert_eq!(device.arch.aosc_arch(), "armv7hf");
+		assert_eq!(DeviceArch::armv6hf.aosc_arch(), "armv6hf");
+		assert_eq!(DeviceArch::arm64.aosc_arch(), "arm64");
+		for arch in [DeviceArch::armhf, DeviceArch::armv6hf] {
+			assert_eq!(arch.get_qemu_binfmt_names(), "qemu-arm");
+			assert_eq!(arch.get_qemu_cpu_models(), None);
+		}
+		if DeviceArch::get_native_arch() == Some(&DeviceArch::amd64) {
+			assert!(!DeviceArch::armhf.is_native());
+		}
+		Ok(())
+	}
 }
@@ -0,0 +1,724 @@
+//! Module handling the estimation of the resources needed to build images.
+//!
+//! Durations come from the stage timings of previous builds, recorded in [`TIMINGS_FILE`] in the working directory.
+//! Without any recorded build, or without a bootstrapped distribution to measure, the estimates are guessed from the image size.
+//!
+//! Before a distribution is copied into a raw image, [`bootstrap_size`] plus [`PACKAGE_SIZE`] for each package to be installed is compared with the free space of the root filesystem.
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::{Path, PathBuf},
+	sync::Mutex,
+	thread,
+	time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+	cli::{Compression, OutputFormat},
+	compress::memory_usage,
+	context::ImageVariant,
+	device::DeviceSpec,
+	gc::measure,
+};
+
+/// Name of the timings store in the working directory.
+pub const TIMINGS_FILE: &str = "timings.json";
+/// Version of the format of the timings store.
+///
+/// Bump this on incompatible changes, stores of other versions are discarded.
+pub const TIMINGS_VERSION: u32 = 1;
+/// Number of the latest builds kept for each device, variant, format and compression.
+const TIMINGS_KEEP: usize = 5;
+/// Share of the image size assumed to be taken by the distribution if it is not bootstrapped yet.
+const GUESSED_USAGE: f64 = 0.5;
+/// Time spent on installing packages, running scripts and so on, regardless of the size.
+const FIXED_DURATION: f64 = 300.0;
+/// Rate of copying the distribution into the image, in bytes per second.
+const INSTALL_RATE: f64 = 64.0 * 1048576.0;
+/// Space assumed to be taken by each package installed into the image, in bytes.
+pub const PACKAGE_SIZE: u64 = 64 * 1048576;
+/// Maximum number of threads walking a distribution at the same time.
+const MAX_WALK_JOBS: usize = 8;
+
+/// Sizes of the distributions measured in this run, shared by the images built from them.
+static BOOTSTRAP_SIZES: Mutex<BTreeMap<PathBuf, u64>> = Mutex::new(BTreeMap::new());
+
+/// Duration of a stage of a build.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StageTiming {
+	pub name: String,
+	/// Duration in seconds.
+	pub secs: f64,
+}
+
+/// Stage timings of a single successful build.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildTimings {
+	/// Stages in the order they ran.
+	pub stages: Vec<StageTiming>,
+}
+
+impl BuildTimings {
+	/// Build the timings from the instants each stage began at, and the end of the build.
+	pub fn from_marks(start: Instant, marks: &[(String, Instant)], end: Instant) -> Self {
+		let mut stages = Vec::new();
+		let mut last = ("Preparing".to_owned(), start);
+		for (name, at) in marks
+			.iter()
+			.cloned()
+			.chain(std::iter::once((String::new(), end)))
+		{
+			let (last_name, since) = std::mem::replace(&mut last, (name, at));
+			stages.push(StageTiming {
+				name: last_name,
+				secs: at.saturating_duration_since(since).as_secs_f64(),
+			});
+		}
+		BuildTimings { stages }
+	}
+
+	pub fn total(&self) -> Duration {
+		Duration::from_secs_f64(self.stages.iter().map(|x| x.secs).sum())
+	}
+}
+
+/// The timings of the previous builds in the working directory.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimingStore {
+	pub version: u32,
+	/// Builds keyed by [`timing_key`], the latest last.
+	pub builds: BTreeMap<String, Vec<BuildTimings>>,
+}
+
+impl Default for TimingStore {
+	fn default() -> Self {
+		TimingStore {
+			version: TIMINGS_VERSION,
+			builds: BTreeMap::new(),
+		}
+	}
+}
+
+impl TimingStore {
+	/// Parse a store, discarding it if it is of another version.
+	pub fn from_json(content: &str) -> Result<Self> {
+		let value: serde_json::Value = serde_json::from_str(content)?;
+		let version = value.get("version").and_then(|x| x.as_u64());
+		if version != Some(TIMINGS_VERSION as u64) {
+			warn!(
+				"Discarding build timings of version {:?}, expected version {}",
+				version, TIMINGS_VERSION
+			);
+			return Ok(TimingStore::default());
+		}
+		Ok(serde_json::from_value(value)?)
+	}
+
+	/// Load the store in `workdir`, or an empty one if there's none.
+	pub fn load<P: AsRef<Path>>(workdir: P) -> Result<Self> {
+		let path = workdir.as_ref().join(TIMINGS_FILE);
+		if !path.exists() {
+			return Ok(TimingStore::default());
+		}
+		let content =
+			fs::read_to_string(&path).context(format!("Unable to read {}", path.display()))?;
+		TimingStore::from_json(&content).context(format!("Unable to parse {}", path.display()))
+	}
+
+	/// Save the store in `workdir`, replacing the previous one at once.
+	pub fn save<P: AsRef<Path>>(&self, workdir: P) -> Result<()> {
+		let path = workdir.as_ref().join(TIMINGS_FILE);
+		let tmp = path.with_extension(format!("json.{}", std::process::id()));
+		fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+		fs::rename(&tmp, &path).context(format!("Unable to save {}", path.display()))?;
+		Ok(())
+	}
+
+	/// Add a build, keeping only the latest ones.
+	pub fn record(&mut self, key: String, timings: BuildTimings) {
+		let builds = self.builds.entry(key).or_default();
+		builds.push(timings);
+		if builds.len() > TIMINGS_KEEP {
+			builds.drain(..builds.len() - TIMINGS_KEEP);
+		}
+	}
+
+	/// The mean duration of the recorded builds, and their number.
+	pub fn mean_duration(&self, key: &str) -> Option<(Duration, usize)> {
+		let builds = self.builds.get(key).filter(|x| !x.is_empty())?;
+		let total: Duration = builds.iter().map(BuildTimings::total).sum();
+		Some((total / builds.len() as u32, builds.len()))
+	}
+}
+
+/// Stage timings of an image built in this run.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ImageTimings {
+	/// Filename of the image.
+	pub name: String,
+	#[serde(flatten)]
+	pub timings: BuildTimings,
+}
+
+/// An image failed to build in this run, with `--keep-going`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ImageFailure {
+	/// Filename of the image.
+	pub name: String,
+	pub device: String,
+	pub variant: String,
+	/// The error and its causes, on one line.
+	pub error: String,
+	/// Time spent before the failure in seconds.
+	pub secs: f64,
+}
+
+/// An image skipped in this run with `--keep-going`, e.g. as the binfmt_misc support for its architecture is not available.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ImageSkip {
+	/// Filename of the image.
+	pub name: String,
+	pub device: String,
+	pub variant: String,
+	/// Why the image is skipped, on one line.
+	pub reason: String,
+}
+
+/// Timings of a build run, summarized at the end of it and written by `--timings-json`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RunTimings {
+	pub run: String,
+	/// Distributions bootstrapped in this run, named `<variant>-<arch>`.
+	pub bootstraps: Vec<StageTiming>,
+	/// Images built successfully.
+	pub images: Vec<ImageTimings>,
+	/// Images failed to build.
+	pub failures: Vec<ImageFailure>,
+	/// Images not built at all.
+	pub skipped: Vec<ImageSkip>,
+	/// Duration of the whole run in seconds.
+	pub secs: f64,
+}
+
+impl RunTimings {
+	/// Format the timings as a table per image, followed by the totals of each stage.
+	pub fn summary(&self) -> String {
+		let line = |name: &str, secs: f64| format!("  {:<32} {:>10.1} s\n", name, secs);
+		let mut result = String::from("Timings of this run:\n");
+		for bootstrap in &self.bootstraps {
+			result += &line(&format!("Bootstrapping {}", bootstrap.name), bootstrap.secs);
+		}
+		// Stages in the order they first appear.
+		let mut totals: Vec<(&str, f64)> = Vec::new();
+		for image in &self.images {
+			result += &format!("{}:\n", image.name);
+			for stage in &image.timings.stages {
+				result += &line(&stage.name, stage.secs);
+				match totals.iter_mut().find(|(name, _)| *name == stage.name) {
+					Some((_, secs)) => *secs += stage.secs,
+					None => totals.push((&stage.name, stage.secs)),
+				}
+			}
+			result += &line("Total", image.timings.total().as_secs_f64());
+		}
+		for failure in &self.failures {
+			result += &format!("{} (failed):\n", failure.name);
+			result += &line("Total", failure.secs);
+		}
+		for skip in &self.skipped {
+			result += &format!("{} (skipped): {}\n", skip.name, skip.reason);
+		}
+		if self.images.len() > 1 {
+			result += "All images:\n";
+			for (name, secs) in totals {
+				result += &line(name, secs);
+			}
+		}
+		result += &format!("Total: {:.1} s", self.secs);
+		result
+	}
+
+	/// Write the timings to `path` in JSON.
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+		let path = path.as_ref();
+		fs::write(path, serde_json::to_string_pretty(self)?)
+			.context(format!("Unable to write the timings to {}", path.display()))
+	}
+}
+
+/// The key of the builds of `device` in the timings store.
+pub fn timing_key(
+	device: &DeviceSpec,
+	variant: &ImageVariant,
+	format: &OutputFormat,
+	compression: &Compression,
+) -> String {
+	format!("{}/{}/{:?}/{:?}", device.id, variant, format, compression).to_lowercase()
+}
+
+/// Where the estimated duration comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DurationSource {
+	/// The mean of the recorded builds.
+	History { builds: usize },
+	/// Guessed from the size.
+	Heuristic,
+}
+
+/// The estimated resources needed to build an image.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Estimate {
+	pub device: String,
+	pub variant: String,
+	/// Peak disk usage of the working and output directories in bytes.
+	pub disk: u64,
+	/// Whether the size of the bootstrapped distribution is measured rather than guessed.
+	pub bootstrap_measured: bool,
+	/// Memory needed by the compression in bytes.
+	pub memory: u64,
+	/// Duration in seconds.
+	pub duration: u64,
+	pub duration_source: DurationSource,
+}
+
+/// Ratio of the compressed size to the data.
+fn compression_ratio(compression: &Compression) -> f64 {
+	match compression {
+		Compression::Xz => 0.25,
+		Compression::Zstd => 0.3,
+		Compression::Gzip => 0.35,
+		Compression::None => 1.0,
+	}
+}
+
+/// Rate of compressing the data using `threads` threads, in bytes per second.
+fn compression_rate(compression: &Compression, threads: u32) -> f64 {
+	let per_thread = match compression {
+		Compression::Xz => 4.0,
+		Compression::Zstd => 32.0,
+		// Single threaded.
+		Compression::Gzip => return 24.0 * 1048576.0,
+		Compression::None => return 512.0 * 1048576.0,
+	};
+	per_thread * 1048576.0 * threads as f64
+}
+
+/// Estimate the resources needed to build `device` with `variant`.
+///
+/// `data` is the size of the bootstrapped distribution, if there is one.
+pub fn estimate_image(
+	device: &DeviceSpec,
+	variant: &ImageVariant,
+	format: &OutputFormat,
+	compression: &Compression,
+	data: Option<u64>,
+	store: &TimingStore,
+) -> Result<Estimate> {
+	let image_size = device.size.get_variant_size_bytes(variant)?;
+	let bootstrap = data.unwrap_or((image_size as f64 * GUESSED_USAGE) as u64);
+	let compressed = (bootstrap as f64 * compression_ratio(compression)) as u64;
+	// The bootstrapped distribution is copied into the raw image or rootfs,
+	// which is compressed into the output in the end.
+	let disk = match (format, compression) {
+		(OutputFormat::Rawimg, Compression::None) => bootstrap + image_size * 2,
+		(OutputFormat::Rawimg, _) => bootstrap + image_size + compressed,
+		(OutputFormat::Tarball, _) => bootstrap * 2 + compressed,
+		// The sparse image takes about the used size next to the raw image.
+		(OutputFormat::Simg, _) => bootstrap * 2 + image_size + compressed,
+	};
+	let key = timing_key(device, variant, format, compression);
+	let (duration, duration_source) = match store.mean_duration(&key) {
+		Some((duration, builds)) => (duration, DurationSource::History { builds }),
+		None => {
+			let threads = crate::compress::get_compression_threads();
+			let secs = FIXED_DURATION
+				+ bootstrap as f64 / INSTALL_RATE
+				+ bootstrap as f64 / compression_rate(compression, threads);
+			(Duration::from_secs_f64(secs), DurationSource::Heuristic)
+		}
+	};
+	Ok(Estimate {
+		device: device.id.clone(),
+		variant: variant.to_string().to_lowercase(),
+		disk,
+		bootstrap_measured: data.is_some(),
+		memory: memory_usage(compression, None)?,
+		duration: duration.as_secs(),
+		duration_source,
+	})
+}
+
+/// Get the apparent size of the files in the directory at `path` in bytes, walking its top-level entries concurrently.
+///
+/// Symbolic links are not followed, and files with several hard links are counted for each of them.
+pub fn apparent_size(path: &Path) -> Result<u64> {
+	let entries = fs::read_dir(path)
+		.context(format!("Unable to read {}", path.display()))?
+		.map(|x| x.map(|x| x.path()))
+		.collect::<std::io::Result<Vec<_>>>()?;
+	let num_entries = entries.len();
+	let queue = Mutex::new(entries.into_iter());
+	let results = Mutex::new(Vec::with_capacity(num_entries));
+	thread::scope(|scope| {
+		for _ in 0..MAX_WALK_JOBS.min(num_entries) {
+			scope.spawn(|| {
+				loop {
+					let Some(entry) = queue.lock().unwrap().next() else {
+						break;
+					};
+					let size = WalkDir::new(&entry)
+						.follow_root_links(false)
+						.into_iter()
+						.try_fold(0, |size, x| {
+							let metadata = x?.metadata()?;
+							Ok::<u64, anyhow::Error>(if metadata.is_dir() {
+								size
+							} else {
+								size + metadata.len()
+							})
+						});
+					results
+						.lock()
+						.unwrap()
+						.push(size.context(format!("Unable to measure {}", entry.display())));
+				}
+			});
+		}
+	});
+	results.into_inner().unwrap().into_iter().sum()
+}
+
+/// Get the [apparent size](apparent_size) of the distribution at `base_dist`, measured once per run.
+pub fn bootstrap_size(base_dist: &Path) -> Result<u64> {
+	if let Some(size) = BOOTSTRAP_SIZES.lock().unwrap().get(base_dist) {
+		return Ok(*size);
+	}
+	let size = apparent_size(base_dist)?;
+	BOOTSTRAP_SIZES
+		.lock()
+		.unwrap()
+		.insert(base_dist.to_owned(), size);
+	Ok(size)
+}
+
+/// Estimate the resources needed to build `devices` with `variants`, and print them to stdout as a table or JSON.
+pub fn estimate_devices<P: AsRef<Path>>(
+	devices: &[DeviceSpec],
+	variants: &[ImageVariant],
+	format: &OutputFormat,
+	compression: &Compression,
+	workdir: P,
+	json: bool,
+) -> Result<()> {
+	let workdir = workdir.as_ref();
+	let store = TimingStore::load(workdir)?;
+	let mut estimates = Vec::new();
+	for device in devices {
+		for variant in variants {
+			let bootstrap = workdir.join(format!(
+				"bootstrap/{}-{}",
+				variant.to_string().to_lowercase(),
+				device.arch.to_string().to_lowercase()
+			));
+			let data = if bootstrap.is_dir() {
+				Some(measure(&bootstrap)?.0)
+			} else {
+				None
+			};
+			estimates.push(estimate_image(
+				device,
+				variant,
+				format,
+				compression,
+				data,
+				&store,
+			)?);
+		}
+	}
+	if json {
+		println!("{}", serde_json::to_string_pretty(&estimates)?);
+	} else {
+		print_table(&estimates);
+	}
+	Ok(())
+}
+
+fn print_table(estimates: &[Estimate]) {
+	println!(
+		"{:<32}{:<10}{:>12}{:>14}{:>12}  BASED ON",
+		"DEVICE", "VARIANT", "DISK (MiB)", "MEMORY (MiB)", "TIME (min)"
+	);
+	for x in estimates {
+		println!(
+			"{:<32}{:<10}{:>11}{}{:>14}{:>12}  {}",
+			x.device,
+			x.variant,
+			x.disk / 1048576,
+			if x.bootstrap_measured { " " } else { "*" },
+			x.memory.div_ceil(1048576),
+			x.duration.div_ceil(60),
+			match x.duration_source {
+				DurationSource::History { builds } => format!(
+					"{} previous build{}",
+					builds,
+					if builds == 1 { "" } else { "s" }
+				),
+				DurationSource::Heuristic => "image size".to_owned(),
+			}
+		);
+	}
+	if estimates.iter().any(|x| !x.bootstrap_measured) {
+		println!(
+			"\n* The distribution is not bootstrapped yet, its size is guessed from the image size."
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	const DEVICE: &str = r#"
+id = "test"
+vendor = "test"
+name = "Test Device"
+arch = "amd64"
+bsp_packages = []
+partition_map = "gpt"
+num_partitions = 1
+
+[size]
+base = 1024
+desktop = 4096
+server = 1024
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+size_in_sectors = 0
+filesystem = "ext4"
+"#;
+
+	fn build(secs: &[f64]) -> BuildTimings {
+		BuildTimings {
+			stages: secs
+				.iter()
+				.map(|x| StageTiming {
+					name: "Stage".to_owned(),
+					secs: *x,
+				})
+				.collect(),
+		}
+	}
+
+	#[test]
+	fn test_timing_store() -> Result<()> {
+		let start = Instant::now();
+		let marks = vec![
+			("Installing".to_owned(), start + Duration::from_secs(2)),
+			("Finishing up".to_owned(), start + Duration::from_secs(5)),
+		];
+		let timings = BuildTimings::from_marks(start, &marks, start + Duration::from_secs(6));
+		let names: Vec<_> = timings.stages.iter().map(|x| x.name.as_str()).collect();
+		assert_eq!(names, ["Preparing", "Installing", "Finishing up"]);
+		assert_eq!(timings.total(), Duration::from_secs(6));
+
+		let mut store = TimingStore::default();
+		assert_eq!(store.mean_duration("a"), None);
+		for secs in 1..=TIMINGS_KEEP + 2 {
+			store.record("a".to_owned(), build(&[secs as f64 * 10.0, 5.0]));
+		}
+		// Only the latest builds: 30..=70 plus 5 each
+		assert_eq!(
+			store.mean_duration("a"),
+			Some((Duration::from_secs(55), TIMINGS_KEEP))
+		);
+
+		let dir = TestDir::new("timings")?;
+		assert_eq!(TimingStore::load(&dir)?, TimingStore::default());
+		store.save(&dir)?;
+		let loaded = TimingStore::load(&dir);
+		assert_eq!(loaded?, store);
+		Ok(())
+	}
+
+	#[test]
+	fn test_run_timings() -> Result<()> {
+		let image = |name: &str, secs: &[(&str, f64)]| ImageTimings {
+			name: name.to_owned(),
+			timings: BuildTimings {
+				stages: secs
+					.iter()
+					.map(|(name, secs)| StageTiming {
+						name: name.to_string(),
+						secs: *secs,
+					})
+					.collect(),
+			},
+		};
+		let mut timings = RunTimings {
+			run: "run".to_owned(),
+			bootstraps: vec![StageTiming {
+				name: "base-amd64".to_owned(),
+				secs: 100.0,
+			}],
+			images: vec![image(
+				"a.img",
+				&[("Installing", 10.0), ("Compressing", 5.0)],
+			)],
+			failures: Vec::new(),
+			skipped: Vec::new(),
+			secs: 120.0,
+		};
+		let summary = timings.summary();
+		assert!(
+			summary.contains(&format!(
+				"  {:<32} {:>10.1} s\n",
+				"Bootstrapping base-amd64", 100.0
+			)),
+			"{}",
+			summary
+		);
+		assert!(summary.contains("a.img:\n  Installing"), "{}", summary);
+		assert!(summary.contains(&format!("  {:<32} {:>10.1} s\n", "Total", 15.0)));
+		assert!(!summary.contains("All images"));
+		assert!(summary.ends_with("Total: 120.0 s"));
+		timings
+			.images
+			.push(image("b.img", &[("Installing", 20.0), ("Applying", 1.0)]));
+		let summary = timings.summary();
+		let all = summary.split("All images:\n").nth(1).unwrap();
+		let names: Vec<_> = all
+			.lines()
+			.filter_map(|l| l.split_whitespace().next())
+			.collect();
+		assert_eq!(names, ["Installing", "Compressing", "Applying", "Total:"]);
+		assert!(all.contains(&format!("  {:<32} {:>10.1} s\n", "Installing", 30.0)));
+
+		let value = serde_json::to_value(&timings)?;
+		assert_eq!(value["images"][1]["name"], "b.img");
+		assert_eq!(value["images"][1]["stages"][0]["secs"], 20.0);
+		assert_eq!(value["bootstraps"][0]["name"], "base-amd64");
+		assert_eq!(value["failures"].as_array().map(Vec::len), Some(0));
+
+		timings.failures.push(ImageFailure {
+			name: "c.img".to_owned(),
+			device: "c".to_owned(),
+			variant: "base".to_owned(),
+			error: "Failed to install packages: exit status: 100".to_owned(),
+			secs: 7.0,
+		});
+		let summary = timings.summary();
+		assert!(
+			summary.contains(&format!(
+				"c.img (failed):\n  {:<32} {:>10.1} s\n",
+				"Total", 7.0
+			)),
+			"{}",
+			summary
+		);
+		let value = serde_json::to_value(&timings)?;
+		assert_eq!(value["failures"][0]["device"], "c");
+		assert_eq!(value["failures"][0]["error"], timings.failures[0].error);
+
+		timings.skipped.push(ImageSkip {
+			name: "d.img".to_owned(),
+			device: "d".to_owned(),
+			variant: "base".to_owned(),
+			reason: "binfmt_misc support for riscv64 is not available".to_owned(),
+		});
+		let summary = timings.summary();
+		assert!(
+			summary.contains("d.img (skipped): binfmt_misc support for riscv64 is not available\n"),
+			"{}",
+			summary
+		);
+		let value = serde_json::to_value(&timings)?;
+		assert_eq!(value["skipped"][0]["device"], "d");
+		Ok(())
+	}
+
+	#[test]
+	fn test_apparent_size() -> Result<()> {
+		let dir = TestDir::new("size")?;
+		fs::create_dir_all(dir.join("usr/lib"))?;
+		fs::create_dir_all(dir.join("etc"))?;
+		fs::write(dir.join("usr/lib/a"), vec![0; 3000])?;
+		fs::write(dir.join("etc/b"), vec![0; 500])?;
+		fs::write(dir.join("c"), vec![0; 20])?;
+		std::os::unix::fs::symlink("/usr", dir.join("d"))?;
+		let symlink = dir.join("d").symlink_metadata()?.len();
+		assert_eq!(apparent_size(&dir)?, 3520 + symlink);
+		assert_eq!(bootstrap_size(&dir)?, 3520 + symlink);
+		// Measured once
+		fs::write(dir.join("e"), vec![0; 100])?;
+		assert_eq!(bootstrap_size(&dir)?, 3520 + symlink);
+		assert_eq!(apparent_size(&dir)?, 3620 + symlink);
+		fs::remove_dir_all(&dir)?;
+		assert!(apparent_size(&dir).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_timing_store_format() -> Result<()> {
+		let store = TimingStore::from_json(
+			r#"{"version":1,"builds":{"test/base/rawimg/xz":[{"stages":[{"name":"Preparing","secs":1.5}]}]}}"#,
+		)?;
+		assert_eq!(
+			store.mean_duration("test/base/rawimg/xz"),
+			Some((Duration::from_secs_f64(1.5), 1))
+		);
+		let other = TimingStore::from_json(r#"{"version":2,"builds":{"x":[]}}"#)?;
+		assert_eq!(other, TimingStore::default());
+		assert!(TimingStore::from_json(r#"{"version":1,"builds":[]}"#).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_estimate_image() -> Result<()> {
+		let device: DeviceSpec = toml::from_str(DEVICE)?;
+		let format = OutputFormat::Rawimg;
+		let mut store = TimingStore::default();
+		let guessed = estimate_image(
+			&device,
+			&ImageVariant::Base,
+			&format,
+			&Compression::None,
+			None,
+			&store,
+		)?;
+		// 512 MiB guessed, copied to the raw image and the output
+		assert_eq!(guessed.disk, (512 + 1024 * 2) * 1048576);
+		assert!(!guessed.bootstrap_measured);
+		assert_eq!(guessed.duration_source, DurationSource::Heuristic);
+
+		let key = timing_key(&device, &ImageVariant::Desktop, &format, &Compression::Xz);
+		assert_eq!(key, "test/desktop/rawimg/xz");
+		store.record(key, build(&[600.0, 60.0]));
+		let measured = estimate_image(
+			&device,
+			&ImageVariant::Desktop,
+			&format,
+			&Compression::Xz,
+			Some(2048 * 1048576),
+			&store,
+		)?;
+		assert_eq!(measured.disk, (2048 + 4096 + 512) * 1048576);
+		assert!(measured.bootstrap_measured);
+		assert!(measured.memory > guessed.memory);
+		assert_eq!(measured.duration, 660);
+		assert_eq!(
+			measured.duration_source,
+			DurationSource::History { builds: 1 }
+		);
+		Ok(())
+	}
+}
@@ -0,0 +1,350 @@
+//! Module handling the export of the scripts and the configuration files a build would generate.
+//!
+//! Nothing is partitioned, formatted or run: the identifiers only known during a build are replaced with placeholders, which are listed in the `README` of the exported directory.
+//! Useful for reviewing the changes to a device spec, or debugging the scripts without building the image.
+use std::{
+	collections::HashMap,
+	fs::{self, create_dir_all},
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+	context::ImageContext,
+	device::{PartitionData, PartitionMapData},
+	partition::PartitionUsage,
+	utils::{chroot_script_command, chroot_str_script_command, format_command, partition_path},
+};
+
+/// Placeholder of the loop device the image is attached to.
+pub(crate) const LOOPDEV_PLACEHOLDER: &str = "@LOOPDEV@";
+/// Placeholder of the mountpoint of the root filesystem on the build host.
+pub(crate) const ROOTFS_PLACEHOLDER: &str = "@ROOTFS@";
+/// Placeholder of the directory the partitions are mounted in on the build host.
+const MOUNTDIR_PLACEHOLDER: &str = "@MOUNTDIR@";
+/// Placeholder of the UUID of the partition table.
+const DISKUUID_PLACEHOLDER: &str = "@DISKUUID@";
+/// Placeholder of the kernel image discovered in the target, relative to the boot partition.
+pub(crate) const LINUX_PLACEHOLDER: &str = "@LINUX@";
+/// Placeholder of the init ramdisk discovered in the target, relative to the boot partition.
+pub(crate) const INITRD_PLACEHOLDER: &str = "@INITRD@";
+
+const README: &str = "Generated by `mkrawimg export-scripts`, for inspection only.
+
+Values only known during the build are replaced with the following placeholders:
+
+- @LOOPDEV@: The loop device the image is attached to, e.g. /dev/loop0.
+  Partitions are @LOOPDEV@p1, @LOOPDEV@p2, ...
+- @ROOTFS@: The mountpoint of the root filesystem on the build host.
+- @MOUNTDIR@: The directory the partitions are mounted in on the build host,
+  as @MOUNTDIR@/p1, @MOUNTDIR@/p2, ...
+- @DISKUUID@: The UUID (GPT) or the disk identifier (MBR) of the partition table.
+- @PARTUUID_N@: The UUID of partition N.
+- @FSUUID_N@: The UUID of the filesystem in partition N, unless it is defined
+  in the device spec.
+- @LINUX@, @INITRD@: The kernel image and the init ramdisk discovered in the
+  target system, relative to the boot partition.
+
+The machine names of the containers contain the process ID, which differs in
+actual builds.
+
+Files:
+
+- spec.sh: The variables sourced by the scripts running in the container.
+- fstab: The entries appended to /etc/fstab.
+- cmdline: The content of /etc/kernel/cmdline, if the kernel command line is
+  defined.
+- sources.list: The content of /etc/apt/sources.list.d/mkrawimg-extra.list,
+  if extra package sources are defined. The temporary entries are marked.
+- commands.sh: The commands a build runs, in order. Not meant to be run as is.
+- bootloaders/: The generated bootloader scripts and configuration files.
+";
+
+impl ImageContext<'_> {
+	/// The partition map data with the placeholders in place of the identifiers generated during the build.
+	fn placeholder_pm_data(&self) -> Result<PartitionMapData> {
+		let mut data = HashMap::new();
+		for partition in &self.device.partitions {
+			let num = partition.num;
+			let filesystem = self.partition_filesystem(partition);
+			let fs_uuid = if !partition.formatted_at_build() {
+				None
+			} else {
+				Some(
+					self.planned_fs_uuid(partition, filesystem)?
+						.unwrap_or_else(|| format!("@FSUUID_{}@", num)),
+				)
+			};
+			data.insert(
+				num,
+				PartitionData {
+					num,
+					part_uuid: format!("@PARTUUID_{}@", num),
+					fs_uuid,
+				},
+			);
+		}
+		Ok(PartitionMapData {
+			uuid: DISKUUID_PLACEHOLDER.to_owned(),
+			data,
+		})
+	}
+
+	/// Write everything generated for this image into `outdir/<device>-<variant>`, returning the path to it.
+	pub fn export_scripts(&self, outdir: &Path) -> Result<PathBuf> {
+		let dir = outdir.join(format!(
+			"{}-{}",
+			&self.device.id,
+			self.variant.to_string().to_lowercase()
+		));
+		let bootloader_dir = dir.join("bootloaders");
+		create_dir_all(&bootloader_dir)
+			.context(format!("Unable to create {}", bootloader_dir.display()))?;
+		let root_num = self
+			.device
+			.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Rootfs)
+			.context("Unable to find a root filesystem")?
+			.num;
+		let loopdev = Path::new(LOOPDEV_PLACEHOLDER);
+		let rootfs = Path::new(ROOTFS_PLACEHOLDER);
+		let pm_data = self.placeholder_pm_data()?;
+		let machine = self.machine_name();
+		let binds = self.nspawn_binds(loopdev)?;
+		let binds = binds.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+
+		fs::write(dir.join("README"), README)?;
+		let spec_script =
+			self.gen_spec_script(&loopdev, &partition_path(loopdev, root_num), &pm_data)?;
+		fs::write(dir.join("spec.sh"), spec_script)?;
+		fs::write(dir.join("fstab"), self.gen_fstab(&pm_data)?)?;
+		if self.device.kernel_cmdline.is_some() {
+			fs::write(
+				dir.join("cmdline"),
+				format!("{}\n", self.kernel_cmdline(&pm_data)?),
+			)?;
+		}
+		let sources = self.extra_sources()?;
+		if !sources.is_empty() {
+			let content: String = sources
+				.iter()
+				.map(|(line, persist)| {
+					if *persist {
+						format!("{}\n", line)
+					} else {
+						format!("# Removed after the post installation scripts\n{}\n", line)
+					}
+				})
+				.collect();
+			fs::write(dir.join("sources.list"), content)?;
+		}
+
+		let mut commands = vec![
+			"# Generated by `mkrawimg export-scripts`, see README for the placeholders.".to_owned(),
+			String::new(),
+			"# Formatting partitions".to_owned(),
+		];
+		for partition in &self.device.partitions {
+			if partition.format_on_first_boot {
+				commands.push(format!(
+					"# Partition {} is formatted on first boot, see fstab",
+					partition.num
+				));
+			}
+			if !partition.formatted_at_build() {
+				continue;
+			}
+			let filesystem = self.partition_filesystem(partition);
+			let cmd = filesystem.get_mkfs_cmdline(
+				&partition_path(loopdev, partition.num),
+				partition.fs_label.to_owned(),
+				self.planned_fs_uuid(partition, filesystem)?.as_deref(),
+			)?;
+			commands.push(format_command(&cmd));
+		}
+		if self.device.partitions.iter().any(|p| p.content.is_some()) {
+			let dirname = self
+				.device
+				.file_path
+				.parent()
+				.context("Unable to find the directory containing the device spec")?;
+			commands.push(String::new());
+			commands.push("# Populating partitions".to_owned());
+			for partition in &self.device.partitions {
+				let Some(content) = &partition.content else {
+					continue;
+				};
+				let dst = Path::new(MOUNTDIR_PLACEHOLDER).join(format!("p{}", partition.num));
+				let cmd = content.populate_command(dirname, &partition.filesystem, &dst);
+				commands.push(format_command(&cmd));
+			}
+		}
+		commands.push(String::new());
+		commands.push("# Installing BSP packages".to_owned());
+		let pkgs = self
+			.device
+			.bsp_packages
+			.iter()
+			.map(String::as_str)
+			.collect::<Vec<&str>>();
+		for script in self.install_scripts(&pkgs)? {
+			let cmd = chroot_str_script_command(&rootfs, &machine, &script, &[], None);
+			commands.push(format_command(&cmd));
+		}
+		commands.push(String::new());
+		commands.push("# Post installation scripts, copied to /tmp of the target".to_owned());
+		for script in self.device.find_postinst_scripts(self.variant)? {
+			let filename = script
+				.file_name()
+				.context("Unable to get the basename of the script")?;
+			commands.push(format!("# {}", script.display()));
+			let script = Path::new("/tmp").join(filename);
+			let cmd = chroot_script_command(rootfs, &machine, &script, &binds, None)?;
+			commands.push(format_command(&cmd));
+		}
+		commands.push(String::new());
+		commands.push("# Bootloaders, generated files are in bootloaders/".to_owned());
+		commands.extend(self.export_bootloaders(loopdev, &binds, &pm_data, &bootloader_dir)?);
+		fs::write(dir.join("commands.sh"), commands.join("\n") + "\n")?;
+		Ok(dir)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		device::DeviceSpec,
+		fixtures::{TestDir, assert_golden, fixture_device, test_context},
+		plan::BuildRun,
+		pm::Distro,
+	};
+
+	const TEST_DEVICE: &str = r#"
+id = "test"
+vendor = "test"
+name = "Test Device"
+arch = "amd64"
+bsp_packages = ["linux+kernel", "systemd-boot"]
+partition_map = "gpt"
+num_partitions = 2
+kernel_cmdline = "rw quiet"
+
+[size]
+base = 512
+desktop = 512
+server = 512
+
+[[partition]]
+num = 1
+type = "efi"
+usage = "boot"
+size_in_sectors = 262144
+mountpoint = "/efi"
+filesystem = "fat32"
+fs_uuid = "abcd1234"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+size_in_sectors = 0
+mountpoint = "/"
+filesystem = "ext4"
+
+[[bootloader]]
+type = "systemd_boot"
+
+[[bootloader]]
+type = "script"
+name = "apply-bootloader.sh"
+"#;
+
+	#[test]
+	fn test_export_scripts() -> Result<()> {
+		let dir = TestDir::new("export")?;
+		let spec_dir = dir.join("spec");
+		create_dir_all(&spec_dir)?;
+		fs::write(spec_dir.join("apply-bootloader.sh"), "echo hi\n")?;
+		fs::write(spec_dir.join("postinst.sh"), "echo hi\n")?;
+		let mut device: DeviceSpec = toml::from_str(TEST_DEVICE)?;
+		device.file_path = spec_dir.join("device.toml");
+		let run = BuildRun::new(None)?;
+		let ctx = test_context(&device, &run);
+		let result = ctx.export_scripts(&dir.join("out"));
+		let read = |name: &str| fs::read_to_string(dir.join("out/test-base").join(name));
+		let files = result.and_then(|out| {
+			assert_eq!(out, dir.join("out/test-base"));
+			Ok((
+				read("spec.sh")?,
+				read("fstab")?,
+				read("cmdline")?,
+				read("commands.sh")?,
+				read("bootloaders/01-aosc-os.conf")?,
+				read("bootloaders/02-apply-bootloader.sh")?,
+			))
+		});
+		// Other distributions have no scripts to install the packages yet
+		let mut debian = device.clone();
+		debian.distro = Distro::Debian;
+		let unsupported = test_context(&debian, &run).export_scripts(&dir.join("debian"));
+		let (spec, fstab, cmdline, commands, entry, script) = files?;
+		assert!(
+			unsupported
+				.unwrap_err()
+				.to_string()
+				.contains("distribution Debian is not supported"),
+		);
+		assert!(spec.contains("LOOPDEV='@LOOPDEV@'\n"), "{}", spec);
+		assert!(spec.contains("ROOTPART='@LOOPDEV@p2'\n"), "{}", spec);
+		assert!(spec.contains("PART2_PARTUUID='@PARTUUID_2@'\n"), "{}", spec);
+		// Filesystem UUIDs defined in the spec are used as is
+		assert!(spec.contains("EFI_FSUUID='ABCD-1234'\n"), "{}", spec);
+		assert!(fstab.contains("UUID=\"@FSUUID_2@\"\t/\text4"), "{}", fstab);
+		assert_eq!(cmdline, "root=UUID=@FSUUID_2@ rw quiet\n");
+		assert!(
+			commands.contains("mkfs.vfat -F 32 -i ABCD1234 -- @LOOPDEV@p1\n"),
+			"{}",
+			commands
+		);
+		assert!(
+			commands.contains("linux+kernel systemd-boot"),
+			"{}",
+			commands
+		);
+		assert!(commands.contains("# 02: script apply-bootloader.sh"));
+		assert_eq!(
+			entry,
+			"title AOSC OS\nlinux @LINUX@\ninitrd @INITRD@\noptions root=UUID=@FSUUID_2@ rw quiet\n"
+		);
+		assert_eq!(script, "echo hi\n");
+		Ok(())
+	}
+
+	#[test]
+	fn test_export_fixtures() -> Result<()> {
+		let dir = TestDir::new("export-fixtures")?;
+		let run = BuildRun::new(None)?;
+		for id in [
+			"fixture-gpt-efi",
+			"fixture-mbr-uboot",
+			"fixture-gpt-layout",
+			"fixture-armhf-sunxi",
+		] {
+			let device = fixture_device(id)?;
+			let ctx = test_context(&device, &run);
+			let out = ctx.export_scripts(&dir)?;
+			for name in ["spec.sh", "fstab", "sources.list"] {
+				if name == "sources.list" && device.sources.is_none() {
+					assert!(!out.join(name).exists());
+					continue;
+				}
+				let actual = fs::read_to_string(out.join(name))?;
+				assert_golden(&format!("{}.{}", id, name), &actual)?;
+			}
+		}
+		Ok(())
+	}
+}
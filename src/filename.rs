@@ -0,0 +1,403 @@
+//! Module handling the templates of the output filenames.
+//!
+//! A template is a filename with placeholders in braces, e.g. the default one:
+//!
+//! ```text
+//! aosc-os_{variant}_{format}_{vendor}_{id}_{date}{revision}_{arch}.{ext}{compress_ext}
+//! ```
+//!
+//! Possible placeholders:
+//!
+//! - `{variant}`: The variant of the image in lowercase, e.g. `desktop`.
+//! - `{format}`: `rawimg` for raw images, `rootfs` for tarballs, `simg` for sparse images.
+//! - `{vendor}`: The vendor of the device, e.g. `raspberrypi`.
+//! - `{id}`: The ID of the device, e.g. `rpi-5b`.
+//! - `{alias0}`: The first alias of the device, or the ID if there's none.
+//! - `{date}`: The date of the build run, e.g. `20241108`.
+//! - `{revision}`: The revision prefixed with a dot, e.g. `.1`. Empty if no revision is specified.
+//! - `{arch}`: The architecture of the device in lowercase, e.g. `arm64`.
+//! - `{ext}`: `img` for raw images, `tar` for tarballs.
+//! - `{compress_ext}`: The extension of the compression format with its dot, e.g. `.xz`. Empty if the output is not compressed.
+//!
+//! Templates are validated before anything is built: unknown placeholders, unbalanced braces and characters which would place the output outside of the output directory are rejected.
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{Context, Error, Result, bail};
+
+/// The template of the names used before templates were introduced.
+pub const DEFAULT_TEMPLATE: &str =
+	"aosc-os_{variant}_{format}_{vendor}_{id}_{date}{revision}_{arch}.{ext}{compress_ext}";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placeholder {
+	Variant,
+	Format,
+	Vendor,
+	Id,
+	Alias0,
+	Date,
+	Revision,
+	Arch,
+	Ext,
+	CompressExt,
+}
+
+impl Placeholder {
+	const ALL: [Placeholder; 10] = [
+		Placeholder::Variant,
+		Placeholder::Format,
+		Placeholder::Vendor,
+		Placeholder::Id,
+		Placeholder::Alias0,
+		Placeholder::Date,
+		Placeholder::Revision,
+		Placeholder::Arch,
+		Placeholder::Ext,
+		Placeholder::CompressExt,
+	];
+
+	fn name(&self) -> &'static str {
+		match self {
+			Placeholder::Variant => "variant",
+			Placeholder::Format => "format",
+			Placeholder::Vendor => "vendor",
+			Placeholder::Id => "id",
+			Placeholder::Alias0 => "alias0",
+			Placeholder::Date => "date",
+			Placeholder::Revision => "revision",
+			Placeholder::Arch => "arch",
+			Placeholder::Ext => "ext",
+			Placeholder::CompressExt => "compress_ext",
+		}
+	}
+
+	/// Whether the placeholder can be replaced with an empty string.
+	fn may_be_empty(&self) -> bool {
+		matches!(self, Placeholder::Revision | Placeholder::CompressExt)
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+	Literal(String),
+	Placeholder(Placeholder),
+}
+
+/// The values of the placeholders for one image.
+#[derive(Clone, Debug)]
+pub struct FilenameFields<'a> {
+	pub variant: &'a str,
+	pub format: &'a str,
+	pub vendor: &'a str,
+	pub id: &'a str,
+	pub alias0: Option<&'a str>,
+	pub date: &'a str,
+	pub revision: Option<u32>,
+	pub arch: &'a str,
+	pub ext: &'a str,
+	pub compress_ext: &'a str,
+}
+
+/// A validated filename template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilenameTemplate {
+	source: String,
+	segments: Vec<Segment>,
+}
+
+impl FromStr for FilenameTemplate {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		if let Some(c) = s.chars().find(|c| *c == '/' || *c == '\0') {
+			bail!(
+				"Filename template '{}' contains {:?}, which would place the output outside of the output directory",
+				s,
+				c
+			);
+		}
+		let mut segments = Vec::new();
+		let mut rest = s;
+		while !rest.is_empty() {
+			let literal_end = rest.find(['{', '}']).unwrap_or(rest.len());
+			if literal_end > 0 {
+				segments.push(Segment::Literal(rest[..literal_end].to_owned()));
+			}
+			rest = &rest[literal_end..];
+			if rest.starts_with('}') {
+				bail!("Filename template '{}' has an unmatched '}}'", s);
+			}
+			let Some(inner) = rest.strip_prefix('{') else {
+				break;
+			};
+			let Some(end) = inner.find('}') else {
+				bail!("Filename template '{}' has an unmatched '{{'", s);
+			};
+			let name = &inner[..end];
+			let Some(placeholder) = Placeholder::ALL.iter().find(|p| p.name() == name) else {
+				bail!(
+					"Unknown placeholder '{{{}}}' in filename template '{}', possible placeholders are: {}",
+					name,
+					s,
+					Placeholder::ALL
+						.iter()
+						.map(|p| format!("{{{}}}", p.name()))
+						.collect::<Vec<_>>()
+						.join(", ")
+				);
+			};
+			segments.push(Segment::Placeholder(*placeholder));
+			rest = &inner[end + 1..];
+		}
+		// The shortest name it renders to, with the placeholders which can be empty left out.
+		let has_value = segments
+			.iter()
+			.any(|s| matches!(s, Segment::Placeholder(p) if !p.may_be_empty()));
+		let literals: String = segments
+			.iter()
+			.filter_map(|s| match s {
+				Segment::Literal(l) => Some(l.as_str()),
+				_ => None,
+			})
+			.collect();
+		if !has_value && matches!(literals.as_str(), "" | "." | "..") {
+			bail!(
+				"Filename template '{}' may render to '{}', which is not a valid filename",
+				s,
+				literals
+			);
+		}
+		Ok(FilenameTemplate {
+			source: s.to_owned(),
+			segments,
+		})
+	}
+}
+
+impl Default for FilenameTemplate {
+	fn default() -> Self {
+		DEFAULT_TEMPLATE
+			.parse()
+			.expect("The default filename template must be valid")
+	}
+}
+
+impl Display for FilenameTemplate {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&self.source)
+	}
+}
+
+impl FilenameTemplate {
+	/// The filename of the image described by `fields`.
+	pub fn render(&self, fields: &FilenameFields) -> String {
+		let mut result = String::new();
+		for segment in &self.segments {
+			match segment {
+				Segment::Literal(l) => result += l,
+				Segment::Placeholder(p) => match p {
+					Placeholder::Variant => result += fields.variant,
+					Placeholder::Format => result += fields.format,
+					Placeholder::Vendor => result += fields.vendor,
+					Placeholder::Id => result += fields.id,
+					Placeholder::Alias0 => result += fields.alias0.unwrap_or(fields.id),
+					Placeholder::Date => result += fields.date,
+					Placeholder::Revision => {
+						if let Some(x) = fields.revision {
+							result += &format!(".{}", x);
+						}
+					}
+					Placeholder::Arch => result += fields.arch,
+					Placeholder::Ext => result += fields.ext,
+					Placeholder::CompressExt => result += fields.compress_ext,
+				},
+			}
+		}
+		result
+	}
+
+	/// The name of the next revision of the image `name`, which was rendered from this template, and the new revision.
+	///
+	/// The revision of an image without one becomes 1.
+	pub fn next_revision(&self, name: &str) -> Result<(String, u32)> {
+		if !self
+			.segments
+			.contains(&Segment::Placeholder(Placeholder::Revision))
+		{
+			bail!(
+				"Filename template '{}' has no {{revision}}, unable to name the next revision of '{}'",
+				self.source,
+				name
+			);
+		}
+		let Some(Some((start, end))) = match_revision(&self.segments, name, 0) else {
+			bail!(
+				"'{}' does not match the filename template '{}'",
+				name,
+				self.source
+			);
+		};
+		let revision = match &name[start..end] {
+			"" => 1,
+			x => x[1..]
+				.parse::<u32>()?
+				.checked_add(1)
+				.context(format!("Revision of '{}' is too large", name))?,
+		};
+		Ok((
+			format!("{}.{}{}", &name[..start], revision, &name[end..]),
+			revision,
+		))
+	}
+}
+
+/// Match `name` from `pos` against `segments`, returning where the first `{revision}` is in it, if any.
+///
+/// The placeholders take as few characters as possible, except `{revision}`, so the date is not mistaken for the revision.
+fn match_revision(segments: &[Segment], name: &str, pos: usize) -> Option<Option<(usize, usize)>> {
+	let Some((first, rest)) = segments.split_first() else {
+		return (pos == name.len()).then_some(None);
+	};
+	match first {
+		Segment::Literal(l) => name[pos..]
+			.starts_with(l.as_str())
+			.then(|| match_revision(rest, name, pos + l.len()))
+			.flatten(),
+		Segment::Placeholder(Placeholder::Revision) => {
+			let digits = name[pos..]
+				.strip_prefix('.')
+				.map_or(0, |x| x.chars().take_while(|c| c.is_ascii_digit()).count());
+			(1..=digits)
+				.rev()
+				.map(|n| pos + 1 + n)
+				.chain(std::iter::once(pos))
+				.find_map(|end| match_revision(rest, name, end).map(|_| Some((pos, end))))
+		}
+		Segment::Placeholder(p) => {
+			let min = if p.may_be_empty() { 0 } else { 1 };
+			(pos + min..=name.len())
+				.filter(|x| name.is_char_boundary(*x))
+				.find_map(|end| match_revision(rest, name, end))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn fields(revision: Option<u32>, compress_ext: &str) -> FilenameFields<'_> {
+		FilenameFields {
+			variant: "desktop",
+			format: "rawimg",
+			vendor: "raspberrypi",
+			id: "rpi-5b",
+			alias0: None,
+			date: "20241108",
+			revision,
+			arch: "arm64",
+			ext: "img",
+			compress_ext,
+		}
+	}
+
+	#[test]
+	fn test_render_filename() -> Result<()> {
+		let template = FilenameTemplate::default();
+		assert_eq!(template.to_string(), DEFAULT_TEMPLATE);
+		assert_eq!(
+			template.render(&fields(Some(1), ".xz")),
+			"aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108.1_arm64.img.xz"
+		);
+		// Without a revision
+		assert_eq!(
+			template.render(&fields(None, ".xz")),
+			"aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108_arm64.img.xz"
+		);
+		// Without compression
+		assert_eq!(
+			template.render(&fields(None, "")),
+			"aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108_arm64.img"
+		);
+		let template: FilenameTemplate = "{alias0}-{arch}-{variant}{revision}.{ext}".parse()?;
+		assert_eq!(
+			template.render(&fields(None, "")),
+			"rpi-5b-arm64-desktop.img"
+		);
+		let mut with_alias = fields(Some(2), "");
+		with_alias.alias0 = Some("pi5");
+		assert_eq!(template.render(&with_alias), "pi5-arm64-desktop.2.img");
+		Ok(())
+	}
+
+	#[test]
+	fn test_next_revision() -> Result<()> {
+		let template = FilenameTemplate::default();
+		assert_eq!(
+			template
+				.next_revision("aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108_arm64.img.xz")?,
+			(
+				"aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108.1_arm64.img.xz".to_owned(),
+				1
+			)
+		);
+		assert_eq!(
+			template
+				.next_revision("aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108.9_arm64.img")?,
+			(
+				"aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108.10_arm64.img".to_owned(),
+				10
+			)
+		);
+		let template: FilenameTemplate = "{id}{revision}.{ext}{compress_ext}".parse()?;
+		assert_eq!(
+			template.next_revision("rpi-5b.img.zst")?,
+			("rpi-5b.1.img.zst".to_owned(), 1)
+		);
+		assert_eq!(
+			template.next_revision("rpi-5b.1.img.zst")?,
+			("rpi-5b.2.img.zst".to_owned(), 2)
+		);
+		// Not rendered from the template
+		assert!(template.next_revision("rpi-5b").is_err());
+		assert!(
+			FilenameTemplate::default()
+				.next_revision("rpi-5b.img")
+				.is_err()
+		);
+		// Without a revision
+		let template: FilenameTemplate = "{id}.{ext}".parse()?;
+		assert!(template.next_revision("rpi-5b.img").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_invalid_templates() {
+		for template in [
+			"{variant}_{name}.img",
+			"{Variant}.img",
+			"{id",
+			"id}.img",
+			"{}.img",
+			"../{id}.img",
+			"{vendor}/{id}.img",
+			"",
+			"..",
+			".{revision}",
+			"{revision}{compress_ext}",
+		] {
+			assert!(
+				template.parse::<FilenameTemplate>().is_err(),
+				"{} is accepted",
+				template
+			);
+		}
+		for template in ["{id}", "..{id}", "{date}{revision}"] {
+			assert!(
+				template.parse::<FilenameTemplate>().is_ok(),
+				"{} is rejected",
+				template
+			);
+		}
+	}
+}
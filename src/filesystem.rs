@@ -1,12 +1,20 @@
 use anyhow::{Context, Ok, Result, anyhow, bail};
+use log::debug;
 use serde::{Deserialize, Serialize};
-use std::{path::Path, process::Command};
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	process::Command,
+	sync::Mutex,
+	thread,
+};
+use uuid::Uuid;
 
 use crate::{
 	context::ImageContext,
 	device::PartitionMapData,
-	partition::PartitionUsage,
-	utils::{cmd_run_check_status, get_fsuuid},
+	partition::{PartitionSpec, PartitionUsage},
+	utils::{get_fsuuid, partition_path},
 };
 
 /// Speifies which filesystem to be formatted to a partition.
@@ -28,32 +36,453 @@ pub enum FilesystemType {
 	None,
 }
 
+/// Syntax of the value of a mount option.
+#[derive(Clone, Copy, Debug)]
+enum OptValue {
+	/// No value, e.g. `noatime`.
+	Flag,
+	/// A decimal integer, e.g. `commit=120`.
+	Uint,
+	/// An octal integer, e.g. `umask=0077`.
+	Octal,
+	/// One of the listed values, e.g. `data=ordered`.
+	Choice(&'static [&'static str]),
+	/// Either no value or one of the listed values, e.g. `space_cache` and `space_cache=v2`.
+	FlagOrChoice(&'static [&'static str]),
+	/// A compression algorithm with an optional level, e.g. `compress=zstd:3`.
+	Compress,
+	/// Any value.
+	Any,
+	/// Either no value or any value, e.g. `x-systemd.makefs` and `x-systemd.idle-timeout=60`.
+	FlagOrAny,
+}
+
+impl OptValue {
+	fn accepts(&self, value: Option<&str>) -> bool {
+		let is_uint = |v: &str| !v.is_empty() && v.chars().all(|c| c.is_ascii_digit());
+		match (self, value) {
+			(Self::Flag, None) | (Self::FlagOrChoice(_), None) => true,
+			(Self::Uint, Some(v)) => is_uint(v),
+			(Self::Octal, Some(v)) => !v.is_empty() && v.chars().all(|c| ('0'..='7').contains(&c)),
+			(Self::Choice(choices), Some(v)) | (Self::FlagOrChoice(choices), Some(v)) => {
+				choices.contains(&v)
+			}
+			(Self::Compress, Some(v)) => {
+				let (algo, level) = v.split_once(':').map_or((v, None), |(a, l)| (a, Some(l)));
+				match algo {
+					"zlib" | "zstd" | "lzo" => {
+						level.is_none_or(is_uint) && !(algo == "lzo" && level.is_some())
+					}
+					"no" | "none" => level.is_none(),
+					_ => false,
+				}
+			}
+			(Self::Any, Some(v)) | (Self::FlagOrAny, Some(v)) => !v.is_empty(),
+			(Self::FlagOrAny, None) => true,
+			_ => false,
+		}
+	}
+}
+
+/// Options handled by the VFS layer or by mount(8) and systemd, valid for all filesystems.
+///
+/// These are written to `/etc/fstab`, but not passed to the filesystem during the build.
+const GENERIC_MOUNT_OPTS: &[(&str, OptValue)] = &[
+	("defaults", OptValue::Flag),
+	("ro", OptValue::Flag),
+	("rw", OptValue::Flag),
+	("exec", OptValue::Flag),
+	("noexec", OptValue::Flag),
+	("suid", OptValue::Flag),
+	("nosuid", OptValue::Flag),
+	("dev", OptValue::Flag),
+	("nodev", OptValue::Flag),
+	("atime", OptValue::Flag),
+	("noatime", OptValue::Flag),
+	("diratime", OptValue::Flag),
+	("nodiratime", OptValue::Flag),
+	("relatime", OptValue::Flag),
+	("norelatime", OptValue::Flag),
+	("strictatime", OptValue::Flag),
+	("nostrictatime", OptValue::Flag),
+	("lazytime", OptValue::Flag),
+	("nolazytime", OptValue::Flag),
+	("sync", OptValue::Flag),
+	("async", OptValue::Flag),
+	("dirsync", OptValue::Flag),
+	("auto", OptValue::Flag),
+	("noauto", OptValue::Flag),
+	("nofail", OptValue::Flag),
+	("user", OptValue::Flag),
+	("nouser", OptValue::Flag),
+	("users", OptValue::Flag),
+	("owner", OptValue::Flag),
+	("group", OptValue::Flag),
+	("_netdev", OptValue::Flag),
+];
+
+const EXT4_MOUNT_OPTS: &[(&str, OptValue)] = &[
+	("acl", OptValue::Flag),
+	("noacl", OptValue::Flag),
+	("user_xattr", OptValue::Flag),
+	("nouser_xattr", OptValue::Flag),
+	("discard", OptValue::Flag),
+	("nodiscard", OptValue::Flag),
+	("barrier", OptValue::FlagOrChoice(&["0", "1"])),
+	("nobarrier", OptValue::Flag),
+	("commit", OptValue::Uint),
+	(
+		"data",
+		OptValue::Choice(&["journal", "ordered", "writeback"]),
+	),
+	(
+		"errors",
+		OptValue::Choice(&["continue", "remount-ro", "panic"]),
+	),
+	("journal_checksum", OptValue::Flag),
+	("nojournal_checksum", OptValue::Flag),
+	("journal_async_commit", OptValue::Flag),
+	("delalloc", OptValue::Flag),
+	("nodelalloc", OptValue::Flag),
+	("dioread_nolock", OptValue::Flag),
+	("dioread_lock", OptValue::Flag),
+	("auto_da_alloc", OptValue::FlagOrChoice(&["0", "1"])),
+	("noauto_da_alloc", OptValue::Flag),
+	("inode_readahead_blks", OptValue::Uint),
+	("stripe", OptValue::Uint),
+	("min_batch_time", OptValue::Uint),
+	("max_batch_time", OptValue::Uint),
+	("init_itable", OptValue::FlagOrChoice(&[])),
+	("noinit_itable", OptValue::Flag),
+	("block_validity", OptValue::Flag),
+	("noblock_validity", OptValue::Flag),
+	("resuid", OptValue::Uint),
+	("resgid", OptValue::Uint),
+	("usrquota", OptValue::Flag),
+	("grpquota", OptValue::Flag),
+	("prjquota", OptValue::Flag),
+];
+
+const XFS_MOUNT_OPTS: &[(&str, OptValue)] = &[
+	("allocsize", OptValue::Any),
+	("attr2", OptValue::Flag),
+	("noattr2", OptValue::Flag),
+	("discard", OptValue::Flag),
+	("nodiscard", OptValue::Flag),
+	("grpid", OptValue::Flag),
+	("nogrpid", OptValue::Flag),
+	("inode32", OptValue::Flag),
+	("inode64", OptValue::Flag),
+	("largeio", OptValue::Flag),
+	("nolargeio", OptValue::Flag),
+	("logbufs", OptValue::Uint),
+	("logbsize", OptValue::Any),
+	("noalign", OptValue::Flag),
+	("norecovery", OptValue::Flag),
+	("nouuid", OptValue::Flag),
+	("noquota", OptValue::Flag),
+	("quota", OptValue::Flag),
+	("uquota", OptValue::Flag),
+	("usrquota", OptValue::Flag),
+	("gquota", OptValue::Flag),
+	("grpquota", OptValue::Flag),
+	("pquota", OptValue::Flag),
+	("prjquota", OptValue::Flag),
+	("sunit", OptValue::Uint),
+	("swidth", OptValue::Uint),
+	("swalloc", OptValue::Flag),
+	("wsync", OptValue::Flag),
+	("filestreams", OptValue::Flag),
+];
+
+const BTRFS_MOUNT_OPTS: &[(&str, OptValue)] = &[
+	("acl", OptValue::Flag),
+	("noacl", OptValue::Flag),
+	("autodefrag", OptValue::Flag),
+	("noautodefrag", OptValue::Flag),
+	("barrier", OptValue::Flag),
+	("nobarrier", OptValue::Flag),
+	("commit", OptValue::Uint),
+	("compress", OptValue::Compress),
+	("compress-force", OptValue::Compress),
+	("datacow", OptValue::Flag),
+	("nodatacow", OptValue::Flag),
+	("datasum", OptValue::Flag),
+	("nodatasum", OptValue::Flag),
+	("degraded", OptValue::Flag),
+	("discard", OptValue::FlagOrChoice(&["sync", "async"])),
+	("nodiscard", OptValue::Flag),
+	("flushoncommit", OptValue::Flag),
+	("noflushoncommit", OptValue::Flag),
+	("skip_balance", OptValue::Flag),
+	("space_cache", OptValue::FlagOrChoice(&["v1", "v2"])),
+	("nospace_cache", OptValue::Flag),
+	("ssd", OptValue::Flag),
+	("ssd_spread", OptValue::Flag),
+	("nossd", OptValue::Flag),
+	("nossd_spread", OptValue::Flag),
+	("subvol", OptValue::Any),
+	("subvolid", OptValue::Uint),
+	("thread_pool", OptValue::Uint),
+	("user_subvol_rm_allowed", OptValue::Flag),
+];
+
+const VFAT_MOUNT_OPTS: &[(&str, OptValue)] = &[
+	("uid", OptValue::Uint),
+	("gid", OptValue::Uint),
+	("umask", OptValue::Octal),
+	("dmask", OptValue::Octal),
+	("fmask", OptValue::Octal),
+	("allow_utime", OptValue::Octal),
+	(
+		"check",
+		OptValue::Choice(&["relaxed", "normal", "strict", "r", "n", "s"]),
+	),
+	("codepage", OptValue::Uint),
+	("iocharset", OptValue::Any),
+	(
+		"shortname",
+		OptValue::Choice(&["lower", "win95", "winnt", "mixed"]),
+	),
+	("utf8", OptValue::FlagOrChoice(&["0", "1"])),
+	(
+		"errors",
+		OptValue::Choice(&["panic", "continue", "remount-ro"]),
+	),
+	("flush", OptValue::Flag),
+	("tz", OptValue::Choice(&["UTC"])),
+	("time_offset", OptValue::Any),
+	("quiet", OptValue::Flag),
+	("showexec", OptValue::Flag),
+	("sys_immutable", OptValue::Flag),
+	("discard", OptValue::Flag),
+	("dos1xfloppy", OptValue::Flag),
+	("nfs", OptValue::Choice(&["stale_rw", "nostale_ro"])),
+];
+
+/// Validated mount options of a partition.
+///
+/// Both the build-time mount and the generated `/etc/fstab` are derived from this, so they can not diverge.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MountOptions {
+	/// All options in the order they are defined, with `(option, is_generic)`.
+	options: Vec<(String, bool)>,
+	/// Options not known for the filesystem, which are passed through anyway.
+	pub unknown: Vec<String>,
+}
+
+impl MountOptions {
+	/// Filesystem specific options, passed as the data of mount(2) during the build.
+	pub fn data(&self) -> String {
+		self.options
+			.iter()
+			.filter(|(_, generic)| !generic)
+			.map(|(opt, _)| opt.as_str())
+			.collect::<Vec<_>>()
+			.join(",")
+	}
+
+	/// Options for the `/etc/fstab` entry. `defaults` if nothing is specified.
+	pub fn fstab(&self) -> String {
+		if self.options.is_empty() {
+			return "defaults".to_owned();
+		}
+		self.options
+			.iter()
+			.map(|(opt, _)| opt.as_str())
+			.collect::<Vec<_>>()
+			.join(",")
+	}
+}
+
 impl FilesystemType {
+	fn mount_opts_table(&self) -> &'static [(&'static str, OptValue)] {
+		match self {
+			Self::Ext4 => EXT4_MOUNT_OPTS,
+			Self::Xfs => XFS_MOUNT_OPTS,
+			Self::Btrfs => BTRFS_MOUNT_OPTS,
+			Self::Fat16 | Self::Fat32 => VFAT_MOUNT_OPTS,
+			Self::None => &[],
+		}
+	}
+
+	/// Validate the mount options against the options known for this filesystem.
+	///
+	/// Options each may contain several comma-separated options. Options known for a different filesystem are errors,
+	/// other unknown options are collected in [`MountOptions::unknown`].
+	pub fn parse_mount_opts<S: AsRef<str>>(&self, opts: &[S]) -> Result<MountOptions> {
+		let mut result = MountOptions::default();
+		if opts.is_empty() {
+			return Ok(result);
+		}
+		if self == &Self::None {
+			bail!("Mount options are specified for a partition not to be formatted");
+		}
+		for opt in opts.iter().flat_map(|x| x.as_ref().split(',')) {
+			if opt.is_empty() {
+				bail!("Empty mount option found, please check for stray commas");
+			}
+			if let Some(c) = opt
+				.chars()
+				.find(|c| !c.is_ascii_alphanumeric() && !"=:-_./@+".contains(*c))
+			{
+				bail!(
+					"Mount option '{}' contains a disallowed character {:?}",
+					opt.escape_default(),
+					c
+				);
+			}
+			let (key, value) = opt
+				.split_once('=')
+				.map_or((opt, None), |(k, v)| (k, Some(v)));
+			let lookup =
+				|table: &[(&str, OptValue)]| table.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+			let (syntax, generic) = if let Some(v) = lookup(GENERIC_MOUNT_OPTS) {
+				(v, true)
+			} else if key.starts_with("x-") {
+				(OptValue::FlagOrAny, true)
+			} else if key == "comment" {
+				(OptValue::Any, true)
+			} else if let Some(v) = lookup(self.mount_opts_table()) {
+				(v, false)
+			} else {
+				let others: Vec<_> = [Self::Ext4, Self::Xfs, Self::Btrfs, Self::Fat32]
+					.iter()
+					.filter(|fs| fs.get_os_fstype().ok() != self.get_os_fstype().ok())
+					.filter(|fs| lookup(fs.mount_opts_table()).is_some())
+					.map(|fs| fs.get_os_fstype().unwrap())
+					.collect();
+				if !others.is_empty() {
+					bail!(
+						"Mount option '{}' is for {}, not {}",
+						opt,
+						others.join(", "),
+						self.get_os_fstype()?
+					);
+				}
+				result.unknown.push(opt.to_owned());
+				result.options.push((opt.to_owned(), false));
+				continue;
+			};
+			if !syntax.accepts(value) {
+				bail!(
+					"Invalid mount option '{}' for {}",
+					opt,
+					self.get_os_fstype()?
+				);
+			}
+			result.options.push((opt.to_owned(), generic));
+		}
+		Ok(result)
+	}
+
 	/// Check validaty of the filesystem parameters.
+	/// Maximum length of the filesystem label in bytes.
+	pub fn max_label_len(&self) -> usize {
+		match self {
+			Self::Ext4 => 16,
+			Self::Xfs => 12,
+			Self::Btrfs => 255,
+			Self::Fat16 | Self::Fat32 => 11,
+			Self::None => 0,
+		}
+	}
+
 	pub fn check<S: AsRef<str>>(&self, label: &Option<S>) -> Result<()> {
 		let label = label.as_ref();
 		// Check for validity of the filesystem labels.
 		if let Some(l) = label {
 			let l = l.as_ref();
+			if self == &Self::None {
+				bail!("A partition without a filesystem can not have a filesystem label.");
+			}
 			match self {
 				Self::Fat16 | Self::Fat32 => {
 					if !l.is_ascii() {
 						bail!("FAT volume label can only contain ASCII characters.");
 					}
-					if l.len() > 11 {
-						bail!("FAT Volume labels can not be longer than 11 characters.");
-					}
-				}
-				_ => {
-					if l.len() > 63 {
-						bail!("Filesystem labels are limited to up to 63 bytes.");
+					if let Some(c) = l.chars().find(|c| FAT_LABEL_INVALID_CHARS.contains(*c)) {
+						bail!("FAT volume label can not contain '{}'.", c);
 					}
 				}
+				_ => (),
 			};
+			// The limits are in bytes, not characters.
+			if l.len() > self.max_label_len() {
+				bail!(
+					"{:?} labels can not be longer than {} bytes, '{}' has {} bytes.",
+					self,
+					self.max_label_len(),
+					l,
+					l.len()
+				);
+			}
 		}
 		Ok(())
 	}
 
+	/// The label written by mkfs. FAT labels are always in upper case.
+	pub fn normalize_label(&self, label: &str) -> String {
+		match self {
+			Self::Fat16 | Self::Fat32 => label.to_ascii_uppercase(),
+			_ => label.to_owned(),
+		}
+	}
+
+	/// Check whether the filesystem fits in a partition of `size_in_sectors` 512-byte sectors.
+	pub fn check_size(&self, size_in_sectors: u64) -> Result<()> {
+		match self {
+			Self::Fat32 if size_in_sectors < FAT32_MIN_SECTORS => bail!(
+				"FAT32 requires a partition of at least {} MiB, use FAT16 instead",
+				FAT32_MIN_SECTORS / 2048
+			),
+			Self::Fat16 if size_in_sectors > FAT16_MAX_SECTORS => bail!(
+				"FAT16 can not be larger than {} MiB, use FAT32 instead",
+				FAT16_MAX_SECTORS / 2048
+			),
+			_ => Ok(()),
+		}
+	}
+
+	/// Check the filesystem UUID `uuid`, returning it in the form reported by blkid.
+	///
+	/// FAT has a 32-bit volume serial of 8 hexadecimal digits, optionally written as `ABCD-1234`. Other filesystems need a full UUID.
+	pub fn check_fs_uuid(&self, uuid: &str) -> Result<String> {
+		match self {
+			Self::None => bail!("A partition without a filesystem can not have a filesystem UUID"),
+			Self::Fat16 | Self::Fat32 => {
+				let serial = match uuid.split_once('-') {
+					Some((a, b)) if a.len() == 4 && b.len() == 4 => format!("{}{}", a, b),
+					Some(_) => bail!("Invalid FAT volume serial '{}'", uuid),
+					None => uuid.to_owned(),
+				};
+				if serial.len() != 8 || !serial.chars().all(|c| c.is_ascii_hexdigit()) {
+					bail!(
+						"FAT volume serial must be 8 hexadecimal digits, e.g. 'ABCD-1234', got '{}'",
+						uuid
+					);
+				}
+				let serial = serial.to_uppercase();
+				Ok(format!("{}-{}", &serial[..4], &serial[4..]))
+			}
+			_ => Ok(Uuid::parse_str(uuid)
+				.context(format!("Invalid filesystem UUID '{}'", uuid))?
+				.to_string()),
+		}
+	}
+
+	/// Filesystem UUID derived from `uuid`, in the form reported by blkid.
+	pub fn fs_uuid_from(&self, uuid: Uuid) -> String {
+		match self {
+			// FAT only has a 32-bit volume serial.
+			Self::Fat16 | Self::Fat32 => {
+				let b = uuid.as_bytes();
+				format!("{:02X}{:02X}-{:02X}{:02X}", b[0], b[1], b[2], b[3])
+			}
+			_ => uuid.to_string(),
+		}
+	}
+
 	pub fn get_os_fstype(&self) -> Result<&'static str> {
 		match self {
 			FilesystemType::Ext4 => Ok("ext4"),
@@ -68,12 +497,14 @@ impl FilesystemType {
 		&self,
 		path: &dyn AsRef<Path>,
 		label: Option<String>,
+		uuid: Option<&str>,
 	) -> Result<Command> {
 		if self == &Self::None {
 			bail!("Instructed to not being formatted");
 		}
 		let path = path.as_ref();
 		self.check(&label)?;
+		let uuid = uuid.map(|x| self.check_fs_uuid(x)).transpose()?;
 		// Decide which command to use.
 		let mut mkfs_command = Command::new(match self {
 			Self::Ext4 => "mkfs.ext4",
@@ -84,8 +515,19 @@ impl FilesystemType {
 				unreachable!();
 			}
 		});
+		// mkfs.vfat picks the FAT width by the size of the partition otherwise.
+		match self {
+			Self::Fat16 => {
+				mkfs_command.args(["-F", "16"]);
+			}
+			Self::Fat32 => {
+				mkfs_command.args(["-F", "32"]);
+			}
+			_ => (),
+		}
 
 		if let Some(l) = label {
+			let l = self.normalize_label(&l);
 			mkfs_command.arg(match self {
 				Self::Ext4 => "-L",
 				Self::Xfs => "-L",
@@ -97,47 +539,201 @@ impl FilesystemType {
 			});
 			mkfs_command.arg(l);
 		}
+		if let Some(uuid) = uuid {
+			match self {
+				Self::Ext4 | Self::Btrfs => {
+					mkfs_command.arg("-U").arg(uuid);
+				}
+				Self::Xfs => {
+					mkfs_command.arg("-m").arg(format!("uuid={}", uuid));
+				}
+				Self::Fat16 | Self::Fat32 => {
+					mkfs_command.arg("-i").arg(uuid.replace('-', ""));
+				}
+				_ => {
+					unreachable!()
+				}
+			}
+		}
 		mkfs_command.arg("--");
 		mkfs_command.arg(path);
 		Ok(mkfs_command)
 	}
 
-	pub fn format(&self, path: &dyn AsRef<Path>, label: Option<String>) -> Result<()> {
+	/// Make the filesystem on `path`, returning the captured output of mkfs.
+	///
+	/// The filesystem UUID is chosen by mkfs unless `uuid` is given.
+	pub fn format(
+		&self,
+		path: &dyn AsRef<Path>,
+		label: Option<String>,
+		uuid: Option<&str>,
+	) -> Result<String> {
 		let dev = path.as_ref();
-		let mut cmd = self.get_mkfs_cmdline(&dev, label)?;
-		cmd_run_check_status(&mut cmd)
+		let mut cmd = self.get_mkfs_cmdline(&dev, label, uuid)?;
+		let output = cmd
+			.output()
+			.context(format!("Failed to run {:?}", cmd.get_program()))?;
+		let content = format!(
+			"{}{}",
+			String::from_utf8_lossy(&output.stdout),
+			String::from_utf8_lossy(&output.stderr)
+		);
+		if !output.status.success() {
+			bail!(
+				"{:?} exited unsuccessfully ({}):\n{}",
+				cmd,
+				output.status,
+				content.trim_end()
+			);
+		}
+		Ok(content)
 	}
 }
 
+/// Characters not allowed in FAT volume labels.
+const FAT_LABEL_INVALID_CHARS: &str = "\"*+,./:;<=>?[\\]|";
+
+/// Minimum size of FAT32 in 512-byte sectors (33 MiB), for it to have enough clusters.
+const FAT32_MIN_SECTORS: u64 = 33 * 2048;
+/// Maximum size of FAT16 in 512-byte sectors (4 GiB).
+const FAT16_MAX_SECTORS: u64 = 4 * 1024 * 2048;
+
+/// Maximum number of partitions being formatted at the same time.
+const MAX_FORMAT_JOBS: usize = 4;
+
+/// A partition to be formatted: `(num, filesystem, path, label, uuid)`.
+type FormatJob<'a> = (
+	u32,
+	&'a FilesystemType,
+	PathBuf,
+	Option<String>,
+	Option<String>,
+);
+
+/// Format the partitions concurrently.
+///
+/// Returns the output of mkfs for each partition in the original order, or an error listing all failures.
+fn format_concurrently(jobs: Vec<FormatJob>) -> Result<Vec<(u32, String)>> {
+	let num_jobs = jobs.len();
+	let queue = Mutex::new(jobs.into_iter().enumerate());
+	let results = Mutex::new(Vec::with_capacity(num_jobs));
+	thread::scope(|scope| {
+		for _ in 0..MAX_FORMAT_JOBS.min(num_jobs) {
+			scope.spawn(|| {
+				loop {
+					// Do not hold the lock while formatting
+					let job = queue.lock().unwrap().next();
+					let Some((idx, (num, filesystem, path, label, uuid))) = job else {
+						break;
+					};
+					let result = filesystem.format(&path, label, uuid.as_deref());
+					results.lock().unwrap().push((idx, num, result));
+				}
+			});
+		}
+	});
+	let mut results = results.into_inner().unwrap();
+	results.sort_by_key(|(idx, _, _)| *idx);
+	let mut outputs = Vec::new();
+	let mut errs = Vec::new();
+	for (_, num, result) in results {
+		match result {
+			Result::Ok(output) => outputs.push((num, output)),
+			Err(e) => errs.push(format!("Partition {}: {:#}", num, e)),
+		}
+	}
+	if !errs.is_empty() {
+		bail!(
+			"Failed to format {} partition(s):\n{}",
+			errs.len(),
+			errs.join("\n")
+		);
+	}
+	Ok(outputs)
+}
+
 impl ImageContext<'_> {
+	/// The filesystem `partition` is formatted with, taking the override of the root filesystem into account.
+	pub fn partition_filesystem<'b>(&'b self, partition: &'b PartitionSpec) -> &'b FilesystemType {
+		if partition.usage == PartitionUsage::Rootfs
+			&& let Some(fstype) = self.override_rootfs_fstype
+		{
+			fstype
+		} else {
+			&partition.filesystem
+		}
+	}
+
+	/// The filesystem UUID of `partition` known before it is formatted, if any.
+	pub fn planned_fs_uuid(
+		&self,
+		partition: &PartitionSpec,
+		filesystem: &FilesystemType,
+	) -> Result<Option<String>> {
+		let num = partition.num;
+		Ok(match &partition.fs_uuid {
+			Some(uuid) => Some(
+				filesystem
+					.check_fs_uuid(uuid)
+					.context(format!("Invalid filesystem UUID for partition {}", num))?,
+			),
+			// Otherwise only pin the filesystem UUID in reproducible builds.
+			None => self
+				.seed
+				.map(|_| filesystem.fs_uuid_from(self.gen_uuid(&format!("filesystem-{}", num)))),
+		})
+	}
+
 	pub fn format_partitions(
 		&self,
 		loopdev: &dyn AsRef<Path>,
 		pm_data: &mut PartitionMapData,
 	) -> Result<()> {
 		let loopdev = loopdev.as_ref();
+		let mut jobs = Vec::new();
+		let mut known_uuids = HashMap::new();
 		for partition in &self.device.partitions {
-			if partition.filesystem == FilesystemType::None {
+			if partition.format_on_first_boot {
+				self.info(format!(
+					"Partition {} will be formatted on first boot",
+					partition.num
+				));
+			}
+			if !partition.formatted_at_build() {
 				continue;
 			}
-			let filesystem = if partition.usage == PartitionUsage::Rootfs {
-				if let Some(fstype) = self.override_rootfs_fstype {
-					fstype
-				} else {
-					&partition.filesystem
-				}
-			} else {
-				&partition.filesystem
-			};
+			let filesystem = self.partition_filesystem(partition);
 			self.info(format!(
 				"Formatting partition {} ({:?})",
 				partition.num, filesystem
 			));
 			let num = partition.num;
-			let part_path = format!("{}p{}", loopdev.to_string_lossy(), num);
-			let label = &partition.label;
-			filesystem.format(&part_path, label.to_owned())?;
-			let fsuuid = get_fsuuid(&part_path)?;
+			let part_path = partition_path(loopdev, num);
+			let uuid = self.planned_fs_uuid(partition, filesystem)?;
+			if let Some(uuid) = &uuid {
+				known_uuids.insert(num, uuid.clone());
+			}
+			jobs.push((
+				num,
+				filesystem,
+				part_path,
+				partition.fs_label.to_owned(),
+				uuid,
+			));
+		}
+		let outputs = format_concurrently(jobs)?;
+		// Probe the UUIDs not known beforehand after all filesystems are made.
+		for (num, output) in outputs {
+			debug!(
+				"Output of mkfs for partition {}:\n{}",
+				num,
+				output.trim_end()
+			);
+			let fsuuid = match known_uuids.remove(&num) {
+				Some(uuid) => uuid,
+				None => get_fsuuid(&partition_path(loopdev, num))?,
+			};
 			let part_data = pm_data.data.get_mut(&num).context(format!(
 				"Unable to get partition data for partition {}",
 				num
@@ -147,3 +743,190 @@ impl ImageContext<'_> {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_format_concurrently_failures() {
+		let fs = FilesystemType::Ext4;
+		let jobs = (1..=3)
+			.map(|num| {
+				(
+					num,
+					&fs,
+					partition_path("/nonexistent/loop0", num),
+					None,
+					None,
+				)
+			})
+			.collect();
+		let err = format!("{:#}", format_concurrently(jobs).unwrap_err());
+		assert!(err.contains("3 partition(s)"), "{}", err);
+		for num in 1..=3 {
+			assert!(err.contains(&format!("Partition {}:", num)), "{}", err);
+		}
+	}
+
+	#[test]
+	fn test_mkfs_cmdline_uuid() -> Result<()> {
+		let uuid = "01020304-0506-4708-890A-0B0C0D0E0F10";
+		let args = |fs: FilesystemType, uuid: &str| -> Result<Vec<String>> {
+			let cmd = fs.get_mkfs_cmdline(&"/dev/loop0p1", None, Some(uuid))?;
+			Ok(cmd
+				.get_args()
+				.map(|x| x.to_string_lossy().into_owned())
+				.collect())
+		};
+		assert_eq!(
+			args(FilesystemType::Ext4, uuid)?,
+			[
+				"-U",
+				"01020304-0506-4708-890a-0b0c0d0e0f10",
+				"--",
+				"/dev/loop0p1"
+			]
+		);
+		assert_eq!(
+			args(FilesystemType::Xfs, uuid)?,
+			[
+				"-m",
+				"uuid=01020304-0506-4708-890a-0b0c0d0e0f10",
+				"--",
+				"/dev/loop0p1"
+			]
+		);
+		assert_eq!(
+			args(FilesystemType::Fat32, "abcd-1234")?,
+			["-F", "32", "-i", "ABCD1234", "--", "/dev/loop0p1"]
+		);
+		// FAT only takes a volume serial
+		assert!(args(FilesystemType::Fat32, uuid).is_err());
+		assert!(args(FilesystemType::Ext4, "ABCD-1234").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_mkfs_cmdline_fat() -> Result<()> {
+		let args = |fs: FilesystemType| -> Result<Vec<String>> {
+			let cmd = fs.get_mkfs_cmdline(&"/dev/loop0p1", Some("Boot".to_owned()), None)?;
+			assert_eq!(cmd.get_program(), "mkfs.vfat");
+			Ok(cmd
+				.get_args()
+				.map(|x| x.to_string_lossy().into_owned())
+				.collect())
+		};
+		assert_eq!(
+			args(FilesystemType::Fat16)?,
+			["-F", "16", "-n", "BOOT", "--", "/dev/loop0p1"]
+		);
+		assert_eq!(
+			args(FilesystemType::Fat32)?,
+			["-F", "32", "-n", "BOOT", "--", "/dev/loop0p1"]
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_label() {
+		let label = |fs: FilesystemType, l: &str| fs.check(&Some(l));
+		label(FilesystemType::Ext4, "AOSC OS Rootfs16").unwrap();
+		assert!(label(FilesystemType::Ext4, "AOSC OS Rootfs 17").is_err());
+		// Bytes, not characters
+		assert!(label(FilesystemType::Ext4, "安同操作系统根文件").is_err());
+		label(FilesystemType::Xfs, "AOSC OS Root").unwrap();
+		assert!(label(FilesystemType::Xfs, "AOSC OS Root1").is_err());
+		label(FilesystemType::Btrfs, &"a".repeat(255)).unwrap();
+		assert!(label(FilesystemType::Btrfs, &"a".repeat(256)).is_err());
+		label(FilesystemType::Fat32, "Boot").unwrap();
+		assert!(label(FilesystemType::Fat32, "BOOTPARTITION").is_err());
+		assert!(label(FilesystemType::Fat32, "BOOT.1").is_err());
+		assert!(label(FilesystemType::None, "BOOT").is_err());
+		FilesystemType::None.check(&None::<&str>).unwrap();
+	}
+
+	#[test]
+	fn test_check_fat_size() {
+		FilesystemType::Fat32.check_size(614400).unwrap();
+		FilesystemType::Fat16.check_size(32768).unwrap();
+		assert!(FilesystemType::Fat32.check_size(32768).is_err());
+		assert!(FilesystemType::Fat16.check_size(16 * 1024 * 2048).is_err());
+		// Fill the rest of the disk
+		FilesystemType::Ext4.check_size(0).unwrap();
+	}
+
+	#[test]
+	fn test_check_fs_uuid() -> Result<()> {
+		let fat = FilesystemType::Fat32;
+		assert_eq!(fat.check_fs_uuid("abcd1234")?, "ABCD-1234");
+		assert_eq!(fat.check_fs_uuid("ABCD-1234")?, "ABCD-1234");
+		assert!(fat.check_fs_uuid("ABC-D1234").is_err());
+		assert!(fat.check_fs_uuid("ABCD123G").is_err());
+		assert!(fat.check_fs_uuid("ABCD12345").is_err());
+		assert_eq!(
+			FilesystemType::Btrfs.check_fs_uuid("01020304-0506-4708-890A-0B0C0D0E0F10")?,
+			"01020304-0506-4708-890a-0b0c0d0e0f10"
+		);
+		assert!(FilesystemType::None.check_fs_uuid("ABCD-1234").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_btrfs_mount_opts() -> Result<()> {
+		let fs = FilesystemType::Btrfs;
+		let opts =
+			fs.parse_mount_opts(&["compress=zstd:3", "noatime,space_cache=v2", "subvol=@"])?;
+		assert_eq!(opts.data(), "compress=zstd:3,space_cache=v2,subvol=@");
+		assert_eq!(
+			opts.fstab(),
+			"compress=zstd:3,noatime,space_cache=v2,subvol=@"
+		);
+		assert!(opts.unknown.is_empty());
+		assert!(fs.parse_mount_opts(&["compress=lz4"]).is_err());
+		assert!(fs.parse_mount_opts(&["compress=lzo:3"]).is_err());
+		assert!(fs.parse_mount_opts(&["space_cache=v3"]).is_err());
+		// ext4 only
+		assert!(fs.parse_mount_opts(&["data=ordered"]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_ext4_mount_opts() -> Result<()> {
+		let fs = FilesystemType::Ext4;
+		let opts = fs.parse_mount_opts(&["defaults", "commit=60", "errors=remount-ro"])?;
+		assert_eq!(opts.data(), "commit=60,errors=remount-ro");
+		assert_eq!(opts.fstab(), "defaults,commit=60,errors=remount-ro");
+		assert_eq!(fs.parse_mount_opts::<&str>(&[])?.fstab(), "defaults");
+		// btrfs only
+		assert!(fs.parse_mount_opts(&["compress=zstd"]).is_err());
+		// stray commas
+		assert!(fs.parse_mount_opts(&["rw,"]).is_err());
+		assert!(fs.parse_mount_opts(&["rw,,noatime"]).is_err());
+		assert!(fs.parse_mount_opts(&["commit=soon"]).is_err());
+		assert!(fs.parse_mount_opts(&["noatime nodev"]).is_err());
+		// Unknown to every filesystem, passed through
+		let opts = fs.parse_mount_opts(&["fast_commit"])?;
+		assert_eq!(opts.unknown, vec!["fast_commit"]);
+		assert_eq!(opts.data(), "fast_commit");
+		Ok(())
+	}
+
+	#[test]
+	fn test_vfat_mount_opts() -> Result<()> {
+		let fs = FilesystemType::Fat32;
+		let opts = fs.parse_mount_opts(&["umask=0077", "shortname=mixed,utf8", "nofail"])?;
+		assert_eq!(opts.data(), "umask=0077,shortname=mixed,utf8");
+		assert_eq!(opts.fstab(), "umask=0077,shortname=mixed,utf8,nofail");
+		assert!(fs.parse_mount_opts(&["umask=0099"]).is_err());
+		assert!(fs.parse_mount_opts(&["shortname=dos"]).is_err());
+		// xfs only
+		assert!(fs.parse_mount_opts(&["inode64"]).is_err());
+		assert!(FilesystemType::None.parse_mount_opts(&["rw"]).is_err());
+		// Userspace options, with or without a value
+		let opts = fs.parse_mount_opts(&["x-systemd.makefs,x-systemd.idle-timeout=60"])?;
+		assert_eq!(opts.data(), "");
+		assert!(fs.parse_mount_opts(&["comment"]).is_err());
+		Ok(())
+	}
+}
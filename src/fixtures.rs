@@ -0,0 +1,144 @@
+//! Module providing the fixtures shared by the tests.
+//!
+//! The fixture registry in [`FIXTURE_REGISTRY`] contains synthetic devices only, so the tests do not change with the devices shipped in `devices/`.
+//! Generated files are compared with the golden files in [`GOLDEN_DIR`]. Run the tests with `MKRAWIMG_UPDATE_GOLDEN=1` to update them after an intended change.
+use std::{
+	fs,
+	ops::Deref,
+	path::{Path, PathBuf},
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+	cli::{Compression, OutputFormat, OutputLayout},
+	context::{ImageContext, ImageVariant},
+	device::DeviceSpec,
+	plan::BuildRun,
+	registry::DeviceRegistry,
+	user::RootPolicy,
+	utils::UserOptions,
+};
+
+/// The registry of synthetic devices, relative to the root of the crate.
+pub const FIXTURE_REGISTRY: &str = "tests/fixtures/registry";
+/// The directory of the expected outputs, relative to the root of the crate.
+pub const GOLDEN_DIR: &str = "tests/fixtures/golden";
+/// Rewrite the golden files with the actual outputs if set.
+const UPDATE_GOLDEN_ENV: &str = "MKRAWIMG_UPDATE_GOLDEN";
+
+/// Device `id` from the fixture registry.
+pub fn fixture_device(id: &str) -> Result<DeviceSpec> {
+	DeviceRegistry::scan(FIXTURE_REGISTRY)?.get(&id.to_owned())
+}
+
+/// Context of the uncompressed base image of `device` in `run`, with the defaults of the command line.
+///
+/// The tests change the fields they need with `..test_context(&device, &run)`.
+pub fn test_context<'a>(device: &'a DeviceSpec, run: &'a BuildRun) -> ImageContext<'a> {
+	ImageContext {
+		device,
+		variant: &ImageVariant::Base,
+		workdir: Path::new("/nonexistent"),
+		outdir: Path::new("/nonexistent"),
+		user: "aosc",
+		password: "anthon",
+		user_options: &UserOptions {
+			groups: None,
+			shell: None,
+			uid: None,
+		},
+		root_policy: &RootPolicy::Locked,
+		filename: String::new(),
+		base_dist: PathBuf::new(),
+		override_rootfs_fstype: &None,
+		additional_packages: &None,
+		oobe_package: &None,
+		compress: &Compression::None,
+		format: &OutputFormat::Rawimg,
+		topics: None,
+		seed: None,
+		run,
+		force_detach: false,
+		min_free_inodes: None,
+		usage_warn_threshold: crate::report::USAGE_WARN_THRESHOLD,
+		usage_fail_threshold: None,
+		loop_attempts: crate::utils::LOOP_ATTACH_ATTEMPTS,
+		log_dir: None,
+		debug_shell: false,
+		no_scrub: false,
+		skip_size_check: false,
+		shrink: false,
+		no_bmap: false,
+		keep_raw: false,
+		resume: false,
+		output_layout: OutputLayout::Hierarchy,
+		mirror: "https://repo.aosc.io/debs",
+	}
+}
+
+/// A temporary directory of a test, removed with its contents when dropped, also if the test fails.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+	/// Create an empty directory named after `name`, unique across the tests of all running test binaries.
+	pub fn new(name: &str) -> Result<Self> {
+		static COUNTER: AtomicUsize = AtomicUsize::new(0);
+		let path = std::env::temp_dir().join(format!(
+			"mkrawimg-test-{}-{}-{}",
+			name,
+			std::process::id(),
+			COUNTER.fetch_add(1, Ordering::Relaxed)
+		));
+		// Left over by a killed test binary with the same PID.
+		if path.exists() {
+			fs::remove_dir_all(&path)?;
+		}
+		fs::create_dir_all(&path)?;
+		Ok(TestDir(path))
+	}
+}
+
+impl Deref for TestDir {
+	type Target = Path;
+
+	fn deref(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl AsRef<Path> for TestDir {
+	fn as_ref(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl Drop for TestDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
+
+/// Compare `actual` with the golden file `name`.
+pub fn assert_golden(name: &str, actual: &str) -> Result<()> {
+	let path = Path::new(GOLDEN_DIR).join(name);
+	if std::env::var_os(UPDATE_GOLDEN_ENV).is_some() {
+		fs::create_dir_all(GOLDEN_DIR)?;
+		fs::write(&path, actual)?;
+		return Ok(());
+	}
+	let expected = fs::read_to_string(&path).context(format!(
+		"Unable to read {}, run the tests with {}=1 to create it",
+		path.display(),
+		UPDATE_GOLDEN_ENV
+	))?;
+	assert_eq!(
+		actual,
+		expected,
+		"The output differs from {}, run the tests with {}=1 to update it if this is intended",
+		path.display(),
+		UPDATE_GOLDEN_ENV
+	);
+	Ok(())
+}
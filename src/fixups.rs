@@ -0,0 +1,187 @@
+//! Module handling the temporary files installed in the target system while the steps run in the container.
+//!
+//! - `/etc/resolv.conf` is replaced by a copy of the one of the build host, so the scripts of the packages can resolve names (e.g. to download firmware blobs).
+//! - `/usr/sbin/policy-rc.d` exits with 101, so the packages do not try to start their services in the container.
+//!
+//! Existing files are moved aside to `<name>.mkrawimg-orig` and moved back once the last step in the container finished, so the image ships the original files (e.g. the symlink to the stub resolver) and no policy file.
+//! The installed files are marked with [`FIXUP_MARKER`], which tells them from the original files if an interrupted build is resumed.
+use std::{
+	ffi::OsString,
+	fs::{self, File},
+	io::Write,
+	os::unix::fs::OpenOptionsExt,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+
+/// Marker in the files installed for the build.
+pub const FIXUP_MARKER: &str = "Installed by mkrawimg for the build, removed afterwards.";
+/// Path to the resolv.conf of the build host.
+pub const HOST_RESOLV_CONF: &str = "/etc/resolv.conf";
+/// Suffix of the original files moved aside.
+const ORIG_SUFFIX: &str = ".mkrawimg-orig";
+
+/// The files installed in the target system at `root`, restored on [`ContainerFixups::restore`] or when dropped.
+#[derive(Debug)]
+pub struct ContainerFixups {
+	root: PathBuf,
+	/// Paths relative to `root`, in the order they are installed.
+	installed: Vec<&'static str>,
+}
+
+impl ContainerFixups {
+	/// Install the resolv.conf at `host_resolv_conf` and the policy file into the system at `root`.
+	///
+	/// The resolv.conf is skipped with a warning if the build host has none.
+	pub fn install(root: &Path, host_resolv_conf: &Path) -> Result<Self> {
+		let mut fixups = ContainerFixups {
+			root: root.to_owned(),
+			installed: Vec::new(),
+		};
+		match fs::read_to_string(host_resolv_conf) {
+			Ok(resolv_conf) => {
+				let content = format!("# {}\n{}", FIXUP_MARKER, resolv_conf);
+				fixups.install_file("etc/resolv.conf", &content, 0o644)?;
+			}
+			Err(e) => warn!(
+				"Unable to read {}, names can not be resolved in the container: {}",
+				host_resolv_conf.display(),
+				e
+			),
+		}
+		let content = format!(
+			"#!/bin/sh\n# {}\n# Services are not started while the image is built.\nexit 101\n",
+			FIXUP_MARKER
+		);
+		fixups.install_file("usr/sbin/policy-rc.d", &content, 0o755)?;
+		Ok(fixups)
+	}
+
+	fn install_file(&mut self, path: &'static str, content: &str, mode: u32) -> Result<()> {
+		let dst = self.root.join(path);
+		let orig = orig_path(&dst);
+		if dst.symlink_metadata().is_ok() {
+			// Left over by an interrupted build, the original is already moved aside.
+			if is_fixup(&dst) || orig.symlink_metadata().is_ok() {
+				fs::remove_file(&dst).context(format!("Unable to remove {}", dst.display()))?;
+			} else {
+				fs::rename(&dst, &orig)
+					.context(format!("Unable to move {} aside", dst.display()))?;
+			}
+		}
+		// Restored if anything fails from now on.
+		self.installed.push(path);
+		File::options()
+			.write(true)
+			.create_new(true)
+			.mode(mode)
+			.open(&dst)
+			.and_then(|mut x| x.write_all(content.as_bytes()))
+			.context(format!("Unable to write {}", dst.display()))
+	}
+
+	/// Remove the installed files and move the original ones back.
+	pub fn restore(mut self) -> Result<()> {
+		self.restore_files()
+	}
+
+	fn restore_files(&mut self) -> Result<()> {
+		while let Some(path) = self.installed.pop() {
+			let dst = self.root.join(path);
+			let orig = orig_path(&dst);
+			if dst.symlink_metadata().is_ok() {
+				fs::remove_file(&dst).context(format!("Unable to remove {}", dst.display()))?;
+			}
+			if orig.symlink_metadata().is_ok() {
+				fs::rename(&orig, &dst).context(format!("Unable to restore {}", dst.display()))?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Drop for ContainerFixups {
+	fn drop(&mut self) {
+		if let Err(e) = self.restore_files() {
+			warn!(
+				"Unable to restore the files installed for the build in {}: {:#}",
+				self.root.display(),
+				e
+			);
+		}
+	}
+}
+
+/// Where the original file at `path` is moved aside.
+fn orig_path(path: &Path) -> PathBuf {
+	let mut orig = OsString::from(path.as_os_str());
+	orig.push(ORIG_SUFFIX);
+	PathBuf::from(orig)
+}
+
+/// Whether the file at `path` is installed by [`ContainerFixups`], rather than the original one.
+fn is_fixup(path: &Path) -> bool {
+	path.symlink_metadata().is_ok_and(|m| m.is_file())
+		&& fs::read_to_string(path).is_ok_and(|x| x.contains(FIXUP_MARKER))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::fs::{PermissionsExt, symlink};
+
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	#[test]
+	fn test_container_fixups() -> Result<()> {
+		let dir = TestDir::new("fixups")?;
+		let root = dir.join("root");
+		let host_resolv_conf = dir.join("resolv.conf");
+		fs::create_dir_all(root.join("etc"))?;
+		fs::create_dir_all(root.join("usr/sbin"))?;
+		fs::write(&host_resolv_conf, "nameserver 192.0.2.1\n")?;
+		let resolv_conf = root.join("etc/resolv.conf");
+		let policy = root.join("usr/sbin/policy-rc.d");
+		symlink("../run/systemd/resolve/stub-resolv.conf", &resolv_conf)?;
+
+		let fixups = ContainerFixups::install(&root, &host_resolv_conf)?;
+		let content = fs::read_to_string(&resolv_conf)?;
+		assert!(content.contains(FIXUP_MARKER) && content.ends_with("nameserver 192.0.2.1\n"));
+		assert!(fs::read_to_string(&policy)?.ends_with("exit 101\n"));
+		assert_eq!(policy.metadata()?.permissions().mode() & 0o777, 0o755);
+		fixups.restore()?;
+		assert_eq!(
+			fs::read_link(&resolv_conf)?,
+			PathBuf::from("../run/systemd/resolve/stub-resolv.conf")
+		);
+		assert!(policy.symlink_metadata().is_err());
+		assert_eq!(fs::read_dir(root.join("etc"))?.count(), 1);
+
+		// Restored when dropped, e.g. if a step fails
+		fs::write(&policy, "#!/bin/sh\nexit 0\n")?;
+		drop(ContainerFixups::install(&root, &host_resolv_conf)?);
+		assert!(resolv_conf.is_symlink());
+		assert_eq!(fs::read_to_string(&policy)?, "#!/bin/sh\nexit 0\n");
+
+		// Left over by an interrupted build
+		let fixups = ContainerFixups::install(&root, &host_resolv_conf)?;
+		std::mem::forget(fixups);
+		ContainerFixups::install(&root, &host_resolv_conf)?.restore()?;
+		assert!(resolv_conf.is_symlink());
+		assert_eq!(fs::read_to_string(&policy)?, "#!/bin/sh\nexit 0\n");
+		fs::remove_file(&policy)?;
+		let fixups = ContainerFixups::install(&root, &host_resolv_conf)?;
+		std::mem::forget(fixups);
+		ContainerFixups::install(&root, &host_resolv_conf)?.restore()?;
+		assert!(policy.symlink_metadata().is_err());
+
+		// Without a resolv.conf on the build host
+		let fixups = ContainerFixups::install(&root, &dir.join("missing"))?;
+		assert!(resolv_conf.is_symlink());
+		assert!(policy.is_file());
+		fixups.restore()?;
+		Ok(())
+	}
+}
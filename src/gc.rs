@@ -0,0 +1,695 @@
+//! Module handling the garbage collection of the working directory.
+//!
+//! Bootstrapped distributions, sketch directories and logs pile up in the working directory of long-lived builders.
+//! A [`RetentionPolicy`] decides which of them are removed, oldest first.
+//! Builds and the garbage collection hold an exclusive [`WorkdirLock`] while running, so two of them never bootstrap into or remove the same directories.
+//! Patching images and applying the bootloaders again hold a shared one, they can run alongside each other, but not alongside a build.
+//! The bootstrapped distributions are locked on their own with [`WorkdirLock::bootstrap`].
+//! The locks are `flock(2)` locks, the kernel releases them when mkrawimg exits, including on Ctrl-C.
+use std::{
+	collections::HashMap,
+	fs::{self, File},
+	io::Write,
+	os::{fd::AsRawFd, unix::fs::MetadataExt},
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result, bail};
+use libc::{LOCK_EX, LOCK_NB, LOCK_SH, flock};
+use log::{info, warn};
+use walkdir::WalkDir;
+
+use crate::utils::{
+	SYSFS_BLOCK_DIR, list_attached_loops, loops_within, mounts_under, release_loop_devices,
+	unmount_busy_retrying,
+};
+
+/// Name of the lock file in the working directory.
+const LOCK_FILE: &str = ".mkrawimg.lock";
+/// Name of the symbolic link to the sketch directory of the image being built, within `sketches`.
+pub const LATEST_SKETCH: &str = "latest";
+
+/// Which items of the working directory are kept.
+///
+/// Nothing is removed if no limit is set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+	/// Maximum total size of the items, in bytes. The oldest items are removed until the rest fit in.
+	pub max_size: Option<u64>,
+	/// Maximum age of the sketch directories and the logs.
+	pub max_age: Option<Duration>,
+	/// Number of the most recent bootstrapped distributions kept for each architecture.
+	pub keep_bootstraps: Option<usize>,
+}
+
+impl RetentionPolicy {
+	pub fn is_empty(&self) -> bool {
+		self == &Self::default()
+	}
+}
+
+/// Kind of an item in the working directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ItemKind {
+	/// A bootstrapped distribution in `bootstrap/<variant>-<arch>`.
+	Bootstrap { arch: String },
+	/// A sketch directory in `sketches`.
+	Sketch,
+	/// A log, e.g. `bootstrap/<variant>-<arch>.log`.
+	Log,
+}
+
+/// An item in the working directory which can be removed.
+#[derive(Clone, Debug)]
+pub struct WorkdirItem {
+	pub path: PathBuf,
+	pub kind: ItemKind,
+	/// The last modification of any file in the item.
+	pub mtime: SystemTime,
+	/// Size on disk, in bytes.
+	pub size: u64,
+}
+
+/// A lock on the working directory, released when dropped.
+pub struct WorkdirLock {
+	file: File,
+	path: PathBuf,
+}
+
+impl WorkdirLock {
+	/// Lock the file at `path`, waiting for the current holder if `wait` is set.
+	///
+	/// The ID of the process is recorded in the file for exclusive locks, to tell who is holding it.
+	fn open(path: PathBuf, operation: i32, wait: bool) -> Result<Option<Self>> {
+		if let Some(dir) = path.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		let file = File::options()
+			.create(true)
+			.truncate(false)
+			.write(true)
+			.open(&path)
+			.context(format!("Unable to open the lock file {}", path.display()))?;
+		let mut lock = Self { file, path };
+		if !lock.lock(operation | LOCK_NB)? {
+			if !wait {
+				return Ok(None);
+			}
+			info!(
+				"Waiting for the lock on {}{} ...",
+				lock.path.display(),
+				lock.holder()
+			);
+			lock.lock(operation)?;
+		}
+		if operation == LOCK_EX {
+			lock.file.set_len(0)?;
+			writeln!(lock.file, "{}", std::process::id())?;
+		}
+		Ok(Some(lock))
+	}
+
+	fn lock(&self, operation: i32) -> Result<bool> {
+		loop {
+			if unsafe { flock(self.file.as_raw_fd(), operation) } == 0 {
+				return Ok(true);
+			}
+			let e = std::io::Error::last_os_error();
+			match e.raw_os_error() {
+				Some(libc::EWOULDBLOCK) => return Ok(false),
+				// Interrupted while waiting.
+				Some(libc::EINTR) => continue,
+				_ => return Err(e).context(format!("Unable to lock {}", self.path.display())),
+			}
+		}
+	}
+
+	/// The process holding the exclusive lock, e.g. ` (pid 1234)`, or nothing if it is unknown or gone.
+	fn holder(&self) -> String {
+		fs::read_to_string(&self.path)
+			.ok()
+			.and_then(|x| x.trim().parse::<u32>().ok())
+			.filter(|pid| Path::new("/proc").join(pid.to_string()).exists())
+			.map(|pid| format!(" (pid {})", pid))
+			.unwrap_or_default()
+	}
+
+	fn workdir(workdir: &Path, operation: i32, wait: bool) -> Result<Self> {
+		let path = workdir.join(LOCK_FILE);
+		match Self::open(path.clone(), operation, wait)? {
+			Some(lock) => Ok(lock),
+			None => {
+				let holder = File::open(&path)
+					.map(|file| Self { file, path }.holder())
+					.unwrap_or_default();
+				bail!(
+					"Another build is using the working directory {}{}, please try again later, or pass --wait-for-lock to wait for it.",
+					workdir.display(),
+					holder
+				)
+			}
+		}
+	}
+
+	/// Lock the working directory for patching an image, which can run alongside other patches but not alongside a build.
+	pub fn shared<P: AsRef<Path>>(workdir: P, wait: bool) -> Result<Self> {
+		Self::workdir(workdir.as_ref(), LOCK_SH, wait)
+	}
+
+	/// Lock the working directory for a build or the garbage collection.
+	pub fn exclusive<P: AsRef<Path>>(workdir: P, wait: bool) -> Result<Self> {
+		Self::workdir(workdir.as_ref(), LOCK_EX, wait)
+	}
+
+	/// Lock the bootstrapped distribution at `path` (i.e. `bootstrap/<variant>-<arch>`) while it is checked or bootstrapped, waiting for the current holder.
+	///
+	/// The lock file is `bootstrap/.<variant>-<arch>.lock`, so distributions of different variants or architectures can be bootstrapped at the same time.
+	pub fn bootstrap(path: &Path) -> Result<Self> {
+		let name = path
+			.file_name()
+			.context("Bootstrapped distribution has no name")?
+			.to_string_lossy();
+		let lock = path.with_file_name(format!(".{}.lock", name));
+		Ok(Self::open(lock, LOCK_EX, true)?.expect("Waiting for the lock always takes it"))
+	}
+
+	/// Release the lock and remove the lock file, e.g. to remove the working directory.
+	pub fn remove(self) -> Result<()> {
+		let path = self.path.clone();
+		drop(self);
+		fs::remove_file(&path).context(format!("Unable to remove the lock file {}", path.display()))
+	}
+}
+
+/// Get the size on disk and the last modification of the file or directory at `path`.
+pub(crate) fn measure(path: &Path) -> Result<(u64, SystemTime)> {
+	let mut size = 0;
+	let mut mtime = None::<SystemTime>;
+	for entry in WalkDir::new(path) {
+		let metadata = entry?.metadata()?;
+		// Sparse raw images only take the allocated blocks.
+		size += metadata.blocks() * 512;
+		if !metadata.is_dir() {
+			let modified = metadata.modified()?;
+			mtime = Some(mtime.map_or(modified, |x| x.max(modified)));
+		}
+	}
+	let mtime = match mtime {
+		Some(x) => x,
+		None => path.symlink_metadata()?.modified()?,
+	};
+	Ok((size, mtime))
+}
+
+/// Find the items in the working directory which can be removed.
+pub fn scan_workdir<P: AsRef<Path>>(workdir: P) -> Result<Vec<WorkdirItem>> {
+	let workdir = workdir.as_ref();
+	let mut items = Vec::new();
+	for (dir, is_bootstrap) in [("bootstrap", true), ("sketches", false)] {
+		let dir = workdir.join(dir);
+		if !dir.is_dir() {
+			continue;
+		}
+		for entry in fs::read_dir(&dir)? {
+			let path = entry?.path();
+			let name = path
+				.file_name()
+				.unwrap_or_default()
+				.to_string_lossy()
+				.into_owned();
+			// Lock files of the bootstrapped distributions, and the link to the latest sketch directory
+			if name.starts_with('.') || path.is_symlink() {
+				continue;
+			}
+			let kind = if path.extension().is_some_and(|x| x == "log") {
+				ItemKind::Log
+			} else if is_bootstrap {
+				// bootstrap/<variant>-<arch>
+				let Some((_, arch)) = name.split_once('-') else {
+					continue;
+				};
+				ItemKind::Bootstrap {
+					arch: arch.to_owned(),
+				}
+			} else {
+				ItemKind::Sketch
+			};
+			let (size, mtime) = measure(&path)?;
+			items.push(WorkdirItem {
+				path,
+				kind,
+				mtime,
+				size,
+			});
+		}
+	}
+	Ok(items)
+}
+
+/// Select the items to be removed by `policy`, oldest first.
+pub fn select_items<'a>(
+	items: &'a [WorkdirItem],
+	policy: &RetentionPolicy,
+	now: SystemTime,
+) -> Vec<&'a WorkdirItem> {
+	let mut sorted: Vec<&WorkdirItem> = items.iter().collect();
+	sorted.sort_by_key(|x| x.mtime);
+	let mut selected = vec![false; sorted.len()];
+	if let Some(max_age) = policy.max_age {
+		for (idx, item) in sorted.iter().enumerate() {
+			let age = now.duration_since(item.mtime).unwrap_or_default();
+			if !matches!(item.kind, ItemKind::Bootstrap { .. }) && age > max_age {
+				selected[idx] = true;
+			}
+		}
+	}
+	if let Some(keep) = policy.keep_bootstraps {
+		let mut seen: HashMap<&str, usize> = HashMap::new();
+		// Newest first
+		for (idx, item) in sorted.iter().enumerate().rev() {
+			if let ItemKind::Bootstrap { arch } = &item.kind {
+				let count = seen.entry(arch.as_str()).or_default();
+				*count += 1;
+				if *count > keep {
+					selected[idx] = true;
+				}
+			}
+		}
+	}
+	if let Some(max_size) = policy.max_size {
+		let mut total: u64 = sorted
+			.iter()
+			.zip(&selected)
+			.filter(|(_, s)| !**s)
+			.map(|(x, _)| x.size)
+			.sum();
+		for (idx, item) in sorted.iter().enumerate() {
+			if total <= max_size {
+				break;
+			}
+			if !selected[idx] {
+				selected[idx] = true;
+				total -= item.size;
+			}
+		}
+	}
+	sorted
+		.into_iter()
+		.zip(selected)
+		.filter_map(|(x, s)| s.then_some(x))
+		.collect()
+}
+
+/// Remove the items of the working directory selected by `policy`, returning the reclaimed bytes.
+///
+/// The caller must hold an exclusive [`WorkdirLock`].
+/// Sketch directories with raw images still attached to loop devices are skipped, unless `force_detach` is set.
+pub fn collect_garbage<P: AsRef<Path>>(
+	workdir: P,
+	policy: &RetentionPolicy,
+	dry_run: bool,
+	force_detach: bool,
+) -> Result<u64> {
+	let workdir = workdir.as_ref();
+	info!(
+		"Collecting garbage in the working directory {} ...",
+		workdir.display()
+	);
+	let items = scan_workdir(workdir)?;
+	let selected = select_items(&items, policy, SystemTime::now());
+	let mut reclaimed = 0;
+	for item in &selected {
+		if item.kind == ItemKind::Sketch {
+			if dry_run {
+				let loops = list_attached_loops(SYSFS_BLOCK_DIR)?;
+				if !force_detach && !loops_within(&loops, &item.path).is_empty() {
+					warn!(
+						"Would skip {}, which is still attached to a loop device",
+						item.path.display()
+					);
+					continue;
+				}
+			} else if !release_loop_devices(&item.path, force_detach)? {
+				continue;
+			}
+		}
+		info!(
+			"{} {} ({} MiB)",
+			if dry_run { "Would remove" } else { "Removing" },
+			item.path.display(),
+			item.size / 1048576
+		);
+		if !dry_run {
+			if item.path.is_dir() {
+				fs::remove_dir_all(&item.path)
+			} else {
+				fs::remove_file(&item.path)
+			}
+			.context(format!("Unable to remove {}", item.path.display()))?;
+		}
+		reclaimed += item.size;
+	}
+	info!(
+		"{} {} MiB from {} of {} items.",
+		if dry_run {
+			"Would reclaim"
+		} else {
+			"Reclaimed"
+		},
+		reclaimed / 1048576,
+		selected.len(),
+		items.len()
+	);
+	Ok(reclaimed)
+}
+
+/// Point [`LATEST_SKETCH`] to the sketch directory `dir`.
+pub fn link_latest_sketch(dir: &Path) -> Result<()> {
+	let sketches = dir.parent().context("Sketch directory has no parent")?;
+	let name = dir.file_name().context("Sketch directory has no name")?;
+	// Replaced atomically, it is never missing.
+	let tmp = sketches.join(format!(".{}.tmp", LATEST_SKETCH));
+	if tmp.symlink_metadata().is_ok() {
+		fs::remove_file(&tmp)?;
+	}
+	std::os::unix::fs::symlink(name, &tmp)
+		.context(format!("Unable to create {}", tmp.display()))?;
+	let link = sketches.join(LATEST_SKETCH);
+	fs::rename(&tmp, &link).context(format!("Unable to update {}", link.display()))
+}
+
+/// Find the most recently modified sketch directory in `sketches` named `<base>-<suffix>`, for a build to resume.
+///
+/// The suffix is the one of [`crate::plan::BuildRun::sketch_suffix`], e.g. `-1a2b3c4d`.
+pub fn latest_sketch(sketches: &Path, base: &str) -> Option<PathBuf> {
+	let prefix = format!("{}-", base);
+	fs::read_dir(sketches)
+		.ok()?
+		.filter_map(|entry| entry.ok())
+		.filter(|entry| {
+			let name = entry.file_name().to_string_lossy().into_owned();
+			name.strip_prefix(&prefix).is_some_and(|suffix| {
+				suffix.len() == 8 && suffix.chars().all(|c| c.is_ascii_hexdigit())
+			}) && entry.file_type().is_ok_and(|x| x.is_dir())
+		})
+		.filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+		.max()
+		.map(|(_, path)| path)
+}
+
+/// Find the sketch directories in `sketches` with filesystems still mounted within them, according to the content of `/proc/self/mountinfo`.
+///
+/// They are left behind by crashed or killed builds.
+pub fn stale_sketches(sketches: &Path, mountinfo: &str) -> Result<Vec<PathBuf>> {
+	if !sketches.is_dir() {
+		return Ok(Vec::new());
+	}
+	let sketches = sketches.canonicalize()?;
+	let mut stale = Vec::new();
+	for entry in fs::read_dir(&sketches)? {
+		let entry = entry?;
+		if !entry.file_type()?.is_dir() {
+			continue;
+		}
+		if !mounts_under(mountinfo, &entry.path()).is_empty() {
+			stale.push(entry.path());
+		}
+	}
+	stale.sort();
+	Ok(stale)
+}
+
+/// Unmount the filesystems and detach the loop devices left behind in the sketch directory `dir`, keeping its content.
+pub fn release_sketch(dir: &Path) -> Result<()> {
+	let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+	let mounts = mounts_under(&mountinfo, &dir.canonicalize()?);
+	for mp in mounts.iter().rev() {
+		info!("Unmounting {} ...", mp.display());
+		unmount_busy_retrying(mp)?;
+	}
+	release_loop_devices(dir, true)?;
+	Ok(())
+}
+
+/// Refuse to build if a sketch directory in `workdir` still has filesystems mounted, except the ones in `resumed`, or release them if `force` is set.
+pub fn check_stale_sketches(workdir: &Path, resumed: &[PathBuf], force: bool) -> Result<()> {
+	let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+	let resumed: Vec<PathBuf> = resumed
+		.iter()
+		.filter_map(|x| x.canonicalize().ok())
+		.collect();
+	let stale: Vec<PathBuf> = stale_sketches(&workdir.join("sketches"), &mountinfo)?
+		.into_iter()
+		.filter(|x| !resumed.contains(x))
+		.collect();
+	if stale.is_empty() {
+		return Ok(());
+	}
+	if !force {
+		bail!(
+			"Filesystems are still mounted in the sketch directories of previous builds:\n{}\nUnmount them first, or pass --force-clean-stale to release them.",
+			stale
+				.iter()
+				.map(|x| format!("\t{}", x.display()))
+				.collect::<Vec<_>>()
+				.join("\n")
+		);
+	}
+	for dir in &stale {
+		warn!("Releasing the stale sketch directory {} ...", dir.display());
+		release_sketch(dir)?;
+	}
+	Ok(())
+}
+
+/// Remove the sketch directories in the working directory, and the `sketches` directory itself if they are all removed.
+///
+/// Sketch directories with raw images still attached to loop devices are skipped, unless `force_detach` is set.
+pub fn remove_sketches<P: AsRef<Path>>(workdir: P, force_detach: bool) -> Result<()> {
+	let sketch_dir = workdir.as_ref().join("sketches");
+	if !sketch_dir.is_dir() {
+		return Ok(());
+	}
+	let mut skipped = false;
+	for entry in fs::read_dir(&sketch_dir)? {
+		let path = entry?.path();
+		if !release_loop_devices(&path, force_detach)? {
+			skipped = true;
+			continue;
+		}
+		if path.is_dir() {
+			fs::remove_dir_all(&path)
+		} else {
+			fs::remove_file(&path)
+		}
+		.context(format!("Unable to remove {}", path.display()))?;
+	}
+	if skipped {
+		bail!("Some of the sketch directories are still in use by loop devices");
+	}
+	fs::remove_dir(&sketch_dir)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::TestDir;
+	use std::fs::FileTimes;
+
+	const DAY: Duration = Duration::from_secs(86400);
+
+	fn item(path: &str, kind: ItemKind, days_ago: u64, size: u64) -> WorkdirItem {
+		WorkdirItem {
+			path: PathBuf::from(path),
+			kind,
+			mtime: SystemTime::UNIX_EPOCH + DAY * (100 - days_ago as u32),
+			size,
+		}
+	}
+
+	fn bootstrap(arch: &str) -> ItemKind {
+		ItemKind::Bootstrap {
+			arch: arch.to_owned(),
+		}
+	}
+
+	fn paths(selected: Vec<&WorkdirItem>) -> Vec<&str> {
+		selected
+			.into_iter()
+			.map(|x| x.path.to_str().unwrap())
+			.collect()
+	}
+
+	#[test]
+	fn test_select_items() {
+		let now = SystemTime::UNIX_EPOCH + DAY * 100;
+		let items = vec![
+			item("bootstrap/base-amd64", bootstrap("amd64"), 1, 100),
+			item("bootstrap/desktop-amd64", bootstrap("amd64"), 20, 300),
+			item("bootstrap/base-arm64", bootstrap("arm64"), 30, 100),
+			item("bootstrap/base-amd64.log", ItemKind::Log, 10, 1),
+			item("sketches/rpi-5b-base", ItemKind::Sketch, 8, 50),
+			item("sketches/pc-efi-base", ItemKind::Sketch, 2, 50),
+		];
+		assert!(select_items(&items, &RetentionPolicy::default(), now).is_empty());
+		// Bootstraps are not removed by age
+		let policy = RetentionPolicy {
+			max_age: Some(DAY * 7),
+			..Default::default()
+		};
+		assert_eq!(
+			paths(select_items(&items, &policy, now)),
+			["bootstrap/base-amd64.log", "sketches/rpi-5b-base"]
+		);
+		let policy = RetentionPolicy {
+			keep_bootstraps: Some(1),
+			..Default::default()
+		};
+		assert_eq!(
+			paths(select_items(&items, &policy, now)),
+			["bootstrap/desktop-amd64"]
+		);
+		// The oldest items go first until the rest fit in
+		let policy = RetentionPolicy {
+			max_size: Some(250),
+			..Default::default()
+		};
+		assert_eq!(
+			paths(select_items(&items, &policy, now)),
+			["bootstrap/base-arm64", "bootstrap/desktop-amd64"]
+		);
+		// Items already removed by other limits count
+		let policy = RetentionPolicy {
+			max_size: Some(250),
+			keep_bootstraps: Some(1),
+			..Default::default()
+		};
+		assert_eq!(
+			paths(select_items(&items, &policy, now)),
+			["bootstrap/base-arm64", "bootstrap/desktop-amd64"]
+		);
+	}
+
+	#[test]
+	fn test_collect_garbage() -> Result<()> {
+		let workdir = TestDir::new("gc")?;
+		let now = SystemTime::now();
+		for (path, days_ago) in [
+			("bootstrap/base-amd64/etc/os-release", 1),
+			("bootstrap/desktop-amd64/etc/os-release", 5),
+			("bootstrap/desktop-amd64.log", 5),
+			("sketches/rpi-5b-base/rawmedia.img", 10),
+			("sketches/pc-efi-base/rawmedia.img", 0),
+		] {
+			let path = workdir.join(path);
+			fs::create_dir_all(path.parent().unwrap())?;
+			fs::write(&path, "content")?;
+			File::options()
+				.write(true)
+				.open(&path)?
+				.set_times(FileTimes::new().set_modified(now - DAY * days_ago))?;
+		}
+		let lock = WorkdirLock::exclusive(&workdir, false)?;
+		let e = WorkdirLock::shared(&workdir, false)
+			.err()
+			.unwrap()
+			.to_string();
+		assert!(
+			e.contains(&format!("(pid {})", std::process::id())),
+			"{}",
+			e
+		);
+		assert!(WorkdirLock::exclusive(&workdir, false).is_err());
+		let bootstrap_lock = WorkdirLock::bootstrap(&workdir.join("bootstrap/base-amd64"))?;
+		assert!(workdir.join("bootstrap/.base-amd64.lock").is_file());
+		// Other distributions are locked on their own
+		drop(WorkdirLock::bootstrap(
+			&workdir.join("bootstrap/desktop-amd64"),
+		)?);
+		let items = scan_workdir(&workdir)?;
+		assert_eq!(items.len(), 5);
+		assert!(
+			items
+				.iter()
+				.any(|x| x.kind == bootstrap("amd64") && x.path.ends_with("desktop-amd64"))
+		);
+		let policy = RetentionPolicy {
+			max_age: Some(DAY * 3),
+			keep_bootstraps: Some(1),
+			..Default::default()
+		};
+		collect_garbage(&workdir, &policy, true, false)?;
+		assert!(workdir.join("sketches/rpi-5b-base").exists());
+		collect_garbage(&workdir, &policy, false, false)?;
+		let remaining = scan_workdir(&workdir)?;
+		remove_sketches(&workdir, false)?;
+		let sketches_removed = !workdir.join("sketches").exists();
+		drop(bootstrap_lock);
+		drop(lock);
+		// Released when dropped
+		WorkdirLock::exclusive(&workdir, true)?.remove()?;
+		let mut remaining: Vec<_> = remaining
+			.into_iter()
+			.map(|x| x.path.strip_prefix(&workdir).unwrap().to_owned())
+			.collect();
+		remaining.sort();
+		assert_eq!(
+			remaining,
+			[
+				PathBuf::from("bootstrap/base-amd64"),
+				PathBuf::from("sketches/pc-efi-base")
+			]
+		);
+		assert!(sketches_removed);
+		Ok(())
+	}
+
+	#[test]
+	fn test_sketch_dirs() -> Result<()> {
+		let workdir = TestDir::new("sketches")?;
+		let sketches = workdir.join("sketches");
+		for (name, days_ago) in [
+			("rpi-5b-Base-0123abcd", 2),
+			("rpi-5b-Base-4567ef00", 1),
+			("rpi-5b-Base-rootfs-89abcdef", 0),
+			("rpi-5b-Base-patch", 0),
+		] {
+			let dir = sketches.join(name);
+			fs::create_dir_all(&dir)?;
+			File::open(&dir)?.set_modified(SystemTime::now() - DAY * days_ago)?;
+		}
+		assert_eq!(
+			latest_sketch(&sketches, "rpi-5b-Base"),
+			Some(sketches.join("rpi-5b-Base-4567ef00"))
+		);
+		assert_eq!(latest_sketch(&sketches, "rpi-5b"), None);
+		link_latest_sketch(&sketches.join("rpi-5b-Base-0123abcd"))?;
+		link_latest_sketch(&sketches.join("rpi-5b-Base-4567ef00"))?;
+		assert_eq!(
+			fs::read_link(sketches.join(LATEST_SKETCH))?,
+			PathBuf::from("rpi-5b-Base-4567ef00")
+		);
+		// Not an item of its own
+		assert_eq!(scan_workdir(&workdir)?.len(), 4);
+
+		let root = sketches.canonicalize()?;
+		let mountinfo = format!(
+			"22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw\n\
+			100 22 7:0 / {}/rpi-5b-Base-0123abcd/mnt/p2 rw,relatime shared:50 - ext4 /dev/loop0p2 rw\n\
+			101 22 7:0 / {}/rpi-5b-Base-patch/mnt/p1 rw,relatime shared:51 - vfat /dev/loop1p1 rw\n",
+			root.display(),
+			root.display()
+		);
+		assert_eq!(
+			stale_sketches(&sketches, &mountinfo)?,
+			[
+				root.join("rpi-5b-Base-0123abcd"),
+				root.join("rpi-5b-Base-patch")
+			]
+		);
+		assert!(stale_sketches(&workdir.join("missing"), &mountinfo)?.is_empty());
+		Ok(())
+	}
+}
@@ -0,0 +1,216 @@
+//! Module handling the build hooks.
+//!
+//! Hooks are scripts run at defined stages of the build, for the steps which can not be done by the bootloaders or the post installation script.
+//!
+//! For details please go to [`HookSpec`].
+//!
+use std::{
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::{
+	context::ImageContext,
+	device::PartitionMapData,
+	utils::{
+		cmd_run_check_status, copy_preserving, partition_path, path_str,
+		run_str_script_with_chroot, shell_quote,
+	},
+};
+
+/// Stages of the build where hooks can run, in the order of execution.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord, strum::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum HookStage {
+	/// Right after the image is partitioned. Filesystems are not created yet.
+	PostPartition,
+	/// Right after the partitions are formatted.
+	PostFormat,
+	/// After the partitions are mounted, before the system distribution is installed.
+	PreRootfs,
+	/// After the system distribution is installed and `/etc/fstab` is generated, before the BSP packages are installed.
+	PostRootfs,
+	/// After the bootloaders are applied, before the image is unmounted and compressed.
+	PreCompress,
+}
+
+impl HookStage {
+	/// Whether the hooks at this stage run on the build host, since the root filesystem is not ready yet.
+	pub fn runs_on_host(&self) -> bool {
+		matches!(
+			self,
+			Self::PostPartition | Self::PostFormat | Self::PreRootfs
+		)
+	}
+}
+
+/// A script to be run at a specific stage of the build.
+///
+/// ```toml
+/// [[hook]]
+/// stage = "pre_rootfs"
+/// # Relative to the directory containing device.toml.
+/// script = "copy-firmware.sh"
+/// ```
+///
+/// Possible stages are, in the order of execution:
+///
+/// - `post_partition`: Right after the image is partitioned. Filesystems are not created yet.
+/// - `post_format`: Right after the partitions are formatted.
+/// - `pre_rootfs`: After the partitions are mounted, before the system distribution is installed.
+/// - `post_rootfs`: After the system distribution is installed and `/etc/fstab` is generated, before the BSP packages are installed.
+/// - `pre_compress`: After the bootloaders are applied, before the image is unmounted and compressed.
+///
+/// Hooks at the same stage run in the order they are defined.
+///
+/// Hooks at `post_partition`, `post_format` and `pre_rootfs` run on the build host with `bash`, within the sketch directory of the image.
+/// The [defined variables] are exported to the environment, filesystem UUIDs are not available for `post_partition`.
+/// Additionally `MOUNTDIR` contains the directory where the partitions are mounted as `p1`, `p2`, etc. for `pre_rootfs`.
+///
+/// Hooks at `post_rootfs` and `pre_compress` run within the target OS image like the post installation script.
+///
+/// `HOOK_STAGE` is set to the current stage for all hooks.
+///
+/// [defined variables]: crate::device::DeviceSpec#available-defined-variables
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct HookSpec {
+	pub stage: HookStage,
+	pub script: PathBuf,
+}
+
+/// Paths used by the hooks.
+pub struct HookEnv<'a> {
+	pub loopdev: &'a Path,
+	pub sketch_dir: &'a Path,
+	pub mountdir: &'a Path,
+	/// Bind mounts for the hooks running in the container.
+	pub binds: &'a [&'a str],
+}
+
+impl ImageContext<'_> {
+	/// Run the hooks defined for `stage`.
+	///
+	/// `rootfs` is the root filesystem of the target, required by hooks running in the container.
+	pub fn run_hooks(
+		&self,
+		stage: HookStage,
+		env: &HookEnv,
+		pm_data: &PartitionMapData,
+		rootfs: Option<&Path>,
+	) -> Result<()> {
+		let Some(hooks) = &self.device.hooks else {
+			return Ok(());
+		};
+		let device_spec_dir = self
+			.device
+			.file_path
+			.parent()
+			.context("Failed to reach the directory containing the device spec file")?;
+		for hook in hooks.iter().filter(|h| h.stage == stage) {
+			let script = device_spec_dir.join(&hook.script);
+			self.info(format!(
+				"Running {} hook {} ...",
+				stage,
+				hook.script.display()
+			));
+			if stage.runs_on_host() {
+				let root_num = self
+					.device
+					.partitions
+					.iter()
+					.find(|p| p.usage == crate::partition::PartitionUsage::Rootfs)
+					.context("Unable to find a root filesystem")?
+					.num;
+				let rootpart = partition_path(env.loopdev, root_num);
+				let vars = self.spec_vars(&env.loopdev, &rootpart, pm_data)?;
+				let mut cmd = Command::new("bash");
+				cmd.arg("--")
+					.arg(&script)
+					.current_dir(env.sketch_dir)
+					.envs(vars)
+					.env("HOOK_STAGE", stage.to_string());
+				if stage == HookStage::PreRootfs {
+					cmd.env("MOUNTDIR", env.mountdir);
+				}
+				cmd_run_check_status(&mut cmd)
+					.context(format!("Hook {} failed", hook.script.display()))?;
+			} else {
+				let rootfs = rootfs.context("Root filesystem is not available for the hook")?;
+				let filename = script
+					.file_name()
+					.context("Unable to get the basename of the script")?;
+				let dst_path = Path::new("/tmp").join(filename);
+				copy_preserving(&script, rootfs.join("tmp").join(filename))
+					.context("Failed to copy the hook script")?;
+				let full_script = format!(
+					"export HOOK_STAGE={}; source {}",
+					shell_quote(stage.to_string()),
+					shell_quote(path_str(&dst_path)?)
+				);
+				run_str_script_with_chroot(
+					&rootfs,
+					&self.machine_name(),
+					&full_script,
+					env.binds,
+					None,
+				)
+				.context(format!("Hook {} failed", hook.script.display()))?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Check the hooks defined in the device spec within `dirname`.
+pub fn check_hooks(hooks: &[HookSpec], dirname: &Path) -> Result<()> {
+	for hook in hooks {
+		if hook.script.is_absolute() {
+			bail!(
+				"Hook script '{}' must be relative to the directory containing device.toml",
+				hook.script.display()
+			);
+		}
+		if !dirname.join(&hook.script).is_file() {
+			bail!(
+				"Hook script '{}' for stage {} not found within the same directory as the device.toml",
+				hook.script.display(),
+				hook.stage
+			);
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	#[test]
+	fn test_hook_spec() -> Result<()> {
+		#[derive(Deserialize)]
+		struct Hooks {
+			hook: Vec<HookSpec>,
+		}
+		let hooks: Hooks = toml::from_str(
+			"[[hook]]\nstage = \"post_format\"\nscript = \"a.sh\"\n[[hook]]\nstage = \"pre_compress\"\nscript = \"b.sh\"\n",
+		)?;
+		assert_eq!(hooks.hook[0].stage, HookStage::PostFormat);
+		assert!(hooks.hook[0].stage.runs_on_host());
+		assert!(!hooks.hook[1].stage.runs_on_host());
+		assert!(
+			toml::from_str::<Hooks>("[[hook]]\nstage = \"post_build\"\nscript = \"a.sh\"\n")
+				.is_err()
+		);
+		assert!(check_hooks(&hooks.hook, Path::new("/nonexistent")).is_err());
+		let dir = TestDir::new("hooks")?;
+		std::fs::write(dir.join("a.sh"), "")?;
+		std::fs::write(dir.join("b.sh"), "")?;
+		check_hooks(&hooks.hook, &dir)?;
+		Ok(())
+	}
+}
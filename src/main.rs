@@ -7,7 +7,7 @@
 //!
 //! ### Library Dependencies (Linked Libraries)
 //!
-//! - `libblkid`: for gathering information for block devices, primarily their unique identifiers.
+//! - `libblkid`: for gathering information for block devices, primarily their unique identifiers. Not needed if built with `--no-default-features --features no-blkid`, which reads the filesystem UUIDs from the superblocks itself.
 //! - `liblzma`: for compressing the image file with LZMA2 (xz).
 //! - `libzstd`: for compressing the image file with ZStandard.
 //!
@@ -20,7 +20,7 @@
 //! - `chroot`: For entering the chroot environment of the target container to perform post-installation steps.
 //! - `useradd` from shadow: For adding user to the target container.
 //! - `chpasswd` from shadow: For changing user passwords.
-//! - `partprobe`: For updating the in-kernel partition table cache.
+//! - `tar` (GNU tar): For creating root filesystem tarballs with `--format tarball`.
 //!
 //! ### `binfmt_misc` support and respective binary interpreters
 //!
@@ -39,6 +39,8 @@
 //!
 //! With `binfmt_misc` support enabled, you will have to install `qemu-user-static` (or equivalent packages for your distribution) to allow your system to execute binary executables for the target device's architecture.
 //!
+//! Images which never execute programs of the target (no BSP packages, post installation scripts or bootloaders running in the target) are built in "no-exec" mode without `binfmt_misc` support, once the release is bootstrapped. See `requires_target_exec` in [`DeviceSpec`].
+//!
 //! Building
 //! --------
 //!
@@ -61,6 +63,8 @@
 //! - `pretty`: table format which contains basic information.
 //! - `simple`: simple column-based format splitted by tab character (`'\t'`).
 //!
+//! Add `--tag TAG` to list only the devices having the tag (or any of the tags, if specified more than once).
+//!
 //! ### Build images for one specific device
 //!
 //! <div class="warning">
@@ -119,28 +123,99 @@
 // I have some sample code from the Linux kernel in my docstrings.
 // Clippy warns me about the tabs, this is denial!
 #![allow(clippy::tabs_in_doc_comments)]
+/// Module handling the block maps of raw images for bmaptool.
+#[doc(hidden)]
+mod bmap;
 mod bootloader;
+/// Module handling the provenance of the bootstrapped distributions.
+#[doc(hidden)]
+mod bootstrap;
 mod cli;
+/// Module handling the compression of the raw images.
+#[doc(hidden)]
+mod compress;
+/// Module handling the configuration file of the global defaults.
+#[doc(hidden)]
+mod config;
+mod content;
 /// Module handling the actual generation jobs.
 #[doc(hidden)]
 mod context;
 mod device;
+/// Module handling the estimation of the build resources.
+#[doc(hidden)]
+mod estimate;
+/// Module handling the export of the generated scripts.
+#[doc(hidden)]
+mod export;
+/// Module handling the templates of the output filenames.
+#[doc(hidden)]
+mod filename;
 /// Module handling the filesystems.
 #[doc(hidden)]
 mod filesystem;
+/// Module providing the fixtures shared by the tests.
+#[doc(hidden)]
+#[cfg(test)]
+mod fixtures;
+/// Module handling the temporary files installed in the target system while the steps run in the container.
+#[doc(hidden)]
+mod fixups;
+/// Module handling the garbage collection of the working directory.
+#[doc(hidden)]
+mod gc;
+mod hook;
 /// Module handling the partitions.
 mod partition;
+/// Module handling the in-place patching of existing images.
+#[doc(hidden)]
+mod patch;
+/// Module planning what to do from the command line.
+#[doc(hidden)]
+mod plan;
 /// Module handling the package installation.
 #[doc(hidden)]
 mod pm;
+/// Module probing filesystem UUIDs without libblkid.
+#[doc(hidden)]
+#[cfg(any(test, feature = "no-blkid", not(feature = "blkid")))]
+mod probe;
 mod registry;
+/// Module handling the build report in the output directory.
+#[doc(hidden)]
+mod report;
+/// Module handling the resume of interrupted builds.
+#[doc(hidden)]
+mod resume;
+/// Module handling the scaffolding of new device directories.
+#[doc(hidden)]
+mod scaffold;
+/// Module detecting the unknown fields of device specifications.
+#[doc(hidden)]
+mod schema;
+/// Module handling the removal of the per-machine identities from the images.
+#[doc(hidden)]
+mod scrub;
+mod secureboot;
+/// Module handling the shrinking of raw images to the used size.
+#[doc(hidden)]
+mod shrink;
+/// Module handling the Android sparse images converted from the raw images.
+#[doc(hidden)]
+mod simg;
+mod sources;
+mod swap;
 #[doc(hidden)]
 mod tests;
 #[doc(hidden)]
 mod topics;
+mod user;
 /// Module containing various utility functions.
 #[doc(hidden)]
 mod utils;
+/// Module validating the strings defined in device specifications.
+#[doc(hidden)]
+mod validate;
 
 pub use cli::Cmdline;
 pub use device::DeviceSpec;
@@ -148,31 +223,37 @@ use topics::{fetch_topics, filter_topics};
 
 use core::time;
 use std::{
-	env::var,
-	fs::{remove_dir, remove_dir_all},
+	fs::{self, remove_dir, remove_dir_all},
+	io::IsTerminal,
 	path::{Path, PathBuf},
 	time::Instant,
 };
 
 use anyhow::bail;
 use anyhow::{Context, Result, anyhow};
-use chrono::Utc;
+use bootstrap::{AB_DIR, check_pins, format_pins, load_hashes, load_pins, system_pins};
 use clap::Parser;
+use clap::ValueEnum;
 use cli::Action;
-use cli::RootFsType;
-use context::{ImageContext, ImageContextQueue};
-use filesystem::FilesystemType;
+use cli::Compression;
+use cli::OutputFormat;
+use cli::OutputLayout;
+use context::{ImageContext, ImageContextQueue, ImageVariant};
+use device::DeviceArch;
+use estimate::{ImageFailure, ImageSkip, ImageTimings, RunTimings, StageTiming};
+use gc::{WorkdirLock, check_stale_sketches, collect_garbage, remove_sketches};
 use log::{debug, error, info, warn};
-use owo_colors::colored::*;
+use owo_colors::{OwoColorize, Stream::Stderr};
+use partition::PartitionUsage;
+use plan::{BuildOptions, BuildRun, DeviceSelection, Plan, check_output_paths, select_devices};
 use registry::DeviceRegistry;
-use utils::{bootstrap_distribution, check_binfmt, restore_term, return_ownership_recursive};
-
-#[doc(hidden)]
-enum BuildMode {
-	BuildOne,
-	BuildAll,
-	None, // check
-}
+use report::{BuildReport, RunEntry, RunStatus, RunSummary};
+use utils::{
+	bootstrap_distribution, check_binfmt, check_binfmt_all, format_binfmt_failures, get_sudo_ids,
+	init_term_caps, path_str, preserve_sudo_env, restore_term, return_ownership_recursive,
+	source_date_epoch,
+};
+use validate::{FieldClass, validate_work_path};
 
 #[doc(hidden)]
 const DISTRO_REGISTRY_DIR: &str = match option_env!("DISTRO_REGISTRY_DIR") {
@@ -197,14 +278,21 @@ fn main() -> Result<()> {
 	// Parse the command line
 	let cmdline = Cmdline::try_parse()?;
 	match &cmdline.action {
-		Action::Build { .. } | Action::BuildAll { .. } => {
-			if unsafe { utils::geteuid() } != 0 {
-				bail!("Please run me as root!");
-			}
+		Action::Build { .. }
+		| Action::BuildAll { .. }
+		| Action::Rebootload { .. }
+		| Action::Patch { .. }
+			if unsafe { utils::geteuid() } != 0 =>
+		{
+			bail!("Please run me as root!");
 		}
 		_ => (),
 	}
+	let term_caps = init_term_caps(cmdline.no_color, cmdline.no_progress);
 	let mut logger = colog::basic_builder();
+	if !term_caps.color {
+		logger.write_style(env_logger::WriteStyle::Never);
+	}
 	if cmdline.debug {
 		logger.filter(None, log::LevelFilter::Debug);
 	} else {
@@ -217,18 +305,7 @@ fn main() -> Result<()> {
 	if let Err(e) = try_main(cmdline) {
 		// Recover the terminal
 		restore_term();
-		// Use logger to pretty-print errors
-		let mut str_buf = String::new();
-		error!("Error encountered!\n{}", e);
-		let mut ident = 0;
-		e.chain().skip(1).for_each(|cause| {
-			let ident_str = "\t".repeat(ident);
-			ident += 1;
-			str_buf += &format!("{0}- Caused by:\n{0}  {1}", ident_str, cause);
-		});
-		if !str_buf.is_empty() {
-			error!("{}", str_buf);
-		}
+		log_error(&e);
 		error!("Exiting now.");
 		std::process::exit(1);
 	}
@@ -236,15 +313,114 @@ fn main() -> Result<()> {
 }
 
 #[doc(hidden)]
-fn try_main(cmdline: Cmdline) -> Result<()> {
-	// Say hi
-	info!("Welcome to mkrawimg!");
-	// Operation mode: build, buildall, test.
-	let action = cmdline.action;
-	let mut buildmode = BuildMode::None;
-	// let mut devices = Vec::new();
-	let registry_dir = if let Some(path) = cmdline.registry {
-		path
+/// The entry of the image `j` failed with `e` in the summary of the build run.
+fn failed_entry(j: &ImageContext, e: &anyhow::Error, start: Instant) -> RunEntry {
+	RunEntry {
+		secs: start.elapsed().as_secs_f64(),
+		log: j.log_path().ok().map(|x| x.display().to_string()),
+		reason: Some(format!("{:#}", e)),
+		..j.run_entry(RunStatus::Failed)
+	}
+}
+
+#[doc(hidden)]
+/// Print the summary of the build run, and record it in the build report of `outdir`.
+fn report_run(summary: &RunSummary, outdir: &Path) {
+	if summary.succeeded() {
+		info!("{}", summary.table());
+	} else {
+		warn!("{}", summary.table());
+	}
+	if let Err(e) = BuildReport::save_run(outdir, summary) {
+		warn!(
+			"Unable to record the build run in the build report: {:?}",
+			e
+		);
+	}
+}
+
+#[doc(hidden)]
+/// Guess the variant of an existing image from its name, e.g. `_desktop_` in the default template.
+fn image_variant(image: &Path) -> ImageVariant {
+	image
+		.file_name()
+		.and_then(|x| {
+			let name = x.to_string_lossy();
+			ImageVariant::value_variants()
+				.iter()
+				.find(|v| name.contains(&format!("_{}_", v.to_string().to_lowercase())))
+				.copied()
+		})
+		.unwrap_or(ImageVariant::Base)
+}
+
+#[doc(hidden)]
+/// The context of a job working on an existing raw image of `device`, instead of building one.
+fn existing_image_context<'a>(
+	device: &'a DeviceSpec,
+	variant: &'a ImageVariant,
+	compression: &'a Compression,
+	cmdline: &'a Cmdline,
+	run: &'a BuildRun,
+) -> ImageContext<'a> {
+	ImageContext {
+		device,
+		variant,
+		workdir: &cmdline.workdir,
+		outdir: &cmdline.outdir,
+		user: &cmdline.user,
+		password: &cmdline.password,
+		user_options: &cmdline.user_options,
+		root_policy: &cmdline.root_policy,
+		filename: String::new(),
+		base_dist: PathBuf::new(),
+		override_rootfs_fstype: &None,
+		additional_packages: &None,
+		oobe_package: &None,
+		compress: compression,
+		format: &OutputFormat::Rawimg,
+		topics: None,
+		seed: None,
+		run,
+		force_detach: cmdline.force_detach,
+		min_free_inodes: cmdline.min_free_inodes,
+		usage_warn_threshold: cmdline.usage_warn_threshold,
+		usage_fail_threshold: cmdline.usage_fail_threshold,
+		loop_attempts: cmdline.loop_attempts,
+		log_dir: cmdline.log_dir.as_deref(),
+		debug_shell: false,
+		no_scrub: false,
+		skip_size_check: false,
+		shrink: false,
+		no_bmap: false,
+		keep_raw: false,
+		resume: false,
+		output_layout: OutputLayout::Hierarchy,
+		mirror: &cmdline.mirror,
+	}
+}
+
+#[doc(hidden)]
+/// Pretty-print the error and its causes with the logger.
+fn log_error(e: &anyhow::Error) {
+	let mut str_buf = String::new();
+	error!("Error encountered!\n{}", e);
+	let mut ident = 0;
+	e.chain().skip(1).for_each(|cause| {
+		let ident_str = "\t".repeat(ident);
+		ident += 1;
+		str_buf += &format!("{0}- Caused by:\n{0}  {1}", ident_str, cause);
+	});
+	if !str_buf.is_empty() {
+		error!("{}", str_buf);
+	}
+}
+
+#[doc(hidden)]
+/// Find the device registry: `--registry`, else `./devices` if it exists, else the one of the distribution.
+fn find_registry(registry: Option<&Path>) -> Result<PathBuf> {
+	let registry_dir = if let Some(path) = registry {
+		path.to_owned()
 	} else if PathBuf::from("./devices").exists() {
 		PathBuf::from("./devices")
 	} else {
@@ -267,87 +443,167 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 			registry_dir.to_string_lossy()
 		))
 	};
-	let registry_dir = if let Ok(x) = registry_dir {
-		x
+	if let Ok(x) = registry_dir {
+		Ok(x)
 	} else {
-		return Err(anyhow!(
+		Err(anyhow!(
 			"Cannot assemble registry: {}",
-			registry_dir.unwrap_err().bright_red()
-		));
-	};
-	let device_str = match &action {
-		cli::Action::Build { device, .. } => {
-			buildmode = BuildMode::BuildOne;
-			Some(device.to_owned())
-		}
-		cli::Action::BuildAll { .. } => {
-			warn!(
-				"Attempting to build images for all devices. Make sure this is what you want to do."
-			);
-			buildmode = BuildMode::BuildAll;
-			None
-		}
-		cli::Action::Check { device } => device.as_ref().map(|d| d.to_owned()),
-		cli::Action::List { .. } => None,
-	};
-	let registry = if let Some(device_str) = &device_str {
-		let try_path = Path::new(&device_str);
-		if try_path.exists() {
-			DeviceRegistry::from(try_path)?
-		} else if registry_dir.join(try_path).exists() {
-			info!("Relative path detected, assuming it's within the registry directory.");
-			DeviceRegistry::from(registry_dir.join(try_path))?
-		} else {
-			info!(
-				"Device ID or alias '{}' provided. Assembling the full registry ...",
-				&device_str
-			);
-			DeviceRegistry::scan(registry_dir)?
-		}
-	} else {
-		DeviceRegistry::scan(registry_dir)?
-	};
-	match action {
-		cli::Action::Build {
-			fstype,
-			compression: compress,
-			variants,
-			revision,
-			additional_packages,
-			topics,
-			..
-		}
-		| cli::Action::BuildAll {
-			fstype,
-			compression: compress,
-			variants,
-			revision,
-			additional_packages,
-			topics,
+			registry_dir
+				.unwrap_err()
+				.if_supports_color(Stderr, |t| t.bright_red())
+		))
+	}
+}
+
+fn try_main(mut cmdline: Cmdline) -> Result<()> {
+	// Say hi
+	info!("Welcome to mkrawimg!");
+	let (config_path, config) = config::Config::find(cmdline.config.as_deref())?;
+	if let Some(path) = &config_path {
+		info!("Using the defaults in {}.", path.display());
+	}
+	cmdline.apply_config(config)?;
+	if cmdline.preserve_env {
+		preserve_sudo_env()?;
+	}
+	let policy = cmdline.retention_policy();
+	let plan = Plan::from(cmdline.action.clone());
+	let mut run = BuildRun::new(match &plan {
+		Plan::Build { options, .. } => options.revision,
+		_ => None,
+	})?;
+	if let Some(template) = &cmdline.filename_template {
+		run.filename_template = template.clone();
+	}
+	if matches!(
+		plan,
+		Plan::Build { .. } | Plan::Rebootload { .. } | Plan::Patch { .. }
+	) {
+		info!(
+			"Build run {} started at {} (UTC).",
+			run.id,
+			run.timestamp.format("%Y-%m-%d %H:%M:%S")
+		);
+	}
+	if matches!(plan, Plan::Build { .. } | Plan::Rebootload { .. }) {
+		validate_work_path("--workdir", &cmdline.workdir)?;
+		validate_work_path("--outdir", &cmdline.outdir)?;
+	}
+	if matches!(plan, Plan::Patch { .. }) {
+		validate_work_path("--workdir", &cmdline.workdir)?;
+	}
+	match plan {
+		Plan::Build {
+			devices: selection,
+			options:
+				BuildOptions {
+					fstype,
+					compression: compress,
+					variants,
+					revision: _,
+					additional_packages,
+					topics,
+					override_spec,
+					defer_triggers,
+					skip_size_check,
+					shrink,
+					no_bmap,
+					keep_raw,
+					format,
+					reproducible,
+					debug_shell,
+					no_scrub,
+					oobe_package,
+					resume,
+				},
 		} => {
-			let fstype = match fstype {
-				Some(RootFsType::Ext4) => Some(FilesystemType::Ext4),
-				Some(RootFsType::Btrfs) => Some(FilesystemType::Btrfs),
-				Some(RootFsType::Xfs) => Some(FilesystemType::Xfs),
-				_ => None,
-			};
-			let date = Utc::now();
-			let date_str = date.format("%Y%m%d");
-			let devices = match buildmode {
-				BuildMode::BuildAll => registry.get_all()?,
-				BuildMode::BuildOne => {
-					let v = vec![registry.get(device_str.as_ref().unwrap())?];
-					// Since we need to try to get a device with that name first.
-					info!(
-						"Going to build images for device '{}'.",
-						&device_str.unwrap()
+			if debug_shell && !std::io::stdin().is_terminal() {
+				bail!("--debug-shell requires stdin to be a terminal");
+			}
+			if resume && format == OutputFormat::Tarball {
+				bail!("--resume is only available for raw images");
+			}
+			if let Some(package) = &oobe_package {
+				FieldClass::PackageName.validate("--oobe-package", package)?;
+			}
+			if reproducible.is_some() && source_date_epoch()?.is_none() {
+				warn!(
+					"SOURCE_DATE_EPOCH is not set, the timestamps are clamped to the Unix epoch."
+				);
+			}
+			if selection == DeviceSelection::All {
+				warn!(
+					"Attempting to build images for all devices. Make sure this is what you want to do."
+				);
+			}
+			let mut devices = select_devices(
+				&selection,
+				&find_registry(cmdline.registry.as_deref())?,
+				cmdline.lenient_scan,
+			)?;
+			if let DeviceSelection::One(device_str) | DeviceSelection::Compatible(device_str) =
+				&selection
+			{
+				if let Some(override_spec) = &override_spec {
+					let device = devices[0].apply_override(override_spec)?;
+					device
+						.check()
+						.context("The overridden device spec is invalid")?;
+					warn!(
+						"Device spec '{}' is overridden by '{}'. The resulting images are not built from the registry as-is!",
+						&device.id,
+						override_spec.display()
 					);
-					v
+					devices[0] = device;
 				}
-				BuildMode::None => {
-					panic!("Should not go here");
+				info!("Going to build images for device '{}'.", device_str);
+			}
+			if oobe_package.is_some() {
+				for device in devices.iter().filter(|x| !x.oobe_wizard) {
+					warn!(
+						"Device '{}' does not enable oobe_wizard, --oobe-package is ignored.",
+						&device.id
+					);
 				}
-			};
+			}
+			if defer_triggers {
+				for device in devices.iter_mut() {
+					if device.initrdless {
+						bail!(
+							"--defer-triggers can not be used with initrdless device '{}'",
+							&device.id
+						);
+					}
+					device.defer_triggers = true;
+				}
+			}
+			// The label of the root filesystem must also fit in the overridden filesystem.
+			if let Some(fstype) = &fstype {
+				for device in &devices {
+					for p in &device.partitions {
+						if p.usage == PartitionUsage::Rootfs {
+							fstype.check(&p.fs_label).context(format!(
+								"The root filesystem label of device '{}' does not fit in {:?}",
+								&device.id, fstype
+							))?;
+						}
+					}
+				}
+			}
+			for device in &devices {
+				if let Some(sources) = &device.sources {
+					sources
+						.check(&cmdline.mirror, &device.arch.aosc_arch())
+						.context(format!(
+							"The extra package sources of device '{}' are invalid with mirror '{}'",
+							&device.id, &cmdline.mirror
+						))?;
+				}
+				validate_work_path(
+					"the sketch directory",
+					&cmdline.workdir.join("sketches").join(&device.id),
+				)?;
+			}
 			let topics = if let Some(topics) = topics.as_ref() {
 				let all_topics = fetch_topics()?;
 				let filtered_topics = filter_topics(topics, all_topics)?;
@@ -359,37 +615,31 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 			// Prepare to build
 			info!("Preparing build ...");
 			std::fs::create_dir_all(&cmdline.workdir)?;
+			let lock = WorkdirLock::exclusive(&cmdline.workdir, cmdline.wait_for_lock)?;
+			if cmdline.gc_before_build {
+				collect_garbage(&cmdline.workdir, &policy, false, cmdline.force_detach)?;
+			}
 			std::fs::create_dir_all(&cmdline.outdir)?;
 			// build image contexts
 			let mut queue = ImageContextQueue::new();
 			let variants = variants.as_slice();
 			let user = &cmdline.user;
 			let password = &cmdline.password;
+			if cmdline.default_password {
+				warn!(
+					"The built-in user has the default password '{}', consider setting one with --password-file or --password-prompt.",
+					password
+				);
+			}
 			for device in devices.as_slice() {
-				check_binfmt(&device.arch)?;
 				for variant in variants {
 					let variant_str = variant.to_string().to_lowercase();
-					// aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108{.1}.img.xz
 					let base_dist = Path::new(&cmdline.workdir).join(format!(
 						"bootstrap/{}-{}",
 						&variant_str,
 						&device.arch.to_string().to_lowercase()
 					));
-					let filename = format!(
-						"aosc-os_{0}_rawimg_{1}_{2}_{3}{4}_{5}.img{6}",
-						&variant.to_string().to_lowercase(),
-						&device.vendor.clone(),
-						&device.id.clone(),
-						&date_str,
-						match revision {
-							Some(x) => {
-								format!(".{}", x)
-							}
-							_ => "".to_string(),
-						},
-						&device.arch.to_string().to_ascii_lowercase(),
-						compress.get_extension()
-					);
+					let filename = run.image_filename(device, variant, &format, &compress);
 					queue.push(ImageContext {
 						device,
 						variant,
@@ -397,20 +647,131 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 						outdir: &cmdline.outdir,
 						user,
 						password,
+						user_options: &cmdline.user_options,
+						root_policy: &cmdline.root_policy,
 						filename,
 						override_rootfs_fstype: &fstype,
 						additional_packages: &additional_packages,
+						oobe_package: &oobe_package,
 						compress: &compress,
+						format: &format,
 						base_dist,
 						topics,
+						seed: reproducible.as_deref(),
+						run: &run,
+						force_detach: cmdline.force_detach,
+						min_free_inodes: cmdline.min_free_inodes,
+						usage_warn_threshold: cmdline.usage_warn_threshold,
+						usage_fail_threshold: cmdline.usage_fail_threshold,
+						loop_attempts: cmdline.loop_attempts,
+						log_dir: cmdline.log_dir.as_deref(),
+						debug_shell,
+						no_scrub,
+						skip_size_check,
+						shrink,
+						no_bmap,
+						keep_raw,
+						resume,
+						output_layout: cmdline.output_layout,
+						mirror: &cmdline.mirror,
 					});
 				}
 			}
+			check_output_paths(&queue)?;
+			for j in &queue {
+				j.check_users()?;
+				j.check_shrink()?;
+			}
+			// The resumed builds release their own leftovers.
+			let resumed: Vec<PathBuf> = queue
+				.iter()
+				.filter(|j| j.resume)
+				.map(|j| j.sketch_dir())
+				.collect();
+			check_stale_sketches(&cmdline.workdir, &resumed, cmdline.force_clean_stale)?;
+			let mut summary = RunSummary {
+				run: run.id.to_string(),
+				images: Vec::new(),
+			};
+			let run_start = Instant::now();
+			let mut timings = RunTimings {
+				run: run.id.to_string(),
+				..Default::default()
+			};
+			// Check binfmt_misc support for all images executing programs of the target at once
+			let mut executes: Vec<bool> = Vec::new();
+			for j in &queue {
+				let requires_target_exec = j.requires_target_exec()?;
+				if !requires_target_exec {
+					info!(
+						"Nothing is executed in the target to build {}, building it in no-exec mode.",
+						&j.filename
+					);
+				}
+				// Bootstrapping the release executes programs of the target too.
+				executes.push(requires_target_exec || !j.base_dist.join("etc/os-release").exists());
+			}
+			let binfmt_failures = check_binfmt_all(
+				queue
+					.iter()
+					.zip(&executes)
+					.filter(|(_, x)| **x)
+					.map(|(j, _)| &j.device.arch),
+			);
+			let failed_archs: Vec<DeviceArch> = binfmt_failures.iter().map(|(x, _)| *x).collect();
+			if !binfmt_failures.is_empty() {
+				let msg = format_binfmt_failures(&binfmt_failures);
+				if !cmdline.keep_going {
+					bail!("{}", msg);
+				}
+				warn!("{}", msg);
+				// Only the images executing programs of the failed architectures are skipped.
+				let mut executes = executes.into_iter();
+				queue.retain(|j| {
+					if !(executes.next().unwrap() && failed_archs.contains(&j.device.arch)) {
+						return true;
+					}
+					let reason = format!(
+						"binfmt_misc support for {} is not available",
+						j.device.arch.to_string().to_lowercase()
+					);
+					warn!("Skipping {}: {}.", &j.filename, &reason);
+					summary.images.push(RunEntry {
+						reason: Some(reason.clone()),
+						..j.run_entry(RunStatus::Skipped)
+					});
+					timings.skipped.push(ImageSkip {
+						name: j.filename.clone(),
+						device: j.device.id.clone(),
+						variant: j.variant.to_string().to_lowercase(),
+						reason,
+					});
+					false
+				});
+				if queue.is_empty() {
+					// Still recorded, so the run is not mistaken for an empty successful one.
+					report_run(&summary, &cmdline.outdir);
+					if let Some(path) = &cmdline.timings_json {
+						timings.secs = run_start.elapsed().as_secs_f64();
+						timings.save(path)?;
+					}
+					bail!("All devices are skipped, nothing to build.");
+				}
+			}
 			info!(
 				"Job queue contains {} images for {} devices.",
-				queue.len().bright_cyan(),
-				devices.len().bright_cyan()
+				queue.len().if_supports_color(Stderr, |t| t.bright_cyan()),
+				devices
+					.iter()
+					.filter(|d| queue.iter().any(|j| j.device.id == d.id))
+					.count()
+					.if_supports_color(Stderr, |t| t.bright_cyan())
 			);
+			let pins = cmdline
+				.pin_bootstrap_hashes
+				.as_deref()
+				.map(load_pins)
+				.transpose()?;
 			info!("Bootstrapping releases...");
 			for variant in variants {
 				let variant_str = variant.to_string().to_lowercase();
@@ -421,6 +782,12 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 						&variant_str,
 						arch.to_string().to_lowercase()
 					));
+					// The images using it are skipped.
+					if failed_archs.contains(&arch)
+						&& !bootstrap_path.join("etc/os-release").exists()
+					{
+						continue;
+					}
 					let dir = device
 						.file_path
 						.parent()
@@ -428,11 +795,13 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 					let sources_list_path = dir.join("sources.list");
 					let sources_list: Option<PathBuf> =
 						sources_list_path.exists().then_some(sources_list_path);
-					let recipe_list_path = dir.join(&format!("{}.lst", variant_str));
+					let recipe_list_path = dir.join(format!("{}.lst", variant_str));
 					let recipe_list: Option<PathBuf> =
 						recipe_list_path.exists().then_some(recipe_list_path);
+					let _bootstrap_lock = WorkdirLock::bootstrap(&bootstrap_path)?;
 					if !bootstrap_path.is_dir() || !(bootstrap_path.join("etc/os-release")).exists()
 					{
+						let start = Instant::now();
 						bootstrap_distribution(
 							variant,
 							bootstrap_path,
@@ -440,7 +809,21 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 							Some(&cmdline.mirror),
 							sources_list,
 							recipe_list,
+							pins.as_ref(),
 						)?;
+						timings.bootstraps.push(StageTiming {
+							name: format!("{}-{}", &variant_str, arch.to_string().to_lowercase()),
+							secs: start.elapsed().as_secs_f64(),
+						});
+					} else if let Some(pins) = &pins {
+						let recorded = load_hashes(&bootstrap_path)?.context(format!(
+							"{} has no recorded aoscbootstrap hashes to check against the pins, please remove it to bootstrap again",
+							bootstrap_path.display()
+						))?;
+						check_pins(&recorded, pins).context(format!(
+							"The bootstrapped distribution {} does not match the pins",
+							bootstrap_path.display()
+						))?;
 					}
 				}
 			}
@@ -450,21 +833,87 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 			std::thread::sleep(time::Duration::from_secs(2));
 			info!("Executing the queue ...");
 			let start = Instant::now();
-			for j in queue {
+			let mut jobs = queue.into_iter();
+			while let Some(j) = jobs.next() {
 				info!("{} images pending.", len - count);
 				count += 1;
-				j.execute(count, len)?;
+				let name = j.filename.clone();
+				let image_start = Instant::now();
+				match j.execute(count, len) {
+					Ok((image_timings, output)) => {
+						summary.images.push(RunEntry {
+							output,
+							secs: image_timings.total().as_secs_f64(),
+							..j.run_entry(RunStatus::Ok)
+						});
+						timings.images.push(ImageTimings {
+							name,
+							timings: image_timings,
+						});
+					}
+					Err(e) if cmdline.keep_going => {
+						restore_term();
+						log_error(&e);
+						warn!("Failed to build {}, continuing with the next image.", &name);
+						if let Err(e) = j.release_leftovers() {
+							warn!(
+								"Unable to clean up after the failed build: {:#}\nYou have to unmount and detach them manually.",
+								e
+							);
+						}
+						summary.images.push(failed_entry(&j, &e, image_start));
+						timings.failures.push(ImageFailure {
+							name,
+							device: j.device.id.clone(),
+							variant: j.variant.to_string().to_lowercase(),
+							error: format!("{:#}", e),
+							secs: image_start.elapsed().as_secs_f64(),
+						});
+					}
+					Err(e) => {
+						restore_term();
+						summary.images.push(failed_entry(&j, &e, image_start));
+						for j in jobs {
+							summary.images.push(RunEntry {
+								reason: Some("Not built after an earlier failure".to_owned()),
+								..j.run_entry(RunStatus::Skipped)
+							});
+						}
+						report_run(&summary, &cmdline.outdir);
+						return Err(e);
+					}
+				}
 			}
 			let duration = start.elapsed();
 			info!(
 				"Done! {} image(s) in {:.03} seconds.",
-				len,
+				timings.images.len(),
 				duration.as_secs_f32()
 			);
-			if cmdline.cleanup {
+			if !timings.skipped.is_empty() {
+				warn!(
+					"{} image(s) skipped, see the table below.",
+					timings.skipped.len()
+				);
+			}
+			timings.secs = run_start.elapsed().as_secs_f64();
+			info!("{}", timings.summary());
+			if let Some(path) = &cmdline.timings_json {
+				timings.save(path)?;
+				info!("Timings written to {}.", path.display());
+			}
+			report_run(&summary, &cmdline.outdir);
+			let skip_cleanup = cmdline.cleanup_on_success_only && !timings.failures.is_empty();
+			if skip_cleanup && (cmdline.cleanup || cmdline.cleanup_bootstrap) {
+				warn!(
+					"Not cleaning up the working directory, as {} image(s) failed to build.",
+					timings.failures.len()
+				);
+			}
+			if cmdline.cleanup && !skip_cleanup {
 				info!("Cleaning up the sketch directories ...");
 				let sketch_dir = cmdline.workdir.join("sketches");
-				match remove_dir_all(&sketch_dir) {
+				match remove_sketches(&cmdline.workdir, cmdline.force_detach) {
 					Ok(_) => (),
 					Err(e) => {
 						warn!(
@@ -475,7 +924,7 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 					}
 				}
 			}
-			if cmdline.cleanup_bootstrap {
+			if cmdline.cleanup_bootstrap && !skip_cleanup {
 				info!("Cleaning up the bootstrapped system distributions ...");
 				let bootstrap_dir = cmdline.workdir.join("bootstrap");
 				match remove_dir_all(&bootstrap_dir) {
@@ -489,9 +938,12 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 					}
 				}
 			}
-			if cmdline.cleanup && cmdline.cleanup_bootstrap {
+			if cmdline.cleanup && cmdline.cleanup_bootstrap && !skip_cleanup {
 				info!("Removing the working directory ...");
-				match remove_dir(&cmdline.workdir) {
+				match lock
+					.remove()
+					.and_then(|_| Ok(remove_dir(&cmdline.workdir)?))
+				{
 					Ok(_) => (),
 					Err(e) => {
 						warn!(
@@ -502,40 +954,330 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 					}
 				}
 			}
-			let uid = var("SUDO_UID").ok();
-			let gid = var("SUDO_GID").ok();
-			if uid.is_some() || gid.is_some() {
-				let uid = uid
-					.map(|x| {
-						x.parse::<u32>().with_context(|| {
-							format!("Failed to parse $SUDO_UID '{}' into integer", &x)
-						})
-					})
-					.transpose()?;
-				let gid = gid
-					.map(|x| {
-						x.parse::<u32>().with_context(|| {
-							format!("Failed to parse $SUDO_GID '{}' into integer", &x)
-						})
-					})
-					.transpose()?;
+			if let Some((uid, gid)) = get_sudo_ids()? {
 				info!(
 					"This tool is running with sudo, fixing ownership of the output directory ..."
 				);
 				return_ownership_recursive(&cmdline.outdir, uid, gid)?;
+				if let Some(log_dir) = &cmdline.log_dir {
+					return_ownership_recursive(log_dir, uid, gid)?;
+				}
+				if let Some(path) = &cmdline.timings_json {
+					return_ownership_recursive(path, uid, gid)?;
+				}
 			}
 			info!("Output directory: {}", &cmdline.outdir.display());
-			info!("Program finished successfully. Exiting.");
+			info!("Build run {} finished.", run.id);
+			if !summary.succeeded() {
+				bail!("{}", summary.counts());
+			}
+			info!("{} Exiting.", summary.counts());
+		}
+		Plan::Rebootload {
+			compression,
+			device,
+			image,
+		} => {
+			let device = select_devices(
+				&DeviceSelection::One(device),
+				&find_registry(cmdline.registry.as_deref())?,
+				cmdline.lenient_scan,
+			)?
+			.remove(0);
+			device.check()?;
+			check_binfmt(&device.arch)?;
+			// The variant is only used for logging.
+			let variant = image_variant(&image);
+			let ctx = existing_image_context(&device, &variant, &compression, &cmdline, &run);
+			let _lock = WorkdirLock::shared(&cmdline.workdir, cmdline.wait_for_lock)?;
+			let outfile = ctx.rebootload(&image)?;
+			if let Some((uid, gid)) = get_sudo_ids()? {
+				return_ownership_recursive(&image, uid, gid)?;
+				if let Some(outfile) = &outfile {
+					return_ownership_recursive(outfile, uid, gid)?;
+				}
+				if let Some(log_dir) = &cmdline.log_dir {
+					return_ownership_recursive(log_dir, uid, gid)?;
+				}
+			}
+			info!("Bootloaders applied to {}.", image.display());
+			info!("Build run {} finished.", run.id);
+		}
+		Plan::Patch {
+			device,
+			image,
+			steps,
+		} => {
+			let device = select_devices(
+				&DeviceSelection::One(device),
+				&find_registry(cmdline.registry.as_deref())?,
+				cmdline.lenient_scan,
+			)?
+			.remove(0);
+			device.check()?;
+			check_binfmt(&device.arch)?;
+			// Post installation scripts are picked by the variant.
+			let variant = image_variant(&image);
+			for step in &steps {
+				step.check(&device, &variant)?;
+			}
+			let image = image
+				.canonicalize()
+				.context(format!("Unable to find the image '{}'", image.display()))?;
+			let name = image
+				.file_name()
+				.and_then(|x| x.to_str())
+				.context("Invalid image name")?;
+			let (name, revision) = run.filename_template.next_revision(name)?;
+			let outfile = image.with_file_name(name);
+			if outfile.exists() {
+				bail!(
+					"{} already exists, refusing to overwrite it",
+					outfile.display()
+				);
+			}
+			// Compressed with the same format, as the extension is kept.
+			let compression = compress::detect_compression(&image)?;
+			let ctx = existing_image_context(&device, &variant, &compression, &cmdline, &run);
+			let _lock = WorkdirLock::shared(&cmdline.workdir, cmdline.wait_for_lock)?;
+			ctx.patch(&image, &steps, &outfile, revision)?;
+			if let Some((uid, gid)) = get_sudo_ids()? {
+				return_ownership_recursive(&outfile, uid, gid)?;
+				if let Some(log_dir) = &cmdline.log_dir {
+					return_ownership_recursive(log_dir, uid, gid)?;
+				}
+			}
+			info!("Patched image saved to {}.", outfile.display());
+			info!("Build run {} finished.", run.id);
+		}
+		Plan::ExportScripts {
+			device,
+			variant,
+			outdir,
+		} => {
+			let device = select_devices(
+				&DeviceSelection::One(device),
+				&find_registry(cmdline.registry.as_deref())?,
+				cmdline.lenient_scan,
+			)?
+			.remove(0);
+			device.check()?;
+			let run = BuildRun::new(None)?;
+			let ctx = ImageContext {
+				device: &device,
+				variant: &variant,
+				workdir: &cmdline.workdir,
+				outdir: &outdir,
+				user: &cmdline.user,
+				password: &cmdline.password,
+				user_options: &cmdline.user_options,
+				root_policy: &cmdline.root_policy,
+				filename: String::new(),
+				base_dist: PathBuf::new(),
+				override_rootfs_fstype: &None,
+				additional_packages: &None,
+				oobe_package: &None,
+				compress: &Compression::None,
+				format: &OutputFormat::Rawimg,
+				topics: None,
+				seed: None,
+				run: &run,
+				force_detach: false,
+				min_free_inodes: None,
+				usage_warn_threshold: cmdline.usage_warn_threshold,
+				usage_fail_threshold: None,
+				loop_attempts: cmdline.loop_attempts,
+				log_dir: None,
+				debug_shell: false,
+				no_scrub: false,
+				skip_size_check: false,
+				shrink: false,
+				no_bmap: false,
+				keep_raw: false,
+				resume: false,
+				output_layout: OutputLayout::Hierarchy,
+				mirror: &cmdline.mirror,
+			};
+			let dir = ctx.export_scripts(&outdir)?;
+			info!("Scripts exported to {}.", dir.display());
+		}
+		Plan::Compress {
+			input,
+			output,
+			compression,
+			level,
+			benchmark,
+		} => {
+			compress_action(&input, output.as_deref(), &compression, level, benchmark)?;
+		}
+		Plan::PinBootstrap { output, extra } => {
+			let pins = format_pins(&system_pins(Path::new(AB_DIR), &extra)?);
+			if output == Path::new("-") {
+				print!("{}", pins);
+			} else {
+				fs::write(&output, pins)
+					.context(format!("Unable to write {}", output.display()))?;
+				info!("Pinned the aoscbootstrap files in {}.", output.display());
+			}
+		}
+		Plan::Gc { dry_run } => {
+			if policy.is_empty() {
+				warn!("No retention policy is set, nothing will be removed.");
+			}
+			let _lock = WorkdirLock::exclusive(&cmdline.workdir, cmdline.wait_for_lock)?;
+			collect_garbage(&cmdline.workdir, &policy, dry_run, cmdline.force_detach)?;
+		}
+		Plan::Show {
+			device,
+			override_spec,
+		} => {
+			let mut device = select_devices(
+				&DeviceSelection::One(device),
+				&find_registry(cmdline.registry.as_deref())?,
+				cmdline.lenient_scan,
+			)?
+			.remove(0);
+			if let Some(override_spec) = &override_spec {
+				device = device.apply_override(override_spec)?;
+			}
+			info!(
+				"Merged device spec of '{}' from {}:",
+				&device.id,
+				device.file_path.display()
+			);
+			print!("{}", device.show()?);
 		}
-		cli::Action::Check { .. } => {
+		Plan::NewDevice {
+			vendor,
+			id,
+			arch,
+			partition_map,
+		} => {
+			let path = scaffold::new_device(
+				&find_registry(cmdline.registry.as_deref())?,
+				&vendor,
+				&id,
+				&arch,
+				&partition_map,
+			)?;
+			info!(
+				"Created {}, edit the fields marked with TODO to finish it.",
+				path.display()
+			);
+		}
+		Plan::Check {
+			devices: selection,
+			strict,
+		} => {
 			info!("Checking validity of the registry ...");
-			registry.check_validity()?;
-			return Ok(());
+			DeviceRegistry::check_devices(
+				select_devices(
+					&selection,
+					&find_registry(cmdline.registry.as_deref())?,
+					cmdline.lenient_scan,
+				)?,
+				strict,
+			)?;
 		}
-		cli::Action::List { format } => {
-			registry.list_devices(format)?;
-			return Ok(());
+		Plan::List {
+			format,
+			tags,
+			compatible,
+			show_sizes,
+		} => {
+			let registry =
+				DeviceRegistry::scan_lenient(&find_registry(cmdline.registry.as_deref())?)?;
+			let registry = if tags.is_empty() {
+				registry
+			} else {
+				registry.retain_tags(&tags)?
+			};
+			let registry = if let Some(compatible) = &compatible {
+				registry.retain_compatible(compatible)?
+			} else {
+				registry
+			};
+			let report = if show_sizes {
+				Some(BuildReport::load(&cmdline.outdir)?)
+			} else {
+				None
+			};
+			registry.list_devices(format, report.as_ref())?;
+		}
+		Plan::Estimate {
+			devices: selection,
+			variants,
+			format,
+			compression,
+			json,
+		} => {
+			estimate::estimate_devices(
+				&select_devices(
+					&selection,
+					&find_registry(cmdline.registry.as_deref())?,
+					cmdline.lenient_scan,
+				)?,
+				&variants,
+				&format,
+				&compression,
+				&cmdline.workdir,
+				json,
+			)?;
 		}
 	};
 	Ok(())
 }
+
+#[doc(hidden)]
+fn compress_action(
+	input: &Path,
+	output: Option<&Path>,
+	compression: &Compression,
+	level: Option<u32>,
+	benchmark: bool,
+) -> Result<()> {
+	if benchmark {
+		return compress::benchmark(input);
+	}
+	let output = match output {
+		Some(x) => x.to_owned(),
+		None if input == Path::new("-") => PathBuf::from("-"),
+		None => {
+			let mut x = input.as_os_str().to_owned();
+			x.push(compression.get_extension());
+			PathBuf::from(x)
+		}
+	};
+	if output == input && input != Path::new("-") {
+		bail!("Output path must be different from the input path.");
+	}
+	info!(
+		"Compressing {} to {} using {:?} ...",
+		input.display(),
+		output.display(),
+		compression
+	);
+	let result = compress::compress_file(input, &output, compression, level)?;
+	info!(
+		"Compression finished in {:.2} seconds, {} bytes written.",
+		result.duration.as_secs_f64(),
+		result.size
+	);
+	if output == Path::new("-") {
+		return Ok(());
+	}
+	let output = output.canonicalize()?;
+	let outdir = output.parent().context("Output has no parent directory")?;
+	let filename = path_str(Path::new(
+		output.file_name().context("Output has no file name")?,
+	))?;
+	compress::update_sha256sums(outdir, filename, &result.sha256)?;
+	info!("SHA256: {}", &result.sha256);
+	info!("SHA256 of the input: {}", &result.input_sha256);
+	if let Some((uid, gid)) = get_sudo_ids()? {
+		info!("This tool is running with sudo, fixing ownership of the output ...");
+		return_ownership_recursive(&output, uid, gid)?;
+		return_ownership_recursive(&outdir.join(compress::SHA256SUMS), uid, gid)?;
+	}
+	Ok(())
+}
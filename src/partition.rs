@@ -1,5 +1,10 @@
-use crate::{device::PartitionMapType, filesystem::FilesystemType};
-use anyhow::{Result, anyhow};
+use crate::{
+	content::PartitionContent,
+	device::PartitionMapType,
+	filesystem::{FilesystemType, MountOptions},
+	utils::MBR_MAX_SECTORS,
+};
+use anyhow::{Context, Result, anyhow, bail};
 use serde::{Deserialize, Serialize};
 use uuid::{Uuid, uuid};
 
@@ -14,6 +19,9 @@ pub const PARTTYPE_LINUX_BYTE: u8 = 0x83;
 pub const PARTTYPE_SWAP_BYTE: u8 = 0x82;
 pub const PARTTYPE_BASIC_BYTE: u8 = 0x07;
 
+/// GPT partition names are stored as 36 UTF-16LE code units.
+pub const GPT_NAME_MAX_UNITS: usize = 36;
+
 #[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 #[allow(clippy::upper_case_acronyms)]
@@ -220,7 +228,7 @@ pub enum PartitionType {
 ///
 /// Name of the partition, only available on GPT partition table. **Not to be confused by filesystem labels.**
 ///
-/// The partition label has a 64-characters limit.
+/// The partition label is stored as UTF-16, and is limited to 36 UTF-16 code units. That is 36 ASCII characters, but fewer if it contains characters outside of the Basic Multilingual Plane, e.g. emojis, which take two code units each.
 ///
 /// ```toml
 /// [[partition]]
@@ -238,8 +246,8 @@ pub enum PartitionType {
 /// - `ext4`: Linux Extended filesystem version 4.
 /// - `btrfs`: B-Tree filesystem.
 /// - `xfs`: XFS from Sun Microsystems.
-/// - `fat16`: FAT16 filesystem, can not be used as the root filesystem.
-/// - `fat32`: FAT32 filesystem, can not be used as the root filesystem.
+/// - `fat16`: FAT16 filesystem, can not be used as the root filesystem. The partition can not be larger than 4 GiB.
+/// - `fat32`: FAT32 filesystem, can not be used as the root filesystem. The partition must be at least 33 MiB.
 /// - `none`: Not to be formatted.
 ///
 /// ```toml
@@ -251,7 +259,7 @@ pub enum PartitionType {
 /// `fs_label` - Filesystem label (Optional)
 /// ----------------------------------------
 ///
-/// Label of the filesystem. Its limitation varies by filesystem type.
+/// Label of the filesystem. Its limitation varies by filesystem type: up to 16 bytes for ext4, 12 bytes for XFS, 255 bytes for Btrfs, and 11 ASCII characters for FAT. FAT labels are written in upper case.
 ///
 /// ```toml
 /// [[partition]]
@@ -259,6 +267,21 @@ pub enum PartitionType {
 /// fs_label = "AOSC OS"
 /// ```
 ///
+/// `fs_uuid` - Filesystem UUID (Optional)
+/// --------------------------------------
+///
+/// UUID of the filesystem, passed to mkfs. If not defined, it is chosen by mkfs.
+///
+/// FAT filesystems have a 32-bit volume serial instead, written as 8 hexadecimal digits. Other filesystems need a full UUID. Filesystem UUIDs must be unique across the partitions.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// fs_uuid = "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+/// # Or for FAT
+/// fs_uuid = "ABCD-1234"
+/// ```
+///
 /// `mountpoint` - Mount point of the filesystem
 /// --------------------------------------------
 ///
@@ -277,13 +300,13 @@ pub enum PartitionType {
 ///
 /// Mount options used to mount the filesystem. This will be present in the generated `/etc/fstab`.
 ///
-/// <div class="warning">
-/// The handling of the mount options is not complete, only options specific to the filesystem type are allowed.
+/// The options are validated against a table of options known for the filesystem:
 ///
-/// That is, options like <code>ro</code>, <code>noexec</code> and <code>nosuid</code> are not handled, and will result in an error if specified.
-/// </div>
+/// - Options belonging to a different filesystem (e.g. `compress=zstd` on ext4) and malformed options (e.g. stray commas) are errors.
+/// - Unknown options are allowed with a warning.
+/// - Generic options like `ro`, `noatime`, `nofail` and `x-systemd.*` are written to `/etc/fstab`, but not used while building the image.
 ///
-/// If not defined, `defaults` will be used. If defined, `defaults` will **not** be joined with the options.
+/// If not defined, `defaults` will be used, or `defaults,nofail` for the firmware partition. If defined, `defaults` will **not** be joined with the options.
 ///
 /// ```toml
 /// mount_opts = ["compress=zstd"]
@@ -297,10 +320,33 @@ pub enum PartitionType {
 /// Possible values are:
 ///
 /// - `boot`: Boot partition. Only one boot partition is allowed, and will be marked as active if MBR is used.
+/// - `firmware`: Partition owned by the firmware of the device, e.g. the FAT partition of Raspberry Pi mounted at `/boot/firmware`. Only one firmware partition is allowed. Files of the built-in bootloaders (like `extlinux.conf`) are written into it instead of the boot partition, and it is not checked by fsck and mounted with `nofail` by default.
 /// - `rootfs`: Root filesystem. Only one root partition is allowed.
 /// - `data`: Data partition.
 /// - `Other`: Other uses.
 ///
+/// `format_on_first_boot` - Format the Partition on First Boot (Optional)
+/// ----------------------------------------------------------------------
+///
+/// Only allowed for `data` partitions with a `filesystem` and a `mountpoint`. Defaults to `false`.
+///
+/// If set to `true`, the partition is created but not formatted during the build, thus it takes no space in the (compressed) image, e.g. for a large media partition.
+/// Instead, its `/etc/fstab` entry refers to it by `PARTUUID` with `x-systemd.makefs` added to the mount options (`defaults,nofail` if `mount_opts` is not defined), letting systemd create the filesystem the first time it is mounted.
+/// Since systemd creates the filesystem with the default options, `fs_label` and `fs_uuid` can not be defined.
+///
+/// ```toml
+/// format_on_first_boot = true
+/// ```
+///
+/// `content` - Content of the Partition (Optional)
+/// -----------------------------------------------
+///
+/// Populate the partition from a directory or a tarball within the directory containing device.toml, e.g. for a recovery partition. See [`PartitionContent`] for details.
+///
+/// ```toml
+/// content = { source = "tar", path = "recovery.tar.zst" }
+/// ```
+///
 /// Examples
 /// ========
 ///
@@ -400,19 +446,251 @@ pub struct PartitionSpec {
 	pub filesystem: FilesystemType,
 	pub mount_opts: Option<Vec<String>>,
 	pub fs_label: Option<String>,
+	pub fs_uuid: Option<String>,
 	pub usage: PartitionUsage,
+	#[serde(default)]
+	pub format_on_first_boot: bool,
+	pub content: Option<PartitionContent>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum PartitionUsage {
 	Boot,
+	Firmware,
 	Rootfs,
 	Swap,
 	Data,
 	Other,
 }
 
+impl PartitionSpec {
+	/// Validated `mount_opts` of this partition.
+	///
+	/// Firmware partitions are mounted with `nofail` if none is defined, so a missing one does not stop the boot.
+	/// Partitions formatted on first boot get `x-systemd.makefs`, and `nofail` if none is defined.
+	pub fn mount_options(&self) -> Result<MountOptions> {
+		let default: &[&str] = match self.usage {
+			PartitionUsage::Firmware => &["defaults", "nofail"],
+			_ if self.format_on_first_boot => &["defaults", "nofail"],
+			_ => &[],
+		};
+		let mut opts = match &self.mount_opts {
+			Some(opts) => opts.iter().map(String::as_str).collect(),
+			None => default.to_vec(),
+		};
+		if self.format_on_first_boot
+			&& !opts
+				.iter()
+				.any(|x| x.split(',').any(|o| o == MAKEFS_MOUNT_OPT))
+		{
+			opts.push(MAKEFS_MOUNT_OPT);
+		}
+		self.filesystem.parse_mount_opts(&opts)
+	}
+
+	/// Whether this partition is formatted during the build.
+	pub fn formatted_at_build(&self) -> bool {
+		self.filesystem != FilesystemType::None && !self.format_on_first_boot
+	}
+}
+
+/// Mount option letting systemd create the filesystem if the partition has none.
+const MAKEFS_MOUNT_OPT: &str = "x-systemd.makefs";
+
+/// Check if `label` fits in the name field of a GPT partition entry.
+pub fn check_gpt_label(label: &str) -> Result<()> {
+	let units = label.encode_utf16().count();
+	if units > GPT_NAME_MAX_UNITS {
+		bail!(
+			"Partition label '{}' takes {} UTF-16 code units, exceeding the limit of {}",
+			label,
+			units,
+			GPT_NAME_MAX_UNITS
+		);
+	}
+	Ok(())
+}
+
+/// Sectors taken by the partition entry array of a GPT, 128 entries of 128 bytes.
+fn gpt_entry_array_sectors(sector_size: u64) -> u64 {
+	(128 * 128 - 1) / sector_size + 1
+}
+
+/// Placement of a partition on the disk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlacedPartition {
+	pub num: u32,
+	/// Starting sector.
+	pub start: u64,
+	/// Size in sectors.
+	pub sectors: u64,
+}
+
+impl PlacedPartition {
+	/// The last sector of this partition.
+	pub fn end(&self) -> u64 {
+		self.start + self.sectors - 1
+	}
+}
+
+/// A virtual disk to place the partitions on, without touching any image.
+///
+/// The free space is tracked exactly like the partition table crates do, so the result is what the partitioner writes.
+struct VirtualDisk {
+	/// First and last sectors usable for partitions.
+	first_usable: u64,
+	last_usable: u64,
+	/// Partitions are aligned to this many sectors.
+	align: u64,
+	placed: Vec<PlacedPartition>,
+}
+
+impl VirtualDisk {
+	/// Free spots of `(starting sector, size in sectors)`, just like `find_free_sectors()` of the partition table crates.
+	fn free_sectors(&self) -> Vec<(u64, u64)> {
+		let mut positions = vec![self.first_usable - 1];
+		for p in &self.placed {
+			positions.push(p.start);
+			positions.push(p.end());
+		}
+		positions.push(self.last_usable + 1);
+		positions.sort_unstable();
+		positions
+			.chunks(2)
+			.map(|x| (x[0] + 1, x[1] - x[0] - 1))
+			.filter(|(_, l)| *l > 0)
+			.map(|(i, l)| (i, l, ((i - 1) / self.align + 1) * self.align - i))
+			.map(|(i, l, s)| (i + s, l.saturating_sub(s)))
+			.filter(|(_, l)| *l > 0)
+			.collect()
+	}
+
+	fn find_first_place(&self, size: u64) -> Option<u64> {
+		self.free_sectors()
+			.iter()
+			.find(|(_, l)| *l >= size)
+			.map(|(i, _)| *i)
+	}
+}
+
+/// Place `partitions` on a disk of `disk_sectors` sectors, just like the partitioner does.
+///
+/// Partitions are aligned to 1MiB. Unless defined, the first partition starts at 1MiB, and the others take the first free space that fits them.
+/// The partition with a size of 0 takes the rest of the disk, it must be the last one.
+pub fn place_partitions(
+	partitions: &[PartitionSpec],
+	partition_map: &PartitionMapType,
+	disk_sectors: u64,
+	sector_size: u64,
+) -> Result<Vec<PlacedPartition>> {
+	let grain = 1048576 / sector_size;
+	let (first_usable, last_usable) = match partition_map {
+		PartitionMapType::GPT => {
+			// Protective MBR, primary and backup headers and entry arrays.
+			let array = gpt_entry_array_sectors(sector_size);
+			if disk_sectors < 2 * array + 4 {
+				bail!("The disk is too small for a GPT partition table");
+			}
+			(2 + array, disk_sectors - array - 2)
+		}
+		PartitionMapType::MBR => {
+			if disk_sectors > MBR_MAX_SECTORS {
+				bail!(
+					"MBR partition tables can address up to {} sectors, but the disk has {} sectors of {} bytes. Please use GPT instead.",
+					MBR_MAX_SECTORS,
+					disk_sectors,
+					sector_size
+				);
+			}
+			if disk_sectors < 2 {
+				bail!("The disk is too small for a MBR partition table");
+			}
+			(1, disk_sectors - 1)
+		}
+	};
+	let mut disk = VirtualDisk {
+		first_usable,
+		last_usable,
+		align: grain,
+		placed: Vec::new(),
+	};
+	let num_partitions = partitions.len() as u32;
+	for partition in partitions {
+		if partition.num == 0 {
+			bail!("Partition number must start from 1.");
+		}
+		if *partition_map == PartitionMapType::MBR && partition.num > 4 {
+			bail!("Extended and logical partitions are not supported.");
+		}
+		let free_blocks = disk.free_sectors();
+		let last_free = free_blocks
+			.last()
+			.context("No more free space available for new partitions")?;
+		let sectors = if partition.size_in_sectors != 0 {
+			partition.size_in_sectors
+		} else {
+			if partition.num != num_partitions {
+				bail!(
+					"Max sized partition {} must stay at the end of the table.",
+					partition.num
+				);
+			}
+			// An explicitly placed one takes the rest of the disk behind its start.
+			let free = match partition.start_sector {
+				Some(start) if start <= last_usable => last_usable - start + 1,
+				Some(_) => 0,
+				None => last_free.1,
+			};
+			if free < grain {
+				bail!(
+					"Not enough free space to create partition {}",
+					partition.num
+				);
+			}
+			free - 1
+		};
+		// The MBR partitioner rejects partitions smaller than the alignment.
+		if *partition_map == PartitionMapType::MBR && sectors < grain {
+			bail!(
+				"Not enough free space to create partition {}",
+				partition.num
+			);
+		}
+		let start = if let Some(start) = partition.start_sector {
+			start
+		} else if partition.num == 1 {
+			// 1MB grain size to reserve some space for bootloaders
+			grain
+		} else {
+			disk.find_first_place(sectors).context(format!(
+				"No suitable space found for partition {} of {} sectors",
+				partition.num, sectors
+			))?
+		};
+		let end = start
+			.checked_add(sectors - 1)
+			.filter(|&end| start >= first_usable && end <= last_usable)
+			.context(format!(
+				"Partition {} does not fit in the disk, whose usable sectors are {} to {}",
+				partition.num, first_usable, last_usable
+			))?;
+		if let Some(p) = disk
+			.placed
+			.iter()
+			.find(|p| start <= p.end() && p.start <= end)
+		{
+			bail!("Partitions {} and {} overlap", p.num, partition.num);
+		}
+		disk.placed.push(PlacedPartition {
+			num: partition.num,
+			start,
+			sectors,
+		});
+	}
+	Ok(disk.placed)
+}
+
 impl PartitionType {
 	pub fn to_byte(&self) -> Result<u8> {
 		match self {
@@ -460,7 +738,6 @@ mod tests {
 	const TEST_EXTENDED: &str = "type = \"byte\"\nbyte = 0x05";
 
 	use super::*;
-	use toml;
 	macro_rules! get {
 		($x:ident) => {
 			toml::from_str::<PartitionType>($x)
@@ -490,4 +767,153 @@ mod tests {
 		);
 		Ok(())
 	}
+	#[test]
+	fn test_check_gpt_label() -> Result<()> {
+		check_gpt_label("")?;
+		check_gpt_label(&"A".repeat(36))?;
+		assert!(check_gpt_label(&"A".repeat(37)).is_err());
+		// CJK characters are in the BMP, taking one code unit each.
+		check_gpt_label(&"分".repeat(36))?;
+		assert!(check_gpt_label(&"分".repeat(37)).is_err());
+		// Emojis are outside of the BMP, taking a surrogate pair each.
+		check_gpt_label(&"🐱".repeat(18))?;
+		assert!(check_gpt_label(&"🐱".repeat(19)).is_err());
+		assert!(check_gpt_label(&format!("{}🐱", "A".repeat(35))).is_err());
+		Ok(())
+	}
+
+	/// Partitions of `(start_sector, size_in_sectors)`, numbered from 1.
+	fn partitions(layout: &[(Option<u64>, u64)]) -> Vec<PartitionSpec> {
+		layout
+			.iter()
+			.enumerate()
+			.map(|(i, (start, size))| {
+				let start = start.map(|x| format!("start_sector = {}\n", x));
+				toml::from_str(&format!(
+					"num = {}\ntype = \"linux\"\nusage = \"data\"\n{}size_in_sectors = {}\n",
+					i + 1,
+					start.unwrap_or_default(),
+					size
+				))
+				.unwrap()
+			})
+			.collect()
+	}
+
+	#[test]
+	fn test_place_partitions() -> Result<()> {
+		let place = |map: PartitionMapType, layout: &[(Option<u64>, u64)]| {
+			place_partitions(&partitions(layout), &map, 1 << 21, 512).map(|x| {
+				x.into_iter()
+					.map(|p| (p.start, p.sectors))
+					.collect::<Vec<_>>()
+			})
+		};
+		// 1 GiB disk, the last GPT usable sector is 2097118.
+		assert_eq!(
+			place(
+				PartitionMapType::GPT,
+				&[(None, 1000), (None, 4096), (None, 0)]
+			)?,
+			vec![(2048, 1000), (4096, 4096), (8192, 2088926)]
+		);
+		assert_eq!(
+			place(PartitionMapType::MBR, &[(None, 4096), (None, 0)])?,
+			vec![(2048, 4096), (6144, 2091007)]
+		);
+		// Partitions fill the gaps before the explicitly placed ones.
+		assert_eq!(
+			place(
+				PartitionMapType::GPT,
+				&[(Some(1 << 20), 2048), (None, 2048), (None, 0)]
+			)?,
+			vec![(1 << 20, 2048), (2048, 2048), (1050624, 1046494)]
+		);
+		let err = |map, layout: &[(Option<u64>, u64)]| -> String {
+			format!("{:#}", place(map, layout).unwrap_err())
+		};
+		assert!(
+			err(PartitionMapType::GPT, &[(None, 0), (None, 4096)]).contains("must stay at the end")
+		);
+		assert!(
+			err(PartitionMapType::GPT, &[(None, 4096), (None, 1 << 21)])
+				.contains("No suitable space")
+		);
+		assert!(
+			err(
+				PartitionMapType::GPT,
+				&[(None, 4096), (Some(1 << 21), 2048)]
+			)
+			.contains("does not fit")
+		);
+		assert!(
+			err(PartitionMapType::MBR, &[(None, 4096), (Some(4096), 4096)]).contains("overlap")
+		);
+		// The MBR partitioner rejects partitions smaller than 1MiB.
+		assert!(err(PartitionMapType::MBR, &[(None, 1000)]).contains("Not enough free space"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_place_partitions_like_crates() -> Result<()> {
+		for sector_size in [512, 4096] {
+			let disk_sectors = (1 << 30) / sector_size;
+			let layout = partitions(&[
+				(None, 3000),
+				(Some(disk_sectors / 2), 2048),
+				(None, 4096),
+				(None, 0),
+			]);
+			let mut cur = std::io::Cursor::new(vec![0; 1 << 30]);
+			let mut gpt = gptman::GPT::new_from(&mut cur, sector_size, [0xff; 16])?;
+			gpt.align = 1048576 / sector_size;
+			let mut mbr = mbrman::MBR::new_from(&mut cur, sector_size as u32, [0xff; 4])?;
+			// Compare the free spots after placing each partition.
+			let mut disk = VirtualDisk {
+				first_usable: gpt.header.first_usable_lba,
+				last_usable: gpt.header.last_usable_lba,
+				align: gpt.align,
+				placed: Vec::new(),
+			};
+			for p in place_partitions(&layout, &PartitionMapType::GPT, disk_sectors, sector_size)? {
+				assert_eq!(disk.free_sectors(), gpt.find_free_sectors());
+				gpt[p.num] = gptman::GPTPartitionEntry {
+					partition_type_guid: [0xff; 16],
+					unique_partition_guid: [0xff; 16],
+					starting_lba: p.start,
+					ending_lba: p.end(),
+					attribute_bits: 0,
+					partition_name: "".into(),
+				};
+				disk.placed.push(p);
+			}
+			assert_eq!(disk.free_sectors(), gpt.find_free_sectors());
+			let mut disk = VirtualDisk {
+				first_usable: 1,
+				last_usable: mbr.disk_size as u64 - 1,
+				align: mbr.align as u64,
+				placed: Vec::new(),
+			};
+			let free = |mbr: &mbrman::MBR| -> Vec<(u64, u64)> {
+				mbr.find_free_sectors()
+					.into_iter()
+					.map(|(i, l)| (i as u64, l as u64))
+					.collect()
+			};
+			for p in place_partitions(&layout, &PartitionMapType::MBR, disk_sectors, sector_size)? {
+				assert_eq!(disk.free_sectors(), free(&mbr));
+				mbr[p.num as usize] = mbrman::MBRPartitionEntry {
+					boot: mbrman::BOOT_INACTIVE,
+					first_chs: mbrman::CHS::empty(),
+					sys: 0x83,
+					last_chs: mbrman::CHS::empty(),
+					starting_lba: p.start as u32,
+					sectors: p.sectors as u32,
+				};
+				disk.placed.push(p);
+			}
+			assert_eq!(disk.free_sectors(), free(&mbr));
+		}
+		Ok(())
+	}
 }
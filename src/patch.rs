@@ -0,0 +1,287 @@
+//! Module handling the in-place patching of existing images.
+//!
+//! The `patch` action runs a few steps of the build again on an image which is already built, instead of rebuilding it:
+//!
+//! - `bootloader:N`: Apply the N-th bootloader of the device spec, counting from 1.
+//! - `file:N:PATH`: Copy `PATH` of the content of partition N into it again, see [`crate::content`].
+//! - `postinst:NAME`: Run the post installation script `NAME` of the device again.
+//!
+//! The image is decompressed into the working directory, patched, and compressed with the same format under the next revision of its name, next to the original image, which is left untouched.
+//! Each patch is recorded in [`PATCHES_PATH`] of the target system.
+use std::{
+	fmt::Display,
+	fs,
+	path::{Path, PathBuf},
+	str::FromStr,
+};
+
+use anyhow::{Context, Error, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	content::ContentSource, context::ImageVariant, device::DeviceSpec, utils::is_contained_path,
+};
+
+/// Path to the recorded patches, relative to the root of the target system.
+pub const PATCHES_PATH: &str = "etc/mkrawimg/patches.json";
+
+/// A step to run on an existing image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchStep {
+	/// Apply the bootloader at the index, counting from 1.
+	Bootloader(usize),
+	/// Copy a file of the content of a partition.
+	File { partition: u32, path: PathBuf },
+	/// Run a post installation script.
+	Postinst(String),
+}
+
+impl FromStr for PatchStep {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let syntax = "expected bootloader:N, file:N:PATH or postinst:NAME";
+		let Some((kind, arg)) = s.split_once(':') else {
+			bail!("Invalid step '{}', {}", s, syntax);
+		};
+		match kind {
+			"bootloader" => {
+				let index = arg
+					.parse::<usize>()
+					.ok()
+					.filter(|x| *x > 0)
+					.context(format!(
+						"Invalid bootloader index '{}' in step '{}'",
+						arg, s
+					))?;
+				Ok(PatchStep::Bootloader(index))
+			}
+			"file" => {
+				let (num, path) = arg
+					.split_once(':')
+					.context(format!("Invalid step '{}', expected file:N:PATH", s))?;
+				let partition = num.parse::<u32>().context(format!(
+					"Invalid partition number '{}' in step '{}'",
+					num, s
+				))?;
+				let path = PathBuf::from(path);
+				if !is_contained_path(&path) {
+					bail!(
+						"Invalid path '{}' in step '{}', it must be relative to the content of the partition",
+						path.display(),
+						s
+					);
+				}
+				Ok(PatchStep::File { partition, path })
+			}
+			"postinst" => {
+				if arg.is_empty() || arg.contains('/') {
+					bail!("Invalid script name '{}' in step '{}'", arg, s);
+				}
+				Ok(PatchStep::Postinst(arg.to_owned()))
+			}
+			_ => bail!("Unknown step '{}', {}", s, syntax),
+		}
+	}
+}
+
+impl Display for PatchStep {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			PatchStep::Bootloader(index) => write!(f, "bootloader:{}", index),
+			PatchStep::File { partition, path } => {
+				write!(f, "file:{}:{}", partition, path.display())
+			}
+			PatchStep::Postinst(name) => write!(f, "postinst:{}", name),
+		}
+	}
+}
+
+impl PatchStep {
+	/// Make sure the step can be run on the `variant` images of `device`.
+	pub fn check(&self, device: &DeviceSpec, variant: &ImageVariant) -> Result<()> {
+		match self {
+			PatchStep::Bootloader(index) => {
+				let count = device.bootloaders.as_ref().map_or(0, |x| x.len());
+				if *index > count {
+					bail!(
+						"Device '{}' has {} bootloader(s), there is no bootloader {}",
+						device.id,
+						count,
+						index
+					);
+				}
+			}
+			PatchStep::File { partition, path } => {
+				let spec = device
+					.partitions
+					.iter()
+					.find(|p| p.num == *partition)
+					.context(format!(
+						"Device '{}' has no partition {}",
+						device.id, partition
+					))?;
+				let Some(content) = &spec.content else {
+					bail!(
+						"Partition {} of device '{}' has no content",
+						partition,
+						device.id
+					);
+				};
+				// Members of tarballs are checked by tar.
+				if content.source == ContentSource::Dir {
+					let dirname = device
+						.file_path
+						.parent()
+						.context("Unable to find the directory containing the device spec")?;
+					if !dirname.join(&content.path).join(path).exists() {
+						bail!(
+							"'{}' is not found in the content of partition {}",
+							path.display(),
+							partition
+						);
+					}
+				}
+			}
+			PatchStep::Postinst(name) => {
+				let scripts = device.find_postinst_scripts(variant)?;
+				if !scripts
+					.iter()
+					.any(|x| x.file_name().is_some_and(|x| x == name.as_str()))
+				{
+					bail!(
+						"'{}' is not a post installation script of the {} images of device '{}'",
+						name,
+						variant,
+						device.id
+					);
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// A patch applied to an image.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PatchRecord {
+	/// When the image was patched, in RFC 3339.
+	pub time: String,
+	/// Version of mkrawimg which patched the image.
+	pub tool_version: String,
+	/// Name of the image which was patched.
+	pub source: String,
+	/// Revision of the patched image.
+	pub revision: u32,
+	/// The steps, in the syntax of the command line.
+	pub steps: Vec<String>,
+}
+
+/// Load the patches recorded in the target system at `rootdir`, oldest first.
+pub fn load_patches(rootdir: &Path) -> Result<Vec<PatchRecord>> {
+	let path = rootdir.join(PATCHES_PATH);
+	let content = match fs::read_to_string(&path) {
+		Ok(x) => x,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(e) => return Err(e).context(format!("Unable to read {}", path.display())),
+	};
+	serde_json::from_str(&content).context(format!("Unable to parse {}", path.display()))
+}
+
+/// Append `record` to the patches recorded in the target system at `rootdir`.
+pub fn record_patch(rootdir: &Path, record: PatchRecord) -> Result<()> {
+	let mut patches = load_patches(rootdir)?;
+	patches.push(record);
+	let path = rootdir.join(PATCHES_PATH);
+	fs::create_dir_all(path.parent().unwrap())?;
+	fs::write(&path, serde_json::to_string_pretty(&patches)? + "\n")
+		.context(format!("Unable to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::{TestDir, fixture_device};
+
+	#[test]
+	fn test_parse_steps() -> Result<()> {
+		for (s, step) in [
+			("bootloader:2", PatchStep::Bootloader(2)),
+			(
+				"file:1:boot/config.txt",
+				PatchStep::File {
+					partition: 1,
+					path: "boot/config.txt".into(),
+				},
+			),
+			(
+				"postinst:postinst.sh",
+				PatchStep::Postinst("postinst.sh".into()),
+			),
+		] {
+			assert_eq!(s.parse::<PatchStep>()?, step);
+			assert_eq!(step.to_string(), s);
+		}
+		for s in [
+			"bootloader",
+			"bootloader:0",
+			"bootloader:x",
+			"file:1",
+			"file:x:a",
+			"file:1:",
+			"file:1:/etc/passwd",
+			"file:1:../a",
+			"postinst:",
+			"postinst:../postinst.sh",
+			"hook:1",
+		] {
+			assert!(s.parse::<PatchStep>().is_err(), "{} is accepted", s);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_steps() -> Result<()> {
+		let device = fixture_device("fixture-gpt-efi")?;
+		let check = |s: &str| s.parse::<PatchStep>()?.check(&device, &ImageVariant::Base);
+		for s in [
+			"bootloader:2",
+			"file:1:EFI/tools/README.txt",
+			"postinst:postinst.sh",
+		] {
+			check(s)?;
+		}
+		for s in [
+			"bootloader:3",
+			"file:1:EFI/missing.txt",
+			"file:2:README.txt",
+			"file:9:README.txt",
+			"postinst:postinst-server.sh",
+		] {
+			assert!(check(s).is_err(), "{} is accepted", s);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_record_patch() -> Result<()> {
+		let dir = TestDir::new("patch")?;
+		assert_eq!(load_patches(&dir)?, vec![]);
+		let record = PatchRecord {
+			time: "2024-11-08T00:00:00Z".into(),
+			tool_version: "0.1.0".into(),
+			source: "rpi-5b.img.xz".into(),
+			revision: 1,
+			steps: vec!["bootloader:1".into()],
+		};
+		record_patch(&dir, record.clone())?;
+		let second = PatchRecord {
+			source: "rpi-5b.1.img.xz".into(),
+			revision: 2,
+			..record.clone()
+		};
+		record_patch(&dir, second.clone())?;
+		assert_eq!(load_patches(&dir)?, vec![record, second]);
+		Ok(())
+	}
+}
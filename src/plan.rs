@@ -0,0 +1,860 @@
+//! Module planning what to do from the command line.
+//!
+//! The actions are turned into a [`Plan`] in one place, so the later steps never have to guess which action is being run.
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use log::info;
+use uuid::Uuid;
+
+use crate::{
+	cli::{Action, Compression, ListFormat, OutputFormat, RootFsType},
+	context::{ImageContext, ImageVariant},
+	device::{DeviceArch, DeviceSpec, PartitionMapType},
+	filename::{FilenameFields, FilenameTemplate},
+	filesystem::FilesystemType,
+	patch::PatchStep,
+	registry::DeviceRegistry,
+	utils::source_date_epoch,
+};
+
+/// A run of this tool, shared by all images built in it.
+///
+/// All artifacts of a run agree on the date and the revision, even if the run crosses midnight.
+#[derive(Clone, Debug)]
+pub struct BuildRun {
+	/// Unique ID of the run.
+	pub id: Uuid,
+	/// When the run started, or `SOURCE_DATE_EPOCH` if it is set.
+	pub timestamp: DateTime<Utc>,
+	/// The date in the filenames, e.g. `20241108`, always in UTC.
+	pub date: String,
+	/// Revision of the images built in this run.
+	pub revision: Option<u32>,
+	/// Template of the filenames of the images.
+	pub filename_template: FilenameTemplate,
+}
+
+impl BuildRun {
+	pub fn new(revision: Option<u32>) -> Result<Self> {
+		let timestamp = match source_date_epoch()? {
+			Some(epoch) => DateTime::from_timestamp(epoch as i64, 0)
+				.context("SOURCE_DATE_EPOCH is out of range")?,
+			None => Utc::now(),
+		};
+		Ok(Self {
+			id: Uuid::new_v4(),
+			timestamp,
+			date: timestamp.format("%Y%m%d").to_string(),
+			revision,
+			filename_template: FilenameTemplate::default(),
+		})
+	}
+
+	/// Suffix of the sketch directories of this run, e.g. `-1a2b3c4d`, so every run has its own ones.
+	pub fn sketch_suffix(&self) -> String {
+		format!("-{}", &self.id.simple().to_string()[..8])
+	}
+
+	/// Filename of the image of `device` and `variant` built in this run, from the filename template.
+	///
+	/// e.g. `aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108{.1}_arm64.img.xz` with the default template.
+	pub fn image_filename(
+		&self,
+		device: &DeviceSpec,
+		variant: &ImageVariant,
+		format: &OutputFormat,
+		compression: &Compression,
+	) -> String {
+		let (kind, extension) = match format {
+			OutputFormat::Rawimg => ("rawimg", "img"),
+			OutputFormat::Tarball => ("rootfs", "tar"),
+			OutputFormat::Simg => ("simg", "simg"),
+		};
+		let variant = variant.to_string().to_lowercase();
+		let arch = device.arch.to_string().to_ascii_lowercase();
+		self.filename_template.render(&FilenameFields {
+			variant: &variant,
+			format: kind,
+			vendor: &device.vendor,
+			id: &device.id,
+			alias0: device
+				.aliases
+				.as_ref()
+				.and_then(|a| a.first())
+				.map(String::as_str),
+			date: &self.date,
+			revision: self.revision,
+			arch: &arch,
+			ext: extension,
+			compress_ext: compression.get_extension(),
+		})
+	}
+}
+
+/// Devices selected by the command line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSelection {
+	/// A device ID, alias or compatible string, or path to the device spec.
+	One(String),
+	/// A device in the registry having the compatible string.
+	Compatible(String),
+	/// All devices in the registry.
+	All,
+	/// Devices in the registry having any of the tags.
+	Tags(Vec<String>),
+}
+
+impl DeviceSelection {
+	/// All devices, or those having any of `tags` if there's some.
+	fn from_tags(tags: Vec<String>) -> Self {
+		if tags.is_empty() {
+			DeviceSelection::All
+		} else {
+			DeviceSelection::Tags(tags)
+		}
+	}
+}
+
+/// Options for building images.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuildOptions {
+	pub fstype: Option<FilesystemType>,
+	pub compression: Compression,
+	pub variants: Vec<ImageVariant>,
+	pub revision: Option<u32>,
+	pub additional_packages: Option<Vec<String>>,
+	pub topics: Option<Vec<String>>,
+	pub override_spec: Option<PathBuf>,
+	pub defer_triggers: bool,
+	pub skip_size_check: bool,
+	pub shrink: bool,
+	pub no_bmap: bool,
+	pub keep_raw: bool,
+	pub format: OutputFormat,
+	pub reproducible: Option<String>,
+	pub debug_shell: bool,
+	pub no_scrub: bool,
+	pub oobe_package: Option<String>,
+	pub resume: bool,
+}
+
+/// What to do, built from the action of the command line.
+#[derive(Clone, Debug)]
+pub enum Plan {
+	Build {
+		devices: DeviceSelection,
+		options: BuildOptions,
+	},
+	Rebootload {
+		device: String,
+		image: PathBuf,
+		compression: Compression,
+	},
+	Patch {
+		device: String,
+		image: PathBuf,
+		steps: Vec<PatchStep>,
+	},
+	Check {
+		devices: DeviceSelection,
+		strict: bool,
+	},
+	List {
+		format: ListFormat,
+		tags: Vec<String>,
+		compatible: Option<String>,
+		show_sizes: bool,
+	},
+	ExportScripts {
+		device: String,
+		variant: ImageVariant,
+		outdir: PathBuf,
+	},
+	Estimate {
+		devices: DeviceSelection,
+		variants: Vec<ImageVariant>,
+		format: OutputFormat,
+		compression: Compression,
+		json: bool,
+	},
+	Compress {
+		input: PathBuf,
+		output: Option<PathBuf>,
+		compression: Compression,
+		level: Option<u32>,
+		benchmark: bool,
+	},
+	Show {
+		device: String,
+		override_spec: Option<PathBuf>,
+	},
+	NewDevice {
+		vendor: String,
+		id: String,
+		arch: DeviceArch,
+		partition_map: PartitionMapType,
+	},
+	Gc {
+		dry_run: bool,
+	},
+	PinBootstrap {
+		output: PathBuf,
+		extra: Vec<PathBuf>,
+	},
+}
+
+impl From<Action> for Plan {
+	fn from(action: Action) -> Self {
+		let fstype = |fstype: Option<RootFsType>| match fstype {
+			Some(RootFsType::Ext4) => Some(FilesystemType::Ext4),
+			Some(RootFsType::Btrfs) => Some(FilesystemType::Btrfs),
+			Some(RootFsType::Xfs) => Some(FilesystemType::Xfs),
+			None => None,
+		};
+		match action {
+			Action::Build {
+				fstype: f,
+				compression,
+				variants,
+				revision,
+				additional_packages,
+				topics,
+				override_spec,
+				defer_triggers,
+				skip_size_check,
+				shrink,
+				no_bmap,
+				keep_raw,
+				format,
+				reproducible,
+				debug_shell,
+				no_scrub,
+				oobe_package,
+				resume,
+				by_compatible,
+				device,
+			} => Plan::Build {
+				devices: if by_compatible {
+					DeviceSelection::Compatible(device)
+				} else {
+					DeviceSelection::One(device)
+				},
+				options: BuildOptions {
+					fstype: fstype(f),
+					compression,
+					variants,
+					revision,
+					additional_packages,
+					topics,
+					override_spec,
+					defer_triggers,
+					skip_size_check,
+					shrink,
+					no_bmap,
+					keep_raw,
+					format,
+					reproducible,
+					debug_shell,
+					no_scrub,
+					oobe_package,
+					resume,
+				},
+			},
+			Action::BuildAll {
+				fstype: f,
+				compression,
+				variants,
+				revision,
+				additional_packages,
+				topics,
+				defer_triggers,
+				skip_size_check,
+				shrink,
+				no_bmap,
+				keep_raw,
+				format,
+				reproducible,
+				tags,
+			} => Plan::Build {
+				devices: DeviceSelection::from_tags(tags),
+				options: BuildOptions {
+					fstype: fstype(f),
+					compression,
+					variants,
+					revision,
+					additional_packages,
+					topics,
+					override_spec: None,
+					defer_triggers,
+					skip_size_check,
+					shrink,
+					no_bmap,
+					keep_raw,
+					format,
+					reproducible,
+					debug_shell: false,
+					no_scrub: false,
+					oobe_package: None,
+					resume: false,
+				},
+			},
+			Action::Rebootload {
+				compression,
+				device,
+				image,
+			} => Plan::Rebootload {
+				device,
+				image,
+				compression,
+			},
+			Action::Patch {
+				device,
+				image,
+				steps,
+			} => Plan::Patch {
+				device,
+				image,
+				steps,
+			},
+			Action::Check { device, strict } => Plan::Check {
+				devices: device.map_or(DeviceSelection::All, DeviceSelection::One),
+				strict,
+			},
+			Action::List {
+				format,
+				tags,
+				compatible,
+				show_sizes,
+			} => Plan::List {
+				format,
+				tags,
+				compatible,
+				show_sizes,
+			},
+			Action::ExportScripts {
+				variant,
+				outdir,
+				device,
+			} => Plan::ExportScripts {
+				device,
+				variant,
+				outdir,
+			},
+			Action::Estimate {
+				compression,
+				variants,
+				format,
+				json,
+				device,
+			} => Plan::Estimate {
+				devices: device.map_or(DeviceSelection::All, DeviceSelection::One),
+				variants,
+				format,
+				compression,
+				json,
+			},
+			Action::Compress {
+				compression,
+				level,
+				benchmark,
+				input,
+				output,
+			} => Plan::Compress {
+				input,
+				output,
+				compression,
+				level,
+				benchmark,
+			},
+			Action::Show {
+				device,
+				override_spec,
+			} => Plan::Show {
+				device,
+				override_spec,
+			},
+			Action::NewDevice {
+				arch,
+				partition_map,
+				vendor,
+				id,
+			} => Plan::NewDevice {
+				vendor,
+				id,
+				arch,
+				partition_map,
+			},
+			Action::Gc { dry_run } => Plan::Gc { dry_run },
+			Action::PinBootstrap { output, extra } => Plan::PinBootstrap { output, extra },
+		}
+	}
+}
+
+/// Load the selected devices from the registry at `registry_dir`.
+///
+/// A selected device can be a path to the device spec, or a path relative to the registry. Otherwise the full registry is scanned to look it up.
+///
+/// With `lenient`, the broken device specs in the registry are skipped, see [`DeviceRegistry::scan_lenient`].
+pub fn select_devices<P: AsRef<Path>>(
+	selection: &DeviceSelection,
+	registry_dir: P,
+	lenient: bool,
+) -> Result<Vec<DeviceSpec>> {
+	let registry_dir = registry_dir.as_ref();
+	let scan = |dir: &Path| {
+		if lenient {
+			DeviceRegistry::scan_lenient(dir)
+		} else {
+			DeviceRegistry::scan(dir)
+		}
+	};
+	let device_str = match selection {
+		DeviceSelection::All => return scan(registry_dir)?.get_all(),
+		DeviceSelection::Tags(tags) => {
+			return scan(registry_dir)?.retain_tags(tags)?.get_all();
+		}
+		DeviceSelection::Compatible(x) => {
+			info!(
+				"Compatible string '{}' provided. Assembling the full registry ...",
+				x
+			);
+			return Ok(vec![scan(registry_dir)?.get_by_compatible(x)?]);
+		}
+		DeviceSelection::One(x) => x,
+	};
+	let try_path = Path::new(device_str);
+	let devices = if try_path.exists() {
+		DeviceRegistry::from(try_path)?.get_all()?
+	} else if registry_dir.join(try_path).exists() {
+		info!("Relative path detected, assuming it's within the registry directory.");
+		DeviceRegistry::from(registry_dir.join(try_path))?.get_all()?
+	} else {
+		info!(
+			"Device ID or alias '{}' provided. Assembling the full registry ...",
+			device_str
+		);
+		vec![scan(registry_dir)?.get(device_str)?]
+	};
+	if devices.len() != 1 {
+		bail!("Expected exactly one device for '{}'", device_str);
+	}
+	Ok(devices)
+}
+
+/// Make sure no two images in `queue` are written to the same path, e.g. with a flat output directory and a filename template without `{id}`.
+pub fn check_output_paths(queue: &[ImageContext]) -> Result<()> {
+	let mut paths = HashMap::new();
+	for ctx in queue {
+		let path = ctx.output_path();
+		if let Some(other) = paths.insert(path.clone(), ctx) {
+			bail!(
+				"Images of {} ({}) and {} ({}) would both be written to {}, please use a different filename template or output layout",
+				other.device.id,
+				other.variant.to_string().to_lowercase(),
+				ctx.device.id,
+				ctx.variant.to_string().to_lowercase(),
+				path.display()
+			);
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use clap::Parser;
+
+	use crate::{
+		cli::{Cmdline, OutputLayout},
+		fixtures::{FIXTURE_REGISTRY, test_context},
+	};
+
+	fn plan(args: &[&str]) -> Result<Plan> {
+		let cmdline = Cmdline::try_parse_from([&["mkrawimg"], args].concat())?;
+		Ok(Plan::from(cmdline.action))
+	}
+
+	#[test]
+	fn test_plan_build() -> Result<()> {
+		let Plan::Build { devices, options } = plan(&[
+			"build",
+			"-V",
+			"desktop",
+			"-f",
+			"btrfs",
+			"--defer-triggers",
+			"--reproducible",
+			"--debug-shell",
+			"--no-scrub",
+			"--oobe-package",
+			"aosc-os-oobe-cli",
+			"--resume",
+			"fixture-gpt-efi",
+		])?
+		else {
+			panic!("Expected a build plan");
+		};
+		assert_eq!(devices, DeviceSelection::One("fixture-gpt-efi".to_owned()));
+		assert_eq!(options.variants, vec![ImageVariant::Desktop]);
+		assert_eq!(options.fstype, Some(FilesystemType::Btrfs));
+		assert_eq!(options.compression, Compression::Xz);
+		assert!(options.defer_triggers);
+		assert_eq!(options.format, OutputFormat::Rawimg);
+		assert_eq!(options.reproducible.as_deref(), Some("0"));
+		assert!(options.debug_shell);
+		assert!(options.no_scrub);
+		assert_eq!(options.oobe_package.as_deref(), Some("aosc-os-oobe-cli"));
+		assert!(options.resume);
+		let selected = select_devices(&devices, FIXTURE_REGISTRY, false)?;
+		assert_eq!(selected.len(), 1);
+		assert_eq!(selected[0].id, "fixture-gpt-efi");
+		// Relative to the registry
+		let selected = select_devices(
+			&DeviceSelection::One("fixture/gpt-efi".to_owned()),
+			FIXTURE_REGISTRY,
+			false,
+		)?;
+		assert_eq!(selected[0].id, "fixture-gpt-efi");
+		assert!(
+			select_devices(
+				&DeviceSelection::One("nonexistent-device".to_owned()),
+				FIXTURE_REGISTRY,
+				false,
+			)
+			.is_err()
+		);
+		let Plan::Build { devices, .. } =
+			plan(&["build", "--by-compatible", "raspberrypi,5-model-b"])?
+		else {
+			panic!("Expected a build plan");
+		};
+		assert_eq!(
+			devices,
+			DeviceSelection::Compatible("raspberrypi,5-model-b".to_owned())
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_plan_build_all() -> Result<()> {
+		let Plan::Build { devices, options } = plan(&[
+			"build-all",
+			"-c",
+			"zstd",
+			"--format",
+			"tarball",
+			"--reproducible=release-1",
+			"--skip-size-check",
+			"--shrink",
+			"--no-bmap",
+			"--keep-raw",
+		])?
+		else {
+			panic!("Expected a build plan");
+		};
+		assert_eq!(devices, DeviceSelection::All);
+		assert_eq!(options.compression, Compression::Zstd);
+		assert_eq!(
+			options.variants,
+			vec![
+				ImageVariant::Base,
+				ImageVariant::Desktop,
+				ImageVariant::Server
+			]
+		);
+		assert_eq!(options.override_spec, None);
+		assert_eq!(options.format, OutputFormat::Tarball);
+		assert_eq!(options.reproducible.as_deref(), Some("release-1"));
+		assert!(options.skip_size_check);
+		assert!(options.shrink);
+		assert!(options.no_bmap);
+		assert!(options.keep_raw);
+		let selected = select_devices(&devices, FIXTURE_REGISTRY, false)?;
+		let all = DeviceRegistry::scan(FIXTURE_REGISTRY)?.get_all()?;
+		assert_eq!(selected.len(), all.len());
+		let Plan::Build { devices, .. } = plan(&["build-all", "--tag", "efi", "--tag", "riscv"])?
+		else {
+			panic!("Expected a build plan");
+		};
+		assert_eq!(
+			devices,
+			DeviceSelection::Tags(vec!["efi".to_owned(), "riscv".to_owned()])
+		);
+		let mut ids: Vec<String> = select_devices(&devices, FIXTURE_REGISTRY, false)?
+			.into_iter()
+			.map(|d| d.id)
+			.collect();
+		ids.sort();
+		assert_eq!(ids, ["fixture-gpt-efi", "fixture-gpt-layout"]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_build_run() -> Result<()> {
+		let run = BuildRun::new(Some(1))?;
+		let device = select_devices(
+			&DeviceSelection::One("fixture-gpt-efi".to_owned()),
+			FIXTURE_REGISTRY,
+			false,
+		)?
+		.remove(0);
+		let base = run.image_filename(
+			&device,
+			&ImageVariant::Base,
+			&OutputFormat::Rawimg,
+			&Compression::Xz,
+		);
+		let desktop = run.image_filename(
+			&device,
+			&ImageVariant::Desktop,
+			&OutputFormat::Tarball,
+			&Compression::Zstd,
+		);
+		assert_eq!(
+			base,
+			format!(
+				"aosc-os_base_rawimg_fixture_fixture-gpt-efi_{}.1_amd64.img.xz",
+				run.date
+			)
+		);
+		assert_eq!(
+			desktop,
+			format!(
+				"aosc-os_desktop_rootfs_fixture_fixture-gpt-efi_{}.1_amd64.tar.zst",
+				run.date
+			)
+		);
+		// Contexts of the same run agree on the values
+		let contexts: Vec<ImageContext> = [ImageVariant::Base, ImageVariant::Server]
+			.iter()
+			.map(|variant| ImageContext {
+				variant,
+				workdir: Path::new("/tmp"),
+				outdir: Path::new("/tmp"),
+				filename: run.image_filename(
+					&device,
+					variant,
+					&OutputFormat::Rawimg,
+					&Compression::Xz,
+				),
+				compress: &Compression::Xz,
+				..test_context(&device, &run)
+			})
+			.collect();
+		assert_eq!(contexts[0].run.id, contexts[1].run.id);
+		assert_eq!(contexts[0].run.timestamp, contexts[1].run.timestamp);
+		assert!(contexts[1].filename.contains(&contexts[0].run.date));
+		assert_ne!(BuildRun::new(None)?.id, run.id);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_output_paths() -> Result<()> {
+		let devices = ["fixture-gpt-efi", "fixture-mbr-uboot"]
+			.iter()
+			.map(|id| {
+				Ok(select_devices(
+					&DeviceSelection::One(id.to_string()),
+					FIXTURE_REGISTRY,
+					false,
+				)?
+				.remove(0))
+			})
+			.collect::<Result<Vec<_>>>()?;
+		let queue = |run: &BuildRun, layout: OutputLayout| -> Result<()> {
+			let contexts: Vec<ImageContext> = devices
+				.iter()
+				.map(|device| ImageContext {
+					workdir: Path::new("/tmp"),
+					outdir: Path::new("/tmp/out"),
+					filename: run.image_filename(
+						device,
+						&ImageVariant::Base,
+						&OutputFormat::Rawimg,
+						&Compression::Xz,
+					),
+					compress: &Compression::Xz,
+					output_layout: layout,
+					..test_context(device, run)
+				})
+				.collect();
+			if layout == OutputLayout::Flat {
+				assert_eq!(contexts[0].output_dir(), Path::new("/tmp/out"));
+				// Named after the output without the extension of the compression
+				let output = contexts[0].output_path();
+				assert_eq!(
+					contexts[0].uncompressed_output_path(),
+					output.with_extension("")
+				);
+				assert_eq!(contexts[0].bmap_path(), output.with_extension("bmap"));
+			} else {
+				assert_eq!(
+					contexts[0].output_dir(),
+					Path::new("/tmp/out/os-amd64/base/rawimg/fixture")
+				);
+			}
+			check_output_paths(&contexts)
+		};
+		let mut run = BuildRun::new(None)?;
+		queue(&run, OutputLayout::Hierarchy)?;
+		queue(&run, OutputLayout::Flat)?;
+		// Both devices are from the same vendor
+		run.filename_template = "{vendor}_{variant}.{ext}{compress_ext}".parse()?;
+		let err = queue(&run, OutputLayout::Flat).unwrap_err();
+		assert!(err.to_string().contains("fixture-mbr-uboot"), "{}", err);
+		// But of different architectures
+		queue(&run, OutputLayout::Hierarchy)?;
+		run.filename_template = "{variant}.{ext}{compress_ext}".parse()?;
+		assert!(queue(&run, OutputLayout::Flat).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_plan_check() -> Result<()> {
+		let Plan::Check { devices, strict } = plan(&[
+			"check",
+			"tests/fixtures/registry/fixture/gpt-efi/device.toml",
+		])?
+		else {
+			panic!("Expected a check plan");
+		};
+		assert_eq!(
+			devices,
+			DeviceSelection::One("tests/fixtures/registry/fixture/gpt-efi/device.toml".to_owned())
+		);
+		let selected = select_devices(&devices, FIXTURE_REGISTRY, false)?;
+		assert_eq!(selected[0].id, "fixture-gpt-efi");
+		assert!(!strict);
+		let Plan::Check { devices, strict } = plan(&["check", "--strict"])? else {
+			panic!("Expected a check plan");
+		};
+		assert_eq!(devices, DeviceSelection::All);
+		assert!(strict);
+		Ok(())
+	}
+
+	#[test]
+	fn test_plan_show() -> Result<()> {
+		let Plan::Show {
+			device,
+			override_spec,
+		} = plan(&["show", "--override-spec", "o.toml", "rock-5b"])?
+		else {
+			panic!("Expected a show plan");
+		};
+		assert_eq!(device, "rock-5b");
+		assert_eq!(override_spec, Some(PathBuf::from("o.toml")));
+		assert!(plan(&["show"]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_plan_new_device() -> Result<()> {
+		assert!(matches!(
+			plan(&["new-device", "--arch", "riscv64", "vendor", "board"])?,
+			Plan::NewDevice {
+				vendor,
+				id,
+				arch: DeviceArch::riscv64,
+				partition_map: PartitionMapType::GPT,
+			} if vendor == "vendor" && id == "board"
+		));
+		assert!(matches!(
+			plan(&[
+				"new-device",
+				"--arch",
+				"arm64",
+				"--partition-map",
+				"dos",
+				"v",
+				"b"
+			])?,
+			Plan::NewDevice {
+				partition_map: PartitionMapType::MBR,
+				..
+			}
+		));
+		assert!(plan(&["new-device", "vendor", "board"]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_plan_patch() -> Result<()> {
+		let Plan::Patch {
+			device,
+			image,
+			steps,
+		} = plan(&[
+			"patch",
+			"rpi-5b",
+			"a.img.xz",
+			"bootloader:1",
+			"postinst:postinst.sh",
+		])?
+		else {
+			panic!("Expected a patch plan");
+		};
+		assert_eq!(device, "rpi-5b");
+		assert_eq!(image, PathBuf::from("a.img.xz"));
+		assert_eq!(
+			steps,
+			vec![
+				PatchStep::Bootloader(1),
+				PatchStep::Postinst("postinst.sh".into())
+			]
+		);
+		// At least one valid step
+		assert!(plan(&["patch", "rpi-5b", "a.img.xz"]).is_err());
+		assert!(plan(&["patch", "rpi-5b", "a.img.xz", "bootloader:0"]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_plan_list() -> Result<()> {
+		assert!(matches!(
+			plan(&["list", "-f", "simple"])?,
+			Plan::List {
+				format: ListFormat::Simple,
+				tags,
+				compatible: None,
+				show_sizes: false,
+			} if tags.is_empty()
+		));
+		assert!(matches!(
+			plan(&["list", "--tag", "tier1"])?,
+			Plan::List {
+				format: ListFormat::Pretty,
+				tags,
+				compatible: None,
+				show_sizes: false,
+			} if tags == ["tier1"]
+		));
+		assert!(matches!(
+			plan(&["list", "-f", "json", "--show-sizes"])?,
+			Plan::List {
+				format: ListFormat::Json,
+				show_sizes: true,
+				..
+			}
+		));
+		assert!(matches!(
+			plan(&["list", "--compatible", "raspberrypi,5-model-b"])?,
+			Plan::List {
+				compatible: Some(c),
+				..
+			} if c == "raspberrypi,5-model-b"
+		));
+		assert!(plan(&["build"]).is_err());
+		assert!(matches!(
+			plan(&["gc", "--dry-run"])?,
+			Plan::Gc { dry_run: true }
+		));
+		Ok(())
+	}
+}
@@ -1,10 +1,15 @@
 #![allow(dead_code)]
 #![allow(clippy::upper_case_acronyms)]
 
-use std::path::Path;
+use std::{
+	fs::{self, File},
+	io::Write,
+	os::unix::fs::symlink,
+	path::Path,
+};
 
-use anyhow::Result;
-use serde::Deserialize;
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
 
 use crate::{
 	context::ImageContext,
@@ -12,6 +17,35 @@ use crate::{
 	utils::{run_str_script_with_chroot, setup_scroll_region},
 };
 
+/// APT configuration disabling the trigger processing, also honored by oma since it is built on libapt-pkg.
+const DEFER_TRIGGERS_APT_CONF_PATH: &str = "etc/apt/apt.conf.d/99mkrawimg-defer-triggers";
+const DEFER_TRIGGERS_APT_CONF: &str = r#"// Written by mkrawimg during the image build, removed afterwards.
+DPkg::NoTriggers "true";
+DPkg::ConfigurePending "false";
+DPkg::TriggersPending "false";
+"#;
+/// Marker for the first boot service, removed by the service.
+pub(crate) const DEFERRED_TRIGGERS_PENDING_PATH: &str = "var/lib/mkrawimg/deferred-triggers";
+/// Record of the deferred triggers in the image metadata.
+pub(crate) const DEFERRED_TRIGGERS_METADATA_PATH: &str = "etc/mkrawimg/deferred-triggers.toml";
+pub(crate) const DEFERRED_TRIGGERS_UNIT_NAME: &str = "mkrawimg-deferred-triggers.service";
+const DEFERRED_TRIGGERS_UNIT: &str = r#"[Unit]
+Description=Process package triggers deferred during the image build
+ConditionPathExists=/var/lib/mkrawimg/deferred-triggers
+After=local-fs.target
+Before=systemd-user-sessions.service display-manager.service
+
+[Service]
+Type=oneshot
+ExecStart=/usr/bin/dpkg --triggers-only --pending
+ExecStart=/usr/bin/dpkg --configure --pending
+ExecStartPost=/usr/bin/rm -f /var/lib/mkrawimg/deferred-triggers
+TimeoutSec=0
+
+[Install]
+WantedBy=multi-user.target
+"#;
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Default, Debug, Deserialize, PartialEq, Eq)]
 pub enum Distro {
@@ -27,12 +61,20 @@ pub enum APT {}
 pub enum Oma {}
 
 pub trait PackageManager {
-	fn install(packages: &[&str], container: &dyn AsRef<Path>) -> Result<()>;
-	fn upgrade_system(container: &dyn AsRef<Path>) -> Result<()>;
+	/// The scripts installing `packages`, to be run in the container in order.
+	fn install_scripts(packages: &[&str]) -> Vec<String>;
+	fn install(packages: &[&str], container: &dyn AsRef<Path>, machine: &str) -> Result<()> {
+		// Block device access is only available to post-installation script and bootloader scripts.
+		for script in Self::install_scripts(packages) {
+			run_str_script_with_chroot(container, machine, &script, &[], None)?;
+		}
+		Ok(())
+	}
+	fn upgrade_system(container: &dyn AsRef<Path>, machine: &str) -> Result<()>;
 }
 
 impl PackageManager for APT {
-	fn install(packages: &[&str], container: &dyn AsRef<Path>) -> Result<()> {
+	fn install_scripts(packages: &[&str]) -> Vec<String> {
 		// Let's do this the easy way.
 		// FIXME might have to fork() and exec() ourselves.
 		let mut argv = Vec::<&str>::from([
@@ -47,24 +89,23 @@ impl PackageManager for APT {
 		let mut script = String::from("export DEBIAN_FRONTEND=noninteractive;apt-get update;");
 		script += &argv.join(" ");
 		// chroot $CONTAINER bash -c "export DEBIAN_FRONTEND=noninteractive;apt-get install --yes -o Dpkg::Options::=--force-confnew pkgs ..."
-		// Block device access is only available to post-installation script and bootloader scripts.
-		run_str_script_with_chroot(container, &script, &[], None)?;
-		run_str_script_with_chroot(container, "apt clean", &[], None)
+		vec![script, "apt clean".to_owned()]
 	}
 
-	fn upgrade_system(container: &dyn AsRef<Path>) -> Result<()> {
+	fn upgrade_system(container: &dyn AsRef<Path>, machine: &str) -> Result<()> {
 		run_str_script_with_chroot(
 			container,
+			machine,
 			"export DEBIAN_FRONTEND=noninteractive;apt-get update;apt-get full-upgrade --yes",
 			&[],
 			None,
 		)?;
-		run_str_script_with_chroot(container, "apt clean", &[], None)
+		run_str_script_with_chroot(container, machine, "apt clean", &[], None)
 	}
 }
 
 impl PackageManager for Oma {
-	fn install(packages: &[&str], container: &dyn AsRef<Path>) -> Result<()> {
+	fn install_scripts(packages: &[&str]) -> Vec<String> {
 		let mut argv = Vec::from([
 			"oma",
 			"--no-check-dbus",
@@ -76,43 +117,176 @@ impl PackageManager for Oma {
 			"--",
 		]);
 		argv.extend_from_slice(packages);
-		run_str_script_with_chroot(container, &argv.join(" "), &[], None)?;
-		run_str_script_with_chroot(container, "oma --no-check-dbus clean", &[], None)
+		vec![argv.join(" "), "oma --no-check-dbus clean".to_owned()]
 	}
-	fn upgrade_system(container: &dyn AsRef<Path>) -> Result<()> {
+	fn upgrade_system(container: &dyn AsRef<Path>, machine: &str) -> Result<()> {
 		run_str_script_with_chroot(
 			container,
+			machine,
 			"oma --no-check-dbus upgrade --no-progress --force-confnew --yes",
 			&[],
 			None,
 		)?;
-		run_str_script_with_chroot(container, "oma --no-check-dbus clean", &[], None)
+		run_str_script_with_chroot(container, machine, "oma --no-check-dbus clean", &[], None)
 	}
 }
 
+/// Whether AOSC OS on `arch` uses APT instead of oma to install packages, i.e. oma does not work under QEMU user mode emulation there.
+#[inline]
+pub(crate) fn aosc_uses_apt(arch: &DeviceArch) -> bool {
+	!arch.is_native()
+		&& matches!(
+			arch,
+			DeviceArch::mips64r6el | DeviceArch::armhf | DeviceArch::armv6hf
+		)
+}
+
 #[inline]
 fn install_packages_aosc(
 	packages: &[&str],
 	container: &dyn AsRef<Path>,
+	machine: &str,
 	arch: &DeviceArch,
 ) -> Result<()> {
-	if arch.is_native() {
-		Oma::install(packages, container)
+	if aosc_uses_apt(arch) {
+		APT::install(packages, container, machine)
 	} else {
-		match arch {
-			DeviceArch::mips64r6el => APT::install(packages, container),
-			_ => Oma::install(packages, container),
+		Oma::install(packages, container, machine)
+	}
+}
+
+/// Record of the deferred triggers, written to [`DEFERRED_TRIGGERS_METADATA_PATH`].
+#[derive(Serialize)]
+struct DeferredTriggers<'a> {
+	packages: &'a [String],
+}
+
+/// Find the packages with pending triggers in the content of the dpkg status database.
+fn parse_pending_triggers(status: &str) -> Vec<String> {
+	let mut result = Vec::new();
+	for stanza in status.split("\n\n") {
+		let mut package = None;
+		let mut pending = false;
+		for line in stanza.lines() {
+			if let Some(name) = line.strip_prefix("Package: ") {
+				package = Some(name.trim());
+			} else if let Some(status) = line.strip_prefix("Status: ") {
+				pending = status
+					.split_whitespace()
+					.last()
+					.is_some_and(|x| x == "triggers-pending" || x == "triggers-awaited");
+			}
+		}
+		if pending && let Some(package) = package {
+			result.push(package.to_owned());
 		}
 	}
+	result
 }
 
 impl ImageContext<'_> {
+	/// Disable the trigger processing of the package manager, if the device defers triggers.
+	pub fn begin_defer_triggers<P: AsRef<Path>>(&self, container: P) -> Result<()> {
+		if !self.device.defer_triggers {
+			return Ok(());
+		}
+		self.info("Deferring package triggers to the first boot ...");
+		fs::write(
+			container.as_ref().join(DEFER_TRIGGERS_APT_CONF_PATH),
+			DEFER_TRIGGERS_APT_CONF,
+		)?;
+		Ok(())
+	}
+
+	/// Remove the configuration written by [`Self::begin_defer_triggers`] if a step failed before [`Self::finish_defer_triggers`], so it is not left in the target system.
+	pub fn abort_defer_triggers<P: AsRef<Path>>(&self, container: P) {
+		let path = container.as_ref().join(DEFER_TRIGGERS_APT_CONF_PATH);
+		match fs::remove_file(&path) {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+				self.warn(format!("Unable to remove {}: {}", path.display(), e));
+			}
+			_ => (),
+		}
+	}
+
+	/// Restore the trigger processing, record the pending triggers and install the first boot service to process them.
+	pub fn finish_defer_triggers<P: AsRef<Path>>(&self, container: P) -> Result<()> {
+		if !self.device.defer_triggers {
+			return Ok(());
+		}
+		let container = container.as_ref();
+		fs::remove_file(container.join(DEFER_TRIGGERS_APT_CONF_PATH))?;
+		let status = fs::read_to_string(container.join("var/lib/dpkg/status"))
+			.context("Unable to read the dpkg status database")?;
+		let packages = parse_pending_triggers(&status);
+		if packages.is_empty() {
+			self.info("No package triggers are pending.");
+			return Ok(());
+		}
+		self.info(format!(
+			"{} packages have pending triggers, installing {} ...",
+			packages.len(),
+			DEFERRED_TRIGGERS_UNIT_NAME
+		));
+		let metadata_path = container.join(DEFERRED_TRIGGERS_METADATA_PATH);
+		fs::create_dir_all(metadata_path.parent().unwrap())?;
+		let mut fd = File::create(&metadata_path)?;
+		fd.write_all(
+			b"# Package triggers were deferred during the build of this image,\n\
+			# and processed by mkrawimg-deferred-triggers.service on the first boot.\n",
+		)?;
+		fd.write_all(
+			toml::to_string(&DeferredTriggers {
+				packages: &packages,
+			})?
+			.as_bytes(),
+		)?;
+		fd.sync_all()?;
+		let pending_path = container.join(DEFERRED_TRIGGERS_PENDING_PATH);
+		fs::create_dir_all(pending_path.parent().unwrap())?;
+		fs::write(&pending_path, packages.join("\n") + "\n")?;
+		let unit_path = container
+			.join("usr/lib/systemd/system")
+			.join(DEFERRED_TRIGGERS_UNIT_NAME);
+		fs::write(&unit_path, DEFERRED_TRIGGERS_UNIT)?;
+		let wants_dir = container.join("etc/systemd/system/multi-user.target.wants");
+		fs::create_dir_all(&wants_dir)?;
+		let link = wants_dir.join(DEFERRED_TRIGGERS_UNIT_NAME);
+		if !link.is_symlink() {
+			symlink(
+				Path::new("/usr/lib/systemd/system").join(DEFERRED_TRIGGERS_UNIT_NAME),
+				&link,
+			)?;
+		}
+		Ok(())
+	}
+
+	/// The scripts [`Self::install_packages`] runs in the container to install `packages`.
+	pub fn install_scripts(&self, packages: &[&str]) -> Result<Vec<String>> {
+		if packages.is_empty() {
+			return Ok(Vec::new());
+		}
+		Ok(match &self.device.distro {
+			Distro::AOSC if aosc_uses_apt(&self.device.arch) => APT::install_scripts(packages),
+			Distro::AOSC => Oma::install_scripts(packages),
+			distro => bail!(
+				"Installing packages for the distribution {:?} is not supported yet",
+				distro
+			),
+		})
+	}
+
 	pub fn install_packages<P: AsRef<Path>>(&self, packages: &[&str], container: P) -> Result<()> {
 		if packages.is_empty() {
 			return Ok(());
 		}
 		match &self.device.distro {
-			Distro::AOSC => install_packages_aosc(packages, &container, &self.device.arch)?,
+			Distro::AOSC => install_packages_aosc(
+				packages,
+				&container,
+				&self.machine_name(),
+				&self.device.arch,
+			)?,
 			Distro::Debian => todo!(),
 			Distro::Ubuntu => todo!(),
 			Distro::ArchLinux => todo!(),
@@ -122,3 +296,57 @@ impl ImageContext<'_> {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		fixtures::{TestDir, fixture_device, test_context},
+		plan::BuildRun,
+	};
+
+	#[test]
+	fn test_parse_pending_triggers() {
+		let status = "Package: man-db\nStatus: install ok triggers-pending\nVersion: 2.12\n\n\
+			Package: bash\nStatus: install ok installed\n\n\
+			Package: systemd\nStatus: install ok triggers-awaited\n";
+		assert_eq!(parse_pending_triggers(status), vec!["man-db", "systemd"]);
+		assert!(parse_pending_triggers("").is_empty());
+	}
+	#[test]
+	fn test_defer_triggers() -> Result<()> {
+		let mut device = fixture_device("fixture-gpt-efi")?;
+		device.defer_triggers = true;
+		let run = BuildRun::new(None)?;
+		let ctx = test_context(&device, &run);
+		let dir = TestDir::new("triggers")?;
+		for path in [
+			"etc/apt/apt.conf.d",
+			"var/lib/dpkg",
+			"usr/lib/systemd/system",
+		] {
+			fs::create_dir_all(dir.join(path))?;
+		}
+		fs::write(
+			dir.join("var/lib/dpkg/status"),
+			"Package: man-db\nStatus: install ok triggers-pending\n",
+		)?;
+		// Not left behind by a failed step
+		ctx.begin_defer_triggers(&dir)?;
+		assert!(dir.join(DEFER_TRIGGERS_APT_CONF_PATH).exists());
+		ctx.abort_defer_triggers(&dir);
+		assert!(!dir.join(DEFER_TRIGGERS_APT_CONF_PATH).exists());
+		ctx.begin_defer_triggers(&dir)?;
+		ctx.finish_defer_triggers(&dir)?;
+		assert!(!dir.join(DEFER_TRIGGERS_APT_CONF_PATH).exists());
+		let metadata: toml::Table = toml::from_str(&fs::read_to_string(
+			dir.join(DEFERRED_TRIGGERS_METADATA_PATH),
+		)?)?;
+		assert_eq!(metadata["packages"], toml::Value::from(vec!["man-db"]));
+		assert_eq!(
+			fs::read_to_string(dir.join(DEFERRED_TRIGGERS_PENDING_PATH))?,
+			"man-db\n"
+		);
+		Ok(())
+	}
+}
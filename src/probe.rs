@@ -0,0 +1,182 @@
+//! Module handling the probing of filesystem UUIDs without libblkid.
+//!
+//! Only the UUID (or the volume serial number for FAT) of the filesystems mkrawimg creates is read from the superblocks.
+//! Used instead of libblkid if mkrawimg is built with the `no-blkid` feature.
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::{Context, Result, bail};
+use uuid::Uuid;
+
+/// Offset of the ext2/3/4 superblock.
+const EXT4_SB_OFFSET: usize = 1024;
+/// Offset of the Btrfs superblock.
+const BTRFS_SB_OFFSET: usize = 0x10000;
+/// Bytes to read from the start of the device, enough for all superblocks.
+const PROBE_SIZE: u64 = (BTRFS_SB_OFFSET + 4096) as u64;
+
+fn uuid_at(buf: &[u8], offset: usize) -> Option<String> {
+	let bytes: [u8; 16] = buf.get(offset..offset + 16)?.try_into().ok()?;
+	Some(Uuid::from_bytes(bytes).hyphenated().to_string())
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> Option<u16> {
+	Some(u16::from_le_bytes(
+		buf.get(offset..offset + 2)?.try_into().ok()?,
+	))
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Option<u32> {
+	Some(u32::from_le_bytes(
+		buf.get(offset..offset + 4)?.try_into().ok()?,
+	))
+}
+
+fn probe_ext4(buf: &[u8]) -> Option<String> {
+	// s_magic, then s_uuid
+	(u16_at(buf, EXT4_SB_OFFSET + 0x38)? == 0xEF53).then_some(())?;
+	uuid_at(buf, EXT4_SB_OFFSET + 0x68)
+}
+
+fn probe_btrfs(buf: &[u8]) -> Option<String> {
+	// magic, then fsid
+	(buf.get(BTRFS_SB_OFFSET + 0x40..BTRFS_SB_OFFSET + 0x48)? == b"_BHRfS_M").then_some(())?;
+	uuid_at(buf, BTRFS_SB_OFFSET + 0x20)
+}
+
+fn probe_xfs(buf: &[u8]) -> Option<String> {
+	// sb_magicnum, then sb_uuid
+	(buf.get(0..4)? == b"XFSB").then_some(())?;
+	uuid_at(buf, 32)
+}
+
+fn probe_fat(buf: &[u8]) -> Option<String> {
+	if u16_at(buf, 510)? != 0xAA55 || u16_at(buf, 11)? == 0 {
+		return None;
+	}
+	// FAT32 has no 16-bit FAT size, and an extended BPB of its own.
+	let ebpb = if u16_at(buf, 22)? == 0 { 64 } else { 36 };
+	// Extended boot signature, followed by the volume serial number.
+	if buf.get(ebpb + 2)? != &0x29 {
+		return None;
+	}
+	let serial = u32_at(buf, ebpb + 3)?;
+	Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xFFFF))
+}
+
+/// Get the UUID of the filesystem from the beginning of the device, in the form reported by blkid.
+pub fn probe_fsuuid_from(buf: &[u8]) -> Option<String> {
+	// FAT goes last, its signature is the weakest.
+	probe_ext4(buf)
+		.or_else(|| probe_btrfs(buf))
+		.or_else(|| probe_xfs(buf))
+		.or_else(|| probe_fat(buf))
+}
+
+/// Get the UUID of the filesystem in `path`, in the form reported by blkid.
+pub fn probe_fsuuid<P: AsRef<Path>>(path: P) -> Result<String> {
+	let path = path.as_ref();
+	let mut buf = Vec::new();
+	File::open(path)
+		.context(format!("Unable to open {}", path.display()))?
+		.take(PROBE_SIZE)
+		.read_to_end(&mut buf)?;
+	let Some(uuid) = probe_fsuuid_from(&buf) else {
+		bail!(
+			"No filesystem UUID found in {}; Perhaps there's no filesystem in this partition, or the type of the filesystem can't be identified",
+			path.display()
+		);
+	};
+	Ok(uuid)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::TestDir;
+	use crate::{
+		filesystem::FilesystemType,
+		utils::{create_sparse_file, find_program},
+	};
+
+	const UUID: &str = "6b1e3d4f-0a2c-4b8e-9d7f-1c2e3a4b5c6d";
+
+	fn fixture(len: usize, fields: &[(usize, &[u8])]) -> Vec<u8> {
+		let mut buf = vec![0; len];
+		for (offset, bytes) in fields {
+			buf[*offset..offset + bytes.len()].copy_from_slice(bytes);
+		}
+		buf
+	}
+
+	#[test]
+	fn test_probe_superblocks() {
+		let uuid = *uuid::uuid!("6b1e3d4f-0a2c-4b8e-9d7f-1c2e3a4b5c6d").as_bytes();
+		let ext4 = fixture(4096, &[(1024 + 0x38, &[0x53, 0xEF]), (1024 + 0x68, &uuid)]);
+		let btrfs = fixture(
+			PROBE_SIZE as usize,
+			&[(0x10040, b"_BHRfS_M"), (0x10020, &uuid)],
+		);
+		let xfs = fixture(512, &[(0, b"XFSB"), (32, &uuid)]);
+		let serial = 0x1234ABCDu32.to_le_bytes();
+		let fat16 = fixture(
+			512,
+			&[
+				(11, &[0, 2]),
+				(22, &[32, 0]),
+				(38, &[0x29]),
+				(39, &serial),
+				(510, &[0x55, 0xAA]),
+			],
+		);
+		let fat32 = fixture(
+			512,
+			&[
+				(11, &[0, 2]),
+				(66, &[0x29]),
+				(67, &serial),
+				(510, &[0x55, 0xAA]),
+			],
+		);
+		for buf in [&ext4, &btrfs, &xfs] {
+			assert_eq!(probe_fsuuid_from(buf).as_deref(), Some(UUID));
+		}
+		for buf in [&fat16, &fat32] {
+			assert_eq!(probe_fsuuid_from(buf).as_deref(), Some("1234-ABCD"));
+		}
+		// Truncated or unknown data
+		assert_eq!(probe_fsuuid_from(&ext4[..1100]), None);
+		assert_eq!(probe_fsuuid_from(&[0; 4096]), None);
+		// An MBR has the boot signature, but no BPB
+		assert_eq!(
+			probe_fsuuid_from(&fixture(512, &[(510, &[0x55, 0xAA])])),
+			None
+		);
+	}
+
+	#[test]
+	fn test_probe_mkfs() -> Result<()> {
+		let dir = TestDir::new("probe")?;
+		for (fstype, size, uuid) in [
+			(FilesystemType::Ext4, 16, UUID),
+			(FilesystemType::Btrfs, 128, UUID),
+			(FilesystemType::Xfs, 320, UUID),
+			(FilesystemType::Fat16, 64, "1234-ABCD"),
+			(FilesystemType::Fat32, 64, "1234-ABCD"),
+		] {
+			let img = dir.join(format!("{:?}.img", fstype));
+			let mut cmd = fstype.get_mkfs_cmdline(&img, None, Some(uuid))?;
+			let mkfs = cmd.get_program().to_string_lossy().to_string();
+			if find_program(&mkfs, "/").is_none() {
+				eprintln!("{} is not installed, skipping {:?}", mkfs, fstype);
+				continue;
+			}
+			create_sparse_file(&img, size * 1048576)?;
+			let output = cmd.output();
+			let probed = probe_fsuuid(&img);
+			let output = output?;
+			assert!(output.status.success(), "{:?}", output);
+			assert_eq!(probed?, uuid);
+		}
+		Ok(())
+	}
+}
@@ -1,17 +1,85 @@
 //! Module handling the registry of the device specifications.
 //!
 //! See [`DeviceRegistry`] for details.
-use crate::{cli::ListFormat, device::DeviceSpec};
+use crate::{
+	cli::ListFormat, context::ImageVariant, device::DeviceSpec, report::BuildReport,
+	schema::deny_unknown_fields, utils::human_size,
+};
 use anyhow::{Context, Result, anyhow, bail};
-use log::{debug, error, info};
-use owo_colors::OwoColorize;
+use log::{debug, error, info, warn};
+use owo_colors::{OwoColorize, Stream::Stderr};
+use serde::Serialize;
 use std::{
-	collections::HashMap,
-	fs,
+	collections::{BTreeMap, HashMap},
 	path::{Path, PathBuf},
+	sync::Mutex,
+	thread,
 };
+use strum::VariantArray;
 use walkdir::WalkDir;
 
+/// Width of the terminals the pretty list must fit in.
+const LIST_WIDTH: usize = 100;
+
+/// Size of the images of a variant, in the JSON list.
+#[derive(Serialize)]
+struct ListedSize {
+	mib: u64,
+	human: String,
+	/// The output of the latest build in the output directory.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	last_build: Option<ListedBuild>,
+}
+
+/// The latest build of a variant, in the JSON list.
+#[derive(Serialize)]
+struct ListedBuild {
+	mib: u64,
+	human: String,
+	file: String,
+	format: String,
+	compression: String,
+}
+
+/// A device in the JSON list.
+#[derive(Serialize)]
+struct ListedDevice<'a> {
+	id: &'a str,
+	vendor: &'a str,
+	arch: String,
+	name: &'a str,
+	aliases: &'a [String],
+	tags: &'a [String],
+	#[serde(skip_serializing_if = "Option::is_none")]
+	compatible: Option<&'a str>,
+	/// Keyed by the variant.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	sizes: Option<BTreeMap<String, ListedSize>>,
+}
+
+/// Parse the device specs at `paths` concurrently, returning the results in the same order.
+fn parse_concurrently(paths: &[PathBuf]) -> Vec<Result<DeviceSpec>> {
+	let queue = Mutex::new(paths.iter().enumerate());
+	let results = Mutex::new(Vec::with_capacity(paths.len()));
+	thread::scope(|scope| {
+		for _ in 0..num_cpus::get().min(paths.len()) {
+			scope.spawn(|| {
+				loop {
+					let job = queue.lock().unwrap().next();
+					let Some((idx, path)) = job else {
+						break;
+					};
+					let result = DeviceSpec::from_path(path);
+					results.lock().unwrap().push((idx, result));
+				}
+			});
+		}
+	});
+	let mut results = results.into_inner().unwrap();
+	results.sort_by_key(|(idx, _)| *idx);
+	results.into_iter().map(|(_, result)| result).collect()
+}
+
 /// Device Registry
 /// ===============
 ///
@@ -36,6 +104,8 @@ use walkdir::WalkDir;
 ///     device4/
 ///       device.toml
 ///       script.sh               # Badly named bootloader script but is acceptable
+///   layouts/                    # Partition layouts shared by devices
+///     raspberrypi-gpt.toml      # Referenced with `layout = "raspberrypi-gpt"`
 /// ```
 ///
 /// - The top-level directory contains vendor-level directories.
@@ -43,7 +113,9 @@ use walkdir::WalkDir;
 /// - The device-level directory is the directory containing the [device specification file]. It can also contain other device-related scripts, like post-installation script, and scripts that set up bootloaders.
 /// - The vendor name and the device ID must contain only ASCII-characters, and must not contain white spaces and symbols other than hyphens and underscores. Hyphen (`-`) is preferred than underscores (`_`).
 /// - Although the rules above are not enforced by the tool, you are encouraged to follow this practice. Usage outside the rules above are allowed if one has to.
-/// - To save space, symbolic links of scripts are allowed.
+/// - To save space, symbolic links of scripts are allowed. Vendor-level and device-level directories can be symbolic links as well, a device linked more than once is registered once.
+/// - Nothing is written into the registry, thus it can be read-only, e.g. installed in `/usr/share/aosc-mkrawimg/devices`.
+/// - The `layouts` directory contains partition layouts shared by many devices, see the `layout` field of the [device specification file].
 ///
 /// [device specification file]: crate::device::DeviceSpec
 pub struct DeviceRegistry {
@@ -56,6 +128,12 @@ pub struct DeviceRegistry {
 	// corresponding device in that list to save some clones.
 	devices: Vec<DeviceSpec>,
 	registry: HashMap<String, usize>,
+	// Devices having each tag, in the same manner.
+	tags: HashMap<String, Vec<usize>>,
+	// Devices by their `of_compatible`. Kept apart from the IDs and
+	// aliases, since a compatible string may be the alias of another
+	// device.
+	compatibles: HashMap<String, usize>,
 }
 
 impl DeviceRegistry {
@@ -67,11 +145,32 @@ impl DeviceRegistry {
 		Ok(self.devices)
 	}
 
+	/// Look up a device by its ID or alias, or its compatible string if no device has such an ID or alias.
 	pub fn get(self, str: &String) -> Result<DeviceSpec> {
-		if !self.registry.contains_key(str) {
-			bail!("Can't find a device with provided ID or alias '{}'", &str);
-		}
-		let idx_device = self.registry.get(str).unwrap();
+		let idx_device = match (self.registry.get(str), self.compatibles.get(str)) {
+			(Some(idx), compatible) => {
+				if let Some(other) = compatible
+					&& other != idx
+				{
+					warn!(
+						"'{}' is also the compatible string of device '{}', use --by-compatible to select it instead.",
+						str, self.devices[*other].id
+					);
+				}
+				idx
+			}
+			(None, Some(idx)) => {
+				info!(
+					"Selected device '{}' by its compatible string '{}'.",
+					self.devices[*idx].id, str
+				);
+				idx
+			}
+			(None, None) => bail!(
+				"Can't find a device with provided ID, alias or compatible string '{}'",
+				&str
+			),
+		};
 		let device: &DeviceSpec = self
 			.devices
 			.get(*idx_device)
@@ -80,88 +179,241 @@ impl DeviceRegistry {
 		Ok(device.to_owned())
 	}
 
+	/// Look up a device by its compatible string (the `compatible` field) only.
+	pub fn get_by_compatible(self, compatible: &str) -> Result<DeviceSpec> {
+		Ok(self.retain_compatible(compatible)?.devices.remove(0))
+	}
+
+	/// Assemble the registry from all device specs within `registry_dir`, failing if any of them is broken.
 	pub fn scan<P: AsRef<Path>>(registry_dir: P) -> Result<Self> {
-		let registry_dir = registry_dir.as_ref();
+		Self::scan_with(registry_dir.as_ref(), false)
+	}
+
+	/// Like [`DeviceRegistry::scan`], but the device specs which can not be read are skipped with a warning.
+	///
+	/// Conflicting IDs and aliases are still fatal, since it is unknown which one of the devices is the right one.
+	pub fn scan_lenient<P: AsRef<Path>>(registry_dir: P) -> Result<Self> {
+		Self::scan_with(registry_dir.as_ref(), true)
+	}
+
+	fn scan_with(registry_dir: &Path, lenient: bool) -> Result<Self> {
 		info!(
 			"Scanning all devices within registry at {} ...",
 			registry_dir.display()
 		);
-		let mut devices = Vec::new();
+		let mut devices: Vec<DeviceSpec> = Vec::new();
 		let mut hashmap = HashMap::new();
-		let walker = WalkDir::new(registry_dir).max_depth(4).into_iter();
+		let mut compatibles = HashMap::new();
+		// Vendor and device directories can be symbolic links.
+		let walker = WalkDir::new(registry_dir)
+			.max_depth(4)
+			.follow_links(true)
+			.into_iter();
+		let mut paths = Vec::new();
 		for file in walker {
 			let f = file?;
 			let p = f.path();
-			if !p.is_file() || p.file_name().unwrap() != "device.toml" {
+			if p.is_file() && p.file_name().unwrap() == "device.toml" {
+				paths.push(p.to_owned());
+			}
+		}
+		// Sorted, so the results do not depend on the order of the directory entries.
+		paths.sort();
+		// The same device linked into the registry more than once is only registered once.
+		let mut seen: HashMap<PathBuf, PathBuf> = HashMap::new();
+		let mut unique_paths = Vec::new();
+		for p in paths {
+			let canonical = p.canonicalize()?;
+			if let Some(first) = seen.get(&canonical) {
+				debug!("Skipping {}, same as {}", p.display(), first.display());
 				continue;
 			}
-			let dev: DeviceSpec = DeviceSpec::from_path(p)?;
+			seen.insert(canonical, p.clone());
+			unique_paths.push(p);
+		}
+		let mut errs: Vec<String> = Vec::new();
+		let mut skipped = 0;
+		for (p, result) in unique_paths.iter().zip(parse_concurrently(&unique_paths)) {
+			let dev = match result {
+				Ok(dev) => dev,
+				Err(e) if lenient => {
+					warn!("Skipping {}: {:#}", p.display(), e);
+					skipped += 1;
+					continue;
+				}
+				Err(e) => {
+					errs.push(format!("{:#}", e));
+					continue;
+				}
+			};
 			debug!("Parsed device \"{}\"\n{:#?}", &dev.name, &dev);
-			let name = dev.name.clone();
-			let id = dev.id.clone();
-			let aliases = dev.aliases.clone();
-			if hashmap.contains_key(&id) {
-				let occupant_idx = hashmap.get(&id).unwrap();
-				let occupant: &DeviceSpec = devices.get(*occupant_idx).context(format!(
-					"Can not get the device which occupies the ID '{}'",
-					id
-				))?;
-				return Err(anyhow!(
-					"Device ID \"{}\" already exists (used by device \"{}\" ({})).\n\
+			let idx = devices.len();
+			let names = std::iter::once(("Device ID", &dev.id))
+				.chain(dev.aliases.iter().flatten().map(|a| ("Alias", a)));
+			for (kind, name) in names {
+				let Some(occupant_idx) = hashmap.get(name) else {
+					hashmap.insert(name.clone(), idx);
+					continue;
+				};
+				let occupant = devices.get(*occupant_idx).unwrap_or(&dev);
+				let used_as = if &occupant.id == name { "ID" } else { "alias" };
+				errs.push(format!(
+					"{} \"{}\" of device \"{}\" ({}) is already used as the {} of device \"{}\" ({}).\n\
 						Please view the following files to decide what to do:\n- {}\n- {}",
-					id,
+					kind,
+					name,
+					dev.name,
+					&dev.id,
+					used_as,
 					occupant.name,
 					occupant.id,
 					p.display(),
-					&occupant.file_path.as_path().display()
-				))
-				.context("Error occurred while assembling the device registry");
+					&occupant.file_path.display()
+				));
 			}
-			devices.push(dev);
-			hashmap.insert(id.clone(), devices.len() - 1);
-			if let Some(arr) = aliases {
-				for alias in arr {
-					if hashmap.contains_key(&alias) {
-						let occupant_idx = hashmap.get(&alias).unwrap();
-						let occupant: &DeviceSpec = devices.get(*occupant_idx).context(format!(
-							"Can not get the device which uses the alias {}",
-							alias
-						))?;
-						return Err(anyhow!(
-							"Alias \"{}\" for device \"{}\" ({}) has been used by device \"{}\" ({})\n.\
-						Please view the following files to decide what to do:\n- {}- \n{}",
-							alias,
-							name,
-							&id,
-							occupant.name,
-							occupant.id,
-							p.display(),
-							&occupant.file_path.as_path().display()
-						));
-					}
-					hashmap.insert(alias.clone(), devices.len() - 1);
+			if let Some(compatible) = &dev.of_compatible {
+				if let Some(occupant_idx) = compatibles.get(compatible) {
+					let occupant: &DeviceSpec = &devices[*occupant_idx];
+					errs.push(format!(
+						"Compatible string \"{}\" of device \"{}\" ({}) is already used by device \"{}\" ({}).\n\
+							Please view the following files to decide what to do:\n- {}\n- {}",
+						compatible,
+						dev.name,
+						&dev.id,
+						occupant.name,
+						occupant.id,
+						p.display(),
+						&occupant.file_path.display()
+					));
+				} else {
+					compatibles.insert(compatible.clone(), idx);
 				}
 			}
+			devices.push(dev);
+		}
+		if !errs.is_empty() {
+			return Err(anyhow!("{}", errs.join("\n"))).context(format!(
+				"{} error(s) occurred while assembling the device registry",
+				errs.len()
+			));
+		}
+		if skipped > 0 {
+			if devices.is_empty() {
+				bail!("All {} device spec(s) in the registry are broken", skipped);
+			}
+			warn!(
+				"{} broken device spec(s) skipped while assembling the device registry.",
+				skipped
+			);
 		}
 		info!(
 			"Scan complete. Registry contains {} names for {} devices.",
 			&hashmap.len(),
 			&devices.len()
 		);
+		let tags = DeviceRegistry::index_tags(&devices);
 		let registry = DeviceRegistry {
 			devices,
 			registry: hashmap,
+			tags,
+			compatibles,
 		};
 		Ok(registry)
 	}
 
+	fn index_tags(devices: &[DeviceSpec]) -> HashMap<String, Vec<usize>> {
+		let mut tags: HashMap<String, Vec<usize>> = HashMap::new();
+		for (idx, device) in devices.iter().enumerate() {
+			for tag in device.tags.iter().flatten() {
+				let indices = tags.entry(tag.clone()).or_default();
+				// A tag listed twice by the same device.
+				if indices.last() != Some(&idx) {
+					indices.push(idx);
+				}
+			}
+		}
+		tags
+	}
+
+	/// Keep only the devices having any of `tags`.
+	///
+	/// Every tag must be used by at least one device of the registry, to catch the typos.
+	pub fn retain_tags(self, tags: &[String]) -> Result<Self> {
+		if let Some(unknown) = tags.iter().find(|t| !self.tags.contains_key(*t)) {
+			let mut known: Vec<&str> = self.tags.keys().map(String::as_str).collect();
+			known.sort();
+			bail!(
+				"Unknown tag '{}'. Known tags are: {}",
+				unknown,
+				if known.is_empty() {
+					"(none)".to_owned()
+				} else {
+					known.join(", ")
+				}
+			);
+		}
+		let mut selected: Vec<usize> = tags.iter().flat_map(|t| self.tags[t].clone()).collect();
+		selected.sort();
+		selected.dedup();
+		let registry = self.retain_indices(&selected);
+		info!(
+			"{} devices have the tags {}.",
+			registry.devices.len(),
+			tags.join(", ")
+		);
+		Ok(registry)
+	}
+
+	/// Keep only the device whose compatible string is `compatible`.
+	pub fn retain_compatible(self, compatible: &str) -> Result<Self> {
+		let Some(idx) = self.compatibles.get(compatible) else {
+			bail!(
+				"Can't find a device with provided compatible string '{}'",
+				compatible
+			);
+		};
+		let idx = *idx;
+		Ok(self.retain_indices(&[idx]))
+	}
+
+	/// Keep only the devices at the sorted indices `selected`.
+	fn retain_indices(self, selected: &[usize]) -> Self {
+		// Old indices to the new ones.
+		let new_idx: HashMap<usize, usize> = selected
+			.iter()
+			.enumerate()
+			.map(|(new, old)| (*old, new))
+			.collect();
+		let remap = |map: HashMap<String, usize>| -> HashMap<String, usize> {
+			map.into_iter()
+				.filter_map(|(name, idx)| Some((name, *new_idx.get(&idx)?)))
+				.collect()
+		};
+		let registry = remap(self.registry);
+		let compatibles = remap(self.compatibles);
+		let devices: Vec<DeviceSpec> = self
+			.devices
+			.into_iter()
+			.enumerate()
+			.filter(|(idx, _)| new_idx.contains_key(idx))
+			.map(|(_, device)| device)
+			.collect();
+		DeviceRegistry {
+			tags: DeviceRegistry::index_tags(&devices),
+			devices,
+			registry,
+			compatibles,
+		}
+	}
+
 	pub fn from<P: AsRef<Path>>(path: P) -> Result<DeviceRegistry> {
 		let path = path.as_ref();
 		let mut registry: HashMap<String, usize> = HashMap::new();
 		let devicetoml = if path.is_dir() {
 			info!(
 				"Trying to find a device with specified path {} ...",
-				path.display().bright_cyan()
+				path.display()
+					.if_supports_color(Stderr, |t| t.bright_cyan())
 			);
 			let f = PathBuf::from(path).join("device.toml");
 			if !&f.exists() {
@@ -173,7 +425,8 @@ impl DeviceRegistry {
 		} else if path.is_file() && path.file_name().unwrap_or_default() == "device.toml" {
 			info!(
 				"Using specified device specification at {} ...",
-				path.display().bright_cyan()
+				path.display()
+					.if_supports_color(Stderr, |t| t.bright_cyan())
 			);
 			PathBuf::from(path)
 		} else {
@@ -181,7 +434,7 @@ impl DeviceRegistry {
 				"Custom path should be either a directory that contains a device.toml or the device.toml itself."
 			);
 		};
-		let device: DeviceSpec = toml::from_str(&fs::read_to_string(&devicetoml)?)?;
+		let device = DeviceSpec::from_path(&devicetoml)?;
 		let name = &device.name;
 		let id = device.id.clone();
 		debug!(
@@ -191,20 +444,40 @@ impl DeviceRegistry {
 			&devicetoml.file_name().unwrap().to_string_lossy()
 		);
 		registry.insert(id, 0);
+		let compatibles = device
+			.of_compatible
+			.iter()
+			.map(|c| (c.clone(), 0))
+			.collect();
+		let devices = vec![device];
 		Ok(DeviceRegistry {
-			devices: vec![device],
+			tags: DeviceRegistry::index_tags(&devices),
+			devices,
 			registry,
+			compatibles,
 		})
 	}
 
-	pub fn check_validity(self) -> Result<()> {
+	/// Check the validity of the devices, reporting the result for each device.
+	///
+	/// With `strict`, unknown fields in the specs are errors too.
+	pub fn check_devices(devices: Vec<DeviceSpec>, strict: bool) -> Result<()> {
 		let mut errs = Vec::<anyhow::Error>::new();
-		for d in self.devices {
-			let result = d.check().context(format!(
-				"Sanity check failed for device '{}' at {}:",
-				&d.id,
-				&d.file_path.display()
-			));
+		for d in devices {
+			let result = d
+				.check()
+				.and_then(|_| {
+					if strict {
+						deny_unknown_fields(&d.unknown_fields)
+					} else {
+						Ok(())
+					}
+				})
+				.context(format!(
+					"Sanity check failed for device '{}' at {}:",
+					&d.id,
+					&d.file_path.display()
+				));
 			match result {
 				Err(e) => {
 					error!(
@@ -241,39 +514,77 @@ impl DeviceRegistry {
 		}
 	}
 
-	fn list_pretty(devices: Vec<DeviceSpec>) {
+	/// Sizes of the variants of `device`, followed by the size of the latest build in `report`.
+	fn size_summary(device: &DeviceSpec, report: &BuildReport) -> String {
+		ImageVariant::VARIANTS
+			.iter()
+			.map(|variant| {
+				let size = human_size(
+					device
+						.size
+						.get_variant_size(variant)
+						.saturating_mul(1 << 20),
+				);
+				let variant_name = variant.to_string().to_lowercase();
+				match report.latest(&device.id, variant) {
+					Some(entry) => format!(
+						"{} {} (last {})",
+						variant_name,
+						size,
+						human_size(entry.size)
+					),
+					None => format!("{} {}", variant_name, size),
+				}
+			})
+			.collect::<Vec<_>>()
+			.join(", ")
+	}
+
+	fn list_pretty(devices: &[&DeviceSpec], report: Option<&BuildReport>) -> String {
 		// The following variables are used for formatting.
 		// I prefer formatting this table by hand, since it does not bring
 		// unnecessary dependencies.
 		let idx_width = (devices.len().ilog10()) as usize + 1;
-		println!(
-			"{0} {1} {2} Vendor\n{3} Description\n{3} Aliases",
+		let mut result = format!(
+			"{0} {1} {2} Vendor\n{3} Description\n{3} Aliases (Tags)\n",
 			format_args!("{}#", " ".repeat(idx_width - 1)),
 			format_args!("{:<32}", "Device ID"),
 			format_args!("{:<12}", "Arch."),
 			" ".repeat(idx_width)
 		);
-		println!("{}", "=".repeat(80));
+		result += &format!("{}\n", "=".repeat(80));
 		let mut idx = 1;
 		for device in devices.iter() {
 			//  # Device ID                        Arch.       Vendor
 			//    Description
-			//    Aliases
+			//    Aliases (Tags)
 			// ================================================================================
 			//  1 pc-efi                           amd64       generic
 			//    Standard PC (UEFI)
 			//    None
 			//  2 rpi-5b                           arm64       raspberrypi
 			//    Raspberrt Pi 5 Model B
-			//    pi5b, pi5
-			println!(
-				"{0} {1} {2} {3}\n{4} {5}\n{4} {6}",
+			//    pi5b, pi5 (tags: raspberrypi, tier1)
+			// With the sizes, the description line is followed by them:
+			//    Raspberrt Pi 5 Model B; base 5.0 GiB (last 812.3 MiB), ...
+			// Or they take a line of their own, if it would not fit.
+			let mut description = device.name.clone();
+			if let Some(report) = report {
+				let sizes = DeviceRegistry::size_summary(device, report);
+				if idx_width + description.len() + sizes.len() + 3 <= LIST_WIDTH {
+					description += &format!("; {}", sizes);
+				} else {
+					description += &format!("\n{} {}", " ".repeat(idx_width), sizes);
+				}
+			}
+			result += &format!(
+				"{0} {1} {2} {3}\n{4} {5}\n{4} {6}{7}\n",
 				format_args!("{}", idx),
 				format_args!("{:<32}", &device.id),
 				format_args!("{:<12}", &device.arch.to_string().to_lowercase()),
 				&device.vendor,
 				" ".repeat(idx_width),
-				&device.name,
+				&description,
 				match &device.aliases {
 					Some(aliases) => {
 						if aliases.is_empty() {
@@ -283,40 +594,408 @@ impl DeviceRegistry {
 						}
 					}
 					_ => "None".to_owned(),
+				},
+				match &device.tags {
+					Some(tags) if !tags.is_empty() => format!(" (tags: {})", tags.join(", ")),
+					_ => String::new(),
 				}
 			);
 			idx += 1;
 			if idx > devices.len() {
-				println!("\n Done listing devices.");
+				result += "\n Done listing devices.\n";
 			} else {
-				println!("{}", "-".repeat(80));
+				result += &format!("{}\n", "-".repeat(80));
 			}
 		}
+		result
 	}
 
-	fn list_simple(devices: Vec<DeviceSpec>) {
+	fn list_simple(devices: &[&DeviceSpec]) -> String {
+		let mut result = String::new();
 		for device in devices {
-			println!(
-				"{:<31}\t{:<15}\t{}",
+			result += &format!(
+				"{:<31}\t{:<15}\t{}\n",
 				&device.id,
 				&device.arch.to_string().to_lowercase(),
 				&device.name
 			);
 		}
+		result
+	}
+
+	fn list_json(devices: &[&DeviceSpec], report: Option<&BuildReport>) -> Result<String> {
+		let listed: Vec<ListedDevice> = devices
+			.iter()
+			.map(|device| ListedDevice {
+				id: &device.id,
+				vendor: &device.vendor,
+				arch: device.arch.to_string().to_lowercase(),
+				name: &device.name,
+				aliases: device.aliases.as_deref().unwrap_or_default(),
+				tags: device.tags.as_deref().unwrap_or_default(),
+				compatible: device.of_compatible.as_deref(),
+				sizes: report.map(|report| {
+					ImageVariant::VARIANTS
+						.iter()
+						.map(|variant| {
+							let mib = device.size.get_variant_size(variant);
+							let last_build =
+								report.latest(&device.id, variant).map(|entry| ListedBuild {
+									mib: entry.size.div_ceil(1 << 20),
+									human: human_size(entry.size),
+									file: entry.file.clone(),
+									format: entry.format.clone(),
+									compression: entry.compression.clone(),
+								});
+							let size = ListedSize {
+								mib,
+								human: human_size(mib.saturating_mul(1 << 20)),
+								last_build,
+							};
+							(variant.to_string().to_lowercase(), size)
+						})
+						.collect()
+				}),
+			})
+			.collect();
+		Ok(serde_json::to_string_pretty(&listed)? + "\n")
 	}
 
-	pub fn list_devices(self, style: ListFormat) -> Result<()> {
-		let mut devices = self.devices;
+	/// Format the list of the devices, sorted by their IDs.
+	///
+	/// The sizes of the variants are included with `report`, along with the latest builds in it. They are not shown in the simple format.
+	pub fn format_list(&self, style: ListFormat, report: Option<&BuildReport>) -> Result<String> {
+		let mut devices: Vec<&DeviceSpec> = self.devices.iter().collect();
 		devices.sort_by_key(|f| f.id.clone());
+		Ok(match style {
+			ListFormat::Pretty => DeviceRegistry::list_pretty(&devices, report),
+			ListFormat::Simple => DeviceRegistry::list_simple(&devices),
+			ListFormat::Json => DeviceRegistry::list_json(&devices, report)?,
+		})
+	}
+
+	pub fn list_devices(self, style: ListFormat, report: Option<&BuildReport>) -> Result<()> {
 		info!("The list is being printned out to stdout.");
-		match style {
-			ListFormat::Pretty => {
-				DeviceRegistry::list_pretty(devices);
+		print!("{}", self.format_list(style, report)?);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		fixtures::{FIXTURE_REGISTRY, TestDir, assert_golden},
+		report::ReportEntry,
+	};
+	use std::{
+		fs,
+		os::unix::fs::{PermissionsExt, symlink},
+	};
+
+	/// Copy the directory tree `src` to `dst`, keeping the symbolic links.
+	fn copy_tree(src: &Path, dst: &Path) -> Result<()> {
+		for entry in WalkDir::new(src) {
+			let entry = entry?;
+			let target = dst.join(entry.path().strip_prefix(src)?);
+			if entry.path_is_symlink() {
+				symlink(fs::read_link(entry.path())?, &target)?;
+			} else if entry.file_type().is_dir() {
+				fs::create_dir_all(&target)?;
+			} else {
+				fs::copy(entry.path(), &target)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Set the permission bits of everything in `dir`, directories get the execute bits as well.
+	fn chmod_tree(dir: &Path, mode: u32) -> Result<()> {
+		for entry in WalkDir::new(dir).contents_first(true) {
+			let entry = entry?;
+			if entry.path_is_symlink() {
+				continue;
 			}
-			ListFormat::Simple => {
-				DeviceRegistry::list_simple(devices);
-			} // _ => todo!(),
+			let mode = if entry.file_type().is_dir() {
+				mode | 0o111
+			} else {
+				mode
+			};
+			fs::set_permissions(entry.path(), fs::Permissions::from_mode(mode))?;
 		}
 		Ok(())
 	}
+
+	/// Paths, sizes and modification times of everything in `dir`.
+	fn snapshot(dir: &Path) -> Result<Vec<(PathBuf, u64, std::time::SystemTime)>> {
+		let mut result = Vec::new();
+		for entry in WalkDir::new(dir).sort_by_file_name() {
+			let entry = entry?;
+			let metadata = entry.path().symlink_metadata()?;
+			result.push((entry.into_path(), metadata.len(), metadata.modified()?));
+		}
+		Ok(result)
+	}
+
+	#[test]
+	fn test_read_only_registry() -> Result<()> {
+		let dir = TestDir::new("ro-registry")?;
+		let registry = dir.join("devices");
+		let outside = dir.join("outside");
+		copy_tree(Path::new(FIXTURE_REGISTRY), &registry)?;
+		// A device directory linked from elsewhere, using a layout of the registry.
+		fs::create_dir_all(&outside)?;
+		fs::rename(
+			registry.join("other/gpt-layout"),
+			outside.join("gpt-layout"),
+		)?;
+		symlink(
+			outside.join("gpt-layout"),
+			registry.join("other/gpt-layout"),
+		)?;
+		// The same device linked twice is registered once.
+		symlink("gpt-efi", registry.join("fixture/gpt-efi-link"))?;
+		chmod_tree(&dir, 0o444)?;
+		let result = (|| -> Result<()> {
+			let before = snapshot(&dir)?;
+			let mut reg = DeviceRegistry::scan(&registry)?;
+			assert_eq!(reg.devices.len(), 4, "{:?}", reg.registry.keys());
+			let linked = reg
+				.devices
+				.iter()
+				.find(|d| d.id == "fixture-gpt-layout")
+				.unwrap();
+			assert_eq!(
+				linked.file_path,
+				registry.join("other/gpt-layout/device.toml")
+			);
+			assert!(!linked.partitions.is_empty());
+			let devices = std::mem::take(&mut reg.devices);
+			DeviceRegistry::check_devices(devices, true)?;
+			assert_eq!(snapshot(&dir)?, before, "The registry is modified");
+			Ok(())
+		})();
+		chmod_tree(&dir, 0o644)?;
+		result
+	}
+
+	#[test]
+	fn test_scan_errors() -> Result<()> {
+		let dir = TestDir::new("scan-errors")?;
+		copy_tree(Path::new(FIXTURE_REGISTRY), &dir)?;
+		// Without its compatible string, which would be another conflict
+		let template = fs::read_to_string(dir.join("fixture/mbr-uboot/device.toml"))?
+			.replace("compatible = \"fixture,mbr-uboot\"\n", "");
+		let broken = dir.join("wrong");
+		for (name, head) in [
+			("dup-id", "id = \"fixture-mbr-uboot\""),
+			("dup-alias", "id = \"dup-alias\"\naliases = [\"layout\"]"),
+			(
+				"alias-of-id",
+				"id = \"alias-of-id\"\naliases = [\"fixture-gpt-efi\"]",
+			),
+		] {
+			fs::create_dir_all(broken.join(name))?;
+			fs::write(
+				broken.join(name).join("device.toml"),
+				template.replace("id = \"fixture-mbr-uboot\"", head),
+			)?;
+		}
+		fs::create_dir_all(broken.join("garbage"))?;
+		fs::write(broken.join("garbage/device.toml"), "id = \n")?;
+		let result = DeviceRegistry::scan(&dir);
+		let e = format!("{:#}", result.err().unwrap());
+		assert!(
+			e.starts_with("4 error(s) occurred while assembling the device registry"),
+			"{}",
+			e
+		);
+		for (path, occupant) in [
+			("wrong/alias-of-id", Some("fixture/gpt-efi")),
+			("wrong/dup-alias", Some("other/gpt-layout")),
+			("wrong/dup-id", Some("fixture/mbr-uboot")),
+			("wrong/garbage", None),
+		] {
+			let path = dir.join(path).join("device.toml");
+			assert!(e.contains(&path.display().to_string()), "{}", e);
+			if let Some(occupant) = occupant {
+				let occupant = dir.join(occupant).join("device.toml");
+				assert!(
+					e.contains(&format!("- {}\n- {}", path.display(), occupant.display())),
+					"{}",
+					e
+				);
+			}
+		}
+		// Sorted by the path
+		let alias_of_id = e.find("alias-of-id/device.toml").unwrap();
+		let garbage = e.find("garbage/device.toml").unwrap();
+		assert!(alias_of_id < garbage, "{}", e);
+		Ok(())
+	}
+
+	#[test]
+	fn test_scan_lenient() -> Result<()> {
+		let dir = TestDir::new("scan-lenient")?;
+		copy_tree(Path::new(FIXTURE_REGISTRY), &dir)?;
+		fs::create_dir_all(dir.join("wrong/garbage"))?;
+		fs::write(dir.join("wrong/garbage/device.toml"), "id = \n")?;
+		assert!(DeviceRegistry::scan(&dir).is_err());
+		let reg = DeviceRegistry::scan_lenient(&dir)?;
+		assert_eq!(reg.devices.len(), 4);
+		reg.get(&"fixture-gpt-efi".to_owned())?;
+		// Conflicts are still fatal
+		// Without its compatible string, which would be another conflict
+		let template = fs::read_to_string(dir.join("fixture/mbr-uboot/device.toml"))?
+			.replace("compatible = \"fixture,mbr-uboot\"\n", "");
+		fs::create_dir_all(dir.join("wrong/dup-id"))?;
+		fs::write(dir.join("wrong/dup-id/device.toml"), &template)?;
+		let e = format!("{:#}", DeviceRegistry::scan_lenient(&dir).err().unwrap());
+		assert!(e.starts_with("1 error(s) occurred"), "{}", e);
+		assert!(e.contains("is already used as the ID"), "{}", e);
+		// Nothing left
+		let only_garbage = dir.join("wrong");
+		fs::remove_dir_all(only_garbage.join("dup-id"))?;
+		let e = format!(
+			"{:#}",
+			DeviceRegistry::scan_lenient(&only_garbage).err().unwrap()
+		);
+		assert!(e.contains("All 1 device spec(s)"), "{}", e);
+		Ok(())
+	}
+
+	#[test]
+	fn test_compatible() -> Result<()> {
+		let dir = TestDir::new("compatible")?;
+		copy_tree(Path::new(FIXTURE_REGISTRY), &dir)?;
+		let spec = |path: &str, compatible: &str| -> Result<()> {
+			let path = Path::new(path).join("device.toml");
+			let content = fs::read_to_string(Path::new(FIXTURE_REGISTRY).join(&path))?
+				.replace("compatible = \"fixture,mbr-uboot\"\n", "");
+			fs::write(
+				dir.join(&path),
+				format!("compatible = \"{}\"\n{}", compatible, content),
+			)?;
+			Ok(())
+		};
+		// Sharing a prefix
+		spec("fixture/gpt-efi", "vendor,board")?;
+		spec("fixture/mbr-uboot", "vendor,board-v2")?;
+		let get = |s: &str| DeviceRegistry::scan(&dir)?.get(&s.to_owned());
+		assert_eq!(get("vendor,board")?.id, "fixture-gpt-efi");
+		assert_eq!(get("vendor,board-v2")?.id, "fixture-mbr-uboot");
+		let e = get("vendor,boar").err().unwrap().to_string();
+		assert!(e.contains("compatible string"), "{}", e);
+		assert!(
+			DeviceRegistry::scan(&dir)?
+				.get_by_compatible("fixture-gpt-efi")
+				.is_err()
+		);
+		let reg = DeviceRegistry::scan(&dir)?.retain_compatible("vendor,board-v2")?;
+		assert_eq!(reg.devices.len(), 1);
+		assert_eq!(
+			reg.get(&"fixture-mbr-uboot".to_owned())?.id,
+			"fixture-mbr-uboot"
+		);
+		// The same as an alias of another device
+		spec("other/gpt-layout", "gpt-efi")?;
+		assert_eq!(get("gpt-efi")?.id, "fixture-gpt-efi");
+		assert_eq!(
+			DeviceRegistry::scan(&dir)?.get_by_compatible("gpt-efi")?.id,
+			"fixture-gpt-layout"
+		);
+		// Conflicts between the compatible strings are fatal
+		spec("other/gpt-layout", "vendor,board")?;
+		let e = format!("{:#}", DeviceRegistry::scan(&dir).err().unwrap());
+		assert!(
+			e.contains("Compatible string \"vendor,board\" of device"),
+			"{}",
+			e
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_retain_tags() -> Result<()> {
+		let ids = |reg: &DeviceRegistry| {
+			let mut ids: Vec<&str> = reg.devices.iter().map(|d| d.id.as_str()).collect();
+			ids.sort();
+			ids.join(" ")
+		};
+		let tags = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+		let reg = DeviceRegistry::scan(FIXTURE_REGISTRY)?.retain_tags(&tags(&["tier1"]))?;
+		assert_eq!(ids(&reg), "fixture-gpt-efi fixture-mbr-uboot");
+		// Names still resolve to the right devices.
+		assert_eq!(reg.get(&"gpt-efi".to_owned())?.id, "fixture-gpt-efi");
+		let reg = DeviceRegistry::scan(FIXTURE_REGISTRY)?.retain_tags(&tags(&["efi", "u-boot"]))?;
+		assert_eq!(ids(&reg), "fixture-gpt-efi fixture-mbr-uboot");
+		let reg = DeviceRegistry::scan(FIXTURE_REGISTRY)?.retain_tags(&tags(&["efi"]))?;
+		assert!(reg.get(&"layout".to_owned()).is_err());
+		let unknown = DeviceRegistry::scan(FIXTURE_REGISTRY)?
+			.retain_tags(&tags(&["tier1", "tier2"]))
+			.err()
+			.unwrap()
+			.to_string();
+		assert_eq!(
+			unknown,
+			"Unknown tag 'tier2'. Known tags are: efi, retro, riscv, tier1, u-boot"
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_list_devices() -> Result<()> {
+		let reg = DeviceRegistry::scan(FIXTURE_REGISTRY)?;
+		assert_golden(
+			"list-simple.txt",
+			&reg.format_list(ListFormat::Simple, None)?,
+		)?;
+		assert_golden(
+			"list-pretty.txt",
+			&reg.format_list(ListFormat::Pretty, None)?,
+		)?;
+		assert_golden("list.json", &reg.format_list(ListFormat::Json, None)?)?;
+		let mut report = BuildReport::default();
+		report.record(
+			"fixture-gpt-efi",
+			&ImageVariant::Base,
+			ReportEntry {
+				file: "os-amd64/base/rawimg/fixture/aosc-os_base_rawimg_fixture_fixture-gpt-efi_20250101_amd64.img.xz".to_owned(),
+				format: "rawimg".to_owned(),
+				compression: "xz".to_owned(),
+				size: 851_760_000,
+				run: "20250101-000000".to_owned(),
+				bootstrap: Default::default(),
+				root_account: "locked".to_owned(),
+				oobe_package: None,
+				disk_usage: Vec::new(),
+			},
+		);
+		// Too wide for the description line
+		for variant in ImageVariant::VARIANTS {
+			report.record(
+				"fixture-gpt-layout",
+				variant,
+				ReportEntry {
+					file: format!("{}.img.zst", variant),
+					format: "rawimg".to_owned(),
+					compression: "zstd".to_owned(),
+					size: 2_000_000_000,
+					run: "20250101-000000".to_owned(),
+					bootstrap: Default::default(),
+					root_account: "locked".to_owned(),
+					oobe_package: None,
+					disk_usage: Vec::new(),
+				},
+			);
+		}
+		assert_golden(
+			"list-pretty-sizes.txt",
+			&reg.format_list(ListFormat::Pretty, Some(&report))?,
+		)?;
+		assert_golden(
+			"list-sizes.json",
+			&reg.format_list(ListFormat::Json, Some(&report))?,
+		)
+	}
 }
@@ -0,0 +1,460 @@
+//! Module handling the build report in the output directory.
+//!
+//! Every successful build records its output in [`REPORT_FILE`] at the top of the output directory, replacing the previous build of the same device and variant.
+//! The sizes are shown by `list --show-sizes`, e.g. for release planning.
+//!
+//! Every image enqueued in the last build run is recorded too, including the failed and skipped ones. The same [`RunSummary`] is printed as a table at the end of the run.
+//!
+//! The usage of the partitions of a raw image is measured right before they are unmounted, see [`DiskUsage`].
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	bootstrap::BootstrapHashes, context::ImageVariant, filesystem::FilesystemType,
+	utils::human_size,
+};
+
+/// Name of the build report in the output directory.
+pub const REPORT_FILE: &str = "build-report.json";
+/// Version of the format of the build report.
+///
+/// Bump this on incompatible changes, reports of other versions are discarded.
+pub const REPORT_VERSION: u32 = 1;
+/// Percentage of the space used by a partition, above which a warning is printed.
+pub const USAGE_WARN_THRESHOLD: u8 = 90;
+
+/// Output of the latest build of a device and variant.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportEntry {
+	/// Path to the output, relative to the output directory.
+	pub file: String,
+	pub format: String,
+	pub compression: String,
+	/// Size of the output in bytes.
+	pub size: u64,
+	/// ID of the build run.
+	pub run: String,
+	/// SHA-256 of the aoscbootstrap files the distribution was bootstrapped with, empty if they were not recorded.
+	#[serde(default)]
+	pub bootstrap: BootstrapHashes,
+	/// The policy of the root account, `locked` or `password`, empty if not recorded.
+	#[serde(default)]
+	pub root_account: String,
+	/// The package of the OOBE wizard, `None` without the wizard or if not recorded.
+	#[serde(default)]
+	pub oobe_package: Option<String>,
+	/// Usage of the partitions of a raw image, empty for tarballs or if not recorded.
+	#[serde(default)]
+	pub disk_usage: Vec<DiskUsage>,
+}
+
+/// Usage of a partition mounted while building a raw image, in bytes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskUsage {
+	pub num: u32,
+	pub mountpoint: String,
+	pub filesystem: FilesystemType,
+	pub size: u64,
+	pub used: u64,
+	/// Space available to unprivileged users, without the blocks reserved for root.
+	pub free: u64,
+}
+
+impl DiskUsage {
+	/// Percentage of the space used, rounded up like df(1).
+	pub fn percent(&self) -> u64 {
+		let total = self.used + self.free;
+		if total == 0 {
+			return 0;
+		}
+		(self.used * 100).div_ceil(total)
+	}
+}
+
+/// Format the usage of the partitions as an aligned table.
+pub fn disk_usage_table(usage: &[DiskUsage]) -> String {
+	let mut result = format!(
+		"{:>4}  {:<16}{:<8}{:>12}{:>12}{:>12}{:>6}\n",
+		"PART", "MOUNTPOINT", "FS", "SIZE", "USED", "FREE", "USE%"
+	);
+	for x in usage {
+		result += &format!(
+			"{:>4}  {:<16}{:<8}{:>12}{:>12}{:>12}{:>5}%\n",
+			x.num,
+			x.mountpoint,
+			format!("{:?}", x.filesystem).to_lowercase(),
+			human_size(x.size),
+			human_size(x.used),
+			human_size(x.free),
+			x.percent()
+		);
+	}
+	result.trim_end().to_owned()
+}
+
+/// Status of an image enqueued in a build run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+	Ok,
+	Failed,
+	/// Not built, e.g. the binfmt_misc support is not available, or an earlier image failed without `--keep-going`.
+	Skipped,
+}
+
+/// An image enqueued in a build run.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RunEntry {
+	pub device: String,
+	pub variant: String,
+	pub status: RunStatus,
+	/// Filename of the image.
+	pub name: String,
+	/// The output of a built image, the same as recorded for its device and variant.
+	pub output: Option<ReportEntry>,
+	/// Time spent on the image in seconds.
+	pub secs: f64,
+	/// The build log of a failed image.
+	pub log: Option<String>,
+	/// Why the image failed or is skipped, on one line.
+	pub reason: Option<String>,
+}
+
+/// Images enqueued in a build run, in the order they are enqueued.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunSummary {
+	/// ID of the build run.
+	pub run: String,
+	pub images: Vec<RunEntry>,
+}
+
+impl RunSummary {
+	/// Number of images with `status`.
+	pub fn count(&self, status: RunStatus) -> usize {
+		self.images.iter().filter(|x| x.status == status).count()
+	}
+
+	/// Whether every image is built.
+	pub fn succeeded(&self) -> bool {
+		self.count(RunStatus::Ok) == self.images.len()
+	}
+
+	/// The counts of the images in one line.
+	pub fn counts(&self) -> String {
+		format!(
+			"{} of {} image(s) built, {} failed, {} skipped.",
+			self.count(RunStatus::Ok),
+			self.images.len(),
+			self.count(RunStatus::Failed),
+			self.count(RunStatus::Skipped)
+		)
+	}
+
+	/// Format the images as an aligned table of the device, variant, status, output, size and duration.
+	///
+	/// The failed and skipped images are followed by the reason, and the failed ones by their build log.
+	pub fn table(&self) -> String {
+		let header = ["DEVICE", "VARIANT", "STATUS", "OUTPUT", "SIZE", "DURATION"];
+		let rows: Vec<[String; 6]> = self
+			.images
+			.iter()
+			.map(|x| {
+				let (output, size) = match &x.output {
+					Some(o) => (o.file.clone(), human_size(o.size)),
+					None => (x.name.clone(), "-".to_owned()),
+				};
+				[
+					x.device.clone(),
+					x.variant.clone(),
+					format!("{:?}", x.status).to_lowercase(),
+					output,
+					size,
+					match x.status {
+						RunStatus::Skipped => "-".to_owned(),
+						_ => format!("{:.1} s", x.secs),
+					},
+				]
+			})
+			.collect();
+		let mut widths = header.map(|x| x.len());
+		for row in &rows {
+			for (w, cell) in widths.iter_mut().zip(row) {
+				*w = (*w).max(cell.chars().count());
+			}
+		}
+		let line = |cells: &[&str]| {
+			let mut s = String::from(" ");
+			for (i, (cell, w)) in cells.iter().zip(widths).enumerate() {
+				// Sizes and durations are right-aligned.
+				if i >= 4 {
+					s += &format!(" {:>w$}", cell, w = w);
+				} else {
+					s += &format!(" {:<w$}", cell, w = w);
+				}
+			}
+			s.trim_end().to_owned() + "\n"
+		};
+		let mut result = format!("Images of build run {}:\n", self.run);
+		result += &line(&header);
+		for (image, row) in self.images.iter().zip(&rows) {
+			result += &line(&row.each_ref().map(|x| x.as_str()));
+			if let Some(reason) = &image.reason {
+				result += &format!("    {}\n", reason);
+			}
+			if let Some(log) = &image.log {
+				result += &format!("    Log: {}\n", log);
+			}
+		}
+		result + &self.counts()
+	}
+}
+
+/// The latest builds in the output directory.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildReport {
+	pub version: u32,
+	/// Builds keyed by the device ID, and then the variant.
+	pub devices: BTreeMap<String, BTreeMap<String, ReportEntry>>,
+	/// All images enqueued in the last build run.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub last_run: Option<RunSummary>,
+}
+
+impl Default for BuildReport {
+	fn default() -> Self {
+		BuildReport {
+			version: REPORT_VERSION,
+			devices: BTreeMap::new(),
+			last_run: None,
+		}
+	}
+}
+
+/// The key of `variant` in the build report.
+fn variant_key(variant: &ImageVariant) -> String {
+	variant.to_string().to_lowercase()
+}
+
+impl BuildReport {
+	/// Parse a report, discarding it if it is of another version.
+	pub fn from_json(content: &str) -> Result<Self> {
+		let value: serde_json::Value = serde_json::from_str(content)?;
+		let version = value.get("version").and_then(|x| x.as_u64());
+		if version != Some(REPORT_VERSION as u64) {
+			warn!(
+				"Discarding build report of version {:?}, expected version {}",
+				version, REPORT_VERSION
+			);
+			return Ok(BuildReport::default());
+		}
+		Ok(serde_json::from_value(value)?)
+	}
+
+	/// Load the report in `outdir`, or an empty one if there's none.
+	pub fn load<P: AsRef<Path>>(outdir: P) -> Result<Self> {
+		let path = outdir.as_ref().join(REPORT_FILE);
+		if !path.exists() {
+			return Ok(BuildReport::default());
+		}
+		let content =
+			fs::read_to_string(&path).context(format!("Unable to read {}", path.display()))?;
+		BuildReport::from_json(&content).context(format!("Unable to parse {}", path.display()))
+	}
+
+	/// Save the report in `outdir`, replacing the previous one at once.
+	pub fn save<P: AsRef<Path>>(&self, outdir: P) -> Result<()> {
+		let path = outdir.as_ref().join(REPORT_FILE);
+		let tmp = path.with_extension(format!("json.{}", std::process::id()));
+		fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+		fs::rename(&tmp, &path).context(format!("Unable to save {}", path.display()))?;
+		Ok(())
+	}
+
+	/// Record a build, replacing the previous one of the same device and variant.
+	pub fn record(&mut self, device: &str, variant: &ImageVariant, entry: ReportEntry) {
+		self.devices
+			.entry(device.to_owned())
+			.or_default()
+			.insert(variant_key(variant), entry);
+	}
+
+	/// Record the images of the last build run in the report in `outdir`.
+	pub fn save_run<P: AsRef<Path>>(outdir: P, summary: &RunSummary) -> Result<()> {
+		let mut report = BuildReport::load(&outdir)?;
+		report.last_run = Some(summary.clone());
+		report.save(outdir)
+	}
+
+	/// The latest build of `device` and `variant`.
+	pub fn latest(&self, device: &str, variant: &ImageVariant) -> Option<&ReportEntry> {
+		self.devices.get(device)?.get(&variant_key(variant))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	fn entry(file: &str, size: u64) -> ReportEntry {
+		ReportEntry {
+			file: file.to_owned(),
+			format: "rawimg".to_owned(),
+			compression: "xz".to_owned(),
+			size,
+			run: "20250101-000000".to_owned(),
+			bootstrap: BootstrapHashes::from([(
+				"/usr/share/aoscbootstrap/config/aosc-mainline.toml".to_owned(),
+				"0".repeat(64),
+			)]),
+			root_account: "locked".to_owned(),
+			oobe_package: None,
+			disk_usage: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn test_disk_usage() -> Result<()> {
+		let partition = |num, mountpoint: &str, used, free| DiskUsage {
+			num,
+			mountpoint: mountpoint.to_owned(),
+			filesystem: FilesystemType::Ext4,
+			size: used + free,
+			used,
+			free,
+		};
+		let usage = [
+			partition(1, "/efi", 1, 2),
+			partition(2, "/", 3 << 30, 1 << 30),
+		];
+		assert_eq!(usage[0].percent(), 34);
+		assert_eq!(usage[1].percent(), 75);
+		assert_eq!(partition(3, "/", 0, 0).percent(), 0);
+		let table = disk_usage_table(&usage);
+		let lines: Vec<_> = table.lines().collect();
+		assert_eq!(lines.len(), 3);
+		assert!(lines[0].starts_with("PART  MOUNTPOINT"));
+		assert!(lines[2].contains("/ ") && lines[2].contains("ext4"));
+		assert!(lines[2].ends_with("3.0 GiB     1.0 GiB   75%"), "{}", table);
+		// Recorded in the build report, and missing in the older ones
+		let mut entry = entry("a.img.xz", 1);
+		entry.disk_usage = usage.to_vec();
+		let value = serde_json::to_value(&entry)?;
+		assert_eq!(value["disk_usage"][1]["filesystem"], "ext4");
+		assert_eq!(serde_json::from_value::<ReportEntry>(value)?, entry);
+		let mut value = serde_json::to_value(self::entry("b.img.xz", 1))?;
+		value.as_object_mut().unwrap().remove("disk_usage");
+		assert!(
+			serde_json::from_value::<ReportEntry>(value)?
+				.disk_usage
+				.is_empty()
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_run_summary() -> Result<()> {
+		let dir = TestDir::new("run")?;
+		let image = |device: &str, variant: &str, status| RunEntry {
+			device: device.to_owned(),
+			variant: variant.to_owned(),
+			status,
+			name: format!("{}-{}.img.xz", device, variant),
+			output: None,
+			secs: 0.0,
+			log: None,
+			reason: None,
+		};
+		let built = entry("os-arm64/base/rawimg/rpi/rpi-5b-base.img.xz", 1536 << 20);
+		let summary = RunSummary {
+			run: "20250101-000000".to_owned(),
+			images: vec![
+				RunEntry {
+					reason: Some("binfmt_misc support for riscv64 is not available".to_owned()),
+					..image("visionfive-2", "base", RunStatus::Skipped)
+				},
+				RunEntry {
+					output: Some(built.clone()),
+					secs: 1234.56,
+					..image("rpi-5b", "base", RunStatus::Ok)
+				},
+				RunEntry {
+					secs: 61.0,
+					log: Some("/var/log/mkrawimg/rpi-5b-desktop.log".to_owned()),
+					reason: Some("Failed to install packages: exit status: 100".to_owned()),
+					..image("rpi-5b", "desktop", RunStatus::Failed)
+				},
+			],
+		};
+		assert!(!summary.succeeded());
+		assert_eq!(
+			summary.counts(),
+			"1 of 3 image(s) built, 1 failed, 1 skipped."
+		);
+		crate::fixtures::assert_golden("run-summary.txt", &summary.table())?;
+		// The table is printed from the same data as recorded in the report.
+		let mut report = BuildReport::default();
+		report.record("rpi-5b", &ImageVariant::Base, built.clone());
+		report.save(&dir)?;
+		BuildReport::save_run(&dir, &summary)?;
+		let loaded = BuildReport::load(&dir)?;
+		let last_run = loaded.last_run.as_ref().unwrap();
+		assert_eq!(last_run, &summary);
+		assert_eq!(last_run.table(), summary.table());
+		assert_eq!(
+			loaded.latest("rpi-5b", &ImageVariant::Base),
+			last_run.images[1].output.as_ref()
+		);
+		let value: serde_json::Value =
+			serde_json::from_str(&fs::read_to_string(dir.join(REPORT_FILE))?)?;
+		assert_eq!(value["last_run"]["images"][2]["status"], "failed");
+		assert_eq!(
+			value["last_run"]["images"][1]["output"]["file"],
+			built.file.as_str()
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_build_report() -> Result<()> {
+		let dir = TestDir::new("report")?;
+		assert_eq!(BuildReport::load(&dir)?, BuildReport::default());
+		let mut report = BuildReport::default();
+		report.record("rpi-5b", &ImageVariant::Base, entry("base.img.xz", 1024));
+		report.record("rpi-5b", &ImageVariant::Base, entry("base.1.img.xz", 2048));
+		report.record(
+			"rpi-5b",
+			&ImageVariant::Desktop,
+			entry("desktop.img.xz", 4096),
+		);
+		report.save(&dir)?;
+		let loaded = BuildReport::load(&dir)?;
+		assert_eq!(loaded, report);
+		assert_eq!(
+			loaded.latest("rpi-5b", &ImageVariant::Base),
+			Some(&entry("base.1.img.xz", 2048))
+		);
+		assert_eq!(loaded.latest("rpi-5b", &ImageVariant::Server), None);
+		assert_eq!(loaded.latest("pc-efi", &ImageVariant::Base), None);
+		// Entries recorded without the hashes are still read.
+		fs::write(
+			dir.join(REPORT_FILE),
+			r#"{"version": 1, "devices": {"rpi-5b": {"base": {"file": "a.img", "format": "rawimg", "compression": "none", "size": 1, "run": "x"}}}}"#,
+		)?;
+		let loaded = BuildReport::load(&dir)?;
+		assert!(
+			loaded
+				.latest("rpi-5b", &ImageVariant::Base)
+				.is_some_and(|x| x.bootstrap.is_empty())
+		);
+		// The last run is optional.
+		assert_eq!(loaded.last_run, None);
+		// Reports of other versions are discarded.
+		fs::write(dir.join(REPORT_FILE), r#"{"version": 0, "images": []}"#)?;
+		assert_eq!(BuildReport::load(&dir)?, BuildReport::default());
+		Ok(())
+	}
+}
@@ -0,0 +1,139 @@
+//! Module handling the resume of interrupted builds of raw images.
+//!
+//! The stages a build has completed are recorded in [`STATE_FILE`] in the sketch directory, along with the partition map data of the raw image.
+//! With `--resume`, a build continues from the first incomplete stage, instead of starting over with a new raw image.
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::device::PartitionMapData;
+
+/// Name of the state file in the sketch directory.
+pub const STATE_FILE: &str = "build-state.json";
+
+/// Stages of a build of a raw image, in the order they run.
+///
+/// A stage interrupted in the middle, e.g. while copying the distribution, runs again from its beginning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildStage {
+	Partitioned,
+	Formatted,
+	RootfsInstalled,
+	PackagesInstalled,
+	PostinstDone,
+	BootloadersApplied,
+}
+
+/// Progress of a build, saved after each stage.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BuildState {
+	/// Size of the raw image in bytes, images of other sizes can not be resumed.
+	pub image_size: u64,
+	/// The last completed stage.
+	pub completed: Option<BuildStage>,
+	/// The partition map data, known once the image is partitioned.
+	pub pm_data: Option<PartitionMapData>,
+}
+
+impl BuildState {
+	pub fn new(image_size: u64) -> Self {
+		BuildState {
+			image_size,
+			completed: None,
+			pm_data: None,
+		}
+	}
+
+	/// Load the state in `sketch_dir`, if there's one.
+	pub fn load<P: AsRef<Path>>(sketch_dir: P) -> Result<Option<Self>> {
+		let path = sketch_dir.as_ref().join(STATE_FILE);
+		if !path.exists() {
+			return Ok(None);
+		}
+		let content =
+			fs::read_to_string(&path).context(format!("Unable to read {}", path.display()))?;
+		let state = serde_json::from_str(&content)
+			.context(format!("Unable to parse {}", path.display()))?;
+		Ok(Some(state))
+	}
+
+	/// Save the state in `sketch_dir`, replacing the previous one at once.
+	pub fn save<P: AsRef<Path>>(&self, sketch_dir: P) -> Result<()> {
+		let path = sketch_dir.as_ref().join(STATE_FILE);
+		let tmp = path.with_extension(format!("json.{}", std::process::id()));
+		fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+		fs::rename(&tmp, &path).context(format!("Unable to save {}", path.display()))?;
+		Ok(())
+	}
+
+	/// Remove the state in `sketch_dir`, if there's one.
+	pub fn remove<P: AsRef<Path>>(sketch_dir: P) -> Result<()> {
+		let path = sketch_dir.as_ref().join(STATE_FILE);
+		match fs::remove_file(&path) {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+				Err(e).context(format!("Unable to remove {}", path.display()))
+			}
+			_ => Ok(()),
+		}
+	}
+
+	/// Whether `stage` is completed.
+	pub fn done(&self, stage: BuildStage) -> bool {
+		self.completed >= Some(stage)
+	}
+
+	/// Mark `stage` as completed, and save the state in `sketch_dir`.
+	pub fn complete<P: AsRef<Path>>(&mut self, stage: BuildStage, sketch_dir: P) -> Result<()> {
+		self.completed = Some(stage);
+		self.save(sketch_dir)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::device::PartitionData;
+	use crate::fixtures::TestDir;
+
+	#[test]
+	fn test_build_state() -> Result<()> {
+		let dir = TestDir::new("resume")?;
+		assert!(BuildState::load(&dir)?.is_none());
+		let mut state = BuildState::new(1048576);
+		assert!(!state.done(BuildStage::Partitioned));
+		state.pm_data = Some(PartitionMapData {
+			uuid: "01234567-89ab-cdef-0123-456789abcdef".to_owned(),
+			data: [(
+				2,
+				PartitionData {
+					num: 2,
+					part_uuid: "00000002-0000-0000-0000-000000000000".to_owned(),
+					fs_uuid: None,
+				},
+			)]
+			.into(),
+		});
+		state.complete(BuildStage::Formatted, &dir)?;
+		let state = BuildState::load(&dir)?.context("The state is not saved")?;
+		assert_eq!(state.image_size, 1048576);
+		assert!(state.done(BuildStage::Partitioned));
+		assert!(state.done(BuildStage::Formatted));
+		assert!(!state.done(BuildStage::RootfsInstalled));
+		let pm_data = state
+			.pm_data
+			.context("The partition map data is not saved")?;
+		assert_eq!(
+			pm_data.data[&2].part_uuid,
+			"00000002-0000-0000-0000-000000000000"
+		);
+		assert_eq!(pm_data.data[&2].fs_uuid, None);
+		let content = fs::read_to_string(dir.join(STATE_FILE))?;
+		assert!(content.contains("\"formatted\""), "{}", content);
+		BuildState::remove(&dir)?;
+		assert!(BuildState::load(&dir)?.is_none());
+		// Removing it again is fine
+		BuildState::remove(&dir)
+	}
+}
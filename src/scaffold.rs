@@ -0,0 +1,256 @@
+//! Module handling the scaffolding of new device directories.
+//!
+//! The `new-device` action writes `<registry>/<vendor>/<id>/device.toml`, pre-filled with the values from the command line and a two-partition layout, and a stub `postinst.bash`.
+//! Existing files are never overwritten. The scaffold is checked like any other device spec right after it is written, and removed again if it is invalid.
+use std::{
+	fs::{self, File},
+	io::Write,
+	os::unix::fs::OpenOptionsExt,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+	device::{DeviceArch, DeviceSpec, PartitionMapType},
+	validate::FieldClass,
+};
+
+/// Name of the stub post installation script.
+const POSTINST_NAME: &str = "postinst.bash";
+
+/// The stub post installation script, documenting the variables of `spec.sh`.
+const POSTINST_STUB: &str = r#"#!/bin/bash
+# Post installation script, run in the target system after the BSP packages
+# are installed and /etc/fstab is generated.
+#
+# The variables of spec.sh are defined, including:
+#
+# - DEVICE_ID, DEVICE_VENDOR, DEVICE_NAME, DEVICE_COMPATIBLE: From device.toml.
+# - VARIANT: The variant being built, one of base, desktop and server.
+# - ARCH: The target architecture, e.g. arm64.
+# - IMAGE_SIZE_MIB: Size of the image in MiB.
+# - LOOPDEV: The loop device the image is attached to.
+# - NUM_PARTITIONS: Number of the partitions.
+# - ROOTPART: The root partition, e.g. /dev/loop0p2.
+# - PARTITION_MAP: Either mbr or gpt.
+# - DISKUUID: UUID of the partition table.
+# - KERNEL_CMDLINE: The kernel command line with root=, empty if not defined.
+# - PARTx_PARTUUID, PARTx_FSUUID: Partition and filesystem UUID of the x-th
+#   partition, e.g. PART2_PARTUUID.
+# - PARTx_MOUNTPOINT, PARTx_FSTYPE, PARTx_USAGE: Mountpoint, filesystem and
+#   usage of the x-th partition.
+# - ROOT_PARTUUID, ROOT_FSUUID, BOOT_PARTUUID, BOOT_FSUUID: UUIDs of the root
+#   and the boot partition.
+# - USERS: Names of the users created in the image, separated by spaces.
+#
+# Please refer to the documentation of the device specification for the full
+# list.
+
+echo "Nothing to set up for $DEVICE_ID yet."
+"#;
+
+/// The device spec of a new device.
+pub fn device_toml(
+	vendor: &str,
+	id: &str,
+	arch: &DeviceArch,
+	partition_map: &PartitionMapType,
+) -> String {
+	let boot_partition = match partition_map {
+		PartitionMapType::GPT => {
+			"type = \"esp\"\n\
+			usage = \"boot\"\n\
+			size_in_sectors = 614400\n\
+			mountpoint = \"/efi\"\n\
+			filesystem = \"fat32\"\n\
+			label = \"EFI\"\n\
+			fs_label = \"EFI\"\n"
+		}
+		PartitionMapType::MBR => {
+			"type = \"byte\"\n\
+			# FAT32 with LBA\n\
+			byte = 0x0c\n\
+			usage = \"boot\"\n\
+			size_in_sectors = 614400\n\
+			mountpoint = \"/boot\"\n\
+			filesystem = \"fat32\"\n\
+			fs_label = \"BOOT\"\n"
+		}
+	};
+	format!(
+		r#"# Device specification, see the documentation of mkrawimg for all fields.
+
+id = "{id}"
+# Other names identifying the device, unique across the registry.
+aliases = []
+vendor = "{vendor}"
+arch = "{arch}"
+# TODO: The name of the device, for humans.
+name = "{vendor} {id}"
+# TODO: The most relevant value of the compatible string in the root of the
+# device tree, if it has one.
+# compatible = "{vendor},{id}"
+
+# TODO: Packages of the kernel, the firmware and the bootloader.
+bsp_packages = []
+
+partition_map = "{partition_map}"
+num_partitions = 2
+
+# Size of the uncompressed raw image, for each variant, in MiB.
+[size]
+base = 6144
+desktop = 22528
+server = 6144
+
+[[partitions]]
+no = 1
+{boot_partition}
+# Expanded to the whole disk once flashed and booted.
+[[partitions]]
+no = 2
+type = "linux"
+usage = "rootfs"
+size_in_sectors = 0
+mountpoint = "/"
+filesystem = "ext4"
+fs_label = "AOSC OS"
+
+# TODO: How to make the image bootable, e.g. a script in this directory.
+# [[bootloader]]
+# type = "script"
+# name = "apply-bootloader.bash"
+"#,
+		arch = arch,
+		partition_map = partition_map.to_string().to_lowercase(),
+	)
+}
+
+/// Create the directory of a new device in `registry_dir`, returning the path to its `device.toml`.
+pub fn new_device(
+	registry_dir: &Path,
+	vendor: &str,
+	id: &str,
+	arch: &DeviceArch,
+	partition_map: &PartitionMapType,
+) -> Result<PathBuf> {
+	for (field, value) in [("vendor", vendor), ("id", id)] {
+		FieldClass::Identifier.validate(field, value)?;
+		// Allowed in IDs, but not as directory names.
+		if value == "." || value == ".." {
+			bail!(
+				"Field {} ('{}') is not a valid directory name",
+				field,
+				value
+			);
+		}
+	}
+	let dir = registry_dir.join(vendor).join(id);
+	let files = [
+		(
+			dir.join("device.toml"),
+			device_toml(vendor, id, arch, partition_map),
+			0o644,
+		),
+		(dir.join(POSTINST_NAME), POSTINST_STUB.to_owned(), 0o755),
+	];
+	if let Some((path, _, _)) = files.iter().find(|(path, _, _)| path.exists()) {
+		bail!(
+			"{} already exists, refusing to overwrite it",
+			path.display()
+		);
+	}
+	let created_dirs: Vec<PathBuf> = [registry_dir.join(vendor), dir.clone()]
+		.into_iter()
+		.filter(|x| !x.exists())
+		.collect();
+	fs::create_dir_all(&dir).context(format!("Unable to create {}", dir.display()))?;
+	let result = (|| -> Result<()> {
+		for (path, content, mode) in &files {
+			File::options()
+				.write(true)
+				.create_new(true)
+				.mode(*mode)
+				.open(path)
+				.and_then(|mut x| x.write_all(content.as_bytes()))
+				.context(format!("Unable to write {}", path.display()))?;
+		}
+		DeviceSpec::from_path(&files[0].0)?
+			.check()
+			.context("The generated device spec is invalid")
+	})();
+	if result.is_err() {
+		for (path, _, _) in &files {
+			fs::remove_file(path).ok();
+		}
+		for dir in created_dirs.iter().rev() {
+			fs::remove_dir(dir).ok();
+		}
+	}
+	result.map(|_| files[0].0.clone())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	#[test]
+	fn test_new_device() -> Result<()> {
+		let registry = TestDir::new("scaffold")?;
+		for (id, partition_map) in [
+			("board-gpt", PartitionMapType::GPT),
+			("board-mbr", PartitionMapType::MBR),
+		] {
+			let path = new_device(&registry, "vendor", id, &DeviceArch::arm64, &partition_map)?;
+			assert_eq!(path, registry.join("vendor").join(id).join("device.toml"));
+			let device = DeviceSpec::from_path(&path)?;
+			assert_eq!(device.id, id);
+			assert_eq!(device.partition_map, partition_map);
+			assert_eq!(device.unknown_fields, vec![]);
+			assert!(path.with_file_name(POSTINST_NAME).is_file());
+			assert_eq!(
+				device.find_postinst_scripts(&crate::context::ImageVariant::Base)?,
+				vec![path.with_file_name(POSTINST_NAME)]
+			);
+		}
+		// Existing files are not overwritten
+		let path = registry.join("vendor/board-gpt/device.toml");
+		fs::write(&path, "id = \"edited\"\n")?;
+		assert!(
+			new_device(
+				&registry,
+				"vendor",
+				"board-gpt",
+				&DeviceArch::arm64,
+				&PartitionMapType::GPT
+			)
+			.is_err()
+		);
+		assert_eq!(fs::read_to_string(&path)?, "id = \"edited\"\n");
+		// Invalid IDs and vendors, nothing is created
+		for (vendor, id) in [
+			("vendor", "board 1"),
+			("vendor", ".."),
+			("véndor", "board"),
+			("vendor", ""),
+		] {
+			assert!(
+				new_device(
+					&registry,
+					vendor,
+					id,
+					&DeviceArch::arm64,
+					&PartitionMapType::GPT
+				)
+				.is_err(),
+				"{}/{} is accepted",
+				vendor,
+				id
+			);
+		}
+		assert!(!registry.join("véndor").exists());
+		Ok(())
+	}
+}
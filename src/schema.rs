@@ -0,0 +1,671 @@
+//! Module detecting the unknown fields of device specifications.
+//!
+//! Unknown fields are ignored when the specs are deserialized, so a typo like `bsp_package` silently drops the setting.
+//! They are collected against the known fields, which are warned about when the spec is loaded, and rejected by `check --strict`.
+//! The fields of the structs are taken from their `Deserialize` implementations.
+//! The partitions, whose types are flattened into them, and the tagged enums have no such list, so their fields are listed here.
+//!
+//! The device spec and all of its tables are covered.
+use std::fmt::Display;
+
+use anyhow::{Result, bail};
+use serde::{
+	Deserializer,
+	de::{DeserializeOwned, Visitor},
+	forward_to_deserialize_any,
+};
+
+use crate::{
+	content::PartitionContent,
+	device::{DeviceSpec, ImageVariantSizes, is_replace_flag},
+	hook::HookSpec,
+	secureboot::SecureBootSpec,
+	sources::{SourceEntry, VariantSources},
+	user::UserSpec,
+	utils::nearest_name,
+};
+
+/// Fields of [`crate::partition::PartitionSpec`] shared by all partition types.
+const PARTITION_FIELDS: &[&str] = &[
+	"num",
+	"no",
+	"type",
+	"start_sector",
+	"size_in_sectors",
+	"label",
+	"mountpoint",
+	"filesystem",
+	"mount_opts",
+	"fs_label",
+	"fs_uuid",
+	"usage",
+	"format_on_first_boot",
+	"content",
+];
+
+/// Fields specific to the partition types, keyed by `type`.
+const PARTITION_TYPE_FIELDS: &[(&str, &[&str])] = &[
+	("uuid", &["uuid"]),
+	("byte", &["byte"]),
+	("nested", &["table_type", "partitions"]),
+];
+
+/// Fields of the variants of [`crate::bootloader::BootloaderSpec`], keyed by `type`.
+const BOOTLOADER_FIELDS: &[(&str, &[&str])] = &[
+	("script", &["name"]),
+	(
+		"flash_partition",
+		&["path", "partition", "source", "sha256"],
+	),
+	(
+		"flash_offset",
+		&["path", "offset", "max_size", "source", "sha256"],
+	),
+	("host_command", &["command", "output", "flash"]),
+	("grub", &["target", "efi_directory", "removable"]),
+	("systemd_boot", &["entry_title", "kernel_cmdline"]),
+	("extlinux", &["dir", "cmdline", "fdt", "fdtdir"]),
+];
+
+/// Fields of [`crate::swap::SwapSpec`] besides `type`, for each type.
+const SWAP_FIELDS: &[(&str, &[&str])] = &[
+	("file", &["size_mib"]),
+	("zram", &["size_mib", "compression"]),
+];
+
+/// Fields of [`crate::bootloader::FlashTarget`], the `flash` table of the `host_command` bootloaders.
+const FLASH_TARGET_FIELDS: &[&str] = &["partition", "offset", "max_size"];
+
+/// Fields of the struct `T` listed by its derived `Deserialize` implementation, including the aliases.
+fn struct_fields<T: DeserializeOwned>() -> &'static [&'static str] {
+	let mut fields: &'static [&'static str] = &[];
+	// The probe always fails, after catching the fields.
+	let _ = T::deserialize(FieldsProbe(&mut fields));
+	fields
+}
+
+/// A deserializer catching the fields of the struct deserialized from it.
+struct FieldsProbe<'a>(&'a mut &'static [&'static str]);
+
+impl<'de> Deserializer<'de> for FieldsProbe<'_> {
+	type Error = serde::de::value::Error;
+
+	fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+		Err(serde::de::Error::custom("Not a struct"))
+	}
+
+	fn deserialize_struct<V: Visitor<'de>>(
+		self,
+		_: &'static str,
+		fields: &'static [&'static str],
+		_: V,
+	) -> Result<V::Value, Self::Error> {
+		*self.0 = fields;
+		Err(serde::de::Error::custom("Fields caught"))
+	}
+
+	forward_to_deserialize_any! {
+		bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+		option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+	}
+}
+
+/// A field of the device spec which is not known.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownField {
+	/// Where the field is, e.g. `partition 2`.
+	pub location: String,
+	pub key: String,
+	/// The known field closest to `key`.
+	pub suggestion: Option<&'static str>,
+}
+
+impl Display for UnknownField {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Unknown field '{}' in {}", self.key, self.location)?;
+		if let Some(s) = self.suggestion {
+			write!(f, ", did you mean '{}'?", s)?;
+		}
+		Ok(())
+	}
+}
+
+/// Collect the unknown fields of the raw device spec `table`, in the order they are defined.
+///
+/// Values of unexpected types are left to the deserialization.
+pub fn unknown_fields(table: &toml::Table) -> Vec<UnknownField> {
+	let mut unknown = Vec::new();
+	collect(
+		table,
+		struct_fields::<DeviceSpec>(),
+		"the device spec",
+		&mut unknown,
+	);
+	// Taken out when the spec is merged on top of the one it inherits or overrides.
+	unknown.retain(|f| !is_replace_flag(&f.key));
+	if let Some(size) = table.get("size").and_then(|x| x.as_table()) {
+		collect(
+			size,
+			struct_fields::<ImageVariantSizes>(),
+			"size",
+			&mut unknown,
+		);
+	}
+	for key in ["partitions", "partition"] {
+		for (i, p) in tables(table, key).enumerate() {
+			collect_partition(p, i, None, &mut unknown);
+		}
+	}
+	for key in ["bootloaders", "bootloader"] {
+		for (i, b) in tables(table, key).enumerate() {
+			let location = match b.get("type").and_then(|x| x.as_str()) {
+				Some(t) => format!("bootloader {} ({})", i + 1, t),
+				None => format!("bootloader {}", i + 1),
+			};
+			let fields = type_fields(b, BOOTLOADER_FIELDS);
+			collect(
+				b,
+				&[&["type"], fields.as_slice()].concat(),
+				&location,
+				&mut unknown,
+			);
+			if let Some(flash) = b.get("flash").and_then(|x| x.as_table()) {
+				collect(
+					flash,
+					FLASH_TARGET_FIELDS,
+					&format!("flash of {}", location),
+					&mut unknown,
+				);
+			}
+		}
+	}
+	for key in ["users", "user"] {
+		for (i, u) in tables(table, key).enumerate() {
+			let location = match u.get("name").and_then(|x| x.as_str()) {
+				Some(name) => format!("user {} ({})", i + 1, name),
+				None => format!("user {}", i + 1),
+			};
+			collect(u, struct_fields::<UserSpec>(), &location, &mut unknown);
+		}
+	}
+	for key in ["hooks", "hook"] {
+		for (i, h) in tables(table, key).enumerate() {
+			let location = match h.get("stage").and_then(|x| x.as_str()) {
+				Some(stage) => format!("hook {} ({})", i + 1, stage),
+				None => format!("hook {}", i + 1),
+			};
+			collect(h, struct_fields::<HookSpec>(), &location, &mut unknown);
+		}
+	}
+	if let Some(sources) = table.get("sources").and_then(|x| x.as_table()) {
+		collect(
+			sources,
+			struct_fields::<VariantSources>(),
+			"sources",
+			&mut unknown,
+		);
+		for variant in sources.keys() {
+			for (i, s) in tables(sources, variant).enumerate() {
+				let location = format!("source {} of {}", i + 1, variant);
+				collect(s, struct_fields::<SourceEntry>(), &location, &mut unknown);
+			}
+		}
+	}
+	if let Some(secureboot) = table.get("efi_secureboot").and_then(|x| x.as_table()) {
+		collect(
+			secureboot,
+			struct_fields::<SecureBootSpec>(),
+			"efi_secureboot",
+			&mut unknown,
+		);
+	}
+	if let Some(swap) = table.get("swap").and_then(|x| x.as_table()) {
+		let fields = type_fields(swap, SWAP_FIELDS);
+		collect(
+			swap,
+			&[&["type"], fields.as_slice()].concat(),
+			"swap",
+			&mut unknown,
+		);
+	}
+	unknown
+}
+
+/// Fail if there are any unknown fields.
+pub fn deny_unknown_fields(unknown: &[UnknownField]) -> Result<()> {
+	if !unknown.is_empty() {
+		bail!(
+			"The device spec contains unknown fields:\n{}",
+			unknown
+				.iter()
+				.map(|x| x.to_string())
+				.collect::<Vec<_>>()
+				.join("\n")
+		);
+	}
+	Ok(())
+}
+
+/// The tables in the array `key` of `table`.
+fn tables<'a>(table: &'a toml::Table, key: &str) -> impl Iterator<Item = &'a toml::Table> {
+	table
+		.get(key)
+		.and_then(|x| x.as_array())
+		.into_iter()
+		.flatten()
+		.filter_map(|x| x.as_table())
+}
+
+/// Fields specific to the `type` of `table`.
+///
+/// Without a type (e.g. in a spec override), the fields of all types are accepted.
+fn type_fields(
+	table: &toml::Table,
+	types: &[(&str, &'static [&'static str])],
+) -> Vec<&'static str> {
+	match table.get("type").and_then(|x| x.as_str()) {
+		Some(t) => types
+			.iter()
+			.filter(|(name, _)| *name == t)
+			.flat_map(|(_, fields)| fields.iter().copied())
+			.collect(),
+		None => types
+			.iter()
+			.flat_map(|(_, fields)| fields.iter().copied())
+			.collect(),
+	}
+}
+
+/// Collect the unknown fields of the partition `table`, which is the `index`-th one of `parent`.
+fn collect_partition(
+	table: &toml::Table,
+	index: usize,
+	parent: Option<&str>,
+	unknown: &mut Vec<UnknownField>,
+) {
+	let num = table
+		.get("num")
+		.or_else(|| table.get("no"))
+		.and_then(|x| x.as_integer());
+	let mut location = match num {
+		Some(n) => format!("partition {}", n),
+		None => format!("partition #{}", index + 1),
+	};
+	if let Some(parent) = parent {
+		location = format!("{} of {}", location, parent);
+	}
+	let fields = [PARTITION_FIELDS, &type_fields(table, PARTITION_TYPE_FIELDS)].concat();
+	collect(table, &fields, &location, unknown);
+	if let Some(content) = table.get("content").and_then(|x| x.as_table()) {
+		collect(
+			content,
+			struct_fields::<PartitionContent>(),
+			&format!("content of {}", location),
+			unknown,
+		);
+	}
+	if table.get("type").and_then(|x| x.as_str()) == Some("nested") {
+		for (i, p) in tables(table, "partitions").enumerate() {
+			collect_partition(p, i, Some(&location), unknown);
+		}
+	}
+}
+
+fn collect(
+	table: &toml::Table,
+	fields: &[&'static str],
+	location: &str,
+	unknown: &mut Vec<UnknownField>,
+) {
+	for key in table.keys() {
+		if !fields.contains(&key.as_str()) {
+			unknown.push(UnknownField {
+				location: location.to_owned(),
+				key: key.to_owned(),
+				suggestion: nearest_name(key, fields),
+			});
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+
+	use super::*;
+	use crate::{fixtures::fixture_device, registry::DeviceRegistry};
+
+	/// A device spec setting every field, one per line.
+	const FULL_SPEC: &str = r#"
+id = "full"
+aliases = ["full-alias"]
+tags = ["fixture"]
+distro = "AOSC"
+vendor = "fixture"
+arch = "arm64"
+soc_vendor = "fixture"
+name = "Full"
+model = "Full Model"
+compatible = "fixture,full"
+bsp_packages = ["linux+kernel"]
+enable_services = ["a.service"]
+disable_services = ["b.service"]
+user_groups = ["audio"]
+user_shell = "/bin/bash"
+user_uid = 1000
+initrdless = true
+defer_triggers = true
+requires_target_exec = true
+postinst_scripts = ["postinst.sh"]
+kernel_cmdline = ["quiet"]
+partition_map = "gpt"
+num_partitions = 3
+layout = "fixture"
+inherits = "fixture/base"
+allow_root_login = false
+scrub = false
+skip_default_user = true
+oobe_wizard = true
+oobe_package = { base = "oobe-base" }
+devena_firstboot_target = "graphical.target"
+[size]
+base = 6144
+desktop = 24576
+server = 8192
+[[partition]]
+num = 1
+type = "efi"
+start_sector = 2048
+size_in_sectors = 614400
+label = "EFI"
+mountpoint = "/efi"
+filesystem = "fat32"
+mount_opts = ["umask=077"]
+fs_label = "EFI"
+fs_uuid = "abcd1234"
+usage = "boot"
+format_on_first_boot = false
+[partition.content]
+source = "dir"
+path = "efi"
+[[partition]]
+no = 2
+type = "uuid"
+uuid = "0fc63daf-8483-4772-8e79-3d69d8477de4"
+size_in_sectors = 0
+usage = "rootfs"
+[[partition]]
+num = 3
+type = "nested"
+table_type = "mbr"
+size_in_sectors = 204800
+usage = "other"
+[[partition.partitions]]
+num = 5
+type = "byte"
+byte = 0x83
+size_in_sectors = 2048
+usage = "data"
+[[bootloader]]
+type = "script"
+name = "apply-bootloader.sh"
+[[bootloader]]
+type = "flash_partition"
+path = "u-boot.img"
+partition = 1
+source = "device_dir"
+sha256 = "00"
+[[bootloader]]
+type = "flash_offset"
+path = "u-boot.bin"
+offset = 0x2000
+max_size = 0xfe000
+source = "url"
+sha256 = "00"
+[[bootloader]]
+type = "host_command"
+command = ["sign", "u-boot.bin"]
+output = "u-boot.signed"
+[bootloader.flash]
+partition = 1
+[[bootloader]]
+type = "host_command"
+command = ["sign", "u-boot.bin"]
+output = "u-boot.signed"
+[bootloader.flash]
+offset = 0x200
+max_size = 0x10000
+[[bootloader]]
+type = "grub"
+target = "arm64-efi"
+efi_directory = "/efi"
+removable = true
+[[bootloader]]
+type = "systemd_boot"
+entry_title = "AOSC OS"
+kernel_cmdline = "quiet"
+[[bootloader]]
+type = "extlinux"
+dir = "/boot/extlinux"
+cmdline = "quiet"
+fdt = "fixture.dtb"
+fdtdir = "/boot/dtbs"
+[[user]]
+name = "operator"
+password_hash = "!"
+groups = ["wheel"]
+comment = "Operator"
+shell = "/bin/bash"
+uid = 1001
+create_home = false
+[[hook]]
+stage = "post_rootfs"
+script = "hook.sh"
+[[sources.base]]
+line = "deb {mirror} stable main"
+persist = true
+[[sources.desktop]]
+line = "deb {mirror} stable main"
+[[sources.server]]
+line = "deb {mirror} stable main"
+[efi_secureboot]
+certificate = "cert.der"
+certificate_name = "fixture"
+shim_package = "shim"
+grub_package = "grub"
+shim = "shim.efi"
+grub = "grub.efi"
+"#;
+
+	/// The swap spaces of each type, appended to [`FULL_SPEC`].
+	const FULL_SWAPS: &[&str] = &[
+		"[swap]\ntype = \"file\"\nsize_mib = 512\n",
+		"[swap]\ntype = \"zram\"\nsize_mib = 512\ncompression = \"zstd\"\n",
+	];
+
+	/// Specs with deliberate typos, relative to the root of the crate.
+	const TYPOS_DIR: &str = "tests/fixtures/typos";
+
+	fn summary(unknown: &[UnknownField]) -> Vec<(&str, &str, Option<&str>)> {
+		unknown
+			.iter()
+			.map(|x| (x.location.as_str(), x.key.as_str(), x.suggestion))
+			.collect()
+	}
+
+	#[test]
+	fn test_unknown_fields() -> Result<()> {
+		let device = DeviceSpec::from_path(&Path::new(TYPOS_DIR).join("device.toml"))?;
+		assert_eq!(
+			summary(&device.unknown_fields),
+			vec![
+				("the device spec", "enable_service", Some("enable_services")),
+				("the device spec", "colour", None),
+				("partition 1", "fs_lable", Some("fs_label")),
+				("partition 2", "mount_options", Some("mount_opts")),
+				("bootloader 1 (extlinux)", "fdtdri", Some("fdtdir")),
+			]
+		);
+		assert_eq!(
+			device.unknown_fields[0].to_string(),
+			"Unknown field 'enable_service' in the device spec, did you mean 'enable_services'?"
+		);
+		assert_eq!(
+			device.unknown_fields[1].to_string(),
+			"Unknown field 'colour' in the device spec"
+		);
+		// Only errors with --strict
+		DeviceRegistry::check_devices(vec![device.clone()], false)?;
+		assert!(DeviceRegistry::check_devices(vec![device.clone()], true).is_err());
+		let err = deny_unknown_fields(&device.unknown_fields)
+			.unwrap_err()
+			.to_string();
+		assert!(
+			err.contains("Unknown field 'mount_options' in partition 2"),
+			"{}",
+			err
+		);
+		// Unknown fields of the override are added
+		let device = device.apply_override(&Path::new(TYPOS_DIR).join("override.toml"))?;
+		assert_eq!(device.unknown_fields.len(), 7);
+		assert_eq!(
+			summary(&device.unknown_fields[5..]),
+			vec![
+				("the device spec", "initrdles", Some("initrdless")),
+				("partition 2", "fs_uid", Some("fs_uuid")),
+			]
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_unknown_fields_tables() -> Result<()> {
+		let table: toml::Table = toml::from_str(
+			r#"
+			bsp_package = ["linux+kernel"]
+			[[partition]]
+			num = 1
+			type = "nested"
+			table_type = "mbr"
+			[[partition.partitions]]
+			no = 5
+			type = "byte"
+			byte = 0x83
+			lable = "data"
+			[[partition]]
+			type = "linux"
+			byte = 0x83
+			[[bootloader]]
+			type = "flash_offset"
+			path = "u-boot.bin"
+			offset = 0x2000
+			max_size = 0xfe000
+			sha256 = "00"
+			[[bootloader]]
+			nme = "apply-bootloader.sh"
+			[[user]]
+			name = "operator"
+			shel = "/usr/bin/zsh"
+			[[hook]]
+			stage = "post_rootfs"
+			scirpt = "hook.sh"
+			[[sources.desktop]]
+			line = "deb {mirror} stable main"
+			persists = true
+			[efi_secureboot]
+			certificate = "cert.der"
+			shim_pkg = "shim"
+			"#,
+		)?;
+		assert_eq!(
+			summary(&unknown_fields(&table)),
+			vec![
+				("the device spec", "bsp_package", Some("bsp_packages")),
+				("partition 5 of partition 1", "lable", Some("label")),
+				("partition #2", "byte", Some("type")),
+				("bootloader 2", "nme", Some("name")),
+				("user 1 (operator)", "shel", Some("shell")),
+				("hook 1 (post_rootfs)", "scirpt", Some("script")),
+				("source 1 of desktop", "persists", Some("persist")),
+				("efi_secureboot", "shim_pkg", None),
+			]
+		);
+		deny_unknown_fields(&[])?;
+		// The fixture registry has no unknown fields
+		for id in [
+			"fixture-gpt-efi",
+			"fixture-mbr-uboot",
+			"fixture-gpt-layout",
+			"fixture-armhf-sunxi",
+		] {
+			assert_eq!(fixture_device(id)?.unknown_fields, vec![], "{}", id);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_struct_fields() {
+		let fields = struct_fields::<DeviceSpec>();
+		for field in [
+			"id",
+			"compatible",
+			"partitions",
+			"partition",
+			"bootloader",
+			"user",
+		] {
+			assert!(fields.contains(&field), "{}", field);
+		}
+		assert!(!fields.contains(&"of_compatible"));
+		assert!(!fields.contains(&"file_path"));
+		assert_eq!(struct_fields::<PartitionContent>(), &["source", "path"]);
+		assert_eq!(struct_fields::<HookSpec>(), &["stage", "script"]);
+	}
+
+	#[test]
+	fn test_known_fields() -> Result<()> {
+		let listed = [PARTITION_FIELDS, FLASH_TARGET_FIELDS]
+			.into_iter()
+			.chain(
+				[PARTITION_TYPE_FIELDS, BOOTLOADER_FIELDS, SWAP_FIELDS]
+					.into_iter()
+					.flatten()
+					.map(|(_, fields)| *fields),
+			)
+			.flatten();
+		let spec = FULL_SWAPS
+			.iter()
+			.fold(FULL_SPEC.to_owned(), |spec, swap| spec + swap);
+		// Set as a value, or as a table like `[partition.content]`.
+		let is_set = |field: &str| {
+			spec.lines().any(|x| {
+				x.starts_with(&format!("{} = ", field))
+					|| x.trim_end_matches(']').ends_with(&format!(".{}", field))
+			})
+		};
+		for field in listed {
+			assert!(is_set(field), "'{}' is not set in the full spec", field);
+		}
+		for swap in FULL_SWAPS {
+			let spec = format!("{}{}", FULL_SPEC, swap);
+			toml::from_str::<DeviceSpec>(&spec)?;
+			assert_eq!(unknown_fields(&toml::from_str(&spec)?), vec![]);
+			// Every field is known to the deserialization: a value of the wrong type is rejected.
+			let lines: Vec<&str> = spec.lines().collect();
+			for (i, line) in lines.iter().enumerate() {
+				let Some((key, _)) = line.split_once(" = ") else {
+					continue;
+				};
+				let mut corrupted = lines.clone();
+				let replaced = format!("{} = 1979-05-27T07:32:00Z", key);
+				corrupted[i] = &replaced;
+				assert!(
+					toml::from_str::<DeviceSpec>(&corrupted.join("\n")).is_err(),
+					"'{}' is ignored by the deserialization",
+					line
+				);
+			}
+		}
+		Ok(())
+	}
+}
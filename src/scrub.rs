@@ -0,0 +1,145 @@
+//! Module handling the removal of the per-machine identities from the images.
+//!
+//! Packages generate a machine ID and SSH host keys while they are installed, which would be shared by every device flashed with the image.
+//! They are removed right before the image is finalized, so each device generates its own on the first boot:
+//!
+//! - `etc/machine-id` is reset to `uninitialized`, which also marks the first boot for systemd.
+//! - `var/lib/dbus/machine-id` is removed if it is a regular file, rather than a link to `etc/machine-id`.
+//! - The SSH host keys `etc/ssh/ssh_host_*` are removed.
+//! - The journals `var/log/journal/<machine ID>` are removed, as they are named after the machine ID.
+use std::{
+	fs,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// Content of a machine ID which is not initialized yet.
+const MACHINE_ID_UNINITIALIZED: &str = "uninitialized\n";
+
+/// Remove the per-machine identities from the system at `rootdir`, returning what was changed relative to it.
+pub fn scrub_identities(rootdir: &Path) -> Result<Vec<PathBuf>> {
+	let mut scrubbed = Vec::new();
+	let machine_id = rootdir.join("etc/machine-id");
+	if let Ok(m) = machine_id.symlink_metadata()
+		&& m.is_file()
+		&& fs::read_to_string(&machine_id).ok().as_deref() != Some(MACHINE_ID_UNINITIALIZED)
+	{
+		fs::write(&machine_id, MACHINE_ID_UNINITIALIZED)
+			.context(format!("Unable to reset {}", machine_id.display()))?;
+		scrubbed.push(machine_id);
+	}
+	let dbus_machine_id = rootdir.join("var/lib/dbus/machine-id");
+	if dbus_machine_id
+		.symlink_metadata()
+		.is_ok_and(|m| m.is_file())
+	{
+		fs::remove_file(&dbus_machine_id)
+			.context(format!("Unable to remove {}", dbus_machine_id.display()))?;
+		scrubbed.push(dbus_machine_id);
+	}
+	for entry in read_dir_sorted(&rootdir.join("etc/ssh"))? {
+		let is_host_key = entry
+			.file_name()
+			.is_some_and(|x| x.to_string_lossy().starts_with("ssh_host_"));
+		if is_host_key && !entry.symlink_metadata()?.is_dir() {
+			fs::remove_file(&entry).context(format!("Unable to remove {}", entry.display()))?;
+			scrubbed.push(entry);
+		}
+	}
+	for entry in read_dir_sorted(&rootdir.join("var/log/journal"))? {
+		if entry.symlink_metadata()?.is_dir() {
+			fs::remove_dir_all(&entry).context(format!("Unable to remove {}", entry.display()))?;
+			scrubbed.push(entry);
+		}
+	}
+	Ok(scrubbed
+		.into_iter()
+		.map(|x| x.strip_prefix(rootdir).map(|x| x.to_owned()).unwrap_or(x))
+		.collect())
+}
+
+/// Entries of the directory at `path`, sorted by name. Empty if it does not exist.
+fn read_dir_sorted(path: &Path) -> Result<Vec<PathBuf>> {
+	let entries = match fs::read_dir(path) {
+		Ok(x) => x,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(e) => return Err(e).context(format!("Unable to read {}", path.display())),
+	};
+	let mut entries = entries
+		.map(|x| x.map(|x| x.path()))
+		.collect::<std::io::Result<Vec<_>>>()?;
+	entries.sort();
+	Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::fs::symlink;
+
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	#[test]
+	fn test_scrub_identities() -> Result<()> {
+		let root = TestDir::new("scrub")?;
+		for dir in [
+			"etc/ssh/sshd_config.d",
+			"var/lib/dbus",
+			"var/log/journal/0123456789abcdef0123456789abcdef",
+		] {
+			fs::create_dir_all(root.join(dir))?;
+		}
+		fs::write(
+			root.join("etc/machine-id"),
+			"0123456789abcdef0123456789abcdef\n",
+		)?;
+		fs::write(
+			root.join("var/lib/dbus/machine-id"),
+			"0123456789abcdef0123456789abcdef\n",
+		)?;
+		for file in [
+			"ssh_host_ed25519_key",
+			"ssh_host_ed25519_key.pub",
+			"ssh_host_rsa_key",
+			"sshd_config",
+		] {
+			fs::write(root.join("etc/ssh").join(file), "")?;
+		}
+		fs::write(
+			root.join("var/log/journal/0123456789abcdef0123456789abcdef/system.journal"),
+			"",
+		)?;
+		assert_eq!(
+			scrub_identities(&root)?,
+			[
+				"etc/machine-id",
+				"var/lib/dbus/machine-id",
+				"etc/ssh/ssh_host_ed25519_key",
+				"etc/ssh/ssh_host_ed25519_key.pub",
+				"etc/ssh/ssh_host_rsa_key",
+				"var/log/journal/0123456789abcdef0123456789abcdef",
+			]
+			.map(PathBuf::from)
+		);
+		assert_eq!(
+			fs::read_to_string(root.join("etc/machine-id"))?,
+			"uninitialized\n"
+		);
+		assert!(root.join("etc/ssh/sshd_config").exists());
+		assert!(root.join("etc/ssh/sshd_config.d").is_dir());
+		assert!(root.join("var/log/journal").is_dir());
+		// Nothing left to scrub, links to etc/machine-id are kept
+		symlink("/etc/machine-id", root.join("var/lib/dbus/machine-id"))?;
+		assert_eq!(scrub_identities(&root)?, Vec::<PathBuf>::new());
+		assert!(
+			root.join("var/lib/dbus/machine-id")
+				.symlink_metadata()
+				.is_ok()
+		);
+		fs::remove_dir_all(&root)?;
+		// Nothing to scrub at all
+		assert_eq!(scrub_identities(&root)?, Vec::<PathBuf>::new());
+		Ok(())
+	}
+}
@@ -0,0 +1,438 @@
+//! Module handling the Secure Boot chain of EFI images.
+//!
+//! Images can boot with Secure Boot enabled through shim, which loads the signed GRUB, with a Machine Owner Key (MOK) certificate staged on the EFI system partition for enrollment.
+//!
+//! For details please go to [`SecureBootSpec`].
+//!
+use std::{
+	fs,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	context::ImageContext,
+	device::{DeviceArch, DeviceSpec},
+	partition::PartitionType,
+	utils::{copy_preserving, is_contained_path, sha256_file},
+};
+
+/// Path to the description of the staged Secure Boot chain, relative to the root of the target.
+pub const SECUREBOOT_MARKER: &str = "etc/mkrawimg/secureboot.json";
+/// Directory of the fallback boot loader in the EFI system partition.
+const FALLBACK_DIR: &str = "EFI/BOOT";
+
+fn default_certificate_name() -> String {
+	"MOK.der".to_owned()
+}
+
+fn default_shim_package() -> String {
+	"shim-signed".to_owned()
+}
+
+fn default_grub_package() -> String {
+	"grub-signed".to_owned()
+}
+
+/// Stage shim, the signed GRUB and a MOK certificate in the EFI system partition.
+///
+/// ```toml
+/// [efi_secureboot]
+/// # DER encoded certificate, relative to the directory containing device.toml.
+/// certificate = "mok.der"
+/// # Optional, path of the certificate in the EFI system partition. Defaults to "MOK.der".
+/// certificate_name = "aosc/MOK.der"
+/// # Optional, packages providing the signed binaries. Default to "shim-signed" and "grub-signed".
+/// shim_package = "shim-signed"
+/// grub_package = "grub-signed"
+/// # Optional, paths to the signed binaries in the target system.
+/// shim = "/usr/lib/shim/shimx64.efi.signed"
+/// grub = "/usr/lib/grub/x86_64-efi-signed/grubx64.efi.signed"
+/// ```
+///
+/// Only `amd64` and `arm64` devices with an EFI system partition can enable it, and both packages must be listed in `bsp_packages`.
+///
+/// After the bootloaders are applied, shim is copied to the fallback path (e.g. `/EFI/BOOT/BOOTX64.EFI`) of the EFI system partition, replacing what is there, and the signed GRUB next to it (e.g. `/EFI/BOOT/grubx64.efi`) for shim to load.
+/// The certificate is copied to `certificate_name`, for the user to enroll with `mokutil --import` or MokManager on the first boot.
+/// What was staged is described in `/etc/mkrawimg/secureboot.json`, and the image fails to build if shim is not at the fallback path in the end.
+///
+/// The default paths of the binaries follow the Debian packages:
+///
+/// | Architecture | `shim`                              | `grub`                                                 |
+/// |--------------|-------------------------------------|--------------------------------------------------------|
+/// | `amd64`      | `/usr/lib/shim/shimx64.efi.signed`  | `/usr/lib/grub/x86_64-efi-signed/grubx64.efi.signed`   |
+/// | `arm64`      | `/usr/lib/shim/shimaa64.efi.signed` | `/usr/lib/grub/arm64-efi-signed/grubaa64.efi.signed`   |
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct SecureBootSpec {
+	pub certificate: String,
+	#[serde(default = "default_certificate_name")]
+	pub certificate_name: String,
+	#[serde(default = "default_shim_package")]
+	pub shim_package: String,
+	#[serde(default = "default_grub_package")]
+	pub grub_package: String,
+	pub shim: Option<String>,
+	pub grub: Option<String>,
+}
+
+/// Description of the staged Secure Boot chain, saved as [`SECUREBOOT_MARKER`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SecureBootMarker {
+	pub shim_package: String,
+	pub grub_package: String,
+	/// Paths in the target system.
+	pub shim: String,
+	pub grub: String,
+	pub certificate: String,
+	pub certificate_sha256: String,
+}
+
+/// The suffix of the EFI binaries for `arch`, e.g. `x64` in `BOOTX64.EFI`.
+fn efi_suffix(arch: &DeviceArch) -> Option<&'static str> {
+	match arch {
+		DeviceArch::amd64 => Some("x64"),
+		DeviceArch::arm64 => Some("aa64"),
+		_ => None,
+	}
+}
+
+/// Read a DER tag-length-value at the start of `data`, returning the tag, the contents and the rest.
+fn der_tlv(data: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+	let (&tag, rest) = data.split_first().context("Unexpected end of data")?;
+	if tag & 0x1f == 0x1f {
+		bail!("High tag numbers are not used in certificates");
+	}
+	let (&first, mut rest) = rest.split_first().context("Unexpected end of data")?;
+	let len = if first < 0x80 {
+		first as usize
+	} else {
+		let num = (first & 0x7f) as usize;
+		// Indefinite lengths are not allowed in DER.
+		if num == 0 || num > size_of::<usize>() || num > rest.len() {
+			bail!("Invalid length");
+		}
+		let (bytes, after) = rest.split_at(num);
+		rest = after;
+		let len = bytes.iter().fold(0usize, |len, &b| (len << 8) | b as usize);
+		if bytes[0] == 0 || len < 0x80 {
+			bail!("Length is not in the shortest form");
+		}
+		len
+	};
+	if len > rest.len() {
+		bail!("Length exceeds the data");
+	}
+	let (content, rest) = rest.split_at(len);
+	Ok((tag, content, rest))
+}
+
+/// Check that `data` is a DER encoded X.509 certificate.
+///
+/// Only the outer structure is checked: a SEQUENCE of the TBSCertificate, the signature algorithm and the signature, see RFC 5280.
+pub fn check_der_certificate(data: &[u8]) -> Result<()> {
+	if data.starts_with(b"-----BEGIN") {
+		bail!(
+			"The certificate is PEM encoded, convert it with `openssl x509 -in cert.pem -outform der -out cert.der`"
+		);
+	}
+	let (tag, content, rest) = der_tlv(data).context("Invalid DER encoding")?;
+	if tag != 0x30 || !rest.is_empty() {
+		bail!("Not a DER encoded certificate");
+	}
+	let (tbs_tag, tbs, content) = der_tlv(content).context("Invalid TBSCertificate")?;
+	let (alg_tag, _, content) = der_tlv(content).context("Invalid signature algorithm")?;
+	let (sig_tag, _, content) = der_tlv(content).context("Invalid signature")?;
+	if tbs_tag != 0x30 || alg_tag != 0x30 || sig_tag != 0x03 || !content.is_empty() {
+		bail!("Not a DER encoded certificate");
+	}
+	// [0] version or the INTEGER serial number.
+	let (first_tag, _, _) = der_tlv(tbs).context("Invalid TBSCertificate")?;
+	if first_tag != 0xa0 && first_tag != 0x02 {
+		bail!("Not a DER encoded certificate");
+	}
+	Ok(())
+}
+
+/// Check that `path` is a relative path staying within its base directory.
+fn check_relative_path(path: &str) -> Result<()> {
+	if !is_contained_path(Path::new(path)) {
+		bail!("'{}' must be a relative path without '..' components", path);
+	}
+	Ok(())
+}
+
+impl SecureBootSpec {
+	/// The paths to shim and the signed GRUB in the target system.
+	pub fn binaries(&self, arch: &DeviceArch) -> Result<(String, String)> {
+		let suffix = efi_suffix(arch).context(format!(
+			"Secure Boot is only supported on amd64 and arm64 devices, not {}",
+			arch
+		))?;
+		let grub_target = if suffix == "x64" { "x86_64" } else { "arm64" };
+		let shim = self
+			.shim
+			.clone()
+			.unwrap_or_else(|| format!("/usr/lib/shim/shim{}.efi.signed", suffix));
+		let grub = self.grub.clone().unwrap_or_else(|| {
+			format!(
+				"/usr/lib/grub/{}-efi-signed/grub{}.efi.signed",
+				grub_target, suffix
+			)
+		});
+		Ok((shim, grub))
+	}
+
+	/// The fallback paths of shim and GRUB in the EFI system partition.
+	pub fn fallback_paths(&self, arch: &DeviceArch) -> Result<(String, String)> {
+		let suffix = efi_suffix(arch).context(format!(
+			"Secure Boot is only supported on amd64 and arm64 devices, not {}",
+			arch
+		))?;
+		Ok((
+			format!("{}/BOOT{}.EFI", FALLBACK_DIR, suffix.to_uppercase()),
+			format!("{}/grub{}.efi", FALLBACK_DIR, suffix),
+		))
+	}
+
+	/// Check this option against `device`, whose spec is in `dirname`.
+	pub fn check(&self, device: &DeviceSpec, dirname: &Path) -> Result<()> {
+		let (shim, grub) = self.binaries(&device.arch)?;
+		for path in [&shim, &grub] {
+			if !path.starts_with('/') {
+				bail!("Path '{}' in the target system must be absolute", path);
+			}
+		}
+		for package in [&self.shim_package, &self.grub_package] {
+			if !device.bsp_packages.contains(package) {
+				bail!("Secure Boot requires package '{}' in bsp_packages", package);
+			}
+		}
+		let esp = device
+			.partitions
+			.iter()
+			.find(|p| p.part_type == PartitionType::EFI && p.mountpoint.is_some())
+			.context("Secure Boot requires an EFI system partition with a mountpoint")?;
+		if !esp.formatted_at_build() {
+			bail!(
+				"Secure Boot requires the EFI system partition to have a filesystem created during the build"
+			);
+		}
+		check_relative_path(&self.certificate_name).context("Invalid certificate_name")?;
+		let (shim_fallback, grub_fallback) = self.fallback_paths(&device.arch)?;
+		let name = Path::new(&self.certificate_name);
+		// FAT is case insensitive.
+		if [&shim_fallback, &grub_fallback]
+			.iter()
+			.any(|x| x.eq_ignore_ascii_case(&name.to_string_lossy()))
+		{
+			bail!(
+				"certificate_name '{}' collides with the boot loaders",
+				self.certificate_name
+			);
+		}
+		check_relative_path(&self.certificate).context("Invalid certificate")?;
+		let cert_path = dirname.join(&self.certificate);
+		let data = fs::read(&cert_path).context(format!(
+			"Unable to read the certificate '{}' within the same directory as the device.toml",
+			&self.certificate
+		))?;
+		check_der_certificate(&data)
+			.context(format!("Invalid certificate '{}'", &self.certificate))?;
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// The Secure Boot option and the EFI system partition mounted in `rootfs`, if enabled.
+	fn secureboot_esp(&self, rootfs: &Path) -> Result<Option<(&SecureBootSpec, String, PathBuf)>> {
+		let Some(spec) = &self.device.efi_secureboot else {
+			return Ok(None);
+		};
+		let mountpoint = self
+			.esp_mountpoint()
+			.context("Secure Boot requires an EFI system partition with a mountpoint")?;
+		let esp = rootfs.join(mountpoint.trim_start_matches('/'));
+		Ok(Some((
+			spec,
+			mountpoint.trim_end_matches('/').to_owned(),
+			esp,
+		)))
+	}
+
+	/// Stage the Secure Boot chain in the EFI system partition of the target system at `rootfs`.
+	///
+	/// This must be done after the bootloaders are applied, as they may replace the fallback boot loader.
+	pub fn stage_secureboot(&self, rootfs: &Path) -> Result<()> {
+		let Some((spec, mountpoint, esp)) = self.secureboot_esp(rootfs)? else {
+			return Ok(());
+		};
+		self.info("Staging the Secure Boot chain ...");
+		let (shim, grub) = spec.binaries(&self.device.arch)?;
+		let (shim_fallback, grub_fallback) = spec.fallback_paths(&self.device.arch)?;
+		fs::create_dir_all(esp.join(FALLBACK_DIR))?;
+		for (src, dst) in [(&shim, &shim_fallback), (&grub, &grub_fallback)] {
+			let src_path = rootfs.join(src.trim_start_matches('/'));
+			if !src_path.is_file() {
+				bail!(
+					"'{}' is not found in the target system, is it installed by package '{}' or '{}'?",
+					src,
+					&spec.shim_package,
+					&spec.grub_package
+				);
+			}
+			copy_preserving(&src_path, esp.join(dst))?;
+		}
+		let device_spec_dir = self
+			.device
+			.file_path
+			.parent()
+			.context("Failed to reach the directory containing the device spec file")?;
+		let cert_src = device_spec_dir.join(&spec.certificate);
+		let cert_dst = esp.join(&spec.certificate_name);
+		if let Some(parent) = cert_dst.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		copy_preserving(&cert_src, &cert_dst)?;
+		let marker = SecureBootMarker {
+			shim_package: spec.shim_package.clone(),
+			grub_package: spec.grub_package.clone(),
+			shim: format!("{}/{}", mountpoint, shim_fallback),
+			grub: format!("{}/{}", mountpoint, grub_fallback),
+			certificate: format!("{}/{}", mountpoint, spec.certificate_name),
+			certificate_sha256: sha256_file(&cert_src)?,
+		};
+		let path = rootfs.join(SECUREBOOT_MARKER);
+		fs::create_dir_all(path.parent().unwrap())?;
+		fs::write(&path, serde_json::to_string_pretty(&marker)? + "\n")
+			.context(format!("Unable to write {}", SECUREBOOT_MARKER))
+	}
+
+	/// Make sure shim is at the fallback path of the EFI system partition of the target system at `rootfs`.
+	pub fn verify_secureboot(&self, rootfs: &Path) -> Result<()> {
+		let Some((spec, _, esp)) = self.secureboot_esp(rootfs)? else {
+			return Ok(());
+		};
+		let (shim, _) = spec.binaries(&self.device.arch)?;
+		let (shim_fallback, _) = spec.fallback_paths(&self.device.arch)?;
+		let fallback = esp.join(&shim_fallback);
+		if !fallback.is_file() {
+			bail!(
+				"Secure Boot is enabled, but shim is missing at /{}",
+				shim_fallback
+			);
+		}
+		if sha256_file(&fallback)? != sha256_file(rootfs.join(shim.trim_start_matches('/')))? {
+			bail!(
+				"Secure Boot is enabled, but /{} of the EFI system partition is not shim",
+				shim_fallback
+			);
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	/// The outer structure of a certificate, with the contents filled with zeros.
+	fn fake_certificate(tbs_len: usize) -> Vec<u8> {
+		let tlv = |tag: u8, content: &[u8]| -> Vec<u8> {
+			let mut v = vec![tag];
+			match content.len() {
+				len @ 0..0x80 => v.push(len as u8),
+				len @ 0x80..0x100 => v.extend([0x81, len as u8]),
+				len => v.extend([0x82, (len >> 8) as u8, len as u8]),
+			}
+			v.extend(content);
+			v
+		};
+		let mut tbs = tlv(0xa0, &tlv(0x02, &[2]));
+		tbs.extend(vec![0; tbs_len]);
+		let mut content = tlv(0x30, &tbs);
+		content.extend(tlv(0x30, &tlv(0x06, &[0x2a])));
+		content.extend(tlv(0x03, &[0; 65]));
+		tlv(0x30, &content)
+	}
+
+	#[test]
+	fn test_check_der_certificate() -> Result<()> {
+		check_der_certificate(&fake_certificate(10))?;
+		check_der_certificate(&fake_certificate(300))?;
+		let cert = fake_certificate(300);
+		// Truncated or trailing data
+		assert!(check_der_certificate(&cert[..cert.len() - 1]).is_err());
+		assert!(check_der_certificate(&[cert.as_slice(), &[0]].concat()).is_err());
+		assert!(check_der_certificate(b"").is_err());
+		let err = check_der_certificate(b"-----BEGIN CERTIFICATE-----\n").unwrap_err();
+		assert!(err.to_string().contains("PEM"), "{}", err);
+		// Lengths must be in the shortest form
+		assert!(check_der_certificate(&[0x30, 0x81, 0x01, 0x00]).is_err());
+		// Not a certificate
+		assert!(check_der_certificate(&[0x30, 0x00]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_secureboot_paths() -> Result<()> {
+		let spec: SecureBootSpec = toml::from_str("certificate = \"mok.der\"")?;
+		assert_eq!(spec.certificate_name, "MOK.der");
+		assert_eq!(
+			spec.binaries(&DeviceArch::amd64)?,
+			(
+				"/usr/lib/shim/shimx64.efi.signed".to_owned(),
+				"/usr/lib/grub/x86_64-efi-signed/grubx64.efi.signed".to_owned()
+			)
+		);
+		assert_eq!(
+			spec.fallback_paths(&DeviceArch::arm64)?,
+			(
+				"EFI/BOOT/BOOTAA64.EFI".to_owned(),
+				"EFI/BOOT/grubaa64.efi".to_owned()
+			)
+		);
+		assert!(spec.binaries(&DeviceArch::riscv64).is_err());
+		assert!(check_relative_path("aosc/MOK.der").is_ok());
+		assert!(check_relative_path("../MOK.der").is_err());
+		assert!(check_relative_path("/MOK.der").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_secureboot() -> Result<()> {
+		let dir = TestDir::new("secureboot")?;
+		fs::write(dir.join("mok.der"), fake_certificate(10))?;
+		fs::write(dir.join("mok.pem"), "-----BEGIN CERTIFICATE-----\n")?;
+		let mut device = crate::fixtures::fixture_device("fixture-gpt-efi")?;
+		let spec: SecureBootSpec = toml::from_str("certificate = \"mok.der\"")?;
+		let err = format!("{:#}", spec.check(&device, &dir).unwrap_err());
+		assert!(err.contains("package 'shim-signed'"), "{}", err);
+		device.bsp_packages.push("shim-signed".to_owned());
+		device.bsp_packages.push("grub-signed".to_owned());
+		spec.check(&device, &dir)?;
+		let check = |certificate: &str, name: &str| -> Result<()> {
+			SecureBootSpec {
+				certificate: certificate.to_owned(),
+				certificate_name: name.to_owned(),
+				..spec.clone()
+			}
+			.check(&device, &dir)
+		};
+		check("mok.der", "aosc/MOK.der")?;
+		assert!(check("mok.der", "../MOK.der").is_err());
+		// FAT is case insensitive
+		assert!(check("mok.der", "efi/boot/bootx64.efi").is_err());
+		let err = format!("{:#}", check("mok.pem", "MOK.der").unwrap_err());
+		assert!(err.contains("PEM"), "{}", err);
+		assert!(check("missing.der", "MOK.der").is_err());
+		// No EFI system partition
+		device
+			.partitions
+			.retain(|p| p.part_type != PartitionType::EFI);
+		assert!(spec.check(&device, &dir).is_err());
+		Ok(())
+	}
+}
@@ -0,0 +1,441 @@
+//! Module handling the shrinking of raw images to the used size, with `--shrink`.
+//!
+//! Once the image is finished and its filesystems are unmounted, the root filesystem is shrunk to its minimum size plus a margin ([`shrink_margin`]):
+//!
+//! - ext4 is checked with `e2fsck -f` and shrunk offline with `resize2fs`, like `resize2fs -M`.
+//! - Btrfs is mounted again and shrunk online with `btrfs filesystem resize`.
+//! - XFS and FAT can not be shrunk, and are refused before anything is built.
+//!
+//! The root partition is then cut down to the filesystem, and the raw image is truncated behind it before it is compressed.
+//! For GPT, the backup header and partition entry array are moved to the new end of the image.
+//! The root partition must be the last one, taking the rest of the disk, so it is expanded to the size of the medium during the first boot just like an image that is not shrunk.
+use std::{
+	fs::{self, File},
+	io::{Seek, SeekFrom},
+	path::Path,
+	process::Command,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use gptman::GPT;
+use mbrman::MBR;
+use sys_mount::Mount;
+
+use crate::{
+	device::PartitionMapType,
+	filesystem::FilesystemType,
+	utils::{cmd_run_check_status, unmount_busy_retrying},
+};
+
+/// Minimum margin left free in the shrunk root filesystem, in bytes.
+pub const SHRINK_MARGIN_MIN: u64 = 256 * 1048576;
+/// Partitions and the shrunk filesystems end at multiples of this many bytes.
+const SHRINK_ALIGN: u64 = 1048576;
+
+/// The root partition after shrinking its filesystem.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShrunkPartition {
+	pub num: u32,
+	/// New size of the filesystem in bytes.
+	pub fs_size: u64,
+	/// Sector size of the image.
+	pub sector_size: u64,
+}
+
+impl ShrunkPartition {
+	/// New size of the partition in sectors, rounded up to 1MiB.
+	pub fn sectors(&self) -> u64 {
+		self.fs_size.div_ceil(SHRINK_ALIGN) * SHRINK_ALIGN / self.sector_size
+	}
+}
+
+/// Make sure the root filesystem `fstype` can be shrunk.
+pub fn check_shrinkable(fstype: &FilesystemType) -> Result<()> {
+	match fstype {
+		FilesystemType::Ext4 | FilesystemType::Btrfs => Ok(()),
+		FilesystemType::Xfs => bail!("XFS can not be shrunk, --shrink requires ext4 or Btrfs"),
+		_ => bail!(
+			"{:?} root filesystems can not be shrunk, --shrink requires ext4 or Btrfs",
+			fstype
+		),
+	}
+}
+
+/// Free space left in a filesystem of `min_size` bytes after shrinking: 10% of it, at least [`SHRINK_MARGIN_MIN`].
+pub fn shrink_margin(min_size: u64) -> u64 {
+	(min_size / 10).max(SHRINK_MARGIN_MIN)
+}
+
+/// Size to shrink a filesystem of `min_size` bytes at least to, with the margin and rounded up to 1MiB.
+fn shrink_target(min_size: u64) -> u64 {
+	(min_size + shrink_margin(min_size)).div_ceil(SHRINK_ALIGN) * SHRINK_ALIGN
+}
+
+/// Run `cmd`, returning its stdout.
+fn cmd_stdout(cmd: &mut Command) -> Result<String> {
+	let output = cmd
+		.output()
+		.context(format!("Failed to run {:?}", cmd.get_program()))?;
+	if !output.status.success() {
+		bail!(
+			"{:?} exited unsuccessfully ({}):\n{}",
+			cmd,
+			output.status,
+			String::from_utf8_lossy(&output.stderr).trim_end()
+		);
+	}
+	Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Find the number in the line of `output` starting with `key`, e.g. `Block size:` of dumpe2fs.
+fn parse_number(output: &str, key: &str) -> Result<u64> {
+	output
+		.lines()
+		.find_map(|x| x.trim().strip_prefix(key))
+		.and_then(|x| x.split_whitespace().next())
+		.and_then(|x| x.parse().ok())
+		.ok_or_else(|| {
+			anyhow!(
+				"Unable to find '{}' in the output:\n{}",
+				key,
+				output.trim_end()
+			)
+		})
+}
+
+/// Shrink the ext4 filesystem on `dev`, returning its new size in bytes.
+fn shrink_ext4(dev: &Path) -> Result<u64> {
+	// resize2fs refuses to shrink a filesystem which is not checked right before.
+	let status = Command::new("e2fsck")
+		.args(["-f", "-y"])
+		.arg(dev)
+		.status()
+		.context("Failed to run e2fsck")?;
+	// 1 means errors are corrected.
+	if !matches!(status.code(), Some(0) | Some(1)) {
+		bail!("e2fsck failed on {} ({})", dev.display(), status);
+	}
+	let header = cmd_stdout(Command::new("dumpe2fs").arg("-h").arg(dev))?;
+	let block_size = parse_number(&header, "Block size:")?;
+	let size = parse_number(&header, "Block count:")? * block_size;
+	let min_blocks = parse_number(
+		&cmd_stdout(Command::new("resize2fs").arg("-P").arg(dev))?,
+		"Estimated minimum size of the filesystem:",
+	)?;
+	let target = shrink_target(min_blocks * block_size);
+	if target >= size {
+		return Ok(size);
+	}
+	cmd_run_check_status(
+		Command::new("resize2fs")
+			.arg(dev)
+			.arg(format!("{}K", target / 1024)),
+	)?;
+	Ok(target)
+}
+
+/// Shrink the Btrfs filesystem on `dev`, mounting it on `mountpoint`, returning its new size in bytes.
+fn shrink_btrfs(dev: &Path, mountpoint: &Path) -> Result<u64> {
+	let size = File::open(dev)
+		.and_then(|mut x| x.seek(SeekFrom::End(0)))
+		.context(format!("Unable to get the size of {}", dev.display()))?;
+	fs::create_dir_all(mountpoint)?;
+	Mount::builder()
+		.fstype("btrfs")
+		.mount(dev, mountpoint)
+		.context(format!("Unable to mount {}", dev.display()))?;
+	let result = (|| -> Result<u64> {
+		let min_size = parse_number(
+			&cmd_stdout(
+				Command::new("btrfs")
+					.args(["inspect-internal", "min-dev-size"])
+					.arg(mountpoint),
+			)?,
+			"",
+		)?;
+		let target = shrink_target(min_size);
+		if target >= size {
+			return Ok(size);
+		}
+		cmd_run_check_status(
+			Command::new("btrfs")
+				.args(["filesystem", "resize"])
+				.arg(target.to_string())
+				.arg(mountpoint),
+		)?;
+		Ok(target)
+	})();
+	unmount_busy_retrying(mountpoint)?;
+	result
+}
+
+/// Shrink the filesystem `fstype` on the unmounted partition `dev`, returning its new size in bytes.
+///
+/// Btrfs is shrunk online, it is mounted on `mountpoint` meanwhile.
+pub fn shrink_filesystem(dev: &Path, fstype: &FilesystemType, mountpoint: &Path) -> Result<u64> {
+	check_shrinkable(fstype)?;
+	match fstype {
+		FilesystemType::Ext4 => shrink_ext4(dev),
+		_ => shrink_btrfs(dev, mountpoint),
+	}
+}
+
+/// Cut the last partition of the raw image at `img` down to `shrunk`, and truncate the image behind it.
+///
+/// Returns the new size of the image in bytes.
+pub fn shrink_partition_table(
+	img: &Path,
+	partition_map: &PartitionMapType,
+	shrunk: &ShrunkPartition,
+) -> Result<u64> {
+	let mut fd = File::options()
+		.read(true)
+		.write(true)
+		.open(img)
+		.context(format!("Unable to open {}", img.display()))?;
+	let sector_size = shrunk.sector_size;
+	let sectors = shrunk.sectors();
+	let len = match partition_map {
+		PartitionMapType::GPT => {
+			let mut table = GPT::read_from(&mut fd, sector_size).context(format!(
+				"Unable to read the GPT partition table of {}",
+				img.display()
+			))?;
+			let entry = &table[shrunk.num];
+			let start = entry.starting_lba;
+			let end = start + sectors - 1;
+			check_last_partition(
+				shrunk.num,
+				entry.ending_lba,
+				end,
+				table
+					.iter()
+					.filter(|(_, e)| e.is_used())
+					.map(|(num, e)| (num, e.ending_lba)),
+			)?;
+			// The backup entry array and header follow the last usable sector.
+			let backup_sectors = table.header.backup_lba - table.header.last_usable_lba;
+			let len = (end + 1 + backup_sectors) * sector_size;
+			fd.set_len(len)?;
+			table.header.update_from(&mut fd, sector_size)?;
+			table[shrunk.num].ending_lba = end;
+			table.write_into(&mut fd)?;
+			GPT::write_protective_mbr_into(&mut fd, sector_size)?;
+			len
+		}
+		PartitionMapType::MBR => {
+			let mut table = MBR::read_from(&mut fd, sector_size as u32).context(format!(
+				"Unable to read the MBR partition table of {}",
+				img.display()
+			))?;
+			let idx = shrunk.num as usize;
+			let entry = &table[idx];
+			let start = entry.starting_lba as u64;
+			let end = start + sectors - 1;
+			check_last_partition(
+				shrunk.num,
+				start + entry.sectors as u64 - 1,
+				end,
+				table
+					.iter()
+					.filter(|(_, e)| e.is_used())
+					.map(|(idx, e)| (idx as u32, e.starting_lba as u64 + e.sectors as u64 - 1)),
+			)?;
+			let len = (end + 1) * sector_size;
+			fd.set_len(len)?;
+			table.disk_size = (end + 1) as u32;
+			table[idx].sectors = sectors as u32;
+			table.write_into(&mut fd)?;
+			len
+		}
+	};
+	fd.sync_all()?;
+	Ok(len)
+}
+
+/// Make sure partition `num` ending at `old_end` is the last one of `partitions` (numbers and last sectors), and is not grown to `new_end`.
+fn check_last_partition(
+	num: u32,
+	old_end: u64,
+	new_end: u64,
+	mut partitions: impl Iterator<Item = (u32, u64)>,
+) -> Result<()> {
+	if let Some((other, _)) = partitions.find(|(x, end)| *x != num && *end > old_end) {
+		bail!(
+			"Partition {} is not the last one of the image, partition {} follows it",
+			num,
+			other
+		);
+	}
+	if new_end > old_end {
+		bail!(
+			"Partition {} can not be shrunk, the filesystem needs more than the partition",
+			num
+		);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use gptman::GPTPartitionEntry;
+	use mbrman::{CHS, MBRPartitionEntry};
+
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	#[test]
+	fn test_shrink_target() -> Result<()> {
+		assert_eq!(shrink_margin(1 << 30), SHRINK_MARGIN_MIN);
+		assert_eq!(shrink_margin(10 << 30), 1 << 30);
+		assert_eq!(shrink_target(1 << 30), (1 << 30) + SHRINK_MARGIN_MIN);
+		// Rounded up to 1MiB
+		assert_eq!(shrink_target((10 << 30) + 1) % SHRINK_ALIGN, 0);
+		let shrunk = ShrunkPartition {
+			num: 2,
+			fs_size: 1048577,
+			sector_size: 512,
+		};
+		assert_eq!(shrunk.sectors(), 4096);
+		check_shrinkable(&FilesystemType::Ext4)?;
+		check_shrinkable(&FilesystemType::Btrfs)?;
+		assert!(check_shrinkable(&FilesystemType::Xfs).is_err());
+		assert!(check_shrinkable(&FilesystemType::Fat32).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_parse_number() -> Result<()> {
+		let dumpe2fs = "dumpe2fs 1.47.0 (5-Feb-2023)\nBlock count:              262144\nReserved block count:     13107\nBlock size:               4096\n";
+		assert_eq!(parse_number(dumpe2fs, "Block count:")?, 262144);
+		assert_eq!(parse_number(dumpe2fs, "Block size:")?, 4096);
+		assert_eq!(
+			parse_number(
+				"Estimated minimum size of the filesystem: 81920\n",
+				"Estimated minimum size of the filesystem:"
+			)?,
+			81920
+		);
+		assert_eq!(
+			parse_number("1395654656 bytes (1.30GiB)\n", "")?,
+			1395654656
+		);
+		assert!(parse_number(dumpe2fs, "Inode size:").is_err());
+		Ok(())
+	}
+
+	fn image(dir: &Path) -> Result<(std::path::PathBuf, File)> {
+		let path = dir.join("disk.img");
+		let fd = File::options()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(true)
+			.open(&path)?;
+		fd.set_len(64 * 1048576)?;
+		Ok((path, fd))
+	}
+
+	#[test]
+	fn test_shrink_ext4() -> Result<()> {
+		// The tools of e2fsprogs work on regular files as well.
+		let dir = TestDir::new("shrink-ext4")?;
+		let (path, fd) = image(&dir)?;
+		fd.set_len(1 << 30)?;
+		cmd_run_check_status(Command::new("mkfs.ext4").args(["-q", "-F"]).arg(&path))?;
+		let size = shrink_filesystem(&path, &FilesystemType::Ext4, Path::new("/nonexistent"))?;
+		assert!((SHRINK_MARGIN_MIN..1 << 30).contains(&size), "{}", size);
+		assert_eq!(size % SHRINK_ALIGN, 0);
+		let header = cmd_stdout(Command::new("dumpe2fs").arg("-h").arg(&path))?;
+		assert_eq!(
+			parse_number(&header, "Block count:")? * parse_number(&header, "Block size:")?,
+			size
+		);
+		// Smaller than the margin
+		fd.set_len(64 * 1048576)?;
+		cmd_run_check_status(Command::new("mkfs.ext4").args(["-q", "-F"]).arg(&path))?;
+		assert_eq!(
+			shrink_filesystem(&path, &FilesystemType::Ext4, Path::new("/nonexistent"))?,
+			64 * 1048576
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_shrink_gpt() -> Result<()> {
+		let dir = TestDir::new("shrink-gpt")?;
+		let (path, mut fd) = image(&dir)?;
+		let mut table = GPT::new_from(&mut fd, 512, [1; 16])?;
+		for (num, start, end) in [(1, 2048, 4095), (2, 4096, table.header.last_usable_lba)] {
+			table[num] = GPTPartitionEntry {
+				partition_type_guid: [2; 16],
+				unique_partition_guid: [num as u8 + 2; 16],
+				starting_lba: start,
+				ending_lba: end,
+				attribute_bits: 0,
+				partition_name: "".into(),
+			};
+		}
+		table.write_into(&mut fd)?;
+		let shrunk = |num| ShrunkPartition {
+			num,
+			fs_size: 8 * 1048576,
+			sector_size: 512,
+		};
+		assert!(shrink_partition_table(&path, &PartitionMapType::GPT, &shrunk(1)).is_err());
+		let len = shrink_partition_table(&path, &PartitionMapType::GPT, &shrunk(2))?;
+		// 1MiB gap, 1MiB partition 1, 8MiB partition 2, backup entry array and header
+		assert_eq!(len, (10 * 2048 + 33) * 512);
+		assert_eq!(fs::metadata(&path)?.len(), len);
+		let table = GPT::read_from(&mut fd, 512)?;
+		assert_eq!(table[2].starting_lba, 4096);
+		assert_eq!(table[2].ending_lba, 10 * 2048 - 1);
+		assert_eq!(table[1].ending_lba, 4095);
+		assert_eq!(table.header.backup_lba, len / 512 - 1);
+		assert_eq!(table.header.last_usable_lba, 10 * 2048 - 1);
+		// The backup header alone is valid
+		fd.seek(SeekFrom::Start(len - 512))?;
+		let backup = gptman::GPTHeader::read_from(&mut fd)?;
+		assert_eq!(backup.primary_lba, len / 512 - 1);
+		assert_eq!(backup.partition_entry_lba, 10 * 2048);
+		// Can not grow
+		let grown = ShrunkPartition {
+			num: 2,
+			fs_size: 32 * 1048576,
+			sector_size: 512,
+		};
+		assert!(shrink_partition_table(&path, &PartitionMapType::GPT, &grown).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_shrink_mbr() -> Result<()> {
+		let dir = TestDir::new("shrink-mbr")?;
+		let (path, mut fd) = image(&dir)?;
+		let mut table = MBR::new_from(&mut fd, 512, [1, 2, 3, 4])?;
+		for (num, start, sectors) in [(1, 2048, 2048), (2, 4096, 64 * 2048 - 4096)] {
+			table[num] = MBRPartitionEntry {
+				boot: mbrman::BOOT_INACTIVE,
+				first_chs: CHS::empty(),
+				sys: 0x83,
+				last_chs: CHS::empty(),
+				starting_lba: start,
+				sectors,
+			};
+		}
+		table.write_into(&mut fd)?;
+		let shrunk = ShrunkPartition {
+			num: 2,
+			fs_size: 8 * 1048576 - 4096,
+			sector_size: 512,
+		};
+		let len = shrink_partition_table(&path, &PartitionMapType::MBR, &shrunk)?;
+		assert_eq!(len, 10 * 1048576);
+		assert_eq!(fs::metadata(&path)?.len(), len);
+		let table = MBR::read_from(&mut fd, 512)?;
+		assert_eq!(table[2].starting_lba, 4096);
+		assert_eq!(table[2].sectors, 8 * 2048);
+		assert_eq!(table[1].sectors, 2048);
+		Ok(())
+	}
+}
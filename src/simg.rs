@@ -0,0 +1,538 @@
+//! Module handling the Android sparse images converted from the raw images.
+//!
+//! With `--format simg`, the raw image is converted to the sparse image format of Android (as `img2simg` does), which `fastboot flash` accepts.
+//! A sparse image is a header of 28 bytes followed by chunks, each covering a number of blocks of the raw image:
+//!
+//! - `RAW` chunks carry the data of the blocks.
+//! - `FILL` chunks carry a 4-byte value repeated over the blocks, e.g. for the blocks filled with zeros.
+//! - `DONT_CARE` chunks carry nothing, for the holes of the raw image.
+//!
+//! The sparse image is compressed in place of the raw image, e.g. to `aosc-os_base_simg_..._arm64.simg.xz`.
+//! Existing sparse images are converted back to raw images with [`decode_file`] before they are patched.
+use std::{
+	fs::File,
+	io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+	path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::bmap::{block_ranges, data_ranges};
+
+/// Magic number at the beginning of sparse images.
+pub const SIMG_MAGIC: u32 = 0xed26ff3a;
+/// Size of the blocks in the sparse images written.
+pub const SIMG_BLOCK_SIZE: u32 = 4096;
+const SIMG_MAJOR_VERSION: u16 = 1;
+const SIMG_MINOR_VERSION: u16 = 0;
+const FILE_HEADER_SIZE: u16 = 28;
+const CHUNK_HEADER_SIZE: u16 = 12;
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+const CHUNK_TYPE_CRC32: u16 = 0xcac4;
+/// Maximum count of blocks in a `RAW` chunk, as the data is buffered before the chunk is written.
+const MAX_RAW_BLOCKS: usize = 16384;
+
+/// Header of a sparse image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SimgHeader {
+	pub block_size: u32,
+	pub total_blocks: u32,
+	pub total_chunks: u32,
+}
+
+impl SimgHeader {
+	fn to_bytes(self) -> [u8; FILE_HEADER_SIZE as usize] {
+		let mut header = [0; FILE_HEADER_SIZE as usize];
+		header[0..4].copy_from_slice(&SIMG_MAGIC.to_le_bytes());
+		header[4..6].copy_from_slice(&SIMG_MAJOR_VERSION.to_le_bytes());
+		header[6..8].copy_from_slice(&SIMG_MINOR_VERSION.to_le_bytes());
+		header[8..10].copy_from_slice(&FILE_HEADER_SIZE.to_le_bytes());
+		header[10..12].copy_from_slice(&CHUNK_HEADER_SIZE.to_le_bytes());
+		header[12..16].copy_from_slice(&self.block_size.to_le_bytes());
+		header[16..20].copy_from_slice(&self.total_blocks.to_le_bytes());
+		header[20..24].copy_from_slice(&self.total_chunks.to_le_bytes());
+		// The checksum of the image is optional, and left as 0.
+		header
+	}
+
+	/// Read the header from the beginning of `reader`, returning it with the size of the chunk headers.
+	fn read<R: Read>(reader: &mut R) -> Result<(Self, u64)> {
+		let mut header = [0; FILE_HEADER_SIZE as usize];
+		reader
+			.read_exact(&mut header)
+			.context("The sparse image is too short")?;
+		let u16_at = |x: usize| u16::from_le_bytes([header[x], header[x + 1]]);
+		let u32_at = |x: usize| u32::from_le_bytes(header[x..x + 4].try_into().unwrap());
+		if u32_at(0) != SIMG_MAGIC {
+			bail!(
+				"Not a sparse image, the magic number is {:#010x}",
+				u32_at(0)
+			);
+		}
+		if u16_at(4) != SIMG_MAJOR_VERSION {
+			bail!("Unsupported version {} of the sparse image", u16_at(4));
+		}
+		let file_header_size = u16_at(8);
+		let chunk_header_size = u16_at(10);
+		if file_header_size < FILE_HEADER_SIZE || chunk_header_size < CHUNK_HEADER_SIZE {
+			bail!(
+				"Invalid sizes of the headers of the sparse image: {} and {} bytes",
+				file_header_size,
+				chunk_header_size
+			);
+		}
+		let block_size = u32_at(12);
+		if block_size == 0 || block_size % 4 != 0 {
+			bail!("Invalid block size {} of the sparse image", block_size);
+		}
+		// Fields added by later minor versions.
+		io::copy(
+			&mut reader.take((file_header_size - FILE_HEADER_SIZE) as u64),
+			&mut io::sink(),
+		)?;
+		Ok((
+			SimgHeader {
+				block_size,
+				total_blocks: u32_at(16),
+				total_chunks: u32_at(20),
+			},
+			chunk_header_size as u64,
+		))
+	}
+}
+
+/// A chunk of a sparse image, with the count of blocks it covers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Chunk {
+	Raw(Vec<u8>),
+	Fill { value: u32, blocks: u32 },
+	DontCare { blocks: u32 },
+}
+
+impl Chunk {
+	/// The chunk of a single block of data.
+	fn from_block(block: &[u8]) -> Self {
+		if block.chunks_exact(4).all(|x| x == &block[..4]) {
+			Chunk::Fill {
+				value: u32::from_le_bytes(block[..4].try_into().unwrap()),
+				blocks: 1,
+			}
+		} else {
+			Chunk::Raw(block.to_vec())
+		}
+	}
+
+	/// Append `other` to this chunk if they are of the same kind, returning whether it is appended.
+	fn merge(&mut self, other: &Chunk) -> bool {
+		match (self, other) {
+			(Chunk::Raw(data), Chunk::Raw(more))
+				if data.len() + more.len() <= MAX_RAW_BLOCKS * SIMG_BLOCK_SIZE as usize =>
+			{
+				data.extend_from_slice(more);
+				true
+			}
+			(
+				Chunk::Fill { value, blocks },
+				Chunk::Fill {
+					value: other_value,
+					blocks: more,
+				},
+			) if value == other_value => blocks.checked_add(*more).map(|x| *blocks = x).is_some(),
+			(Chunk::DontCare { blocks }, Chunk::DontCare { blocks: more }) => {
+				blocks.checked_add(*more).map(|x| *blocks = x).is_some()
+			}
+			_ => false,
+		}
+	}
+
+	fn write<W: Write>(&self, out: &mut W) -> Result<()> {
+		let fill;
+		let (chunk_type, blocks, data): (u16, u32, &[u8]) = match self {
+			Chunk::Raw(data) => (
+				CHUNK_TYPE_RAW,
+				(data.len() / SIMG_BLOCK_SIZE as usize) as u32,
+				data,
+			),
+			Chunk::Fill { value, blocks } => {
+				fill = value.to_le_bytes();
+				(CHUNK_TYPE_FILL, *blocks, &fill)
+			}
+			Chunk::DontCare { blocks } => (CHUNK_TYPE_DONT_CARE, *blocks, &[]),
+		};
+		out.write_all(&chunk_type.to_le_bytes())?;
+		out.write_all(&[0; 2])?;
+		out.write_all(&blocks.to_le_bytes())?;
+		out.write_all(&(CHUNK_HEADER_SIZE as u32 + data.len() as u32).to_le_bytes())?;
+		out.write_all(data)?;
+		Ok(())
+	}
+}
+
+/// Writes the chunks of a sparse image, merging the adjacent ones of the same kind.
+struct ChunkWriter<W: Write> {
+	out: W,
+	current: Option<Chunk>,
+	total_chunks: u32,
+}
+
+impl<W: Write> ChunkWriter<W> {
+	fn push(&mut self, chunk: Chunk) -> Result<()> {
+		if let Some(current) = &mut self.current
+			&& current.merge(&chunk)
+		{
+			return Ok(());
+		}
+		self.flush()?;
+		self.current = Some(chunk);
+		Ok(())
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		if let Some(chunk) = self.current.take() {
+			chunk.write(&mut self.out)?;
+			self.total_chunks += 1;
+		}
+		Ok(())
+	}
+}
+
+/// Convert the raw image at `raw` to a sparse image at `simg`.
+///
+/// The holes of the raw image become `DONT_CARE` chunks, the blocks filled with a repeated value (e.g. zeros) become `FILL` chunks.
+/// The raw image is padded with zeros to a multiple of [`SIMG_BLOCK_SIZE`].
+pub fn encode_file(raw: &Path, simg: &Path) -> Result<SimgHeader> {
+	let mut fd = File::open(raw).context(format!("Unable to open {}", raw.display()))?;
+	let size = fd.metadata()?.len();
+	let block_size = SIMG_BLOCK_SIZE as u64;
+	let total_blocks: u32 = size.div_ceil(block_size).try_into().context(format!(
+		"The raw image of {} bytes is too large for a sparse image",
+		size
+	))?;
+	let ranges = block_ranges(&data_ranges(&fd)?, block_size);
+	let out = File::create(simg).context(format!("Unable to create {}", simg.display()))?;
+	let mut writer = ChunkWriter {
+		out: BufWriter::new(out),
+		current: None,
+		total_chunks: 0,
+	};
+	// Written again once the chunks are counted.
+	writer.out.write_all(&[0; FILE_HEADER_SIZE as usize])?;
+	let mut block = vec![0; SIMG_BLOCK_SIZE as usize];
+	let mut next = 0;
+	for (first, last) in ranges {
+		if first > next {
+			writer.push(Chunk::DontCare {
+				blocks: (first - next) as u32,
+			})?;
+		}
+		fd.seek(SeekFrom::Start(first * block_size))?;
+		for num in first..=last {
+			let len = block_size.min(size - num * block_size) as usize;
+			block[len..].fill(0);
+			fd.read_exact(&mut block[..len])
+				.context(format!("Unable to read {}", raw.display()))?;
+			writer.push(Chunk::from_block(&block))?;
+		}
+		next = last + 1;
+	}
+	if (total_blocks as u64) > next {
+		writer.push(Chunk::DontCare {
+			blocks: (total_blocks as u64 - next) as u32,
+		})?;
+	}
+	writer.flush()?;
+	let header = SimgHeader {
+		block_size: SIMG_BLOCK_SIZE,
+		total_blocks,
+		total_chunks: writer.total_chunks,
+	};
+	let mut out = writer.out.into_inner()?;
+	out.seek(SeekFrom::Start(0))?;
+	out.write_all(&header.to_bytes())?;
+	out.sync_all()
+		.context(format!("Unable to write {}", simg.display()))?;
+	Ok(header)
+}
+
+/// Convert the sparse image at `simg` back to a raw image at `raw`.
+///
+/// `DONT_CARE` chunks and `FILL` chunks of zeros are left as holes in the raw image.
+pub fn decode_file(simg: &Path, raw: &Path) -> Result<SimgHeader> {
+	let fd = File::open(simg).context(format!("Unable to open {}", simg.display()))?;
+	let mut input = BufReader::new(fd);
+	let (header, chunk_header_size) = SimgHeader::read(&mut input)?;
+	let block_size = header.block_size as u64;
+	let mut out = File::create(raw).context(format!("Unable to create {}", raw.display()))?;
+	out.set_len(header.total_blocks as u64 * block_size)?;
+	let mut chunk_header = vec![0; chunk_header_size as usize];
+	let mut blocks_done = 0;
+	for num in 0..header.total_chunks {
+		input
+			.read_exact(&mut chunk_header)
+			.context(format!("Chunk {} of the sparse image is truncated", num))?;
+		let chunk_type = u16::from_le_bytes([chunk_header[0], chunk_header[1]]);
+		let blocks = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap()) as u64;
+		let total_size = u32::from_le_bytes(chunk_header[8..12].try_into().unwrap()) as u64;
+		let data_size = total_size
+			.checked_sub(chunk_header_size)
+			.context(format!("Invalid size of chunk {} of the sparse image", num))?;
+		if blocks_done + blocks > header.total_blocks as u64 {
+			bail!(
+				"Chunk {} of the sparse image goes beyond the {} blocks of the image",
+				num,
+				header.total_blocks
+			);
+		}
+		let expected = match chunk_type {
+			CHUNK_TYPE_RAW => blocks * block_size,
+			CHUNK_TYPE_FILL | CHUNK_TYPE_CRC32 => 4,
+			CHUNK_TYPE_DONT_CARE => 0,
+			_ => bail!(
+				"Unknown type {:#06x} of chunk {} of the sparse image",
+				chunk_type,
+				num
+			),
+		};
+		if data_size != expected {
+			bail!(
+				"Chunk {} of the sparse image has {} bytes of data, expected {}",
+				num,
+				data_size,
+				expected
+			);
+		}
+		match chunk_type {
+			CHUNK_TYPE_RAW => {
+				out.seek(SeekFrom::Start(blocks_done * block_size))?;
+				let copied = io::copy(&mut (&mut input).take(data_size), &mut out)?;
+				if copied != data_size {
+					bail!("Chunk {} of the sparse image is truncated", num);
+				}
+			}
+			CHUNK_TYPE_FILL => {
+				let mut value = [0; 4];
+				input.read_exact(&mut value)?;
+				if value != [0; 4] {
+					let pattern = value.repeat(header.block_size as usize / 4);
+					out.seek(SeekFrom::Start(blocks_done * block_size))?;
+					for _ in 0..blocks {
+						out.write_all(&pattern)?;
+					}
+				}
+			}
+			// The checksum of the preceding data is not verified.
+			CHUNK_TYPE_CRC32 => input.read_exact(&mut [0; 4])?,
+			_ => (),
+		}
+		blocks_done += blocks;
+	}
+	if blocks_done != header.total_blocks as u64 {
+		bail!(
+			"The chunks of the sparse image cover {} blocks, expected {}",
+			blocks_done,
+			header.total_blocks
+		);
+	}
+	out.sync_all()
+		.context(format!("Unable to write {}", raw.display()))?;
+	Ok(header)
+}
+
+/// Whether the file at `path` is a sparse image.
+pub fn is_simg(path: &Path) -> Result<bool> {
+	let mut fd = File::open(path).context(format!("Unable to open {}", path.display()))?;
+	let mut magic = [0; 4];
+	match fd.read_exact(&mut magic) {
+		Ok(()) => Ok(u32::from_le_bytes(magic) == SIMG_MAGIC),
+		Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+		Err(e) => Err(e).context(format!("Unable to read {}", path.display())),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	/// The types and block counts of the chunks of the sparse image at `path`.
+	fn chunks(path: &Path) -> Result<Vec<(u16, u32)>> {
+		let mut input = BufReader::new(File::open(path)?);
+		let (header, _) = SimgHeader::read(&mut input)?;
+		let mut chunks = Vec::new();
+		for _ in 0..header.total_chunks {
+			let mut chunk_header = [0; CHUNK_HEADER_SIZE as usize];
+			input.read_exact(&mut chunk_header)?;
+			let total_size = u32::from_le_bytes(chunk_header[8..12].try_into()?);
+			chunks.push((
+				u16::from_le_bytes([chunk_header[0], chunk_header[1]]),
+				u32::from_le_bytes(chunk_header[4..8].try_into()?),
+			));
+			input.seek_relative((total_size - CHUNK_HEADER_SIZE as u32) as i64)?;
+		}
+		Ok(chunks)
+	}
+
+	#[test]
+	fn test_merge_chunks() {
+		let mut fill = Chunk::Fill {
+			value: 0,
+			blocks: 1,
+		};
+		assert!(fill.merge(&Chunk::Fill {
+			value: 0,
+			blocks: 2
+		}));
+		assert_eq!(
+			fill,
+			Chunk::Fill {
+				value: 0,
+				blocks: 3
+			}
+		);
+		assert!(!fill.merge(&Chunk::Fill {
+			value: 1,
+			blocks: 1
+		}));
+		assert!(!fill.merge(&Chunk::DontCare { blocks: 1 }));
+		let mut hole = Chunk::DontCare { blocks: u32::MAX };
+		assert!(!hole.merge(&Chunk::DontCare { blocks: 1 }));
+		let block = vec![1; SIMG_BLOCK_SIZE as usize];
+		let mut raw = Chunk::Raw(vec![2; (MAX_RAW_BLOCKS - 1) * SIMG_BLOCK_SIZE as usize]);
+		assert!(raw.merge(&Chunk::Raw(block.clone())));
+		assert!(!raw.merge(&Chunk::Raw(block)));
+		assert_eq!(
+			Chunk::from_block(&[7, 0, 0, 0].repeat(1024)),
+			Chunk::Fill {
+				value: 7,
+				blocks: 1
+			}
+		);
+		assert!(matches!(
+			Chunk::from_block(&[[7, 0, 0, 0], [0; 4]].concat().repeat(512)),
+			Chunk::Raw(_)
+		));
+	}
+
+	#[test]
+	fn test_encode_decode() -> Result<()> {
+		let dir = TestDir::new("simg-encode")?;
+		let raw = dir.join("raw.img");
+		let simg = dir.join("raw.simg");
+		let decoded = dir.join("decoded.img");
+		let mut fd = File::create(&raw)?;
+		fd.set_len(8 * 1048576)?;
+		fd.write_all(&(0..8192).map(|x| x as u8).collect::<Vec<_>>())?;
+		// Zeros written out
+		fd.write_all(&[0; 8192])?;
+		fd.write_all(&[0xab; 4096])?;
+		fd.seek(SeekFrom::Start(4 * 1048576 + 100))?;
+		fd.write_all(&[2; 5000])?;
+		fd.sync_all()?;
+
+		let header = encode_file(&raw, &simg)?;
+		assert_eq!(header.total_blocks, 2048);
+		assert!(is_simg(&simg)?);
+		assert!(!is_simg(&raw)?);
+		let chunks = chunks(&simg)?;
+		assert_eq!(chunks.len() as u32, header.total_chunks);
+		assert_eq!(chunks.iter().map(|x| x.1).sum::<u32>(), 2048);
+		assert_eq!(chunks[0], (CHUNK_TYPE_RAW, 2));
+		assert_eq!(chunks[1], (CHUNK_TYPE_FILL, 2));
+		assert_eq!(chunks[2], (CHUNK_TYPE_FILL, 1));
+		assert!(chunks[3..].contains(&(CHUNK_TYPE_RAW, 2)));
+		// Much smaller than the raw image, if the filesystem keeps the holes
+		if chunks.iter().any(|x| x.0 == CHUNK_TYPE_DONT_CARE) {
+			assert!(fs::metadata(&simg)?.len() < 1048576);
+		}
+
+		let header = decode_file(&simg, &decoded)?;
+		assert_eq!(header.block_size, SIMG_BLOCK_SIZE);
+		assert_eq!(fs::read(&decoded)?, fs::read(&raw)?);
+
+		// Padded to a multiple of the block size
+		File::options()
+			.write(true)
+			.open(&raw)?
+			.set_len(8 * 1048576 + 10)?;
+		encode_file(&raw, &simg)?;
+		decode_file(&simg, &decoded)?;
+		let content = fs::read(&decoded)?;
+		assert_eq!(content.len(), 8 * 1048576 + 4096);
+		assert_eq!(content[..8 * 1048576], fs::read(&raw)?[..8 * 1048576]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_decode_synthetic() -> Result<()> {
+		let dir = TestDir::new("simg-synthetic")?;
+		let simg = dir.join("synthetic.simg");
+		let raw = dir.join("synthetic.img");
+		let chunk = |chunk_type: u16, blocks: u32, data: &[u8]| {
+			[
+				&chunk_type.to_le_bytes()[..],
+				&[0; 2],
+				&blocks.to_le_bytes(),
+				&(12 + data.len() as u32).to_le_bytes(),
+				data,
+			]
+			.concat()
+		};
+		// A block size of 1024, with a field added by a later minor version
+		let mut content = [
+			&SIMG_MAGIC.to_le_bytes()[..],
+			&[1, 0, 1, 0, 32, 0, 12, 0],
+			&1024u32.to_le_bytes(),
+			&7u32.to_le_bytes(),
+			&5u32.to_le_bytes(),
+			&[0; 4],
+			&[0xff; 4],
+		]
+		.concat();
+		content.extend(chunk(CHUNK_TYPE_RAW, 1, &[3; 1024]));
+		content.extend(chunk(CHUNK_TYPE_DONT_CARE, 2, &[]));
+		content.extend(chunk(CHUNK_TYPE_FILL, 3, &[0x12, 0x34, 0x56, 0x78]));
+		content.extend(chunk(CHUNK_TYPE_CRC32, 0, &[0; 4]));
+		content.extend(chunk(CHUNK_TYPE_FILL, 1, &[0; 4]));
+		fs::write(&simg, &content)?;
+		let header = decode_file(&simg, &raw)?;
+		assert_eq!(
+			header,
+			SimgHeader {
+				block_size: 1024,
+				total_blocks: 7,
+				total_chunks: 5
+			}
+		);
+		let expected = [
+			vec![3; 1024],
+			vec![0; 2048],
+			[0x12, 0x34, 0x56, 0x78].repeat(768),
+			vec![0; 1024],
+		]
+		.concat();
+		assert_eq!(fs::read(&raw)?, expected);
+
+		// Round trip in blocks of 4096
+		encode_file(&raw, &simg)?;
+		decode_file(&simg, &raw)?;
+		assert_eq!(fs::read(&raw)?, [expected, vec![0; 1024]].concat());
+
+		// Invalid images
+		let invalid = [
+			content[..20].to_vec(),
+			[&[0; 4], &content[4..]].concat(),
+			// Blocks missing
+			content[..content.len() - 16].to_vec(),
+			[&content[..12], &1001u32.to_le_bytes(), &content[16..]].concat(),
+		];
+		for content in invalid {
+			fs::write(&simg, content)?;
+			assert!(decode_file(&simg, &raw).is_err());
+		}
+		Ok(())
+	}
+}
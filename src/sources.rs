@@ -0,0 +1,345 @@
+//! Module handling the extra package sources of the images.
+//!
+//! Images of each variant can enable additional APT sources on top of the ones shipped by the system distribution, e.g. a partner repository for desktop images.
+//!
+//! For details please go to [`VariantSources`].
+//!
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::context::{ImageContext, ImageVariant};
+
+/// Path to the list of the extra sources, relative to the root of the target.
+pub const EXTRA_SOURCES_PATH: &str = "etc/apt/sources.list.d/mkrawimg-extra.list";
+
+/// Placeholders which can be used in the source entries.
+const SOURCE_PLACEHOLDERS: &[&str] = &["mirror", "arch"];
+/// The mirror to check the source entries of the device specs with, the actual one is given at build time.
+pub const CHECK_MIRROR: &str = "https://repo.aosc.io/debs";
+
+/// An entry of the extra package sources.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct SourceEntry {
+	/// The one-line-style source entry, e.g. `deb {mirror} stable main`.
+	pub line: String,
+	/// Keep the entry in the final image. Otherwise it is only used while building the image.
+	#[serde(default)]
+	pub persist: bool,
+}
+
+/// Extra package sources for the images of each variant.
+///
+/// ```toml
+/// [sources]
+/// desktop = [
+///     { line = "deb {mirror} stable main partner", persist = true },
+/// ]
+/// server = [
+///     { line = "deb [arch={arch}] https://example.com/debs stable main" },
+/// ]
+/// ```
+///
+/// Each entry is a line in the [one-line-style format] of APT, with the following placeholders:
+///
+/// - `{mirror}`: The mirror given with `--mirror`.
+/// - `{arch}`: The architecture of the device in AOSC OS (the architecture of dpkg), e.g. `arm64`, or `armv7hf` for `armhf` devices.
+///
+/// The entries are written into `/etc/apt/sources.list.d/mkrawimg-extra.list` after the system distribution is installed, before the hooks at the `post_rootfs` stage and the BSP packages.
+/// Entries with `persist = true` are kept in the image. Others are removed after the post installation scripts, and the image fails to build if they are left behind.
+/// The package lists downloaded from the removed entries are left in place until the package lists are refreshed with them gone.
+///
+/// [one-line-style format]: https://manpages.debian.org/stable/apt/sources.list.5.en.html#ONE-LINE-STYLE_FORMAT
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct VariantSources {
+	#[serde(default)]
+	pub base: Vec<SourceEntry>,
+	#[serde(default)]
+	pub desktop: Vec<SourceEntry>,
+	#[serde(default)]
+	pub server: Vec<SourceEntry>,
+}
+
+impl VariantSources {
+	pub fn get(&self, variant: &ImageVariant) -> &[SourceEntry] {
+		match variant {
+			ImageVariant::Base => &self.base,
+			ImageVariant::Desktop => &self.desktop,
+			ImageVariant::Server => &self.server,
+		}
+	}
+
+	/// Check the syntax of all entries, with the placeholders replaced by `mirror` and `arch`.
+	pub fn check(&self, mirror: &str, arch: &str) -> Result<()> {
+		for variant in ImageVariant::value_variants() {
+			for entry in self.get(variant) {
+				let line = render_source_line(&entry.line, mirror, arch)?;
+				check_source_line(&line).context(format!(
+					"Invalid source entry '{}' for {} images",
+					&entry.line,
+					variant.to_string().to_lowercase()
+				))?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Replace the placeholders in the source entry `line`.
+pub fn render_source_line(line: &str, mirror: &str, arch: &str) -> Result<String> {
+	let mut result = String::new();
+	let mut rest = line;
+	while let Some(start) = rest.find('{') {
+		result += &rest[..start];
+		let inner = &rest[start + 1..];
+		let Some(end) = inner.find('}') else {
+			bail!("Source entry '{}' has an unmatched '{{'", line);
+		};
+		result += match &inner[..end] {
+			"mirror" => mirror,
+			"arch" => arch,
+			name => bail!(
+				"Unknown placeholder '{{{}}}' in source entry '{}', possible placeholders are: {}",
+				name,
+				line,
+				SOURCE_PLACEHOLDERS
+					.iter()
+					.map(|p| format!("{{{}}}", p))
+					.collect::<Vec<_>>()
+					.join(", ")
+			),
+		};
+		rest = &inner[end + 1..];
+	}
+	result += rest;
+	Ok(result)
+}
+
+/// Check the syntax of a one-line-style source entry, see sources.list(5).
+pub fn check_source_line(line: &str) -> Result<()> {
+	if line.contains(['\n', '\r', '#']) {
+		bail!("Source entries must be a single line without comments");
+	}
+	let mut rest = line.trim();
+	let Some((kind, after)) = rest.split_once(char::is_whitespace) else {
+		bail!("Source entries must contain the type, the URI and the suite");
+	};
+	if kind != "deb" && kind != "deb-src" {
+		bail!("Unknown source type '{}', must be 'deb' or 'deb-src'", kind);
+	}
+	rest = after.trim_start();
+	if let Some(options) = rest.strip_prefix('[') {
+		let Some((options, after)) = options.split_once(']') else {
+			bail!("The options of the source entry are not closed with ']'");
+		};
+		for option in options.split_whitespace() {
+			match option.split_once('=') {
+				Some((key, value)) if !key.is_empty() && !value.is_empty() => (),
+				_ => bail!(
+					"Invalid option '{}', must be in the form 'key=value'",
+					option
+				),
+			}
+		}
+		rest = after.trim_start();
+	}
+	let mut fields = rest.split_whitespace();
+	let uri = fields.next().context("The URI is missing")?;
+	match uri.split_once("://") {
+		Some((scheme, path))
+			if !path.is_empty()
+				&& !scheme.is_empty()
+				&& scheme
+					.chars()
+					.all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) => {}
+		_ => bail!("Invalid URI '{}'", uri),
+	}
+	if uri.contains(['[', ']']) {
+		bail!("Invalid URI '{}'", uri);
+	}
+	let suite = fields.next().context("The suite is missing")?;
+	let components = fields.count();
+	// An exact path as the suite takes no components.
+	if suite.ends_with('/') && components > 0 {
+		bail!(
+			"Suite '{}' is an exact path, which can not be followed by components",
+			suite
+		);
+	}
+	if !suite.ends_with('/') && components == 0 {
+		bail!("No components are given for suite '{}'", suite);
+	}
+	Ok(())
+}
+
+impl ImageContext<'_> {
+	/// The extra source entries of this image, with the placeholders replaced.
+	pub(crate) fn extra_sources(&self) -> Result<Vec<(String, bool)>> {
+		let Some(sources) = &self.device.sources else {
+			return Ok(Vec::new());
+		};
+		let arch = self.device.arch.aosc_arch();
+		sources
+			.get(self.variant)
+			.iter()
+			.map(|entry| {
+				Ok((
+					render_source_line(&entry.line, self.mirror, &arch)?,
+					entry.persist,
+				))
+			})
+			.collect()
+	}
+
+	/// Write the extra source entries of this image into the target system at `rootdir`.
+	pub fn write_extra_sources(&self, rootdir: &Path) -> Result<()> {
+		let sources = self.extra_sources()?;
+		if sources.is_empty() {
+			return Ok(());
+		}
+		self.info(format!(
+			"Adding {} extra package source(s) ...",
+			sources.len()
+		));
+		let content: String = sources
+			.iter()
+			.map(|(line, _)| format!("{}\n", line))
+			.collect();
+		let path = rootdir.join(EXTRA_SOURCES_PATH);
+		fs::create_dir_all(path.parent().unwrap())?;
+		fs::write(&path, content).context(format!("Unable to write {}", EXTRA_SOURCES_PATH))
+	}
+
+	/// Remove the extra source entries which are not persistent from the target system at `rootdir`.
+	pub fn remove_extra_sources(&self, rootdir: &Path) -> Result<()> {
+		let sources = self.extra_sources()?;
+		if sources.iter().all(|(_, persist)| *persist) {
+			return Ok(());
+		}
+		self.info("Removing the temporary package sources ...");
+		let path = rootdir.join(EXTRA_SOURCES_PATH);
+		let kept: String = sources
+			.iter()
+			.filter(|(_, persist)| *persist)
+			.map(|(line, _)| format!("{}\n", line))
+			.collect();
+		if kept.is_empty() {
+			fs::remove_file(&path).context(format!("Unable to remove {}", EXTRA_SOURCES_PATH))
+		} else {
+			fs::write(&path, kept).context(format!("Unable to write {}", EXTRA_SOURCES_PATH))
+		}
+	}
+
+	/// Make sure the temporary source entries are not left in the target system at `rootdir`.
+	pub fn verify_extra_sources(&self, rootdir: &Path) -> Result<()> {
+		let path = rootdir.join(EXTRA_SOURCES_PATH);
+		let content = match fs::read_to_string(&path) {
+			Ok(x) => x,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+			Err(e) => {
+				return Err(e).context(format!("Unable to read {}", EXTRA_SOURCES_PATH));
+			}
+		};
+		let sources = self.extra_sources()?;
+		let is_persistent = |line: &str| sources.iter().any(|(x, persist)| *persist && x == line);
+		for (line, persist) in &sources {
+			if !persist && !is_persistent(line) && content.lines().any(|x| x.trim() == line) {
+				bail!(
+					"Temporary source entry '{}' is left in /{}",
+					line,
+					EXTRA_SOURCES_PATH
+				);
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		fixtures::{TestDir, fixture_device, test_context},
+		plan::BuildRun,
+	};
+
+	#[test]
+	fn test_extra_sources() -> Result<()> {
+		let device = fixture_device("fixture-gpt-efi")?;
+		let run = BuildRun::new(None)?;
+		let ctx = |variant| ImageContext {
+			variant,
+			mirror: "https://mirrors.example.com/aosc",
+			..test_context(&device, &run)
+		};
+		let rootdir = TestDir::new("sources")?;
+		let path = rootdir.join(EXTRA_SOURCES_PATH);
+		let base = ctx(&ImageVariant::Base);
+		base.write_extra_sources(&rootdir)?;
+		assert_eq!(
+			fs::read_to_string(&path)?,
+			"deb https://mirrors.example.com/aosc stable main partner
+\
+			deb [arch=amd64] https://example.com/debs stable main
+"
+		);
+		assert!(base.verify_extra_sources(&rootdir).is_err());
+		base.remove_extra_sources(&rootdir)?;
+		assert_eq!(
+			fs::read_to_string(&path)?,
+			"deb https://mirrors.example.com/aosc stable main partner
+"
+		);
+		base.verify_extra_sources(&rootdir)?;
+		// Nothing is defined for desktop images.
+		fs::remove_file(&path)?;
+		let desktop = ctx(&ImageVariant::Desktop);
+		desktop.write_extra_sources(&rootdir)?;
+		desktop.remove_extra_sources(&rootdir)?;
+		assert!(!path.exists());
+		desktop.verify_extra_sources(&rootdir)
+	}
+
+	#[test]
+	fn test_render_source_line() -> Result<()> {
+		assert_eq!(
+			render_source_line(
+				"deb [arch={arch}] {mirror} stable main",
+				"https://repo.aosc.io/debs",
+				"arm64"
+			)?,
+			"deb [arch=arm64] https://repo.aosc.io/debs stable main"
+		);
+		assert!(render_source_line("deb {mirror} {suite} main", "", "").is_err());
+		assert!(render_source_line("deb {mirror stable main", "", "").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_source_line() {
+		for line in [
+			"deb https://repo.aosc.io/debs stable main",
+			"deb-src https://repo.aosc.io/debs stable main partner",
+			"deb [arch=arm64 signed-by=/usr/share/keyrings/x.gpg] https://example.com/debs stable main",
+			"deb file:///srv/debs ./",
+		] {
+			assert!(check_source_line(line).is_ok(), "{} is rejected", line);
+		}
+		for line in [
+			"",
+			"deb",
+			"rpm https://example.com/debs stable main",
+			"deb https://example.com/debs stable",
+			"deb https://example.com/debs ./ main",
+			"deb example.com/debs stable main",
+			"deb [arch=arm64 https://example.com/debs stable main",
+			"deb [arch] https://example.com/debs stable main",
+			"deb https://example.com/debs stable main # partner",
+			"deb https://example.com/debs stable main\ndeb https://example.com/debs stable partner",
+		] {
+			assert!(check_source_line(line).is_err(), "{:?} is accepted", line);
+		}
+	}
+}
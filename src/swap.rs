@@ -0,0 +1,313 @@
+//! Module handling the swap space of the images.
+//!
+//! Swap partitions are not allowed, low-RAM devices can have a swapfile on the root filesystem, or a compressed swap space in RAM with zram instead.
+//!
+//! For details please go to [`SwapSpec`].
+//!
+use std::{
+	fs::{self, File},
+	io::Write,
+	os::unix::fs::OpenOptionsExt,
+	path::Path,
+	process::Command,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use strum::VariantArray;
+
+use crate::{
+	context::ImageVariant, device::ImageVariantSizes, filesystem::FilesystemType,
+	utils::cmd_run_check_status,
+};
+
+/// Path to the swapfile, relative to the root of the target.
+pub const SWAPFILE_PATH: &str = "swapfile";
+/// Path to the config of zram-generator, relative to the root of the target.
+pub const ZRAM_GENERATOR_CONF: &str = "etc/systemd/zram-generator.conf";
+/// Compression algorithms of zram known to the kernel.
+const ZRAM_COMPRESSION: &[&str] = &["lzo", "lzo-rle", "lz4", "lz4hc", "zstd", "deflate", "842"];
+
+/// Swap space of the images, either a swapfile or zram.
+///
+/// ```toml
+/// [swap]
+/// type = "file"
+/// size_mib = 1024
+/// ```
+///
+/// ```toml
+/// [swap]
+/// type = "zram"
+/// size_mib = 2048
+/// # Optional, the default of the kernel is used otherwise.
+/// compression = "zstd"
+/// ```
+///
+/// - `file`: `/swapfile` of `size_mib` MiB is allocated on the root filesystem and added to `/etc/fstab`, after the users are created and before the post installation scripts run.
+///   It must be smaller than the images of all variants, and the root filesystem must be ext4, XFS or Btrfs.
+///   The swapfile is filled with zeros except on ext4, as swapfiles allocated with `fallocate` are rejected by the kernel on some filesystems.
+///   On Btrfs, copy-on-write is disabled for the swapfile with `chattr +C`, which must be available on the build host.
+///   Root filesystem tarballs can not carry an allocated swapfile, it is skipped for them.
+/// - `zram`: `/etc/systemd/zram-generator.conf` is written, setting up `/dev/zram0` of `size_mib` MiB as swap on every boot.
+///   `compression` is one of `lzo`, `lzo-rle`, `lz4`, `lz4hc`, `zstd`, `deflate` and `842`. The `zram-generator` package must be installed, e.g. in `bsp_packages`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum SwapSpec {
+	File {
+		size_mib: u64,
+	},
+	Zram {
+		size_mib: u64,
+		compression: Option<String>,
+	},
+}
+
+impl SwapSpec {
+	/// Make sure the swap space fits the images of `sizes`, with the root filesystem `rootfs_type`.
+	pub fn check(&self, sizes: &ImageVariantSizes, rootfs_type: &FilesystemType) -> Result<()> {
+		match self {
+			SwapSpec::File { size_mib } => {
+				if *size_mib == 0 {
+					bail!("The size of the swapfile can not be 0");
+				}
+				for variant in ImageVariant::VARIANTS {
+					let image_size = sizes.get_variant_size(variant);
+					if *size_mib >= image_size {
+						bail!(
+							"The swapfile of {} MiB does not fit in the {} images of {} MiB",
+							size_mib,
+							variant.to_string().to_lowercase(),
+							image_size
+						);
+					}
+				}
+				check_swapfile_filesystem(rootfs_type)?;
+			}
+			SwapSpec::Zram {
+				size_mib,
+				compression,
+			} => {
+				if *size_mib == 0 {
+					bail!("The size of zram can not be 0");
+				}
+				if let Some(compression) = compression
+					&& !ZRAM_COMPRESSION.contains(&compression.as_str())
+				{
+					bail!(
+						"Unknown compression algorithm '{}' of zram, expected one of {}",
+						compression,
+						ZRAM_COMPRESSION.join(", ")
+					);
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Make sure a swapfile can be created on `fstype`.
+fn check_swapfile_filesystem(fstype: &FilesystemType) -> Result<()> {
+	match fstype {
+		FilesystemType::Ext4 | FilesystemType::Xfs => Ok(()),
+		FilesystemType::Btrfs => {
+			if crate::utils::find_program("chattr", ".").is_none() {
+				bail!(
+					"Swapfiles on Btrfs need copy-on-write disabled with chattr +C, but chattr is not found on the build host"
+				);
+			}
+			Ok(())
+		}
+		_ => bail!(
+			"Swapfiles can not be created on {:?} root filesystems, only on ext4, XFS and Btrfs",
+			fstype
+		),
+	}
+}
+
+/// Create the swapfile of `size_mib` MiB in the system at `rootdir`, whose root filesystem is `fstype`, and add it to `/etc/fstab`.
+pub fn create_swapfile(rootdir: &Path, size_mib: u64, fstype: &FilesystemType) -> Result<()> {
+	check_swapfile_filesystem(fstype)?;
+	let path = rootdir.join(SWAPFILE_PATH);
+	// Left over by an interrupted build.
+	if path.symlink_metadata().is_ok() {
+		fs::remove_file(&path).context(format!("Unable to remove {}", path.display()))?;
+	}
+	File::options()
+		.write(true)
+		.create_new(true)
+		.mode(0o600)
+		.open(&path)
+		.context(format!("Unable to create {}", path.display()))?;
+	if fstype == &FilesystemType::Btrfs {
+		// Only takes effect while the file is empty.
+		cmd_run_check_status(Command::new("chattr").arg("+C").arg(&path))?;
+	}
+	if fstype == &FilesystemType::Ext4 {
+		cmd_run_check_status(
+			Command::new("fallocate")
+				.arg("-l")
+				.arg(format!("{}MiB", size_mib))
+				.arg(&path),
+		)?;
+	} else {
+		cmd_run_check_status(
+			Command::new("dd")
+				.arg("if=/dev/zero")
+				.arg(format!("of={}", path.display()))
+				.args(["bs=1M", "conv=notrunc,fsync", "status=none"])
+				.arg(format!("count={}", size_mib)),
+		)?;
+	}
+	cmd_run_check_status(Command::new("mkswap").arg("-q").arg(&path))?;
+	add_fstab_entry(rootdir)
+}
+
+/// The line of the swapfile in `/etc/fstab`.
+fn fstab_entry() -> String {
+	format!("/{}\tnone\tswap\tdefaults\t0\t0", SWAPFILE_PATH)
+}
+
+/// Add the swapfile to `/etc/fstab` of the system at `rootdir`, unless it is already there.
+fn add_fstab_entry(rootdir: &Path) -> Result<()> {
+	let path = rootdir.join("etc/fstab");
+	let content = fs::read_to_string(&path).unwrap_or_default();
+	let swapfile = format!("/{}", SWAPFILE_PATH);
+	if content
+		.lines()
+		.any(|x| x.split_whitespace().next() == Some(swapfile.as_str()))
+	{
+		return Ok(());
+	}
+	let mut fstab = File::options()
+		.create(true)
+		.append(true)
+		.open(&path)
+		.context(format!("Unable to open {}", path.display()))?;
+	if !content.is_empty() && !content.ends_with('\n') {
+		writeln!(fstab)?;
+	}
+	writeln!(fstab, "{}", fstab_entry())?;
+	Ok(())
+}
+
+/// The config of zram-generator for `size_mib` MiB of zram, compressed with `compression`.
+pub fn zram_generator_conf(size_mib: u64, compression: Option<&str>) -> String {
+	let mut conf = format!(
+		"# Generated by mkrawimg from the device spec.\n[zram0]\nzram-size = {}\n",
+		size_mib
+	);
+	if let Some(compression) = compression {
+		conf += &format!("compression-algorithm = {}\n", compression);
+	}
+	conf
+}
+
+/// Write the config of zram-generator into the system at `rootdir`.
+pub fn write_zram_generator_conf(
+	rootdir: &Path,
+	size_mib: u64,
+	compression: Option<&str>,
+) -> Result<()> {
+	let path = rootdir.join(ZRAM_GENERATOR_CONF);
+	if let Some(dir) = path.parent() {
+		fs::create_dir_all(dir).context(format!("Unable to create {}", dir.display()))?;
+	}
+	fs::write(&path, zram_generator_conf(size_mib, compression))
+		.context(format!("Unable to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::fs::PermissionsExt;
+
+	use super::*;
+	use crate::fixtures::TestDir;
+
+	fn sizes() -> ImageVariantSizes {
+		ImageVariantSizes {
+			base: 4096,
+			desktop: 16384,
+			server: 4096,
+		}
+	}
+
+	fn swap(s: &str) -> Result<SwapSpec> {
+		#[derive(Deserialize)]
+		struct Spec {
+			swap: SwapSpec,
+		}
+		Ok(toml::from_str::<Spec>(s)?.swap)
+	}
+
+	#[test]
+	fn test_check_swap() -> Result<()> {
+		let file = swap("[swap]\ntype = \"file\"\nsize_mib = 1024")?;
+		assert_eq!(file, SwapSpec::File { size_mib: 1024 });
+		file.check(&sizes(), &FilesystemType::Ext4)?;
+		file.check(&sizes(), &FilesystemType::Xfs)?;
+		assert!(file.check(&sizes(), &FilesystemType::Fat32).is_err());
+		// Larger than the base images
+		let file = SwapSpec::File { size_mib: 8192 };
+		let e = file
+			.check(&sizes(), &FilesystemType::Ext4)
+			.unwrap_err()
+			.to_string();
+		assert!(e.contains("base images"), "{}", e);
+		assert!(
+			SwapSpec::File { size_mib: 0 }
+				.check(&sizes(), &FilesystemType::Ext4)
+				.is_err()
+		);
+		// Not limited by the image size
+		let zram = swap("[swap]\ntype = \"zram\"\nsize_mib = 8192\ncompression = \"zstd\"")?;
+		zram.check(&sizes(), &FilesystemType::Fat32)?;
+		for invalid in [
+			"[swap]\ntype = \"zram\"\nsize_mib = 0",
+			"[swap]\ntype = \"zram\"\nsize_mib = 1024\ncompression = \"gzip\"",
+		] {
+			assert!(
+				swap(invalid)?
+					.check(&sizes(), &FilesystemType::Ext4)
+					.is_err(),
+				"{}",
+				invalid
+			);
+		}
+		assert!(swap("[swap]\ntype = \"partition\"\nsize_mib = 1024").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_zram_generator_conf() {
+		assert_eq!(
+			zram_generator_conf(2048, Some("zstd")),
+			"# Generated by mkrawimg from the device spec.\n[zram0]\nzram-size = 2048\ncompression-algorithm = zstd\n"
+		);
+		assert!(!zram_generator_conf(512, None).contains("compression"));
+	}
+
+	#[test]
+	fn test_create_swapfile() -> Result<()> {
+		let root = TestDir::new("swap")?;
+		fs::create_dir_all(root.join("etc"))?;
+		fs::write(root.join("etc/fstab"), "UUID=0 / ext4 defaults 0 1")?;
+		let swapfile = root.join(SWAPFILE_PATH);
+		for fstype in [FilesystemType::Ext4, FilesystemType::Xfs] {
+			create_swapfile(&root, 1, &fstype)?;
+			let metadata = swapfile.metadata()?;
+			assert_eq!(metadata.len(), 1024 * 1024);
+			assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+			// The swap signature at the end of the first page
+			let content = fs::read(&swapfile)?;
+			assert!(content.windows(10).any(|x| x == b"SWAPSPACE2"));
+		}
+		// Added once
+		assert_eq!(
+			fs::read_to_string(root.join("etc/fstab"))?,
+			format!("UUID=0 / ext4 defaults 0 1\n{}\n", fstab_entry())
+		);
+		assert!(create_swapfile(&root, 1, &FilesystemType::Fat32).is_err());
+		Ok(())
+	}
+}
@@ -1,21 +1,33 @@
 #![cfg(test)]
-use std::str::FromStr;
+use std::{
+	path::Path,
+	str::FromStr,
+	time::{Duration, Instant},
+};
 
 use crate::{
+	bootloader::BootloaderSpec,
+	context::{ImageContext, ImageVariant},
+	device::DeviceSpec,
+	filesystem::FilesystemType,
+	fixtures::{TestDir, test_context},
 	partition::PartitionType,
-	utils::{create_sparse_file, geteuid},
+	plan::BuildRun,
+	utils::{
+		cmd_run_check_status, create_sparse_file, get_fsuuid, geteuid, refresh_partition_table,
+		return_ownership_recursive, wait_for_partitions,
+	},
 };
 use anyhow::{Context, Result, bail};
 use log::info;
-use loopdev;
-use toml;
+use sys_mount::{Mount, MountFlags, UnmountFlags, unmount};
 use uuid::Uuid;
 
 #[test]
 fn test_loopdev() -> Result<()> {
-	env_logger::builder()
+	let _ = env_logger::builder()
 		.filter_level(log::LevelFilter::Info)
-		.init();
+		.try_init();
 	if unsafe { geteuid() } != 0 {
 		bail!("Not being run as root user, aborting.");
 	}
@@ -36,9 +48,9 @@ fn test_loopdev() -> Result<()> {
 
 #[test]
 fn test_partition_type() -> Result<()> {
-	env_logger::builder()
+	let _ = env_logger::builder()
 		.filter_level(log::LevelFilter::Info)
-		.init();
+		.try_init();
 	let s1 = toml::to_string_pretty(&PartitionType::Basic)?;
 	let s2 = toml::to_string_pretty(&PartitionType::Linux)?;
 	let s3 = toml::to_string_pretty(&PartitionType::EFI)?;
@@ -51,3 +63,385 @@ fn test_partition_type() -> Result<()> {
 	info!("{}\n{}\n{}\n{}\n{}\n{}", s1, s2, s3, s4, s5, s6);
 	Ok(())
 }
+
+/// A device with three partitions to be formatted at the same time.
+const THREE_PARTITIONS: &str = r#"
+id = "test"
+vendor = "test"
+name = "Test Device"
+arch = "amd64"
+bsp_packages = []
+partition_map = "gpt"
+num_partitions = 3
+
+[size]
+base = 512
+desktop = 512
+server = 512
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "boot"
+size_in_sectors = 262144
+filesystem = "ext4"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "other"
+size_in_sectors = 262144
+filesystem = "ext4"
+
+[[partition]]
+num = 3
+type = "linux"
+usage = "rootfs"
+size_in_sectors = 0
+filesystem = "ext4"
+"#;
+
+#[test]
+fn test_format_partitions() -> Result<()> {
+	if unsafe { geteuid() } != 0 {
+		bail!("Not being run as root user, aborting.");
+	}
+	let device: DeviceSpec = toml::from_str(THREE_PARTITIONS)?;
+	let run = BuildRun::new(None)?;
+	let ctx = ImageContext {
+		workdir: Path::new("/tmp"),
+		outdir: Path::new("/tmp"),
+		..test_context(&device, &run)
+	};
+	let img = Path::new("/tmp/mkrawimg-test-format.img");
+	create_sparse_file(img, 512 * 1024 * 1024)?;
+	let loopctl = loopdev::LoopControl::open()?;
+	let loopdev = loopctl.next_free()?;
+	loopdev.with().part_scan(true).attach(img)?;
+	let loopdev_path = loopdev
+		.path()
+		.context("Unable to get the loop device path")?;
+	let result = ctx.partition_gpt(&loopdev_path).and_then(|mut pm_data| {
+		refresh_partition_table(&loopdev_path)?;
+		wait_for_partitions(&loopdev_path, &[1, 2, 3])?;
+		ctx.format_partitions(&loopdev_path, &mut pm_data)?;
+		Ok(pm_data)
+	});
+	loopdev.detach()?;
+	std::fs::remove_file(img)?;
+	let pm_data = result?;
+	for num in 1..=3 {
+		assert!(pm_data.data[&num].fs_uuid.is_some());
+	}
+	Ok(())
+}
+
+/// Write the partition table of `device` with `seed` to a new image, returning the first 2 MiB of it.
+fn partition_with_seed(device: &DeviceSpec, seed: &str, img: &Path) -> Result<Vec<u8>> {
+	let run = BuildRun::new(None)?;
+	let ctx = ImageContext {
+		workdir: Path::new("/tmp"),
+		outdir: Path::new("/tmp"),
+		seed: Some(seed),
+		..test_context(device, &run)
+	};
+	create_sparse_file(img, 512 * 1024 * 1024)?;
+	let loopctl = loopdev::LoopControl::open()?;
+	let loopdev = loopctl.next_free()?;
+	loopdev.attach_file(img)?;
+	let loopdev_path = loopdev
+		.path()
+		.context("Unable to get the loop device path")?;
+	let result = ctx.partition_gpt(&loopdev_path);
+	loopdev.detach()?;
+	result?;
+	let mut head = std::fs::read(img)?;
+	std::fs::remove_file(img)?;
+	head.truncate(2 * 1024 * 1024);
+	Ok(head)
+}
+
+#[test]
+fn test_reproducible_partitioning() -> Result<()> {
+	if unsafe { geteuid() } != 0 {
+		bail!("Not being run as root user, aborting.");
+	}
+	let device: DeviceSpec = toml::from_str(THREE_PARTITIONS)?;
+	let first = partition_with_seed(
+		&device,
+		"0",
+		Path::new("/tmp/mkrawimg-test-reproducible-1.img"),
+	)?;
+	let second = partition_with_seed(
+		&device,
+		"0",
+		Path::new("/tmp/mkrawimg-test-reproducible-2.img"),
+	)?;
+	assert!(first == second, "The partition tables differ");
+	let other = partition_with_seed(
+		&device,
+		"1",
+		Path::new("/tmp/mkrawimg-test-reproducible-3.img"),
+	)?;
+	assert!(first != other, "The seed is not used");
+	Ok(())
+}
+
+/// A 3 TiB device with a fill-remaining data partition beyond the first 2 TiB.
+const LARGE_GPT: &str = r#"
+id = "test"
+vendor = "test"
+name = "Test Device"
+arch = "amd64"
+bsp_packages = []
+partition_map = "gpt"
+num_partitions = 3
+
+[size]
+base = 3145728
+desktop = 3145728
+server = 3145728
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "boot"
+size_in_sectors = 262144
+filesystem = "ext4"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+size_in_sectors = 4294967296
+filesystem = "ext4"
+
+[[partition]]
+num = 3
+type = "linux"
+usage = "other"
+size_in_sectors = 0
+filesystem = "none"
+"#;
+
+#[test]
+fn test_large_gpt_image() -> Result<()> {
+	if unsafe { geteuid() } != 0 {
+		bail!("Not being run as root user, aborting.");
+	}
+	let device: DeviceSpec = toml::from_str(LARGE_GPT)?;
+	let run = BuildRun::new(None)?;
+	let ctx = ImageContext {
+		workdir: Path::new("/tmp"),
+		outdir: Path::new("/tmp"),
+		..test_context(&device, &run)
+	};
+	let size = device.size.get_variant_size_bytes(&ImageVariant::Base)?;
+	let img = Path::new("/var/tmp/mkrawimg-test-large.img");
+	create_sparse_file(img, size)?;
+	let loopctl = loopdev::LoopControl::open()?;
+	let loopdev = loopctl.next_free()?;
+	loopdev.attach_file(img)?;
+	let loopdev_path = loopdev
+		.path()
+		.context("Unable to get the loop device path")?;
+	let result = ctx.partition_gpt(&loopdev_path);
+	loopdev.detach()?;
+	let table = result.and_then(|_| {
+		let mut fd = std::fs::File::open(img)?;
+		Ok(gptman::GPT::read_from(&mut fd, 512)?)
+	});
+	std::fs::remove_file(img)?;
+	let table = table?;
+	// The sectors beyond 2 TiB do not fit in 32 bits
+	assert!(table[3].starting_lba > u32::MAX as u64);
+	// The last partition takes the rest of the image
+	assert!(table[3].ending_lba <= table.header.last_usable_lba);
+	assert!(table[3].ending_lba > size / 512 - 2048);
+	assert_eq!(table.header.last_usable_lba, size / 512 - 34);
+	Ok(())
+}
+
+#[test]
+fn test_umount_stack() -> Result<()> {
+	if unsafe { geteuid() } != 0 {
+		bail!("Not being run as root user, aborting.");
+	}
+	let img = Path::new("/tmp/mkrawimg-test-umount.img");
+	let mnt = Path::new("/tmp/mkrawimg-test-umount");
+	create_sparse_file(img, 64 * 1024 * 1024)?;
+	std::fs::create_dir_all(mnt)?;
+	let loopctl = loopdev::LoopControl::open()?;
+	let loopdev = loopctl.next_free()?;
+	loopdev.attach_file(img)?;
+	let loopdev_path = loopdev
+		.path()
+		.context("Unable to get the loop device path")?;
+	let result = FilesystemType::Ext4
+		.get_mkfs_cmdline(&loopdev_path, None, None)
+		.and_then(|mut cmd| cmd_run_check_status(&mut cmd))
+		.and_then(|_| {
+			let mut stack = Vec::new();
+			Mount::builder().fstype("ext4").mount(&loopdev_path, mnt)?;
+			stack.push(mnt.to_owned());
+			// The same filesystem mounted twice, and a tmpfs on top of it.
+			std::fs::create_dir_all(mnt.join("bind"))?;
+			std::fs::create_dir_all(mnt.join("tmp"))?;
+			Mount::builder()
+				.flags(MountFlags::BIND)
+				.mount(mnt, mnt.join("bind"))?;
+			stack.push(mnt.join("bind"));
+			Mount::builder()
+				.fstype("tmpfs")
+				.mount("tmpfs", mnt.join("tmp"))?;
+			stack.push(mnt.join("tmp"));
+			std::fs::write(mnt.join("data"), "durable")?;
+			let start = Instant::now();
+			ImageContext::umount_stack(&mut stack)?;
+			// Used to take 100 ms for each filesystem.
+			assert!(start.elapsed() < Duration::from_secs(5));
+			assert!(stack.is_empty());
+			Ok(())
+		});
+	loopdev.detach()?;
+	// Everything is written to the image before the loop device is detached.
+	let content = result.and_then(|_| {
+		let loopdev = loopctl.next_free()?;
+		loopdev.attach_file(img)?;
+		let loopdev_path = loopdev
+			.path()
+			.context("Unable to get the loop device path")?;
+		let content = Mount::builder()
+			.fstype("ext4")
+			.mount(&loopdev_path, mnt)
+			.map_err(anyhow::Error::from)
+			.and_then(|_| {
+				let content = std::fs::read_to_string(mnt.join("data"));
+				unmount(mnt, UnmountFlags::empty())?;
+				Ok(content?)
+			});
+		loopdev.detach()?;
+		content
+	});
+	std::fs::remove_file(img)?;
+	std::fs::remove_dir(mnt)?;
+	assert_eq!(content?, "durable");
+	Ok(())
+}
+
+#[test]
+fn test_apply_offset_loopdev() -> Result<()> {
+	if unsafe { geteuid() } != 0 {
+		bail!("Not being run as root user, aborting.");
+	}
+	let img = Path::new("/tmp/mkrawimg-test-flash.img");
+	let blob = Path::new("/tmp/mkrawimg-test-flash.bin");
+	std::fs::write(img, vec![0xaau8; 4 * 1024 * 1024])?;
+	std::fs::write(blob, vec![1u8; 1024 * 1024 + 100])?;
+	let loopctl = loopdev::LoopControl::open()?;
+	let loopdev = loopctl.next_free()?;
+	loopdev.attach_file(img)?;
+	let loopdev_path = loopdev
+		.path()
+		.context("Unable to get the loop device path")?;
+	// The reserved gap is deallocated in the backing file through the loop device.
+	let result = BootloaderSpec::apply_offset(
+		blob,
+		0x8000 + 17,
+		Some(2 * 1024 * 1024),
+		Some((1, 3 * 1024 * 1024)),
+		&loopdev_path,
+	);
+	loopdev.detach()?;
+	let content = std::fs::read(img)?;
+	std::fs::remove_file(img)?;
+	std::fs::remove_file(blob)?;
+	result?;
+	let start = 0x8000 + 17;
+	let end = start + 1024 * 1024 + 100;
+	assert!(content[..start].iter().all(|x| *x == 0xaa));
+	assert!(content[start..end].iter().all(|x| *x == 1));
+	assert!(
+		content[end..start + 2 * 1024 * 1024]
+			.iter()
+			.all(|x| *x == 0)
+	);
+	assert!(
+		content[start + 2 * 1024 * 1024..]
+			.iter()
+			.all(|x| *x == 0xaa)
+	);
+	Ok(())
+}
+
+#[test]
+fn test_get_fsuuid() -> Result<()> {
+	if unsafe { geteuid() } != 0 {
+		bail!("Not being run as root user, aborting.");
+	}
+	const UUID: &str = "0f1e2d3c-4b5a-6978-8796-a5b4c3d2e1f0";
+	let img = Path::new("/tmp/mkrawimg-test-fsuuid.img");
+	create_sparse_file(img, 64 * 1024 * 1024)?;
+	let loopctl = loopdev::LoopControl::open()?;
+	let loopdev = loopctl.next_free()?;
+	loopdev.attach_file(img)?;
+	let loopdev_path = loopdev
+		.path()
+		.context("Unable to get the loop device path")?;
+	let result = FilesystemType::Ext4
+		.get_mkfs_cmdline(&loopdev_path, None, Some(UUID))
+		.and_then(|mut cmd| cmd_run_check_status(&mut cmd))
+		.and_then(|_| get_fsuuid(&loopdev_path));
+	loopdev.detach()?;
+	std::fs::remove_file(img)?;
+	assert_eq!(result?, UUID);
+	Ok(())
+}
+
+#[test]
+fn test_return_ownership_recursive() -> Result<()> {
+	use std::os::unix::fs::{MetadataExt, symlink};
+
+	if unsafe { geteuid() } != 0 {
+		bail!("Not being run as root user, aborting.");
+	}
+	let dir = TestDir::new("ownership")?;
+	let out = dir.join("out");
+	std::fs::create_dir_all(out.join("sub"))?;
+	// Owned by root, outside of the directory
+	let secret = dir.join("secret");
+	let outside = dir.join("outside");
+	std::fs::write(&secret, "root only")?;
+	std::fs::write(&outside, "linked")?;
+	std::fs::write(out.join("sub/image.img"), "image")?;
+	symlink(&secret, out.join("link"))?;
+	symlink(dir.join("missing"), out.join("dangling"))?;
+	std::fs::hard_link(&outside, out.join("hard"))?;
+	std::fs::write(out.join("a"), "linked within")?;
+	std::fs::hard_link(out.join("a"), out.join("sub/b"))?;
+	let owner = |path: &Path| -> Result<(u32, u32)> {
+		let metadata = std::fs::symlink_metadata(path)?;
+		Ok((metadata.uid(), metadata.gid()))
+	};
+
+	// Not followed as the root either
+	let root_link = dir.join("root-link");
+	symlink(&secret, &root_link)?;
+	let result = return_ownership_recursive(&out, Some(1234), Some(1234)).and(
+		return_ownership_recursive(&root_link, Some(1234), Some(1234)),
+	);
+	let root_link_owner = owner(&root_link);
+	let owners =
+		["", "sub", "sub/image.img", "link", "dangling", "a", "sub/b"].map(|x| owner(&out.join(x)));
+	let secret_owner = owner(&secret)?;
+	let outside_owner = owner(&outside)?;
+	result?;
+	for owner in owners {
+		assert_eq!(owner?, (1234, 1234));
+	}
+	assert_eq!(root_link_owner?, (1234, 1234));
+	// The targets of the links are untouched
+	assert_eq!(secret_owner, (0, 0));
+	assert_eq!(outside_owner, (0, 0));
+	Ok(())
+}
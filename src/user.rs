@@ -0,0 +1,260 @@
+//! Module handling the extra users created in the images.
+//!
+//! Besides the built-in user given on the command line, devices can define more users, e.g. an administrator and an unprivileged operator of a kiosk.
+//!
+//! For details please go to [`UserSpec`].
+//!
+use std::{collections::HashSet, fmt::Display};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::{
+	utils::{UserOptions, is_password_hash},
+	validate::{
+		validate_account_name, validate_password, validate_user_options, validate_username,
+	},
+};
+
+/// A user created in the images, after the built-in user.
+///
+/// ```toml
+/// [[user]]
+/// name = "admin"
+/// # Generated with mkpasswd.
+/// password_hash = "$y$j9T$..."
+/// groups = ["wheel", "audio", "video"]
+/// comment = "Administrator"
+///
+/// [[user]]
+/// name = "operator"
+/// password_hash = "$6$..."
+/// groups = ["dialout"]
+/// shell = "/usr/bin/zsh"
+/// ```
+///
+/// - `name`: Name of the user. It must be a valid username (see `--user` of the [global options](crate::cli::Cmdline)), can not be `root` or the name of the built-in user, and must be unique.
+/// - `password_hash`: The password as a crypt(3) hash, starting with `$6$` (SHA-512) or `$y$` (yescrypt), e.g. from `mkpasswd`. Plaintext passwords are rejected, as they would be committed to the registry. Without it, the user can not log in with a password.
+/// - `groups`: Groups of the user, the default groups of the built-in user are not added. Groups which do not exist in the target system are created as system groups with `groupadd -r`.
+/// - `comment`: The comment (GECOS field), e.g. the full name.
+/// - `shell`: Absolute path to the login shell, `/bin/bash` by default.
+/// - `uid`: UID of the user, picked by `useradd` by default.
+/// - `create_home`: Whether to create `/home/<name>` from `/etc/skel`, `true` by default.
+///
+/// The users are created in the order they are defined. Set `skip_default_user = true` in the device spec to only create these users, not the built-in one.
+///
+/// The names of all created users are available to the scripts as `USERS`, see the [defined variables].
+///
+/// [defined variables]: crate::device::DeviceSpec#available-defined-variables
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct UserSpec {
+	pub name: String,
+	pub password_hash: Option<String>,
+	pub groups: Option<Vec<String>>,
+	pub comment: Option<String>,
+	pub shell: Option<String>,
+	pub uid: Option<u32>,
+	#[serde(default = "default_create_home")]
+	pub create_home: bool,
+}
+
+fn default_create_home() -> bool {
+	true
+}
+
+impl UserSpec {
+	/// The built-in user named `name`, with the extra groups, shell and UID in `options`.
+	pub fn default_user(name: &str, options: &UserOptions) -> Self {
+		UserSpec {
+			name: name.to_owned(),
+			password_hash: None,
+			groups: Some(
+				options
+					.all_groups()
+					.into_iter()
+					.map(|x| x.to_owned())
+					.collect(),
+			),
+			comment: Some("Default User".to_owned()),
+			shell: options.shell.clone(),
+			uid: options.uid,
+			create_home: true,
+		}
+	}
+
+	fn check(&self) -> Result<()> {
+		validate_username(&self.name)?;
+		if self.name == "root" {
+			bail!("User root can not be defined");
+		}
+		if let Some(hash) = &self.password_hash {
+			if !is_password_hash(hash) {
+				bail!(
+					"password_hash does not look like a crypt(3) hash starting with $6$ or $y$, plaintext passwords are not allowed"
+				);
+			}
+			validate_password(hash)?;
+		}
+		for group in self.groups.iter().flatten() {
+			validate_account_name("Group name", group)?;
+		}
+		validate_user_options(&UserOptions {
+			groups: None,
+			shell: self.shell.clone(),
+			uid: self.uid,
+		})
+	}
+}
+
+/// What to do with the password of root.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum RootPolicy {
+	/// Lock the password, so root can not log in with a password.
+	#[default]
+	Locked,
+	/// Set the password to the crypt(3) hash.
+	PasswordHash(String),
+}
+
+impl Display for RootPolicy {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			RootPolicy::Locked => write!(f, "locked"),
+			RootPolicy::PasswordHash(_) => write!(f, "password"),
+		}
+	}
+}
+
+impl RootPolicy {
+	/// The policy setting the password of root to `hash`, which must be a crypt(3) hash.
+	pub fn password_hash(hash: String) -> Result<Self> {
+		if !is_password_hash(&hash) {
+			bail!(
+				"The password of root must be a crypt(3) hash starting with $6$ or $y$, e.g. from mkpasswd"
+			);
+		}
+		validate_password(&hash)?;
+		Ok(RootPolicy::PasswordHash(hash))
+	}
+}
+
+/// Make sure the `users` of a device are valid and unique.
+///
+/// Without the built-in user (`skip_default_user`), there must be at least one user.
+pub fn check_users(users: &[UserSpec], skip_default_user: bool) -> Result<()> {
+	if skip_default_user && users.is_empty() {
+		bail!("skip_default_user is set, but no users are defined");
+	}
+	let mut names = HashSet::new();
+	let mut uids = HashSet::new();
+	for user in users {
+		user.check()
+			.context(format!("Invalid user '{}'", user.name))?;
+		if !names.insert(user.name.as_str()) {
+			bail!("User '{}' is defined more than once", user.name);
+		}
+		if let Some(uid) = user.uid
+			&& !uids.insert(uid)
+		{
+			bail!("UID {} is used by more than one user", uid);
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn users(s: &str) -> Result<Vec<UserSpec>> {
+		#[derive(Deserialize)]
+		struct Users {
+			user: Vec<UserSpec>,
+		}
+		Ok(toml::from_str::<Users>(s)?.user)
+	}
+
+	#[test]
+	fn test_check_users() -> Result<()> {
+		let valid = users(
+			r#"
+			[[user]]
+			name = "admin"
+			password_hash = "$y$j9T$salt$hash"
+			groups = ["wheel"]
+			[[user]]
+			name = "operator"
+			password_hash = "$6$salt$hash"
+			shell = "/usr/bin/zsh"
+			uid = 1100
+			create_home = false
+			[[user]]
+			name = "kiosk"
+			"#,
+		)?;
+		assert!(valid[0].create_home);
+		assert!(!valid[1].create_home);
+		check_users(&valid, true)?;
+		check_users(&[], false)?;
+		assert!(check_users(&[], true).is_err());
+		for invalid in [
+			"name = \"root\"",
+			"name = \"Admin\"",
+			"name = \"admin\"\npassword_hash = \"anthon\"",
+			"name = \"admin\"\npassword_hash = \"$6$a:b\"",
+			"name = \"admin\"\ngroups = [\"Wheel\"]",
+			"name = \"admin\"\nshell = \"bash\"",
+			"name = \"admin\"\nuid = 0",
+		] {
+			let users = users(&format!("[[user]]\n{}", invalid))?;
+			assert!(
+				check_users(&users, false).is_err(),
+				"{} is accepted",
+				invalid
+			);
+		}
+		// Duplicate names and UIDs
+		for invalid in [
+			"[[user]]\nname = \"admin\"\n[[user]]\nname = \"admin\"",
+			"[[user]]\nname = \"a\"\nuid = 1100\n[[user]]\nname = \"b\"\nuid = 1100",
+		] {
+			assert!(check_users(&users(invalid)?, false).is_err(), "{}", invalid);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_root_policy() -> Result<()> {
+		assert_eq!(RootPolicy::default().to_string(), "locked");
+		let policy = RootPolicy::password_hash("$6$salt$hash".to_owned())?;
+		assert_eq!(policy, RootPolicy::PasswordHash("$6$salt$hash".to_owned()));
+		assert_eq!(policy.to_string(), "password");
+		for hash in ["anthon", "", "$6$a:b", "$1$salt$hash"] {
+			assert!(
+				RootPolicy::password_hash(hash.to_owned()).is_err(),
+				"{} is accepted",
+				hash
+			);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_default_user() {
+		let user = UserSpec::default_user(
+			"aosc",
+			&UserOptions {
+				groups: Some(vec!["gpio".into()]),
+				shell: None,
+				uid: Some(1000),
+			},
+		);
+		assert_eq!(user.name, "aosc");
+		assert_eq!(
+			user.groups.unwrap(),
+			vec!["audio", "video", "cdrom", "plugdev", "tty", "wheel", "gpio"]
+		);
+		assert_eq!(user.uid, Some(1000));
+		assert!(user.create_home);
+	}
+}
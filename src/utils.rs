@@ -1,20 +1,39 @@
 use std::{
-	ffi::{CString, c_int, c_void},
-	fs::File,
-	io::{Seek, Write},
-	os::unix::fs::chown,
-	path::{Path, PathBuf},
-	process::{Command, Stdio},
+	collections::{HashMap, VecDeque},
+	ffi::{CStr, CString, OsString, c_int, c_void},
+	fs::{File, FileTimes},
+	io::{BufRead, BufReader, IsTerminal, Read, Seek, Write},
+	os::{
+		fd::AsRawFd,
+		unix::{
+			ffi::OsStringExt,
+			fs::{FileTypeExt, MetadataExt, PermissionsExt, chown, lchown},
+		},
+	},
+	path::{Component, Path, PathBuf},
+	process::{Command, ExitStatus, Stdio},
+	sync::{Mutex, OnceLock, mpsc},
+	thread,
+	time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result, anyhow, bail};
+#[cfg(all(feature = "blkid", not(feature = "no-blkid")))]
 use blkid::prober::ProbeState;
 use libc::{O_NONBLOCK, O_RDONLY, close, open};
-use log::{debug, info};
+use log::{debug, info, warn};
+use loopdev::{LoopControl, LoopDevice};
+use sha2::{Digest, Sha256};
+use sys_mount::{UnmountFlags, unmount};
 use termsize::Size;
 use walkdir::WalkDir;
 
-use crate::{context::ImageVariant, device::DeviceArch};
+use crate::{
+	bootstrap::{AB_DIR, BootstrapHashes, BootstrapInputs, check_pins, save_hashes},
+	context::ImageVariant,
+	device::DeviceArch,
+	user::UserSpec,
+};
 
 #[link(name = "c")]
 unsafe extern "C" {
@@ -25,14 +44,118 @@ unsafe extern "C" {
 	pub fn syncfs(fd: c_int) -> c_int;
 }
 
-const AB_DIR: &str = "/usr/share/aoscbootstrap";
 const DEFAULT_GROUPS: &[&str] = &["audio", "video", "cdrom", "plugdev", "tty", "wheel"];
-const LOCALCONF_PATH: &str = "etc/locale.conf";
+const DEFAULT_SHELL: &str = "/bin/bash";
+pub(crate) const LOCALCONF_PATH: &str = "etc/locale.conf";
 const BINFMT_DIR: &str = "/proc/sys/fs/binfmt_misc";
+pub(crate) const SYSFS_BLOCK_DIR: &str = "/sys/block";
+const PARTITION_TABLE_REREAD_ATTEMPTS: u32 = 5;
+const PARTITION_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+/// `BLKPG` of `linux/fs.h`.
+const BLKPG: libc::Ioctl = 0x1269;
+/// `BLKPG_ADD_PARTITION` of `linux/blkpg.h`.
+const BLKPG_ADD_PARTITION: c_int = 1;
+
+/// Capabilities of the terminal attached to stderr.
+///
+/// Determined once at startup, see [`init_term_caps`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TermCaps {
+	/// Whether colored output is allowed.
+	pub color: bool,
+	/// Whether the scroll region and the progress bar can be drawn.
+	pub progress: bool,
+}
+
+static TERM_CAPS: OnceLock<TermCaps> = OnceLock::new();
+
+impl TermCaps {
+	/// Decide the capabilities from the environment and the command line.
+	///
+	/// - Nothing fancy is done if stderr is not a terminal.
+	/// - A non-empty `NO_COLOR` environment variable disables colors.
+	pub fn detect(no_color: bool, no_progress: bool) -> Self {
+		Self::resolve(
+			std::io::stderr().is_terminal(),
+			|x| std::env::var_os(x),
+			no_color,
+			no_progress,
+		)
+	}
+
+	/// Decide the capabilities as [`Self::detect`] does, from whether stderr `is_tty` and the environment variables in `env`.
+	fn resolve(
+		is_tty: bool,
+		env: impl Fn(&str) -> Option<OsString>,
+		no_color: bool,
+		no_progress: bool,
+	) -> Self {
+		let env_no_color = env("NO_COLOR").is_some_and(|x| !x.is_empty());
+		TermCaps {
+			color: is_tty && !no_color && !env_no_color,
+			progress: is_tty && !no_progress,
+		}
+	}
+}
+
+/// Detect and remember the terminal capabilities.
+///
+/// Only the first call takes effect.
+pub fn init_term_caps(no_color: bool, no_progress: bool) -> TermCaps {
+	let caps = *TERM_CAPS.get_or_init(|| TermCaps::detect(no_color, no_progress));
+	owo_colors::set_override(caps.color);
+	colored::control::set_override(caps.color);
+	caps
+}
+
+/// Get the terminal capabilities.
+///
+/// Falls back to the detected defaults if [`init_term_caps`] is not called yet.
+#[inline]
+pub fn term_caps() -> TermCaps {
+	*TERM_CAPS.get_or_init(|| TermCaps::detect(false, false))
+}
+
+/// Size of the sectors partitions are planned in, before the sector size of the loop device is known.
+pub const PLANNING_SECTOR_SIZE: u64 = 512;
+/// The number of sectors a MBR partition table can address, 2 TiB with 512-byte sectors.
+pub const MBR_MAX_SECTORS: u64 = u32::MAX as u64;
+
+/// Convert a size in MiB to bytes, failing instead of wrapping around.
+pub fn mib_to_bytes(mib: u64) -> Result<u64> {
+	mib.checked_mul(1 << 20)
+		.context(format!("Size of {} MiB overflows a 64-bit byte count", mib))
+}
+
+/// Convert a number of sectors to bytes, failing instead of wrapping around.
+pub fn sectors_to_bytes(sectors: u64, sector_size: u64) -> Result<u64> {
+	sectors.checked_mul(sector_size).context(format!(
+		"{} sectors of {} bytes overflow a 64-bit byte count",
+		sectors, sector_size
+	))
+}
+
+/// Format a size in bytes with the largest binary unit below it, e.g. `5.0 GiB`.
+pub fn human_size(bytes: u64) -> String {
+	const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+	if bytes < 1024 {
+		return format!("{} B", bytes);
+	}
+	let mut size = bytes as f64 / 1024.0;
+	let mut unit = 0;
+	while size >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+	format!("{:.1} {}", size, UNITS[unit])
+}
 
 /// Create a sparse file with specified size in bytes.
 pub fn get_sparse_file<P: AsRef<Path>>(path: P, size: u64) -> Result<File> {
 	let img_path = path.as_ref();
+	if size == 0 {
+		bail!("Unable to create '{}' with a size of 0", img_path.display());
+	}
 	let parent = img_path.parent().unwrap_or(Path::new("/"));
 	if !parent.exists() {
 		return Err(anyhow!(
@@ -51,9 +174,11 @@ pub fn get_sparse_file<P: AsRef<Path>>(path: P, size: u64) -> Result<File> {
 	// Seek to the desired size
 	img_file.seek(std::io::SeekFrom::Start(size - 1))?;
 	// Write zero at the end of file to punch a hole
-	img_file.write_all(&[0]).context(
-		"Failed to punch hole for sparse file. Does your filesystem support sparse files?",
-	)?;
+	// Filesystems without large file support refuse with EFBIG here.
+	img_file.write_all(&[0]).context(format!(
+		"Failed to punch hole for sparse file of {} bytes. Does your filesystem support sparse files of this size?",
+		size
+	))?;
 	img_file.sync_all()?;
 	Ok(img_file)
 }
@@ -63,20 +188,178 @@ pub fn create_sparse_file<P: AsRef<Path>>(path: P, size: u64) -> Result<()> {
 	Ok(())
 }
 
-/// Tell kernel to reread the partition table.
+/// Ask the kernel to re-read the partition table of `dev`.
+///
+/// Loop devices must be attached with partition scanning enabled (`LO_FLAGS_PARTSCAN`), otherwise the kernel refuses with `EINVAL`.
 pub fn refresh_partition_table<P: AsRef<Path>>(dev: P) -> Result<()> {
 	debug!("Refreshing partition table ...");
 	let dev = dev.as_ref();
-	let mut command = Command::new("partprobe");
-	let command = command.arg("--summary").arg(dev).stdout(Stdio::piped());
-	let out = command
-		.output()
-		.context("Failed to run partprobe(8) to refresh the partition table")?
-		.stdout;
-	info!("partprobe: {}", String::from_utf8_lossy(&out).trim());
+	let mut fd = File::options().read(true).write(true).open(dev)?;
+	let mut attempt = 1;
+	// The device is busy for a short while if it has just been attached and is being probed.
+	while let Err(e) = gptman::linux::reread_partition_table(&mut fd) {
+		if attempt == PARTITION_TABLE_REREAD_ATTEMPTS {
+			return Err(e).context(format!(
+				"Unable to re-read the partition table of {}",
+				dev.display()
+			));
+		}
+		debug!("Failed to re-read the partition table: {}, retrying ...", e);
+		thread::sleep(Duration::from_millis(200));
+		attempt += 1;
+	}
+	Ok(())
+}
+
+/// Path to the partition `num` of the block device `dev`, e.g. `/dev/loop0p1`.
+pub fn partition_path<P: AsRef<Path>>(dev: P, num: impl std::fmt::Display) -> PathBuf {
+	let mut path = dev.as_ref().as_os_str().to_owned();
+	path.push(format!("p{}", num));
+	PathBuf::from(path)
+}
+
+/// Get `path` as a string, where it has to be written as text (e.g. into scripts or the bind mounts of systemd-nspawn).
+///
+/// Fails if the path is not valid UTF-8, instead of silently replacing the invalid bytes.
+pub fn path_str(path: &Path) -> Result<&str> {
+	path.to_str().context(format!(
+		"Path '{}' is not valid UTF-8, which is not supported here",
+		path.display()
+	))
+}
+
+/// Wait until the device nodes of the partitions `nums` of `dev` show up, e.g. `/dev/loop0p1`.
+///
+/// The nodes are created asynchronously by devtmpfs and udev after the partition table is (re-)read.
+/// Partitions the kernel has not found in the table are added first, see [`add_missing_partitions`].
+pub fn wait_for_partitions<P: AsRef<Path>>(dev: P, nums: &[u32]) -> Result<()> {
+	add_missing_partitions(dev.as_ref(), nums)?;
+	let nodes: Vec<PathBuf> = nums.iter().map(|n| partition_path(&dev, *n)).collect();
+	wait_for_block_devices(&nodes, PARTITION_WAIT_TIMEOUT)
+}
+
+/// `struct blkpg_ioctl_arg` of `linux/blkpg.h`.
+#[repr(C)]
+struct BlkpgIoctlArg {
+	op: c_int,
+	flags: c_int,
+	datalen: c_int,
+	data: *mut c_void,
+}
+
+/// `struct blkpg_partition` of `linux/blkpg.h`, with the start and the length in bytes.
+#[repr(C)]
+struct BlkpgPartition {
+	start: i64,
+	length: i64,
+	pno: c_int,
+	devname: [u8; 64],
+	volname: [u8; 64],
+}
+
+/// The partitions in the GPT or MBR partition table of `dev`, as their numbers, starts and lengths in bytes.
+fn read_partition_extents(dev: &Path) -> Result<Vec<(u32, u64, u64)>> {
+	let mut fd = File::open(dev)?;
+	if let Ok(table) = gptman::GPT::find_from(&mut fd) {
+		let sector_size = table.sector_size;
+		return table
+			.iter()
+			.filter(|(_, e)| e.is_used())
+			.map(|(num, e)| Ok((num, e.starting_lba * sector_size, e.size()? * sector_size)))
+			.collect();
+	}
+	let sector_size = gptman::linux::get_sector_size(&mut fd)?;
+	let table = mbrman::MBR::read_from(&mut fd, sector_size as u32).context(format!(
+		"Unable to read the partition table of {}",
+		dev.display()
+	))?;
+	Ok(table
+		.iter()
+		.filter(|(_, e)| e.is_used())
+		.map(|(idx, e)| {
+			(
+				idx as u32,
+				e.starting_lba as u64 * sector_size,
+				e.sectors as u64 * sector_size,
+			)
+		})
+		.collect())
+}
+
+/// Add the partitions `nums` of `dev` which the kernel has not found when the partition table is read, like `partx --add` does.
+///
+/// Kernels built without the parser of the partition table type (e.g. `CONFIG_EFI_PARTITION`) find no partitions at all.
+fn add_missing_partitions(dev: &Path, nums: &[u32]) -> Result<()> {
+	let Some(name) = dev.file_name() else {
+		return Ok(());
+	};
+	let sysfs = Path::new(SYSFS_BLOCK_DIR).join(name);
+	let missing: Vec<u32> = nums
+		.iter()
+		.copied()
+		.filter(|n| !sysfs.join(partition_path(name, n)).exists())
+		.collect();
+	if missing.is_empty() || !sysfs.is_dir() {
+		return Ok(());
+	}
+	debug!(
+		"The kernel has not found partition(s) {:?} of {}, adding them ...",
+		missing,
+		dev.display()
+	);
+	let fd = File::open(dev)?;
+	for (num, start, length) in read_partition_extents(dev)? {
+		if !missing.contains(&num) {
+			continue;
+		}
+		let mut partition = BlkpgPartition {
+			start: start as i64,
+			length: length as i64,
+			pno: num as c_int,
+			devname: [0; 64],
+			volname: [0; 64],
+		};
+		let mut arg = BlkpgIoctlArg {
+			op: BLKPG_ADD_PARTITION,
+			flags: 0,
+			datalen: size_of::<BlkpgPartition>() as c_int,
+			data: (&raw mut partition).cast(),
+		};
+		if unsafe { libc::ioctl(fd.as_raw_fd(), BLKPG, &raw mut arg) } != 0 {
+			return Err(std::io::Error::last_os_error()).context(format!(
+				"Unable to add partition {} of {}",
+				num,
+				dev.display()
+			));
+		}
+	}
 	Ok(())
 }
 
+fn wait_for_block_devices(nodes: &[PathBuf], timeout: Duration) -> Result<()> {
+	let start = Instant::now();
+	loop {
+		let missing: Vec<_> = nodes
+			.iter()
+			.filter(|p| !p.metadata().is_ok_and(|m| m.file_type().is_block_device()))
+			.collect();
+		if missing.is_empty() {
+			return Ok(());
+		}
+		if start.elapsed() >= timeout {
+			bail!(
+				"Timed out waiting for the partitions to show up: {}",
+				missing
+					.iter()
+					.map(|p| p.display().to_string())
+					.collect::<Vec<_>>()
+					.join(", ")
+			);
+		}
+		thread::sleep(Duration::from_millis(50));
+	}
+}
+
 /// Run aoscbootstrap to generate a system release
 pub fn bootstrap_distribution<P: AsRef<Path>, S: AsRef<str>>(
 	variant: &ImageVariant,
@@ -85,6 +368,7 @@ pub fn bootstrap_distribution<P: AsRef<Path>, S: AsRef<str>>(
 	mirror: Option<S>,
 	sources_list: Option<P>,
 	recipe_list: Option<P>,
+	pins: Option<&BootstrapHashes>,
 ) -> Result<()> {
 	let path = path.as_ref();
 	let mirror = mirror.as_ref();
@@ -99,16 +383,24 @@ pub fn bootstrap_distribution<P: AsRef<Path>, S: AsRef<str>>(
 		info!("recipe.lst is provided, ignoring variant...");
 	}
 
+	let inputs = BootstrapInputs::new(
+		Path::new(AB_DIR),
+		variant,
+		sources_list.is_none(),
+		recipe_list.map(|x| x.as_ref()),
+	);
+	// Hash the files before they are used, so the pins are checked before spending time on bootstrapping.
+	let hashes = inputs.hashes()?;
+	if let Some(pins) = pins {
+		check_pins(&hashes, pins)?;
+	}
+
 	// Display a progressbar
 	setup_scroll_region();
-
-	let size = termsize::get().unwrap_or(Size { rows: 25, cols: 80 });
-	eprint!("\x1b7\x1b[{};0f\x1b[42m\x1b[0K\x1b[2K", size.rows);
-	eprint!(
-		"\x1b[30m[{}] Bootstrapping release ...",
+	draw_progressbar(&format!(
+		"[{}] Bootstrapping release ...",
 		variant.to_string().to_lowercase()
-	);
-	eprint!("\x1b8\x1b[0m");
+	));
 
 	info!(
 		"Bootstrapping {} system distribution to {} ...",
@@ -117,7 +409,7 @@ pub fn bootstrap_distribution<P: AsRef<Path>, S: AsRef<str>>(
 	);
 	let mut command = Command::new("aoscbootstrap");
 	let command = if let Some(sources_list) = sources_list {
-		command.args(["--sources-list", sources_list.as_ref().to_str().unwrap()])
+		command.arg("--sources-list").arg(sources_list.as_ref())
 	} else if let Some(mirror) = mirror {
 		command
 			.args(["--branch", "stable"])
@@ -129,42 +421,166 @@ pub fn bootstrap_distribution<P: AsRef<Path>, S: AsRef<str>>(
 		.arg("--target")
 		.arg(path)
 		.arg("-x")
-		.args([
-			"--config",
-			&format!("{}/{}", AB_DIR, "config/aosc-mainline.toml"),
-		])
-		.args(["--arch", &arch.to_string().to_lowercase()]);
-	if sources_list.is_none() {
-		command.args(["-s", &format!("{}/{}", AB_DIR, "scripts/reset-repo.sh")]);
-	}
-	command.args(["-s", &format!("{}/{}", AB_DIR, "scripts/enable-dkms.sh")]);
-	let command = if let Some(recipe_list) = recipe_list {
-		command.args(["--include-files", recipe_list.as_ref().to_str().unwrap()])
-	} else {
-		command.args([
-			"--include-files",
-			&format!(
-				"{}/recipes/mainline/{}-common.lst",
-				AB_DIR,
-				match &variant {
-					ImageVariant::Desktop => "kde".to_owned(),
-					_ => variant.to_string().to_lowercase(),
-				}
-			),
-		])
-	};
+		.arg("--config")
+		.arg(&inputs.config)
+		.args(["--arch", &arch.aosc_arch()]);
+	for script in &inputs.scripts {
+		command.arg("-s").arg(script);
+	}
+	let command = command.arg("--include-files").arg(&inputs.recipe);
 	debug!("Running command {:?} ...", command);
-	let status = command.status().context("Failed to run aoscbootstrap")?;
+	let mut log_path = path.as_os_str().to_owned();
+	log_path.push(".log");
+	let log_path = PathBuf::from(log_path);
+	if let Some(parent) = log_path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+	info!("Logging aoscbootstrap output to {}", log_path.display());
+	let variant_str = variant.to_string().to_lowercase();
+	let mut stage = None;
+	let mut last_drawn = Instant::now();
+	let mut num_lines: usize = 0;
+	let result = run_with_log(command, &log_path, |line| {
+		num_lines += 1;
+		let new_stage = BootstrapStage::from_line(line);
+		if new_stage.is_some() && new_stage != stage {
+			stage = new_stage;
+			info!("aoscbootstrap: {}", line.trim());
+		}
+		// Do not redraw the progressbar for every line
+		if last_drawn.elapsed() >= Duration::from_secs(1) {
+			last_drawn = Instant::now();
+			draw_progressbar(&format!(
+				"[{}] Bootstrapping release: {} ({} lines of output)",
+				variant_str,
+				stage.map_or("starting".to_owned(), |x| x.to_string()),
+				num_lines
+			));
+		}
+	});
 	// Recover the terminal
 	restore_term();
+	let (status, tail) = result.context("Failed to run aoscbootstrap")?;
 	if status.success() {
 		info!("Successfully bootstrapped {} distribution.", variant);
-		Ok(())
-	} else if let Some(c) = status.code() {
-		Err(anyhow!("aoscbootstrap exited unsuccessfully (code {})", c))
+		return save_hashes(path, &hashes);
+	}
+	let reason = if let Some(c) = status.code() {
+		format!("aoscbootstrap exited unsuccessfully (code {})", c)
 	} else {
-		Err(anyhow!("rsync exited abnormally"))
+		"aoscbootstrap exited abnormally".to_owned()
+	};
+	Err(anyhow!(
+		"Last {} lines of output:\n{}",
+		tail.len(),
+		Vec::from(tail).join("\n")
+	)
+	.context(format!(
+		"{}. Command: {:?}\nThe bootstrap path {} may be partially populated, please remove it before trying again. Full log: {}",
+		reason,
+		command,
+		path.display(),
+		log_path.display()
+	)))
+}
+
+/// Stages of aoscbootstrap, recognized from its output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum BootstrapStage {
+	Downloading,
+	Unpacking,
+	Configuring,
+}
+
+impl BootstrapStage {
+	fn from_line(line: &str) -> Option<Self> {
+		let line = line.trim_start().to_lowercase();
+		if ["download", "fetch", "get:"]
+			.iter()
+			.any(|x| line.starts_with(x))
+		{
+			Some(Self::Downloading)
+		} else if ["unpack", "extract"].iter().any(|x| line.starts_with(x)) {
+			Some(Self::Unpacking)
+		} else if ["setting up", "configur", "running", "processing triggers"]
+			.iter()
+			.any(|x| line.starts_with(x))
+		{
+			Some(Self::Configuring)
+		} else {
+			None
+		}
+	}
+}
+
+/// Number of lines of the output kept by [`run_with_log`].
+pub const LOG_TAIL_LINES: usize = 80;
+
+/// Run the command with stdout and stderr captured, line by line.
+///
+/// Every line is written to `log_path` and passed to `on_line`. Returns the exit status and the last [`LOG_TAIL_LINES`] lines.
+pub fn run_with_log<P: AsRef<Path>>(
+	command: &mut Command,
+	log_path: P,
+	on_line: impl FnMut(&str),
+) -> Result<(ExitStatus, VecDeque<String>)> {
+	let log_path = log_path.as_ref();
+	let mut log = File::create(log_path)
+		.context(format!("Failed to create log file {}", log_path.display()))?;
+	run_with_log_file(command, &mut log, on_line)
+}
+
+/// Same as [`run_with_log`], writing to the opened `log`.
+fn run_with_log_file(
+	command: &mut Command,
+	log: &mut File,
+	mut on_line: impl FnMut(&str),
+) -> Result<(ExitStatus, VecDeque<String>)> {
+	let mut child = command
+		.stdin(Stdio::null())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.context(format!("Failed to run {:?}", command.get_program()))?;
+	let (tx, rx) = mpsc::channel::<String>();
+	let mut readers = Vec::new();
+	let stdout = child
+		.stdout
+		.take()
+		.map(|x| Box::new(x) as Box<dyn Read + Send>);
+	let stderr = child
+		.stderr
+		.take()
+		.map(|x| Box::new(x) as Box<dyn Read + Send>);
+	for pipe in [stdout, stderr].into_iter().flatten() {
+		let tx = tx.clone();
+		readers.push(thread::spawn(move || {
+			for line in BufReader::new(pipe).split(b'\n').map_while(|x| x.ok()) {
+				if tx
+					.send(String::from_utf8_lossy(&line).into_owned())
+					.is_err()
+				{
+					break;
+				}
+			}
+		}));
 	}
+	drop(tx);
+	let mut tail = VecDeque::with_capacity(LOG_TAIL_LINES);
+	for line in rx {
+		writeln!(log, "{}", line)?;
+		on_line(&line);
+		if tail.len() == LOG_TAIL_LINES {
+			tail.pop_front();
+		}
+		tail.push_back(line);
+	}
+	for reader in readers {
+		reader.join().ok();
+	}
+	log.sync_all()?;
+	Ok((child.wait()?, tail))
 }
 
 pub fn rsync_sysroot<P: AsRef<Path>>(src: P, dst: P) -> Result<()> {
@@ -180,24 +596,87 @@ pub fn rsync_sysroot<P: AsRef<Path>>(src: P, dst: P) -> Result<()> {
 	);
 	let mut command = Command::new("rsync");
 	command.args(["-axAHXSW", "--numeric-ids", "--info=progress2", "--no-i-r"]);
-	command.arg(format!("{}/", src.to_string_lossy()));
-	command.arg(format!("{}/", dst.to_string_lossy()));
+	// Trailing slashes make rsync copy the contents of the directories.
+	for dir in [src, dst] {
+		let mut arg = dir.as_os_str().to_owned();
+		arg.push("/");
+		command.arg(arg);
+	}
 	debug!("Running command {:?}", command);
 	// return Ok(());
 	cmd_run_check_status(&mut command)
 }
 
+/// Create an uncompressed tarball `dst` containing everything in the directory `src`.
+///
+/// Numeric ownership, ACLs and extended attributes are preserved.
+pub fn create_tarball<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> Result<()> {
+	let src = src.as_ref();
+	let dst = dst.as_ref();
+	if !src.is_dir() {
+		bail!("Directory {} does not exist.", src.display());
+	}
+	info!(
+		"Creating tarball {} from {} ...",
+		dst.display(),
+		src.display()
+	);
+	let mut command = Command::new("tar");
+	command.args(tarball_args(src, dst));
+	debug!("Running command {:?}", command);
+	cmd_run_check_status(&mut command).context("Failed to create the tarball")
+}
+
+fn tarball_args(src: &Path, dst: &Path) -> Vec<OsString> {
+	let mut args: Vec<OsString> = [
+		"--create",
+		"--numeric-owner",
+		"--acls",
+		"--xattrs",
+		"--xattrs-include=*",
+		"--sparse",
+	]
+	.iter()
+	.map(OsString::from)
+	.collect();
+	args.push("--file".into());
+	args.push(dst.into());
+	args.push("--directory".into());
+	args.push(src.into());
+	args.push(".".into());
+	args
+}
+
 /// Set up the scroll region (for a progress bar on the bottom)
 #[inline]
 pub fn setup_scroll_region() {
+	if !term_caps().progress {
+		return;
+	}
 	let term_geometry = termsize::get().unwrap_or(Size { rows: 25, cols: 80 });
 	// Set up the scroll region
 	eprint!("\n\x1b7\x1b[0;{}r\x1b8\x1b[1A", term_geometry.rows - 1);
 }
 
+/// Draw the progress bar on the last line of the terminal.
+#[inline]
+pub fn draw_progressbar(content: &str) {
+	if !term_caps().progress {
+		return;
+	}
+	// we don't want to screw up the terminal.
+	let size = termsize::get().unwrap_or(Size { rows: 25, cols: 80 });
+	eprint!("\x1b7\x1b[{};0f\x1b[42m\x1b[0K\x1b[2K", size.rows);
+	eprint!("\x1b[30m{}", content);
+	eprint!("\x1b8\x1b[0m");
+}
+
 /// Recover the terminal
 #[inline]
 pub fn restore_term() {
+	if !term_caps().progress {
+		return;
+	}
 	let term_geometry = termsize::get().unwrap_or(Size { rows: 25, cols: 80 });
 	eprint!(
 		"\x1b7\x1b[0;{}r\x1b[{};0f\x1b[0K\x1b8",
@@ -248,65 +727,241 @@ pub fn sync_filesystem(path: &dyn AsRef<Path>) -> Result<()> {
 	Ok(())
 }
 
-pub fn add_user<S, T, P>(
+/// Whether `password` is a crypt(3) hash (SHA-512 or yescrypt) instead of plaintext.
+pub fn is_password_hash(password: &str) -> bool {
+	password.starts_with("$6$") || password.starts_with("$y$")
+}
+
+/// Read a password from the file at `path`, without the trailing newline.
+pub fn read_password_file(path: &Path) -> Result<String> {
+	let content = std::fs::read_to_string(path).context(format!(
+		"Unable to read the password file {}",
+		path.display()
+	))?;
+	let password = content.strip_suffix('\n').unwrap_or(&content);
+	let password = password.strip_suffix('\r').unwrap_or(password);
+	if password.is_empty() {
+		bail!("The password file {} is empty", path.display());
+	}
+	if password.contains(['\n', '\r']) {
+		bail!(
+			"The password file {} contains more than one line",
+			path.display()
+		);
+	}
+	Ok(password.to_owned())
+}
+
+/// Print `prompt` to stderr and read a password from the terminal at stdin, without echoing it.
+pub fn prompt_password(prompt: &str) -> Result<String> {
+	let stdin = std::io::stdin();
+	if !stdin.is_terminal() {
+		bail!("Unable to prompt for the password, stdin is not a terminal");
+	}
+	let fd = stdin.as_raw_fd();
+	let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+	if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+		return Err(std::io::Error::last_os_error())
+			.context("Unable to get the attributes of the terminal");
+	}
+	let saved = termios;
+	termios.c_lflag &= !libc::ECHO;
+	// Still move to the next line on Enter.
+	termios.c_lflag |= libc::ECHONL;
+	if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+		return Err(std::io::Error::last_os_error()).context("Unable to disable the echo");
+	}
+	eprint!("{}", prompt);
+	std::io::stderr().flush().ok();
+	let mut line = String::new();
+	let result = stdin.lock().read_line(&mut line);
+	unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+	result.context("Unable to read the password")?;
+	Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Optional settings of the built-in user, see [`UserSpec::default_user`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserOptions {
+	/// Groups in addition to the default ones.
+	pub groups: Option<Vec<String>>,
+	/// Login shell, `/bin/bash` if not specified.
+	pub shell: Option<String>,
+	/// UID, picked by useradd if not specified.
+	pub uid: Option<u32>,
+}
+
+impl UserOptions {
+	/// The options of `self`, falling back to the ones of `other`.
+	pub fn or(&self, other: UserOptions) -> UserOptions {
+		UserOptions {
+			groups: self.groups.clone().or(other.groups),
+			shell: self.shell.clone().or(other.shell),
+			uid: self.uid.or(other.uid),
+		}
+	}
+
+	/// The default groups merged with the extra ones, in order and without duplicates.
+	pub fn all_groups(&self) -> Vec<&str> {
+		let mut groups: Vec<&str> = DEFAULT_GROUPS.to_vec();
+		for g in self.groups.iter().flatten() {
+			if !groups.contains(&g.as_str()) {
+				groups.push(g);
+			}
+		}
+		groups
+	}
+}
+
+/// Names of the groups defined in the `/etc/group` of the system at `root`.
+fn read_group_names(root: &Path) -> Result<Vec<String>> {
+	let path = root.join("etc/group");
+	let content = match std::fs::read_to_string(&path) {
+		Ok(x) => x,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(e) => return Err(e).context(format!("Unable to read {}", path.display())),
+	};
+	Ok(content
+		.lines()
+		.filter_map(|x| x.split(':').next())
+		.filter(|x| !x.is_empty())
+		.map(|x| x.to_owned())
+		.collect())
+}
+
+/// Add `user` to the system at `root`, with `password` if given.
+///
+/// `password` may also be a crypt(3) hash, see [`is_password_hash`]. Without it, the user can not log in with a password.
+/// Groups of the user which do not exist in the system are created as system groups first.
+///
+/// The commands run in the container `machine`, or on the build host with `--root` if it is `None`.
+pub fn add_user<P: AsRef<Path>>(
 	root: P,
-	name: S,
-	password: S,
-	comment: Option<T>,
-	homedir: Option<P>,
-	groups: Option<&[&str]>,
-) -> Result<()>
-where
-	S: AsRef<str>,
-	T: AsRef<str>,
-	P: AsRef<Path>,
-{
+	machine: Option<&str>,
+	user: &UserSpec,
+	password: Option<&str>,
+) -> Result<()> {
 	// shadow does not expose such functionality through a library,
 	// we have to invoke commands to achieve this.
-	let name = name.as_ref();
-	let root = root.as_ref().to_string_lossy().to_string();
-	let password = password.as_ref();
-	let comment = comment.as_ref();
-	let homedir = if let Some(h) = homedir {
-		PathBuf::from(h.as_ref())
-	} else {
-		PathBuf::from("/home").join(name)
+	let root = root.as_ref();
+	let name = user.name.as_str();
+	// Runs a shadow utility in the container, or on the build host.
+	let shadow_command = |program: &str| -> Command {
+		if let Some(machine) = machine {
+			let mut cmd = nspawn_command(root, machine);
+			cmd.args(["--", program]);
+			cmd
+		} else {
+			let mut cmd = Command::new(program);
+			cmd.arg("--root").arg(root);
+			cmd
+		}
 	};
-	let homedir = homedir.to_string_lossy();
-	let groups = if let Some(g) = groups {
-		g
+	let groups: Vec<&str> = user.groups.iter().flatten().map(|x| x.as_str()).collect();
+	let existing = read_group_names(root)?;
+	for group in groups.iter().filter(|x| !existing.iter().any(|g| g == *x)) {
+		info!("Creating group {} ...", group);
+		let mut cmd = shadow_command("groupadd");
+		cmd.args(["-r", group]);
+		cmd_run_logged(&mut cmd)?;
+	}
+	let mut cmd_useradd = shadow_command("useradd");
+	if user.create_home {
+		cmd_useradd.arg("-m").args(["-k", "/etc/skel"]);
 	} else {
-		DEFAULT_GROUPS
-	};
-	let groups = groups.join(",");
-	let mut cmd_useradd = Command::new("systemd-nspawn");
-	let mut cmd_chpasswd = Command::new("systemd-nspawn");
+		cmd_useradd.arg("-M");
+	}
 	cmd_useradd
-		.args(["-D", &root])
-		.arg("--")
-		.arg("useradd")
-		.arg("-m")
-		.args(["-k", "/etc/skel"])
-		.args(["-s", "/bin/bash"])
-		.args(["-d", &homedir])
-		.args(["-G", &groups]);
-	if let Some(c) = comment {
-		cmd_useradd.args(["-c", c.as_ref()]);
+		.args(["-s", user.shell.as_deref().unwrap_or(DEFAULT_SHELL)])
+		.arg("-d")
+		.arg(PathBuf::from("/home").join(name));
+	if !groups.is_empty() {
+		cmd_useradd.args(["-G", &groups.join(",")]);
+	}
+	if let Some(uid) = user.uid {
+		cmd_useradd.args(["-u", &uid.to_string()]);
+	}
+	if let Some(c) = &user.comment {
+		cmd_useradd.args(["-c", c]);
 	}
 	cmd_useradd.arg(name);
-	cmd_chpasswd.args([
-		"-D",
-		&root,
-		"--",
-		"bash",
-		"-c",
-		&format!("echo \"{}:{}\" | chpasswd", name, password),
-	]);
-	cmd_run_check_status(&mut cmd_useradd)?;
-	cmd_run_check_status(&mut cmd_chpasswd)?;
+	cmd_run_logged(&mut cmd_useradd)?;
+	if let Some(password) = password {
+		set_password(root, machine, name, password)?;
+	}
 	Ok(())
 }
 
+/// Set the password of the user `name` in the system at `root`, which may also be a crypt(3) hash.
+///
+/// The commands run in the container `machine`, or on the build host with `--root` if it is `None`.
+pub fn set_password(root: &Path, machine: Option<&str>, name: &str, password: &str) -> Result<()> {
+	let chpasswd_input = shell_quote(format!("{}:{}", name, password));
+	let chpasswd_args = if is_password_hash(password) {
+		" -e"
+	} else {
+		""
+	};
+	let mut cmd_chpasswd = if let Some(machine) = machine {
+		let mut cmd_chpasswd = nspawn_command(root, machine);
+		cmd_chpasswd.args([
+			"--",
+			"bash",
+			"-c",
+			&format!(
+				"printf '%s\\n' {} | chpasswd{}",
+				chpasswd_input, chpasswd_args
+			),
+		]);
+		cmd_chpasswd
+	} else {
+		let mut cmd_chpasswd = Command::new("bash");
+		cmd_chpasswd.args([
+			"-c",
+			&format!(
+				"printf '%s\\n' {} | chpasswd{} --root {}",
+				chpasswd_input,
+				chpasswd_args,
+				shell_quote(path_str(root)?)
+			),
+		]);
+		cmd_chpasswd
+	};
+	cmd_run_logged(&mut cmd_chpasswd)
+}
+
+/// Lock the password of the user `name` in the system at `root` like `passwd -l`, by prefixing it with `!` in `/etc/shadow`.
+///
+/// The file is edited directly, so nothing runs under emulation.
+pub fn lock_password(root: &Path, name: &str) -> Result<()> {
+	let path = root.join("etc/shadow");
+	let content =
+		std::fs::read_to_string(&path).context(format!("Unable to read {}", path.display()))?;
+	let mut found = false;
+	let lines: Vec<String> = content
+		.lines()
+		.map(|line| {
+			let mut fields: Vec<&str> = line.split(':').collect();
+			if fields.len() < 2 || fields[0] != name {
+				return line.to_owned();
+			}
+			found = true;
+			let locked;
+			if !fields[1].starts_with('!') {
+				locked = format!("!{}", fields[1]);
+				fields[1] = &locked;
+			}
+			fields.join(":")
+		})
+		.collect();
+	if !found {
+		bail!("User {} is not found in {}", name, path.display());
+	}
+	// Keeps the mode of the file.
+	std::fs::write(&path, lines.join("\n") + "\n")
+		.context(format!("Unable to write {}", path.display()))
+}
+
 pub fn set_locale<S: AsRef<str>, P: AsRef<Path>>(root: P, locale: S) -> Result<()> {
 	let root = root.as_ref();
 	let locale = locale.as_ref();
@@ -322,6 +977,259 @@ pub fn set_locale<S: AsRef<str>, P: AsRef<Path>>(root: P, locale: S) -> Result<(
 	Ok(())
 }
 
+/// Quote a string for POSIX shells, using single quotes.
+pub fn shell_quote<S: AsRef<str>>(s: S) -> String {
+	format!("'{}'", s.as_ref().replace('\'', "'\\''"))
+}
+
+/// Find an executable program. Names containing a `/` are relative to `base_dir`, others are searched in `PATH`.
+pub fn find_program<P: AsRef<Path>>(name: &str, base_dir: P) -> Option<PathBuf> {
+	let is_executable = |p: &Path| {
+		p.metadata()
+			.is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+	};
+	if name.contains('/') {
+		let path = base_dir.as_ref().join(name);
+		return is_executable(&path).then_some(path);
+	}
+	std::env::var_os("PATH")
+		.iter()
+		.flat_map(std::env::split_paths)
+		.map(|dir| dir.join(name))
+		.find(|path| is_executable(path))
+}
+
+/// Suffixes of the systemd units which can be enabled.
+const UNIT_SUFFIXES: &[&str] = &[
+	".service",
+	".socket",
+	".timer",
+	".target",
+	".path",
+	".mount",
+	".automount",
+	".swap",
+];
+/// Directories containing systemd units, relative to the root.
+const UNIT_DIRS: &[&str] = &[
+	"etc/systemd/system",
+	"usr/lib/systemd/system",
+	"lib/systemd/system",
+];
+
+/// Append `.service` to the unit name if it has no known suffix.
+pub fn normalize_unit_name(name: &str) -> String {
+	if UNIT_SUFFIXES.iter().any(|s| name.ends_with(s)) {
+		name.to_owned()
+	} else {
+		format!("{}.service", name)
+	}
+}
+
+/// Check the name of a systemd unit, see systemd.unit(5).
+pub fn check_unit_name(name: &str) -> Result<()> {
+	if name.is_empty() || name.starts_with('-') || name.starts_with('.') {
+		bail!("Invalid unit name '{}'", name);
+	}
+	if let Some(c) = name
+		.chars()
+		.find(|c| !c.is_ascii_alphanumeric() && !":-_.\\@".contains(*c))
+	{
+		bail!(
+			"Unit name '{}' contains a disallowed character {:?}",
+			name,
+			c
+		);
+	}
+	Ok(())
+}
+
+/// Find the unit file of `name` within `root`. Instances of template units are resolved to the template.
+pub fn find_unit_file<P: AsRef<Path>>(root: P, name: &str) -> Option<PathBuf> {
+	let root = root.as_ref();
+	let name = normalize_unit_name(name);
+	let mut candidates = vec![name.clone()];
+	if let Some((prefix, rest)) = name.split_once('@')
+		&& let Some(suffix) = UNIT_SUFFIXES.iter().find(|s| rest.ends_with(*s))
+	{
+		candidates.push(format!("{}@{}", prefix, suffix));
+	}
+	UNIT_DIRS
+		.iter()
+		.flat_map(|dir| candidates.iter().map(move |c| root.join(dir).join(c)))
+		.find(|path| path.exists() || path.is_symlink())
+}
+
+/// The Levenshtein distance between `a` and `b`, in characters.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	// Distances from the prefix of `a` processed so far to every prefix of `b`.
+	let mut row: Vec<usize> = (0..=b.len()).collect();
+	for (i, ca) in a.chars().enumerate() {
+		let mut diagonal = row[0];
+		row[0] = i + 1;
+		for (j, cb) in b.iter().enumerate() {
+			let substitution = diagonal + usize::from(ca != *cb);
+			diagonal = row[j + 1];
+			row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+		}
+	}
+	row[b.len()]
+}
+
+/// The candidate closest to `name`, if it is close enough to be a typo of it.
+pub fn nearest_name<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+	let max_distance = name.chars().count().div_ceil(3);
+	candidates
+		.iter()
+		.map(|c| (edit_distance(name, c), *c))
+		.filter(|(d, _)| *d <= max_distance)
+		.min_by_key(|(d, _)| *d)
+		.map(|(_, c)| c)
+}
+
+/// Whether `path` is a non-empty relative path without `..` components, which stays within the directory it is joined to.
+pub fn is_contained_path(path: &Path) -> bool {
+	!path.as_os_str().is_empty()
+		&& path
+			.components()
+			.all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// Resolve `.` and `..` in `path` lexically, without following symbolic links.
+pub fn normalize_path(path: &Path) -> PathBuf {
+	let mut result = PathBuf::new();
+	for c in path.components() {
+		match c {
+			Component::CurDir => (),
+			Component::ParentDir => {
+				result.pop();
+			}
+			c => result.push(c),
+		}
+	}
+	result
+}
+
+/// Calculate the SHA256 checksum of a file, in lowercase hex.
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> Result<String> {
+	let path = path.as_ref();
+	let mut fd = File::open(path).context(format!("Unable to open '{}'", path.display()))?;
+	let mut hasher = Sha256::new();
+	std::io::copy(&mut fd, &mut hasher)?;
+	Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Download a file to `dest`, replacing the existing one.
+pub fn download_file<P: AsRef<Path>>(url: &str, dest: P) -> Result<()> {
+	let dest = dest.as_ref();
+	if let Some(parent) = dest.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+	let client = reqwest::blocking::Client::builder()
+		.user_agent("Wget/1.20.3 (linux-gnu)")
+		.build()?;
+	let mut response = client
+		.get(url)
+		.send()
+		.context(format!("Unable to download '{}'", url))?;
+	response.error_for_status_ref()?;
+	let mut fd = File::create(dest)?;
+	response.copy_to(&mut fd)?;
+	fd.sync_all()?;
+	Ok(())
+}
+
+/// A binfmt_misc registration, parsed from `/proc/sys/fs/binfmt_misc/<name>`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BinfmtEntry {
+	pub enabled: bool,
+	pub interpreter: PathBuf,
+	pub flags: String,
+}
+
+impl BinfmtEntry {
+	pub fn parse(content: &str) -> Result<Self> {
+		let mut entry = BinfmtEntry::default();
+		let mut interpreter = None;
+		for line in content.lines() {
+			let line = line.trim();
+			if line == "enabled" {
+				entry.enabled = true;
+			} else if let Some(x) = line.strip_prefix("interpreter ") {
+				interpreter = Some(PathBuf::from(x.trim()));
+			} else if let Some(x) = line.strip_prefix("flags:") {
+				entry.flags = x.trim().to_owned();
+			}
+		}
+		entry.interpreter = interpreter.context("binfmt_misc entry has no interpreter")?;
+		Ok(entry)
+	}
+}
+
+/// Check the output of `qemu-* -cpu help` against the CPU models required to run binaries of `arch`.
+fn check_qemu_cpu_models(arch: &DeviceArch, interpreter: &Path, cpu_help: &str) -> Result<()> {
+	let Some(models) = arch.get_qemu_cpu_models() else {
+		return Ok(());
+	};
+	let supported = cpu_help.lines().any(|line| {
+		line.split_whitespace()
+			.any(|w| models.contains(&w.trim_matches(|c| c == '\'' || c == '"')))
+	});
+	if supported {
+		return Ok(());
+	}
+	let explanation = match arch {
+		DeviceArch::mips64r6el => {
+			"MIPS64 Release 6 binaries can not run on pre-R6 CPU models. Please install a QEMU build which supports MIPS R6 CPUs (e.g. I6400)."
+		}
+		DeviceArch::loongson3 => {
+			"Loongson 3 (pre-R6) binaries can not run on MIPS R6 CPU models. Please install a QEMU build which supports Loongson 3 CPUs (e.g. Loongson-3A4000)."
+		}
+		_ => "Please install a QEMU build which supports the required CPU models.",
+	};
+	bail!(
+		"The registered interpreter '{}' for {} does not support any of the CPU models required by {}: {}\n{}",
+		interpreter.display(),
+		arch.get_qemu_binfmt_names(),
+		arch.to_string().to_lowercase(),
+		models.join(", "),
+		explanation
+	);
+}
+
+/// Check binfmt_misc support for all of the given architectures at once.
+///
+/// Returns the architectures which can not be used, each with the reason.
+pub fn check_binfmt_all<'a, I>(archs: I) -> Vec<(DeviceArch, anyhow::Error)>
+where
+	I: IntoIterator<Item = &'a DeviceArch>,
+{
+	let mut archs = archs.into_iter().copied().collect::<Vec<_>>();
+	archs.sort();
+	archs.dedup();
+	archs
+		.into_iter()
+		.filter_map(|arch| check_binfmt(&arch).err().map(|e| (arch, e)))
+		.collect()
+}
+
+/// Format the failures returned by [`check_binfmt_all`] into a single message.
+pub fn format_binfmt_failures(failures: &[(DeviceArch, anyhow::Error)]) -> String {
+	let mut msg = format!(
+		"binfmt_misc support is missing or broken for {} architecture(s):\n",
+		failures.len()
+	);
+	for (arch, e) in failures {
+		msg += &format!("\n- {}: {}\n", arch.to_string().to_lowercase(), e);
+		msg += "  Packages to install:\n";
+		for pkg in arch.get_qemu_packages() {
+			msg += &format!("  - {}\n", pkg);
+		}
+	}
+	msg
+}
+
 pub fn check_binfmt(arch: &DeviceArch) -> Result<()> {
 	if arch.is_native() {
 		return Ok(());
@@ -338,21 +1246,108 @@ pub fn check_binfmt(arch: &DeviceArch) -> Result<()> {
 			name
 		);
 	}
+	let entry = BinfmtEntry::parse(&std::fs::read_to_string(&path)?)
+		.context(format!("Unable to parse binfmt_misc entry {}", name))?;
+	if !entry.enabled {
+		bail!("binfmt_misc entry {} is registered but disabled.", name);
+	}
+	if arch.get_qemu_cpu_models().is_some() {
+		let output = Command::new(&entry.interpreter)
+			.args(["-cpu", "help"])
+			.output()
+			.context(format!(
+				"Unable to probe the registered interpreter '{}' for {}",
+				entry.interpreter.display(),
+				name
+			))?;
+		check_qemu_cpu_models(
+			arch,
+			&entry.interpreter,
+			&String::from_utf8_lossy(&output.stdout),
+		)?;
+	}
 	Ok(())
 }
 
-pub fn cmd_run_check_status(cmd: &mut Command) -> Result<()> {
-	let result = cmd
-		.status()
-		.context(format!("Failed to run {:?}", cmd.get_program()))?;
-	if result.success() {
-		Ok(())
-	} else if let Some(c) = result.code() {
-		Err(anyhow!(
-			"The following command failed with exit code {}:\n{:?}",
-			c,
-			cmd
-		))
+/// The log file of the image being built, see [`BuildLog`].
+static BUILD_LOG: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Number of lines of the output included in the errors of [`cmd_run_logged`].
+const BUILD_LOG_TAIL_LINES: usize = 50;
+
+/// The log file recording the output of the commands run in the containers of the image being built.
+///
+/// Images are built one at a time, the commands run by [`cmd_run_logged`] are logged until this is dropped.
+pub struct BuildLog {
+	_private: (),
+}
+
+impl BuildLog {
+	/// Create (or truncate) the log file at `path`, and start logging into it.
+	pub fn start(path: PathBuf) -> Result<Self> {
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+		File::create(&path).context(format!("Failed to create log file {}", path.display()))?;
+		*BUILD_LOG.lock().unwrap() = Some(path);
+		Ok(Self { _private: () })
+	}
+}
+
+impl Drop for BuildLog {
+	fn drop(&mut self) {
+		*BUILD_LOG.lock().unwrap() = None;
+	}
+}
+
+/// Run the command like [`cmd_run_check_status`], appending its output to the current [`BuildLog`] while still printing it.
+///
+/// On failure, the last lines of the output are included in the error.
+pub fn cmd_run_logged(cmd: &mut Command) -> Result<()> {
+	let Some(log_path) = BUILD_LOG.lock().unwrap().clone() else {
+		return cmd_run_check_status(cmd);
+	};
+	let mut log = File::options()
+		.create(true)
+		.append(true)
+		.open(&log_path)
+		.context(format!("Failed to open log file {}", log_path.display()))?;
+	writeln!(log, "==> {}", format_command(cmd))?;
+	let (status, tail) = run_with_log_file(cmd, &mut log, |line| println!("{}", line))?;
+	if status.success() {
+		return Ok(());
+	}
+	let reason = if let Some(c) = status.code() {
+		format!(
+			"The following command failed with exit code {}:\n{:?}",
+			c, cmd
+		)
+	} else {
+		format!("The following command exited abnormally:\n{:?}", cmd)
+	};
+	let tail = Vec::from(tail);
+	let tail = &tail[tail.len().saturating_sub(BUILD_LOG_TAIL_LINES)..];
+	Err(
+		anyhow!("Last {} lines of output:\n{}", tail.len(), tail.join("\n")).context(format!(
+			"{}\nFull log: {}",
+			reason,
+			log_path.display()
+		)),
+	)
+}
+
+pub fn cmd_run_check_status(cmd: &mut Command) -> Result<()> {
+	let result = cmd
+		.status()
+		.context(format!("Failed to run {:?}", cmd.get_program()))?;
+	if result.success() {
+		Ok(())
+	} else if let Some(c) = result.code() {
+		Err(anyhow!(
+			"The following command failed with exit code {}:\n{:?}",
+			c,
+			cmd
+		))
 	} else {
 		Err(anyhow!(
 			"The following command exited abnormally:\n{:?}",
@@ -361,13 +1356,50 @@ pub fn cmd_run_check_status(cmd: &mut Command) -> Result<()> {
 	}
 }
 
-pub fn run_str_script_with_chroot(
+/// Escape a path to be used as a bind mount argument of systemd-nspawn, where `:` separates the source, destination and options.
+pub fn escape_nspawn_bind(path: &str) -> String {
+	path.replace('\\', "\\\\").replace(':', "\\:")
+}
+
+/// Maximum length of machine names of systemd-nspawn, which must be valid hostnames.
+const NSPAWN_MACHINE_NAME_MAX: usize = 64;
+
+/// A machine name of systemd-nspawn unique to the build of `device_id` and `variant` in this process.
+///
+/// Builds running at the same time would collide on the default name derived from the directory.
+pub fn nspawn_machine_name(device_id: &str, variant: &str, pid: u32) -> String {
+	let suffix = format!("-{}-{}", variant, pid);
+	let prefix: String = format!("mkrawimg-{}", device_id)
+		.chars()
+		.map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+		.take(NSPAWN_MACHINE_NAME_MAX - suffix.len())
+		.collect();
+	prefix + &suffix
+}
+
+/// Start a systemd-nspawn command line running in `root` as `machine`.
+///
+/// The containers are not registered with systemd-machined, the transient scope units are still named after `machine`.
+/// `--keep-unit` is not used, since the build may not run in a service unit of its own.
+fn nspawn_command(root: &Path, machine: &str) -> Command {
+	let mut cmd = Command::new("systemd-nspawn");
+	cmd.args(["-q", "-D"])
+		.arg(root)
+		.args(["--register=no", "--machine", machine])
+		// resolv.conf is installed in the target for the build, see crate::fixups.
+		.arg("--resolv-conf=off");
+	cmd
+}
+
+/// Build the systemd-nspawn command line running `script` in `root` with `shell`, after sourcing `spec.sh`.
+pub fn chroot_str_script_command(
 	root: &dyn AsRef<Path>,
+	machine: &str,
 	script: &str,
 	binds: &[&str],
 	shell: Option<&dyn AsRef<str>>,
-) -> Result<()> {
-	let mut cmd = Command::new("systemd-nspawn");
+) -> Command {
+	let mut cmd = nspawn_command(root.as_ref(), machine);
 	let shell = if let Some(s) = shell {
 		s.as_ref()
 	} else {
@@ -376,23 +1408,35 @@ pub fn run_str_script_with_chroot(
 	// Let's assume all shells supports "-c SCRIPT".
 	// But I think it is better to pipe into the shell's stdin.
 	// bash -c -- script $0 $1 ...
-	// The positional param after "-c script" is $0 of that script.
+	// The positional param after "-c script" is $0 of that script.
 	let script = format!("source /tmp/spec.sh ;{}", script);
-	cmd.args(["-q", "-D", &root.as_ref().to_string_lossy()]);
 	for bind in binds {
-		cmd.args(["--bind", bind]);
+		cmd.args(["--bind", &escape_nspawn_bind(bind)]);
 	}
 	cmd.args(["--", shell, "-c", "--", &script, "<tmp_script>"]);
-	cmd_run_check_status(&mut cmd)
+	cmd
 }
 
-pub fn run_script_with_chroot<P: AsRef<Path>>(
+pub fn run_str_script_with_chroot(
+	root: &dyn AsRef<Path>,
+	machine: &str,
+	script: &str,
+	binds: &[&str],
+	shell: Option<&dyn AsRef<str>>,
+) -> Result<()> {
+	let mut cmd = chroot_str_script_command(root, machine, script, binds, shell);
+	cmd_run_logged(&mut cmd)
+}
+
+/// Build the systemd-nspawn command line sourcing the script file at `script` within `root`, after sourcing `spec.sh`.
+pub fn chroot_script_command<P: AsRef<Path>>(
 	root: P,
+	machine: &str,
 	script: P,
 	binds: &[&str],
 	shell: Option<&dyn AsRef<str>>,
-) -> Result<()> {
-	let mut cmd = Command::new("systemd-nspawn");
+) -> Result<Command> {
+	let mut cmd = nspawn_command(root.as_ref(), machine);
 	let shell = if let Some(s) = shell {
 		s.as_ref()
 	} else {
@@ -401,15 +1445,12 @@ pub fn run_script_with_chroot<P: AsRef<Path>>(
 	// Let's assume all shells supports "-c SCRIPT".
 	// But I think it is better to pipe into the shell's stdin.
 	// bash -c -- script $0 $1 ...
-	// The positional param after "-c script" is $0 of that script.
+	// The positional param after "-c script" is $0 of that script.
 	// We are using 'source' to let the script being run to use the information we provided.
-	let full_script = format!(
-		"source /tmp/spec.sh ; source {}",
-		&script.as_ref().to_string_lossy()
-	);
-	cmd.args(["-q", "-D", &root.as_ref().to_string_lossy()]);
+	let script = path_str(script.as_ref())?;
+	let full_script = format!("source /tmp/spec.sh ; source {}", script);
 	for bind in binds {
-		cmd.args(["--bind", bind]);
+		cmd.args(["--bind", &escape_nspawn_bind(bind)]);
 	}
 	cmd.args([
 		"--",
@@ -418,12 +1459,58 @@ pub fn run_script_with_chroot<P: AsRef<Path>>(
 		"--",
 		&full_script,
 		// Set $0 to the path of the script
-		&script.as_ref().to_string_lossy(),
+		script,
 	]);
-	cmd_run_check_status(&mut cmd).context("Failed to run script with chroot")
+	Ok(cmd)
+}
+
+pub fn run_script_with_chroot<P: AsRef<Path>>(
+	root: P,
+	machine: &str,
+	script: P,
+	binds: &[&str],
+	shell: Option<&dyn AsRef<str>>,
+) -> Result<()> {
+	let mut cmd = chroot_script_command(root, machine, script, binds, shell)?;
+	cmd_run_logged(&mut cmd).context("Failed to run script with chroot")
+}
+
+/// Build the systemd-nspawn command line of an interactive shell in `root`, with `spec.sh` sourced.
+pub fn chroot_shell_command(root: &Path, machine: &str, binds: &[&str]) -> Command {
+	let mut cmd = nspawn_command(root, machine);
+	for bind in binds {
+		cmd.args(["--bind", &escape_nspawn_bind(bind)]);
+	}
+	// spec.sh takes the place of ~/.bashrc.
+	cmd.args(["--", "/bin/bash", "--rcfile", "/tmp/spec.sh", "-i"]);
+	cmd
+}
+
+/// Format the command line of `cmd` to be pasted into a shell, quoting the arguments where necessary.
+///
+/// The environment and the working directory of `cmd` are not included.
+pub fn format_command(cmd: &Command) -> String {
+	let is_plain = |s: &str| {
+		!s.is_empty()
+			&& s.chars()
+				.all(|c| c.is_ascii_alphanumeric() || "-_=+/.,:@%".contains(c))
+	};
+	std::iter::once(cmd.get_program())
+		.chain(cmd.get_args())
+		.map(|x| {
+			let x = x.to_string_lossy();
+			if is_plain(&x) {
+				x.into_owned()
+			} else {
+				shell_quote(x)
+			}
+		})
+		.collect::<Vec<_>>()
+		.join(" ")
 }
 
 /// Get filesystem UUID of the given block device.
+#[cfg(all(feature = "blkid", not(feature = "no-blkid")))]
 pub fn get_fsuuid(fspath: &dyn AsRef<Path>) -> Result<String> {
 	let fspath = fspath.as_ref();
 	// WARNING! ACHTUNG!
@@ -464,34 +1551,1349 @@ pub fn get_fsuuid(fspath: &dyn AsRef<Path>) -> Result<String> {
 	}
 }
 
+/// Get filesystem UUID of the given block device, reading the superblock directly.
+#[cfg(any(feature = "no-blkid", not(feature = "blkid")))]
+pub fn get_fsuuid(fspath: &dyn AsRef<Path>) -> Result<String> {
+	crate::probe::probe_fsuuid(fspath)
+}
+
+/// Copy a file, preserving its permissions, ownership, timestamps and extended attributes (including file capabilities).
+///
+/// Use this for every in-process copy targeting the root filesystem or the boot partitions, as [`std::fs::copy`] drops extended attributes.
+/// Extended attributes are skipped with a warning if the destination filesystem does not support them (e.g. FAT).
+pub fn copy_preserving<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> Result<u64> {
+	let src = src.as_ref();
+	let dst = dst.as_ref();
+	// Copies the permission bits as well.
+	let size = std::fs::copy(src, dst).context(format!(
+		"Unable to copy '{}' to '{}'",
+		src.display(),
+		dst.display()
+	))?;
+	let metadata = std::fs::metadata(src)?;
+	// Changing the ownership clears the file capabilities, do it before copying xattrs.
+	if let Err(e) = chown(dst, Some(metadata.uid()), Some(metadata.gid())) {
+		warn!(
+			"Unable to preserve the ownership of '{}': {}",
+			dst.display(),
+			e
+		);
+	}
+	// chown() also clears the setuid and setgid bits.
+	std::fs::set_permissions(dst, metadata.permissions())?;
+	match xattr::list(src) {
+		Ok(names) => {
+			for name in names {
+				let Some(value) = xattr::get(src, &name)? else {
+					continue;
+				};
+				if let Err(e) = xattr::set(dst, &name, &value) {
+					if e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+						warn!(
+							"Filesystem of '{}' does not support extended attributes, they are not preserved.",
+							dst.display()
+						);
+						break;
+					}
+					return Err(e).context(format!(
+						"Unable to set extended attribute {:?} on '{}'",
+						name,
+						dst.display()
+					));
+				}
+			}
+		}
+		Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => (),
+		Err(e) => {
+			return Err(e).context(format!(
+				"Unable to list extended attributes of '{}'",
+				src.display()
+			));
+		}
+	}
+	let times = FileTimes::new()
+		.set_accessed(metadata.accessed()?)
+		.set_modified(metadata.modified()?);
+	File::options().write(true).open(dst)?.set_times(times)?;
+	Ok(size)
+}
+
+/// A loop device attached to a file.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AttachedLoop {
+	/// Device node of the loop device, e.g. `/dev/loop0`.
+	pub device: PathBuf,
+	/// Path of the backing file. The kernel appends ` (deleted)` to the unlinked ones, which is stripped.
+	pub backing_file: PathBuf,
+}
+
+/// List the loop devices attached to a file, from the block devices in sysfs at `sysfs_block`.
+pub fn list_attached_loops<P: AsRef<Path>>(sysfs_block: P) -> Result<Vec<AttachedLoop>> {
+	let sysfs_block = sysfs_block.as_ref();
+	let mut loops = Vec::new();
+	if !sysfs_block.is_dir() {
+		return Ok(loops);
+	}
+	for entry in std::fs::read_dir(sysfs_block)? {
+		let entry = entry?;
+		let name = entry.file_name();
+		if !name.to_string_lossy().starts_with("loop") {
+			continue;
+		}
+		// Only exists while the loop device is attached.
+		let backing_file = match std::fs::read_to_string(entry.path().join("loop/backing_file")) {
+			Ok(x) => x,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+			Err(e) => {
+				return Err(e).context(format!(
+					"Unable to read the backing file of {}",
+					name.to_string_lossy()
+				));
+			}
+		};
+		let backing_file = backing_file.trim_end_matches('\n');
+		let backing_file = backing_file
+			.strip_suffix(" (deleted)")
+			.unwrap_or(backing_file);
+		loops.push(AttachedLoop {
+			device: Path::new("/dev").join(&name),
+			backing_file: PathBuf::from(backing_file),
+		});
+	}
+	loops.sort_by(|a, b| a.device.cmp(&b.device));
+	Ok(loops)
+}
+
+/// The loop devices from `loops` whose backing files are within `dir`.
+pub fn loops_within<P: AsRef<Path>>(loops: &[AttachedLoop], dir: P) -> Vec<&AttachedLoop> {
+	let dir = dir.as_ref();
+	// Backing files are recorded with their absolute paths.
+	let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_owned());
+	loops
+		.iter()
+		.filter(|l| l.backing_file.starts_with(&dir))
+		.collect()
+}
+
+/// Make sure no loop device is attached to a file within `dir` before removing it.
+///
+/// Removing an attached backing file leaves a loop device holding an unlinked file behind.
+/// The loop devices are detached if `force_detach` is set. Otherwise returns `false`, warning about each of them.
+pub fn release_loop_devices<P: AsRef<Path>>(dir: P, force_detach: bool) -> Result<bool> {
+	let dir = dir.as_ref();
+	let loops = list_attached_loops(SYSFS_BLOCK_DIR)?;
+	let attached = loops_within(&loops, dir);
+	if attached.is_empty() {
+		return Ok(true);
+	}
+	for l in &attached {
+		if force_detach {
+			info!(
+				"Detaching {} attached to {} ...",
+				l.device.display(),
+				l.backing_file.display()
+			);
+			loopdev::LoopDevice::open(&l.device)
+				.and_then(|d| d.detach())
+				.context(format!("Unable to detach {}", l.device.display()))?;
+		} else {
+			warn!(
+				"Loop device {} is still attached to {}, skipping {}.\nDetach it first, or use --force-detach.",
+				l.device.display(),
+				l.backing_file.display(),
+				dir.display()
+			);
+		}
+	}
+	Ok(force_detach)
+}
+
+/// Get the mount points within `dir` from the content of `/proc/self/mountinfo`, in the order they are mounted.
+pub fn mounts_under(mountinfo: &str, dir: &Path) -> Vec<PathBuf> {
+	// Spaces, tabs, newlines and backslashes are escaped in octal, e.g. `\040`.
+	let unescape = |field: &str| {
+		let bytes = field.as_bytes();
+		let mut result = Vec::with_capacity(bytes.len());
+		let mut i = 0;
+		while i < bytes.len() {
+			let octal = bytes.get(i + 1..i + 4).and_then(|x| {
+				std::str::from_utf8(x)
+					.ok()
+					.and_then(|x| u8::from_str_radix(x, 8).ok())
+			});
+			match octal {
+				Some(c) if bytes[i] == b'\\' => {
+					result.push(c);
+					i += 4;
+				}
+				_ => {
+					result.push(bytes[i]);
+					i += 1;
+				}
+			}
+		}
+		PathBuf::from(OsString::from_vec(result))
+	};
+	mountinfo
+		.lines()
+		.filter_map(|l| l.split(' ').nth(4))
+		.map(unescape)
+		.filter(|mp| mp.starts_with(dir))
+		.collect()
+}
+
+/// Number of attempts to attach a loop device by default.
+pub const LOOP_ATTACH_ATTEMPTS: u32 = 5;
+/// Delay before the first retry of attaching a loop device, doubled after each retry.
+const LOOP_ATTACH_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Whether attaching a loop device failed because of others using loop devices at the same time.
+///
+/// The free device might be taken before we attach to it (EBUSY),
+/// or its device node might not be created yet (ENOENT).
+fn is_loop_contention(e: &std::io::Error) -> bool {
+	matches!(e.raw_os_error(), Some(libc::EBUSY) | Some(libc::ENOENT))
+}
+
+/// Run `attach` up to `attempts` times, until it fails for reasons other than contention.
+///
+/// `attach` returns the path of the attempted device along with the error, if it's known.
+fn attach_with_retries<T>(
+	attempts: u32,
+	backoff: Duration,
+	mut attach: impl FnMut() -> Result<T, (Option<PathBuf>, std::io::Error)>,
+) -> Result<T> {
+	let attempts = attempts.max(1);
+	let mut delay = backoff;
+	for attempt in 1..=attempts {
+		let (device, e) = match attach() {
+			Ok(x) => return Ok(x),
+			Err(x) => x,
+		};
+		let device = device.map_or("the next free loop device".to_owned(), |x| {
+			x.display().to_string()
+		});
+		if !is_loop_contention(&e) {
+			return Err(e).context(format!("Unable to attach {}", device));
+		}
+		if attempt == attempts {
+			return Err(e).context(format!(
+				"Unable to attach a loop device after {} attempts, the last attempted device is {}",
+				attempts, device
+			));
+		}
+		debug!(
+			"Attempt {}/{} to attach {} failed: {}, retrying in {:?} ...",
+			attempt, attempts, device, e, delay
+		);
+		thread::sleep(delay);
+		delay *= 2;
+	}
+	unreachable!()
+}
+
+/// Initial delay before unmounting a busy filesystem again, doubled after each attempt.
+const UNMOUNT_BACKOFF: Duration = Duration::from_millis(20);
+/// Maximum number of attempts to unmount a busy filesystem, waiting about 2.5 seconds in total.
+const UNMOUNT_ATTEMPTS: u32 = 8;
+
+/// Run `op` up to `attempts` times, until it fails with an error other than EBUSY.
+fn retry_while_busy(
+	attempts: u32,
+	backoff: Duration,
+	mut op: impl FnMut() -> std::io::Result<()>,
+) -> std::io::Result<()> {
+	let attempts = attempts.max(1);
+	let mut delay = backoff;
+	for attempt in 1..attempts {
+		match op() {
+			Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+				debug!(
+					"Attempt {}/{} failed: {}, retrying in {:?} ...",
+					attempt, attempts, e, delay
+				);
+				thread::sleep(delay);
+				delay *= 2;
+			}
+			result => return result,
+		}
+	}
+	op()
+}
+
+/// Unmount `path`, retrying for a while if it is busy.
+///
+/// Processes which just exited (e.g. the container) may keep the filesystem busy for a moment.
+pub fn unmount_busy_retrying(path: &Path) -> Result<()> {
+	retry_while_busy(UNMOUNT_ATTEMPTS, UNMOUNT_BACKOFF, || {
+		unmount(path, UnmountFlags::empty())
+	})
+	.context(format!("Unable to unmount {}", path.display()))
+}
+
+/// Attach `file` to the next free loop device with partition scanning enabled.
+///
+/// Other tools might take the same device at once, attaching is retried up to `attempts` times then.
+pub fn attach_loop_device<P: AsRef<Path>>(file: P, attempts: u32) -> Result<LoopDevice> {
+	let file = file.as_ref();
+	debug!("Getting fd on /dev/loop-control ...");
+	let loop_ctl = LoopControl::open().context("Unable to open /dev/loop-control")?;
+	attach_with_retries(attempts, LOOP_ATTACH_BACKOFF, || {
+		let loop_dev = loop_ctl.next_free().map_err(|e| (None, e))?;
+		// Partition scanning allows the kernel to re-read the partition table written later.
+		match loop_dev.with().part_scan(true).attach(file) {
+			Ok(()) => Ok(loop_dev),
+			Err(e) => Err((loop_dev.path(), e)),
+		}
+	})
+	.context(format!("Failed to attach {}", file.display()))
+}
+
+/// Get the statistics of the filesystem containing `path`.
+fn statvfs(path: &Path) -> Result<libc::statvfs> {
+	let c_path = CString::new(path.as_os_str().as_encoded_bytes())?;
+	let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+	if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+		return Err(std::io::Error::last_os_error()).context(format!(
+			"Unable to get the statistics of the filesystem containing {}",
+			path.display()
+		));
+	}
+	Ok(unsafe { stat.assume_init() })
+}
+
+/// Get the numbers of used and free inodes of the filesystem containing `path`.
+pub fn inode_usage<P: AsRef<Path>>(path: P) -> Result<(u64, u64)> {
+	let stat = statvfs(path.as_ref())?;
+	Ok((stat.f_files - stat.f_ffree, stat.f_favail))
+}
+
+/// Get the size, the used space and the space available to unprivileged users of the filesystem containing `path` in bytes.
+pub fn filesystem_usage<P: AsRef<Path>>(path: P) -> Result<(u64, u64, u64)> {
+	let stat = statvfs(path.as_ref())?;
+	Ok((
+		stat.f_blocks * stat.f_frsize,
+		(stat.f_blocks - stat.f_bfree) * stat.f_frsize,
+		stat.f_bavail * stat.f_frsize,
+	))
+}
+
+/// Get the free space of the filesystem containing `path` in bytes.
+///
+/// Blocks reserved for root count as free space, as the build runs as root.
+pub fn free_space<P: AsRef<Path>>(path: P) -> Result<u64> {
+	let stat = statvfs(path.as_ref())?;
+	Ok(stat.f_bfree * stat.f_frsize)
+}
+
+/// Get the timestamp defined by `SOURCE_DATE_EPOCH`, if it is set.
+pub fn source_date_epoch() -> Result<Option<u64>> {
+	match std::env::var("SOURCE_DATE_EPOCH") {
+		Ok(x) => Ok(Some(
+			x.trim()
+				.parse()
+				.context(format!("Invalid SOURCE_DATE_EPOCH '{}'", x))?,
+		)),
+		Err(_) => Ok(None),
+	}
+}
+
+/// Derive 16 bytes from the seed and the given components, for reproducible identifiers.
+pub fn derive_bytes(seed: &str, components: &[&str]) -> [u8; 16] {
+	let mut hasher = Sha256::new();
+	hasher.update(seed.as_bytes());
+	for c in components {
+		// Separate the components, so ("ab", "c") and ("a", "bc") differ.
+		hasher.update([0u8]);
+		hasher.update(c.as_bytes());
+	}
+	let digest = hasher.finalize();
+	let mut bytes = [0u8; 16];
+	bytes.copy_from_slice(&digest[..16]);
+	bytes
+}
+
+/// Clamp the access and modification time of the file at `path` to `epoch`, if it is newer.
+///
+/// Symbolic links are skipped.
+pub fn clamp_file_times<P: AsRef<Path>>(path: P, epoch: u64) -> Result<()> {
+	let path = path.as_ref();
+	let metadata = path.symlink_metadata()?;
+	if metadata.is_symlink() {
+		return Ok(());
+	}
+	let epoch = std::time::UNIX_EPOCH + Duration::from_secs(epoch);
+	let times = FileTimes::new()
+		.set_accessed(metadata.accessed()?.min(epoch))
+		.set_modified(metadata.modified()?.min(epoch));
+	File::options().write(true).open(path)?.set_times(times)?;
+	Ok(())
+}
+
+/// Get the user and group ID of the user invoking sudo, if any.
+pub fn get_sudo_ids() -> Result<Option<(Option<u32>, Option<u32>)>> {
+	let uid = std::env::var("SUDO_UID").ok();
+	let gid = std::env::var("SUDO_GID").ok();
+	if uid.is_none() && gid.is_none() {
+		return Ok(None);
+	}
+	let uid = uid
+		.map(|x| {
+			x.parse::<u32>()
+				.with_context(|| format!("Failed to parse $SUDO_UID '{}' into integer", &x))
+		})
+		.transpose()?;
+	let gid = gid
+		.map(|x| {
+			x.parse::<u32>()
+				.with_context(|| format!("Failed to parse $SUDO_GID '{}' into integer", &x))
+		})
+		.transpose()?;
+	Ok(Some((uid, gid)))
+}
+
+/// Environment variables imported from the user invoking sudo with `--preserve-env`.
+pub const PRESERVED_ENV_VARS: &[&str] = &["http_proxy", "https_proxy", "no_proxy", "RSYNC_PROXY"];
+
+/// Read the environment of the user invoking sudo, from the sudo process which started us.
+///
+/// Returns `None` if the parent process is not run by the user `uid`.
+fn read_sudo_env(uid: u32) -> Result<Option<HashMap<String, String>>> {
+	let ppid = std::os::unix::process::parent_id();
+	let status = std::fs::read_to_string(format!("/proc/{}/status", ppid))?;
+	// The real user ID of sudo is the one of the invoking user.
+	let real_uid = status
+		.lines()
+		.find_map(|l| l.strip_prefix("Uid:"))
+		.and_then(|l| l.split_whitespace().next())
+		.and_then(|x| x.parse::<u32>().ok());
+	if real_uid != Some(uid) {
+		return Ok(None);
+	}
+	let environ = std::fs::read(format!("/proc/{}/environ", ppid))?;
+	let env = environ
+		.split(|&b| b == 0)
+		.filter_map(|x| std::str::from_utf8(x).ok())
+		.filter_map(|x| x.split_once('='))
+		.map(|(k, v)| (k.to_owned(), v.to_owned()))
+		.collect();
+	Ok(Some(env))
+}
+
+/// Get the home directory of the user `uid` from the user database.
+fn passwd_home(uid: u32) -> Option<PathBuf> {
+	// SAFETY: the entry is copied before any other call to the user database.
+	unsafe {
+		let pw = libc::getpwuid(uid);
+		if pw.is_null() || (*pw).pw_dir.is_null() {
+			return None;
+		}
+		let dir = CStr::from_ptr((*pw).pw_dir);
+		Some(PathBuf::from(OsString::from_vec(dir.to_bytes().to_vec())))
+	}
+}
+
+/// The cache directory of the user with the environment `env` and the home directory `home`.
+///
+/// That is `XDG_CACHE_HOME`, `$HOME/.cache` or `home/.cache` in this order. Relative paths are ignored like the XDG spec says.
+pub fn cache_dir(env: impl Fn(&str) -> Option<String>, home: Option<&Path>) -> Option<PathBuf> {
+	let absolute = |x: &String| Path::new(x).is_absolute();
+	if let Some(dir) = env("XDG_CACHE_HOME").filter(absolute) {
+		Some(PathBuf::from(dir))
+	} else if let Some(home) = env("HOME").filter(absolute) {
+		Some(Path::new(&home).join(".cache"))
+	} else {
+		home.map(|home| home.join(".cache"))
+	}
+}
+
+/// Resolve the environment variables to set from the environment of the invoking user `invoking`.
+///
+/// Variables already set in our environment (e.g. by `sudo -E` or `sudo http_proxy=...`) take precedence.
+/// `XDG_CACHE_HOME` is set to the [`cache_dir`] of the invoking user, whose home directory in the user database is `passwd_home`.
+pub fn resolve_sudo_env(
+	current: impl Fn(&str) -> Option<String>,
+	invoking: &HashMap<String, String>,
+	passwd_home: Option<&Path>,
+) -> Vec<(String, String)> {
+	let mut env = Vec::new();
+	for &name in PRESERVED_ENV_VARS {
+		if current(name).is_none()
+			&& let Some(value) = invoking.get(name)
+		{
+			env.push((name.to_owned(), value.to_owned()));
+		}
+	}
+	if current("XDG_CACHE_HOME").is_none()
+		&& let Some(cache) = cache_dir(|x| invoking.get(x).cloned(), passwd_home)
+	{
+		env.push((
+			"XDG_CACHE_HOME".to_owned(),
+			cache.to_string_lossy().into_owned(),
+		));
+	}
+	env
+}
+
+/// Import the proxy and cache environment of the user invoking sudo, if any.
+///
+/// Must be called before any other thread reads the environment.
+pub fn preserve_sudo_env() -> Result<()> {
+	let Some((Some(uid), _)) = get_sudo_ids()? else {
+		return Ok(());
+	};
+	let invoking = match read_sudo_env(uid) {
+		Ok(Some(env)) => env,
+		Ok(None) => {
+			warn!(
+				"The parent process is not run by the user invoking sudo, only the cache directory is resolved."
+			);
+			HashMap::new()
+		}
+		Err(e) => {
+			warn!(
+				"Unable to read the environment of the user invoking sudo: {}",
+				e
+			);
+			HashMap::new()
+		}
+	};
+	let home = passwd_home(uid);
+	for (name, value) in resolve_sudo_env(|x| std::env::var(x).ok(), &invoking, home.as_deref()) {
+		debug!("Importing {}={} from the user invoking sudo", name, value);
+		// SAFETY: see above.
+		unsafe { std::env::set_var(name, value) };
+	}
+	Ok(())
+}
+
 /// Change the ownership of a filesystem object, recursively.
+///
+/// Symbolic links, including `path` itself, are changed themselves and never followed, and other filesystems mounted within it are not entered.
+/// Files with hard links are only changed if all of their links are within `path`.
+/// Objects already owned by `to_user` and `to_group`, or removed while walking, are skipped.
 pub fn return_ownership_recursive(
 	path: &dyn AsRef<Path>,
 	to_user: Option<u32>,
 	to_group: Option<u32>,
 ) -> Result<()> {
-	let walker = WalkDir::new(path.as_ref());
+	let path = path.as_ref();
+	// Inode of the hard-linked files => their number of links and the paths found.
+	let mut linked: HashMap<(u64, u64), (u64, Vec<PathBuf>)> = HashMap::new();
+	let walker = WalkDir::new(path)
+		.follow_root_links(false)
+		.same_file_system(true);
 	for entry in walker {
-		let entry = entry.context("Loop or unexpected object detected")?;
-		chown(entry.path(), to_user, to_group).context(format!(
+		let entry = match entry {
+			Ok(entry) => entry,
+			Err(e) if e.io_error().map(|x| x.kind()) == Some(std::io::ErrorKind::NotFound) => {
+				continue;
+			}
+			Err(e) => return Err(e).context("Loop or unexpected object detected"),
+		};
+		let metadata = match std::fs::symlink_metadata(entry.path()) {
+			Ok(metadata) => metadata,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+			Err(e) => {
+				return Err(e).context(format!("Unable to stat '{}'", entry.path().display()));
+			}
+		};
+		if to_user.is_none_or(|x| x == metadata.uid())
+			&& to_group.is_none_or(|x| x == metadata.gid())
+		{
+			continue;
+		}
+		if !metadata.is_dir() && metadata.nlink() > 1 {
+			linked
+				.entry((metadata.dev(), metadata.ino()))
+				.or_insert((metadata.nlink(), Vec::new()))
+				.1
+				.push(entry.into_path());
+			continue;
+		}
+		change_ownership(entry.path(), to_user, to_group)?;
+	}
+	for (nlink, paths) in linked.into_values() {
+		if paths.len() as u64 == nlink {
+			change_ownership(&paths[0], to_user, to_group)?;
+		} else {
+			warn!(
+				"Not changing the ownership of '{}', it has hard links outside of '{}'.",
+				paths[0].display(),
+				path.display()
+			);
+		}
+	}
+	Ok(())
+}
+
+/// Change the ownership of `path`, not of the target if it is a symbolic link. Missing objects are skipped.
+fn change_ownership(path: &Path, to_user: Option<u32>, to_group: Option<u32>) -> Result<()> {
+	match lchown(path, to_user, to_group) {
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+		result => result.context(format!(
 			"Failed to change the ownership of '{}' to {:?}:{:?}",
-			entry.path().display(),
+			path.display(),
 			to_user,
 			to_group
-		))?;
+		)),
 	}
-	Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-	use super::get_fsuuid;
-	use anyhow::Result;
+	use super::*;
+	use crate::cli::Cmdline;
+	use crate::fixtures::TestDir;
+	use clap::Parser;
 
 	#[test]
-	fn test_get_uuid() -> Result<()> {
-		let uuid = get_fsuuid(&"/dev/nvme0n1p2")?;
-		eprintln!("FSUUID for nvme0n1p2: {}", uuid);
+	fn test_term_caps() -> Result<()> {
+		let no_env = |_: &str| None;
+		let env = |value: &'static str| move |x: &str| (x == "NO_COLOR").then(|| value.into());
+		let caps = |color, progress| TermCaps { color, progress };
+		assert_eq!(
+			TermCaps::resolve(true, no_env, false, false),
+			caps(true, true)
+		);
+		// Nothing fancy if stderr is not a terminal, whatever is asked for
+		assert_eq!(
+			TermCaps::resolve(false, no_env, false, false),
+			caps(false, false)
+		);
+		assert_eq!(
+			TermCaps::resolve(false, env(""), true, true),
+			caps(false, false)
+		);
+		// Only a non-empty NO_COLOR disables the colors, not the progress bar
+		assert_eq!(
+			TermCaps::resolve(true, env("1"), false, false),
+			caps(false, true)
+		);
+		assert_eq!(
+			TermCaps::resolve(true, env(""), false, false),
+			caps(true, true)
+		);
+		let cmdline = |args: &[&str]| {
+			Cmdline::try_parse_from([&["mkrawimg"], args, &["list"]].concat())
+				.map(|x| TermCaps::resolve(true, no_env, x.no_color, x.no_progress))
+		};
+		assert_eq!(cmdline(&[])?, caps(true, true));
+		assert_eq!(cmdline(&["--no-color"])?, caps(false, true));
+		assert_eq!(cmdline(&["--no-progress"])?, caps(true, false));
+		assert_eq!(
+			cmdline(&["--no-color", "--no-progress"])?,
+			caps(false, false)
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_normalize_path() {
+		assert_eq!(
+			normalize_path(Path::new("/a/b/./c/../../d.toml")),
+			PathBuf::from("/a/d.toml")
+		);
+		assert_eq!(normalize_path(Path::new("/a/../..")), PathBuf::from("/"));
+	}
+
+	#[test]
+	fn test_edit_distance() {
+		assert_eq!(edit_distance("", ""), 0);
+		assert_eq!(edit_distance("abc", ""), 3);
+		assert_eq!(edit_distance("", "abc"), 3);
+		assert_eq!(edit_distance("kitten", "sitting"), 3);
+		assert_eq!(edit_distance("bsp_package", "bsp_packages"), 1);
+		assert_eq!(edit_distance("fdtdri", "fdtdir"), 2);
+		assert_eq!(edit_distance("äb", "ab"), 1);
+		assert_eq!(
+			nearest_name("mount_options", &["mountpoint", "mount_opts"]),
+			Some("mount_opts")
+		);
+		assert_eq!(nearest_name("nmae", &["name", "num"]), Some("name"));
+		assert_eq!(nearest_name("foo", &["fdt", "dir"]), None);
+	}
+
+	const TEST_BINFMT_ENTRY: &str = "enabled
+interpreter /usr/bin/qemu-mips64el-static
+flags: OCF
+offset 0
+magic 7f454c4602010100000000000000000002000800
+mask ffffffffffffff00fffffffffffffffffeffffff
+";
+
+	const TEST_CPU_HELP_PRE_R6: &str = "MIPS '4Kc'
+MIPS '5KEf'
+MIPS '20Kc'
+MIPS 'Loongson-2E'
+MIPS 'Loongson-3A1000'
+MIPS 'Loongson-3A4000'
+";
+
+	const TEST_CPU_HELP_R6: &str = "MIPS '5KEf'
+MIPS 'I6400'
+MIPS 'I6500'
+";
+
+	#[test]
+	fn test_parse_binfmt_entry() -> Result<()> {
+		let entry = BinfmtEntry::parse(TEST_BINFMT_ENTRY)?;
+		assert!(entry.enabled);
+		assert_eq!(
+			entry.interpreter,
+			PathBuf::from("/usr/bin/qemu-mips64el-static")
+		);
+		assert_eq!(entry.flags, "OCF");
+		let entry = BinfmtEntry::parse(&TEST_BINFMT_ENTRY.replace("enabled", "disabled"))?;
+		assert!(!entry.enabled);
+		assert!(BinfmtEntry::parse("enabled\nflags: F\n").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_human_size() {
+		assert_eq!(human_size(512), "512 B");
+		assert_eq!(human_size(1536), "1.5 KiB");
+		assert_eq!(human_size(5120 * 1048576), "5.0 GiB");
+		assert_eq!(human_size(851_760_000), "812.3 MiB");
+	}
+
+	#[test]
+	fn test_copy_preserving() -> Result<()> {
+		if unsafe { geteuid() } != 0 {
+			bail!("Not being run as root user, aborting.");
+		}
+		// tmpfs supports security.* extended attributes.
+		let dir = Path::new("/dev/shm").join(format!("mkrawimg-test-copy-{}", std::process::id()));
+		std::fs::create_dir_all(&dir)?;
+		let src = dir.join("ping");
+		let dst = dir.join("ping.copy");
+		std::fs::write(&src, b"#!/bin/sh\n")?;
+		std::fs::set_permissions(&src, std::os::unix::fs::PermissionsExt::from_mode(0o4755))?;
+		// VFS_CAP_REVISION_2, cap_net_raw (13) permitted
+		let cap = [
+			0x00, 0x00, 0x00, 0x02, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+			0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+		];
+		xattr::set(&src, "security.capability", &cap)?;
+		let times = FileTimes::new().set_modified(std::time::UNIX_EPOCH);
+		File::options().write(true).open(&src)?.set_times(times)?;
+		copy_preserving(&src, &dst)?;
+		assert_eq!(
+			xattr::get(&dst, "security.capability")?.as_deref(),
+			Some(&cap[..])
+		);
+		let metadata = std::fs::metadata(&dst)?;
+		assert_eq!(metadata.mode() & 0o7777, 0o4755);
+		assert_eq!(metadata.modified()?, std::time::UNIX_EPOCH);
+		std::fs::remove_dir_all(&dir)?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_find_program() {
+		assert!(find_program("sh", "/nonexistent").is_some());
+		assert!(find_program("mkrawimg-nonexistent-tool", "/").is_none());
+		assert_eq!(
+			find_program("bin/sh", "/usr"),
+			Some(PathBuf::from("/usr/bin/sh"))
+		);
+		assert!(find_program("./Cargo.toml", env!("CARGO_MANIFEST_DIR")).is_none());
+	}
+
+	#[test]
+	fn test_unit_names() -> Result<()> {
+		assert_eq!(normalize_unit_name("sshd"), "sshd.service");
+		assert_eq!(normalize_unit_name("fstrim.timer"), "fstrim.timer");
+		check_unit_name("serial-getty@ttyS0.service")?;
+		check_unit_name("NetworkManager")?;
+		assert!(check_unit_name("sshd; reboot").is_err());
+		assert!(check_unit_name("--now").is_err());
+		let root = TestDir::new("units")?;
+		let unit_dir = root.join("usr/lib/systemd/system");
+		std::fs::create_dir_all(&unit_dir)?;
+		std::fs::write(unit_dir.join("sshd.service"), "")?;
+		std::fs::write(unit_dir.join("serial-getty@.service"), "")?;
+		assert_eq!(
+			find_unit_file(&root, "sshd"),
+			Some(unit_dir.join("sshd.service"))
+		);
+		assert_eq!(
+			find_unit_file(&root, "serial-getty@ttyS0.service"),
+			Some(unit_dir.join("serial-getty@.service"))
+		);
+		assert!(find_unit_file(&root, "nonexistent").is_none());
+		Ok(())
+	}
+
+	#[test]
+	fn test_password_file() -> Result<()> {
+		let dir = TestDir::new("password-file")?;
+		let path = dir.join("password");
+		for (content, password) in [
+			("secret\n", Some("secret")),
+			("secret\r\n", Some("secret")),
+			("secret", Some("secret")),
+			(" secret \n", Some(" secret ")),
+			("\n", None),
+			("secret\nmore\n", None),
+		] {
+			std::fs::write(&path, content)?;
+			assert_eq!(
+				read_password_file(&path).ok().as_deref(),
+				password,
+				"{:?}",
+				content
+			);
+		}
+		assert!(read_password_file(&dir.join("missing")).is_err());
+		assert!(is_password_hash("$6$salt$hash"));
+		assert!(is_password_hash("$y$j9T$salt$hash"));
+		assert!(!is_password_hash("anthon"));
+		assert!(!is_password_hash("$1$salt$hash"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_lock_password() -> Result<()> {
+		let root = TestDir::new("shadow")?;
+		std::fs::create_dir_all(root.join("etc"))?;
+		std::fs::write(
+			root.join("etc/shadow"),
+			"root:$6$salt$hash:19000:0:99999:7:::\naosc:!:19000::::::\nnobody::19000::::::\n",
+		)?;
+		lock_password(&root, "root")?;
+		lock_password(&root, "aosc")?;
+		lock_password(&root, "nobody")?;
+		assert_eq!(
+			std::fs::read_to_string(root.join("etc/shadow"))?,
+			"root:!$6$salt$hash:19000:0:99999:7:::\naosc:!:19000::::::\nnobody:!:19000::::::\n"
+		);
+		assert!(lock_password(&root, "missing").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_shell_quote() {
+		assert_eq!(shell_quote("abc"), "'abc'");
+		assert_eq!(shell_quote("a b"), "'a b'");
+		assert_eq!(shell_quote("it's"), "'it'\\''s'");
+		assert_eq!(shell_quote("$(reboot)"), "'$(reboot)'");
+	}
+
+	#[test]
+	fn test_sha256_file() -> Result<()> {
+		let dir = TestDir::new("sha256")?;
+		let path = dir.join("abc");
+		std::fs::write(&path, b"abc")?;
+		assert_eq!(
+			sha256_file(&path)?,
+			"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_format_binfmt_failures() {
+		let failures = vec![
+			(DeviceArch::arm64, anyhow!("qemu-aarch64 is not found")),
+			(DeviceArch::riscv64, anyhow!("qemu-riscv64 is not found")),
+		];
+		let msg = format_binfmt_failures(&failures);
+		assert!(msg.contains("2 architecture(s)"));
+		assert!(msg.contains("- arm64: qemu-aarch64 is not found"));
+		assert!(msg.contains("qemu-user-static-riscv (Fedora)"));
+	}
+
+	#[test]
+	fn test_check_qemu_cpu_models() {
+		let interp = Path::new("/usr/bin/qemu-mips64el-static");
+		assert!(
+			check_qemu_cpu_models(&DeviceArch::loongson3, interp, TEST_CPU_HELP_PRE_R6).is_ok()
+		);
+		assert!(
+			check_qemu_cpu_models(&DeviceArch::mips64r6el, interp, TEST_CPU_HELP_PRE_R6).is_err()
+		);
+		assert!(check_qemu_cpu_models(&DeviceArch::mips64r6el, interp, TEST_CPU_HELP_R6).is_ok());
+		assert!(check_qemu_cpu_models(&DeviceArch::loongson3, interp, TEST_CPU_HELP_R6).is_err());
+		// Architectures without a shared registration are not checked.
+		assert!(check_qemu_cpu_models(&DeviceArch::riscv64, interp, "").is_ok());
+	}
+
+	#[test]
+	fn test_escape_nspawn_bind() {
+		assert_eq!(escape_nspawn_bind("/dev/loop0p1"), "/dev/loop0p1");
+		assert_eq!(
+			escape_nspawn_bind("/srv/builds/2024:nightly"),
+			"/srv/builds/2024\\:nightly"
+		);
+		assert_eq!(escape_nspawn_bind("/srv/a\\b:c"), "/srv/a\\\\b\\:c");
+	}
+
+	#[test]
+	fn test_tarball_args() {
+		let args = tarball_args(Path::new("/work/rootfs"), Path::new("/out/rootfs.tar"));
+		for arg in [
+			"--numeric-owner",
+			"--acls",
+			"--xattrs",
+			"--xattrs-include=*",
+		] {
+			assert!(args.contains(&OsString::from(arg)), "{}", arg);
+		}
+		assert_eq!(
+			&args[args.len() - 5..],
+			[
+				"--file",
+				"/out/rootfs.tar",
+				"--directory",
+				"/work/rootfs",
+				"."
+			]
+			.map(OsString::from)
+		);
+	}
+
+	#[test]
+	fn test_bootstrap_stage() {
+		assert_eq!(
+			BootstrapStage::from_line("Downloading packages ..."),
+			Some(BootstrapStage::Downloading)
+		);
+		assert_eq!(
+			BootstrapStage::from_line("  Unpacking bash (5.2.37) ..."),
+			Some(BootstrapStage::Unpacking)
+		);
+		assert_eq!(
+			BootstrapStage::from_line("Setting up systemd (257.2) ..."),
+			Some(BootstrapStage::Configuring)
+		);
+		assert_eq!(BootstrapStage::from_line("warning: foo"), None);
+	}
+
+	#[test]
+	fn test_run_with_log() -> Result<()> {
+		let dir = TestDir::new("log")?;
+		let log_path = dir.join("build.log");
+		let mut command = Command::new("sh");
+		command.args([
+			"-c",
+			"echo out; echo err >&2; i=0; while [ $i -lt 100 ]; do echo $i; i=$((i+1)); done; exit 3",
+		]);
+		let mut count = 0;
+		let (status, tail) = run_with_log(&mut command, &log_path, |_| count += 1)?;
+		let log = std::fs::read_to_string(&log_path)?;
+		assert_eq!(status.code(), Some(3));
+		assert_eq!(count, 102);
+		assert_eq!(tail.len(), LOG_TAIL_LINES);
+		// stdout and stderr are read concurrently, their order is not kept.
+		assert!(tail.iter().any(|x| x == "99"));
+		assert!(log.contains("out\n") && log.contains("err\n"));
+		assert_eq!(log.lines().count(), 102);
+		Ok(())
+	}
+
+	#[test]
+	fn test_cmd_run_logged() -> Result<()> {
+		let dir = TestDir::new("build-log")?;
+		let log_path = dir.join("build.log");
+		let log = BuildLog::start(log_path.clone())?;
+		cmd_run_logged(Command::new("sh").args(["-c", "echo first"]))?;
+		let err = cmd_run_logged(Command::new("sh").args([
+			"-c",
+			"i=0; while [ $i -lt 60 ]; do echo line$i; i=$((i+1)); done; exit 2",
+		]))
+		.unwrap_err();
+		drop(log);
+		let content = std::fs::read_to_string(&log_path);
+		let content = content?;
+		// Both commands are appended to the same log
+		assert!(
+			content.starts_with("==> sh -c 'echo first'\nfirst\n"),
+			"{}",
+			content
+		);
+		assert!(content.contains("line0\n") && content.contains("line59\n"));
+		let err = format!("{:#}", err);
+		assert!(err.contains("exit code 2"), "{}", err);
+		assert!(err.contains(&log_path.display().to_string()), "{}", err);
+		assert!(
+			err.contains("Last 50 lines of output:\nline10\n"),
+			"{}",
+			err
+		);
+		assert!(!err.contains("line9\n"), "{}", err);
+		// Nothing is logged after the log is dropped
+		assert!(BUILD_LOG.lock().unwrap().is_none());
+		Ok(())
+	}
+
+	#[test]
+	fn test_derive_bytes() {
+		let a = derive_bytes("0", &["rpi-5b", "partition-1"]);
+		assert_eq!(a, derive_bytes("0", &["rpi-5b", "partition-1"]));
+		assert_ne!(a, derive_bytes("1", &["rpi-5b", "partition-1"]));
+		assert_ne!(a, derive_bytes("0", &["rpi-5b", "partition-2"]));
+		assert_ne!(
+			derive_bytes("0", &["ab", "c"]),
+			derive_bytes("0", &["a", "bc"])
+		);
+	}
+
+	#[test]
+	fn test_clamp_file_times() -> Result<()> {
+		let dir = TestDir::new("clamp")?;
+		let path = dir.join("file");
+		std::fs::write(&path, "")?;
+		clamp_file_times(&path, 1700000000)?;
+		let mtime = path.metadata()?.mtime();
+		// Older timestamps are kept
+		clamp_file_times(&path, 1800000000)?;
+		let kept = path.metadata()?.mtime();
+		assert_eq!(mtime, 1700000000);
+		assert_eq!(kept, 1700000000);
+		Ok(())
+	}
+	#[test]
+	fn test_wait_for_block_devices() {
+		let nodes = [
+			PathBuf::from("/dev/null"),
+			PathBuf::from("/nonexistent/loop0p1"),
+		];
+		let start = Instant::now();
+		let err = wait_for_block_devices(&nodes, Duration::from_millis(200)).unwrap_err();
+		assert!(start.elapsed() >= Duration::from_millis(200));
+		// Character devices do not count
+		assert!(
+			err.to_string()
+				.ends_with(": /dev/null, /nonexistent/loop0p1"),
+			"{}",
+			err
+		);
+		wait_for_block_devices(&[], Duration::ZERO).unwrap();
+	}
+
+	#[test]
+	fn test_nspawn_machine_name() {
+		assert_eq!(
+			nspawn_machine_name("rpi-5b", "base", 1234),
+			"mkrawimg-rpi-5b-base-1234"
+		);
+		assert_eq!(
+			nspawn_machine_name("vendor_board.v2", "desktop", 1),
+			"mkrawimg-vendor-board-v2-desktop-1"
+		);
+		let long = nspawn_machine_name(&"x".repeat(80), "server", 4194304);
+		assert_eq!(long.len(), NSPAWN_MACHINE_NAME_MAX);
+		assert!(long.ends_with("x-server-4194304"), "{}", long);
+		let args: Vec<_> = nspawn_command(Path::new("/tmp/root"), "mkrawimg-test")
+			.get_args()
+			.map(|x| x.to_string_lossy().into_owned())
+			.collect();
+		assert_eq!(
+			args,
+			[
+				"-q",
+				"-D",
+				"/tmp/root",
+				"--register=no",
+				"--machine",
+				"mkrawimg-test",
+				"--resolv-conf=off"
+			]
+		);
+	}
+
+	#[test]
+	fn test_resolve_sudo_env() {
+		let invoking = HashMap::from([
+			("http_proxy".to_owned(), "http://proxy:8080".to_owned()),
+			("https_proxy".to_owned(), "http://proxy:8080".to_owned()),
+			("PATH".to_owned(), "/home/user/bin:/usr/bin".to_owned()),
+			("HOME".to_owned(), "/home/user".to_owned()),
+		]);
+		let current = |name: &str| (name == "https_proxy").then(|| "http://other:3128".to_owned());
+		// The other variables are not imported
+		let env = resolve_sudo_env(current, &invoking, Some(Path::new("/var/home/user")));
+		assert_eq!(
+			env,
+			vec![
+				("http_proxy".to_owned(), "http://proxy:8080".to_owned()),
+				("XDG_CACHE_HOME".to_owned(), "/home/user/.cache".to_owned()),
+			]
+		);
+		// Without an environment, the home directory in the user database is used
+		let env = resolve_sudo_env(|_| None, &HashMap::new(), Some(Path::new("/var/home/user")));
+		assert_eq!(
+			env,
+			vec![(
+				"XDG_CACHE_HOME".to_owned(),
+				"/var/home/user/.cache".to_owned()
+			)]
+		);
+		// An explicit XDG_CACHE_HOME of our own is kept
+		let current = |name: &str| (name == "XDG_CACHE_HOME").then(|| "/root/.cache".to_owned());
+		assert!(resolve_sudo_env(current, &HashMap::new(), None).is_empty());
+		assert!(resolve_sudo_env(|_| None, &HashMap::new(), None).is_empty());
+	}
+
+	#[test]
+	fn test_cache_dir() {
+		let env = HashMap::from([
+			("XDG_CACHE_HOME".to_owned(), "/tmp/cache".to_owned()),
+			("HOME".to_owned(), "/home/user".to_owned()),
+		]);
+		let home = Some(Path::new("/var/home/user"));
+		assert_eq!(
+			cache_dir(|x| env.get(x).cloned(), home),
+			Some(PathBuf::from("/tmp/cache"))
+		);
+		let env = HashMap::from([
+			("XDG_CACHE_HOME".to_owned(), "cache".to_owned()),
+			("HOME".to_owned(), "/home/user".to_owned()),
+		]);
+		assert_eq!(
+			cache_dir(|x| env.get(x).cloned(), home),
+			Some(PathBuf::from("/home/user/.cache"))
+		);
+		// Relative paths are ignored
+		let env = HashMap::from([("HOME".to_owned(), "home".to_owned())]);
+		assert_eq!(
+			cache_dir(|x| env.get(x).cloned(), home),
+			Some(PathBuf::from("/var/home/user/.cache"))
+		);
+		assert_eq!(cache_dir(|_| None, None), None);
+	}
+
+	#[test]
+	fn test_mounts_under() {
+		let mountinfo = "\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+100 22 7:0 / /work/sketches/test-Base/mnt/p2 rw,relatime shared:50 - ext4 /dev/loop0p2 rw
+101 100 7:0 / /work/sketches/test-Base/mnt/p2/boot rw,relatime shared:51 - vfat /dev/loop0p1 rw
+102 22 7:0 / /work/sketches/test-Base/mnt/p1 rw,relatime shared:52 - vfat /dev/loop0p1 rw
+103 22 0:5 / /work/sketches/test-Base-rebootload/mnt/p2 rw - ext4 /dev/loop1p2 rw
+104 22 0:6 / /work/sketches/test-Base/mnt/with\\040space rw - tmpfs tmpfs rw
+";
+		assert_eq!(
+			mounts_under(mountinfo, Path::new("/work/sketches/test-Base/mnt")),
+			vec![
+				PathBuf::from("/work/sketches/test-Base/mnt/p2"),
+				PathBuf::from("/work/sketches/test-Base/mnt/p2/boot"),
+				PathBuf::from("/work/sketches/test-Base/mnt/p1"),
+				PathBuf::from("/work/sketches/test-Base/mnt/with space"),
+			]
+		);
+	}
+
+	#[test]
+	fn test_format_command() {
+		let cmd = chroot_str_script_command(
+			&Path::new("/tmp/root"),
+			"mkrawimg-test",
+			"echo 'hi'",
+			&["/dev/loop0"],
+			None,
+		);
+		assert_eq!(
+			format_command(&cmd),
+			concat!(
+				"systemd-nspawn -q -D /tmp/root --register=no --machine mkrawimg-test ",
+				"--resolv-conf=off --bind /dev/loop0 -- /bin/bash -c -- ",
+				r#"'source /tmp/spec.sh ;echo '\''hi'\''' '<tmp_script>'"#
+			)
+		);
+		let mut cmd = Command::new("mkfs.vfat");
+		cmd.args(["-n", "", "-F", "32"]);
+		assert_eq!(format_command(&cmd), "mkfs.vfat -n '' -F 32");
+		let cmd = chroot_shell_command(Path::new("/tmp/root"), "mkrawimg-test", &["/dev/loop0"]);
+		assert!(
+			format_command(&cmd)
+				.ends_with(" --bind /dev/loop0 -- /bin/bash --rcfile /tmp/spec.sh -i"),
+			"{:?}",
+			cmd
+		);
+	}
+
+	#[test]
+	fn test_non_utf8_paths() {
+		use std::os::unix::ffi::OsStrExt;
+		let dev = Path::new(std::ffi::OsStr::from_bytes(b"/tmp/w\xffrk/loop0"));
+		// Invalid bytes are kept as is
+		assert_eq!(
+			partition_path(dev, 2).as_os_str().as_bytes(),
+			b"/tmp/w\xffrk/loop0p2"
+		);
+		assert_eq!(partition_path("/dev/loop0", 1), Path::new("/dev/loop0p1"));
+		let err = path_str(dev).unwrap_err();
+		assert!(
+			err.to_string().contains("'/tmp/w\u{FFFD}rk/loop0'"),
+			"{}",
+			err
+		);
+		assert_eq!(path_str(Path::new("/dev/loop0")).unwrap(), "/dev/loop0");
+	}
+
+	#[test]
+	fn test_retry_while_busy() {
+		let busy = || std::io::Error::from_raw_os_error(libc::EBUSY);
+		let mut calls = 0;
+		let result = retry_while_busy(3, Duration::ZERO, || {
+			calls += 1;
+			if calls < 3 { Err(busy()) } else { Ok(()) }
+		});
+		assert!(result.is_ok());
+		assert_eq!(calls, 3);
+		// Exhausted
+		let mut calls = 0;
+		let result = retry_while_busy(2, Duration::ZERO, || {
+			calls += 1;
+			Err(busy())
+		});
+		assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EBUSY));
+		assert_eq!(calls, 2);
+		// Other errors fail at once
+		let mut calls = 0;
+		let result = retry_while_busy(5, Duration::ZERO, || {
+			calls += 1;
+			Err(std::io::Error::from_raw_os_error(libc::EINVAL))
+		});
+		assert!(result.is_err());
+		assert_eq!(calls, 1);
+	}
+
+	#[test]
+	fn test_attach_with_retries() {
+		let busy = || std::io::Error::from_raw_os_error(libc::EBUSY);
+		let mut calls = 0;
+		let result = attach_with_retries(3, Duration::ZERO, || {
+			calls += 1;
+			if calls < 3 {
+				Err((Some(PathBuf::from(format!("/dev/loop{}", calls))), busy()))
+			} else {
+				Ok(calls)
+			}
+		});
+		assert_eq!(result.unwrap(), 3);
+		// Exhausted, naming the last device
+		let mut calls = 0;
+		let err = attach_with_retries(2, Duration::ZERO, || -> Result<(), _> {
+			calls += 1;
+			Err((Some(PathBuf::from(format!("/dev/loop{}", calls))), busy()))
+		})
+		.unwrap_err();
+		assert_eq!(calls, 2);
+		assert!(err.to_string().ends_with("/dev/loop2"), "{}", err);
+		// Other errors fail at once
+		let mut calls = 0;
+		let err = attach_with_retries(5, Duration::ZERO, || -> Result<(), _> {
+			calls += 1;
+			Err((None, std::io::Error::from_raw_os_error(libc::EINVAL)))
+		})
+		.unwrap_err();
+		assert_eq!(calls, 1);
+		assert!(err.to_string().contains("next free loop device"), "{}", err);
+	}
+
+	#[test]
+	fn test_inode_usage() -> Result<()> {
+		let (used, _) = inode_usage("/")?;
+		// At least the root directory itself
+		assert!(used > 0);
+		assert!(inode_usage("/nonexistent/mkrawimg").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_free_space() -> Result<()> {
+		let dir = std::env::temp_dir();
+		let free = free_space(&dir)?;
+		// Not more than the size of the filesystem
+		let stat = statvfs(&dir)?;
+		assert!(free <= stat.f_blocks * stat.f_frsize);
+		assert!(free_space("/nonexistent/mkrawimg").is_err());
+		let (size, used, available) = filesystem_usage(&dir)?;
+		assert!(used + available <= size && available <= free);
+		assert!(filesystem_usage("/nonexistent/mkrawimg").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_list_attached_loops() -> Result<()> {
+		let dir = TestDir::new("sysfs")?;
+		let block = dir.join("block");
+		for (name, backing_file) in [
+			(
+				"loop0",
+				Some("/srv/work/sketches/rpi-5b-base/rawmedia.img\n"),
+			),
+			("loop1", None),
+			(
+				"loop2",
+				Some("/srv/work/sketches/pc-efi-base/rawmedia.img (deleted)\n"),
+			),
+			("loop10", Some("/home/user/disk.img\n")),
+			("sda", Some("/srv/work/sketches/bogus.img\n")),
+		] {
+			std::fs::create_dir_all(block.join(name).join("queue"))?;
+			if let Some(f) = backing_file {
+				std::fs::create_dir_all(block.join(name).join("loop"))?;
+				std::fs::write(block.join(name).join("loop/backing_file"), f)?;
+			}
+		}
+		let loops = list_attached_loops(&block)?;
+		assert!(list_attached_loops(dir.join("nonexistent"))?.is_empty());
+		assert_eq!(
+			loops,
+			[
+				("/dev/loop0", "/srv/work/sketches/rpi-5b-base/rawmedia.img"),
+				("/dev/loop10", "/home/user/disk.img"),
+				("/dev/loop2", "/srv/work/sketches/pc-efi-base/rawmedia.img"),
+			]
+			.map(|(device, backing_file)| AttachedLoop {
+				device: PathBuf::from(device),
+				backing_file: PathBuf::from(backing_file),
+			})
+		);
+		let devices = |dir| -> Vec<&Path> {
+			loops_within(&loops, dir)
+				.iter()
+				.map(|l| l.device.as_path())
+				.collect()
+		};
+		assert_eq!(
+			devices("/srv/work/sketches"),
+			[Path::new("/dev/loop0"), Path::new("/dev/loop2")]
+		);
+		assert_eq!(
+			devices("/srv/work/sketches/rpi-5b-base"),
+			[Path::new("/dev/loop0")]
+		);
+		// Paths are compared by components
+		assert!(devices("/srv/work/sketches/rpi-5b").is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn test_large_sparse_file() -> Result<()> {
+		use std::os::unix::fs::MetadataExt;
+		let dir = TestDir::new("sparse-file")?;
+		let path = dir.join("sparse.img");
+		assert!(create_sparse_file(&path, 0).is_err());
+		let size = 3u64 << 40;
+		if let Err(e) = create_sparse_file(&path, size) {
+			if let Some(io) = e.downcast_ref::<std::io::Error>()
+				&& io.kind() == std::io::ErrorKind::FileTooLarge
+			{
+				eprintln!("The filesystem does not support files of 3 TiB, skipping");
+				return Ok(());
+			}
+			return Err(e);
+		}
+		let metadata = std::fs::metadata(&path)?;
+		assert_eq!(metadata.len(), size);
+		// Only the last byte is allocated
+		assert!(metadata.blocks() * 512 < 1048576);
 		Ok(())
 	}
 }
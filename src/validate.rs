@@ -0,0 +1,453 @@
+//! Validation of the strings defined in device specifications.
+//!
+//! Most of the strings end up in shell scripts (e.g. `spec.sh`), command lines or configuration files (e.g. `fstab`).
+//! Instead of rejecting known dangerous characters, each class of fields has a list of allowed characters.
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use log::warn;
+
+use crate::utils::UserOptions;
+
+/// Class of a field, deciding which characters are allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldClass {
+	/// IDs, vendors, aliases and compatible strings: ASCII letters, digits and `-_.,+`. No spaces.
+	Identifier,
+	/// Names and models: letters, digits, spaces and `-_.,+():#@`.
+	DisplayName,
+	/// Partition and filesystem labels: ASCII letters, digits, spaces and `-_.+`.
+	Label,
+	/// GPT partition labels: like [`FieldClass::Label`], plus printable non-ASCII characters.
+	///
+	/// Partition labels only end up in the partition table, which stores them as UTF-16.
+	PartitionLabel,
+	/// Mountpoints: absolute paths consisting of ASCII letters, digits and `-_.+`.
+	Mountpoint,
+	/// Package names and parts of them: lowercase ASCII letters, digits and `-.+`, starting with a letter or a digit.
+	PackageName,
+}
+
+impl FieldClass {
+	fn is_allowed(&self, c: char) -> bool {
+		match self {
+			Self::Identifier => c.is_ascii_alphanumeric() || "-_.,+".contains(c),
+			Self::DisplayName => c.is_alphanumeric() || " -_.,+():#@".contains(c),
+			Self::Label => c.is_ascii_alphanumeric() || " -_.+".contains(c),
+			Self::PartitionLabel => {
+				Self::Label.is_allowed(c) || !(c.is_ascii() || c.is_control() || c.is_whitespace())
+			}
+			Self::Mountpoint => c.is_ascii_alphanumeric() || "/-_.+".contains(c),
+			Self::PackageName => c.is_ascii_lowercase() || c.is_ascii_digit() || "-.+".contains(c),
+		}
+	}
+
+	fn describe(&self) -> &'static str {
+		match self {
+			Self::Identifier => "ASCII letters, digits and '-_.,+'",
+			Self::DisplayName => "letters, digits, spaces and '-_.,+():#@'",
+			Self::Label => "ASCII letters, digits, spaces and '-_.+'",
+			Self::PartitionLabel => {
+				"ASCII letters, digits, spaces, '-_.+' and printable non-ASCII characters"
+			}
+			Self::Mountpoint => "ASCII letters, digits and '/-_.+'",
+			Self::PackageName => "lowercase ASCII letters, digits and '-.+'",
+		}
+	}
+
+	/// Validate `value` of the field named `field`.
+	pub fn validate(&self, field: &str, value: &str) -> Result<()> {
+		if value.is_empty() {
+			bail!("Field {} can not be empty", field);
+		}
+		if let Some(c) = value.chars().find(|c| !self.is_allowed(*c)) {
+			bail!(
+				"Field {} ('{}') contains a disallowed character {:?}. Only {} are allowed.",
+				field,
+				value.escape_default(),
+				c,
+				self.describe()
+			);
+		}
+		match self {
+			Self::DisplayName | Self::Label | Self::PartitionLabel => {
+				if value.starts_with(' ') || value.ends_with(' ') {
+					bail!(
+						"Field {} ('{}') can not start or end with spaces",
+						field,
+						value
+					);
+				}
+			}
+			Self::Mountpoint => {
+				if !value.starts_with('/') {
+					bail!("Mountpoint '{}' must be an absolute path", value);
+				}
+				if value
+					.split('/')
+					.skip(1)
+					.any(|x| x == "." || x == ".." || (x.is_empty() && value != "/"))
+				{
+					bail!("Mountpoint '{}' must be a normalized path", value);
+				}
+			}
+			Self::Identifier => {
+				if value.starts_with('-') {
+					bail!("Field {} ('{}') can not start with '-'", field, value);
+				}
+			}
+			Self::PackageName => {
+				if !value.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+					bail!(
+						"Field {} ('{}') must start with a letter or a digit",
+						field,
+						value
+					);
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Validate the kernel command line.
+///
+/// The command line ends up in a single line of configuration files, and the kernel only understands double quotes.
+pub fn validate_kernel_cmdline(value: &str) -> Result<()> {
+	if let Some(c) = value.chars().find(|c| c.is_control()) {
+		bail!(
+			"Kernel command line '{}' contains a disallowed character {:?}",
+			value.escape_default(),
+			c
+		);
+	}
+	if !value.matches('"').count().is_multiple_of(2) {
+		bail!("Kernel command line '{}' has unbalanced quotes", value);
+	}
+	Ok(())
+}
+
+/// Validate a path used for the working directory, the output directory or derived from them.
+///
+/// The paths end up in the arguments of systemd-nspawn and in mount option strings, where commas and newlines can not be escaped.
+/// Colons are escaped for the bind mounts of systemd-nspawn, but other tools may still misinterpret them.
+pub fn validate_work_path(name: &str, path: &Path) -> Result<()> {
+	let value = path.to_string_lossy();
+	if let Some(c) = value.chars().find(|c| c.is_control() || *c == ',') {
+		bail!(
+			"Path '{}' of {} contains a character {:?} which is not supported, please use another path",
+			value.escape_default(),
+			name,
+			c
+		);
+	}
+	if value.starts_with('-') {
+		bail!(
+			"Path '{}' of {} can not start with '-', please use './{}' instead",
+			value,
+			name,
+			value
+		);
+	}
+	if value.contains(':') {
+		warn!(
+			"Path '{}' of {} contains ':', which might break the tools used to build the images.",
+			value, name
+		);
+	}
+	Ok(())
+}
+
+/// Maximum length of user and group names accepted by useradd.
+const USERNAME_MAX_LEN: usize = 32;
+
+/// Validate the `kind` (e.g. "Username") `name` of a user or a group.
+///
+/// shadow only accepts names starting with a lowercase letter or `_`, followed by lowercase letters, digits, `_` and `-`.
+pub fn validate_account_name(kind: &str, name: &str) -> Result<()> {
+	let mut chars = name.chars();
+	let Some(first) = chars.next() else {
+		bail!("{} can not be empty", kind);
+	};
+	if !(first.is_ascii_lowercase() || first == '_') {
+		bail!(
+			"{} '{}' must start with a lowercase letter or '_'",
+			kind,
+			name.escape_default()
+		);
+	}
+	if let Some(c) =
+		chars.find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || "_-".contains(*c)))
+	{
+		bail!(
+			"{} '{}' contains a disallowed character {:?}. Only lowercase ASCII letters, digits and '_-' are allowed.",
+			kind,
+			name.escape_default(),
+			c
+		);
+	}
+	if name.len() > USERNAME_MAX_LEN {
+		bail!(
+			"{} '{}' is longer than {} characters",
+			kind,
+			name,
+			USERNAME_MAX_LEN
+		);
+	}
+	Ok(())
+}
+
+/// Validate the username of the built-in user.
+pub fn validate_username(name: &str) -> Result<()> {
+	validate_account_name("Username", name)
+}
+
+/// Validate the extra groups, the shell and the UID of the built-in user.
+pub fn validate_user_options(options: &UserOptions) -> Result<()> {
+	for group in options.groups.iter().flatten() {
+		validate_account_name("Group name", group)?;
+	}
+	if let Some(shell) = &options.shell {
+		FieldClass::Mountpoint
+			.validate("user_shell", shell)
+			.context("The shell must be an absolute path")?;
+	}
+	// Taken by root and nobody.
+	if let Some(uid @ (0 | 65534)) = options.uid {
+		bail!("UID {} can not be used for the built-in user", uid);
+	}
+	Ok(())
+}
+
+/// Validate the password of the built-in user, which is passed to chpasswd as `name:password` in a line.
+pub fn validate_password(password: &str) -> Result<()> {
+	if let Some(c) = password.chars().find(|c| matches!(c, ':' | '\n' | '\r')) {
+		bail!(
+			"The password contains a character {:?} which chpasswd does not support",
+			c
+		);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_user() {
+		for v in ["aosc", "_build", "user-01", "a_b"] {
+			assert!(validate_username(v).is_ok(), "{}", v);
+		}
+		for v in [
+			"",
+			"Aosc",
+			"1user",
+			"-user",
+			"user name",
+			"user:x",
+			"user\n",
+			"usér",
+			"a23456789012345678901234567890123",
+		] {
+			assert!(validate_username(v).is_err(), "{}", v);
+		}
+		for v in ["anthon", "$6$salt$hash", "with spaces", "", "'\"$"] {
+			assert!(validate_password(v).is_ok(), "{}", v);
+		}
+		for v in ["a:b", "a\nb", "a\r"] {
+			assert!(validate_password(v).is_err(), "{:?}", v);
+		}
+		let options = UserOptions {
+			groups: Some(vec!["gpio".into(), "i2c".into(), "dialout".into()]),
+			shell: Some("/usr/bin/zsh".into()),
+			uid: Some(1500),
+		};
+		validate_user_options(&options).unwrap();
+		validate_user_options(&UserOptions::default()).unwrap();
+		for invalid in [
+			UserOptions {
+				groups: Some(vec!["GPIO".into()]),
+				..Default::default()
+			},
+			UserOptions {
+				groups: Some(vec!["gpio,i2c".into()]),
+				..Default::default()
+			},
+			UserOptions {
+				shell: Some("zsh".into()),
+				..Default::default()
+			},
+			UserOptions {
+				shell: Some("/bin/sh -x".into()),
+				..Default::default()
+			},
+			UserOptions {
+				uid: Some(0),
+				..Default::default()
+			},
+		] {
+			assert!(
+				validate_user_options(&invalid).is_err(),
+				"{:?} is accepted",
+				invalid
+			);
+		}
+	}
+
+	const ADVERSARIAL: &[&str] = &[
+		"$(reboot)",
+		"`reboot`",
+		"a;b",
+		"a|b",
+		"a&b",
+		"a>b",
+		"'quoted'",
+		"\"quoted\"",
+		"a\\b",
+		"new\nline",
+		"tab\tbed",
+		"${HOME}",
+		"a*b",
+		"[a]",
+		"!a",
+		"",
+	];
+
+	#[test]
+	fn test_adversarial() {
+		for class in [
+			FieldClass::Identifier,
+			FieldClass::DisplayName,
+			FieldClass::Label,
+			FieldClass::PartitionLabel,
+			FieldClass::Mountpoint,
+			FieldClass::PackageName,
+		] {
+			for value in ADVERSARIAL {
+				assert!(
+					class.validate("test", value).is_err(),
+					"{:?} accepted {:?}",
+					class,
+					value
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn test_identifier() {
+		let c = FieldClass::Identifier;
+		for v in ["rpi-5b", "raspberrypi", "raspberrypi,5-model-b", "pc_efi.2"] {
+			assert!(c.validate("id", v).is_ok(), "{}", v);
+		}
+		for v in ["rpi 5b", "-rf", "rpi/5b", "设备"] {
+			assert!(c.validate("id", v).is_err(), "{}", v);
+		}
+	}
+
+	#[test]
+	fn test_display_name() {
+		let c = FieldClass::DisplayName;
+		for v in [
+			"Raspberry Pi 5",
+			"Standard PC (UEFI)",
+			"Orange Pi 5 Max",
+			"龙芯 2K0300",
+		] {
+			assert!(c.validate("name", v).is_ok(), "{}", v);
+		}
+		for v in [" Pi", "Pi/5", "Pi $5"] {
+			assert!(c.validate("name", v).is_err(), "{}", v);
+		}
+	}
+
+	#[test]
+	fn test_label() {
+		let c = FieldClass::Label;
+		for v in ["EFI", "AOSC OS", "boot_a", "u-boot.env"] {
+			assert!(c.validate("label", v).is_ok(), "{}", v);
+		}
+		for v in ["AOSC,OS", "ROOT ", "/boot"] {
+			assert!(c.validate("label", v).is_err(), "{}", v);
+		}
+	}
+
+	#[test]
+	fn test_partition_label() {
+		let c = FieldClass::PartitionLabel;
+		for v in ["EFI", "AOSC OS", "根文件系统", "🐧 Root"] {
+			assert!(c.validate("label", v).is_ok(), "{}", v);
+		}
+		for v in ["AOSC,OS", "ROOT\u{3000}", "a\u{85}b", "/boot"] {
+			assert!(c.validate("label", v).is_err(), "{}", v);
+		}
+	}
+
+	#[test]
+	fn test_mountpoint() {
+		let c = FieldClass::Mountpoint;
+		for v in ["/", "/boot", "/boot/efi", "/var/lib/data-1"] {
+			assert!(c.validate("mountpoint", v).is_ok(), "{}", v);
+		}
+		for v in [
+			"boot",
+			"/boot/",
+			"//boot",
+			"/boot/../etc",
+			"/./boot",
+			"/my data",
+		] {
+			assert!(c.validate("mountpoint", v).is_err(), "{}", v);
+		}
+	}
+
+	#[test]
+	fn test_package_name() {
+		let c = FieldClass::PackageName;
+		for v in ["rpi", "arm64-generic", "libstdc++6", "2k1000la.v2"] {
+			assert!(c.validate("devena_firstboot_target", v).is_ok(), "{}", v);
+		}
+		for v in ["RPi", "-rpi", ".rpi", "rpi_5", "rpi 5"] {
+			assert!(c.validate("devena_firstboot_target", v).is_err(), "{}", v);
+		}
+	}
+
+	#[test]
+	fn test_kernel_cmdline() {
+		for v in [
+			"rw console=ttyS2,1500000 rootwait",
+			"root=PARTUUID=01234567-01 rw",
+			"dyndbg=\"file drivers/usb/* +p\" quiet",
+			"",
+		] {
+			assert!(validate_kernel_cmdline(v).is_ok(), "{}", v);
+		}
+		for v in ["rw\nquiet", "rw\rquiet", "dyndbg=\"+p quiet", "a\"b\"c\""] {
+			assert!(validate_kernel_cmdline(v).is_err(), "{}", v);
+		}
+	}
+
+	#[test]
+	fn test_work_path() {
+		for v in [
+			"/srv/builds/work",
+			"./work",
+			"/srv/builds/2024:nightly",
+			"/tmp/-work",
+		] {
+			assert!(
+				validate_work_path("--workdir", Path::new(v)).is_ok(),
+				"{}",
+				v
+			);
+		}
+		for v in ["/srv/builds/a,b", "/srv/new\nline", "-work", "--workdir=/"] {
+			assert!(
+				validate_work_path("--workdir", Path::new(v)).is_err(),
+				"{}",
+				v
+			);
+		}
+	}
+}
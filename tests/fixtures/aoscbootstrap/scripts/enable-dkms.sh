@@ -0,0 +1,3 @@
+#!/bin/bash
+# Fake script for the tests.
+echo "Enabling DKMS ..."
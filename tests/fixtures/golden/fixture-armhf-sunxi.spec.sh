@@ -0,0 +1,32 @@
+DEVICE_ID='fixture-armhf-sunxi'
+DEVICE_COMPATIBLE='fixture,armhf-sunxi'
+DEVICE_VENDOR='other'
+DEVICE_NAME='Fixture 32-bit ARM Device'
+VARIANT='base'
+ARCH='armhf'
+IMAGE_SIZE_MIB='3072'
+LOOPDEV='@LOOPDEV@'
+NUM_PARTITIONS='2'
+ROOTPART='@LOOPDEV@p2'
+PARTITION_MAP='mbr'
+DISKLABEL='mbr'
+DISKUUID='@DISKUUID@'
+KERNEL_CMDLINE=''
+DEFAULT_USER='aosc'
+USERS='aosc'
+OOBE_WIZARD='0'
+DEVENA_TARGET=''
+PART1_MOUNTPOINT='/boot'
+PART1_FSTYPE='ext4'
+PART1_USAGE='boot'
+PART1_PARTUUID='@PARTUUID_1@'
+BOOT_PARTUUID='@PARTUUID_1@'
+PART1_FSUUID='@FSUUID_1@'
+BOOT_FSUUID='@FSUUID_1@'
+PART2_MOUNTPOINT='/'
+PART2_FSTYPE='ext4'
+PART2_USAGE='rootfs'
+PART2_PARTUUID='@PARTUUID_2@'
+ROOT_PARTUUID='@PARTUUID_2@'
+PART2_FSUUID='@FSUUID_2@'
+ROOT_FSUUID='@FSUUID_2@'
@@ -0,0 +1,34 @@
+DEVICE_ID='fixture-gpt-efi'
+DEVICE_COMPATIBLE=''
+DEVICE_VENDOR='fixture'
+DEVICE_NAME='Fixture GPT EFI Device'
+VARIANT='base'
+ARCH='amd64'
+IMAGE_SIZE_MIB='4096'
+LOOPDEV='@LOOPDEV@'
+NUM_PARTITIONS='2'
+ROOTPART='@LOOPDEV@p2'
+PARTITION_MAP='gpt'
+DISKLABEL='gpt'
+DISKUUID='@DISKUUID@'
+KERNEL_CMDLINE='root=UUID=@FSUUID_2@ rw quiet'
+DEFAULT_USER='aosc'
+USERS='aosc'
+OOBE_WIZARD='0'
+DEVENA_TARGET=''
+PART1_MOUNTPOINT='/efi'
+PART1_FSTYPE='fat32'
+PART1_USAGE='boot'
+PART1_PARTUUID='@PARTUUID_1@'
+BOOT_PARTUUID='@PARTUUID_1@'
+EFI_PARTUUID='@PARTUUID_1@'
+PART1_FSUUID='ABCD-1234'
+BOOT_FSUUID='ABCD-1234'
+EFI_FSUUID='ABCD-1234'
+PART2_MOUNTPOINT='/'
+PART2_FSTYPE='btrfs'
+PART2_USAGE='rootfs'
+PART2_PARTUUID='@PARTUUID_2@'
+ROOT_PARTUUID='@PARTUUID_2@'
+PART2_FSUUID='@FSUUID_2@'
+ROOT_FSUUID='@FSUUID_2@'
@@ -0,0 +1,36 @@
+DEVICE_ID='fixture-gpt-layout'
+DEVICE_COMPATIBLE=''
+DEVICE_VENDOR='other'
+DEVICE_NAME='Fixture Device with a Shared Layout'
+VARIANT='base'
+ARCH='riscv64'
+IMAGE_SIZE_MIB='4096'
+LOOPDEV='@LOOPDEV@'
+NUM_PARTITIONS='3'
+ROOTPART='@LOOPDEV@p3'
+PARTITION_MAP='gpt'
+DISKLABEL='gpt'
+DISKUUID='@DISKUUID@'
+KERNEL_CMDLINE=''
+DEFAULT_USER='aosc'
+USERS='aosc'
+OOBE_WIZARD='0'
+DEVENA_TARGET=''
+PART1_MOUNTPOINT=''
+PART1_FSTYPE='none'
+PART1_USAGE='other'
+PART1_PARTUUID='@PARTUUID_1@'
+PART2_MOUNTPOINT='/boot'
+PART2_FSTYPE='ext4'
+PART2_USAGE='boot'
+PART2_PARTUUID='@PARTUUID_2@'
+BOOT_PARTUUID='@PARTUUID_2@'
+PART2_FSUUID='@FSUUID_2@'
+BOOT_FSUUID='@FSUUID_2@'
+PART3_MOUNTPOINT='/'
+PART3_FSTYPE='ext4'
+PART3_USAGE='rootfs'
+PART3_PARTUUID='@PARTUUID_3@'
+ROOT_PARTUUID='@PARTUUID_3@'
+PART3_FSUUID='@FSUUID_3@'
+ROOT_FSUUID='@FSUUID_3@'
@@ -0,0 +1,2 @@
+#!/bin/bash
+echo "Post installation of $DEVICE_ID"
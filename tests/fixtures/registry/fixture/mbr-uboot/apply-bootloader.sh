@@ -0,0 +1,2 @@
+#!/bin/bash
+echo "Applying the bootloader of $DEVICE_ID to $LOOPDEV"